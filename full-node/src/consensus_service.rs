@@ -165,11 +165,25 @@ enum ToBackground {
     },
 }
 
+/// Number of times [`ConsensusService::new`] retries accessing the database when it is locked by
+/// another connection, before returning [`InitError::DatabaseBusy`].
+const MAX_BUSY_RETRIES: u32 = 10;
+
+/// Delay before the first retry of [`ConsensusService::new`] when the database is locked by
+/// another connection. The delay grows linearly with each retry.
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(500);
+
 /// Potential error when calling [`ConsensusService::new`].
 #[derive(Debug, derive_more::Display)]
 pub enum InitError {
     /// Database is corrupted.
     DatabaseCorruption(full_sqlite::CorruptedError),
+    /// Database has remained locked by another connection despite several attempts to access
+    /// it.
+    DatabaseBusy,
+    /// Access to the database has been interrupted, for example because the node is shutting
+    /// down. This doesn't indicate a problem with the database.
+    Interrupted,
     /// Error parsing the header of a block in the database.
     InvalidHeader(header::Error),
     /// `:code` key is missing from the finalized block storage.
@@ -180,10 +194,23 @@ pub enum InitError {
     FinalizedRuntimeInit(executor::host::NewErr),
 }
 
+/// Turns an error returned by the database during [`ConsensusService::new`] into an
+/// [`InitError`].
+fn database_init_err(err: full_sqlite::CorruptedError) -> InitError {
+    match err {
+        full_sqlite::CorruptedError::Busy => InitError::DatabaseBusy,
+        full_sqlite::CorruptedError::Interrupted => InitError::Interrupted,
+        err => InitError::DatabaseCorruption(err),
+    }
+}
+
 impl ConsensusService {
     /// Initializes the [`ConsensusService`] with the given configuration.
     pub async fn new(mut config: Config) -> Result<Arc<Self>, InitError> {
         // Perform the initial access to the database to load a bunch of information.
+        // The database might be temporarily locked by another connection, for example a backup
+        // tool, in which case the access is retried a few times after a delay.
+        let mut busy_retries = 0;
         let (
             finalized_block_number,
             finalized_heap_pages,
@@ -191,85 +218,128 @@ impl ConsensusService {
             best_block_hash,
             best_block_number,
             finalized_chain_information,
-        ) = config
-            .database
-            .with_database({
-                let block_number_bytes = config.block_number_bytes;
-                move |database| {
-                    // If the previous run of the full node crashed, the database will contain
-                    // blocks that are no longer useful in any way. We purge them all here.
-                    database
-                        .purge_finality_orphans()
-                        .map_err(InitError::DatabaseCorruption)?;
-
-                    let finalized_block_hash = database
-                        .finalized_block_hash()
-                        .map_err(InitError::DatabaseCorruption)?;
-                    let finalized_block_number = header::decode(
-                        &database
-                            .block_scale_encoded_header(&finalized_block_hash)
-                            .map_err(InitError::DatabaseCorruption)?
-                            .unwrap(), // A panic here would indicate a bug in the database code.
-                        block_number_bytes,
-                    )
-                    .map_err(InitError::InvalidHeader)?
-                    .number;
-                    let best_block_hash = database.best_block_hash().unwrap();
-                    let best_block_number = header::decode(
-                        &database
-                            .block_scale_encoded_header(&best_block_hash)
-                            .map_err(InitError::DatabaseCorruption)?
-                            .unwrap(), // A panic here would indicate a bug in the database code.
-                        block_number_bytes,
-                    )
-                    .map_err(InitError::InvalidHeader)?
-                    .number;
-                    let finalized_chain_information =
-                        match database.to_chain_information(&finalized_block_hash) {
-                            Ok(info) => info,
+        ) = loop {
+            let result = config
+                .database
+                .with_database({
+                    let block_number_bytes = config.block_number_bytes;
+                    move |database| {
+                        // If the previous run of the full node crashed, the database will contain
+                        // blocks that are no longer useful in any way. We purge them all here.
+                        database
+                            .purge_finality_orphans(None)
+                            .map_err(database_init_err)?;
+
+                        let finalized_block_hash =
+                            database.finalized_block_hash().map_err(database_init_err)?;
+                        let finalized_block_number = header::decode(
+                            &database
+                                .block_scale_encoded_header(&finalized_block_hash)
+                                .map_err(database_init_err)?
+                                .unwrap(), // A panic here would indicate a bug in the database code.
+                            block_number_bytes,
+                        )
+                        .map_err(InitError::InvalidHeader)?
+                        .number;
+                        let best_block_hash = database.best_block_hash().unwrap();
+                        let best_block_number = header::decode(
+                            &database
+                                .block_scale_encoded_header(&best_block_hash)
+                                .map_err(database_init_err)?
+                                .unwrap(), // A panic here would indicate a bug in the database code.
+                            block_number_bytes,
+                        )
+                        .map_err(InitError::InvalidHeader)?
+                        .number;
+                        let finalized_chain_information =
+                            match database.to_chain_information(&finalized_block_hash) {
+                                Ok(info) => info,
+                                Err(full_sqlite::StorageAccessError::Corrupted(err)) => {
+                                    return Err(database_init_err(err))
+                                }
+                                Err(full_sqlite::StorageAccessError::Busy) => {
+                                    return Err(InitError::DatabaseBusy)
+                                }
+                                Err(full_sqlite::StorageAccessError::Interrupted) => {
+                                    return Err(InitError::Interrupted)
+                                }
+                                Err(full_sqlite::StorageAccessError::IncompleteStorage)
+                                | Err(full_sqlite::StorageAccessError::UnknownBlock)
+                                | Err(full_sqlite::StorageAccessError::InvalidNibble { .. })
+                                | Err(full_sqlite::StorageAccessError::OddNumberOfNibbles) => {
+                                    unreachable!()
+                                }
+                            };
+                        let finalized_code = match database.block_storage_get_versioned(
+                            &finalized_block_hash,
+                            iter::empty::<iter::Empty<_>>(),
+                            trie::bytes_to_nibbles(b":code".iter().copied()).map(u8::from),
+                        ) {
+                            Ok(Some((code, _))) => code,
+                            Ok(None) => return Err(InitError::FinalizedCodeMissing),
                             Err(full_sqlite::StorageAccessError::Corrupted(err)) => {
-                                return Err(InitError::DatabaseCorruption(err))
+                                return Err(database_init_err(err))
+                            }
+                            Err(full_sqlite::StorageAccessError::Busy) => {
+                                return Err(InitError::DatabaseBusy)
+                            }
+                            Err(full_sqlite::StorageAccessError::Interrupted) => {
+                                return Err(InitError::Interrupted)
                             }
                             Err(full_sqlite::StorageAccessError::IncompleteStorage)
-                            | Err(full_sqlite::StorageAccessError::UnknownBlock) => unreachable!(),
+                            | Err(full_sqlite::StorageAccessError::UnknownBlock)
+                            | Err(full_sqlite::StorageAccessError::InvalidNibble { .. })
+                            | Err(full_sqlite::StorageAccessError::OddNumberOfNibbles) => {
+                                unreachable!()
+                            }
                         };
-                    let finalized_code = match database.block_storage_get(
-                        &finalized_block_hash,
-                        iter::empty::<iter::Empty<_>>(),
-                        trie::bytes_to_nibbles(b":code".iter().copied()).map(u8::from),
-                    ) {
-                        Ok(Some((code, _))) => code,
-                        Ok(None) => return Err(InitError::FinalizedCodeMissing),
-                        Err(full_sqlite::StorageAccessError::Corrupted(err)) => {
-                            return Err(InitError::DatabaseCorruption(err))
-                        }
-                        Err(full_sqlite::StorageAccessError::IncompleteStorage)
-                        | Err(full_sqlite::StorageAccessError::UnknownBlock) => unreachable!(),
-                    };
-                    let finalized_heap_pages = match database.block_storage_get(
-                        &finalized_block_hash,
-                        iter::empty::<iter::Empty<_>>(),
-                        trie::bytes_to_nibbles(b":heappages".iter().copied()).map(u8::from),
-                    ) {
-                        Ok(Some((hp, _))) => Some(hp),
-                        Ok(None) => None,
-                        Err(full_sqlite::StorageAccessError::Corrupted(err)) => {
-                            return Err(InitError::DatabaseCorruption(err))
-                        }
-                        Err(full_sqlite::StorageAccessError::IncompleteStorage)
-                        | Err(full_sqlite::StorageAccessError::UnknownBlock) => unreachable!(),
-                    };
-                    Ok((
-                        finalized_block_number,
-                        finalized_heap_pages,
-                        finalized_code,
-                        best_block_hash,
-                        best_block_number,
-                        finalized_chain_information,
-                    ))
+                        let finalized_heap_pages = match database.block_storage_get_versioned(
+                            &finalized_block_hash,
+                            iter::empty::<iter::Empty<_>>(),
+                            trie::bytes_to_nibbles(b":heappages".iter().copied()).map(u8::from),
+                        ) {
+                            Ok(Some((hp, _))) => Some(hp),
+                            Ok(None) => None,
+                            Err(full_sqlite::StorageAccessError::Corrupted(err)) => {
+                                return Err(database_init_err(err))
+                            }
+                            Err(full_sqlite::StorageAccessError::Busy) => {
+                                return Err(InitError::DatabaseBusy)
+                            }
+                            Err(full_sqlite::StorageAccessError::Interrupted) => {
+                                return Err(InitError::Interrupted)
+                            }
+                            Err(full_sqlite::StorageAccessError::IncompleteStorage)
+                            | Err(full_sqlite::StorageAccessError::UnknownBlock)
+                            | Err(full_sqlite::StorageAccessError::InvalidNibble { .. })
+                            | Err(full_sqlite::StorageAccessError::OddNumberOfNibbles) => {
+                                unreachable!()
+                            }
+                        };
+                        Ok((
+                            finalized_block_number,
+                            finalized_heap_pages,
+                            finalized_code,
+                            best_block_hash,
+                            best_block_number,
+                            finalized_chain_information,
+                        ))
+                    }
+                })
+                .await;
+
+            match result {
+                Err(InitError::DatabaseBusy) if busy_retries < MAX_BUSY_RETRIES => {
+                    busy_retries += 1;
+                    config.log_callback.log(
+                        LogLevel::Warn,
+                        format!("database-busy; attempt={}", busy_retries),
+                    );
+                    smol::Timer::after(BUSY_RETRY_DELAY * busy_retries).await;
                 }
-            })
-            .await?;
+                result => break result?,
+            }
+        };
 
         // The Kusama chain contains a fork hardcoded in the official Polkadot client.
         // See <https://github.com/paritytech/polkadot/blob/93f45f996a3d5592a57eba02f91f2fc2bc5a07cf/node/service/src/grandpa_support.rs#L111-L216>
@@ -1854,7 +1924,7 @@ impl SyncBackground {
                         let value = self
                             .database
                             .with_database(move |db| {
-                                db.block_storage_get(
+                                db.block_storage_get_versioned(
                                     &parent_hash,
                                     parent_paths.into_iter().map(|p| p.into_iter()),
                                     key.iter().copied(),
@@ -1863,13 +1933,11 @@ impl SyncBackground {
                             .await
                             .expect("database access error");
 
-                        block_authoring = req.inject_value(value.as_ref().map(|(val, vers)| {
-                            (
-                                iter::once(&val[..]),
-                                runtime_host::TrieEntryVersion::try_from(*vers)
-                                    .expect("corrupted database"),
-                            )
-                        }));
+                        block_authoring = req.inject_value(
+                            value
+                                .as_ref()
+                                .map(|(val, vers)| (iter::once(&val[..]), *vers)),
+                        );
                     }
                     author::build::BuilderAuthoring::ClosestDescendantMerkleValue(req) => {
                         let parent_paths = req.child_trie().map(|child_trie| {
@@ -2157,8 +2225,7 @@ impl SyncBackground {
                     }
                     Err(ExecuteBlockError::VerificationFailure(
                         ExecuteBlockVerificationFailureError::ParentCodeEmptyInDatabase
-                        | ExecuteBlockVerificationFailureError::InvaliParentHeapPagesInDatabase(_)
-                        | ExecuteBlockVerificationFailureError::DatabaseInvalidStateTrieVersion,
+                        | ExecuteBlockVerificationFailureError::InvaliParentHeapPagesInDatabase(_),
                     )) => panic!("corrupted database"),
                     Err(error) => {
                         // Print a separate warning because it is important for the user
@@ -2200,7 +2267,7 @@ impl SyncBackground {
 
                 match execute_block_success.block_insertion {
                     Ok(()) => {}
-                    Err(full_sqlite::InsertError::Duplicate) => {} // TODO: this should be an error ; right now we silence them because non-finalized blocks aren't loaded from the database at startup, resulting in them being downloaded again
+                    Err(full_sqlite::InsertError::Duplicate { .. }) => {} // TODO: this should be an error ; right now we silence them because non-finalized blocks aren't loaded from the database at startup, resulting in them being downloaded again
                     Err(error) => panic!("failed to insert block in database: {error}"),
                 }
 
@@ -2481,7 +2548,7 @@ pub async fn execute_block_and_insert(
                     let parent_block_hash = *parent_block_hash;
                     let value = database
                         .with_database(move |db| {
-                            db.block_storage_get(
+                            db.block_storage_get_versioned(
                                 &parent_block_hash,
                                 parent_paths.into_iter().map(|p| p.into_iter()),
                                 key.iter().copied(),
@@ -2489,14 +2556,9 @@ pub async fn execute_block_and_insert(
                         })
                         .await
                         .map_err(ExecuteBlockVerificationFailureError::DatabaseParentAccess)?;
-                    let value = match value.as_ref() {
-                        Some((val, vers)) => Some((
-                            iter::once(&val[..]),
-                            runtime_host::TrieEntryVersion::try_from(*vers)
-                                .map_err(|_| ExecuteBlockVerificationFailureError::DatabaseInvalidStateTrieVersion)?
-                        )),
-                        None => None,
-                    };
+                    let value = value
+                        .as_ref()
+                        .map(|(val, vers)| (iter::once(&val[..]), *vers));
 
                     database_accesses_duration += when_database_access_started.elapsed();
                     call = req.inject_value(value);
@@ -2603,7 +2665,7 @@ pub async fn execute_block_and_insert(
                     let parent_block_hash = *parent_block_hash;
                     database
                         .with_database(move |db| {
-                            db.block_storage_get(
+                            db.block_storage_get_versioned(
                                 &parent_block_hash,
                                 iter::empty::<iter::Empty<_>>(),
                                 trie::bytes_to_nibbles(b":code".into_iter().copied()).map(u8::from),
@@ -2623,7 +2685,7 @@ pub async fn execute_block_and_insert(
                     let parent_block_hash = *parent_block_hash;
                     database
                         .with_database(move |db| {
-                            db.block_storage_get(
+                            db.block_storage_get_versioned(
                                 &parent_block_hash,
                                 iter::empty::<iter::Empty<_>>(),
                                 trie::bytes_to_nibbles(b":heappages".into_iter().copied())
@@ -2664,7 +2726,13 @@ pub async fn execute_block_and_insert(
                 .map(|tx| tx.as_ref().to_owned())
                 .collect::<Vec<_>>();
             move |database| {
-                database.insert(&block_header, is_new_best, block_body.into_iter())?;
+                database.insert(
+                    &block_header,
+                    is_new_best,
+                    block_body.into_iter(),
+                    None,
+                    None,
+                )?;
 
                 let trie_nodes = storage_changes
                     .trie_changes_iter_ordered()
@@ -2707,7 +2775,7 @@ pub async fn execute_block_and_insert(
                                 runtime_host::TrieChangeStorageValue::Unmodified => {
                                     // TODO: overhead, and no child trie support
                                     if let Some((value_in_parent, _)) = database
-                                        .block_storage_get(
+                                        .block_storage_get_versioned(
                                             &parent_block_hash,
                                             iter::empty::<iter::Empty<_>>(),
                                             key.iter().map(|n| u8::from(*n)),
@@ -2733,8 +2801,22 @@ pub async fn execute_block_and_insert(
                     .collect::<Vec<_>>();
 
                 database
-                    .insert_trie_nodes(trie_nodes.into_iter(), u8::from(state_trie_version))
-                    .map_err(full_sqlite::InsertError::Corrupted)
+                    .insert_trie_nodes_versioned(trie_nodes.into_iter(), state_trie_version)
+                    .map_err(|err| match err {
+                        full_sqlite::InsertTrieNodesError::Corrupted(err) => {
+                            full_sqlite::InsertError::Corrupted(err)
+                        }
+                        // Partial keys are built from nibbles, Merkle values are calculated,
+                        // nodes aren't validated, and the version is typed.
+                        full_sqlite::InsertTrieNodesError::InvalidNibble { .. }
+                        | full_sqlite::InsertTrieNodesError::InvalidChildMerkleValue { .. }
+                        | full_sqlite::InsertTrieNodesError::InvalidTrieEntryVersion
+                        | full_sqlite::InsertTrieNodesError::OrphanTrieNodes { .. } => {
+                            unreachable!()
+                        }
+                    })?;
+
+                Ok(())
             }
         })
         .await;
@@ -2784,8 +2866,6 @@ pub enum ExecuteBlockVerificationFailureError {
     RuntimeStartError(executor::host::StartErr),
     /// Error while accessing the parent block in the database.
     DatabaseParentAccess(full_sqlite::StorageAccessError),
-    /// State trie version stored in database is invalid.
-    DatabaseInvalidStateTrieVersion,
     /// Runtime has tried to call a forbidden host function.
     ForbiddenHostFunction,
     /// The `:code` of the parent block in database is empty.
@@ -746,7 +746,7 @@ impl SubscribeStorage {
                 let (key, result) = self
                     .database
                     .with_database(move |database| {
-                        let result = database.block_storage_get(
+                        let result = database.block_storage_get_versioned(
                             &best_block_hash,
                             iter::empty::<iter::Empty<_>>(),
                             trie::bytes_to_nibbles(key.iter().copied()).map(u8::from),
@@ -766,10 +766,17 @@ impl SubscribeStorage {
                         self.subscription = None;
                         continue 'main_subscription;
                     }
-                    Err(database_thread::StorageAccessError::Corrupted(_)) => {
+                    Err(database_thread::StorageAccessError::Corrupted(_))
+                    | Err(database_thread::StorageAccessError::Busy)
+                    | Err(database_thread::StorageAccessError::Interrupted) => {
                         // Database corruption errors are ignored.
                         continue;
                     }
+                    Err(database_thread::StorageAccessError::InvalidNibble { .. })
+                    | Err(database_thread::StorageAccessError::OddNumberOfNibbles) => {
+                        // The keys are built from bytes.
+                        unreachable!()
+                    }
                 }
             }
 
@@ -18,6 +18,7 @@
 use futures_lite::future;
 use smol::stream::StreamExt as _;
 use smoldot::{
+    database::full_sqlite,
     executor,
     json_rpc::{methods, parse, service},
     trie,
@@ -280,12 +281,16 @@ pub fn spawn_requests_handler(config: Config) {
                                 request.respond(methods::Response::state_getKeysPaged(out));
                             }
                             Err(database_thread::StorageAccessError::IncompleteStorage)
-                            | Err(database_thread::StorageAccessError::UnknownBlock) => {
+                            | Err(database_thread::StorageAccessError::UnknownBlock)
+                            | Err(database_thread::StorageAccessError::InvalidNibble { .. })
+                            | Err(database_thread::StorageAccessError::OddNumberOfNibbles) => {
                                 // Note that it is unclear how the function should behave in
                                 // that situation.
                                 request.fail(service::ErrorResponse::InvalidParams);
                             }
-                            Err(database_thread::StorageAccessError::Corrupted(_)) => {
+                            Err(database_thread::StorageAccessError::Corrupted(_))
+                            | Err(database_thread::StorageAccessError::Busy)
+                            | Err(database_thread::StorageAccessError::Interrupted) => {
                                 request.fail(service::ErrorResponse::InternalError);
                             }
                         }
@@ -371,7 +376,7 @@ pub fn spawn_requests_handler(config: Config) {
                                     let value = config
                                         .database
                                         .with_database(move |db| {
-                                            db.block_storage_get(
+                                            db.block_storage_get_versioned(
                                                 &hash,
                                                 parent_paths.into_iter().map(|p| p.into_iter()),
                                                 key.iter().copied(),
@@ -382,13 +387,9 @@ pub fn spawn_requests_handler(config: Config) {
                                         request.fail(service::ErrorResponse::InternalError);
                                         break;
                                     };
-                                    let value = value.as_ref().map(|(val, vers)| {
-                                        (
-                                            iter::once(&val[..]),
-                                            executor::runtime_host::TrieEntryVersion::try_from(*vers)
-                                                .expect("corrupted database"),
-                                        )
-                                    });
+                                    let value = value
+                                        .as_ref()
+                                        .map(|(val, vers)| (iter::once(&val[..]), *vers));
 
                                     call = req.inject_value(value);
                                 }
@@ -539,9 +540,17 @@ pub fn spawn_requests_handler(config: Config) {
                                     None => db.best_block_hash()?,
                                 };
 
-                                let parent = db
-                                    .block_parent(&at)?
-                                    .ok_or(database_thread::StorageAccessError::UnknownBlock)?;
+                                // In case where `at` is the genesis block, we assume that its
+                                // "parent" (which doesn't exist) has an empty storage.
+                                let parent = match db.block_parent(&at)? {
+                                    Some(full_sqlite::ParentOf::Block(parent)) => Some(parent),
+                                    Some(full_sqlite::ParentOf::Genesis) => None,
+                                    None => {
+                                        return Err(
+                                            database_thread::StorageAccessError::UnknownBlock,
+                                        )
+                                    }
+                                };
 
                                 let mut out = methods::StorageChangeSet {
                                     block: methods::HashHexString(at),
@@ -551,24 +560,16 @@ pub fn spawn_requests_handler(config: Config) {
                                 for (key_nibbles, key) in
                                     keys_nibbles.into_iter().zip(keys.into_iter())
                                 {
-                                    let before = match db.block_storage_get(
-                                        &parent,
-                                        iter::empty::<iter::Empty<_>>(),
-                                        key_nibbles.iter().copied(),
-                                    ) {
-                                        Ok(v) => v,
-                                        Err(database_thread::StorageAccessError::UnknownBlock)
-                                            if parent == [0; 32] =>
-                                        {
-                                            // In case where `at` is the genesis block, we
-                                            // assume that its "parent" (which doesn't exist)
-                                            // has an empty storage.
-                                            None
-                                        }
-                                        Err(err) => return Err(err),
+                                    let before = match &parent {
+                                        Some(parent) => db.block_storage_get_versioned(
+                                            parent,
+                                            iter::empty::<iter::Empty<_>>(),
+                                            key_nibbles.iter().copied(),
+                                        )?,
+                                        None => None,
                                     };
 
-                                    let after = db.block_storage_get(
+                                    let after = db.block_storage_get_versioned(
                                         &at,
                                         iter::empty::<iter::Empty<_>>(),
                                         key_nibbles.iter().copied(),
@@ -590,12 +591,16 @@ pub fn spawn_requests_handler(config: Config) {
                                 request.respond(methods::Response::state_queryStorageAt(vec![out]));
                             }
                             Err(database_thread::StorageAccessError::IncompleteStorage)
-                            | Err(database_thread::StorageAccessError::UnknownBlock) => {
+                            | Err(database_thread::StorageAccessError::UnknownBlock)
+                            | Err(database_thread::StorageAccessError::InvalidNibble { .. })
+                            | Err(database_thread::StorageAccessError::OddNumberOfNibbles) => {
                                 // Note that it is unclear how the function should behave in
                                 // that situation.
                                 request.fail(service::ErrorResponse::InvalidParams);
                             }
-                            Err(database_thread::StorageAccessError::Corrupted(_)) => {
+                            Err(database_thread::StorageAccessError::Corrupted(_))
+                            | Err(database_thread::StorageAccessError::Busy)
+                            | Err(database_thread::StorageAccessError::Interrupted) => {
                                 request.fail(service::ErrorResponse::InternalError);
                             }
                         }
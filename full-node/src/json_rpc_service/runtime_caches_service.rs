@@ -81,12 +81,12 @@ impl RuntimeCachesService {
                         let (code, heap_pages) = config
                             .database
                             .with_database(move |database| {
-                                let code = database.block_storage_get(
+                                let code = database.block_storage_get_versioned(
                                     &block_hash,
                                     iter::empty::<iter::Empty<_>>(),
                                     trie::bytes_to_nibbles(b":code".iter().copied()).map(u8::from),
                                 );
-                                let heap_pages = database.block_storage_get(
+                                let heap_pages = database.block_storage_get_versioned(
                                     &block_hash,
                                     iter::empty::<iter::Empty<_>>(),
                                     trie::bytes_to_nibbles(b":heappages".iter().copied())
@@ -128,8 +128,19 @@ impl RuntimeCachesService {
                                 let _ = result_tx.send(Err(GetError::Pruned));
                                 continue;
                             }
+                            (Err(database_thread::StorageAccessError::InvalidNibble { .. }), _)
+                            | (_, Err(database_thread::StorageAccessError::InvalidNibble { .. }))
+                            | (Err(database_thread::StorageAccessError::OddNumberOfNibbles), _)
+                            | (_, Err(database_thread::StorageAccessError::OddNumberOfNibbles)) => {
+                                // The keys are built from bytes.
+                                unreachable!()
+                            }
                             (Err(database_thread::StorageAccessError::Corrupted(_)), _)
-                            | (_, Err(database_thread::StorageAccessError::Corrupted(_))) => {
+                            | (_, Err(database_thread::StorageAccessError::Corrupted(_)))
+                            | (Err(database_thread::StorageAccessError::Busy), _)
+                            | (_, Err(database_thread::StorageAccessError::Busy))
+                            | (Err(database_thread::StorageAccessError::Interrupted), _)
+                            | (_, Err(database_thread::StorageAccessError::Interrupted)) => {
                                 // Note that we don't put the `CorruptedError` in the cache, in
                                 // case the database somehow recovers.
                                 let _ = result_tx.send(Err(GetError::CorruptedDatabase));
@@ -33,7 +33,9 @@ use smoldot::{
     },
     trie,
 };
-use std::{array, borrow::Cow, io, iter, mem, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    array, borrow::Cow, io, iter, mem, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
 
 mod consensus_service;
 mod database_thread;
@@ -338,7 +340,18 @@ pub async fn start(mut config: Config<'_>) -> Result<Client, StartError> {
     // a query fails.
     config.log_callback.log(
         LogLevel::Debug,
-        format!("sqlite-version; version={}", full_sqlite::sqlite_version()),
+        match full_sqlite::sqlite_build_info() {
+            Ok(info) => format!(
+                "sqlite-version; version={}; compile_options={}; max_length={}; \
+                max_sql_length={}; max_expr_depth={}",
+                info.version,
+                info.compile_options.join(","),
+                info.max_length,
+                info.max_sql_length,
+                info.max_expr_depth
+            ),
+            Err(_) => format!("sqlite-version; version={}", full_sqlite::sqlite_version()),
+        },
     );
 
     let (database, database_existed) = {
@@ -347,6 +360,7 @@ pub async fn start(mut config: Config<'_>) -> Result<Client, StartError> {
             genesis_chain_information.as_ref(),
             config.chain.sqlite_database_path,
             config.chain.sqlite_cache_size,
+            &config.log_callback,
         )
         .await;
 
@@ -360,6 +374,7 @@ pub async fn start(mut config: Config<'_>) -> Result<Client, StartError> {
                 relay_genesis_chain_information.as_ref().unwrap().as_ref(),
                 relay_chain.sqlite_database_path.clone(),
                 relay_chain.sqlite_cache_size,
+                &config.log_callback,
             )
             .await
             .0,
@@ -810,11 +825,40 @@ async fn open_database(
     genesis_chain_information: chain::chain_information::ChainInformationRef<'_>,
     db_path: Option<PathBuf>,
     sqlite_cache_size: usize,
+    log_callback: &Arc<dyn LogCallback + Send + Sync>,
 ) -> (full_sqlite::SqliteFullDatabase, bool) {
-    // The `unwrap()` here can panic for example in case of access denied.
-    match full_sqlite::open(full_sqlite::Config {
+    // Upgrading the schema of a large database can take several minutes, during which the
+    // node would otherwise appear stuck.
+    let progress = {
+        let log_callback = log_callback.clone();
+        let chain_id = chain_spec.id().to_owned();
+        move |step| {
+            if let full_sqlite::OpenProgressStep::Migration { index, total } = step {
+                log_callback.log(
+                    LogLevel::Info,
+                    format!("database-upgrade; chain={chain_id}; migration={index}/{total}"),
+                );
+            }
+        }
+    };
+
+    // Panics for example in case of access denied, or if another node is using the database.
+    let database = full_sqlite::open(full_sqlite::Config {
         block_number_bytes: chain_spec.block_number_bytes().into(),
+        chain_name: None,
         cache_size: sqlite_cache_size,
+        statement_cache_capacity: 128,
+        busy_timeout: Duration::from_secs(5),
+        read_connections: 4,
+        commit_policy: full_sqlite::CommitPolicy::EveryOperation,
+        body_retention: None,
+        storage_diff_retention: None,
+        store_events: false,
+        on_operation: None,
+        auto_optimize_interval: Some(Duration::from_secs(2 * 3600)),
+        value_compression: None,
+        allow_shared_readers: false,
+        progress: Some(Box::new(progress)),
         ty: if let Some(path) = &db_path {
             full_sqlite::ConfigTy::Disk {
                 path,
@@ -824,8 +868,22 @@ async fn open_database(
             full_sqlite::ConfigTy::Memory
         },
     })
-    .unwrap()
-    {
+    .unwrap_or_else(|err| panic!("Failed to open the database: {err}"));
+
+    let report = database.report();
+    if report.schema_version_found != 0 && report.migrations_applied != 0 {
+        log_callback.log(
+            LogLevel::Info,
+            format!(
+                "database-upgraded; chain={}; migrations={}; duration={:?}",
+                chain_spec.id(),
+                report.migrations_applied,
+                report.duration
+            ),
+        );
+    }
+
+    match database {
         // Database already exists and contains data.
         full_sqlite::DatabaseOpen::Open(database) => {
             if database.block_hash_by_number(0).unwrap().next().unwrap()
@@ -860,8 +918,7 @@ async fn open_database(
             .runtime_version()
             .decode()
             .state_version
-            .map(u8::from)
-            .unwrap_or(0);
+            .unwrap_or(trie::TrieEntryVersion::V0);
 
             // The chain specification only contains trie nodes that have a storage value attached
             // to them, while the database needs to know all trie nodes (including branch nodes).
@@ -916,7 +973,7 @@ async fn open_database(
                     // checking difficulties.
                     let storage_value_hashed =
                         match (node_access.user_data().0.as_ref(), state_version) {
-                            (Some(v), 1) => {
+                            (Some(v), trie::TrieEntryVersion::V1) => {
                                 if v.len() >= 33 {
                                     Some(blake2_rfc::blake2b::blake2b(32, &[], v))
                                 } else {
@@ -993,12 +1050,15 @@ async fn open_database(
 
             // The finalized block is the genesis block. As such, it has an empty body and
             // no justification.
+            // The initialization is only marked as complete once the genesis storage has been
+            // inserted, so that it starts over if the node is stopped in the meantime.
             let database = empty
-                .initialize(genesis_chain_information, iter::empty(), None)
+                .initialize_incomplete(genesis_chain_information, iter::empty(), None)
                 .unwrap();
             database
-                .insert_trie_nodes(genesis_storage_full_trie, state_version)
+                .insert_trie_nodes_versioned(genesis_storage_full_trie, state_version)
                 .unwrap();
+            database.finish_initialization().unwrap();
             (database, false)
         }
     }
@@ -2276,7 +2276,7 @@ async fn blocks_request_response(
                 let hash = match next_block {
                     codec::BlocksRequestConfigStart::Hash(hash) => hash,
                     codec::BlocksRequestConfigStart::Number(number) => {
-                        // TODO: naive block selection ; should choose the best chain instead
+                        // The block of the best chain, if any, is always the first one.
                         match database.block_hash_by_number(number)?.next() {
                             Some(h) => h,
                             None => break,
@@ -2284,16 +2284,19 @@ async fn blocks_request_response(
                     }
                 };
 
-                let header = match database.block_scale_encoded_header(&hash)? {
-                    Some(h) => h,
+                // The header, body, and justifications are read at once, so that the block
+                // can't be removed from the database in between.
+                let block = match database.full_block(&hash)? {
+                    Some(b) => b,
                     None => break,
                 };
 
                 next_block = {
-                    let decoded = header::decode(&header, block_number_bytes).unwrap();
+                    let decoded =
+                        header::decode(&block.scale_encoded_header, block_number_bytes).unwrap();
                     match config.direction {
                         codec::BlocksRequestDirection::Ascending => {
-                            // TODO: right now, since we don't necessarily pick the best chain in `block_hash_by_number`, it is possible that the next block doesn't have the current block as parent
+                            // TODO: `block_hash_by_number` picks the best chain, and if the current block isn't part of the best chain the next block doesn't have it as parent
                             codec::BlocksRequestConfigStart::Number(decoded.number + 1)
                         }
                         codec::BlocksRequestDirection::Descending => {
@@ -2305,21 +2308,29 @@ async fn blocks_request_response(
                 output.push(codec::BlockData {
                     hash,
                     header: if config.fields.header {
-                        Some(header)
+                        Some(block.scale_encoded_header)
                     } else {
                         None
                     },
                     body: if config.fields.body {
-                        Some(match database.block_extrinsics(&hash)? {
-                            Some(body) => body.collect(),
+                        match block.body {
+                            Some(body) => Some(body),
                             None => break,
-                        })
+                        }
                     } else {
                         None
                     },
                     justifications: if config.fields.justifications {
-                        // TODO: justifications aren't saved in database at the moment
-                        Some(Vec::new())
+                        Some(
+                            block
+                                .justifications
+                                .into_iter()
+                                .map(|(engine_id, justification)| codec::Justification {
+                                    engine_id,
+                                    justification,
+                                })
+                                .collect(),
+                        )
                     } else {
                         None
                     },
@@ -0,0 +1,287 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::{array, borrow::Cow, iter, time::Duration};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use smoldot::{
+    chain::chain_information,
+    database::full_sqlite::{
+        open, CommitPolicy, Config, ConfigTy, DatabaseOpen, InsertTrieNode,
+        InsertTrieNodeStorageValue,
+    },
+    header, trie,
+};
+
+fn benchmark_storage_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("full-sqlite-storage-get");
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        block_number_bytes: 4,
+        chain_name: None,
+        cache_size: 64 * 1024 * 1024,
+        statement_cache_capacity: 128,
+        busy_timeout: Duration::from_secs(5),
+        read_connections: 0,
+        commit_policy: CommitPolicy::EveryOperation,
+        body_retention: None,
+        storage_diff_retention: None,
+        store_events: false,
+        on_operation: None,
+        auto_optimize_interval: None,
+        value_compression: None,
+        allow_shared_readers: false,
+        progress: None,
+        ty: ConfigTy::Memory,
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    // The main trie and the child trie have the same layout. The child trie is referenced by the
+    // storage value of the root of the main trie, in other words is found at the empty key.
+    let main_trie_root = node_hash(0, &[]);
+    let child_trie_root = node_hash(1, &[]);
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &main_trie_root,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+
+    db.insert_trie_nodes_versioned(
+        trie_nodes(0, 3, Some(child_trie_root)).chain(trie_nodes(1, 3, None)),
+        trie::TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    let keys = (0..256u16)
+        .map(|n| {
+            let path = [(n >> 4) as u8, (n & 0xf) as u8, 0];
+            path.iter()
+                .copied()
+                .chain(leaf_partial_key(&path))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    for (name, parent_tries_paths) in [("main-trie", &[][..]), ("child-trie", &[&[][..]][..])] {
+        group.bench_function(BenchmarkId::new("get", name), |b| {
+            let mut keys = keys.iter().cycle();
+            b.iter(|| {
+                let key = keys.next().unwrap();
+                db.block_storage_get_versioned(
+                    &block_hash,
+                    parent_tries_paths.iter().map(|p| p.iter().copied()),
+                    key.iter().copied(),
+                )
+                .unwrap()
+                .unwrap()
+            })
+        });
+    }
+
+    group.finish()
+}
+
+/// Same as [`benchmark_storage_get`], but on a disk database containing a trie of around 1.1
+/// million nodes, which is more representative of the storage of a real chain. Accessing an
+/// entry walks down 6 nodes.
+fn benchmark_storage_get_large_trie(c: &mut Criterion) {
+    let mut group = c.benchmark_group("full-sqlite-storage-get-large-trie");
+    group.sample_size(20);
+
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_config = || Config {
+        block_number_bytes: 4,
+        chain_name: None,
+        cache_size: 64 * 1024 * 1024,
+        statement_cache_capacity: 128,
+        busy_timeout: Duration::from_secs(5),
+        read_connections: 0,
+        commit_policy: CommitPolicy::EveryOperation,
+        body_retention: None,
+        storage_diff_retention: None,
+        store_events: false,
+        on_operation: None,
+        auto_optimize_interval: None,
+        value_compression: None,
+        allow_shared_readers: false,
+        progress: None,
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+    };
+
+    let DatabaseOpen::Empty(empty_db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+
+    let trie_root = node_hash(0, &[]);
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &trie_root,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+
+    db.insert_trie_nodes_versioned(trie_nodes(0, 5, None), trie::TrieEntryVersion::V0)
+        .unwrap();
+
+    // The size of the database is printed in order to be able to compare the layouts of the
+    // database. The database is closed beforehand so that the write-ahead log is merged into the
+    // database file.
+    drop(db);
+    println!(
+        "Database size: {} MiB",
+        std::fs::metadata(&path).unwrap().len() / (1024 * 1024)
+    );
+
+    let DatabaseOpen::Open(db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+
+    // The keys are spread over the entire trie, so that the accesses don't always hit the same
+    // pages of the database.
+    let keys = (0..4096u32)
+        .map(|n| {
+            let n = n.wrapping_mul(2654435761);
+            let path = (0..5)
+                .map(|d| ((n >> (4 * d)) & 0xf) as u8)
+                .collect::<Vec<_>>();
+            path.iter()
+                .copied()
+                .chain(leaf_partial_key(&path))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    group.bench_function("get", |b| {
+        let mut keys = keys.iter().cycle();
+        b.iter(|| {
+            let key = keys.next().unwrap();
+            db.block_storage_get_versioned(
+                &block_hash,
+                iter::empty::<iter::Empty<u8>>(),
+                key.iter().copied(),
+            )
+            .unwrap()
+            .unwrap()
+        })
+    });
+
+    group.finish()
+}
+
+/// Builds a trie whose nodes at depth strictly inferior to `leaves_depth` are branch nodes with
+/// 16 children each, and whose nodes at depth `leaves_depth` each hold a storage value, for a
+/// total of `16^leaves_depth` storage entries.
+///
+/// `salt` is used to make the hashes of the nodes different between tries.
+fn trie_nodes(
+    salt: u8,
+    leaves_depth: u32,
+    root_storage_value: Option<[u8; 32]>,
+) -> impl Iterator<Item = InsertTrieNode<'static>> {
+    (0..=leaves_depth).flat_map(move |depth| {
+        (0..16u32.pow(depth)).map(move |n| {
+            let path = (0..depth)
+                .rev()
+                .map(|d| ((n >> (4 * d)) & 0xf) as u8)
+                .collect::<Vec<_>>();
+
+            if depth == leaves_depth {
+                InsertTrieNode {
+                    merkle_value: Cow::Owned(node_hash(salt, &path).to_vec()),
+                    partial_key_nibbles: Cow::Owned(leaf_partial_key(&path).collect()),
+                    children_merkle_values: array::from_fn(|_| None),
+                    storage_value: InsertTrieNodeStorageValue::Value {
+                        value: Cow::Owned(vec![path[0] ^ path[1] ^ path[2]; 32]),
+                        references_merkle_value: false,
+                    },
+                }
+            } else {
+                InsertTrieNode {
+                    merkle_value: Cow::Owned(node_hash(salt, &path).to_vec()),
+                    partial_key_nibbles: Cow::Borrowed(&[]),
+                    children_merkle_values: array::from_fn(|child| {
+                        let mut child_path = path.clone();
+                        child_path.push(child as u8);
+                        Some(Cow::Owned(node_hash(salt, &child_path).to_vec()))
+                    }),
+                    storage_value: match (depth, root_storage_value) {
+                        (0, Some(value)) => InsertTrieNodeStorageValue::Value {
+                            value: Cow::Owned(value.to_vec()),
+                            references_merkle_value: true,
+                        },
+                        _ => InsertTrieNodeStorageValue::NoValue,
+                    },
+                }
+            }
+        })
+    })
+}
+
+/// Returns a fake but unique hash for the node of the given trie and path.
+fn node_hash(salt: u8, path: &[u8]) -> [u8; 32] {
+    let mut hash = [0xff; 32];
+    hash[0] = salt;
+    hash[1] = path.len() as u8;
+    hash[2..][..path.len()].copy_from_slice(path);
+    hash
+}
+
+/// Partial key of the leaf at the given path. The partial key of the leaves is chosen so that
+/// the keys of the storage entries are 32 bytes long.
+fn leaf_partial_key(path: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    path.iter().copied().cycle().take(64 - path.len())
+}
+
+criterion_group!(
+    benches,
+    benchmark_storage_get,
+    benchmark_storage_get_large_trie
+);
+criterion_main!(benches);
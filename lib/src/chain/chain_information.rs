@@ -215,7 +215,7 @@ pub enum ChainInformationConsensus {
 }
 
 /// Information about a Babe epoch.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BabeEpochInformation {
     /// Index of the epoch.
     ///
@@ -635,7 +635,7 @@ pub enum ValidityError {
 }
 
 /// Error when checking the validity of a Babe epoch.
-#[derive(Debug, derive_more::Display)]
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
 pub enum BabeValidityError {
     /// Babe constant should be a fraction where the numerator is inferior or equal to the
     /// denominator.
@@ -37,7 +37,7 @@
 use crate::{
     chain::chain_information::{
         build, BabeEpochInformation, ChainInformation, ChainInformationConsensus,
-        ChainInformationFinality, ValidChainInformation, ValidityError,
+        ChainInformationFinality, ChainInformationRef, ValidChainInformation, ValidityError,
     },
     executor, libp2p, trie,
 };
@@ -339,6 +339,7 @@ impl ChainSpec {
             .map(|state| LightSyncState {
                 // We made sure at initialization that the decoding succeeds.
                 inner: state.decode(self.block_number_bytes().into()).unwrap(),
+                raw: state.clone(),
             })
     }
 }
@@ -442,6 +443,7 @@ impl<'a> GenesisStorageItems<'a> {
 
 pub struct LightSyncState {
     inner: light_sync_state::DecodedLightSyncState,
+    raw: light_sync_state::LightSyncState,
 }
 
 fn convert_epoch(epoch: &light_sync_state::BabeEpoch) -> Box<BabeEpochInformation> {
@@ -465,6 +467,43 @@ fn convert_epoch(epoch: &light_sync_state::BabeEpoch) -> Box<BabeEpochInformatio
 }
 
 impl LightSyncState {
+    /// Builds a [`LightSyncState`] whose finalized block and finalized state are the ones of the
+    /// given chain information, for example in order to publish a checkpoint.
+    ///
+    /// Only chains using Babe and Grandpa are supported, as this is the only combination that
+    /// Substrate checkpoints can describe.
+    pub fn from_chain_information<'a>(
+        chain_information: impl Into<ChainInformationRef<'a>>,
+        block_number_bytes: usize,
+    ) -> Result<Self, ChainInformationToCheckpointError> {
+        let raw = light_sync_state::LightSyncState::from_chain_information(
+            chain_information.into(),
+            block_number_bytes,
+        )?;
+        let inner = raw
+            .decode(block_number_bytes)
+            .map_err(|_| ChainInformationToCheckpointError::InvalidFinalizedBlockHeader)?;
+        Ok(LightSyncState { inner, raw })
+    }
+
+    /// Parses the JSON representation of a light sync state, in the format of the
+    /// `lightSyncState` field of Substrate chain specs.
+    ///
+    /// This is the reverse operation of [`LightSyncState::to_json`].
+    pub fn from_json(json: &str, block_number_bytes: usize) -> Result<Self, ParseError> {
+        let raw: light_sync_state::LightSyncState = serde_json::from_str(json)
+            .map_err(ParseErrorInner::Serde)
+            .map_err(ParseError)?;
+        let inner = raw.decode(block_number_bytes)?;
+        Ok(LightSyncState { inner, raw })
+    }
+
+    /// Returns the JSON representation of this light sync state, in the format of the
+    /// `lightSyncState` field of Substrate chain specs.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.raw).unwrap()
+    }
+
     pub fn to_chain_information(
         &self,
     ) -> Result<ValidChainInformation, CheckpointToChainInformationError> {
@@ -575,3 +614,21 @@ pub enum CheckpointToChainInformationError {
     #[display(fmt = "{_0}")]
     InvalidData(ValidityError),
 }
+
+/// Error when building a checkpoint from a chain information.
+#[derive(Debug, derive_more::Display)]
+pub enum ChainInformationToCheckpointError {
+    /// The chain information corresponds to the genesis block.
+    GenesisBlockCheckpoint,
+    /// The chain doesn't use Babe. Only Babe chains can be described by a checkpoint.
+    NotBabe,
+    /// The chain doesn't use Grandpa. Only Grandpa chains can be described by a checkpoint.
+    NotGrandpa,
+    /// The slot number at which a Babe epoch starts is unknown.
+    UnknownEpochStartSlot,
+    /// A block number doesn't fit in 32 bits.
+    BlockNumberOverflow,
+    /// The finalized block header can't be decoded back after having been encoded, for example
+    /// because its number doesn't fit in the given number of bytes.
+    InvalidFinalizedBlockHeader,
+}
@@ -15,8 +15,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use super::{ParseError, ParseErrorInner};
-use crate::header::BabeNextConfig;
+use super::{ChainInformationToCheckpointError, ParseError, ParseErrorInner};
+use crate::{
+    chain::chain_information::{
+        BabeEpochInformationRef, ChainInformationConsensusRef, ChainInformationFinalityRef,
+        ChainInformationRef,
+    },
+    header::{self, BabeNextConfig},
+    util,
+};
 
 use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
 use serde::{Deserialize, Serialize};
@@ -67,6 +74,172 @@ impl LightSyncState {
             babe_epoch_changes,
         })
     }
+
+    /// Builds the light sync state corresponding to the given chain information, in the same
+    /// format as Substrate.
+    ///
+    /// Substrate stores all the epochs and pending authority set changes that it knows about,
+    /// while the chain information only contains the ones relevant to the finalized block. The
+    /// epochs are consequently attached to the finalized block and its parent, and the
+    /// scheduled Grandpa change, if any, to the finalized block. The weight of the finalized
+    /// block isn't known and is set to 0.
+    pub(super) fn from_chain_information(
+        chain_information: ChainInformationRef,
+        block_number_bytes: usize,
+    ) -> Result<Self, ChainInformationToCheckpointError> {
+        let finalized_block_header = &chain_information.finalized_block_header;
+        if finalized_block_header.number == 0 {
+            return Err(ChainInformationToCheckpointError::GenesisBlockCheckpoint);
+        }
+        let finalized_block_number = u32::try_from(finalized_block_header.number)
+            .map_err(|_| ChainInformationToCheckpointError::BlockNumberOverflow)?;
+        let finalized_block_hash = finalized_block_header.hash(block_number_bytes);
+
+        let (slots_per_epoch, current_epoch, next_epoch) = match chain_information.consensus {
+            ChainInformationConsensusRef::Babe {
+                slots_per_epoch,
+                finalized_block_epoch_information: Some(current_epoch),
+                finalized_next_epoch_transition,
+            } => (
+                slots_per_epoch.get(),
+                current_epoch,
+                finalized_next_epoch_transition,
+            ),
+            ChainInformationConsensusRef::Babe {
+                finalized_block_epoch_information: None,
+                ..
+            } => return Err(ChainInformationToCheckpointError::GenesisBlockCheckpoint),
+            _ => return Err(ChainInformationToCheckpointError::NotBabe),
+        };
+
+        let (set_id, triggered_authorities, scheduled_change) = match chain_information.finality {
+            ChainInformationFinalityRef::Grandpa {
+                after_finalized_block_authorities_set_id,
+                finalized_triggered_authorities,
+                finalized_scheduled_change,
+            } => (
+                after_finalized_block_authorities_set_id,
+                finalized_triggered_authorities,
+                finalized_scheduled_change,
+            ),
+            ChainInformationFinalityRef::Outsourced => {
+                return Err(ChainInformationToCheckpointError::NotGrandpa)
+            }
+        };
+
+        // Epoch changes. The current epoch is attached to the parent of the finalized block,
+        // and the next epoch to the finalized block, in order for the decoding to find them in
+        // the right order.
+        let mut babe_epoch_changes = Vec::new();
+        let current_epoch_start = current_epoch
+            .start_slot_number
+            .ok_or(ChainInformationToCheckpointError::UnknownEpochStartSlot)?;
+        let next_epoch_start = next_epoch
+            .start_slot_number
+            .ok_or(ChainInformationToCheckpointError::UnknownEpochStartSlot)?;
+        babe_epoch_changes.extend_from_slice(util::encode_scale_compact_usize(1).as_ref());
+        babe_epoch_changes.extend_from_slice(finalized_block_header.parent_hash);
+        babe_epoch_changes.extend_from_slice(&(finalized_block_number - 1).to_le_bytes());
+        encode_epoch_header(
+            &mut babe_epoch_changes,
+            current_epoch_start,
+            slots_per_epoch,
+        );
+        babe_epoch_changes.extend_from_slice(util::encode_scale_compact_usize(1).as_ref());
+        babe_epoch_changes.extend_from_slice(&finalized_block_hash);
+        babe_epoch_changes.extend_from_slice(&finalized_block_number.to_le_bytes());
+        encode_epoch_header(&mut babe_epoch_changes, next_epoch_start, slots_per_epoch);
+        babe_epoch_changes.extend_from_slice(util::encode_scale_compact_usize(0).as_ref());
+        babe_epoch_changes.push(0); // Best finalized number of the fork tree.
+        babe_epoch_changes.extend_from_slice(util::encode_scale_compact_usize(2).as_ref());
+        babe_epoch_changes.extend_from_slice(finalized_block_header.parent_hash);
+        babe_epoch_changes.extend_from_slice(&(finalized_block_number - 1).to_le_bytes());
+        encode_epoch(&mut babe_epoch_changes, &current_epoch, slots_per_epoch)?;
+        babe_epoch_changes.extend_from_slice(&finalized_block_hash);
+        babe_epoch_changes.extend_from_slice(&finalized_block_number.to_le_bytes());
+        encode_epoch(&mut babe_epoch_changes, &next_epoch, slots_per_epoch)?;
+        babe_epoch_changes.push(0); // Gap epochs.
+
+        // Authority set.
+        let mut grandpa_authority_set = Vec::new();
+        encode_grandpa_authorities(&mut grandpa_authority_set, triggered_authorities);
+        grandpa_authority_set.extend_from_slice(&set_id.to_le_bytes());
+        if let Some((trigger_block_number, next_authorities)) = scheduled_change {
+            let delay = trigger_block_number
+                .checked_sub(finalized_block_header.number)
+                .and_then(|delay| u32::try_from(delay).ok())
+                .ok_or(ChainInformationToCheckpointError::BlockNumberOverflow)?;
+            grandpa_authority_set.extend_from_slice(util::encode_scale_compact_usize(1).as_ref());
+            grandpa_authority_set.extend_from_slice(&finalized_block_hash);
+            grandpa_authority_set.extend_from_slice(&finalized_block_number.to_le_bytes());
+            encode_grandpa_authorities(&mut grandpa_authority_set, next_authorities);
+            grandpa_authority_set.extend_from_slice(&delay.to_le_bytes());
+            grandpa_authority_set.extend_from_slice(&finalized_block_number.to_le_bytes());
+            grandpa_authority_set.extend_from_slice(&finalized_block_hash);
+            grandpa_authority_set.push(0); // `DelayKind::Finalized`
+            grandpa_authority_set.extend_from_slice(util::encode_scale_compact_usize(0).as_ref());
+        } else {
+            grandpa_authority_set.extend_from_slice(util::encode_scale_compact_usize(0).as_ref());
+        }
+        grandpa_authority_set.push(0); // Best finalized number of the fork tree.
+        grandpa_authority_set.extend_from_slice(util::encode_scale_compact_usize(0).as_ref()); // Forced changes.
+        grandpa_authority_set.extend_from_slice(util::encode_scale_compact_usize(0).as_ref()); // Authority set changes.
+
+        Ok(LightSyncState {
+            babe_epoch_changes: HexString(babe_epoch_changes),
+            babe_finalized_block_weight: 0,
+            finalized_block_header: HexString(
+                finalized_block_header.scale_encoding_vec(block_number_bytes),
+            ),
+            grandpa_authority_set: HexString(grandpa_authority_set),
+        })
+    }
+}
+
+fn encode_epoch_header(out: &mut Vec<u8>, start_slot: u64, slots_per_epoch: u64) {
+    out.push(1); // `PersistedEpochHeader::Regular`
+    out.extend_from_slice(&start_slot.to_le_bytes());
+    out.extend_from_slice(&start_slot.saturating_add(slots_per_epoch).to_le_bytes());
+}
+
+fn encode_epoch(
+    out: &mut Vec<u8>,
+    epoch: &BabeEpochInformationRef,
+    slots_per_epoch: u64,
+) -> Result<(), ChainInformationToCheckpointError> {
+    out.push(1); // `PersistedEpoch::Regular`
+    out.extend_from_slice(&epoch.epoch_index.to_le_bytes());
+    out.extend_from_slice(
+        &epoch
+            .start_slot_number
+            .ok_or(ChainInformationToCheckpointError::UnknownEpochStartSlot)?
+            .to_le_bytes(),
+    );
+    out.extend_from_slice(&slots_per_epoch.to_le_bytes());
+    out.extend_from_slice(util::encode_scale_compact_usize(epoch.authorities.len()).as_ref());
+    for authority in epoch.authorities.clone() {
+        for buffer in authority.scale_encoding() {
+            out.extend_from_slice(buffer.as_ref());
+        }
+    }
+    out.extend_from_slice(epoch.randomness);
+    let config = BabeNextConfig {
+        c: epoch.c,
+        allowed_slots: epoch.allowed_slots,
+    };
+    for buffer in config.scale_encoding() {
+        out.extend_from_slice(buffer.as_ref());
+    }
+    Ok(())
+}
+
+fn encode_grandpa_authorities(out: &mut Vec<u8>, authorities: &[header::GrandpaAuthority]) {
+    out.extend_from_slice(util::encode_scale_compact_usize(authorities.len()).as_ref());
+    for authority in authorities {
+        for buffer in authority.scale_encoding() {
+            out.extend_from_slice(buffer.as_ref());
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -17,7 +17,19 @@
 
 #![cfg(test)]
 
-use super::{Bootnode, ChainSpec, CheckpointToChainInformationError};
+use super::{
+    light_sync_state, Bootnode, ChainInformationToCheckpointError, ChainSpec,
+    CheckpointToChainInformationError, LightSyncState,
+};
+use crate::{
+    chain::chain_information::{
+        BabeEpochInformation, ChainInformation, ChainInformationConsensus, ChainInformationFinality,
+    },
+    header,
+};
+
+use alloc::{boxed::Box, vec};
+use core::num::NonZeroU64;
 
 #[test]
 fn can_decode_polkadot_genesis() {
@@ -155,3 +167,109 @@ fn issue_598() {
         Err(CheckpointToChainInformationError::GenesisBlockCheckpoint)
     ));
 }
+
+#[test]
+fn light_sync_state_from_chain_information() {
+    let epoch = |epoch_index, start_slot_number| {
+        Box::new(BabeEpochInformation {
+            epoch_index,
+            start_slot_number: Some(start_slot_number),
+            authorities: vec![header::BabeAuthority {
+                public_key: [epoch_index as u8; 32],
+                weight: 1,
+            }],
+            randomness: [epoch_index as u8 + 100; 32],
+            c: (1, 4),
+            allowed_slots: header::BabeAllowedSlots::PrimaryAndSecondaryVrfSlots,
+        })
+    };
+    let grandpa_authorities = vec![header::GrandpaAuthority {
+        public_key: [7; 32],
+        weight: NonZeroU64::new(3).unwrap(),
+    }];
+
+    let mut chain_information = ChainInformation {
+        finalized_block_header: Box::new(header::Header {
+            parent_hash: [1; 32],
+            number: 1000,
+            state_root: [2; 32],
+            extrinsics_root: [3; 32],
+            digest: header::DigestRef::from_slice(&[
+                header::DigestItem::BabePreDigest(header::BabePreDigest::SecondaryPlain(
+                    header::BabeSecondaryPlainPreDigest {
+                        authority_index: 0,
+                        slot_number: 2000,
+                    },
+                )),
+                header::DigestItem::BabeSeal([0; 64]),
+            ])
+            .unwrap()
+            .into(),
+        }),
+        consensus: ChainInformationConsensus::Babe {
+            slots_per_epoch: NonZeroU64::new(600).unwrap(),
+            finalized_block_epoch_information: Some(epoch(3, 1800)),
+            finalized_next_epoch_transition: epoch(4, 2400),
+        },
+        finality: ChainInformationFinality::Grandpa {
+            after_finalized_block_authorities_set_id: 5,
+            finalized_triggered_authorities: grandpa_authorities.clone(),
+            finalized_scheduled_change: Some((1010, grandpa_authorities.clone())),
+        },
+    };
+
+    // Go through the JSON format in order to make sure that it can be parsed back.
+    let json = LightSyncState::from_chain_information(&chain_information, 4)
+        .unwrap()
+        .to_json();
+    let raw = serde_json::from_str::<light_sync_state::LightSyncState>(&json).unwrap();
+    let decoded = LightSyncState {
+        inner: raw.decode(4).unwrap(),
+        raw,
+    }
+    .to_chain_information()
+    .unwrap();
+    let decoded = ChainInformation::from(decoded);
+
+    assert_eq!(
+        decoded.finalized_block_header.hash(4),
+        chain_information.finalized_block_header.hash(4)
+    );
+    let ChainInformationConsensus::Babe {
+        slots_per_epoch,
+        finalized_block_epoch_information: Some(current_epoch),
+        finalized_next_epoch_transition,
+    } = decoded.consensus
+    else {
+        panic!()
+    };
+    assert_eq!(slots_per_epoch.get(), 600);
+    for (decoded, expected) in [
+        (current_epoch, epoch(3, 1800)),
+        (finalized_next_epoch_transition, epoch(4, 2400)),
+    ] {
+        assert_eq!(decoded.epoch_index, expected.epoch_index);
+        assert_eq!(decoded.start_slot_number, expected.start_slot_number);
+        assert_eq!(decoded.authorities, expected.authorities);
+        assert_eq!(decoded.randomness, expected.randomness);
+        assert_eq!(decoded.c, expected.c);
+        assert_eq!(decoded.allowed_slots, expected.allowed_slots);
+    }
+    let ChainInformationFinality::Grandpa {
+        after_finalized_block_authorities_set_id,
+        finalized_triggered_authorities,
+        ..
+    } = decoded.finality
+    else {
+        panic!()
+    };
+    assert_eq!(after_finalized_block_authorities_set_id, 5);
+    assert_eq!(finalized_triggered_authorities, grandpa_authorities);
+
+    // The genesis block can't be turned into a checkpoint.
+    chain_information.finalized_block_header.number = 0;
+    assert!(matches!(
+        LightSyncState::from_chain_information(&chain_information, 4),
+        Err(ChainInformationToCheckpointError::GenesisBlockCheckpoint)
+    ));
+}
@@ -51,10 +51,30 @@
 //! reasonable solutions are either to stop the program, or to delete the entire database and
 //! recreate it.
 //!
+//! The exception is [`CorruptedError::Busy`] (and its equivalent [`StorageAccessError::Busy`]),
+//! which is returned if another connection, such as a backup job, holds a lock on the database
+//! for longer than [`Config::busy_timeout`]. The operation can simply be retried later.
+//!
+//! Similarly, [`CorruptedError::Interrupted`] (and its equivalent
+//! [`StorageAccessError::Interrupted`]) is returned if the operation has been aborted using an
+//! [`InterruptHandle`].
+//!
+//! [`CorruptedError::StorageFull`] is returned if the disk is full. The database is left intact,
+//! and the operation can be retried once space has been freed. However, the modifications that
+//! were waiting to be committed because of the [`CommitPolicy`] might have been rolled back as
+//! well, which is reported by [`CorruptedError::UncommittedWritesLost`].
+//!
 //! # Schema
 //!
 //! The SQL schema of the database, with explanatory comments, can be found in `open.rs`.
 //!
+//! # Multiple chains
+//!
+//! A single database file can contain the blocks of multiple chains. Each [`SqliteFullDatabase`]
+//! only accesses the blocks of the chain designated by [`Config::chain_name`], and behaves as if
+//! the other chains didn't exist. The trie nodes, which are indexed by their hash, are shared
+//! between all the chains of the database.
+//!
 //! # About blocking behavior
 //!
 //! This implementation uses the SQLite library, which isn't Rust-asynchronous-compatible. Many
@@ -65,62 +85,603 @@
 //! For this reason, you are encouraged to isolate the database in its own threads and never
 //! access it directly from an asynchronous context.
 //!
+//! # About concurrent accesses
+//!
+//! All the methods of [`SqliteFullDatabase`] take `&self`, and the database can be shared
+//! between multiple threads. Methods that modify the database are serialized through a single
+//! connection. Methods that only read from the database use a pool of read-only connections
+//! (see [`Config::read_connections`]), and can thus run in parallel with each other and with a
+//! modification.
+//!
+//! Each read-only method sees a consistent snapshot of the database corresponding to the moment
+//! when it started, and any modification committed before that moment. A modification that is
+//! in progress while a read is performed is thus either entirely visible or entirely invisible
+//! to this read. Consequently, a read performed right after a method that modifies the database
+//! has returned always sees this modification. However, two successive reads might observe
+//! different states if a modification has been committed in between.
+//!
+//! # Durability
+//!
+//! By default, each modification is committed to the disk before the method that performs it
+//! returns. [`Config::commit_policy`] makes it possible to group multiple modifications into a
+//! single commit, in which case [`SqliteFullDatabase::flush`] must be called before relying on
+//! these modifications having been persisted, for example before announcing a block to other
+//! peers. See [`CommitPolicy`] for what happens in case of a crash.
+//!
 
 // TODO: better docs
 
 #![cfg(feature = "database-sqlite")]
 #![cfg_attr(docsrs, doc(cfg(feature = "database-sqlite")))]
 
-use crate::{chain::chain_information, header, util};
-
-use alloc::borrow::Cow;
-use core::{fmt, iter, num::NonZeroU64};
-use parking_lot::Mutex;
+use crate::{chain::chain_information, executor, header, trie, util};
+
+use alloc::{borrow::Cow, collections::BTreeMap, string::String, sync::Arc};
+use core::{
+    cmp, fmt, mem,
+    num::{NonZeroU64, NonZeroUsize},
+    ops,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+use parking_lot::{Mutex, MutexGuard};
 use rusqlite::OptionalExtension as _;
-
-pub use open::{open, Config, ConfigTy, DatabaseEmpty, DatabaseOpen};
-
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+pub use backup::{BackupError, BackupProgress};
+pub use bulk_load::{BeginBulkLoadError, BulkLoadGuard};
+pub use checkpoint::{Checkpoint, CheckpointFromJsonError, InitializeFromCheckpointError};
+pub use dump::{ExportBlocksError, ImportBlocksError};
+pub use events::{DatabaseEventReceiver, Event};
+pub use maintenance::{Budget, MaintenanceProgress};
+pub use metrics::{OperationCallback, OperationKind, OperationMetrics, StatementCacheStats};
+pub use open::{
+    open, CommitPolicy, CompressionConfig, Config, ConfigTy, DatabaseEmpty, DatabaseOpen,
+    InvalidRow, OpenError, OpenProgressCallback, OpenProgressStep, OpenReport,
+};
+pub use query::{QueryError, QUERY_MAX_ROWS, QUERY_TIMEOUT};
+pub use raw_state::{ExportStateError, InitializeFromRawStateError, RawStateProgress};
+pub use resume::ResumeState;
+pub use salvage::{salvage, SalvageError, SalvageReport};
+pub use snapshot::ReadSnapshot;
+pub use storage_diffs::InsertStorageDiffError;
+pub use storage_iter::{PrefixEntries, StorageIter};
+
+pub mod async_wrapper;
+pub mod consistency;
+pub mod import;
+
+mod backup;
+mod bulk_load;
+mod checkpoint;
+mod dump;
+mod events;
+mod fixtures;
+mod maintenance;
+mod metrics;
 mod open;
+mod query;
+mod raw_state;
+mod resume;
+mod salvage;
+mod snapshot;
+mod storage_diffs;
+mod storage_iter;
 mod tests;
 
+/// Maximum number of blocks that [`SqliteFullDatabase::blocks_by_hash_prefix`] can return,
+/// regardless of the limit passed to it.
+pub const HASH_PREFIX_MAX_RESULTS: usize = 1000;
+
+/// Maximum number of nodes between the root of a trie and any of its nodes, including the nodes
+/// of the child tries.
+///
+/// Legitimate tries are much shallower than this, as every node is at least one nibble of key
+/// deeper than its parent and nodes with a single child are merged with it. A trie deeper than
+/// this is the consequence of a loop between trie nodes in the database, and traversing it
+/// returns [`CorruptedError::TrieLoopDetected`].
+const MAX_TRIE_DEPTH: usize = 1024;
+
+/// Prefix of the keys of the main trie that contain the root of a default child trie.
+const CHILD_STORAGE_DEFAULT_PREFIX: &[u8] = b":child_storage:default:";
+
+/// Consensus engine id of GrandPa justifications.
+const GRANDPA_ENGINE_ID: [u8; 4] = *b"FRNK";
+
+// The statements below are executed by frequently-called functions, and the tests verify that
+// the query planner of SQLite executes them using the indices of the database.
+
+/// Statement of [`SqliteFullDatabase::best_chain_bodies_in_range`].
+const BEST_CHAIN_BODIES_IN_RANGE_QUERY: &str = r#"
+    SELECT blocks.number, blocks.hash, blocks_body.idx, extrinsics.bytes
+    FROM blocks
+    JOIN blocks_body ON blocks_body.hash = blocks.hash
+    JOIN extrinsics ON extrinsics.hash = blocks_body.extrinsic_hash
+    WHERE blocks.chain_id = (SELECT id FROM current_chain) AND blocks.is_best_chain = TRUE
+        AND blocks.number >= ? AND blocks.number <= ?
+    ORDER BY blocks.number ASC, blocks_body.idx ASC
+"#;
+
+/// Statement of [`SqliteFullDatabase::purge_finality_orphans`].
+const FINALITY_ORPHANS_QUERY: &str = r#"SELECT hash FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number <= ? AND is_best_chain = FALSE ORDER BY number DESC LIMIT ?"#;
+
+/// Statement of [`block_parent`].
+const BLOCK_PARENT_QUERY: &str = r#"SELECT parent_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#;
+
+/// Statement of [`best_block_hash_by_number`].
+const BEST_BLOCK_HASH_BY_NUMBER_QUERY: &str = r#"SELECT hash FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number = ? AND is_best_chain = TRUE"#;
+
+/// Statement of [`blocks_by_number`].
+const BLOCKS_BY_NUMBER_QUERY: &str = r#"SELECT hash, is_best_chain FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number = ? ORDER BY is_best_chain DESC, hash ASC"#;
+
+/// Temporary table filled by [`PURGED_TRIE_NODES_QUERY`].
+const PURGED_TRIE_NODE_TABLE: &str =
+    "CREATE TEMP TABLE IF NOT EXISTS purged_trie_node(hash BLOB NOT NULL PRIMARY KEY)";
+
+/// Statement of [`purge_block_storage`] that determines the trie nodes to remove.
+///
+/// `CROSS JOIN` forces SQLite to look up each candidate in `trie_node`, rather than scan
+/// `trie_node` in its entirety.
+const PURGED_TRIE_NODES_QUERY: &str = r#"
+    WITH RECURSIVE
+        nibble(num) AS (
+            VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+                (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+        ),
+        candidates(node_hash) AS (
+            SELECT hash FROM trie_node WHERE hash = :state_trie_root_hash
+            UNION
+            SELECT child_merkle_value(trie_node.children, nibble.num)
+                FROM candidates
+                JOIN trie_node ON trie_node.hash = candidates.node_hash
+                JOIN nibble
+                WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL
+            UNION
+            SELECT trie_node_storage.trie_root_ref
+                FROM candidates
+                JOIN trie_node_storage ON trie_node_storage.node_hash = candidates.node_hash
+                WHERE trie_node_storage.trie_root_ref IS NOT NULL
+        ),
+        kept(node_hash) AS (
+            SELECT candidates.node_hash
+                FROM candidates
+                WHERE EXISTS (
+                        SELECT 1 FROM blocks
+                        WHERE blocks.state_trie_root_hash = candidates.node_hash
+                    )
+                    OR EXISTS (
+                        SELECT 1 FROM trie_node_parent
+                        WHERE trie_node_parent.child_hash = candidates.node_hash
+                            AND trie_node_parent.hash NOT IN candidates
+                    )
+                    OR EXISTS (
+                        SELECT 1 FROM trie_node_storage
+                        WHERE trie_node_storage.trie_root_ref = candidates.node_hash
+                            AND trie_node_storage.node_hash NOT IN candidates
+                    )
+            UNION
+            SELECT child_merkle_value(trie_node.children, nibble.num)
+                FROM kept
+                JOIN trie_node ON trie_node.hash = kept.node_hash
+                JOIN nibble
+                WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL
+            UNION
+            SELECT trie_node_storage.trie_root_ref
+                FROM kept
+                JOIN trie_node_storage ON trie_node_storage.node_hash = kept.node_hash
+                WHERE trie_node_storage.trie_root_ref IS NOT NULL
+        )
+    INSERT OR IGNORE INTO purged_trie_node(hash)
+        SELECT trie_node.hash
+        FROM candidates
+        CROSS JOIN trie_node ON trie_node.hash = candidates.node_hash
+        WHERE candidates.node_hash NOT IN (SELECT node_hash FROM kept)
+"#;
+
 /// Returns an opaque string representing the version number of the SQLite library this binary
 /// is using.
 pub fn sqlite_version() -> &'static str {
     rusqlite::version()
 }
 
+/// Returns information about how the SQLite library this binary is using has been built.
+///
+/// This is meant to be included in bug reports, as the performance of some queries depends on
+/// the options SQLite has been compiled with.
+pub fn sqlite_build_info() -> Result<SqliteBuildInfo, InternalError> {
+    // Compile options and limits can only be queried through a connection.
+    let connection = rusqlite::Connection::open_in_memory()
+        .map_err(|err| InternalError::new("sqlite_build_info", err))?;
+
+    let compile_options = connection
+        .prepare("PRAGMA compile_options")
+        .map_err(|err| InternalError::new("sqlite_build_info", err))?
+        .query_map((), |row| row.get::<_, String>(0))
+        .map_err(|err| InternalError::new("sqlite_build_info", err))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| InternalError::new("sqlite_build_info", err))?;
+
+    let limit = |limit| u32::try_from(connection.limit(limit)).unwrap_or(0);
+
+    Ok(SqliteBuildInfo {
+        version: rusqlite::version(),
+        compile_options,
+        max_length: limit(rusqlite::limits::Limit::SQLITE_LIMIT_LENGTH),
+        max_sql_length: limit(rusqlite::limits::Limit::SQLITE_LIMIT_SQL_LENGTH),
+        max_expr_depth: limit(rusqlite::limits::Limit::SQLITE_LIMIT_EXPR_DEPTH),
+    })
+}
+
+/// See [`sqlite_build_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqliteBuildInfo {
+    /// Same value as returned by [`sqlite_version`].
+    pub version: &'static str,
+
+    /// Options SQLite has been compiled with, as returned by `PRAGMA compile_options`. For
+    /// example `ENABLE_STAT4` or `MAX_EXPR_DEPTH=1000`.
+    pub compile_options: Vec<String>,
+
+    /// Maximum size, in bytes, of a string or blob, including the ones built by the queries
+    /// while they are being executed.
+    pub max_length: u32,
+
+    /// Maximum size, in bytes, of the text of an SQL statement.
+    pub max_sql_length: u32,
+
+    /// Maximum depth of the parse tree of an expression, which bounds how deeply expressions
+    /// and sub-queries can be nested. `0` means that there is no limit.
+    pub max_expr_depth: u32,
+}
+
 /// An open database. Holds file descriptors.
 pub struct SqliteFullDatabase {
     /// The SQLite connection.
     ///
-    /// The database is constantly within a transaction.
-    /// When the database is opened, `BEGIN TRANSACTION` is immediately run. We periodically
-    /// call `COMMIT; BEGIN_TRANSACTION` when deemed necessary. `COMMIT` is basically the
-    /// equivalent of `fsync`, and must be called carefully in order to not lose too much speed.
+    /// Modifications are performed within a savepoint of a transaction that stays open until
+    /// it is committed according to [`SqliteFullDatabase::commit_policy`]. `COMMIT` is basically
+    /// the equivalent of `fsync`, and must be called carefully in order to not lose too much
+    /// speed.
     database: Mutex<rusqlite::Connection>,
 
+    /// See [`Config::commit_policy`].
+    commit_policy: CommitPolicy,
+
+    /// See [`Config::body_retention`].
+    body_retention: Option<u64>,
+
+    /// See [`Config::storage_diff_retention`].
+    storage_diff_retention: Option<u64>,
+
+    /// See [`Config::store_events`].
+    store_events: bool,
+
+    /// See [`Config::on_operation`].
+    on_operation: Option<OperationCallback>,
+
+    /// See [`Config::auto_optimize_interval`].
+    auto_optimize_interval: Option<Duration>,
+
+    /// See [`Config::value_compression`].
+    value_compression: Option<CompressionConfig>,
+
+    /// See [`Config::statement_cache_capacity`].
+    statement_cache_capacity: usize,
+
+    /// Moment when `PRAGMA optimize` has last been run, or when the database has been opened.
+    ///
+    /// Must only be modified while [`SqliteFullDatabase::database`] is locked.
+    last_optimize: Mutex<Instant>,
+
+    /// `true` if a large number of rows have been inserted since the last call to
+    /// [`SqliteFullDatabase::maintain`], in which case the statistics used by the query planner
+    /// should be entirely rebuilt.
+    analyze_pending: AtomicBool,
+
+    /// `true` if a [`BulkLoadGuard`] is alive. See [`SqliteFullDatabase::begin_bulk_load`].
+    bulk_load_active: AtomicBool,
+
+    /// Modifications that have been performed on [`SqliteFullDatabase::database`] but not
+    /// committed yet. `None` if there isn't any.
+    ///
+    /// Must only be modified while [`SqliteFullDatabase::database`] is locked.
+    uncommitted_writes: Mutex<Option<UncommittedWrites>>,
+
+    /// See [`SqliteFullDatabase::statement_cache_stats`]. Shared between all the connections.
+    statement_cache_stats: metrics::StatementCacheStatsMap,
+
+    /// Read-only connections to the same database, used by the methods that don't modify the
+    /// database. Empty for in-memory databases. See [`Config::read_connections`].
+    readers: Vec<Mutex<rusqlite::Connection>>,
+
+    /// Index within [`SqliteFullDatabase::readers`] of the next connection to wait for if all
+    /// of them are in use.
+    next_reader: AtomicUsize,
+
+    /// Path to the database file. `None` for in-memory databases.
+    path: Option<PathBuf>,
+
+    /// See [`Config::chain_name`].
+    chain_name: Option<String>,
+
     /// Number of bytes used to encode the block number.
     block_number_bytes: usize,
+
+    /// Receivers of the events returned by [`SqliteFullDatabase::subscribe`].
+    subscribers: events::Subscribers,
+
+    /// Handles that interrupt the queries of [`SqliteFullDatabase::database`] and of
+    /// [`SqliteFullDatabase::readers`]. Shared with the [`InterruptHandle`]s.
+    interrupt_handles: Arc<[rusqlite::InterruptHandle]>,
+
+    /// File holding the advisory lock of the database. The lock is released when the file is
+    /// closed. `None` for in-memory databases. See [`Config::allow_shared_readers`].
+    // Never read, but must be kept alive for as long as the database is open.
+    #[allow(dead_code)]
+    lock_file: Option<std::fs::File>,
+
+    /// See [`SqliteFullDatabase::open_report`].
+    open_report: OpenReport,
 }
 
 impl SqliteFullDatabase {
-    /// Returns the hash of the block in the database whose storage is currently accessible.
-    pub fn best_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
+    /// Returns a connection that can be used to read from the database.
+    ///
+    /// This is one of the read-only connections if there is any, or the main connection
+    /// otherwise. The main connection is also used if some modifications haven't been
+    /// committed yet, as they aren't visible to the other connections.
+    fn read_connection(&self) -> ReadGuard<'_> {
+        snapshot::assert_no_active_snapshot(self);
+
+        let connection = if self.readers.is_empty() || self.uncommitted_writes.lock().is_some() {
+            self.database.lock()
+        } else if let Some(reader) = self.readers.iter().find_map(|reader| reader.try_lock()) {
+            reader
+        } else {
+            let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+            self.readers[index].lock()
+        };
+
+        ReadGuard {
+            connection,
+            _statement_cache_stats: metrics::StatementCacheStatsGuard::new(
+                &self.statement_cache_stats,
+            ),
+        }
+    }
+
+    /// Starts measuring the duration of an operation. The duration is reported to
+    /// [`Config::on_operation`] when the returned object is destroyed.
+    fn time_operation(&self, kind: OperationKind) -> metrics::OperationTimer<'_> {
+        metrics::OperationTimer::new(self.on_operation.as_ref(), kind)
+    }
+
+    /// Locks the main connection in order to modify the database.
+    ///
+    /// The main connection is within a transaction for as long as the returned object is alive.
+    /// Modifications must be performed within a savepoint, after which [`WriteGuard::finish`]
+    /// must be called.
+    fn lock_write(&self) -> Result<WriteGuard<'_>, CorruptedError> {
+        snapshot::assert_no_active_snapshot(self);
         let connection = self.database.lock();
+        let statement_cache_stats =
+            metrics::StatementCacheStatsGuard::new(&self.statement_cache_stats);
+        self.check_uncommitted_writes(&connection)?;
+        if connection.is_autocommit() {
+            prepare_cached(&connection, "BEGIN IMMEDIATE")
+                .and_then(|mut statement| statement.execute(()))
+                .map_err(sqlite_err("lock_write"))?;
+        }
 
-        let val = meta_get_blob(&connection, "best")?.ok_or(CorruptedError::MissingMetaKey)?;
-        if val.len() == 32 {
-            let mut out = [0; 32];
-            out.copy_from_slice(&val);
-            Ok(out)
-        } else {
-            Err(CorruptedError::InvalidBlockHashLen)
+        Ok(WriteGuard {
+            database: self,
+            connection,
+            _statement_cache_stats: statement_cache_stats,
+        })
+    }
+
+    /// Commits all the modifications that haven't been committed yet because of the
+    /// [`CommitPolicy`].
+    ///
+    /// After this function has returned successfully, all the modifications performed so far
+    /// are guaranteed to survive a crash. This does nothing if there isn't any uncommitted
+    /// modification, which is always the case with [`CommitPolicy::EveryOperation`].
+    pub fn flush(&self) -> Result<(), CorruptedError> {
+        let _timer = self.time_operation(OperationKind::Flush);
+        snapshot::assert_no_active_snapshot(self);
+        let connection = self.database.lock();
+        self.check_uncommitted_writes(&connection)?;
+        if !connection.is_autocommit() {
+            connection
+                .execute_batch("COMMIT")
+                .map_err(sqlite_err("flush"))?;
+        }
+        let events = self.clear_uncommitted_writes();
+        drop(connection);
+        self.emit_events(events);
+        Ok(())
+    }
+
+    /// Returns an error if the modifications that haven't been committed yet have been lost.
+    ///
+    /// SQLite can roll back the entire transaction on its own, for example when the disk is full
+    /// or after an I/O error, in which case the connection is no longer within a transaction.
+    /// The events describing the lost modifications are discarded. Must be called while
+    /// [`SqliteFullDatabase::database`] is locked.
+    fn check_uncommitted_writes(
+        &self,
+        connection: &rusqlite::Connection,
+    ) -> Result<(), CorruptedError> {
+        if !connection.is_autocommit() {
+            return Ok(());
+        }
+
+        match self.uncommitted_writes.lock().take() {
+            Some(writes) => Err(CorruptedError::UncommittedWritesLost {
+                num_blocks: writes.num_blocks,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Marks all the modifications as committed. Must be called after a successful `COMMIT`
+    /// while [`SqliteFullDatabase::database`] is locked.
+    ///
+    /// Returns the events describing the modifications, which must be passed to
+    /// [`SqliteFullDatabase::emit_events`] once the database has been unlocked.
+    fn clear_uncommitted_writes(&self) -> Vec<Event> {
+        self.uncommitted_writes
+            .lock()
+            .take()
+            .map(|writes| writes.events)
+            .unwrap_or_default()
+    }
+
+    /// Sends the given events, returned by [`SqliteFullDatabase::clear_uncommitted_writes`], to
+    /// the subscribers.
+    fn emit_events(&self, events: Vec<Event>) {
+        for event in events {
+            self.subscribers.emit(event);
+        }
+    }
+
+    /// Updates the statistics that SQLite uses in order to decide how to execute queries.
+    ///
+    /// This runs `PRAGMA optimize`, which only analyzes the tables whose statistics are likely
+    /// to be outdated. If a large number of blocks or trie nodes have been imported since the
+    /// previous call, for example with [`SqliteFullDatabase::import_blocks`], all the statistics
+    /// are instead rebuilt with `ANALYZE`, which can take a long time.
+    ///
+    /// The SQLite documentation recommends doing this every couple of hours on databases that
+    /// stay open for a long time. See also [`Config::auto_optimize_interval`].
+    pub fn maintain(&self) -> Result<(), CorruptedError> {
+        let _timer = self.time_operation(OperationKind::Maintain);
+        let database = self.lock_write()?;
+        database.optimize(true)?;
+        database.finish(0)
+    }
+
+    /// Returns the list of rows of the database that refer to a row that doesn't exist, for
+    /// example the body of a block that isn't in the database.
+    ///
+    /// Foreign keys are enforced when the database is modified, and this list is thus normally
+    /// empty. A non-empty list indicates that the database has been modified by something else
+    /// than this code, for example the `sqlite3` CLI, or that it is corrupted.
+    ///
+    /// This runs `PRAGMA foreign_key_check`, which goes through all the tables of the database
+    /// and can thus take a long time.
+    pub fn check_foreign_keys(&self) -> Result<Vec<ForeignKeyViolation>, CorruptedError> {
+        let connection = self.read_connection();
+        let mut statement = connection
+            .prepare("PRAGMA foreign_key_check")
+            .map_err(sqlite_err("check_foreign_keys"))?;
+        let violations = statement
+            .query_map((), |row| {
+                Ok(ForeignKeyViolation {
+                    table: row.get(0)?,
+                    rowid: row.get(1)?,
+                    parent: row.get(2)?,
+                })
+            })
+            .map_err(sqlite_err("check_foreign_keys"))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(sqlite_err("check_foreign_keys"))?;
+        Ok(violations)
+    }
+
+    /// Returns, for each statement that has been executed on the database since it has been
+    /// opened, the number of times it has been found in the cache of prepared statements and
+    /// the number of times it had to be prepared. Intended for debugging purposes.
+    ///
+    /// Each statement has to be prepared at least once per connection. A number of misses that
+    /// keeps increasing indicates that [`Config::statement_cache_capacity`] is too low.
+    ///
+    /// The statements are identified by their SQL text.
+    pub fn statement_cache_stats(&self) -> BTreeMap<String, StatementCacheStats> {
+        self.statement_cache_stats.lock().clone()
+    }
+
+    /// Returns an [`InterruptHandle`] that can be used to abort the operations being performed
+    /// on this database, for example a storage query that is taking too long.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            connections: self.interrupt_handles.clone(),
+        }
+    }
+
+    /// Returns the path of the database file, or `None` if the database is stored in memory.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the name of the chain accessed within the database. See [`Config::chain_name`].
+    pub fn chain_name(&self) -> Option<&str> {
+        self.chain_name.as_deref()
+    }
+
+    /// Returns information about how the database has been opened. See [`DatabaseOpen::report`].
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    /// Returns all the entries of the `meta` table of the chain, ordered by key. Intended for
+    /// debugging purposes, for example in order to find out which consensus-related keys are
+    /// set when a [`CorruptedError::ConsensusAlgorithmMix`] is returned.
+    ///
+    /// Some of the values are large, such as the list of authorities of the current Babe epoch.
+    /// Blob values are consequently truncated to [`MetaEntry::MAX_BLOB_LEN`] bytes, and only
+    /// their length is reported in full.
+    ///
+    /// > **Note**: The keys and their format are an implementation detail and can change at any
+    /// >           moment. Don't rely on them.
+    pub fn meta_entries(&self) -> Result<Vec<MetaEntry>, CorruptedError> {
+        let connection = self.read_connection();
+        let mut statement = prepare_cached(
+            &connection,
+            r#"SELECT key, value_number, substr(value_blob, 1, ?), length(value_blob) FROM meta WHERE chain_id = (SELECT id FROM current_chain) ORDER BY key"#,
+        )
+        .map_err(sqlite_err("meta_entries"))?;
+        let rows = statement
+            .query_map((i64::try_from(MetaEntry::MAX_BLOB_LEN).unwrap(),), |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, Option<Vec<u8>>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                ))
+            })
+            .map_err(sqlite_err("meta_entries"))?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (key, value_number, value_blob, value_blob_len) =
+                row.map_err(sqlite_err("meta_entries"))?;
+            entries.push(MetaEntry {
+                key,
+                value_number: value_number
+                    .map(|n| u64::try_from(n).map_err(|_| CorruptedError::InvalidNumber))
+                    .transpose()?,
+                value_blob,
+                value_blob_len: value_blob_len
+                    .map(|n| usize::try_from(n).map_err(|_| CorruptedError::InvalidNumber))
+                    .transpose()?,
+            });
         }
+        Ok(entries)
+    }
+
+    /// Returns the hash of the block in the database whose storage is currently accessible.
+    pub fn best_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
+        let connection = self.read_connection();
+        best_block_hash(&connection)
     }
 
     /// Returns the hash of the finalized block in the database.
     pub fn finalized_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
-        let database = self.database.lock();
+        let database = self.read_connection();
         finalized_hash(&database)
     }
 
@@ -133,38 +694,55 @@ impl SqliteFullDatabase {
         &self,
         block_hash: &[u8; 32],
     ) -> Result<Option<Vec<u8>>, CorruptedError> {
-        let connection = self.database.lock();
-
-        let out = connection
-            .prepare_cached(r#"SELECT header FROM blocks WHERE hash = ?"#)
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .query_row((&block_hash[..],), |row| row.get::<_, Vec<u8>>(0))
-            .optional()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
-        Ok(out)
+        let _timer = self.time_operation(OperationKind::BlockHeader);
+        let connection = self.read_connection();
+        block_header(&connection, block_hash)
     }
 
-    /// Returns the hash of the parent of the given block, or `None` if the block is unknown.
+    /// Returns the parent of the given block, or `None` if the block is unknown.
+    ///
+    /// The genesis block doesn't have a parent, in which case [`ParentOf::Genesis`] is returned.
     ///
     /// > **Note**: If this method is called twice times in a row with the same block hash, it
     /// >           is possible for the first time to return `Some` and the second time to return
     /// >           `None`, in case the block has since been removed from the database.
-    pub fn block_parent(&self, block_hash: &[u8; 32]) -> Result<Option<[u8; 32]>, CorruptedError> {
-        let connection = self.database.lock();
+    pub fn block_parent(&self, block_hash: &[u8; 32]) -> Result<Option<ParentOf>, CorruptedError> {
+        let connection = self.read_connection();
+        block_parent(&connection, block_hash)
+    }
 
-        let out = connection
-            .prepare_cached(r#"SELECT parent_hash FROM blocks WHERE hash = ?"#)
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .query_row((&block_hash[..],), |row| row.get::<_, [u8; 32]>(0))
-            .optional()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+    /// Returns the information about the given block, or `None` if the block is unknown.
+    ///
+    /// All the fields of the [`BlockRef`] are obtained at once, and are thus always coherent
+    /// with each other.
+    pub fn block_info(&self, block_hash: &[u8; 32]) -> Result<Option<BlockRef>, CorruptedError> {
+        let connection = self.read_connection();
+        block_info(&connection, block_hash)
+    }
 
-        Ok(out)
+    /// Returns a summary of the consensus-related items found in the digest of the header of
+    /// the given block, or `None` if the block is unknown.
+    ///
+    /// This avoids having to fetch and decode the header of the block when only these items are
+    /// needed.
+    pub fn block_digest_summary(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<DigestSummary>, CorruptedError> {
+        let connection = self.read_connection();
+        let Some(scale_encoded_header) = block_header(&connection, block_hash)? else {
+            return Ok(None);
+        };
+        let decoded = header::decode(&scale_encoded_header, self.block_number_bytes)
+            .map_err(CorruptedError::BlockHeaderCorrupted)?;
+        Ok(Some(DigestSummary::from_digest(decoded.digest)))
     }
 
     /// Returns the list of extrinsics of the given block, or `None` if the block is unknown.
     ///
+    /// Returns [`BlockExtrinsicsError::BodyPruned`] if the block is known but its body has been
+    /// removed from the database. See [`SqliteFullDatabase::prune_bodies_below`].
+    ///
     /// > **Note**: The list of extrinsics of a block is also known as its *body*.
     ///
     /// > **Note**: If this method is called twice times in a row with the same block hash, it
@@ -173,1539 +751,3356 @@ impl SqliteFullDatabase {
     pub fn block_extrinsics(
         &self,
         block_hash: &[u8; 32],
-    ) -> Result<Option<impl ExactSizeIterator<Item = Vec<u8>>>, CorruptedError> {
-        let connection = self.database.lock();
-
-        // TODO: doesn't detect if block is absent
-
-        let result = connection
-            .prepare_cached(r#"SELECT extrinsic FROM blocks_body WHERE hash = ? ORDER BY idx ASC"#)
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .query_map((&block_hash[..],), |row| row.get::<_, Vec<u8>>(0))
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
-        Ok(Some(result.into_iter()))
+    ) -> Result<Option<impl ExactSizeIterator<Item = Vec<u8>>>, BlockExtrinsicsError> {
+        let mut result = Vec::new();
+        let outcome = self.with_block_extrinsics(block_hash, |extrinsic| {
+            result.push(extrinsic.to_vec());
+            ops::ControlFlow::Continue(())
+        })?;
+        Ok(outcome.map(|()| result.into_iter()))
     }
 
-    /// Returns the hashes of the blocks given a block number.
-    pub fn block_hash_by_number(
+    /// Calls `on_extrinsic` with each extrinsic of the given block, in order, without copying
+    /// the entire list of extrinsics in memory first. Returns `None` if the block is unknown.
+    ///
+    /// Iterating stops early if `on_extrinsic` returns [`ops::ControlFlow::Break`].
+    ///
+    /// The database connection being used is locked for the entire duration of this call, and
+    /// `on_extrinsic` must thus not access the database.
+    ///
+    /// > **Note**: The same remark as [`SqliteFullDatabase::block_extrinsics`] applies.
+    pub fn with_block_extrinsics(
         &self,
-        block_number: u64,
-    ) -> Result<impl ExactSizeIterator<Item = [u8; 32]>, CorruptedError> {
-        let connection = self.database.lock();
-        let result = block_hashes_by_number(&connection, block_number)?;
-        Ok(result.into_iter())
+        block_hash: &[u8; 32],
+        on_extrinsic: impl FnMut(&[u8]) -> ops::ControlFlow<()>,
+    ) -> Result<Option<()>, BlockExtrinsicsError> {
+        let _timer = self.time_operation(OperationKind::BlockExtrinsics);
+        let mut connection = self.read_connection();
+
+        // The existence of the block and its body are read within the same transaction in
+        // order to not report an empty body for a block that has just been removed or whose
+        // body has just been pruned.
+        let transaction = connection
+            .savepoint()
+            .map_err(sqlite_err("with_block_extrinsics"))?;
+
+        with_block_extrinsics(&transaction, block_hash, on_extrinsic)
     }
 
-    /// Returns the hash of the block of the best chain given a block number.
-    pub fn best_block_hash_by_number(
+    /// Calls `on_extrinsic` with the number, hash, index within the body, and content of each
+    /// extrinsic of the blocks of the best chain whose number is within `range`. Extrinsics are
+    /// ordered by increasing block number, then by increasing index within the body.
+    ///
+    /// Blocks whose body has been removed from the database (see
+    /// [`SqliteFullDatabase::prune_bodies_below`]) are silently skipped, exactly like blocks
+    /// with an empty body.
+    ///
+    /// Iterating stops early if `on_extrinsic` returns [`ops::ControlFlow::Break`].
+    ///
+    /// All the extrinsics are read with a single query. The database connection being used is
+    /// locked for the entire duration of this call, and `on_extrinsic` must thus not access the
+    /// database.
+    pub fn best_chain_bodies_in_range(
         &self,
-        block_number: u64,
-    ) -> Result<Option<[u8; 32]>, CorruptedError> {
-        let connection = self.database.lock();
+        range: ops::RangeInclusive<u64>,
+        mut on_extrinsic: impl FnMut(u64, [u8; 32], u32, &[u8]) -> ops::ControlFlow<()>,
+    ) -> Result<(), CorruptedError> {
+        let _timer = self.time_operation(OperationKind::BlockExtrinsics);
 
-        let block_number = match i64::try_from(block_number) {
-            Ok(n) => n,
-            Err(_) => return Ok(None),
+        // Block numbers are stored as signed integers, and no block can have a number above
+        // `i64::MAX`.
+        let Ok(start) = i64::try_from(*range.start()) else {
+            return Ok(());
         };
+        let end = i64::try_from(*range.end()).unwrap_or(i64::MAX);
+
+        let connection = self.read_connection();
+        let mut statement = prepare_cached(&connection, BEST_CHAIN_BODIES_IN_RANGE_QUERY)
+            .map_err(sqlite_err("best_chain_bodies_in_range"))?;
+        let mut rows = statement
+            .query((start, end))
+            .map_err(sqlite_err("best_chain_bodies_in_range"))?;
+
+        while let Some(row) = rows
+            .next()
+            .map_err(sqlite_err("best_chain_bodies_in_range"))?
+        {
+            let number = u64::try_from(
+                row.get::<_, i64>(0)
+                    .map_err(sqlite_err("best_chain_bodies_in_range"))?,
+            )
+            .map_err(|_| CorruptedError::InvalidNumber)?;
+            let hash = <[u8; 32]>::try_from(
+                row.get_ref(1)
+                    .and_then(|value| Ok(value.as_blob()?))
+                    .map_err(sqlite_err("best_chain_bodies_in_range"))?,
+            )
+            .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
+            let index = u32::try_from(
+                row.get::<_, i64>(2)
+                    .map_err(sqlite_err("best_chain_bodies_in_range"))?,
+            )
+            .map_err(|_| CorruptedError::InvalidNumber)?;
+            let extrinsic = row
+                .get_ref(3)
+                .and_then(|value| Ok(value.as_blob()?))
+                .map_err(sqlite_err("best_chain_bodies_in_range"))?;
+
+            if on_extrinsic(number, hash, index, extrinsic).is_break() {
+                break;
+            }
+        }
 
-        let result = connection
-            .prepare_cached(r#"SELECT hash FROM blocks WHERE number = ? AND is_best_chain = TRUE"#)
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .query_row((block_number,), |row| row.get::<_, Vec<u8>>(0))
-            .optional()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))
-            .and_then(|value| {
-                let Some(value) = value else { return Ok(None) };
-                Ok(Some(
-                    <[u8; 32]>::try_from(&value[..])
-                        .map_err(|_| CorruptedError::InvalidBlockHashLen)?,
-                ))
-            })?;
+        Ok(())
+    }
+
+    /// Returns the metadata passed to [`SqliteFullDatabase::insert`] when the given block was
+    /// inserted.
+    ///
+    /// Returns `None` if the block is unknown or if no metadata was passed when inserting it.
+    pub fn block_insert_metadata(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<InsertMeta>, CorruptedError> {
+        let connection = self.read_connection();
+        let meta = prepare_cached(
+            &connection,
+            r#"SELECT inserted_at_unix_ms, source FROM blocks_meta WHERE hash = ?"#,
+        )
+        .map_err(sqlite_err("block_insert_metadata"))?
+        .query_row((&block_hash[..],), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })
+        .optional()
+        .map_err(sqlite_err("block_insert_metadata"))?;
+
+        let Some((inserted_at_unix_ms, source)) = meta else {
+            return Ok(None);
+        };
 
-        Ok(result)
+        Ok(Some(InsertMeta {
+            inserted_at_unix_ms: u64::try_from(inserted_at_unix_ms)
+                .map_err(|_| CorruptedError::InvalidNumber)?,
+            source,
+        }))
     }
 
-    /// Returns a [`chain_information::ChainInformation`] struct containing the information about
-    /// the current finalized state of the chain.
+    /// Returns the specification version of the runtime of the given block, as passed to
+    /// [`SqliteFullDatabase::insert`].
     ///
-    /// This method is relatively expensive and should preferably not be called repeatedly.
+    /// Returns `None` if the block is unknown or if its specification version hasn't been
+    /// provided when it was inserted.
+    pub fn runtime_spec_version_at(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<u32>, CorruptedError> {
+        let connection = self.read_connection();
+        let spec_version = prepare_cached(
+            &connection,
+            r#"SELECT runtime_spec_version FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+        )
+        .map_err(sqlite_err("runtime_spec_version_at"))?
+        .query_row((&block_hash[..],), |row| row.get::<_, Option<i64>>(0))
+        .optional()
+        .map_err(sqlite_err("runtime_spec_version_at"))?
+        .flatten();
+
+        spec_version
+            .map(|v| u32::try_from(v).map_err(|_| CorruptedError::InvalidNumber))
+            .transpose()
+    }
+
+    /// Stores in the database the given runtime, so that it can later be retrieved with
+    /// [`SqliteFullDatabase::runtime_by_spec_version`] without having to read the storage of a
+    /// block.
     ///
-    /// In order to avoid race conditions, the known finalized block hash must be passed as
-    /// parameter. If the finalized block in the database doesn't match the hash passed as
-    /// parameter, most likely because it has been updated in a parallel thread, a
-    /// [`StorageAccessError::IncompleteStorage`] error is returned.
-    // TODO: an IncompleteStorage error doesn't seem appropriate; also, why is it even a problem given that the chain information contains the finalized block anyway
-    pub fn to_chain_information(
+    /// Overwrites the runtime previously stored with the same specification version, if any.
+    pub fn store_runtime(
         &self,
-        finalized_block_hash: &[u8; 32],
-    ) -> Result<chain_information::ValidChainInformation, StorageAccessError> {
-        let connection = self.database.lock();
-        if finalized_hash(&connection)? != *finalized_block_hash {
-            return Err(StorageAccessError::IncompleteStorage);
-        }
+        spec_version: u32,
+        code: &[u8],
+        heap_pages: executor::vm::HeapPages,
+    ) -> Result<(), CorruptedError> {
+        let code_hash = blake2_rfc::blake2b::blake2b(32, &[], code);
 
-        let finalized_block_header = block_header(&connection, finalized_block_hash)?
-            .ok_or(CorruptedError::MissingBlockHeader)?;
+        let mut database = self.lock_write()?;
+        let transaction = database.savepoint().map_err(sqlite_err("store_runtime"))?;
+        prepare_cached(
+            &transaction,
+            r#"INSERT OR REPLACE INTO runtimes(chain_id, spec_version, code_hash, code, heap_pages) VALUES ((SELECT id FROM current_chain), ?, ?, ?, ?)"#,
+        )
+        .map_err(sqlite_err("store_runtime"))?
+        .execute((
+            spec_version,
+            code_hash.as_bytes(),
+            code,
+            u32::from(heap_pages),
+        ))
+        .map_err(sqlite_err("store_runtime"))?;
+        transaction.commit().map_err(sqlite_err("store_runtime"))?;
+        database.finish(0)?;
+        Ok(())
+    }
 
-        let finality = match (
-            grandpa_authorities_set_id(&connection)?,
-            grandpa_finalized_triggered_authorities(&connection)?,
-            grandpa_finalized_scheduled_change(&connection)?,
-        ) {
-            (
-                Some(after_finalized_block_authorities_set_id),
-                finalized_triggered_authorities,
-                finalized_scheduled_change,
-            ) => chain_information::ChainInformationFinality::Grandpa {
-                after_finalized_block_authorities_set_id,
-                finalized_triggered_authorities,
-                finalized_scheduled_change,
-            },
-            (None, auth, None) if auth.is_empty() => {
-                chain_information::ChainInformationFinality::Outsourced
-            }
-            _ => {
-                return Err(StorageAccessError::Corrupted(
-                    CorruptedError::ConsensusAlgorithmMix,
-                ))
-            }
-        };
+    /// Returns the runtime stored with [`SqliteFullDatabase::store_runtime`] with the given
+    /// specification version, or `None` if there is none.
+    pub fn runtime_by_spec_version(
+        &self,
+        spec_version: u32,
+    ) -> Result<Option<StoredRuntime>, CorruptedError> {
+        let connection = self.read_connection();
+        let runtime = prepare_cached(
+            &connection,
+            r#"SELECT code_hash, code, heap_pages FROM runtimes WHERE chain_id = (SELECT id FROM current_chain) AND spec_version = ?"#,
+        )
+        .map_err(sqlite_err("runtime_by_spec_version"))?
+        .query_row((spec_version,), |row| {
+            Ok((
+                row.get::<_, Vec<u8>>(0)?,
+                row.get::<_, Vec<u8>>(1)?,
+                row.get::<_, i64>(2)?,
+            ))
+        })
+        .optional()
+        .map_err(sqlite_err("runtime_by_spec_version"))?;
 
-        let consensus = match (
-            meta_get_number(&connection, "aura_slot_duration")?,
-            meta_get_number(&connection, "babe_slots_per_epoch")?,
-            meta_get_blob(&connection, "babe_finalized_next_epoch")?,
-        ) {
-            (None, Some(slots_per_epoch), Some(finalized_next_epoch)) => {
-                let slots_per_epoch = expect_nz_u64(slots_per_epoch)?;
-                let finalized_next_epoch_transition =
-                    Box::new(decode_babe_epoch_information(&finalized_next_epoch)?);
-                let finalized_block_epoch_information =
-                    meta_get_blob(&connection, "babe_finalized_epoch")?
-                        .map(|v| decode_babe_epoch_information(&v))
-                        .transpose()?
-                        .map(Box::new);
-                chain_information::ChainInformationConsensus::Babe {
-                    finalized_block_epoch_information,
-                    finalized_next_epoch_transition,
-                    slots_per_epoch,
-                }
-            }
-            (Some(slot_duration), None, None) => {
-                let slot_duration = expect_nz_u64(slot_duration)?;
-                let finalized_authorities_list = aura_finalized_authorities(&connection)?;
-                chain_information::ChainInformationConsensus::Aura {
-                    finalized_authorities_list,
-                    slot_duration,
-                }
-            }
-            (None, None, None) => chain_information::ChainInformationConsensus::Unknown,
-            _ => {
-                return Err(StorageAccessError::Corrupted(
-                    CorruptedError::ConsensusAlgorithmMix,
-                ))
-            }
+        let Some((code_hash, code, heap_pages)) = runtime else {
+            return Ok(None);
         };
 
-        match chain_information::ValidChainInformation::try_from(
-            chain_information::ChainInformation {
-                finalized_block_header: {
-                    let header = header::decode(&finalized_block_header, self.block_number_bytes)
-                        .map_err(CorruptedError::BlockHeaderCorrupted)
-                        .map_err(StorageAccessError::Corrupted)?;
-                    Box::new(header.into())
-                },
-                consensus,
-                finality,
-            },
-        ) {
-            Ok(ci) => Ok(ci),
-            Err(err) => Err(StorageAccessError::Corrupted(
-                CorruptedError::InvalidChainInformation(err),
-            )),
-        }
+        Ok(Some(StoredRuntime {
+            code_hash: <[u8; 32]>::try_from(&code_hash[..])
+                .map_err(|_| CorruptedError::InvalidRuntimeCodeHashLen)?,
+            code,
+            heap_pages: executor::vm::HeapPages::new(
+                u32::try_from(heap_pages).map_err(|_| CorruptedError::InvalidNumber)?,
+            ),
+        }))
     }
 
-    /// Insert a new block in the database.
-    ///
-    /// Must pass the header and body of the block.
-    ///
-    /// Blocks must be inserted in the correct order. An error is returned if the parent of the
-    /// newly-inserted block isn't present in the database.
+    /// Stores the SCALE-encoded list of events emitted by the given block, overwriting the
+    /// events previously stored for this block, if any.
     ///
-    /// > **Note**: It is not necessary for the newly-inserted block to be a descendant of the
-    /// >           finalized block, unless `is_new_best` is true.
+    /// Does nothing if [`Config::store_events`] is `false`.
     ///
-    pub fn insert<'a>(
+    /// The events are removed from the database at the same time as the body of the block.
+    /// See [`SqliteFullDatabase::prune_bodies_below`].
+    pub fn set_block_events(
         &self,
-        scale_encoded_header: &[u8],
-        is_new_best: bool,
-        body: impl ExactSizeIterator<Item = impl AsRef<[u8]>>,
-    ) -> Result<(), InsertError> {
-        // Calculate the hash of the new best block.
-        let block_hash = header::hash_from_scale_encoded_header(scale_encoded_header);
-
-        // Decode the header, as we will need various information from it.
-        // TODO: this module shouldn't decode headers
-        let header = header::decode(scale_encoded_header, self.block_number_bytes)
-            .map_err(InsertError::BadHeader)?;
-
-        // Locking is performed as late as possible.
-        let mut database = self.database.lock();
-
-        // Start a transaction to insert everything at once.
-        let transaction = database
-            .transaction()
-            .map_err(|err| InsertError::Corrupted(CorruptedError::Internal(InternalError(err))))?;
-
-        // Make sure that the block to insert isn't already in the database.
-        if has_block(&transaction, &block_hash)? {
-            return Err(InsertError::Duplicate);
-        }
-
-        // Make sure that the parent of the block to insert is in the database.
-        if !has_block(&transaction, header.parent_hash)? {
-            return Err(InsertError::MissingParent);
+        block_hash: &[u8; 32],
+        scale_encoded_events: &[u8],
+    ) -> Result<(), SetBlockEventsError> {
+        if !self.store_events {
+            return Ok(());
         }
 
-        transaction
-            .prepare_cached(
-                "INSERT INTO blocks(number, hash, parent_hash, state_trie_root_hash, header, is_best_chain, justification) VALUES (?, ?, ?, ?, ?, FALSE, NULL)",
-            )
-            .unwrap()
-            .execute((
-                i64::try_from(header.number).unwrap(),
-                &block_hash[..],
-                &header.parent_hash[..],
-                &header.state_root[..],
-                scale_encoded_header
-            ))
-            .unwrap();
+        let mut database = self.lock_write()?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("set_block_events"))?;
 
-        {
-            let mut statement = transaction
-                .prepare_cached("INSERT INTO blocks_body(hash, idx, extrinsic) VALUES (?, ?, ?)")
-                .unwrap();
-            for (index, item) in body.enumerate() {
-                statement
-                    .execute((
-                        &block_hash[..],
-                        i64::try_from(index).unwrap(),
-                        item.as_ref(),
-                    ))
-                    .unwrap();
-            }
+        if !has_block(&transaction, block_hash)? {
+            return Err(SetBlockEventsError::UnknownBlock);
         }
 
-        // Change the best chain to be the new block.
-        if is_new_best {
-            // It would be illegal to change the best chain to not overlay with the
-            // finalized chain.
-            if header.number <= finalized_num(&transaction)? {
-                return Err(InsertError::BestNotInFinalizedChain);
-            }
-
-            set_best_chain(&transaction, &block_hash)?;
-        }
+        prepare_cached(
+            &transaction,
+            r#"INSERT OR REPLACE INTO blocks_events(hash, events) VALUES (?, ?)"#,
+        )
+        .map_err(sqlite_err("set_block_events"))?
+        .execute((&block_hash[..], scale_encoded_events))
+        .map_err(sqlite_err("set_block_events"))?;
 
-        // If everything is successful, we commit.
         transaction
             .commit()
-            .map_err(|err| InsertError::Corrupted(CorruptedError::Internal(InternalError(err))))?;
-
+            .map_err(sqlite_err("set_block_events"))?;
+        database.finish(0)?;
         Ok(())
     }
 
-    // TODO: needs documentation
-    // TODO: should we refuse inserting disjoint storage nodes?
-    pub fn insert_trie_nodes<'a>(
-        &self,
-        new_trie_nodes: impl Iterator<Item = InsertTrieNode<'a>>,
-        trie_entries_version: u8,
-    ) -> Result<(), CorruptedError> {
-        let mut database = self.database.lock();
+    /// Returns the SCALE-encoded list of events stored with
+    /// [`SqliteFullDatabase::set_block_events`].
+    ///
+    /// Returns `None` if the block is unknown or if no events are stored for this block.
+    pub fn block_events(&self, block_hash: &[u8; 32]) -> Result<Option<Vec<u8>>, CorruptedError> {
+        let connection = self.read_connection();
+        let events = prepare_cached(
+            &connection,
+            r#"SELECT events FROM blocks_events WHERE hash = ?"#,
+        )
+        .map_err(sqlite_err("block_events"))?
+        .query_row((&block_hash[..],), |row| row.get::<_, Vec<u8>>(0))
+        .optional()
+        .map_err(sqlite_err("block_events"))?;
+        Ok(events)
+    }
 
+    /// Stores a justification of the given block, replacing the justification of the same
+    /// consensus engine that might already be stored.
+    ///
+    /// `engine_id` is the identifier of the consensus engine that the justification belongs
+    /// to, for example `FRNK` for GrandPa.
+    pub fn set_block_justification(
+        &self,
+        block_hash: &[u8; 32],
+        engine_id: &[u8; 4],
+        justification: &[u8],
+    ) -> Result<(), SetBlockJustificationError> {
+        let mut database = self.lock_write()?;
         let transaction = database
-            .transaction()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+            .savepoint()
+            .map_err(sqlite_err("set_block_justification"))?;
 
-        {
-            // TODO: should check whether the existing merkle values that are referenced from inserted nodes exist in the parent's storage
-            // TODO: is it correct to have OR IGNORE everywhere?
-            let mut insert_node_statement = transaction
-                .prepare_cached("INSERT OR IGNORE INTO trie_node(hash, partial_key) VALUES(?, ?)")
-                .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-            let mut insert_node_storage_statement = transaction
-                .prepare_cached("INSERT OR IGNORE INTO trie_node_storage(node_hash, value, trie_root_ref, trie_entry_version) VALUES(?, ?, ?, ?)")
-                .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-            let mut insert_child_statement = transaction
-                .prepare_cached(
-                    "INSERT OR IGNORE INTO trie_node_child(hash, child_num, child_hash) VALUES(?, ?, ?)",
-                )
-                .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-            // TODO: if the iterator's `next()` function accesses the database, we deadlock
-            for trie_node in new_trie_nodes {
-                assert!(trie_node.partial_key_nibbles.iter().all(|n| *n < 16)); // TODO: document
-                insert_node_statement
-                    .execute((&trie_node.merkle_value, trie_node.partial_key_nibbles))
-                    .map_err(|err: rusqlite::Error| CorruptedError::Internal(InternalError(err)))?;
-                match trie_node.storage_value {
-                    InsertTrieNodeStorageValue::Value {
-                        value,
-                        references_merkle_value,
-                    } => {
-                        insert_node_storage_statement
-                            .execute((
-                                &trie_node.merkle_value,
-                                if !references_merkle_value {
-                                    Some(&value)
-                                } else {
-                                    None
-                                },
-                                if references_merkle_value {
-                                    Some(&value)
-                                } else {
-                                    None
-                                },
-                                trie_entries_version,
-                            ))
-                            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-                    }
-                    InsertTrieNodeStorageValue::NoValue => {}
-                }
-                for (child_num, child) in trie_node.children_merkle_values.iter().enumerate() {
-                    if let Some(child) = child {
-                        let child_num =
-                            vec![u8::try_from(child_num).unwrap_or_else(|_| unreachable!())];
-                        insert_child_statement
-                            .execute((&trie_node.merkle_value, child_num, child))
-                            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-                    }
-                }
-            }
+        if !has_block(&transaction, block_hash)? {
+            return Err(SetBlockJustificationError::UnknownBlock);
         }
 
+        set_block_justification(&transaction, block_hash, engine_id, Some(justification))?;
+
         transaction
             .commit()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
+            .map_err(sqlite_err("set_block_justification"))?;
+        database.finish(0)?;
         Ok(())
     }
 
-    /// Returns a list of trie nodes that are missing from the database and that belong to the
-    /// state of a block whose number is superior or equal to the finalized block.
-    ///
-    /// The ordering of the returned trie nodes is unspecified.
+    /// Returns the GrandPa justification of the given block.
     ///
-    /// > **Note**: This function call is relatively expensive, and the API user is expected to
-    /// >           cache the return value.
-    pub fn finalized_and_above_missing_trie_nodes_unordered(
+    /// Returns `None` if the block is unknown or if no GrandPa justification is stored for this
+    /// block. See also [`SqliteFullDatabase::block_justifications`].
+    pub fn block_justification(
         &self,
-    ) -> Result<Vec<MissingTrieNode>, CorruptedError> {
-        let database = self.database.lock();
+        block_hash: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, CorruptedError> {
+        let connection = self.read_connection();
+        block_justification(&connection, block_hash)
+    }
 
-        let mut statement = database
-            .prepare_cached(
-                r#"
-            WITH RECURSIVE
-                -- List of all block hashes that are equal to the finalized block or above.
-                finalized_and_above_blocks(block_hash) AS (
-                    SELECT blocks.hash
-                    FROM blocks
-                    JOIN meta ON meta.key = "finalized"
-                    WHERE blocks.number >= meta.value_number
-                ),
+    /// Returns all the justifications of the given block, with the identifier of the consensus
+    /// engine they belong to, ordered by engine identifier.
+    ///
+    /// Returns an empty list if the block is unknown or doesn't have any justification.
+    pub fn block_justifications(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Justifications, CorruptedError> {
+        let connection = self.read_connection();
+        block_justifications(&connection, block_hash)
+    }
 
-                -- List of all trie nodes for these blocks.
-                trie_nodes(block_hash, node_hash, node_key, is_present) AS (
-                    SELECT  blocks.hash, blocks.state_trie_root_hash,
-                            CASE WHEN trie_node.partial_key IS NULL THEN X'' ELSE trie_node.partial_key END,
-                            trie_node.hash IS NOT NULL
-                        FROM blocks
-                        JOIN finalized_and_above_blocks
-                            ON blocks.hash = finalized_and_above_blocks.block_hash
-                        LEFT JOIN trie_node
-                            ON trie_node.hash = blocks.state_trie_root_hash
+    /// Returns the header, the body, and the justifications of the given block, or `None` if
+    /// the block is unknown.
+    ///
+    /// Contrary to calling [`SqliteFullDatabase::block_scale_encoded_header`],
+    /// [`SqliteFullDatabase::block_extrinsics`], and
+    /// [`SqliteFullDatabase::block_justifications`] one after the other, all the fields of the
+    /// [`FullBlock`] are read within the same transaction and are thus always coherent with each
+    /// other, even if the block is concurrently removed from the database or its body pruned.
+    pub fn full_block(&self, block_hash: &[u8; 32]) -> Result<Option<FullBlock>, CorruptedError> {
+        let _timer = self.time_operation(OperationKind::FullBlock);
+        let mut connection = self.read_connection();
+        let transaction = connection.savepoint().map_err(sqlite_err("full_block"))?;
+        full_block(&transaction, block_hash)
+    }
 
-                    UNION ALL
-                    SELECT  trie_nodes.block_hash, trie_node_child.child_hash,
-                            CASE WHEN trie_node.hash IS NULL THEN CAST(trie_nodes.node_key || trie_node_child.child_num AS BLOB)
-                            ELSE CAST(trie_nodes.node_key || trie_node_child.child_num || trie_node.partial_key AS BLOB) END,
-                            trie_node.hash IS NOT NULL
-                        FROM trie_nodes
-                        JOIN trie_node_child
-                            ON trie_nodes.node_hash = trie_node_child.hash
-                        LEFT JOIN trie_node
-                            ON trie_node.hash = trie_node_child.child_hash
-                        WHERE trie_nodes.is_present
+    /// Returns the hashes of the blocks given a block number.
+    ///
+    /// The hashes are in the same order as [`SqliteFullDatabase::blocks_by_number`]. In other
+    /// words, the block of the best chain, if any, comes first.
+    pub fn block_hash_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<impl ExactSizeIterator<Item = [u8; 32]>, CorruptedError> {
+        Ok(self
+            .blocks_by_number(block_number)?
+            .into_iter()
+            .map(|block| block.hash))
+    }
 
-                    UNION ALL
-                    SELECT  trie_nodes.block_hash, trie_node_storage.trie_root_ref,
-                            CASE WHEN trie_node.hash IS NULL THEN CAST(trie_nodes.node_key || X'10' AS BLOB)
-                            ELSE CAST(trie_nodes.node_key || X'10' || trie_node.partial_key AS BLOB) END,
-                            trie_node.hash IS NOT NULL
-                        FROM trie_nodes
-                        JOIN trie_node_storage
-                            ON trie_nodes.node_hash = trie_node_storage.node_hash AND trie_node_storage.trie_root_ref IS NOT NULL
-                        LEFT JOIN trie_node
-                            ON trie_node.hash = trie_node_storage.trie_root_ref
-                        WHERE trie_nodes.is_present
-                )
+    /// Returns the blocks of the given number.
+    ///
+    /// The block of the best chain, if any, comes first, followed with the other blocks ordered
+    /// by increasing hash. This order is part of the API and doesn't depend on the order in
+    /// which the blocks have been inserted.
+    pub fn blocks_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<BlockAtNumber>, CorruptedError> {
+        let connection = self.read_connection();
+        blocks_by_number(&connection, block_number)
+    }
 
-            SELECT group_concat(HEX(trie_nodes.block_hash)), group_concat(CAST(blocks.number as TEXT)), trie_nodes.node_hash, group_concat(HEX(trie_nodes.node_key))
-            FROM trie_nodes
-            JOIN blocks ON blocks.hash = trie_nodes.block_hash
-            WHERE is_present = false
-            GROUP BY trie_nodes.node_hash
-            "#)
-            .map_err(|err| {
-                CorruptedError::Internal(
-                    InternalError(err),
-                )
-            })?;
+    /// Returns the hash of the block of the best chain given a block number.
+    pub fn best_block_hash_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<[u8; 32]>, CorruptedError> {
+        let connection = self.read_connection();
+        best_block_hash_by_number(&connection, block_number)
+    }
 
-        let results = statement
+    /// Returns the hashes and numbers of all the blocks that aren't part of the best chain and
+    /// whose number is strictly superior to the one of the finalized block, ordered by
+    /// increasing number.
+    ///
+    /// Blocks at or below the finalized block that aren't part of the best chain are never
+    /// finalized and can be removed with [`SqliteFullDatabase::purge_finality_orphans`].
+    pub fn fork_blocks_above_finalized(&self) -> Result<Vec<([u8; 32], u64)>, CorruptedError> {
+        let connection = self.read_connection();
+        let mut statement = prepare_cached(
+            &connection,
+            r#"SELECT hash, number FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND is_best_chain = FALSE
+                AND number > (SELECT value_number FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = "finalized")
+            ORDER BY number ASC"#,
+        )
+        .map_err(sqlite_err("fork_blocks_above_finalized"))?;
+        let rows = statement
             .query_map((), |row| {
-                let block_hashes = row.get::<_, String>(0)?;
-                let block_numbers = row.get::<_, String>(1)?;
-                let node_hash = row.get::<_, Vec<u8>>(2)?;
-                let node_keys = row.get::<_, String>(3)?;
-                Ok((block_hashes, block_numbers, node_hash, node_keys))
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
             })
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .map(|row| {
-                let (block_hashes, block_numbers, trie_node_hash, node_keys) = match row {
-                    Ok(r) => r,
-                    Err(err) => return Err(CorruptedError::Internal(InternalError(err))),
-                };
-
-                let mut block_hashes_iter = block_hashes
-                    .split(',')
-                    .map(|hash| hex::decode(hash).unwrap());
-                let mut block_numbers_iter = block_numbers
-                    .split(',')
-                    .map(|n| <u64 as core::str::FromStr>::from_str(n).unwrap());
-                let mut node_keys_iter =
-                    node_keys.split(',').map(|hash| hex::decode(hash).unwrap());
-
-                let mut blocks = Vec::with_capacity(32);
-                loop {
-                    match (
-                        block_hashes_iter.next(),
-                        block_numbers_iter.next(),
-                        node_keys_iter.next(),
-                    ) {
-                        (Some(hash), Some(number), Some(node_key)) => {
-                            let hash = <[u8; 32]>::try_from(hash)
-                                .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
-                            let mut trie_node_key_nibbles = Vec::with_capacity(node_key.len());
-                            let mut parent_tries_paths_nibbles = Vec::with_capacity(node_key.len());
-                            for nibble in node_key {
-                                debug_assert!(nibble <= 16);
-                                if nibble == 16 {
-                                    parent_tries_paths_nibbles.push(trie_node_key_nibbles.clone());
-                                    trie_node_key_nibbles.clear();
-                                } else {
-                                    trie_node_key_nibbles.push(nibble);
-                                }
-                            }
+            .map_err(sqlite_err("fork_blocks_above_finalized"))?;
+
+        let mut blocks = Vec::new();
+        for row in rows {
+            let (hash, number) = row.map_err(sqlite_err("fork_blocks_above_finalized"))?;
+            let hash =
+                <[u8; 32]>::try_from(&hash[..]).map_err(|_| CorruptedError::InvalidBlockHashLen)?;
+            let number = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
+            blocks.push((hash, number));
+        }
+        Ok(blocks)
+    }
 
-                            blocks.push(MissingTrieNodeBlock {
-                                hash,
-                                number,
-                                parent_tries_paths_nibbles,
-                                trie_node_key_nibbles,
-                            })
-                        }
-                        (None, None, None) => break,
-                        _ => {
-                            // The iterators are supposed to have the same number of elements.
-                            debug_assert!(false);
-                            break;
-                        }
-                    }
+    /// Returns the hashes and numbers of the blocks whose hash starts with `prefix`, ordered by
+    /// increasing hash. At most `limit` blocks, and never more than [`HASH_PREFIX_MAX_RESULTS`],
+    /// are returned.
+    ///
+    /// This is intended for tools that let users designate a block by the beginning of its
+    /// hash, as found for example in logs.
+    pub fn blocks_by_hash_prefix(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+    ) -> Result<Vec<([u8; 32], u64)>, CorruptedError> {
+        let limit = cmp::min(limit, HASH_PREFIX_MAX_RESULTS);
+
+        // The blocks are found by scanning the range of hashes between `prefix` included and
+        // the first value that doesn't start with `prefix` excluded, which is obtained by
+        // incrementing `prefix`. If `prefix` only contains `0xff` bytes, there isn't any such
+        // value, and 33 `0xff` bytes, which compare superior to any hash, are used instead.
+        let upper_bound = {
+            let mut upper_bound = prefix.to_vec();
+            while upper_bound.last() == Some(&0xff) {
+                upper_bound.pop();
+            }
+            match upper_bound.last_mut() {
+                Some(last) => {
+                    *last += 1;
+                    upper_bound
                 }
+                None => vec![0xff; 33],
+            }
+        };
 
-                let trie_node_hash = <[u8; 32]>::try_from(trie_node_hash)
-                    .map_err(|_| CorruptedError::InvalidTrieHashLen)?;
-
-                debug_assert!(!blocks.is_empty());
-
-                Ok(MissingTrieNode {
-                    blocks,
-                    trie_node_hash,
-                })
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-
-        Ok(results)
+        let connection = self.read_connection();
+        let mut statement = prepare_cached(
+            &connection,
+            r#"SELECT hash, number FROM blocks
+            WHERE hash >= :prefix AND hash < :upper_bound
+                AND chain_id = (SELECT id FROM current_chain)
+            ORDER BY hash ASC
+            LIMIT :limit"#,
+        )
+        .map_err(sqlite_err("blocks_by_hash_prefix"))?;
+        let rows = statement
+            .query_map(
+                rusqlite::named_params! {
+                    ":prefix": prefix,
+                    ":upper_bound": upper_bound,
+                    ":limit": i64::try_from(limit).unwrap_or(i64::MAX),
+                },
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .map_err(sqlite_err("blocks_by_hash_prefix"))?;
+
+        let mut blocks = Vec::new();
+        for row in rows {
+            let (hash, number) = row.map_err(sqlite_err("blocks_by_hash_prefix"))?;
+            let hash =
+                <[u8; 32]>::try_from(&hash[..]).map_err(|_| CorruptedError::InvalidBlockHashLen)?;
+            let number = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
+            blocks.push((hash, number));
+        }
+        Ok(blocks)
     }
 
-    /// Changes the finalized block to the given one.
-    ///
-    /// The block must have been previously inserted using [`SqliteFullDatabase::insert`],
-    /// otherwise an error is returned.
+    /// Returns the path to follow in order to go from the block `from` to the block `to`, in
+    /// other words the blocks that are no longer part of the chain and the blocks that are newly
+    /// part of the chain when switching from `from` to `to`.
     ///
-    /// Blocks are expected to be valid in context of the chain. Inserting an invalid block can
-    /// result in the database being corrupted.
+    /// Returns `None` if either of the two blocks isn't in the database. If `from` and `to` are
+    /// the same block, the returned route is empty.
+    pub fn tree_route(
+        &self,
+        from: &[u8; 32],
+        to: &[u8; 32],
+    ) -> Result<Option<TreeRoute>, CorruptedError> {
+        let connection = self.read_connection();
+        tree_route(&connection, from, to)
+    }
+
+    /// Returns the hash and number of the closest ancestor of the given block whose storage is
+    /// entirely present in the database.
     ///
-    /// The block must be a descendant of the current finalized block. Reverting finalization is
-    /// forbidden, as the database intentionally discards some information when finality is
-    /// applied.
+    /// When the storage of a block is unavailable, it can be rebuilt by executing again the
+    /// blocks between this ancestor and the block.
     ///
-    /// > **Note**: This function doesn't remove any block from the database but simply moves
-    /// >           the finalized block "cursor".
+    /// Returns `None` if the block isn't in the database, or if none of its ancestors that are in
+    /// the database have a complete storage. The block itself is never returned.
     ///
-    pub fn set_finalized(
+    /// > **Note**: This function verifies the presence of all the trie nodes of the storage of
+    /// >           each ancestor, and is thus expensive.
+    pub fn closest_ancestor_with_storage(
         &self,
-        new_finalized_block_hash: &[u8; 32],
-    ) -> Result<(), SetFinalizedError> {
-        let mut database = self.database.lock();
+        block_hash: &[u8; 32],
+    ) -> Result<Option<([u8; 32], u64)>, CorruptedError> {
+        // All the queries below are performed within the same transaction in order to read
+        // from a single consistent snapshot of the database.
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("closest_ancestor_with_storage"))?;
+
+        let Some(mut block) = block_ancestry_entry(&connection, block_hash)? else {
+            return Ok(None);
+        };
 
-        // Start a transaction to insert everything at once.
-        let transaction = database.transaction().map_err(|err| {
-            SetFinalizedError::Corrupted(CorruptedError::Internal(InternalError(err)))
-        })?;
+        // Consecutive blocks frequently have the same state, and the tries that have already
+        // been found to be incomplete are remembered in order to not verify them again.
+        let mut incomplete_tries = hashbrown::HashSet::<[u8; 32], fnv::FnvBuildHasher>::default();
 
-        // Fetch the header of the block to finalize.
-        let new_finalized_header = block_header(&transaction, new_finalized_block_hash)?
-            .ok_or(SetFinalizedError::UnknownBlock)?;
-        let new_finalized_header = header::decode(&new_finalized_header, self.block_number_bytes)
-            .map_err(CorruptedError::BlockHeaderCorrupted)
-            .map_err(SetFinalizedError::Corrupted)?;
+        loop {
+            let Some(parent_hash) = block.parent_hash else {
+                return Ok(None);
+            };
+            let Some(parent) = block_ancestry_entry(&connection, &parent_hash)? else {
+                return Ok(None);
+            };
 
-        // Fetch the current finalized block.
-        let current_finalized = finalized_num(&transaction)?;
+            if let Some(state_trie_root_hash) = parent.state_trie_root_hash {
+                if !incomplete_tries.contains(&state_trie_root_hash) {
+                    if trie_is_complete(&connection, &state_trie_root_hash)? {
+                        return Ok(Some((parent_hash, parent.number)));
+                    }
+                    incomplete_tries.insert(state_trie_root_hash);
+                }
+            }
 
-        // If the block to finalize is at the same height as the already-finalized
-        // block, considering that the database only contains one block per height on
-        // the finalized chain, and that the presence of the block to finalize in
-        // the database has already been verified, it is guaranteed that the block
-        // to finalize is already the one already finalized.
-        // TODO: this comment is obsolete ^, should also compare the block hashes
-        if new_finalized_header.number == current_finalized {
-            return Ok(());
+            block = parent;
         }
+    }
 
-        // Cannot set the finalized block to a past block. The database can't support
-        // reverting finalization.
-        if new_finalized_header.number < current_finalized {
-            return Err(SetFinalizedError::RevertForbidden);
+    /// Verifies that the blocks of the best chain whose number is between `from_number` and the
+    /// number of the finalized block, inclusive, are all in the database and that each of them is
+    /// the parent of the next one.
+    ///
+    /// Returns [`CorruptedError::BrokenChain`] containing the number of the first block that is
+    /// either missing or whose parent isn't the block of the best chain right below it.
+    ///
+    /// This is useful in order to verify a database file that has been copied or restored from
+    /// a backup before trusting it. Does nothing if `from_number` is superior to the number of
+    /// the finalized block.
+    pub fn verify_finalized_chain_continuity(
+        &self,
+        from_number: u64,
+    ) -> Result<(), CorruptedError> {
+        // All the queries below are performed within the same transaction in order to read
+        // from a single consistent snapshot of the database.
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("verify_finalized_chain_continuity"))?;
+
+        let finalized_number = finalized_num(&connection)?;
+        if from_number > finalized_number {
+            return Ok(());
         }
 
-        // At this point, we are sure that the operation will succeed unless the database is
-        // corrupted.
-        // Update the finalized block in meta.
-        meta_set_number(&transaction, "finalized", new_finalized_header.number)?;
+        let mut statement = prepare_cached(
+            &connection,
+            r#"SELECT number, hash, parent_hash FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND is_best_chain = TRUE AND number >= ? AND number <= ?
+            ORDER BY number ASC"#,
+        )
+        .map_err(sqlite_err("verify_finalized_chain_continuity"))?;
+        let rows = statement
+            .query_map(
+                (
+                    i64::try_from(from_number).map_err(|_| CorruptedError::InvalidNumber)?,
+                    i64::try_from(finalized_number).map_err(|_| CorruptedError::InvalidNumber)?,
+                ),
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, [u8; 32]>(1)?,
+                        row.get::<_, Option<[u8; 32]>>(2)?,
+                    ))
+                },
+            )
+            .map_err(sqlite_err("verify_finalized_chain_continuity"))?;
+
+        // Hash of the block of the best chain whose number is `expected_number - 1`, or `None`
+        // if `expected_number` is equal to `from_number`.
+        let mut previous_hash = None::<[u8; 32]>;
+        let mut expected_number = from_number;
+
+        for row in rows {
+            let (number, hash, parent_hash) =
+                row.map_err(sqlite_err("verify_finalized_chain_continuity"))?;
+            let number = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
+
+            if number != expected_number {
+                return Err(CorruptedError::BrokenChain {
+                    number: expected_number,
+                });
+            }
 
-        // Now update the finalized block storage.
-        for height in current_finalized + 1..=new_finalized_header.number {
-            let block_hash = {
-                let list = block_hashes_by_number(&transaction, height)?;
-                debug_assert_eq!(list.len(), 1);
-                list.into_iter().next().ok_or(SetFinalizedError::Corrupted(
-                    CorruptedError::MissingBlockHeader,
-                ))?
-            };
+            if let Some(previous_hash) = previous_hash {
+                if parent_hash != Some(previous_hash) {
+                    return Err(CorruptedError::BrokenChain { number });
+                }
+            }
 
-            let block_header = block_header(&transaction, &block_hash)?.ok_or(
-                SetFinalizedError::Corrupted(CorruptedError::MissingBlockHeader),
-            )?;
-            let block_header = header::decode(&block_header, self.block_number_bytes)
-                .map_err(CorruptedError::BlockHeaderCorrupted)
-                .map_err(SetFinalizedError::Corrupted)?;
+            previous_hash = Some(hash);
+            expected_number += 1;
+        }
 
-            // TODO: the code below is very verbose and redundant with other similar code in smoldot ; could be improved
+        if expected_number <= finalized_number {
+            return Err(CorruptedError::BrokenChain {
+                number: expected_number,
+            });
+        }
 
-            if let Some((new_epoch, next_config)) = block_header.digest.babe_epoch_information() {
-                let epoch = meta_get_blob(&transaction, "babe_finalized_next_epoch")?.unwrap(); // TODO: don't unwrap
-                let decoded_epoch = decode_babe_epoch_information(&epoch)?;
-                transaction.execute(r#"INSERT OR REPLACE INTO meta(key, value_blob) SELECT "babe_finalized_epoch", value_blob FROM meta WHERE key = "babe_finalized_next_epoch""#, ()).unwrap();
+        Ok(())
+    }
 
-                let slot_number = block_header
-                    .digest
-                    .babe_pre_runtime()
-                    .unwrap()
-                    .slot_number();
-                let slots_per_epoch =
-                    expect_nz_u64(meta_get_number(&transaction, "babe_slots_per_epoch")?.unwrap())?; // TODO: don't unwrap
+    /// Returns the numbers of the blocks of the finalized chain that enact a change of the
+    /// Grandpa authorities set, but whose justification isn't stored in the database, in
+    /// increasing order.
+    ///
+    /// The justifications of these blocks are necessary in order to serve warp sync requests.
+    /// If this list is non-empty, they must first be downloaded from other peers.
+    ///
+    /// The database doesn't keep track of the history of the authorities sets. Instead, the
+    /// headers of the blocks of the finalized chain whose number is between `from_number` and
+    /// `to_number`, inclusive, are decoded in order to find the Grandpa scheduled changes they
+    /// contain. A change scheduled in block `N` with a delay of `D` is enacted by block `N + D`.
+    /// Changes that aren't enacted yet by the finalized block are ignored.
+    ///
+    /// > **Note**: This method is slow if the range of blocks is large.
+    pub fn missing_justification_set_changes(
+        &self,
+        from_number: u64,
+        to_number: u64,
+    ) -> Result<Vec<u64>, CorruptedError> {
+        // All the queries below are performed within the same transaction in order to read
+        // from a single consistent snapshot of the database.
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("missing_justification_set_changes"))?;
+
+        let finalized_number = finalized_num(&connection)?;
+        let to_number = cmp::min(to_number, finalized_number);
+        if from_number > to_number {
+            return Ok(Vec::new());
+        }
 
-                let new_epoch = if let Some(next_config) = next_config {
-                    chain_information::BabeEpochInformation {
-                        epoch_index: decoded_epoch.epoch_index.checked_add(1).unwrap(),
-                        start_slot_number: Some(
-                            decoded_epoch
-                                .start_slot_number
-                                .unwrap_or(slot_number)
-                                .checked_add(slots_per_epoch.get())
-                                .unwrap(),
-                        ),
-                        authorities: new_epoch.authorities.map(Into::into).collect(),
-                        randomness: *new_epoch.randomness,
-                        c: next_config.c,
-                        allowed_slots: next_config.allowed_slots,
-                    }
-                } else {
-                    chain_information::BabeEpochInformation {
-                        epoch_index: decoded_epoch.epoch_index.checked_add(1).unwrap(),
-                        start_slot_number: Some(
-                            decoded_epoch
-                                .start_slot_number
-                                .unwrap_or(slot_number)
-                                .checked_add(slots_per_epoch.get())
-                                .unwrap(),
-                        ),
-                        authorities: new_epoch.authorities.map(Into::into).collect(),
-                        randomness: *new_epoch.randomness,
-                        c: decoded_epoch.c,
-                        allowed_slots: decoded_epoch.allowed_slots,
-                    }
-                };
-
-                meta_set_blob(
-                    &transaction,
-                    "babe_finalized_next_epoch",
-                    &encode_babe_epoch_information(From::from(&new_epoch)),
-                )?;
-            }
-
-            // TODO: implement Aura
-
-            if grandpa_authorities_set_id(&transaction)?.is_some() {
-                for grandpa_digest_item in block_header.digest.logs().filter_map(|d| match d {
-                    header::DigestItemRef::GrandpaConsensus(gp) => Some(gp),
-                    _ => None,
-                }) {
-                    // TODO: implement items other than ScheduledChange
-                    if let header::GrandpaConsensusLogRef::ScheduledChange(change) =
-                        grandpa_digest_item
-                    {
-                        assert_eq!(change.delay, 0); // TODO: not implemented if != 0
-
-                        transaction
-                            .execute("DELETE FROM grandpa_triggered_authorities", ())
-                            .unwrap();
-
-                        let mut statement = transaction.prepare_cached("INSERT INTO grandpa_triggered_authorities(idx, public_key, weight) VALUES(?, ?, ?)").unwrap();
-                        for (index, item) in change.next_authorities.enumerate() {
-                            statement
-                                .execute((
-                                    i64::try_from(index).unwrap(),
-                                    &item.public_key[..],
-                                    i64::from_ne_bytes(item.weight.get().to_ne_bytes()),
-                                ))
-                                .unwrap();
-                        }
-
-                        transaction.execute(r#"UPDATE meta SET value_number = value_number + 1 WHERE key = "grandpa_authorities_set_id""#, ()).unwrap();
+        let mut statement = prepare_cached(
+            &connection,
+            r#"SELECT number, header FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND is_best_chain = TRUE AND number >= ? AND number <= ?
+            ORDER BY number ASC"#,
+        )
+        .map_err(sqlite_err("missing_justification_set_changes"))?;
+        let rows = statement
+            .query_map(
+                (
+                    i64::try_from(from_number).map_err(|_| CorruptedError::InvalidNumber)?,
+                    i64::try_from(to_number).map_err(|_| CorruptedError::InvalidNumber)?,
+                ),
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?)),
+            )
+            .map_err(sqlite_err("missing_justification_set_changes"))?;
+
+        let mut enacting_blocks = Vec::new();
+        for row in rows {
+            let (number, scale_encoded_header) =
+                row.map_err(sqlite_err("missing_justification_set_changes"))?;
+            let number = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
+            let decoded = header::decode(&scale_encoded_header, self.block_number_bytes)
+                .map_err(CorruptedError::BlockHeaderCorrupted)?;
+            for item in decoded.digest.logs() {
+                if let header::DigestItemRef::GrandpaConsensus(
+                    header::GrandpaConsensusLogRef::ScheduledChange(change),
+                ) = item
+                {
+                    match number.checked_add(change.delay) {
+                        Some(n) if n <= finalized_number => enacting_blocks.push(n),
+                        _ => {}
                     }
                 }
             }
         }
 
-        // It is possible that the best block has been pruned.
-        // TODO: ^ yeah, how do we handle that exactly ^ ?
-
-        // If everything went well up to this point, commit the transaction.
-        transaction.commit().map_err(|err| {
-            SetFinalizedError::Corrupted(CorruptedError::Internal(InternalError(err)))
-        })?;
-
-        Ok(())
-    }
-
-    /// Removes from the database all blocks that aren't a descendant of the current finalized
-    /// block.
-    pub fn purge_finality_orphans(&self) -> Result<(), CorruptedError> {
-        let mut database = self.database.lock();
-
-        // TODO: untested
-
-        let transaction = database
-            .transaction()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
-        // Temporarily disable foreign key checks in order to make the insertion easier, as we
-        // don't have to make sure that trie nodes are sorted.
-        // Note that this is immediately disabled again when we `COMMIT` later down below.
-        // TODO: is this really necessary?
-        transaction
-            .execute("PRAGMA defer_foreign_keys = ON", ())
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
-        let current_finalized = finalized_num(&transaction)?;
-
-        let blocks = transaction
-            .prepare_cached(
-                r#"SELECT hash FROM blocks WHERE number <= ? AND is_best_chain = FALSE"#,
-            )
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .query_map((current_finalized,), |row| row.get::<_, Vec<u8>>(0))
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+        enacting_blocks.sort_unstable();
+        enacting_blocks.dedup();
 
-        for block in blocks {
-            purge_block(&transaction, &block)?;
+        let mut statement = prepare_cached(
+            &connection,
+            r#"SELECT EXISTS(SELECT 1 FROM blocks_justifications WHERE blocks_justifications.hash = blocks.hash AND engine_id = ?)
+            FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND is_best_chain = TRUE AND number = ?"#,
+        )
+        .map_err(sqlite_err("missing_justification_set_changes"))?;
+        let mut missing = Vec::new();
+        for number in enacting_blocks {
+            let has_justification = statement
+                .query_row(
+                    (
+                        &GRANDPA_ENGINE_ID[..],
+                        i64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?,
+                    ),
+                    |row| row.get::<_, bool>(0),
+                )
+                .optional()
+                .map_err(sqlite_err("missing_justification_set_changes"))?;
+            if has_justification != Some(true) {
+                missing.push(number);
+            }
         }
 
-        // If everything went well up to this point, commit the transaction.
-        transaction
-            .commit()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
-        Ok(())
+        Ok(missing)
     }
 
-    /// Returns the value associated with a node of the trie of the given block.
-    ///
-    /// `parent_tries_paths_nibbles` is a list of keys to follow in order to find the root of the
-    /// trie into which `key_nibbles` should be searched.
-    ///
-    /// Beware that both `parent_tries_paths_nibbles` and `key_nibbles` must yield *nibbles*, in
-    /// other words values strictly inferior to 16.
-    ///
-    /// Returns an error if the block or its storage can't be found in the database.
-    ///
-    /// # Panic
+    /// Returns a [`chain_information::ChainInformation`] struct containing the information about
+    /// the current finalized state of the chain.
     ///
-    /// Panics if any of the values yielded by `parent_tries_paths_nibbles` or `key_nibbles` is
-    /// superior or equal to 16.
+    /// This method is relatively expensive and should preferably not be called repeatedly.
     ///
-    pub fn block_storage_get(
+    /// In order to avoid race conditions, the known finalized block hash must be passed as
+    /// parameter. If the finalized block in the database doesn't match the hash passed as
+    /// parameter, most likely because it has been updated in a parallel thread, a
+    /// [`StorageAccessError::IncompleteStorage`] error is returned.
+    // TODO: an IncompleteStorage error doesn't seem appropriate; also, why is it even a problem given that the chain information contains the finalized block anyway
+    pub fn to_chain_information(
         &self,
-        block_hash: &[u8; 32],
-        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
-        key_nibbles: impl Iterator<Item = u8>,
-    ) -> Result<Option<(Vec<u8>, u8)>, StorageAccessError> {
-        // Process the iterators at the very beginning and before locking the database, in order
-        // to avoid a deadlock in case the `next()` function of one of the iterators accesses
-        // the database as well.
-        let key_vectored = parent_tries_paths_nibbles
-            .flat_map(|t| t.inspect(|n| assert!(*n < 16)).chain(iter::once(0x10)))
-            .chain(key_nibbles.inspect(|n| assert!(*n < 16)))
-            .collect::<Vec<_>>();
-
-        let connection = self.database.lock();
-
-        // TODO: could be optimized by having a different request when `parent_tries_paths_nibbles` is empty and when it isn't
-        // TODO: trie_root_ref system untested
-        // TODO: infinite loop if there's a loop in the trie; detect this
-        let mut statement = connection
-            .prepare_cached(
-                r#"
-            WITH RECURSIVE
-                -- At the end of the recursive statement, `node_with_key` must always contain
-                -- one and exactly one item where `search_remain` is either empty or null. Empty
-                -- indicates that we have found a match, while null means that the search has
-                -- been interrupted due to a storage entry not being in the database. If
-                -- `search_remain` is empty, then `node_hash` is either a hash in case of a match
-                -- or null in case there is no entry with the requested key. If `search_remain`
-                -- is null, then `node_hash` is irrelevant.
-                --
-                -- In order to properly handle the situation where the key is empty, the initial
-                -- request of the recursive table building must check whether the partial key of
-                -- the root matches. In other words, all the entries of `node_with_key` (where
-                -- `node_hash` is non-null) contain entries that are known to be in the database
-                -- and after the partial key has already been verified to be correct.
-                node_with_key(node_hash, search_remain) AS (
-                        SELECT
-                            IIF(COALESCE(SUBSTR(:key, 1, LENGTH(trie_node.partial_key)), X'') = trie_node.partial_key, trie_node.hash, NULL),
-                            IIF(trie_node.partial_key IS NULL, NULL, COALESCE(SUBSTR(:key, 1 + LENGTH(trie_node.partial_key)), X''))
-                        FROM blocks
-                        LEFT JOIN trie_node ON blocks.state_trie_root_hash = trie_node.hash
-                        WHERE blocks.hash = :block_hash
-                    UNION ALL
-                    SELECT
-                        CASE
-                            WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' THEN trie_node_storage.trie_root_ref
-                            WHEN SUBSTR(node_with_key.search_remain, 2, LENGTH(trie_node.partial_key)) = trie_node.partial_key THEN trie_node_child.child_hash
-                            ELSE NULL END,
-                        CASE
-                            WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' THEN SUBSTR(node_with_key.search_remain, 1)
-                            WHEN trie_node_child.child_hash IS NULL THEN X''
-                            WHEN trie_node.partial_key IS NULL THEN NULL
-                            WHEN SUBSTR(node_with_key.search_remain, 2, LENGTH(trie_node.partial_key)) = trie_node.partial_key THEN SUBSTR(node_with_key.search_remain, 2 + LENGTH(trie_node.partial_key))
-                            ELSE X'' END
-                    FROM node_with_key
-                        LEFT JOIN trie_node_child
-                            ON node_with_key.node_hash = trie_node_child.hash
-                            AND SUBSTR(node_with_key.search_remain, 1, 1) = trie_node_child.child_num
-                        LEFT JOIN trie_node
-                            ON trie_node.hash = trie_node_child.child_hash
-                        LEFT JOIN trie_node_storage
-                            ON node_with_key.node_hash = trie_node_storage.node_hash
-                        WHERE LENGTH(node_with_key.search_remain) >= 1
-                )
-            SELECT COUNT(blocks.hash) >= 1, node_with_key.search_remain IS NULL, COALESCE(trie_node_storage.value, trie_node_storage.trie_root_ref), trie_node_storage.trie_entry_version
-            FROM blocks
-            JOIN node_with_key ON LENGTH(node_with_key.search_remain) = 0 OR node_with_key.search_remain IS NULL
-            LEFT JOIN trie_node_storage ON node_with_key.node_hash = trie_node_storage.node_hash AND node_with_key.search_remain IS NOT NULL
-            WHERE blocks.hash = :block_hash;
-            "#)
-            .map_err(|err| {
-                StorageAccessError::Corrupted(CorruptedError::Internal(
-                    InternalError(err),
-                ))
-            })?;
-
-        // In order to debug the SQL query above (for example in case of a failing test),
-        // uncomment this block:
-        //
-        /*println!("{:?}", {
-            let mut statement = connection
-                    .prepare_cached(
-                        r#"
-                    WITH RECURSIVE
-                        copy-paste the definition of node_with_key here
-
-                    SELECT * FROM node_with_key"#).unwrap();
-            statement
-                .query_map(
-                    rusqlite::named_params! {
-                        ":block_hash": &block_hash[..],
-                        ":key": key_vectored,
-                    },
-                    |row| {
-                        let node_hash = row.get::<_, Option<Vec<u8>>>(0)?.map(hex::encode);
-                        let search_remain = row.get::<_, Option<Vec<u8>>>(1)?;
-                        Ok((node_hash, search_remain))
-                    },
-                )
-                .unwrap()
-                .collect::<Vec<_>>()
-        });*/
-
-        let (has_block, incomplete_storage, value, trie_entry_version) = statement
-            .query_row(
-                rusqlite::named_params! {
-                    ":block_hash": &block_hash[..],
-                    ":key": key_vectored,
-                },
-                |row| {
-                    let has_block = row.get::<_, i64>(0)? != 0;
-                    let incomplete_storage = row.get::<_, i64>(1)? != 0;
-                    let value = row.get::<_, Option<Vec<u8>>>(2)?;
-                    let trie_entry_version = row.get::<_, Option<i64>>(3)?;
-                    Ok((has_block, incomplete_storage, value, trie_entry_version))
-                },
-            )
-            .map_err(|err| {
-                StorageAccessError::Corrupted(CorruptedError::Internal(InternalError(err)))
-            })?;
-
-        if !has_block {
-            return Err(StorageAccessError::UnknownBlock);
-        }
-
-        if incomplete_storage {
+        finalized_block_hash: &[u8; 32],
+    ) -> Result<chain_information::ValidChainInformation, StorageAccessError> {
+        // All the queries below are performed within the same transaction in order to read
+        // from a single consistent snapshot of the database.
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("to_chain_information"))?;
+        if finalized_hash(&connection)? != *finalized_block_hash {
             return Err(StorageAccessError::IncompleteStorage);
         }
 
-        let Some(value) = value else { return Ok(None) };
-
-        let trie_entry_version = u8::try_from(trie_entry_version.unwrap())
-            .map_err(|_| CorruptedError::InvalidTrieEntryVersion)
-            .map_err(StorageAccessError::Corrupted)?;
-        Ok(Some((value, trie_entry_version)))
+        Ok(finalized_chain_information(
+            &connection,
+            self.block_number_bytes,
+        )?)
     }
 
-    /// Returns the key in the storage that immediately follows or is equal to the key passed as
-    /// parameter in the storage of the block.
+    /// Insert a new block in the database.
     ///
-    /// `key_nibbles` must be an iterator to the **nibbles** of the key.
+    /// Must pass the header and body of the block.
     ///
-    /// `prefix_nibbles` must be an iterator to nibbles. If the result of the function wouldn't
-    /// start with this specific list of bytes, `None` is returned.
+    /// If known, `runtime_spec_version` is the specification version of the runtime of the
+    /// block, and can later be retrieved with [`SqliteFullDatabase::runtime_spec_version_at`].
     ///
-    /// `parent_tries_paths_nibbles` is a list of keys to follow in order to find the root of the
-    /// trie into which `key_nibbles` should be searched.
+    /// If `Some`, `meta` is stored alongside the block and can later be retrieved with
+    /// [`SqliteFullDatabase::block_insert_metadata`].
     ///
-    /// Returns `None` if `parent_tries_paths_nibbles` didn't lead to any trie, or if there is no
-    /// next key.
+    /// Blocks must be inserted in the correct order. An error is returned if the parent of the
+    /// newly-inserted block isn't present in the database.
     ///
-    /// The key is returned in the same format as `key_nibbles`.
+    /// If the block is already in the database, [`InsertError::Duplicate`] is returned and
+    /// indicates whether the body that is stored is identical to `body`.
     ///
-    /// If `branch_nodes` is `false`, then branch nodes (i.e. nodes with no value associated to
-    /// them) are ignored during the search.
+    /// On success, returns the amount of data that has been written to the database and, if
+    /// `is_new_best` is true, the blocks that have been retracted from and enacted into the best
+    /// chain. These blocks are determined within the same transaction as the update of the best
+    /// chain, and are thus guaranteed to be coherent with it.
     ///
-    /// > **Note**: Contrary to many other similar functions in smoldot, there is no `or_equal`
-    /// >           parameter to this function. Instead, `or_equal` is implicitly `true`, and a
-    /// >           value of `false` can be easily emulated by appending a `0` at the end
-    /// >           of `key_nibbles`.
+    /// > **Note**: It is not necessary for the newly-inserted block to be a descendant of the
+    /// >           finalized block, unless `is_new_best` is true, in which case
+    /// >           [`InsertError::BestNotInFinalizedChain`] is returned.
+    ///
+    pub fn insert<'a>(
+        &self,
+        scale_encoded_header: &[u8],
+        is_new_best: bool,
+        body: impl ExactSizeIterator<Item = impl AsRef<[u8]>>,
+        runtime_spec_version: Option<u32>,
+        meta: Option<&InsertMeta>,
+    ) -> Result<InsertOutcome, InsertError> {
+        let header = header::decode(scale_encoded_header, self.block_number_bytes)
+            .map_err(InsertError::BadHeader)?;
+        self.insert_decoded(
+            header,
+            scale_encoded_header,
+            is_new_best,
+            body,
+            runtime_spec_version,
+            meta,
+        )
+    }
+
+    /// Insert a new block in the database, similar to [`SqliteFullDatabase::insert`], but
+    /// with a body provided in its SCALE-encoded form, in other words as found in block
+    /// responses of the networking protocol.
     ///
-    /// # Panics
+    /// The extrinsics are read directly from `scale_encoded_body` without being copied.
+    /// [`InsertError::BadBody`] is returned if `scale_encoded_body` isn't a valid SCALE-encoded
+    /// list of extrinsics, in which case nothing is modified.
+    pub fn insert_scale_body(
+        &self,
+        scale_encoded_header: &[u8],
+        is_new_best: bool,
+        scale_encoded_body: &[u8],
+        runtime_spec_version: Option<u32>,
+        meta: Option<&InsertMeta>,
+    ) -> Result<InsertOutcome, InsertError> {
+        let body = decode_scale_body(scale_encoded_body).ok_or(InsertError::BadBody)?;
+        self.insert(
+            scale_encoded_header,
+            is_new_best,
+            body.into_iter(),
+            runtime_spec_version,
+            meta,
+        )
+    }
+
+    /// Insert a new block in the database, similar to [`SqliteFullDatabase::insert`], but
+    /// without decoding its header.
     ///
-    /// Panics if any of the values yielded by `parent_tries_paths_nibbles`, `key_nibbles`, or
-    /// `prefix_nibbles` is superior or equal to 16.
+    /// `header` must be the decoded version of `scale_encoded_header`. This function is useful
+    /// when the caller has already decoded the header, for example in order to verify the block.
     ///
-    pub fn block_storage_next_key(
+    /// The number, parent hash, and state root of the block are read from `header`, while the
+    /// hash of the block is calculated from `scale_encoded_header`, which is also what is stored
+    /// in the database. No verification is performed that the two match, and passing a `header`
+    /// that doesn't correspond to `scale_encoded_header` leaves the database in an inconsistent
+    /// state.
+    pub fn insert_decoded(
         &self,
-        block_hash: &[u8; 32],
-        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
-        key_nibbles: impl Iterator<Item = u8>,
-        prefix_nibbles: impl Iterator<Item = u8>,
-        branch_nodes: bool,
-    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
-        // Process the iterators at the very beginning and before locking the database, in order
-        // to avoid a deadlock in case the `next()` function of one of the iterators accesses
-        // the database as well.
-        let parent_tries_paths_nibbles = parent_tries_paths_nibbles
-            .flat_map(|t| t.inspect(|n| assert!(*n < 16)).chain(iter::once(0x10)))
-            .collect::<Vec<_>>();
-        let parent_tries_paths_nibbles_length = parent_tries_paths_nibbles.len();
-        let key_nibbles = {
-            let mut v = parent_tries_paths_nibbles.clone();
-            v.extend(key_nibbles.inspect(|n| assert!(*n < 16)));
-            v
-        };
-        let prefix_nibbles = {
-            let mut v = parent_tries_paths_nibbles;
-            v.extend(prefix_nibbles.inspect(|n| assert!(*n < 16)));
-            v
-        };
+        header: header::HeaderRef,
+        scale_encoded_header: &[u8],
+        is_new_best: bool,
+        mut body: impl ExactSizeIterator<Item = impl AsRef<[u8]>>,
+        runtime_spec_version: Option<u32>,
+        meta: Option<&InsertMeta>,
+    ) -> Result<InsertOutcome, InsertError> {
+        let mut timer = self.time_operation(OperationKind::Insert);
+        timer.set_rows(body.len());
 
-        let connection = self.database.lock();
+        // Calculate the hash of the new best block.
+        let block_hash = header::hash_from_scale_encoded_header(scale_encoded_header);
 
-        // Sorry for that extremely complicated SQL statement. While the logic isn't actually very
-        // complicated, we have to jump through many hoops in order to go around quirks in the
-        // SQL language.
-        // If you want to work on this SQL code, there is no miracle: write tests, and if a test
-        // fails debug the content of `next_key` to find out where the iteration doesn't behave
-        // as expected.
-        // TODO: this algorithm relies the fact that leaf nodes always have a storage value, which isn't exactly clear in the schema ; however not relying on this makes it way harder to write
-        // TODO: trie_root_ref system untested and most likely not working
-        // TODO: infinite loop if there's a loop in the trie; detect this
-        // TODO: could also check the prefix while iterating instead of only at the very end, which could maybe save many lookups
-        let mut statement = connection
-            .prepare_cached(
-                r#"
-            WITH RECURSIVE
-                -- We build a temporary table `next_key`, inserting entries one after one as we
-                -- descend the trie by trying to match entries with `:key`.
-                -- At each iteration, `node_hash` is the root where to continue the search,
-                -- `node_is_branch` is true if `node_hash` is a branch node, `node_full_key` is
-                -- the key of `node_hash` (that we build along the way) and serves as the final
-                -- result, and `key_search_remain` contains the `:key` that remains to be matched.
-                -- Can also be NULL to indicate that the search ended because the node necessary to
-                -- continue was missing from the database, in which case the values of
-                -- `node_hash` and `node_is_branch` have irrelevant values, and the value of
-                -- `node_full_key` is the "best known key".
-                -- If `:skip_branches` is false, the search ends when `key_search_remain` is null
-                -- or empty. If `:skip_branches` is true, the search ends when `key_search_remain`
-                -- is null or empty and that `node_is_branch` is false.
-                --
-                -- `next_key` has zero elements if the block can't be found in the database or if
-                -- the trie has no next key at all. These two situations need to be differentiated
-                -- in the final SELECT statement.
-                --
-                -- When encountering a node, we follow both the child that exactly matches `:key`
-                -- and also the first child that is strictly superior to `:key`. This is necessary
-                -- because `:key` might be equal to something like `ffffffff...`, in which case the
-                -- result will be after any equal match.
-                -- This means that the number of entries in `next_key` at the end of the recursion
-                -- is something like `2 * depth_in_trie(key)`.
-                -- In order to obtain the final result, we take the entry in `next_key` with the
-                -- minimal `node_full_key` amongst the ones that have finished the search.
-                --
-                -- Note that in the code below we do a lot of `COALESCE(SUBSTR(...), X'')`. This
-                -- is because, for some reason, `SUBSTR(X'', ...)` always produces `NULL`. For this
-                -- reason, it is also not possible to automatically pass NULL values
-                -- through `SUSBTR`, and we have to use CASE/IIFs instead.
-                next_key(node_hash, node_is_branch, node_full_key, key_search_remain) AS (
-                        SELECT
-                            CASE
-                                WHEN trie_node.hash IS NULL
-                                    THEN NULL
-                                WHEN COALESCE(SUBSTR(:key, 1, LENGTH(trie_node.partial_key)), X'') <= trie_node.partial_key
-                                    THEN trie_node.hash
-                                ELSE
-                                    NULL
-                            END,
-                            trie_node_storage.value IS NULL AND trie_node_storage.trie_root_ref IS NULL,
-                            COALESCE(trie_node.partial_key, X''),
-                            CASE
-                                WHEN trie_node.partial_key IS NULL
-                                    THEN NULL
-                                WHEN COALESCE(SUBSTR(:key, 1, LENGTH(trie_node.partial_key)), X'') <= trie_node.partial_key
-                                    THEN COALESCE(SUBSTR(:key, 1 + LENGTH(trie_node.partial_key)), X'')
-                                ELSE
-                                    X''   -- The partial key is strictly inferior to `:key`
-                            END
-                        FROM blocks
-                        LEFT JOIN trie_node ON trie_node.hash = blocks.state_trie_root_hash
-                        LEFT JOIN trie_node_storage ON trie_node_storage.node_hash = trie_node.hash
-                        WHERE blocks.hash = :block_hash
+        let number = i64::try_from(header.number).map_err(|_| InsertError::NumberOutOfRange {
+            number: header.number,
+        })?;
 
-                    UNION ALL
-                        SELECT
-                            COALESCE(trie_node.hash, trie_node_trieref.hash),
-                            trie_node_storage.value IS NULL AND trie_node_storage.trie_root_ref IS NULL,
-                            CASE
-                                WHEN trie_node_child.child_num IS NULL
-                                    THEN next_key.node_full_key
-                                WHEN trie_node.partial_key IS NULL AND trie_node_trieref.partial_key IS NULL
-                                    THEN CAST(next_key.node_full_key || trie_node_child.child_num AS BLOB)
-                                ELSE
-                                    CAST(next_key.node_full_key || trie_node_child.child_num || COALESCE(trie_node.partial_key, trie_node_trieref.partial_key) AS BLOB)
-                            END,
-                            CASE
-                                WHEN trie_node_child.child_num IS NOT NULL AND trie_node.partial_key IS NULL
-                                    THEN NULL    -- Child exists but is missing from database
-                                WHEN HEX(SUBSTR(next_key.key_search_remain, 1, 1)) = '10' AND trie_node_trieref.hash IS NULL
-                                    THEN NULL    -- Trie reference exists but is missing from database
-                                WHEN SUBSTR(next_key.key_search_remain, 1, 1) = trie_node_child.child_num AND SUBSTR(next_key.key_search_remain, 2, LENGTH(trie_node.partial_key)) = trie_node.partial_key
-                                    THEN SUBSTR(next_key.key_search_remain, 2 + LENGTH(trie_node.partial_key))    -- Equal match, continue iterating
-                                WHEN SUBSTR(next_key.key_search_remain, 1, 1) = trie_node_child.child_num AND SUBSTR(next_key.key_search_remain, 2, LENGTH(trie_node.partial_key)) < trie_node.partial_key
-                                    THEN X''     -- Searched key is before the node we are iterating to, thus we cut the search short
-                                WHEN HEX(SUBSTR(next_key.key_search_remain, 1, 1)) = '10' AND COALESCE(SUBSTR(next_key.key_search_remain, 2, LENGTH(trie_node_trieref.partial_key)), X'') = trie_node_trieref.partial_key
-                                    THEN COALESCE(SUBSTR(next_key.key_search_remain, 2 + LENGTH(trie_node_trieref.partial_key)), X'')
-                                ELSE
-                                    X''          -- Shouldn't be reachable.
-                            END
-                        FROM next_key
-
-                        LEFT JOIN trie_node_child
-                            ON next_key.node_hash = trie_node_child.hash
-                            AND CASE WHEN LENGTH(next_key.key_search_remain) = 0 THEN TRUE
-                                ELSE SUBSTR(next_key.key_search_remain, 1, 1) <= trie_node_child.child_num END
-                        LEFT JOIN trie_node ON trie_node.hash = trie_node_child.child_hash
-
-                        -- We want to keep only situations where `trie_node_child` is either
-                        -- equal to the key, or the first child strictly superior to the key. In
-                        -- order to do that, we try to find another child that is strictly
-                        -- in-between the key and `trie_node_child`. In the `WHERE` clause at the
-                        -- bottom, we only keep rows where `trie_node_child_before` is NULL.
-                        LEFT JOIN trie_node_child AS trie_node_child_before
-                            ON next_key.node_hash = trie_node_child_before.hash
-                            AND trie_node_child_before.child_num < trie_node_child.child_num
-                            AND (next_key.key_search_remain = X'' OR trie_node_child_before.child_num > SUBSTR(next_key.key_search_remain, 1, 1))
-
-                        LEFT JOIN trie_node_storage AS trie_node_storage_trieref
-                            ON HEX(SUBSTR(next_key.key_search_remain, 1, 1)) = '10' AND next_key.node_hash = trie_node_storage_trieref.node_hash AND trie_node_storage_trieref.trie_root_ref IS NOT NULL
-                        LEFT JOIN trie_node AS trie_node_trieref
-                            ON trie_node_trieref.hash = trie_node_storage_trieref.node_hash
-                            AND COALESCE(SUBSTR(next_key.key_search_remain, 2, LENGTH(trie_node_trieref.partial_key)), X'') <= trie_node_trieref.partial_key
-
-                        LEFT JOIN trie_node_storage
-                            ON trie_node_storage.node_hash = COALESCE(trie_node.hash, trie_node_trieref.hash)
-
-                        WHERE
-                            -- Don't pull items that have already finished searching.
-                            next_key.node_hash IS NOT NULL AND next_key.key_search_remain IS NOT NULL AND (next_key.key_search_remain != X'' OR (next_key.node_is_branch AND :skip_branches))
-                            -- See explanation above.
-                            AND trie_node_child_before.hash IS NULL
-                            -- Don't generate an item if there's nowhere to go to.
-                            AND (HEX(SUBSTR(next_key.key_search_remain, 1, 1)) = '10' OR trie_node_child.child_num IS NOT NULL)
-                            -- Stop iterating if the child's partial key is before the searched key.
-                            AND (trie_node.hash IS NULL OR NOT (COALESCE(SUBSTR(next_key.key_search_remain, 1, 1), X'') = trie_node_child.child_num AND COALESCE(SUBSTR(next_key.key_search_remain, 2, LENGTH(trie_node.partial_key)), X'') > trie_node.partial_key))
-                ),
+        let inserted_at_unix_ms = meta
+            .map(|meta| {
+                i64::try_from(meta.inserted_at_unix_ms).map_err(|_| InsertError::InvalidMeta {
+                    inserted_at_unix_ms: meta.inserted_at_unix_ms,
+                })
+            })
+            .transpose()?;
 
-                -- Now keep only the entries of `next_key` which have finished iterating.
-                terminal_next_key(incomplete_storage, node_full_key, output) AS (
-                    SELECT
-                        CASE
-                            WHEN COALESCE(SUBSTR(node_full_key, 1, LENGTH(:prefix)), X'') != :prefix THEN FALSE
-                            ELSE key_search_remain IS NULL
-                        END,
-                        node_full_key,
-                        CASE
-                            WHEN node_hash IS NULL THEN NULL
-                            WHEN COALESCE(SUBSTR(node_full_key, 1, LENGTH(:prefix)), X'') = :prefix THEN node_full_key
-                            ELSE NULL
-                        END
-                    FROM next_key
-                    WHERE key_search_remain IS NULL OR (LENGTH(key_search_remain) = 0 AND (NOT :skip_branches OR NOT node_is_branch))
-                )
+        // Locking is performed as late as possible.
+        let mut database = self.lock_write().map_err(InsertError::Corrupted)?;
 
-            SELECT
-                COUNT(blocks.hash) >= 1,
-                COALESCE(terminal_next_key.incomplete_storage, FALSE),
-                terminal_next_key.output
-            FROM blocks
-            LEFT JOIN terminal_next_key
-            WHERE blocks.hash = :block_hash
-                -- We pick the entry of `terminal_next_key` with the smallest full key. Note that
-                -- it might seem like a good idea to not using any GROUP BY and instead just do
-                -- `ORDER BY node_full_key ASC LIMIT 1`, but doing so sometimes leads to SQLite
-                -- not picking the entry with the smallest full key for a reason I couldn't
-                -- figure out.
-                AND (terminal_next_key.node_full_key IS NULL OR terminal_next_key.node_full_key = (SELECT MIN(node_full_key) FROM terminal_next_key))
-            LIMIT 1"#,
-            )
-            .map_err(|err| {
-                StorageAccessError::Corrupted(CorruptedError::Internal(
-                    InternalError(err),
-                ))
-            })?;
-
-        // In order to debug the SQL query above (for example in case of a failing test),
-        // uncomment this block:
-        //
-        /*println!("{:?}", {
-            let mut statement = connection
-                    .prepare_cached(
-                        r#"
-                    WITH RECURSIVE
-                        copy-paste the definition of next_key here
-
-                    SELECT * FROM next_key"#).unwrap();
-            statement
-                .query_map(
-                    rusqlite::named_params! {
-                        ":block_hash": &block_hash[..],
-                        ":key": key_nibbles,
-                        //":prefix": prefix_nibbles,
-                        ":skip_branches": !branch_nodes
-                    },
-                    |row| {
-                        let node_hash = row.get::<_, Option<Vec<u8>>>(0)?.map(hex::encode);
-                        let node_is_branch = row.get::<_, Option<i64>>(1)?.map(|n| n != 0);
-                        let node_full_key = row.get::<_, Option<Vec<u8>>>(2)?;
-                        let search_remain = row.get::<_, Option<Vec<u8>>>(3)?;
-                        Ok((node_hash, node_is_branch, node_full_key, search_remain))
-                    },
-                )
-                .unwrap()
-                .collect::<Vec<_>>()
-        });*/
+        // Start a transaction to insert everything at once.
+        let transaction = database
+            .savepoint()
+            .map_err(|err| InsertError::Corrupted(sqlite_err("insert")(err)))?;
 
-        let result = statement
-            .query_row(
-                rusqlite::named_params! {
-                    ":block_hash": &block_hash[..],
-                    ":key": key_nibbles,
-                    ":prefix": prefix_nibbles,
-                    ":skip_branches": !branch_nodes
-                },
-                |row| {
-                    let block_is_known = row.get::<_, i64>(0)? != 0;
-                    let incomplete_storage = row.get::<_, i64>(1)? != 0;
-                    let next_key = row.get::<_, Option<Vec<u8>>>(2)?;
-                    Ok((block_is_known, incomplete_storage, next_key))
-                },
-            )
-            .optional()
-            .map_err(|err| {
-                StorageAccessError::Corrupted(CorruptedError::Internal(InternalError(err)))
-            })?;
+        // Make sure that the block to insert isn't already in the database. If it is, its body
+        // is compared with the one that has been provided, so that the caller can detect a
+        // previous insertion that didn't complete as expected.
+        if has_block(&transaction, &block_hash)? {
+            let mut body_matches = true;
+            let body_stored =
+                match with_block_extrinsics(&transaction, &block_hash, |stored| match body.next() {
+                    Some(provided) if provided.as_ref() == stored => ops::ControlFlow::Continue(()),
+                    _ => {
+                        body_matches = false;
+                        ops::ControlFlow::Break(())
+                    }
+                }) {
+                    Ok(_) => true,
+                    Err(BlockExtrinsicsError::BodyPruned) => false,
+                    Err(BlockExtrinsicsError::Corrupted(err)) => {
+                        return Err(InsertError::Corrupted(err))
+                    }
+                };
 
-        let Some((block_is_known, incomplete_storage, mut next_key)) = result else {
-            return Ok(None);
+            return Err(InsertError::Duplicate {
+                body_stored,
+                body_matches: body_stored && body_matches && body.next().is_none(),
+            });
+        }
+
+        let mut stats = InsertStats {
+            header_bytes: scale_encoded_header.len(),
+            body_items: 0,
+            body_bytes: 0,
         };
 
-        if !block_is_known {
-            return Err(StorageAccessError::UnknownBlock);
+        // Make sure that the parent of the block to insert is in the database, and that the
+        // number of the block is coherent with the one of its parent.
+        let parent_number =
+            prepare_cached(&transaction, "SELECT number FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)")
+                .map_err(sqlite_err("insert"))?
+                .query_row((&header.parent_hash[..],), |row| row.get::<_, i64>(0))
+                .optional()
+                .map_err(sqlite_err("insert"))?
+                .ok_or(InsertError::MissingParent)?;
+        if parent_number.checked_add(1) != Some(number) {
+            return Err(InsertError::NumberMismatch {
+                parent: u64::try_from(parent_number).map_err(|_| CorruptedError::InvalidNumber)?,
+                child: header.number,
+            });
         }
 
-        if incomplete_storage {
-            return Err(StorageAccessError::IncompleteStorage);
+        prepare_cached(
+            &transaction,
+            "INSERT INTO blocks(chain_id, number, hash, parent_hash, state_trie_root_hash, header, is_best_chain, runtime_spec_version) VALUES ((SELECT id FROM current_chain), ?, ?, ?, ?, ?, FALSE, ?)",
+        )
+        .map_err(sqlite_err("insert"))?
+        .execute((
+            number,
+            &block_hash[..],
+            &header.parent_hash[..],
+            &header.state_root[..],
+            scale_encoded_header,
+            runtime_spec_version,
+        ))
+        .map_err(sqlite_err("insert"))?;
+
+        if let (Some(meta), Some(inserted_at_unix_ms)) = (meta, inserted_at_unix_ms) {
+            prepare_cached(
+                &transaction,
+                "INSERT INTO blocks_meta(hash, inserted_at_unix_ms, source) VALUES (?, ?, ?)",
+            )
+            .map_err(sqlite_err("insert"))?
+            .execute((&block_hash[..], inserted_at_unix_ms, &meta.source))
+            .map_err(sqlite_err("insert"))?;
         }
 
-        if parent_tries_paths_nibbles_length != 0 {
-            next_key = next_key.map(|nk| nk[parent_tries_paths_nibbles_length..].to_vec());
+        for (index, item) in body.enumerate() {
+            insert_body_extrinsic(
+                &transaction,
+                &block_hash,
+                i64::try_from(index).map_err(|_| CorruptedError::InvalidNumber)?,
+                item.as_ref(),
+            )
+            .map_err(sqlite_err("insert"))?;
+            stats.body_items += 1;
+            stats.body_bytes += item.as_ref().len();
         }
 
-        Ok(next_key)
+        // Change the best chain to be the new block.
+        let best_chain_change = if is_new_best {
+            // It would be illegal to change the best chain to not overlay with the
+            // finalized chain. Comparing the numbers is enough to reject most blocks, but a
+            // block can also be higher than the finalized block while being on a fork that
+            // branches off below it.
+            if header.number <= finalized_num(&transaction)?
+                || !descends_from_finalized(&transaction, &block_hash)?
+            {
+                return Err(InsertError::BestNotInFinalizedChain);
+            }
+
+            // Both blocks are known to be in the database, and `tree_route` thus can't return
+            // `None`.
+            let previous_best = best_block_hash(&transaction)?;
+            let route = tree_route(&transaction, &previous_best, &block_hash)?
+                .ok_or(CorruptedError::MissingBlockHeader)?;
+
+            set_best_chain(&transaction, &block_hash)?;
+            Some(route)
+        } else {
+            None
+        };
+
+        // If everything is successful, we commit.
+        transaction
+            .commit()
+            .map_err(|err| InsertError::Corrupted(sqlite_err("insert")(err)))?;
+        database
+            .finish_with_events(
+                1,
+                is_new_best.then_some(Event::NewBest {
+                    hash: block_hash,
+                    number: header.number,
+                }),
+            )
+            .map_err(InsertError::Corrupted)?;
+
+        Ok(InsertOutcome {
+            stats,
+            best_chain_change,
+        })
     }
 
-    /// Returns the Merkle value of the trie node in the storage that is the closest descendant
-    /// of the provided key.
-    ///
-    /// `key_nibbles` must be an iterator to the **nibbles** of the key.
-    ///
-    /// `parent_tries_paths_nibbles` is a list of keys to follow in order to find the root of the
-    /// trie into which `key_nibbles` should be searched.
-    ///
-    /// Returns `None` if `parent_tries_paths_nibbles` didn't lead to any trie, or if there is no
-    /// such descendant.
-    ///
-    /// # Panics
+    /// Changes the best block of the database to the given block, which must already be in the
+    /// database.
     ///
-    /// Panics if any of the values yielded by `parent_tries_paths_nibbles` or `key_nibbles` is
-    /// superior or equal to 16.
+    /// The new best block must be either the finalized block or one of its descendants,
+    /// otherwise [`SetBestError::NotInFinalizedChain`] is returned.
     ///
-    pub fn block_storage_closest_descendant_merkle_value(
-        &self,
-        block_hash: &[u8; 32],
-        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
-        key_nibbles: impl Iterator<Item = u8>,
-    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
-        // Process the iterators at the very beginning and before locking the database, in order
-        // to avoid a deadlock in case the `next()` function of one of the iterators accesses
-        // the database as well.
-        let key_vectored = parent_tries_paths_nibbles
-            .flat_map(|t| t.inspect(|n| assert!(*n < 16)).chain(iter::once(0x10)))
-            .chain(key_nibbles.inspect(|n| assert!(*n < 16)))
-            .collect::<Vec<_>>();
-
-        let connection = self.database.lock();
+    /// This is useful when the fork choice changes its mind about blocks that have already been
+    /// inserted. See also the `is_new_best` parameter of [`SqliteFullDatabase::insert`].
+    pub fn set_best_block(&self, block_hash: &[u8; 32]) -> Result<(), SetBestError> {
+        let _timer = self.time_operation(OperationKind::SetBest);
+        let mut database = self.lock_write()?;
+        let transaction = database.savepoint().map_err(sqlite_err("set_best_block"))?;
+
+        let number = prepare_cached(&transaction, "SELECT number FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)")
+            .map_err(sqlite_err("set_best_block"))?
+            .query_row((&block_hash[..],), |row| row.get::<_, i64>(0))
+            .optional()
+            .map_err(sqlite_err("set_best_block"))?
+            .ok_or(SetBestError::UnknownBlock)?;
+        let number = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
 
-        // TODO: trie_root_ref system untested
-        // TODO: infinite loop if there's a loop in the trie; detect this
-        let mut statement = connection
-            .prepare_cached(
-                r#"
-            WITH RECURSIVE
-                -- At the end of the recursive statement, `closest_descendant` must always contain
-                -- at most one item where `search_remain` is either empty or null. Empty
-                -- indicates that we have found a match, while null means that the search has
-                -- been interrupted due to a storage entry not being in the database. If
-                -- `search_remain` is null, then `node_hash` is irrelevant.
-                -- If `closest_descendant` doesn't have any entry where `search_remain` is empty
-                -- or null, then the request key doesn't have any descendant.
-                closest_descendant(node_hash, search_remain) AS (
-                    SELECT
-                            blocks.state_trie_root_hash,
-                            CASE
-                                WHEN trie_node.partial_key IS NULL AND LENGTH(:key) = 0
-                                    THEN X''   -- Trie root node isn't in database, but since key is empty we have a match anyway
-                                WHEN trie_node.partial_key IS NULL AND LENGTH(:key) != 0
-                                    THEN NULL  -- Trie root node isn't in database and we can't iterate further
-                                ELSE
-                                    COALESCE(SUBSTR(:key, 1 + LENGTH(trie_node.partial_key)), X'')
-                            END
-                        FROM blocks
-                        LEFT JOIN trie_node ON blocks.state_trie_root_hash = trie_node.hash
-                        WHERE blocks.hash = :block_hash
-                            AND (
-                                trie_node.partial_key IS NULL
-                                OR COALESCE(SUBSTR(trie_node.partial_key, 1, LENGTH(:key)), X'') = :key
-                                OR COALESCE(SUBSTR(:key, 1, LENGTH(trie_node.partial_key)), X'') = trie_node.partial_key
-                            )
+        if !descends_from_finalized(&transaction, block_hash)? {
+            return Err(SetBestError::NotInFinalizedChain);
+        }
 
-                    UNION ALL
-                    SELECT
-                            COALESCE(trie_node_child.child_hash, trie_node_storage.trie_root_ref),
-                            CASE
-                                WHEN trie_node_child.child_hash IS NULL AND HEX(SUBSTR(closest_descendant.search_remain, 1, 1)) != '10'
-                                    THEN X''      -- No child matching the key.
-                                WHEN trie_node_child.child_hash IS NOT NULL AND trie_node.hash IS NULL AND LENGTH(closest_descendant.search_remain) = 1
-                                    THEN X''      -- Descendant node not in trie but we know that it's the result.
-                                WHEN trie_node_child.child_hash IS NOT NULL AND trie_node.hash IS NULL
-                                    THEN NULL     -- Descendant node not in trie.
-                                WHEN COALESCE(SUBSTR(trie_node.partial_key, 1, LENGTH(closest_descendant.search_remain) - 1), X'') = COALESCE(SUBSTR(closest_descendant.search_remain, 2), X'')
-                                        OR COALESCE(SUBSTR(closest_descendant.search_remain, 2, LENGTH(trie_node.partial_key)), X'') = trie_node.partial_key
-                                    THEN SUBSTR(closest_descendant.search_remain, 2 + LENGTH(trie_node.partial_key))
-                                ELSE
-                                    X''           -- Unreachable.
-                            END
-                        FROM closest_descendant
-                        LEFT JOIN trie_node_child ON closest_descendant.node_hash = trie_node_child.hash
-                            AND SUBSTR(closest_descendant.search_remain, 1, 1) = trie_node_child.child_num
-                        LEFT JOIN trie_node ON trie_node.hash = trie_node_child.child_hash
-                        LEFT JOIN trie_node_storage
-                            ON closest_descendant.node_hash = trie_node_storage.node_hash
-                            AND HEX(SUBSTR(closest_descendant.search_remain, 1, 1)) = '10'
-                            AND trie_node_storage.trie_root_ref IS NOT NULL
-                        WHERE
-                            LENGTH(closest_descendant.search_remain) >= 1
-                            AND (
-                                trie_node.hash IS NULL
-                                OR COALESCE(SUBSTR(trie_node.partial_key, 1, LENGTH(closest_descendant.search_remain) - 1), X'') = COALESCE(SUBSTR(closest_descendant.search_remain, 2), X'')
-                                OR COALESCE(SUBSTR(closest_descendant.search_remain, 2, LENGTH(trie_node.partial_key)), X'') = trie_node.partial_key
-                            )
-                )
-            SELECT COUNT(blocks.hash) >= 1, closest_descendant.node_hash IS NOT NULL AND closest_descendant.search_remain IS NULL, closest_descendant.node_hash
-            FROM blocks
-            LEFT JOIN closest_descendant ON LENGTH(closest_descendant.search_remain) = 0 OR closest_descendant.search_remain IS NULL
-            WHERE blocks.hash = :block_hash
-            LIMIT 1"#,
-            )
-            .map_err(|err| {
-                StorageAccessError::Corrupted(CorruptedError::Internal(
-                    InternalError(err),
-                ))
-            })?;
-
-        // In order to debug the SQL query above (for example in case of a failing test),
-        // uncomment this block:
-        //
-        /*println!("{:?}", {
-            let mut statement = connection
-                    .prepare_cached(
-                        r#"
-                    WITH RECURSIVE
-                        copy-paste the definition of closest_descendant here
-
-                    SELECT * FROM closest_descendant"#).unwrap();
-            statement
-                .query_map(
-                    rusqlite::named_params! {
-                        ":block_hash": &block_hash[..],
-                        ":key": key_vectored,
-                    },
-                    |row| {
-                        let node_hash = row.get::<_, Option<Vec<u8>>>(0)?.map(hex::encode);
-                        let search_remain = row.get::<_, Option<Vec<u8>>>(1)?;
-                        Ok((node_hash, search_remain))
-                    },
-                )
-                .unwrap()
-                .collect::<Vec<_>>()
-        });*/
+        let is_new_best = best_block_hash(&transaction)? != *block_hash;
+        if is_new_best {
+            set_best_chain(&transaction, block_hash)?;
+        }
 
-        let (has_block, incomplete_storage, merkle_value) = statement
-            .query_row(
-                rusqlite::named_params! {
-                    ":block_hash": &block_hash[..],
-                    ":key": key_vectored,
-                },
-                |row| {
-                    let has_block = row.get::<_, i64>(0)? != 0;
-                    let incomplete_storage = row.get::<_, i64>(1)? != 0;
-                    let merkle_value = row.get::<_, Option<Vec<u8>>>(2)?;
-                    Ok((has_block, incomplete_storage, merkle_value))
-                },
-            )
-            .map_err(|err| {
-                StorageAccessError::Corrupted(CorruptedError::Internal(InternalError(err)))
-            })?;
+        transaction.commit().map_err(sqlite_err("set_best_block"))?;
+        database.finish_with_events(
+            0,
+            is_new_best.then_some(Event::NewBest {
+                hash: *block_hash,
+                number,
+            }),
+        )?;
 
-        if !has_block {
-            return Err(StorageAccessError::UnknownBlock);
-        }
+        Ok(())
+    }
 
-        if incomplete_storage {
-            return Err(StorageAccessError::IncompleteStorage);
-        }
+    // TODO: needs documentation
+    pub fn insert_trie_nodes_versioned<'a>(
+        &self,
+        new_trie_nodes: impl Iterator<Item = InsertTrieNode<'a>>,
+        trie_entries_version: trie::TrieEntryVersion,
+    ) -> Result<InsertTrieNodesStats, InsertTrieNodesError> {
+        // Process the iterator at the very beginning and before locking the database, in order
+        // to avoid a deadlock in case the `next()` function of the iterator accesses the
+        // database as well.
+        let new_trie_nodes = new_trie_nodes.collect::<Vec<_>>();
+        self.insert_trie_nodes_inner(new_trie_nodes, trie_entries_version, false)
+    }
 
-        Ok(merkle_value)
+    /// Same as [`SqliteFullDatabase::insert_trie_nodes_versioned`], but the version of the trie
+    /// entries is passed as an integer.
+    ///
+    /// Returns [`InsertTrieNodesError::InvalidTrieEntryVersion`] if the version is invalid.
+    #[deprecated(note = "Use `insert_trie_nodes_versioned` with a `TrieEntryVersion` instead")]
+    pub fn insert_trie_nodes<'a>(
+        &self,
+        new_trie_nodes: impl Iterator<Item = InsertTrieNode<'a>>,
+        trie_entries_version: u8,
+    ) -> Result<InsertTrieNodesStats, InsertTrieNodesError> {
+        let trie_entries_version = trie::TrieEntryVersion::try_from(trie_entries_version)
+            .map_err(|()| InsertTrieNodesError::InvalidTrieEntryVersion)?;
+        self.insert_trie_nodes_versioned(new_trie_nodes, trie_entries_version)
     }
 
-    /// Inserts a block in the database and sets it as the finalized block.
+    /// Similar to [`SqliteFullDatabase::insert_trie_nodes_versioned`], but inserts the nodes by
+    /// groups of `chunk_size` and commits after each group, in order to not hold the database
+    /// locked and not accumulate a large amount of uncommitted modifications when inserting a
+    /// huge number of nodes.
     ///
-    /// The parent of the block doesn't need to be present in the database.
+    /// `progress` is called after each group with the total number of nodes inserted so far.
     ///
-    /// If the block is already in the database, it is replaced by the one provided.
-    pub fn reset<'a>(
+    /// If an error happens, the nodes of the groups that precede the one that has failed remain
+    /// in the database. This is not a problem, as they are simply reported as present by
+    /// [`SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`], and the
+    /// insertion can later be resumed with the nodes that are still missing.
+    pub fn insert_trie_nodes_chunked<'a>(
         &self,
-        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
-        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
-        finalized_block_justification: Option<Vec<u8>>,
-    ) -> Result<(), CorruptedError> {
-        // Start a transaction to insert everything in one go.
-        let mut database = self.database.lock();
-        let transaction = database
-            .transaction()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-
-        // Temporarily disable foreign key checks in order to make the initial insertion easier,
-        // as we don't have to make sure that trie nodes are sorted.
-        // Note that this is immediately disabled again when we `COMMIT`.
-        transaction
-            .execute("PRAGMA defer_foreign_keys = ON", ())
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+        mut new_trie_nodes: impl Iterator<Item = InsertTrieNode<'a>>,
+        trie_entries_version: trie::TrieEntryVersion,
+        chunk_size: NonZeroUsize,
+        mut progress: impl FnMut(usize),
+    ) -> Result<InsertTrieNodesStats, InsertTrieNodesError> {
+        let mut num_inserted = 0;
+        let mut stats = InsertTrieNodesStats {
+            inserted_nodes: 0,
+            ignored_nodes: 0,
+            storage_value_bytes: 0,
+        };
 
-        let chain_information = chain_information.into();
+        loop {
+            // Each group is extracted from the iterator before locking the database, in order
+            // to avoid a deadlock in case the `next()` function of the iterator accesses the
+            // database as well.
+            let chunk = new_trie_nodes
+                .by_ref()
+                .take(chunk_size.get())
+                .collect::<Vec<_>>();
+            if chunk.is_empty() {
+                break;
+            }
 
-        let finalized_block_hash = chain_information
-            .finalized_block_header
-            .hash(self.block_number_bytes);
+            num_inserted += chunk.len();
+            let chunk_stats = self.insert_trie_nodes_inner(chunk, trie_entries_version, false)?;
+            stats.inserted_nodes += chunk_stats.inserted_nodes;
+            stats.ignored_nodes += chunk_stats.ignored_nodes;
+            stats.storage_value_bytes += chunk_stats.storage_value_bytes;
+            self.flush()?;
+            progress(num_inserted);
+        }
 
-        let scale_encoded_finalized_block_header = chain_information
-            .finalized_block_header
-            .scale_encoding(self.block_number_bytes)
-            .fold(Vec::new(), |mut a, b| {
-                a.extend_from_slice(b.as_ref());
-                a
-            });
+        Ok(stats)
+    }
 
-        transaction
-            .prepare_cached(
-                "INSERT OR REPLACE INTO blocks(hash, parent_hash, state_trie_root_hash, number, header, is_best_chain, justification) VALUES(?, ?, ?, ?, ?, TRUE, ?)",
-            )
-            .unwrap()
-            .execute((
-                &finalized_block_hash[..],
-                if chain_information.finalized_block_header.number != 0 {
-                    Some(&chain_information.finalized_block_header.parent_hash[..])
-                } else { None },
-                &chain_information.finalized_block_header.state_root[..],
-                i64::try_from(chain_information.finalized_block_header.number).unwrap(),
-                &scale_encoded_finalized_block_header[..],
-                finalized_block_justification.as_deref(),
-            ))
-            .unwrap();
+    /// Similar to [`SqliteFullDatabase::insert_trie_nodes_versioned`], but additionally verifies
+    /// that each of the inserted nodes is reachable, in other words is the child of another node,
+    /// the root of a child trie, or the state trie root of a block. The other node can be either
+    /// already in the database or part of the inserted nodes.
+    ///
+    /// If this isn't the case, [`InsertTrieNodesError::OrphanTrieNodes`] is returned and nothing
+    /// is inserted.
+    pub fn insert_trie_nodes_validated<'a>(
+        &self,
+        new_trie_nodes: impl Iterator<Item = InsertTrieNode<'a>>,
+        trie_entries_version: trie::TrieEntryVersion,
+    ) -> Result<InsertTrieNodesStats, InsertTrieNodesError> {
+        let new_trie_nodes = new_trie_nodes.collect::<Vec<_>>();
+        self.insert_trie_nodes_inner(new_trie_nodes, trie_entries_version, true)
+    }
 
-        transaction
-            .execute(
-                "DELETE FROM blocks_body WHERE hash = ?",
-                (&finalized_block_hash[..],),
-            )
-            .unwrap();
+    fn insert_trie_nodes_inner(
+        &self,
+        new_trie_nodes: Vec<InsertTrieNode>,
+        trie_entries_version: trie::TrieEntryVersion,
+        validate: bool,
+    ) -> Result<InsertTrieNodesStats, InsertTrieNodesError> {
+        let mut timer = self.time_operation(OperationKind::InsertTrieNodes);
+        timer.set_rows(new_trie_nodes.len());
 
-        {
-            let mut statement = transaction
-                .prepare_cached(
-                    "INSERT OR IGNORE INTO blocks_body(hash, idx, extrinsic) VALUES(?, ?, ?)",
-                )
-                .unwrap();
-            for (index, item) in finalized_block_body.enumerate() {
-                statement
-                    .execute((
-                        &finalized_block_hash[..],
-                        i64::try_from(index).unwrap(),
-                        item,
-                    ))
-                    .unwrap();
-            }
-        }
+        let mut database = self.lock_write()?;
 
-        meta_set_blob(&transaction, "best", &finalized_block_hash[..]).unwrap();
-        meta_set_number(
-            &transaction,
-            "finalized",
-            chain_information.finalized_block_header.number,
-        )?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("insert_trie_nodes"))?;
 
-        meta_clear(&transaction, "grandpa_authorities_set_id")?;
-        meta_clear(&transaction, "grandpa_scheduled_target")?;
-        transaction
-            .execute("DELETE FROM grandpa_triggered_authorities WHERE TRUE;", ())
-            .unwrap();
-        transaction
-            .execute("DELETE FROM grandpa_scheduled_authorities WHERE TRUE;", ())
-            .unwrap();
+        // List of the Merkle values of the inserted nodes, if `validate` is `true`.
+        let mut inserted_nodes = Vec::new();
 
-        match &chain_information.finality {
-            chain_information::ChainInformationFinalityRef::Outsourced => {}
-            chain_information::ChainInformationFinalityRef::Grandpa {
-                finalized_triggered_authorities,
-                after_finalized_block_authorities_set_id,
-                finalized_scheduled_change,
-            } => {
-                meta_set_number(
-                    &transaction,
-                    "grandpa_authorities_set_id",
-                    *after_finalized_block_authorities_set_id,
-                )?;
+        let mut stats = InsertTrieNodesStats {
+            inserted_nodes: 0,
+            ignored_nodes: 0,
+            storage_value_bytes: 0,
+        };
 
-                let mut statement = transaction
-                    .prepare_cached("INSERT INTO grandpa_triggered_authorities(idx, public_key, weight) VALUES(?, ?, ?)")
-                    .unwrap();
-                for (index, item) in finalized_triggered_authorities.iter().enumerate() {
-                    statement
-                        .execute((
-                            i64::try_from(index).unwrap(),
-                            &item.public_key[..],
-                            i64::from_ne_bytes(item.weight.get().to_ne_bytes()),
-                        ))
-                        .unwrap();
+        {
+            // TODO: should check whether the existing merkle values that are referenced from inserted nodes exist in the parent's storage
+            let mut insert_node_statement = prepare_cached(
+                &transaction,
+                "INSERT OR IGNORE INTO trie_node(hash, partial_key, children) VALUES(?, ?, ?)",
+            )
+            .map_err(sqlite_err("insert_trie_nodes"))?;
+            let mut insert_node_storage_statement = prepare_cached(&transaction, "INSERT OR IGNORE INTO trie_node_storage(node_hash, value, value_compressed, value_hash, trie_root_ref, trie_entry_version) VALUES(?, ?, ?, ?, ?, ?)")
+                .map_err(sqlite_err("insert_trie_nodes"))?;
+            let mut insert_parent_statement = prepare_cached(
+                &transaction,
+                "INSERT OR IGNORE INTO trie_node_parent(child_hash, hash) VALUES(?, ?)",
+            )
+            .map_err(sqlite_err("insert_trie_nodes"))?;
+            for trie_node in new_trie_nodes {
+                if let Some((position, value)) = trie_node
+                    .partial_key_nibbles
+                    .iter()
+                    .enumerate()
+                    .find(|(_, n)| **n >= 16)
+                {
+                    return Err(InsertTrieNodesError::InvalidNibble {
+                        position,
+                        value: *value,
+                    });
                 }
-
-                if let Some((height, list)) = finalized_scheduled_change {
-                    meta_set_number(&transaction, "grandpa_scheduled_target", *height)?;
-
-                    let mut statement = transaction
-                        .prepare_cached("INSERT INTO grandpa_scheduled_authorities(idx, public_key, weight) VALUES(?, ?, ?)")
-                        .unwrap();
-                    for (index, item) in list.iter().enumerate() {
-                        statement
+                if let Some(child_num) = trie_node
+                    .children_merkle_values
+                    .iter()
+                    .position(|child| child.as_ref().is_some_and(|child| child.len() > 32))
+                {
+                    return Err(InsertTrieNodesError::InvalidChildMerkleValue {
+                        child_num: u8::try_from(child_num).unwrap_or_else(|_| unreachable!()),
+                    });
+                }
+                let is_new_node = insert_node_statement
+                    .execute((
+                        &trie_node.merkle_value,
+                        pack_nibbles(&trie_node.partial_key_nibbles),
+                        encode_children(
+                            trie_node
+                                .children_merkle_values
+                                .iter()
+                                .map(|child| child.as_deref()),
+                        ),
+                    ))
+                    .map_err(sqlite_err("insert_trie_nodes"))?
+                    != 0;
+                if validate {
+                    inserted_nodes.push(trie_node.merkle_value.clone().into_owned());
+                }
+                // If a node with the same Merkle value is already in the database, it must be
+                // identical to the inserted one, as the Merkle value is a hash of the node.
+                if !is_new_node {
+                    stats.ignored_nodes += 1;
+                    if !trie_node_matches(&transaction, &trie_node)? {
+                        return Err(InsertTrieNodesError::Corrupted(
+                            CorruptedError::ConflictingTrieNode {
+                                merkle_value: trie_node.merkle_value.into_owned(),
+                            },
+                        ));
+                    }
+                    continue;
+                }
+                stats.inserted_nodes += 1;
+                match trie_node.storage_value {
+                    InsertTrieNodeStorageValue::Value {
+                        value,
+                        references_merkle_value,
+                    } => {
+                        if !references_merkle_value {
+                            stats.storage_value_bytes += value.len();
+                        }
+                        let compressed_value = match self.value_compression {
+                            Some(config) if !references_merkle_value => {
+                                compress_value(&value, &config)
+                            }
+                            _ => None,
+                        };
+                        insert_node_storage_statement
                             .execute((
-                                i64::try_from(index).unwrap(),
-                                &item.public_key[..],
-                                i64::from_ne_bytes(item.weight.get().to_ne_bytes()),
+                                &trie_node.merkle_value,
+                                match (&compressed_value, references_merkle_value) {
+                                    (Some(compressed), _) => Some(&compressed[..]),
+                                    (None, false) => Some(&value[..]),
+                                    (None, true) => None,
+                                },
+                                compressed_value.is_some(),
+                                if references_merkle_value {
+                                    None
+                                } else {
+                                    Some(blake2_hash(&value))
+                                },
+                                if references_merkle_value {
+                                    Some(&value)
+                                } else {
+                                    None
+                                },
+                                u8::from(trie_entries_version),
                             ))
-                            .unwrap();
+                            .map_err(sqlite_err("insert_trie_nodes"))?;
                     }
+                    InsertTrieNodeStorageValue::NoValue => {}
+                }
+                for child in trie_node.children_merkle_values.iter().flatten() {
+                    insert_parent_statement
+                        .execute((child, &trie_node.merkle_value))
+                        .map_err(sqlite_err("insert_trie_nodes"))?;
                 }
             }
         }
 
-        meta_clear(&transaction, "aura_slot_duration")?;
-        transaction
-            .execute("DELETE FROM aura_finalized_authorities WHERE TRUE;", ())
-            .unwrap();
-        meta_clear(&transaction, "babe_slots_per_epoch")?;
-        meta_clear(&transaction, "babe_finalized_next_epoch")?;
-        meta_clear(&transaction, "babe_finalized_epoch")?;
-
-        match &chain_information.consensus {
-            chain_information::ChainInformationConsensusRef::Unknown => {}
-            chain_information::ChainInformationConsensusRef::Aura {
-                finalized_authorities_list,
-                slot_duration,
-            } => {
-                meta_set_number(&transaction, "aura_slot_duration", slot_duration.get()).unwrap();
-
-                let mut statement = transaction
-                    .prepare_cached(
-                        "INSERT INTO aura_finalized_authorities(idx, public_key) VALUES(?, ?)",
-                    )
-                    .unwrap();
-                for (index, item) in finalized_authorities_list.clone().enumerate() {
-                    statement
-                        .execute((i64::try_from(index).unwrap(), &item.public_key[..]))
-                        .unwrap();
+        // Because all the nodes have been inserted at this point, nodes referenced by other
+        // nodes of the same batch are considered as reachable.
+        if validate {
+            let mut statement = prepare_cached(
+                &transaction,
+                r#"SELECT
+                        EXISTS(SELECT 1 FROM trie_node_parent WHERE child_hash = :hash)
+                        OR EXISTS(SELECT 1 FROM trie_node_storage WHERE trie_root_ref = :hash)
+                        OR EXISTS(SELECT 1 FROM blocks WHERE state_trie_root_hash = :hash)"#,
+            )
+            .map_err(sqlite_err("insert_trie_nodes"))?;
+
+            let mut orphans = Vec::new();
+            for merkle_value in inserted_nodes {
+                let is_reachable = statement
+                    .query_row(rusqlite::named_params! { ":hash": &merkle_value }, |row| {
+                        row.get::<_, bool>(0)
+                    })
+                    .map_err(sqlite_err("insert_trie_nodes"))?;
+                if !is_reachable {
+                    orphans.push(merkle_value);
                 }
             }
-            chain_information::ChainInformationConsensusRef::Babe {
-                slots_per_epoch,
-                finalized_next_epoch_transition,
-                finalized_block_epoch_information,
-            } => {
-                meta_set_number(&transaction, "babe_slots_per_epoch", slots_per_epoch.get())
-                    .unwrap();
-                meta_set_blob(
-                    &transaction,
-                    "babe_finalized_next_epoch",
-                    &encode_babe_epoch_information(finalized_next_epoch_transition.clone())[..],
-                )
-                .unwrap();
 
-                if let Some(finalized_block_epoch_information) = finalized_block_epoch_information {
-                    meta_set_blob(&transaction, "babe_finalized_epoch", &encode_babe_epoch_information(
-                    finalized_block_epoch_information.clone(),
-                    )[..]).unwrap();
-                }
+            if !orphans.is_empty() {
+                return Err(InsertTrieNodesError::OrphanTrieNodes {
+                    merkle_values: orphans,
+                });
             }
         }
 
         transaction
             .commit()
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+            .map_err(sqlite_err("insert_trie_nodes"))?;
+        database.finish(0)?;
 
-        Ok(())
+        Ok(stats)
     }
-}
 
-impl fmt::Debug for SqliteFullDatabase {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_tuple("SqliteFullDatabase").finish()
-    }
-}
+    /// Returns a list of trie nodes that are missing from the database and that belong to the
+    /// state of a block whose number is superior or equal to the finalized block.
+    ///
+    /// The ordering of the returned trie nodes is unspecified.
+    ///
+    /// > **Note**: This function call is relatively expensive, and the API user is expected to
+    /// >           cache the return value.
+    pub fn finalized_and_above_missing_trie_nodes_unordered(
+        &self,
+    ) -> Result<Vec<MissingTrieNode>, CorruptedError> {
+        let mut timer = self.time_operation(OperationKind::MissingTrieNodes);
+        let database = self.read_connection();
 
-impl Drop for SqliteFullDatabase {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            // The SQLite documentation recommends running `PRAGMA optimize` when the database
-            // closes.
-            // TODO: it is also recommended to do this every 2 hours
-            let _ = self.database.get_mut().execute("PRAGMA optimize", ());
+        let mut statement = prepare_cached(
+            &database,
+            r#"
+        WITH RECURSIVE
+            -- List of all block hashes that are equal to the finalized block or above.
+            finalized_and_above_blocks(block_hash) AS (
+                SELECT blocks.hash
+                FROM blocks
+                JOIN meta ON meta.chain_id = blocks.chain_id AND meta.key = "finalized"
+                WHERE blocks.chain_id = (SELECT id FROM current_chain) AND blocks.number >= meta.value_number
+            ),
+
+            -- The 16 possible child indices.
+            nibble(num) AS (VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+                (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')),
+
+            -- List of all trie nodes for these blocks. The recursion stops at a depth of
+            -- `:max_trie_depth` in order to not loop indefinitely if there is a loop in the
+            -- trie. `children` is a copy of the column of `trie_node`, in order to not
+            -- look up each node twice.
+            trie_nodes(block_hash, node_hash, node_key, is_present, children, depth) AS (
+                SELECT  blocks.hash, blocks.state_trie_root_hash,
+                        COALESCE(unpack_nibbles(trie_node.partial_key), X''),
+                        trie_node.hash IS NOT NULL, trie_node.children, 0
+                    FROM blocks
+                    JOIN finalized_and_above_blocks
+                        ON blocks.hash = finalized_and_above_blocks.block_hash
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = blocks.state_trie_root_hash
+
+                UNION ALL
+                SELECT  trie_nodes.block_hash, child_merkle_value(trie_nodes.children, nibble.num),
+                        CASE WHEN trie_node.hash IS NULL THEN CAST(trie_nodes.node_key || nibble.num AS BLOB)
+                        ELSE CAST(trie_nodes.node_key || nibble.num || unpack_nibbles(trie_node.partial_key) AS BLOB) END,
+                        trie_node.hash IS NOT NULL, trie_node.children, trie_nodes.depth + 1
+                    FROM trie_nodes
+                    JOIN nibble
+                        ON child_merkle_value(trie_nodes.children, nibble.num) IS NOT NULL
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = child_merkle_value(trie_nodes.children, nibble.num)
+                    WHERE trie_nodes.is_present AND trie_nodes.depth < :max_trie_depth
+
+                UNION ALL
+                SELECT  trie_nodes.block_hash, trie_node_storage.trie_root_ref,
+                        CASE WHEN trie_node.hash IS NULL THEN CAST(trie_nodes.node_key || X'10' AS BLOB)
+                        ELSE CAST(trie_nodes.node_key || X'10' || unpack_nibbles(trie_node.partial_key) AS BLOB) END,
+                        trie_node.hash IS NOT NULL, trie_node.children, trie_nodes.depth + 1
+                    FROM trie_nodes
+                    JOIN trie_node_storage
+                        ON trie_nodes.node_hash = trie_node_storage.node_hash AND trie_node_storage.trie_root_ref IS NOT NULL
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = trie_node_storage.trie_root_ref
+                    WHERE trie_nodes.is_present AND trie_nodes.depth < :max_trie_depth
+            )
+
+        SELECT trie_nodes.node_hash, trie_nodes.block_hash, blocks.number, trie_nodes.node_key, trie_nodes.depth >= :max_trie_depth
+        FROM trie_nodes
+        JOIN blocks ON blocks.hash = trie_nodes.block_hash
+        WHERE is_present = false OR trie_nodes.depth >= :max_trie_depth
+        ORDER BY trie_nodes.node_hash
+        "#)
+        .map_err(sqlite_err("finalized_and_above_missing_trie_nodes_unordered"))?;
+
+        let rows = statement
+            .query_map(
+                rusqlite::named_params! {
+                    ":max_trie_depth": i64::try_from(MAX_TRIE_DEPTH).unwrap(),
+                },
+                |row| {
+                    let node_hash = row.get::<_, Vec<u8>>(0)?;
+                    let block_hash = row.get::<_, Vec<u8>>(1)?;
+                    let block_number = row.get::<_, i64>(2)?;
+                    let node_key = row.get::<_, Vec<u8>>(3)?;
+                    let too_deep = row.get::<_, bool>(4)?;
+                    Ok((node_hash, block_hash, block_number, node_key, too_deep))
+                },
+            )
+            .map_err(sqlite_err(
+                "finalized_and_above_missing_trie_nodes_unordered",
+            ))?;
+
+        // The query returns one row per missing trie node and block the node belongs to. Rows
+        // are ordered by trie node, and rows concerning the same trie node are thus grouped
+        // together.
+        let mut results = Vec::<MissingTrieNode>::new();
+        for row in rows {
+            let (trie_node_hash, block_hash, block_number, node_key, too_deep) = row.map_err(
+                sqlite_err("finalized_and_above_missing_trie_nodes_unordered"),
+            )?;
+
+            if too_deep {
+                return Err(CorruptedError::TrieLoopDetected);
+            }
+
+            let trie_node_hash = <[u8; 32]>::try_from(trie_node_hash)
+                .map_err(|_| CorruptedError::InvalidTrieHashLen)?;
+            let hash = <[u8; 32]>::try_from(block_hash)
+                .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
+            let number = u64::try_from(block_number).map_err(|_| CorruptedError::InvalidNumber)?;
+
+            let mut trie_node_key_nibbles = Vec::with_capacity(node_key.len());
+            let mut parent_tries_paths_nibbles = Vec::with_capacity(node_key.len());
+            for nibble in node_key {
+                debug_assert!(nibble <= 16);
+                if nibble == 16 {
+                    parent_tries_paths_nibbles.push(trie_node_key_nibbles.clone());
+                    trie_node_key_nibbles.clear();
+                } else {
+                    trie_node_key_nibbles.push(nibble);
+                }
+            }
+
+            let block = MissingTrieNodeBlock {
+                hash,
+                number,
+                parent_tries_paths_nibbles,
+                trie_node_key_nibbles,
+            };
+
+            match results.last_mut() {
+                Some(last) if last.trie_node_hash == trie_node_hash => last.blocks.push(block),
+                _ => results.push(MissingTrieNode {
+                    blocks: vec![block],
+                    trie_node_hash,
+                }),
+            }
         }
+
+        timer.set_rows(results.len());
+        Ok(results)
     }
-}
 
-/// See [`SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`].
-#[derive(Debug)]
-pub struct MissingTrieNode {
-    /// Blocks the trie node is known to belong to.
+    /// Changes the finalized block to the given one.
     ///
-    /// Guaranteed to never be empty.
+    /// The block must have been previously inserted using [`SqliteFullDatabase::insert`],
+    /// otherwise an error is returned.
     ///
-    /// Only contains blocks whose number is superior or equal to the latest finalized block
-    /// number.
-    pub blocks: Vec<MissingTrieNodeBlock>,
-    /// Hash of the missing trie node.
-    pub trie_node_hash: [u8; 32],
-}
-
-/// See [`MissingTrieNode::blocks`].
-#[derive(Debug)]
+    /// Blocks are expected to be valid in context of the chain. Inserting an invalid block can
+    /// result in the database being corrupted.
+    ///
+    /// The block must be a descendant of the current finalized block. Reverting finalization is
+    /// forbidden, as the database intentionally discards some information when finality is
+    /// applied.
+    ///
+    /// If the best block isn't a descendant of the new finalized block, the new finalized block
+    /// becomes the best block.
+    ///
+    /// On success, returns the number of blocks that have become finalized, the number of
+    /// extrinsics that have been removed, and the new Babe epochs if the finalized block has
+    /// moved to a different epoch.
+    ///
+    /// > **Note**: This function doesn't remove any block from the database but simply moves
+    /// >           the finalized block "cursor". If [`Config::body_retention`] or
+    /// >           [`Config::storage_diff_retention`] is `Some`, the bodies or storage diffs of
+    /// >           the old finalized blocks are however removed.
+    ///
+    pub fn set_finalized(
+        &self,
+        new_finalized_block_hash: &[u8; 32],
+    ) -> Result<SetFinalizedStats, SetFinalizedError> {
+        let mut timer = self.time_operation(OperationKind::SetFinalized);
+        let mut database = self.lock_write().map_err(SetFinalizedError::Corrupted)?;
+
+        // Start a transaction to insert everything at once.
+        let transaction = database
+            .savepoint()
+            .map_err(|err| SetFinalizedError::Corrupted(sqlite_err("set_finalized/begin")(err)))?;
+
+        // Fetch the header of the block to finalize.
+        let new_finalized_header = block_header(&transaction, new_finalized_block_hash)?
+            .ok_or(SetFinalizedError::UnknownBlock)?;
+        let new_finalized_header = header::decode(&new_finalized_header, self.block_number_bytes)
+            .map_err(CorruptedError::BlockHeaderCorrupted)
+            .map_err(SetFinalizedError::Corrupted)?;
+
+        // Fetch the current finalized block.
+        let current_finalized = finalized_num(&transaction)?;
+        let current_finalized_hash = finalized_hash(&transaction)?;
+
+        // A block at the same height as the already-finalized block can only be the
+        // already-finalized block itself, as the other blocks at that height aren't descendants
+        // of it.
+        if new_finalized_header.number == current_finalized {
+            if *new_finalized_block_hash != current_finalized_hash {
+                return Err(SetFinalizedError::NotDescendant);
+            }
+            return Ok(SetFinalizedStats {
+                newly_finalized_blocks: 0,
+                pruned_body_rows: 0,
+                pruned_storage_diffs: 0,
+                babe_epoch_transition: None,
+            });
+        }
+
+        // Cannot set the finalized block to a past block. The database can't support
+        // reverting finalization.
+        if new_finalized_header.number < current_finalized {
+            return Err(SetFinalizedError::RevertForbidden);
+        }
+
+        // Blocks that become finalized, from the lowest to the highest. Because there might be
+        // multiple blocks at each height, they are found by walking up the ancestry of the new
+        // finalized block.
+        let newly_finalized = prepare_cached(
+            &transaction,
+            r#"
+            WITH RECURSIVE
+                ancestry(hash, parent_hash, number) AS (
+                    SELECT hash, parent_hash, number FROM blocks WHERE hash = :new_finalized AND chain_id = (SELECT id FROM current_chain)
+                    UNION ALL
+                    SELECT blocks.hash, blocks.parent_hash, blocks.number
+                        FROM ancestry
+                        JOIN blocks ON blocks.hash = ancestry.parent_hash
+                        WHERE blocks.number > :current_finalized
+                )
+            SELECT hash, parent_hash FROM ancestry ORDER BY number ASC
+            "#,
+        )
+        .map_err(sqlite_err("set_finalized/newly_finalized"))?
+        .query_map(
+            rusqlite::named_params! {
+                ":new_finalized": &new_finalized_block_hash[..],
+                ":current_finalized": i64::try_from(current_finalized)
+                    .map_err(|_| CorruptedError::InvalidNumber)?,
+            },
+            |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?)),
+        )
+        .map_err(sqlite_err("set_finalized/newly_finalized"))?
+        .map(|row| row.map_err(sqlite_err("set_finalized/newly_finalized")))
+        .collect::<Result<Vec<_>, _>>()?;
+        if u64::try_from(newly_finalized.len()).ok()
+            != Some(new_finalized_header.number - current_finalized)
+        {
+            return Err(SetFinalizedError::Corrupted(
+                CorruptedError::MissingBlockHeader,
+            ));
+        }
+
+        // The walk stops at the height of the current finalized block, and the lowest block
+        // found must thus be a child of it. This isn't the case if the new finalized block
+        // belongs to a fork that has branched off below the current finalized block.
+        if newly_finalized[0].1.as_deref() != Some(&current_finalized_hash[..]) {
+            return Err(SetFinalizedError::NotDescendant);
+        }
+        let newly_finalized = newly_finalized
+            .into_iter()
+            .map(|(hash, _)| {
+                <[u8; 32]>::try_from(&hash[..]).map_err(|_| CorruptedError::InvalidBlockHashLen)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        timer.set_rows(newly_finalized.len());
+
+        // At this point, we are sure that the operation will succeed unless the database is
+        // corrupted.
+        // Update the finalized block in meta.
+        meta_set_number(&transaction, "finalized", new_finalized_header.number)?;
+
+        // The finalized block is identified by being part of the best chain. If the new
+        // finalized block isn't, then the current best block doesn't descend from it and the
+        // new finalized block becomes the best block.
+        let is_best_chain = prepare_cached(
+            &transaction,
+            "SELECT is_best_chain FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)",
+        )
+        .map_err(sqlite_err("set_finalized/is_best_chain"))?
+        .query_row((&new_finalized_block_hash[..],), |row| {
+            row.get::<_, bool>(0)
+        })
+        .map_err(sqlite_err("set_finalized/is_best_chain"))?;
+        if !is_best_chain {
+            set_best_chain(&transaction, new_finalized_block_hash)?;
+        }
+
+        let mut stats = SetFinalizedStats {
+            newly_finalized_blocks: new_finalized_header.number - current_finalized,
+            pruned_body_rows: 0,
+            pruned_storage_diffs: 0,
+            babe_epoch_transition: None,
+        };
+
+        // Blocks that can't be finalized anymore, reported in the finality event. These are the
+        // blocks at the same heights as the newly-finalized blocks.
+        let retracted = prepare_cached(&transaction, r#"SELECT hash, number FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number > ? AND number <= ?"#)
+            .map_err(sqlite_err("set_finalized/retracted"))?
+            .query_map(
+                (
+                    i64::try_from(current_finalized).map_err(|_| CorruptedError::InvalidNumber)?,
+                    i64::try_from(new_finalized_header.number)
+                        .map_err(|_| CorruptedError::InvalidNumber)?,
+                ),
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .map_err(sqlite_err("set_finalized/retracted"))?
+            .filter_map(|row| {
+                let (hash, number) = match row {
+                    Ok(row) => row,
+                    Err(err) => return Some(Err(sqlite_err("set_finalized/retracted")(err))),
+                };
+                // `newly_finalized` contains exactly one block per height, starting at
+                // `current_finalized + 1`.
+                let index = u64::try_from(number)
+                    .ok()
+                    .and_then(|n| n.checked_sub(current_finalized + 1))
+                    .and_then(|n| usize::try_from(n).ok());
+                match index.and_then(|index| newly_finalized.get(index)) {
+                    Some(finalized) if finalized[..] == hash[..] => None,
+                    Some(_) => Some(
+                        <[u8; 32]>::try_from(&hash[..])
+                            .map_err(|_| CorruptedError::InvalidBlockHashLen),
+                    ),
+                    None => Some(Err(CorruptedError::InvalidNumber)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Now update the finalized block storage.
+        for block_hash in newly_finalized {
+            let block_header = block_header(&transaction, &block_hash)?.ok_or(
+                SetFinalizedError::Corrupted(CorruptedError::MissingBlockHeader),
+            )?;
+            let block_header = header::decode(&block_header, self.block_number_bytes)
+                .map_err(CorruptedError::BlockHeaderCorrupted)
+                .map_err(SetFinalizedError::Corrupted)?;
+
+            // TODO: the code below is very verbose and redundant with other similar code in smoldot ; could be improved
+
+            if let Some((new_epoch, next_config)) = block_header.digest.babe_epoch_information() {
+                let epoch = meta_get_blob(&transaction, "babe_finalized_next_epoch")?
+                    .ok_or(CorruptedError::MissingMetaKey)?;
+                let decoded_epoch = decode_babe_epoch_information(&epoch)?;
+                let prev_epoch = meta_get_blob(&transaction, "babe_finalized_epoch")?
+                    .map(|v| decode_babe_epoch_information(&v))
+                    .transpose()?;
+                transaction.execute(r#"INSERT OR REPLACE INTO meta(chain_id, key, value_blob) SELECT chain_id, "babe_finalized_epoch", value_blob FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = "babe_finalized_next_epoch""#, ()).map_err(sqlite_err("set_finalized/babe_epoch"))?;
+
+                let slot_number = block_header
+                    .digest
+                    .babe_pre_runtime()
+                    .unwrap()
+                    .slot_number();
+                let slots_per_epoch = expect_nz_u64(
+                    meta_get_u64_blob(&transaction, "babe_slots_per_epoch")?
+                        .ok_or(CorruptedError::MissingMetaKey)?,
+                )?;
+
+                let new_epoch = if let Some(next_config) = next_config {
+                    chain_information::BabeEpochInformation {
+                        epoch_index: decoded_epoch.epoch_index.checked_add(1).unwrap(),
+                        start_slot_number: Some(
+                            decoded_epoch
+                                .start_slot_number
+                                .unwrap_or(slot_number)
+                                .checked_add(slots_per_epoch.get())
+                                .unwrap(),
+                        ),
+                        authorities: new_epoch.authorities.map(Into::into).collect(),
+                        randomness: *new_epoch.randomness,
+                        c: next_config.c,
+                        allowed_slots: next_config.allowed_slots,
+                    }
+                } else {
+                    chain_information::BabeEpochInformation {
+                        epoch_index: decoded_epoch.epoch_index.checked_add(1).unwrap(),
+                        start_slot_number: Some(
+                            decoded_epoch
+                                .start_slot_number
+                                .unwrap_or(slot_number)
+                                .checked_add(slots_per_epoch.get())
+                                .unwrap(),
+                        ),
+                        authorities: new_epoch.authorities.map(Into::into).collect(),
+                        randomness: *new_epoch.randomness,
+                        c: decoded_epoch.c,
+                        allowed_slots: decoded_epoch.allowed_slots,
+                    }
+                };
+
+                meta_set_blob(
+                    &transaction,
+                    "babe_finalized_next_epoch",
+                    &encode_babe_epoch_information(From::from(&new_epoch)),
+                )?;
+
+                // If multiple epoch transitions happen, only the last one is reported, as it
+                // contains the epoch that the finalized block now belongs to.
+                stats.babe_epoch_transition = Some(BabeEpochTransition {
+                    prev: prev_epoch,
+                    next: decoded_epoch,
+                });
+            }
+
+            // TODO: implement Aura
+
+            if grandpa_authorities_set_id(&transaction)?.is_some() {
+                for grandpa_digest_item in block_header.digest.logs().filter_map(|d| match d {
+                    header::DigestItemRef::GrandpaConsensus(gp) => Some(gp),
+                    _ => None,
+                }) {
+                    // TODO: implement items other than ScheduledChange
+                    if let header::GrandpaConsensusLogRef::ScheduledChange(change) =
+                        grandpa_digest_item
+                    {
+                        assert_eq!(change.delay, 0); // TODO: not implemented if != 0
+
+                        prepare_cached(
+                            &transaction,
+                            "DELETE FROM grandpa_triggered_authorities WHERE chain_id = (SELECT id FROM current_chain)",
+                        )
+                        .and_then(|mut statement| statement.execute(()))
+                        .map_err(sqlite_err("set_finalized/grandpa_authorities"))?;
+
+                        let mut statement = prepare_cached(&transaction, "INSERT INTO grandpa_triggered_authorities(chain_id, idx, public_key, weight) VALUES((SELECT id FROM current_chain), ?, ?, ?)").map_err(sqlite_err("set_finalized/grandpa_authorities"))?;
+                        for (index, item) in change.next_authorities.enumerate() {
+                            statement
+                                .execute((
+                                    i64::try_from(index)
+                                        .map_err(|_| CorruptedError::InvalidNumber)?,
+                                    &item.public_key[..],
+                                    encode_u64(item.weight.get()),
+                                ))
+                                .map_err(sqlite_err("set_finalized/grandpa_authorities"))?;
+                        }
+
+                        let set_id = grandpa_authorities_set_id(&transaction)?
+                            .ok_or(CorruptedError::MissingMetaKey)?
+                            .checked_add(1)
+                            .ok_or(CorruptedError::InvalidNumber)?;
+                        meta_set_u64_blob(&transaction, "grandpa_authorities_set_id", set_id)?;
+                    }
+                }
+            }
+        }
+
+        if let Some(body_retention) = self.body_retention {
+            stats.pruned_body_rows = prune_bodies_below(
+                &transaction,
+                new_finalized_header.number.saturating_sub(body_retention),
+            )?;
+        }
+
+        if let Some(storage_diff_retention) = self.storage_diff_retention {
+            stats.pruned_storage_diffs = storage_diffs::prune_storage_diffs_below(
+                &transaction,
+                new_finalized_header
+                    .number
+                    .saturating_sub(storage_diff_retention),
+            )?;
+        }
+
+        // It is possible that the best block has been pruned.
+        // TODO: ^ yeah, how do we handle that exactly ^ ?
+
+        // If everything went well up to this point, commit the transaction.
+        transaction
+            .commit()
+            .map_err(|err| SetFinalizedError::Corrupted(sqlite_err("set_finalized/commit")(err)))?;
+        let new_best_event = (!is_best_chain).then_some(Event::NewBest {
+            hash: *new_finalized_block_hash,
+            number: new_finalized_header.number,
+        });
+        let finalized_event = Event::Finalized {
+            hash: *new_finalized_block_hash,
+            number: new_finalized_header.number,
+            retracted,
+        };
+        database
+            .finish_with_events(0, new_best_event.into_iter().chain([finalized_event]))
+            .map_err(SetFinalizedError::Corrupted)?;
+
+        Ok(stats)
+    }
+
+    /// Removes from the database all blocks that aren't a descendant of the current finalized
+    /// block.
+    ///
+    /// If `limit` is `Some`, at most this number of blocks are removed. The blocks with the
+    /// highest number are removed first, meaning that the remaining orphans can be removed by
+    /// calling this function again later. This makes it possible to spread the work over
+    /// multiple calls, for example when the database contains a lot of orphans after the node
+    /// has been offline for a long time.
+    pub fn purge_finality_orphans(
+        &self,
+        limit: Option<NonZeroUsize>,
+    ) -> Result<PurgeReport, CorruptedError> {
+        let mut timer = self.time_operation(OperationKind::PurgeFinalityOrphans);
+        let mut database = self.lock_write()?;
+
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("purge_finality_orphans"))?;
+
+        // Temporarily disable foreign key checks in order to make the insertion easier, as we
+        // don't have to make sure that trie nodes are sorted.
+        // Note that this is immediately disabled again when the transaction is committed.
+        // TODO: is this really necessary?
+        prepare_cached(&transaction, "PRAGMA defer_foreign_keys = ON")
+            .and_then(|mut statement| statement.execute(()))
+            .map_err(sqlite_err("purge_finality_orphans"))?;
+
+        let current_finalized = finalized_num(&transaction)?;
+
+        // One more block than the limit is queried in order to know whether orphans remain.
+        // A negative `LIMIT` means no limit.
+        let query_limit = limit.map_or(-1, |limit| {
+            i64::try_from(limit.get())
+                .unwrap_or(i64::MAX)
+                .saturating_add(1)
+        });
+        let mut blocks = prepare_cached(&transaction, FINALITY_ORPHANS_QUERY)
+            .map_err(sqlite_err("purge_finality_orphans"))?
+            .query_map((current_finalized, query_limit), |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .map_err(sqlite_err("purge_finality_orphans"))?
+            .map(|value| {
+                let value = value.map_err(sqlite_err("purge_finality_orphans"))?;
+                <[u8; 32]>::try_from(&value[..]).map_err(|_| CorruptedError::InvalidBlockHashLen)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let more_remaining = limit.is_some_and(|limit| blocks.len() > limit.get());
+        if let Some(limit) = limit {
+            blocks.truncate(limit.get());
+        }
+
+        let mut report = PurgeReport {
+            purged_blocks: Vec::with_capacity(blocks.len()),
+            deleted_body_rows: 0,
+            deleted_trie_nodes: 0,
+            more_remaining,
+        };
+
+        for block in blocks {
+            let (deleted_body_rows, deleted_trie_nodes) = purge_block(&transaction, &block)?;
+            report.deleted_body_rows += deleted_body_rows;
+            report.deleted_trie_nodes += deleted_trie_nodes;
+            report.purged_blocks.push(block);
+        }
+
+        // If everything went well up to this point, commit the transaction.
+        transaction
+            .commit()
+            .map_err(sqlite_err("purge_finality_orphans"))?;
+        database.finish(0)?;
+
+        timer.set_rows(report.purged_blocks.len());
+        Ok(report)
+    }
+
+    /// Removes from the database the bodies of the blocks of the best chain whose number is
+    /// strictly inferior to `block_number`. Returns the number of extrinsics that have been
+    /// removed.
+    ///
+    /// The headers of these blocks are kept, and [`SqliteFullDatabase::block_extrinsics`] now
+    /// returns [`BlockExtrinsicsError::BodyPruned`] for them. The bodies of the non-finalized
+    /// blocks are never removed, even if their number is inferior to `block_number`.
+    ///
+    /// The events of these blocks (see [`SqliteFullDatabase::set_block_events`]) are removed
+    /// as well.
+    ///
+    /// See also [`Config::body_retention`].
+    pub fn prune_bodies_below(&self, block_number: u64) -> Result<u64, CorruptedError> {
+        let mut timer = self.time_operation(OperationKind::PruneBodies);
+        let mut database = self.lock_write()?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("prune_bodies_below"))?;
+        let num_removed = prune_bodies_below(&transaction, block_number)?;
+        transaction
+            .commit()
+            .map_err(sqlite_err("prune_bodies_below"))?;
+        database.finish(0)?;
+        timer.set_rows(num_removed);
+        Ok(num_removed)
+    }
+
+    /// Returns the value associated with a node of the trie of the given block.
+    ///
+    /// `parent_tries_paths_nibbles` is a list of keys to follow in order to find the root of the
+    /// trie into which `key_nibbles` should be searched.
+    ///
+    /// Beware that both `parent_tries_paths_nibbles` and `key_nibbles` must yield *nibbles*, in
+    /// other words values strictly inferior to 16.
+    ///
+    /// Returns an error if the block or its storage can't be found in the database, or
+    /// [`StorageAccessError::InvalidNibble`] if any of the values yielded by
+    /// `parent_tries_paths_nibbles` or `key_nibbles` is superior or equal to 16.
+    pub fn block_storage_get_versioned(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+    ) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+        let _timer = self.time_operation(OperationKind::StorageGet);
+        // Process the iterators at the very beginning and before locking the database, in order
+        // to avoid a deadlock in case the `next()` function of one of the iterators accesses
+        // the database as well.
+        let (key_vectored, is_main_trie) =
+            storage_key_vectored(parent_tries_paths_nibbles, key_nibbles)?;
+
+        let connection = self.read_connection();
+        block_storage_get(&connection, block_hash, &key_vectored, is_main_trie)
+    }
+
+    /// Same as [`SqliteFullDatabase::block_storage_get_versioned`], but the version of the trie
+    /// entry is returned as an integer.
+    #[deprecated(
+        note = "Use `block_storage_get_versioned`, which returns a `TrieEntryVersion`, instead"
+    )]
+    pub fn block_storage_get(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+    ) -> Result<Option<(Vec<u8>, u8)>, StorageAccessError> {
+        let value =
+            self.block_storage_get_versioned(block_hash, parent_tries_paths_nibbles, key_nibbles)?;
+        Ok(value.map(|(value, version)| (value, u8::from(version))))
+    }
+
+    /// Returns the value associated with the given key in the main trie of the given block.
+    ///
+    /// Contrary to [`SqliteFullDatabase::block_storage_get_versioned`], `key` is a list of bytes
+    /// rather than nibbles. The conversion is performed by this method, which otherwise behaves
+    /// exactly like [`SqliteFullDatabase::block_storage_get_versioned`]. Child tries can't be
+    /// accessed.
+    pub fn block_storage_get_bytes(
+        &self,
+        block_hash: &[u8; 32],
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+        self.block_storage_get_versioned(
+            block_hash,
+            core::iter::empty::<core::iter::Empty<u8>>(),
+            trie::bytes_to_nibbles(key.iter().copied()).map(u8::from),
+        )
+    }
+
+    /// Returns the blake2 hash of the value associated with the given key in the main trie of
+    /// the given block, alongside with the version of the trie entry.
+    ///
+    /// The hashes of the values are stored in the database when the values are inserted, meaning
+    /// that the value itself doesn't need to be read, which is advantageous for large values.
+    /// The hashes of the values inserted with an older version of the database are calculated
+    /// and stored the first time they are requested.
+    ///
+    /// Errors are the same as [`SqliteFullDatabase::block_storage_get_versioned`].
+    pub fn block_storage_value_hash(
+        &self,
+        block_hash: &[u8; 32],
+        key_nibbles: impl Iterator<Item = u8>,
+    ) -> Result<Option<([u8; 32], trie::TrieEntryVersion)>, StorageAccessError> {
+        let _timer = self.time_operation(OperationKind::StorageGet);
+        let (key_vectored, is_main_trie) =
+            storage_key_vectored(core::iter::empty::<core::iter::Empty<u8>>(), key_nibbles)?;
+
+        let entry = {
+            let connection = self.read_connection();
+            block_storage_entry(&connection, block_hash, &key_vectored, is_main_trie, true)?
+        };
+        let Some(entry) = entry else { return Ok(None) };
+
+        let value_hash = match (entry.value_hash, &entry.value) {
+            (Some(value_hash), _) => value_hash,
+            (None, Some(value)) => blake2_hash(value),
+            (None, None) => unreachable!(),
+        };
+
+        if let Some(node_hash) = entry.node_without_value_hash {
+            let connection = self.lock_write()?;
+            // The node might have been removed in the meanwhile, in which case nothing is
+            // updated.
+            prepare_cached(
+                &connection,
+                "UPDATE trie_node_storage SET value_hash = ? WHERE node_hash = ? AND value_hash IS NULL",
+            )
+            .map_err(sqlite_err("block_storage_value_hash"))?
+            .execute((&value_hash[..], &node_hash))
+            .map_err(sqlite_err("block_storage_value_hash"))?;
+            connection.finish(0)?;
+        }
+
+        Ok(Some((value_hash, entry.trie_entry_version)))
+    }
+
+    /// Returns the Wasm code of the runtime of the given block, in other words the value
+    /// associated with the `:code` key of its storage.
+    ///
+    /// Returns `None` if the storage of the block doesn't contain any such value.
+    pub fn block_storage_code(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        Ok(self
+            .block_storage_get_bytes(block_hash, b":code")?
+            .map(|(value, _)| value))
+    }
+
+    /// Returns the value associated with the `:heappages` key of the storage of the given block.
+    ///
+    /// Returns `None` if the storage of the block doesn't contain any such value. The value can
+    /// be turned into a number of heap pages using [`executor::storage_heap_pages_to_value`].
+    pub fn block_storage_heap_pages(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        Ok(self
+            .block_storage_get_bytes(block_hash, b":heappages")?
+            .map(|(value, _)| value))
+    }
+
+    /// Returns the key in the storage that immediately follows or is equal to the key passed as
+    /// parameter in the storage of the block.
+    ///
+    /// `key_nibbles` must be an iterator to the **nibbles** of the key.
+    ///
+    /// `prefix_nibbles` must be an iterator to nibbles. If the result of the function wouldn't
+    /// start with this specific list of bytes, `None` is returned.
+    ///
+    /// `parent_tries_paths_nibbles` is a list of keys to follow in order to find the root of the
+    /// trie into which `key_nibbles` should be searched.
+    ///
+    /// Returns `None` if `parent_tries_paths_nibbles` didn't lead to any trie, or if there is no
+    /// next key.
+    ///
+    /// The key is returned in the same format as `key_nibbles`.
+    ///
+    /// If `branch_nodes` is `false`, then branch nodes (i.e. nodes with no value associated to
+    /// them) are ignored during the search.
+    ///
+    /// > **Note**: Contrary to many other similar functions in smoldot, there is no `or_equal`
+    /// >           parameter to this function. Instead, `or_equal` is implicitly `true`, and a
+    /// >           value of `false` can be easily emulated by appending a `0` at the end
+    /// >           of `key_nibbles`.
+    ///
+    /// Returns [`StorageAccessError::InvalidNibble`] if any of the values yielded by
+    /// `parent_tries_paths_nibbles`, `key_nibbles`, or `prefix_nibbles` is superior or equal
+    /// to 16.
+    pub fn block_storage_next_key(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+        prefix_nibbles: impl Iterator<Item = u8>,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        let mut timer = self.time_operation(OperationKind::StorageNextKey);
+        // Process the iterators at the very beginning and before locking the database, in order
+        // to avoid a deadlock in case the `next()` function of one of the iterators accesses
+        // the database as well.
+        let parent_tries_paths_nibbles = parent_tries_paths_nibbles
+            .map(collect_nibbles)
+            .collect::<Result<Vec<_>, _>>()?;
+        let key_nibbles = collect_nibbles(key_nibbles)?;
+        let prefix_nibbles = collect_nibbles(prefix_nibbles)?;
+
+        let mut connection = self.read_connection();
+
+        // All the nodes are read within the same transaction in order to not observe a
+        // modification made in parallel.
+        let transaction = connection
+            .savepoint()
+            .map_err(sqlite_err("block_storage_next_key"))?;
+
+        let mut nodes_read = 0;
+        let result = block_storage_next_key(
+            &transaction,
+            block_hash,
+            &parent_tries_paths_nibbles,
+            &key_nibbles,
+            &prefix_nibbles,
+            branch_nodes,
+            &mut nodes_read,
+        );
+        timer.set_rows(nodes_read);
+        result
+    }
+
+    /// Returns the key in the main trie of the given block that immediately follows `key`, or
+    /// that is equal to `key` if `or_equal` is `true`.
+    ///
+    /// Contrary to [`SqliteFullDatabase::block_storage_next_key`], `key` and `prefix` are lists
+    /// of bytes rather than nibbles, and the key is returned as a list of bytes. The conversions
+    /// are performed by this method, which otherwise behaves exactly like
+    /// [`SqliteFullDatabase::block_storage_next_key`]. Child tries can't be accessed.
+    ///
+    /// The keys of storage values always contain an entire number of bytes. However, if
+    /// `branch_nodes` is `true`, the next node might be a branch node whose key contains an odd
+    /// number of nibbles, in which case [`StorageAccessError::OddNumberOfNibbles`] is returned.
+    pub fn block_storage_next_key_bytes(
+        &self,
+        block_hash: &[u8; 32],
+        key: &[u8],
+        prefix: &[u8],
+        or_equal: bool,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.storage_next_key_bytes(block_hash, None, key, prefix, or_equal, branch_nodes)
+    }
+
+    /// Returns the value associated with the given key in the given default child trie of the
+    /// given block.
+    ///
+    /// `child_name` is the name of the child trie, in other words the key of the main trie that
+    /// contains the root of the child trie without its `:child_storage:default:` prefix.
+    /// Otherwise behaves exactly like [`SqliteFullDatabase::block_storage_get_bytes`].
+    ///
+    /// Returns `None` if the child trie doesn't exist.
+    pub fn child_storage_get(
+        &self,
+        block_hash: &[u8; 32],
+        child_name: &[u8],
+        key: &[u8],
+    ) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+        self.block_storage_get_versioned(
+            block_hash,
+            core::iter::once(child_trie_path_nibbles(child_name)),
+            trie::bytes_to_nibbles(key.iter().copied()).map(u8::from),
+        )
+    }
+
+    /// Returns the key in the given default child trie of the given block that immediately
+    /// follows `key`, or that is equal to `key` if `or_equal` is `true`.
+    ///
+    /// See [`SqliteFullDatabase::child_storage_get`] for the meaning of `child_name`. Otherwise
+    /// behaves exactly like [`SqliteFullDatabase::block_storage_next_key_bytes`].
+    ///
+    /// Returns `None` if the child trie doesn't exist.
+    pub fn child_storage_next_key(
+        &self,
+        block_hash: &[u8; 32],
+        child_name: &[u8],
+        key: &[u8],
+        prefix: &[u8],
+        or_equal: bool,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.storage_next_key_bytes(
+            block_hash,
+            Some(child_name),
+            key,
+            prefix,
+            or_equal,
+            branch_nodes,
+        )
+    }
+
+    /// Returns the hash of the root node of the given default child trie of the given block, or
+    /// `None` if the child trie doesn't exist.
+    ///
+    /// See [`SqliteFullDatabase::child_storage_get`] for the meaning of `child_name`.
+    pub fn child_storage_root(
+        &self,
+        block_hash: &[u8; 32],
+        child_name: &[u8],
+    ) -> Result<Option<[u8; 32]>, StorageAccessError> {
+        // The Merkle value of the root node of a trie is always its hash.
+        self.block_storage_closest_descendant_merkle_value(
+            block_hash,
+            core::iter::once(child_trie_path_nibbles(child_name)),
+            core::iter::empty(),
+        )?
+        .map(|merkle_value| {
+            <[u8; 32]>::try_from(merkle_value)
+                .map_err(|_| StorageAccessError::Corrupted(CorruptedError::InvalidTrieHashLen))
+        })
+        .transpose()
+    }
+
+    /// Returns the number of trie nodes reachable from the root of the storage of the given
+    /// block, including the nodes of the child tries, and their size.
+    ///
+    /// This walks through the entire storage of the block, and is thus slow.
+    ///
+    /// Trie nodes that are missing from the database are ignored. See
+    /// [`SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`].
+    pub fn trie_stats(&self, block_hash: &[u8; 32]) -> Result<TrieStats, StorageAccessError> {
+        let mut connection = self.read_connection();
+        let connection = connection.savepoint().map_err(sqlite_err("trie_stats"))?;
+        let root = block_state_trie_root(&connection, block_hash)?;
+        Ok(trie_sharing_stats(&connection, &root, None)?.only_a)
+    }
+
+    /// Returns the number of trie nodes, and their size, that are only reachable from the
+    /// storage of `block_a`, only reachable from the storage of `block_b`, or reachable from
+    /// both.
+    ///
+    /// Because trie nodes are identified by their hash, the nodes that are identical between
+    /// the storage of two blocks are only stored once. This method makes it possible to find out
+    /// how much space the storage of a block occupies in addition to the one of another block,
+    /// for example in order to estimate the cost of keeping a fork.
+    ///
+    /// Same remarks as [`SqliteFullDatabase::trie_stats`].
+    pub fn trie_dedup_stats(
+        &self,
+        block_a: &[u8; 32],
+        block_b: &[u8; 32],
+    ) -> Result<DedupStats, StorageAccessError> {
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("trie_dedup_stats"))?;
+        let root_a = block_state_trie_root(&connection, block_a)?;
+        let root_b = block_state_trie_root(&connection, block_b)?;
+        Ok(trie_sharing_stats(&connection, &root_a, Some(&root_b))?)
+    }
+
+    /// Returns, for each of the given Merkle values, the encoding of the corresponding trie node
+    /// as found in Merkle proofs, or `None` if the node isn't in the database.
+    ///
+    /// The encoding is reconstructed from the components of the node that are stored in the
+    /// database, and is the one whose hash is the requested Merkle value. Storage values are
+    /// replaced with their hash when the version of their trie entry is
+    /// [`trie::TrieEntryVersion::V1`] and they are at least 33 bytes long.
+    ///
+    /// This is useful in order to serve the trie nodes requested by other peers.
+    pub fn trie_nodes_wire_encoded(
+        &self,
+        hashes: impl Iterator<Item = [u8; 32]>,
+    ) -> Result<Vec<Option<Vec<u8>>>, CorruptedError> {
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("trie_nodes_wire_encoded"))?;
+
+        let mut statement = prepare_cached(
+            &connection,
+            r#"
+            SELECT
+                unpack_nibbles(trie_node.partial_key), trie_node.children,
+                trie_node_storage.node_hash IS NOT NULL,
+                COALESCE(decompress_value(trie_node_storage.value, trie_node_storage.value_compressed), trie_node_storage.trie_root_ref),
+                trie_node_storage.trie_entry_version
+            FROM trie_node
+            LEFT JOIN trie_node_storage ON trie_node_storage.node_hash = trie_node.hash
+            WHERE trie_node.hash = ?"#,
+        )
+        .map_err(sqlite_err("trie_nodes_wire_encoded"))?;
+
+        let mut out = Vec::with_capacity(hashes.size_hint().0);
+        for hash in hashes {
+            let Some((partial_key, children, has_storage_value, storage_value, entry_version)) =
+                statement
+                    .query_row((&hash[..],), |row| {
+                        Ok((
+                            row.get::<_, Vec<u8>>(0)?,
+                            row.get::<_, Option<Vec<u8>>>(1)?,
+                            row.get::<_, bool>(2)?,
+                            row.get::<_, Option<Vec<u8>>>(3)?,
+                            row.get::<_, Option<i64>>(4)?,
+                        ))
+                    })
+                    .optional()
+                    .map_err(sqlite_err("trie_nodes_wire_encoded"))?
+            else {
+                out.push(None);
+                continue;
+            };
+
+            let children = match &children {
+                Some(children) => {
+                    decode_children(children).ok_or(CorruptedError::InvalidTrieNodeChildren)?
+                }
+                None => [None; 16],
+            };
+
+            let value_hash;
+            let storage_value = match (has_storage_value, &storage_value, entry_version) {
+                (false, _, _) => trie::trie_node::StorageValue::None,
+                (true, Some(value), Some(entry_version)) => {
+                    let entry_version = u8::try_from(entry_version)
+                        .ok()
+                        .and_then(|version| trie::TrieEntryVersion::try_from(version).ok())
+                        .ok_or(CorruptedError::InvalidTrieEntryVersion)?;
+                    if matches!(entry_version, trie::TrieEntryVersion::V1) && value.len() >= 33 {
+                        value_hash = blake2_hash(value);
+                        trie::trie_node::StorageValue::Hashed(&value_hash)
+                    } else {
+                        trie::trie_node::StorageValue::Unhashed(value)
+                    }
+                }
+                (true, _, _) => return Err(CorruptedError::InvalidTrieEntryVersion),
+            };
+
+            let encoded = trie::trie_node::encode_to_vec(trie::trie_node::Decoded {
+                partial_key: partial_key.iter().map(|nibble| {
+                    trie::Nibble::try_from(*nibble).unwrap_or_else(|_| unreachable!())
+                }),
+                children,
+                storage_value,
+            })
+            .map_err(|_| CorruptedError::InvalidTrieNodeChildren)?;
+            out.push(Some(encoded));
+        }
+
+        Ok(out)
+    }
+
+    /// Returns `true` if the trie node with the given Merkle value is in the database.
+    ///
+    /// Trie nodes are shared between all the chains of the database (see
+    /// [`Config::chain_name`]).
+    pub fn trie_node_exists(&self, merkle_value: &[u8]) -> Result<bool, CorruptedError> {
+        let connection = self.read_connection();
+        let exists = prepare_cached(
+            &connection,
+            r#"SELECT EXISTS(SELECT 1 FROM trie_node WHERE hash = ?)"#,
+        )
+        .map_err(sqlite_err("trie_node_exists"))?
+        .query_row((merkle_value,), |row| row.get::<_, bool>(0))
+        .map_err(sqlite_err("trie_node_exists"))?;
+        Ok(exists)
+    }
+
+    /// Returns the hashes and numbers of the blocks whose storage contains the trie node with
+    /// the given Merkle value, either in their main trie or in one of their child tries, ordered
+    /// by increasing number.
+    ///
+    /// The parents of the node are walked up until the roots of the main tries of the blocks.
+    /// Blocks whose trie refers to this node are returned even if the node itself is missing
+    /// from the database.
+    ///
+    /// Returns [`CorruptedError::TrieLoopDetected`] if the node has an unreasonably large
+    /// number of levels of ancestors, which can only happen if there is a loop in the trie.
+    ///
+    /// > **Note**: Nodes can be shared by a large number of tries, in which case this function
+    /// >           can take a long time.
+    pub fn trie_node_referencing_blocks(
+        &self,
+        merkle_value: &[u8],
+    ) -> Result<Vec<([u8; 32], u64)>, CorruptedError> {
+        let connection = self.read_connection();
+
+        // The recursion stops at a depth of `:max_trie_depth` in order to not loop
+        // indefinitely if there is a loop in the trie, in which case a row made of `NULL`s is
+        // returned first.
+        let mut statement = prepare_cached(
+            &connection,
+            r#"
+            WITH RECURSIVE
+                ancestors(node_hash, depth) AS (
+                    VALUES (:merkle_value, 0)
+                    UNION
+                    SELECT trie_node_parent.hash, ancestors.depth + 1
+                        FROM ancestors
+                        JOIN trie_node_parent ON trie_node_parent.child_hash = ancestors.node_hash
+                        WHERE ancestors.depth < :max_trie_depth
+                    UNION
+                    SELECT trie_node_storage.node_hash, ancestors.depth + 1
+                        FROM ancestors
+                        JOIN trie_node_storage ON trie_node_storage.trie_root_ref = ancestors.node_hash
+                        WHERE ancestors.depth < :max_trie_depth
+                )
+            SELECT DISTINCT blocks.hash AS hash, blocks.number AS number
+            FROM ancestors
+            JOIN blocks ON blocks.state_trie_root_hash = ancestors.node_hash
+            WHERE blocks.chain_id = (SELECT id FROM current_chain)
+            UNION ALL
+            SELECT NULL, NULL
+            WHERE EXISTS(SELECT 1 FROM ancestors WHERE depth >= :max_trie_depth)
+            ORDER BY number ASC, hash ASC
+            "#,
+        )
+        .map_err(sqlite_err("trie_node_referencing_blocks"))?;
+        let rows = statement
+            .query_map(
+                rusqlite::named_params! {
+                    ":merkle_value": merkle_value,
+                    ":max_trie_depth": i64::try_from(MAX_TRIE_DEPTH).unwrap(),
+                },
+                |row| {
+                    Ok((
+                        row.get::<_, Option<Vec<u8>>>(0)?,
+                        row.get::<_, Option<i64>>(1)?,
+                    ))
+                },
+            )
+            .map_err(sqlite_err("trie_node_referencing_blocks"))?;
+
+        let mut blocks = Vec::new();
+        for row in rows {
+            let (Some(hash), Some(number)) =
+                row.map_err(sqlite_err("trie_node_referencing_blocks"))?
+            else {
+                return Err(CorruptedError::TrieLoopDetected);
+            };
+            let hash =
+                <[u8; 32]>::try_from(&hash[..]).map_err(|_| CorruptedError::InvalidBlockHashLen)?;
+            let number = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
+            blocks.push((hash, number));
+        }
+        Ok(blocks)
+    }
+
+    /// Returns statistics about the forks of the chain. See [`ForkMetrics`].
+    ///
+    /// A quickly-growing number of leaves or of non-best blocks can indicate that finality is
+    /// stalled, or that block producers are equivocating.
+    pub fn fork_metrics(&self) -> Result<ForkMetrics, CorruptedError> {
+        let mut connection = self.read_connection();
+        let connection = connection.savepoint().map_err(sqlite_err("fork_metrics"))?;
+        let finalized_number = i64::try_from(finalized_num(&connection)?)
+            .map_err(|_| CorruptedError::InvalidNumber)?;
+
+        let (leaves, max_fork_depth, non_best_blocks) = prepare_cached(
+            &connection,
+            r#"
+            WITH RECURSIVE
+                -- Non-best blocks above the finalized block, alongside with their distance to
+                -- the block of the best chain they branch from.
+                forks(hash, depth) AS (
+                    SELECT child.hash, 1
+                    FROM blocks AS child
+                    JOIN blocks AS parent ON parent.hash = child.parent_hash
+                    WHERE child.chain_id = (SELECT id FROM current_chain)
+                        AND child.number > :finalized_number
+                        AND child.is_best_chain = FALSE AND parent.is_best_chain = TRUE
+                UNION ALL
+                    SELECT child.hash, forks.depth + 1
+                    FROM forks
+                    JOIN blocks AS child ON child.parent_hash = forks.hash
+                )
+            SELECT
+                (SELECT COUNT(*) FROM blocks
+                    WHERE chain_id = (SELECT id FROM current_chain)
+                        AND (number > :finalized_number OR (number = :finalized_number AND is_best_chain = TRUE))
+                        AND NOT EXISTS (SELECT 1 FROM blocks AS child WHERE child.parent_hash = blocks.hash)),
+                (SELECT COALESCE(MAX(depth), 0) FROM forks),
+                (SELECT COUNT(*) FROM blocks
+                    WHERE chain_id = (SELECT id FROM current_chain) AND is_best_chain = FALSE)
+            "#,
+        )
+        .map_err(sqlite_err("fork_metrics"))?
+        .query_row(
+            rusqlite::named_params! {
+                ":finalized_number": finalized_number,
+            },
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            },
+        )
+        .map_err(sqlite_err("fork_metrics"))?;
+
+        Ok(ForkMetrics {
+            leaves: u64::try_from(leaves).unwrap_or(0),
+            max_fork_depth: u64::try_from(max_fork_depth).unwrap_or(0),
+            non_best_blocks: u64::try_from(non_best_blocks).unwrap_or(0),
+        })
+    }
+
+    /// Shared implementation of [`SqliteFullDatabase::block_storage_next_key_bytes`] and
+    /// [`SqliteFullDatabase::child_storage_next_key`].
+    fn storage_next_key_bytes(
+        &self,
+        block_hash: &[u8; 32],
+        child_name: Option<&[u8]>,
+        key: &[u8],
+        prefix: &[u8],
+        or_equal: bool,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        // The smallest key strictly superior to `key` is `key` followed with a `0` nibble.
+        let key_nibbles = trie::bytes_to_nibbles(key.iter().copied())
+            .map(u8::from)
+            .chain(if or_equal { None } else { Some(0) });
+
+        let Some(next_key) = self.block_storage_next_key(
+            block_hash,
+            child_name.map(child_trie_path_nibbles).into_iter(),
+            key_nibbles,
+            trie::bytes_to_nibbles(prefix.iter().copied()).map(u8::from),
+            branch_nodes,
+        )?
+        else {
+            return Ok(None);
+        };
+
+        if next_key.len() % 2 != 0 {
+            return Err(StorageAccessError::OddNumberOfNibbles);
+        }
+
+        Ok(Some(
+            next_key
+                .chunks_exact(2)
+                .map(|nibbles| (nibbles[0] << 4) | nibbles[1])
+                .collect(),
+        ))
+    }
+
+    /// Returns the Merkle value of the trie node in the storage that is the closest descendant
+    /// of the provided key.
+    ///
+    /// `key_nibbles` must be an iterator to the **nibbles** of the key.
+    ///
+    /// `parent_tries_paths_nibbles` is a list of keys to follow in order to find the root of the
+    /// trie into which `key_nibbles` should be searched.
+    ///
+    /// Returns `None` if `parent_tries_paths_nibbles` didn't lead to any trie, or if there is no
+    /// such descendant.
+    ///
+    /// Returns [`StorageAccessError::InvalidNibble`] if any of the values yielded by
+    /// `parent_tries_paths_nibbles` or `key_nibbles` is superior or equal to 16.
+    pub fn block_storage_closest_descendant_merkle_value(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        let _timer = self.time_operation(OperationKind::StorageClosestDescendantMerkleValue);
+        // Process the iterators at the very beginning and before locking the database, in order
+        // to avoid a deadlock in case the `next()` function of one of the iterators accesses
+        // the database as well.
+        let (key_vectored, _) = storage_key_vectored(parent_tries_paths_nibbles, key_nibbles)?;
+
+        let connection = self.read_connection();
+        block_storage_closest_descendant_merkle_value(&connection, block_hash, &key_vectored)
+    }
+
+    /// Inserts a block in the database and sets it as the finalized block.
+    ///
+    /// The parent of the block doesn't need to be present in the database.
+    ///
+    /// If the block is already in the database, it is replaced by the one provided.
+    ///
+    /// The other blocks and the trie nodes are left in the database. See
+    /// [`SqliteFullDatabase::reset_and_wipe`].
+    pub fn reset<'a>(
+        &self,
+        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
+        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
+        finalized_block_justification: Option<Vec<u8>>,
+    ) -> Result<(), ResetError> {
+        self.reset_inner(
+            chain_information,
+            finalized_block_body,
+            finalized_block_justification,
+            false,
+        )
+    }
+
+    /// Same as [`SqliteFullDatabase::reset`], but additionally removes, within the same
+    /// transaction, all the other blocks of the chain alongside with their bodies, and all the
+    /// trie nodes that aren't reachable from the remaining blocks.
+    ///
+    /// This is typically used after a warp sync towards a block that is far away from the
+    /// current finalized block, as the blocks and the storage that the database contains are
+    /// then useless.
+    ///
+    /// The blocks of the other chains of the database (see [`Config::chain_name`]) and their
+    /// trie nodes are kept.
+    ///
+    /// > **Note**: Finding the unreachable trie nodes requires going through the tries of all
+    /// >           the remaining blocks, which is slow if the database is large.
+    pub fn reset_and_wipe<'a>(
+        &self,
+        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
+        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
+        finalized_block_justification: Option<Vec<u8>>,
+    ) -> Result<(), ResetError> {
+        self.reset_inner(
+            chain_information,
+            finalized_block_body,
+            finalized_block_justification,
+            true,
+        )
+    }
+
+    fn reset_inner<'a>(
+        &self,
+        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
+        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
+        finalized_block_justification: Option<Vec<u8>>,
+        wipe: bool,
+    ) -> Result<(), ResetError> {
+        let _timer = self.time_operation(OperationKind::Reset);
+        let chain_information = chain_information.into();
+
+        let finalized_block_hash = chain_information
+            .finalized_block_header
+            .hash(self.block_number_bytes);
+
+        // The header is stored in its encoded form, and must decode back into the same header
+        // later. This is verified before anything is written to the database.
+        let scale_encoded_finalized_block_header = encode_header_checked(
+            &chain_information.finalized_block_header,
+            self.block_number_bytes,
+        )
+        .ok_or(ResetError::HeaderRoundTripMismatch)?;
+        if header::hash_from_scale_encoded_header(&scale_encoded_finalized_block_header)
+            != finalized_block_hash
+        {
+            return Err(ResetError::HeaderRoundTripMismatch);
+        }
+
+        // Start a transaction to insert everything in one go.
+        let mut database = self.lock_write()?;
+        let transaction = database.savepoint().map_err(sqlite_err("reset"))?;
+
+        // Blocks are identified by their hash alone. A block that is part of another chain of
+        // the database, which is typically the case of the genesis block of a chain that has the
+        // same genesis, can't also be part of the current chain.
+        if block_of_other_chain(&transaction, &finalized_block_hash)? {
+            return Err(ResetError::BlockOfOtherChain);
+        }
+
+        // Temporarily disable foreign key checks in order to make the initial insertion easier,
+        // as we don't have to make sure that trie nodes are sorted.
+        // Note that this is immediately disabled again when the transaction is committed.
+        transaction
+            .execute("PRAGMA defer_foreign_keys = ON", ())
+            .map_err(sqlite_err("reset"))?;
+
+        prepare_cached(
+            &transaction,
+            "INSERT OR REPLACE INTO blocks(chain_id, hash, parent_hash, state_trie_root_hash, number, header, is_best_chain) VALUES((SELECT id FROM current_chain), ?, ?, ?, ?, ?, TRUE)",
+        )
+        .map_err(sqlite_err("reset"))?
+        .execute((
+            &finalized_block_hash[..],
+            if chain_information.finalized_block_header.number != 0 {
+                Some(&chain_information.finalized_block_header.parent_hash[..])
+            } else { None },
+            &chain_information.finalized_block_header.state_root[..],
+            i64::try_from(chain_information.finalized_block_header.number)
+                .map_err(|_| CorruptedError::InvalidNumber)?,
+            &scale_encoded_finalized_block_header[..],
+        ))
+        .map_err(sqlite_err("reset"))?;
+
+        set_block_justification(
+            &transaction,
+            &finalized_block_hash,
+            &GRANDPA_ENGINE_ID,
+            finalized_block_justification.as_deref(),
+        )?;
+
+        transaction
+            .execute(
+                "DELETE FROM blocks_body WHERE hash = ?",
+                (&finalized_block_hash[..],),
+            )
+            .map_err(sqlite_err("reset"))?;
+
+        for (index, item) in finalized_block_body.enumerate() {
+            insert_body_extrinsic(
+                &transaction,
+                &finalized_block_hash,
+                i64::try_from(index).map_err(|_| CorruptedError::InvalidNumber)?,
+                item,
+            )
+            .map_err(sqlite_err("reset"))?;
+        }
+
+        if wipe {
+            wipe_except_block(&transaction, &finalized_block_hash)?;
+        }
+
+        meta_set_blob(&transaction, "best", &finalized_block_hash[..])?;
+        meta_set_number(
+            &transaction,
+            "finalized",
+            chain_information.finalized_block_header.number,
+        )?;
+
+        meta_clear(&transaction, "grandpa_authorities_set_id")?;
+        meta_clear(&transaction, "grandpa_scheduled_target")?;
+        transaction
+            .execute("DELETE FROM grandpa_triggered_authorities WHERE chain_id = (SELECT id FROM current_chain);", ())
+            .map_err(sqlite_err("reset"))?;
+        transaction
+            .execute("DELETE FROM grandpa_scheduled_authorities WHERE chain_id = (SELECT id FROM current_chain);", ())
+            .map_err(sqlite_err("reset"))?;
+
+        match &chain_information.finality {
+            chain_information::ChainInformationFinalityRef::Outsourced => {}
+            chain_information::ChainInformationFinalityRef::Grandpa {
+                finalized_triggered_authorities,
+                after_finalized_block_authorities_set_id,
+                finalized_scheduled_change,
+            } => {
+                meta_set_u64_blob(
+                    &transaction,
+                    "grandpa_authorities_set_id",
+                    *after_finalized_block_authorities_set_id,
+                )?;
+
+                let mut statement = prepare_cached(&transaction, "INSERT INTO grandpa_triggered_authorities(chain_id, idx, public_key, weight) VALUES((SELECT id FROM current_chain), ?, ?, ?)")
+                    .map_err(sqlite_err("reset"))?;
+                for (index, item) in finalized_triggered_authorities.iter().enumerate() {
+                    statement
+                        .execute((
+                            i64::try_from(index).map_err(|_| CorruptedError::InvalidNumber)?,
+                            &item.public_key[..],
+                            encode_u64(item.weight.get()),
+                        ))
+                        .map_err(sqlite_err("reset"))?;
+                }
+
+                if let Some((height, list)) = finalized_scheduled_change {
+                    meta_set_number(&transaction, "grandpa_scheduled_target", *height)?;
+
+                    let mut statement = prepare_cached(&transaction, "INSERT INTO grandpa_scheduled_authorities(chain_id, idx, public_key, weight) VALUES((SELECT id FROM current_chain), ?, ?, ?)")
+                        .map_err(sqlite_err("reset"))?;
+                    for (index, item) in list.iter().enumerate() {
+                        statement
+                            .execute((
+                                i64::try_from(index).map_err(|_| CorruptedError::InvalidNumber)?,
+                                &item.public_key[..],
+                                encode_u64(item.weight.get()),
+                            ))
+                            .map_err(sqlite_err("reset"))?;
+                    }
+                }
+            }
+        }
+
+        meta_clear(&transaction, "aura_slot_duration")?;
+        transaction
+            .execute("DELETE FROM aura_finalized_authorities WHERE chain_id = (SELECT id FROM current_chain);", ())
+            .map_err(sqlite_err("reset"))?;
+        meta_clear(&transaction, "babe_slots_per_epoch")?;
+        meta_clear(&transaction, "babe_finalized_next_epoch")?;
+        meta_clear(&transaction, "babe_finalized_epoch")?;
+
+        match &chain_information.consensus {
+            chain_information::ChainInformationConsensusRef::Unknown => {}
+            chain_information::ChainInformationConsensusRef::Aura {
+                finalized_authorities_list,
+                slot_duration,
+            } => {
+                meta_set_u64_blob(&transaction, "aura_slot_duration", slot_duration.get())?;
+
+                let mut statement = prepare_cached(
+                    &transaction,
+                    "INSERT INTO aura_finalized_authorities(chain_id, idx, public_key) VALUES((SELECT id FROM current_chain), ?, ?)",
+                )
+                .map_err(sqlite_err("reset"))?;
+                for (index, item) in finalized_authorities_list.clone().enumerate() {
+                    statement
+                        .execute((
+                            i64::try_from(index).map_err(|_| CorruptedError::InvalidNumber)?,
+                            &item.public_key[..],
+                        ))
+                        .map_err(sqlite_err("reset"))?;
+                }
+            }
+            chain_information::ChainInformationConsensusRef::Babe {
+                slots_per_epoch,
+                finalized_next_epoch_transition,
+                finalized_block_epoch_information,
+            } => {
+                meta_set_u64_blob(&transaction, "babe_slots_per_epoch", slots_per_epoch.get())?;
+                meta_set_blob(
+                    &transaction,
+                    "babe_finalized_next_epoch",
+                    &encode_babe_epoch_information(finalized_next_epoch_transition.clone())[..],
+                )?;
+
+                if let Some(finalized_block_epoch_information) = finalized_block_epoch_information {
+                    meta_set_blob(
+                        &transaction,
+                        "babe_finalized_epoch",
+                        &encode_babe_epoch_information(finalized_block_epoch_information.clone())[..],
+                    )?;
+                }
+            }
+        }
+
+        transaction.commit().map_err(sqlite_err("reset"))?;
+        let number = chain_information.finalized_block_header.number;
+        database.finish_with_events(
+            0,
+            [
+                Event::NewBest {
+                    hash: finalized_block_hash,
+                    number,
+                },
+                Event::Finalized {
+                    hash: finalized_block_hash,
+                    number,
+                    retracted: Vec::new(),
+                },
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Marks the initialization of the database as complete, then commits.
+    ///
+    /// Must be called after [`DatabaseEmpty::initialize_incomplete`] once everything that is
+    /// part of the initialization has been inserted. Has no effect if the initialization is
+    /// already complete.
+    pub fn finish_initialization(&self) -> Result<(), CorruptedError> {
+        let database = self.lock_write()?;
+        meta_set_number(&database, "initialized", 1)?;
+        database.finish(0)?;
+
+        // The initialization is always committed, in order for the database to be considered
+        // as non-empty when it is opened again.
+        self.flush()
+    }
+}
+
+impl fmt::Debug for SqliteFullDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("SqliteFullDatabase");
+        match &self.path {
+            Some(path) => debug.field("path", path),
+            None => debug.field("path", &":memory:"),
+        };
+        if let Some(chain_name) = &self.chain_name {
+            debug.field("chain_name", chain_name);
+        }
+        debug.field("block_number_bytes", &self.block_number_bytes);
+
+        // The numbers are only printed if they can be obtained without waiting, as printing
+        // shouldn't be blocked by an operation performed in parallel.
+        if let Some(database) = self.database.try_lock() {
+            if let Ok(number) = best_block_number(&database) {
+                debug.field("best_number", &number);
+            }
+            if let Ok(number) = finalized_num(&database) {
+                debug.field("finalized_number", &number);
+            }
+        }
+
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl Drop for SqliteFullDatabase {
+    fn drop(&mut self) {
+        // Modifications that have been performed successfully are committed, even in case of a
+        // panic, as they leave the database in a consistent state.
+        let _ = self.flush();
+
+        if !std::thread::panicking() {
+            // The SQLite documentation recommends running `PRAGMA optimize` when the database
+            // closes. See also `SqliteFullDatabase::maintain`.
+            let _ = self.database.get_mut().execute("PRAGMA optimize", ());
+        }
+    }
+}
+
+/// Exclusive access to the main connection. See [`SqliteFullDatabase::lock_write`].
+struct WriteGuard<'a> {
+    database: &'a SqliteFullDatabase,
+    connection: MutexGuard<'a, rusqlite::Connection>,
+    _statement_cache_stats: metrics::StatementCacheStatsGuard,
+}
+
+impl<'a> WriteGuard<'a> {
+    /// Must be called after a modification has been successfully performed. `num_blocks` is
+    /// the number of blocks that this modification has inserted.
+    ///
+    /// Commits the transaction if demanded by the [`CommitPolicy`].
+    fn finish(self, num_blocks: u64) -> Result<(), CorruptedError> {
+        self.finish_with_events(num_blocks, [])
+    }
+
+    /// Same as [`WriteGuard::finish`], but additionally queues events describing the
+    /// modification.
+    ///
+    /// The events are sent to the subscribers only once the modification has actually been
+    /// committed, which can happen during a later call if the [`CommitPolicy`] delays commits.
+    fn finish_with_events(
+        self,
+        num_blocks: u64,
+        events: impl IntoIterator<Item = Event>,
+    ) -> Result<(), CorruptedError> {
+        if let Some(interval) = self.database.auto_optimize_interval {
+            if self.database.last_optimize.lock().elapsed() >= interval {
+                // The modification has already succeeded at this point, and failing to optimize
+                // isn't a reason to report an error.
+                let _ = self.optimize(false);
+            }
+        }
+
+        let mut uncommitted = self.database.uncommitted_writes.lock();
+        let writes = uncommitted.get_or_insert_with(|| UncommittedWrites {
+            num_blocks: 0,
+            since: Instant::now(),
+            events: Vec::new(),
+        });
+        writes.num_blocks = writes.num_blocks.saturating_add(num_blocks);
+        writes.events.extend(events);
+
+        let must_commit = match self.database.commit_policy {
+            _ if self.database.bulk_load_active.load(Ordering::Relaxed) => {
+                writes.num_blocks >= bulk_load::BLOCKS_PER_COMMIT
+            }
+            CommitPolicy::EveryOperation => true,
+            CommitPolicy::EveryNBlocks(num) => writes.num_blocks >= num.get(),
+            CommitPolicy::EveryDuration(duration) => writes.since.elapsed() >= duration,
+        };
+
+        if !must_commit {
+            return Ok(());
+        }
+
+        prepare_cached(&self.connection, "COMMIT")
+            .and_then(|mut statement| statement.execute(()))
+            .map_err(sqlite_err("finish"))?;
+        drop(uncommitted);
+
+        // Events are sent after the database has been unlocked.
+        let database = self.database;
+        let events = database.clear_uncommitted_writes();
+        drop(self);
+        database.emit_events(events);
+        Ok(())
+    }
+}
+
+impl<'a> WriteGuard<'a> {
+    /// Runs `PRAGMA optimize`, or `ANALYZE` if `allow_analyze` is `true` and
+    /// [`SqliteFullDatabase::analyze_pending`] is `true`. See [`SqliteFullDatabase::maintain`].
+    fn optimize(&self, allow_analyze: bool) -> Result<(), CorruptedError> {
+        *self.database.last_optimize.lock() = Instant::now();
+
+        if allow_analyze && self.database.analyze_pending.swap(false, Ordering::Relaxed) {
+            if let Err(err) = self.connection.execute_batch("ANALYZE") {
+                self.database.analyze_pending.store(true, Ordering::Relaxed);
+                return Err(sqlite_err("optimize")(err));
+            }
+            return Ok(());
+        }
+
+        self.connection
+            .execute_batch("PRAGMA optimize")
+            .map_err(sqlite_err("optimize"))
+    }
+}
+
+impl<'a> ops::Deref for WriteGuard<'a> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &rusqlite::Connection {
+        &self.connection
+    }
+}
+
+impl<'a> ops::DerefMut for WriteGuard<'a> {
+    fn deref_mut(&mut self) -> &mut rusqlite::Connection {
+        &mut self.connection
+    }
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+    fn drop(&mut self) {
+        // If the modification has failed and that no previous modification is waiting to be
+        // committed, the transaction is ended in order to not keep the database locked.
+        if !self.connection.is_autocommit() && self.database.uncommitted_writes.lock().is_none() {
+            let _ = self.connection.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+/// Access to a connection used for reading. See [`SqliteFullDatabase::read_connection`].
+struct ReadGuard<'a> {
+    connection: MutexGuard<'a, rusqlite::Connection>,
+    _statement_cache_stats: metrics::StatementCacheStatsGuard,
+}
+
+impl<'a> ops::Deref for ReadGuard<'a> {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &rusqlite::Connection {
+        &self.connection
+    }
+}
+
+impl<'a> ops::DerefMut for ReadGuard<'a> {
+    fn deref_mut(&mut self) -> &mut rusqlite::Connection {
+        &mut self.connection
+    }
+}
+
+/// Modifications that haven't been committed yet. See
+/// [`SqliteFullDatabase::uncommitted_writes`].
+struct UncommittedWrites {
+    /// Number of blocks inserted since the last commit.
+    num_blocks: u64,
+    /// Moment when the oldest uncommitted modification has been performed.
+    since: Instant,
+    /// Events describing the uncommitted modifications, sent to the subscribers once the
+    /// modifications are committed.
+    events: Vec<Event>,
+}
+
+/// Information about the arrival of a block. See [`SqliteFullDatabase::insert`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertMeta {
+    /// Moment when the block has been received, as a number of milliseconds since the UNIX
+    /// epoch.
+    pub inserted_at_unix_ms: u64,
+    /// Free-form description of where the block comes from, for example the identity of the
+    /// peer that has sent it.
+    pub source: String,
+}
+
+/// Entry of the `meta` table. See [`SqliteFullDatabase::meta_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetaEntry {
+    /// Name of the entry.
+    pub key: String,
+    /// Value of the entry, if it is a number.
+    pub value_number: Option<u64>,
+    /// Value of the entry, if it is a blob. Truncated to [`MetaEntry::MAX_BLOB_LEN`] bytes.
+    pub value_blob: Option<Vec<u8>>,
+    /// Length in bytes of the non-truncated blob value, if the value is a blob.
+    pub value_blob_len: Option<usize>,
+}
+
+impl MetaEntry {
+    /// Maximum number of bytes of [`MetaEntry::value_blob`].
+    pub const MAX_BLOB_LEN: usize = 128;
+}
+
+/// Number of trie nodes and their size. See [`SqliteFullDatabase::trie_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrieStats {
+    /// Number of trie nodes.
+    pub nodes: u64,
+    /// Total size in bytes of the partial keys, children, and storage values of the trie nodes.
+    ///
+    /// This doesn't include the overhead of the database, such as the indices, and is thus only
+    /// an approximation of the space that the trie nodes occupy on disk. Storage values are
+    /// counted after compression, see [`Config::value_compression`].
+    pub bytes: u64,
+}
+
+/// Sharing of trie nodes between the storage of two blocks. See
+/// [`SqliteFullDatabase::trie_dedup_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Trie nodes only reachable from the storage of the first block.
+    pub only_a: TrieStats,
+    /// Trie nodes only reachable from the storage of the second block.
+    pub only_b: TrieStats,
+    /// Trie nodes reachable from the storage of both blocks.
+    pub shared: TrieStats,
+}
+
+/// Statistics about the forks of the chain. See [`SqliteFullDatabase::fork_metrics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForkMetrics {
+    /// Number of blocks that don't have any child, among the finalized block and the blocks
+    /// above it. Includes the best block.
+    pub leaves: u64,
+    /// Maximum number of blocks of a fork above the finalized block, counted from the block of
+    /// the best chain the fork branches from. `0` if there isn't any fork.
+    pub max_fork_depth: u64,
+    /// Number of blocks that aren't part of the best chain, including the ones below the
+    /// finalized block that haven't been purged yet. See
+    /// [`SqliteFullDatabase::purge_finality_orphans`].
+    pub non_best_blocks: u64,
+}
+
+/// Consensus-related items of the digest of a block. See
+/// [`SqliteFullDatabase::block_digest_summary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DigestSummary {
+    /// Consensus engine whose pre-runtime item is found in the digest, or `None` if there is
+    /// none, for example for the genesis block.
+    pub consensus_engine: Option<ConsensusEngine>,
+    /// Slot during which the block has been produced, as indicated by the pre-runtime item.
+    pub slot_number: Option<u64>,
+    /// Index of the authority that has produced the block, within the list of authorities of the
+    /// epoch. Always `None` for Aura, as Aura pre-runtime items don't contain it.
+    pub authority_index: Option<u32>,
+    /// `true` if the digest announces the next Babe epoch.
+    pub epoch_change: bool,
+    /// `true` if the digest contains a Grandpa scheduled or forced change, or an Aura
+    /// authorities change.
+    pub authorities_change: bool,
+    /// `true` if the digest contains a seal, in other words if the header is signed.
+    pub has_seal: bool,
+}
+
+impl DigestSummary {
+    fn from_digest(digest: header::DigestRef) -> Self {
+        let (consensus_engine, slot_number, authority_index) =
+            if let Some(pre_digest) = digest.babe_pre_runtime() {
+                (
+                    Some(ConsensusEngine::Babe),
+                    Some(pre_digest.slot_number()),
+                    Some(pre_digest.authority_index()),
+                )
+            } else if let Some(pre_digest) = digest.aura_pre_runtime() {
+                (
+                    Some(ConsensusEngine::Aura),
+                    Some(pre_digest.slot_number),
+                    None,
+                )
+            } else {
+                (None, None, None)
+            };
+
+        DigestSummary {
+            consensus_engine,
+            slot_number,
+            authority_index,
+            epoch_change: digest.babe_epoch_information().is_some(),
+            authorities_change: digest.logs().any(|item| {
+                matches!(
+                    item,
+                    header::DigestItemRef::GrandpaConsensus(
+                        header::GrandpaConsensusLogRef::ScheduledChange(_)
+                            | header::GrandpaConsensusLogRef::ForcedChange { .. }
+                    ) | header::DigestItemRef::AuraConsensus(
+                        header::AuraConsensusLogRef::AuthoritiesChange(_)
+                    )
+                )
+            }),
+            has_seal: digest.logs().any(|item| {
+                matches!(
+                    item,
+                    header::DigestItemRef::BabeSeal(_)
+                        | header::DigestItemRef::AuraSeal(_)
+                        | header::DigestItemRef::UnknownSeal { .. }
+                )
+            }),
+        }
+    }
+}
+
+/// Consensus engine that has produced a block. See [`DigestSummary::consensus_engine`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ConsensusEngine {
+    /// Aura consensus engine.
+    Aura,
+    /// Babe consensus engine.
+    Babe,
+}
+
+/// Information about a block of the database. See [`SqliteFullDatabase::block_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockRef {
+    /// Hash of the block.
+    pub hash: [u8; 32],
+    /// Height of the block.
+    pub number: u64,
+    /// Hash of the parent of the block. `None` if the block is the first block that has been
+    /// inserted in the database, in which case its parent isn't in the database.
+    pub parent_hash: Option<[u8; 32]>,
+    /// Root of the state trie of the block. `None` if the trie is empty or if the storage of the
+    /// block has been pruned.
+    pub state_trie_root: Option<[u8; 32]>,
+    /// `true` if the block is part of the chain going from the finalized block to the best block.
+    pub is_best_chain: bool,
+    /// `true` if the body of the block is available, in other words if it hasn't been pruned.
+    pub has_body: bool,
+    /// `true` if a justification of the block is stored in the database.
+    pub has_justification: bool,
+}
+
+/// Justifications of a block, with the identifier of the consensus engine they belong to,
+/// ordered by engine identifier. See [`SqliteFullDatabase::block_justifications`].
+pub type Justifications = Vec<([u8; 4], Vec<u8>)>;
+
+/// Header, body, and justifications of a block. See [`SqliteFullDatabase::full_block`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FullBlock {
+    /// SCALE-encoded header of the block.
+    pub scale_encoded_header: Vec<u8>,
+    /// List of extrinsics of the block. `None` if the body of the block has been removed from
+    /// the database. See [`SqliteFullDatabase::prune_bodies_below`].
+    pub body: Option<Vec<Vec<u8>>>,
+    /// Justifications of the block, with the identifier of the consensus engine they belong
+    /// to, ordered by engine identifier. See [`SqliteFullDatabase::block_justifications`].
+    pub justifications: Justifications,
+}
+
+/// Parent of a block. See [`SqliteFullDatabase::block_parent`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParentOf {
+    /// The block is the genesis block, which doesn't have any parent.
+    Genesis,
+    /// Hash of the parent of the block.
+    Block([u8; 32]),
+}
+
+/// Block at a certain height. See [`SqliteFullDatabase::blocks_by_number`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockAtNumber {
+    /// Hash of the block.
+    pub hash: [u8; 32],
+    /// `true` if the block is part of the chain going from the finalized block to the best block.
+    pub is_best_chain: bool,
+}
+
+/// See [`SqliteFullDatabase::tree_route`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeRoute {
+    /// Hash and number of the most recent block that is both `from` or one of its ancestors and
+    /// `to` or one of its ancestors.
+    pub common_ancestor: ([u8; 32], u64),
+    /// Hashes and numbers of the blocks going from `from` (included) to the common ancestor
+    /// (excluded), in that order.
+    pub retracted: Vec<([u8; 32], u64)>,
+    /// Hashes and numbers of the blocks going from the common ancestor (excluded) to `to`
+    /// (included), in that order.
+    pub enacted: Vec<([u8; 32], u64)>,
+}
+
+/// See [`SqliteFullDatabase::runtime_by_spec_version`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredRuntime {
+    /// BLAKE2 hash of [`StoredRuntime::code`].
+    pub code_hash: [u8; 32],
+    /// Wasm code of the runtime.
+    pub code: Vec<u8>,
+    /// Number of heap pages to use when running the runtime.
+    pub heap_pages: executor::vm::HeapPages,
+}
+
+/// See [`SqliteFullDatabase::insert`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertOutcome {
+    /// Amount of data that has been written to the database.
+    pub stats: InsertStats,
+    /// If the block has been inserted with `is_new_best` equal to `true`, contains the route
+    /// from the previous best block to the newly-inserted block. If the new block is a child of
+    /// the previous best block, [`TreeRoute::retracted`] is empty and [`TreeRoute::enacted`]
+    /// only contains the new block.
+    ///
+    /// `None` if `is_new_best` was `false`.
+    pub best_chain_change: Option<TreeRoute>,
+}
+
+/// See [`InsertOutcome::stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertStats {
+    /// Size in bytes of the SCALE-encoded header of the block.
+    pub header_bytes: usize,
+    /// Number of extrinsics in the body of the block.
+    pub body_items: usize,
+    /// Total size in bytes of the extrinsics of the body of the block.
+    pub body_bytes: usize,
+}
+
+/// See [`SqliteFullDatabase::insert_trie_nodes_versioned`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsertTrieNodesStats {
+    /// Number of trie nodes that weren't in the database and that have been inserted.
+    pub inserted_nodes: usize,
+    /// Number of trie nodes that were already in the database and that have thus been ignored.
+    pub ignored_nodes: usize,
+    /// Total size in bytes of the storage values of the inserted nodes. Storage values that
+    /// reference the root of a child trie aren't included.
+    pub storage_value_bytes: usize,
+}
+
+/// See [`SqliteFullDatabase::set_finalized`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetFinalizedStats {
+    /// Number of blocks that have become finalized, in other words the difference between the
+    /// number of the new and of the previous finalized block.
+    pub newly_finalized_blocks: u64,
+    /// Number of extrinsics that have been removed because of [`Config::body_retention`].
+    pub pruned_body_rows: u64,
+    /// Number of blocks whose storage diff has been removed because of
+    /// [`Config::storage_diff_retention`].
+    pub pruned_storage_diffs: u64,
+    /// `Some` if one of the newly-finalized blocks has started a new Babe epoch.
+    pub babe_epoch_transition: Option<BabeEpochTransition>,
+}
+
+/// Babe epochs before and after the finalized block has moved to a new epoch. See
+/// [`SetFinalizedStats::babe_epoch_transition`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BabeEpochTransition {
+    /// Epoch that the finalized block belonged to before the transition. `None` if the
+    /// finalized block didn't belong to any epoch, which is the case for the genesis block.
+    pub prev: Option<chain_information::BabeEpochInformation>,
+    /// Epoch that the new finalized block belongs to. This is the epoch that was announced as
+    /// the next one before the call to [`SqliteFullDatabase::set_finalized`].
+    pub next: chain_information::BabeEpochInformation,
+}
+
+/// See [`SqliteFullDatabase::purge_finality_orphans`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PurgeReport {
+    /// Hashes of the blocks that have been removed from the database, from the highest to the
+    /// lowest number.
+    pub purged_blocks: Vec<[u8; 32]>,
+    /// Number of extrinsics of the bodies of the removed blocks that have been removed.
+    pub deleted_body_rows: usize,
+    /// Number of trie nodes that have been removed, including the nodes of child tries.
+    ///
+    /// Trie nodes that are still used by a block that remains in the database are kept.
+    pub deleted_trie_nodes: usize,
+    /// `true` if the limit passed to [`SqliteFullDatabase::purge_finality_orphans`] has been
+    /// reached and some orphan blocks remain in the database.
+    pub more_remaining: bool,
+}
+
+/// See [`SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`].
+#[derive(Debug)]
+pub struct MissingTrieNode {
+    /// Blocks the trie node is known to belong to.
+    ///
+    /// Guaranteed to never be empty.
+    ///
+    /// Only contains blocks whose number is superior or equal to the latest finalized block
+    /// number.
+    pub blocks: Vec<MissingTrieNodeBlock>,
+    /// Hash of the missing trie node.
+    pub trie_node_hash: [u8; 32],
+}
+
+/// See [`MissingTrieNode::blocks`].
+#[derive(Debug)]
 pub struct MissingTrieNodeBlock {
     /// Hash of the block.
     pub hash: [u8; 32],
@@ -1717,168 +4112,2020 @@ pub struct MissingTrieNodeBlock {
     pub trie_node_key_nibbles: Vec<u8>,
 }
 
-pub struct InsertTrieNode<'a> {
-    pub merkle_value: Cow<'a, [u8]>,
-    pub partial_key_nibbles: Cow<'a, [u8]>,
-    pub children_merkle_values: [Option<Cow<'a, [u8]>>; 16],
-    pub storage_value: InsertTrieNodeStorageValue<'a>,
+pub struct InsertTrieNode<'a> {
+    pub merkle_value: Cow<'a, [u8]>,
+    pub partial_key_nibbles: Cow<'a, [u8]>,
+    pub children_merkle_values: [Option<Cow<'a, [u8]>>; 16],
+    pub storage_value: InsertTrieNodeStorageValue<'a>,
+}
+
+pub enum InsertTrieNodeStorageValue<'a> {
+    NoValue,
+    Value {
+        value: Cow<'a, [u8]>,
+        /// If `true`, the value is equal to the Merkle value of the root of another trie.
+        references_merkle_value: bool,
+    },
+}
+
+/// Error while calling [`SqliteFullDatabase::insert`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum InsertError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Block was already in the database. Nothing has been modified.
+    #[display(
+        fmt = "Block already in the database (body stored: {body_stored}, body matches: {body_matches})"
+    )]
+    #[from(ignore)]
+    Duplicate {
+        /// `false` if the body of the block that is already in the database has been pruned.
+        /// See [`SqliteFullDatabase::prune_bodies_below`].
+        body_stored: bool,
+        /// `true` if the body of the block that is already in the database is stored and is
+        /// identical to the one that has been provided, in other words if it contains the same
+        /// extrinsics in the same order.
+        body_matches: bool,
+    },
+    /// Error when decoding the header to import.
+    #[display(fmt = "Failed to decode header: {_0}")]
+    BadHeader(header::Error),
+    /// The body passed to [`SqliteFullDatabase::insert_scale_body`] isn't a valid SCALE-encoded
+    /// list of extrinsics.
+    #[display(fmt = "Failed to decode body")]
+    BadBody,
+    /// Parent of the block to insert isn't in the database.
+    MissingParent,
+    /// The number of the block to insert isn't equal to the number of its parent plus one.
+    #[display(fmt = "Block number {child} doesn't follow parent number {parent}")]
+    #[from(ignore)]
+    NumberMismatch {
+        /// Number of the parent of the block, as found in the database.
+        parent: u64,
+        /// Number found in the header of the block.
+        child: u64,
+    },
+    /// The number of the block to insert is too large to be stored in the database.
+    #[display(fmt = "Block number {number} is out of range")]
+    #[from(ignore)]
+    NumberOutOfRange {
+        /// Number found in the header of the block.
+        number: u64,
+    },
+    /// The new best block would be outside of the finalized chain.
+    BestNotInFinalizedChain,
+    /// The [`InsertMeta`] passed alongside with the block can't be stored in the database.
+    #[display(fmt = "Insertion time {inserted_at_unix_ms} is out of range")]
+    #[from(ignore)]
+    InvalidMeta {
+        /// Value of [`InsertMeta::inserted_at_unix_ms`].
+        inserted_at_unix_ms: u64,
+    },
+}
+
+impl std::error::Error for InsertError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // The `Display` implementation of this variant is the one of the inner error.
+            InsertError::Corrupted(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::insert_trie_nodes_versioned`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum InsertTrieNodesError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// One of the values of the partial key of a node is superior or equal to 16.
+    #[display(fmt = "Invalid nibble {value} at position {position} of a partial key")]
+    #[from(ignore)]
+    InvalidNibble {
+        /// Index of the invalid value within the partial key.
+        position: usize,
+        /// The invalid value.
+        value: u8,
+    },
+    /// The Merkle value of a child of a node is longer than 32 bytes.
+    #[display(fmt = "Merkle value of child {child_num} is longer than 32 bytes")]
+    #[from(ignore)]
+    InvalidChildMerkleValue {
+        /// Index of the child within its parent.
+        child_num: u8,
+    },
+    /// The version of the trie entries isn't valid. Only ever returned by
+    /// [`SqliteFullDatabase::insert_trie_nodes`].
+    InvalidTrieEntryVersion,
+    /// Some of the inserted nodes aren't reachable from any other node or block. Only ever
+    /// returned by [`SqliteFullDatabase::insert_trie_nodes_validated`].
+    #[display(fmt = "{} inserted trie nodes are unreachable", "merkle_values.len()")]
+    #[from(ignore)]
+    OrphanTrieNodes {
+        /// Merkle values of the nodes that aren't reachable.
+        merkle_values: Vec<Vec<u8>>,
+    },
+}
+
+/// Error while calling [`SqliteFullDatabase::set_best_block`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum SetBestError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// New best block isn't in the database.
+    UnknownBlock,
+    /// New best block is neither the finalized block nor one of its descendants.
+    NotInFinalizedChain,
+}
+
+impl std::error::Error for SetBestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SetBestError::Corrupted(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::set_finalized`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum SetFinalizedError {
+    /// Error accessing the database.
+    Corrupted(CorruptedError),
+    /// New finalized block isn't in the database.
+    UnknownBlock,
+    /// New finalized block must be a child of the previous finalized block.
+    RevertForbidden,
+    /// New finalized block isn't a descendant of the current finalized block, for example
+    /// because it belongs to a fork that has branched off below the current finalized block.
+    NotDescendant,
+}
+
+impl std::error::Error for SetFinalizedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SetFinalizedError::Corrupted(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::reset`], [`SqliteFullDatabase::reset_and_wipe`],
+/// or [`DatabaseEmpty::initialize`] and similar functions.
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum ResetError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// The header of the finalized block doesn't decode back into the same header once
+    /// encoded. This typically happens when the header contains block numbers that don't fit in
+    /// [`Config::block_number_bytes`]. Nothing has been written to the database.
+    #[display(fmt = "Finalized block header doesn't decode back into the same header")]
+    HeaderRoundTripMismatch,
+    /// The finalized block is already stored in the database as part of another chain. Since
+    /// blocks are identified by their hash, a block can only belong to one of the chains of the
+    /// database. Nothing has been written to the database.
+    #[display(fmt = "Finalized block belongs to another chain of the database")]
+    BlockOfOtherChain,
+}
+
+impl std::error::Error for ResetError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ResetError::Corrupted(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::set_block_events`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum SetBlockEventsError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Block couldn't be found in the database.
+    UnknownBlock,
+}
+
+/// Error while calling [`SqliteFullDatabase::set_block_justification`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum SetBlockJustificationError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Block couldn't be found in the database.
+    UnknownBlock,
+}
+
+/// Error while calling [`SqliteFullDatabase::block_extrinsics`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum BlockExtrinsicsError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// The block is known, but its body has been removed from the database.
+    /// See [`SqliteFullDatabase::prune_bodies_below`].
+    #[display(fmt = "Body of the block has been pruned")]
+    BodyPruned,
+}
+
+/// Makes it possible to abort the operations being performed on a [`SqliteFullDatabase`].
+///
+/// See [`SqliteFullDatabase::interrupt_handle`].
+#[derive(Clone)]
+pub struct InterruptHandle {
+    connections: Arc<[rusqlite::InterruptHandle]>,
+}
+
+impl InterruptHandle {
+    /// Aborts all the operations currently being performed on the database. These operations
+    /// return [`CorruptedError::Interrupted`] or [`StorageAccessError::Interrupted`]. The
+    /// operations started after this function has returned aren't affected.
+    ///
+    /// This can be called from any thread, including while the database is locked.
+    ///
+    /// > **Note**: Modifications being performed are aborted as well and have no effect. If
+    /// >           [`Config::commit_policy`] isn't [`CommitPolicy::EveryOperation`], the
+    /// >           modifications that haven't been committed yet might also be reverted.
+    pub fn interrupt(&self) {
+        for connection in self.connections.iter() {
+            connection.interrupt();
+        }
+    }
+}
+
+impl fmt::Debug for InterruptHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterruptHandle").finish_non_exhaustive()
+    }
+}
+
+/// Error while accessing the storage of the finalized block.
+#[derive(Debug, derive_more::Display)]
+pub enum StorageAccessError {
+    /// Error accessing the database.
+    Corrupted(CorruptedError),
+    /// Some trie nodes of the storage of the requested block hash are missing.
+    IncompleteStorage,
+    /// Requested block couldn't be found in the database.
+    UnknownBlock,
+    /// The database is temporarily locked by another connection. Contrary to
+    /// [`StorageAccessError::Corrupted`], this error isn't fatal and the operation can be
+    /// retried later.
+    Busy,
+    /// The operation has been aborted using [`InterruptHandle::interrupt`]. Contrary to
+    /// [`StorageAccessError::Corrupted`], this error isn't fatal.
+    Interrupted,
+    /// One of the values passed as a nibble is superior or equal to 16.
+    #[display(fmt = "Invalid nibble {value} at position {position}")]
+    InvalidNibble {
+        /// Index of the invalid value within the iterator that has yielded it.
+        position: usize,
+        /// The invalid value.
+        value: u8,
+    },
+    /// The requested key contains an odd number of nibbles and can't be represented as a list
+    /// of bytes. See [`SqliteFullDatabase::block_storage_next_key_bytes`].
+    OddNumberOfNibbles,
+}
+
+impl std::error::Error for StorageAccessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageAccessError::Corrupted(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<CorruptedError> for StorageAccessError {
+    fn from(err: CorruptedError) -> StorageAccessError {
+        match err {
+            CorruptedError::Busy => StorageAccessError::Busy,
+            CorruptedError::Interrupted => StorageAccessError::Interrupted,
+            err => StorageAccessError::Corrupted(err),
+        }
+    }
+}
+
+/// Error in the content of the database.
+// TODO: document and see if any entry is unused
+#[derive(Debug, derive_more::Display)]
+pub enum CorruptedError {
+    /// A number doesn't fit in the 64 bits signed integers used by the database, or a number
+    /// found in the database is negative, zero where it must not be, or isn't a valid 64bits
+    /// big endian encoding.
+    InvalidNumber,
+    /// Finalized block number stored in the database doesn't match any block.
+    InvalidFinalizedNum,
+    /// Multiple blocks at the height of the finalized block are marked as being part of the
+    /// best chain.
+    MultipleFinalizedBlocks,
+    /// A block hash is expected to be 32 bytes. This isn't the case.
+    InvalidBlockHashLen,
+    /// A trie hash is expected to be 32 bytes. This isn't the case.
+    InvalidTrieHashLen,
+    /// The hash of the code of a runtime is expected to be 32 bytes. This isn't the case.
+    InvalidRuntimeCodeHashLen,
+    /// The hash of a storage value is expected to be 32 bytes. This isn't the case.
+    InvalidValueHashLen,
+    /// The identifier of the consensus engine of a justification is expected to be 4 bytes.
+    /// This isn't the case.
+    InvalidEngineIdLen,
+    /// Values in the database are all well-formatted, but are incoherent.
+    #[display(fmt = "Invalid chain information: {_0}")]
+    InvalidChainInformation(chain_information::ValidityError),
+    /// The parent of a block in the database couldn't be found in that same database.
+    #[display(fmt = "Broken chain at block #{number}")]
+    BrokenChain {
+        /// Number of the block whose parent couldn't be found, or of the missing block.
+        number: u64,
+    },
+    /// Missing a key in the `meta` table.
+    MissingMetaKey,
+    /// Some parts of the database refer to a block by its hash, but the block's constituents
+    /// couldn't be found.
+    MissingBlockHeader,
+    /// The header of a block in the database has failed to decode.
+    #[display(fmt = "Corrupted block header: {_0}")]
+    BlockHeaderCorrupted(header::Error),
+    /// Multiple different consensus algorithms are mixed within the database.
+    ConsensusAlgorithmMix,
+    /// The information about a Babe epoch found in the database has failed to decode.
+    #[display(fmt = "Invalid Babe epoch information: {_0}")]
+    InvalidBabeEpochInformation(BabeEpochInformationDecodeError),
+    /// The version information about a storage entry has failed to decode.
+    InvalidTrieEntryVersion,
+    /// The key of a storage entry doesn't contain an entire number of bytes.
+    InvalidStorageKey,
+    /// The list of the children of a trie node found in the database has failed to decode.
+    InvalidTrieNodeChildren,
+    /// A trie in the database is deeper than any legitimate trie can be, which indicates a loop
+    /// between trie nodes.
+    TrieLoopDetected,
+    /// A trie node being inserted has the same Merkle value as a node already in the database,
+    /// but a different content.
+    #[display(fmt = "Conflicting trie node 0x{}", "hex::encode(merkle_value)")]
+    ConflictingTrieNode {
+        /// Merkle value of the node.
+        merkle_value: Vec<u8>,
+    },
+    /// A modification has been refused by the database because it would have left a row
+    /// referring to a row that doesn't exist, for example the body of an unknown block.
+    #[display(fmt = "Foreign key constraint violated during {operation}")]
+    ForeignKeyViolation {
+        /// Name of the operation that has been refused.
+        operation: &'static str,
+    },
+    /// The database is temporarily locked by another connection, and the time configured in
+    /// [`Config::busy_timeout`] has elapsed while waiting for the lock to be released.
+    ///
+    /// Despite being part of [`CorruptedError`], this doesn't indicate a corruption, and the
+    /// operation can be retried later.
+    #[display(fmt = "Database is locked by another connection")]
+    Busy,
+    /// The operation has been aborted using [`InterruptHandle::interrupt`].
+    ///
+    /// Despite being part of [`CorruptedError`], this doesn't indicate a corruption.
+    #[display(fmt = "Operation has been interrupted")]
+    Interrupted,
+    /// The disk is full, or SQLite has failed to write to the disk. The operation has been
+    /// rolled back. If the [`CommitPolicy`] delays commits, SQLite might also have rolled back
+    /// the previous modifications that were waiting to be committed, in which case the next
+    /// operation returns [`CorruptedError::UncommittedWritesLost`].
+    ///
+    /// Despite being part of [`CorruptedError`], this doesn't indicate a corruption, and the
+    /// operation can be retried once space has been freed.
+    #[display(fmt = "Not enough space left to write to the database")]
+    StorageFull,
+    /// SQLite has rolled back the modifications that were waiting to be committed because of
+    /// the [`CommitPolicy`], typically after a [`CorruptedError::StorageFull`]. These
+    /// modifications, including the blocks they have inserted, are no longer in the database
+    /// and their events are never sent to the subscribers. The operation that has returned this
+    /// error hasn't been performed and can be retried.
+    ///
+    /// Despite being part of [`CorruptedError`], this doesn't indicate a corruption.
+    #[display(
+        fmt = "{num_blocks} uncommitted block(s) and the related modifications have been lost"
+    )]
+    UncommittedWritesLost {
+        /// Number of blocks whose insertion has been lost.
+        num_blocks: u64,
+    },
+    /// The database has been opened with [`Config::allow_shared_readers`] and can't be
+    /// modified.
+    ///
+    /// Despite being part of [`CorruptedError`], this doesn't indicate a corruption.
+    #[display(fmt = "Database is opened in read-only mode")]
+    ReadOnly,
+    #[display(fmt = "Internal error: {_0}")]
+    Internal(InternalError),
+}
+
+impl std::error::Error for CorruptedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CorruptedError::Internal(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Row of the database that refers to a row that doesn't exist.
+///
+/// See [`SqliteFullDatabase::check_foreign_keys`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignKeyViolation {
+    /// Name of the table containing the row.
+    pub table: String,
+    /// Identifier of the row within its table, or `None` if the table doesn't have row
+    /// identifiers.
+    pub rowid: Option<i64>,
+    /// Name of the table that the row refers to.
+    pub parent: String,
+}
+
+/// See [`CorruptedError::InvalidBabeEpochInformation`].
+#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+pub enum BabeEpochInformationDecodeError {
+    /// The information has been encoded using a version of the format that isn't supported,
+    /// which likely indicates that the database has been written by a more recent version of
+    /// this code.
+    #[display(fmt = "Unknown encoding version {_0}")]
+    UnknownVersion(u8),
+    /// The information ends in the middle of a field. `offset` is the position, from the start
+    /// of the encoded information, of the field that couldn't be decoded.
+    #[display(fmt = "Unexpected end of data at offset {offset}")]
+    Truncated {
+        /// Offset of the field that couldn't be decoded.
+        offset: usize,
+    },
+    /// The information ends in the middle of the list of authorities.
+    #[display(
+        fmt = "Only {found} out of {expected} authorities found starting at offset {offset}"
+    )]
+    TruncatedAuthorities {
+        /// Offset of the first authority.
+        offset: usize,
+        /// Number of authorities indicated by the encoding.
+        expected: usize,
+        /// Number of authorities that could be entirely decoded.
+        found: usize,
+    },
+    /// A field has a value that isn't allowed by the format, such as an unknown enum variant.
+    #[display(fmt = "Invalid value at offset {offset}")]
+    InvalidValue {
+        /// Offset of the invalid field.
+        offset: usize,
+    },
+    /// All the fields have been decoded but there are bytes left.
+    #[display(fmt = "Unexpected trailing bytes at offset {offset}")]
+    TrailingBytes {
+        /// Offset of the first byte that isn't part of any field.
+        offset: usize,
+    },
+    /// The information has been successfully decoded, but isn't valid.
+    #[display(fmt = "Invalid epoch: {_0}")]
+    ValidationFailed(chain_information::BabeValidityError),
+}
+
+/// Low-level database error, such as an error while accessing the file system.
+#[derive(Debug, derive_more::Display)]
+#[display(fmt = "{source} (during {operation})")]
+pub struct InternalError {
+    /// Name of the logical operation that has failed.
+    operation: &'static str,
+    /// Error returned by SQLite.
+    source: rusqlite::Error,
+}
+
+impl InternalError {
+    pub(super) fn new(operation: &'static str, source: rusqlite::Error) -> Self {
+        InternalError { operation, source }
+    }
+
+    /// Returns the name of the logical operation that has failed, such as `block_storage_get`
+    /// or `set_finalized/babe_epoch`.
+    ///
+    /// This name is meant to help diagnosing problems, and no guarantee is made about its
+    /// stability.
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+}
+
+impl std::error::Error for InternalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Same as [`rusqlite::Connection::prepare_cached`], but also updates the statistics returned by
+/// [`SqliteFullDatabase::statement_cache_stats`].
+///
+/// Must be used for all the statements executed while the database is accessed, except for the
+/// ones that are rarely executed.
+fn prepare_cached<'a>(
+    database: &'a rusqlite::Connection,
+    sql: &str,
+) -> Result<rusqlite::CachedStatement<'a>, rusqlite::Error> {
+    let statement = database.prepare_cached(sql)?;
+    // Statements are reset after having been executed, which increments their `Run` counter.
+    // A counter of zero thus indicates that the statement has just been prepared.
+    let hit = statement.get_status(rusqlite::StatementStatus::Run) != 0;
+    metrics::record_statement_cache_lookup(sql, hit);
+    Ok(statement)
+}
+
+/// Returns a function that turns an error returned by SQLite during the given logical operation
+/// into a [`CorruptedError`].
+///
+/// `SQLITE_BUSY` and `SQLITE_LOCKED` indicate that another connection holds a lock on the
+/// database, and are turned into [`CorruptedError::Busy`]. `SQLITE_INTERRUPT` is turned into
+/// [`CorruptedError::Interrupted`]. `SQLITE_FULL` and `SQLITE_IOERR_WRITE`, which is what
+/// writing to a full disk can also produce, are turned into [`CorruptedError::StorageFull`].
+/// `SQLITE_READONLY` is turned into [`CorruptedError::ReadOnly`]. Everything else is an internal error.
+fn sqlite_err(operation: &'static str) -> impl Fn(rusqlite::Error) -> CorruptedError {
+    move |err| match err.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked) => {
+            CorruptedError::Busy
+        }
+        Some(rusqlite::ErrorCode::OperationInterrupted) => CorruptedError::Interrupted,
+        Some(rusqlite::ErrorCode::DiskFull) => CorruptedError::StorageFull,
+        Some(rusqlite::ErrorCode::ReadOnly) => CorruptedError::ReadOnly,
+        Some(rusqlite::ErrorCode::SystemIoFailure)
+            if matches!(&err, rusqlite::Error::SqliteFailure(err, _)
+                if err.extended_code == rusqlite::ffi::SQLITE_IOERR_WRITE) =>
+        {
+            CorruptedError::StorageFull
+        }
+        Some(rusqlite::ErrorCode::ConstraintViolation)
+            if matches!(&err, rusqlite::Error::SqliteFailure(err, _)
+                if err.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY) =>
+        {
+            CorruptedError::ForeignKeyViolation { operation }
+        }
+        _ => CorruptedError::Internal(InternalError::new(operation, err)),
+    }
+}
+
+fn meta_get_blob(
+    database: &rusqlite::Connection,
+    key: &str,
+) -> Result<Option<Vec<u8>>, CorruptedError> {
+    let value = prepare_cached(database, r#"SELECT value_blob FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = ?"#)
+        .map_err(sqlite_err("meta_get_blob"))?
+        .query_row((key,), |row| row.get::<_, Vec<u8>>(0))
+        .optional()
+        .map_err(sqlite_err("meta_get_blob"))?;
+    Ok(value)
+}
+
+/// Reads a number written with [`meta_set_number`].
+fn meta_get_number(
+    database: &rusqlite::Connection,
+    key: &str,
+) -> Result<Option<u64>, CorruptedError> {
+    let value = prepare_cached(database, r#"SELECT value_number FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = ?"#)
+        .map_err(sqlite_err("meta_get_number"))?
+        .query_row((key,), |row| row.get::<_, i64>(0))
+        .optional()
+        .map_err(sqlite_err("meta_get_number"))?;
+    value
+        .map(|value| u64::try_from(value).map_err(|_| CorruptedError::InvalidNumber))
+        .transpose()
+}
+
+/// Reads a number written with [`meta_set_u64_blob`].
+fn meta_get_u64_blob(
+    database: &rusqlite::Connection,
+    key: &str,
+) -> Result<Option<u64>, CorruptedError> {
+    meta_get_blob(database, key)?
+        .map(|value| decode_u64(&value))
+        .transpose()
+}
+
+fn meta_clear(database: &rusqlite::Connection, key: &str) -> Result<(), CorruptedError> {
+    prepare_cached(
+        database,
+        r#"DELETE FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = ?"#,
+    )
+    .map_err(sqlite_err("meta_clear"))?
+    .execute((key,))
+    .map_err(sqlite_err("meta_clear"))?;
+    Ok(())
+}
+
+fn meta_set_blob(
+    database: &rusqlite::Connection,
+    key: &str,
+    value: &[u8],
+) -> Result<(), CorruptedError> {
+    prepare_cached(database, r#"INSERT OR REPLACE INTO meta(chain_id, key, value_blob) VALUES ((SELECT id FROM current_chain), ?, ?)"#)
+        .map_err(sqlite_err("meta_set_blob"))?
+        .execute((key, value))
+        .map_err(sqlite_err("meta_set_blob"))?;
+    Ok(())
+}
+
+/// Stores a number as an SQL integer, so that it can be compared with other numbers, such as
+/// block heights, from within SQL queries.
+///
+/// Returns [`CorruptedError::InvalidNumber`] if the number doesn't fit in the signed 64 bits
+/// integers used by SQLite. Numbers that don't necessarily fit should use
+/// [`meta_set_u64_blob`] instead.
+fn meta_set_number(
+    database: &rusqlite::Connection,
+    key: &str,
+    value: u64,
+) -> Result<(), CorruptedError> {
+    let value = i64::try_from(value).map_err(|_| CorruptedError::InvalidNumber)?;
+    prepare_cached(database, r#"INSERT OR REPLACE INTO meta(chain_id, key, value_number) VALUES ((SELECT id FROM current_chain), ?, ?)"#)
+        .map_err(sqlite_err("meta_set_number"))?
+        .execute((key, value))
+        .map_err(sqlite_err("meta_set_number"))?;
+    Ok(())
+}
+
+/// Stores a number that doesn't necessarily fit in an SQL integer. See [`encode_u64`].
+fn meta_set_u64_blob(
+    database: &rusqlite::Connection,
+    key: &str,
+    value: u64,
+) -> Result<(), CorruptedError> {
+    meta_set_blob(database, key, &encode_u64(value))
+}
+
+/// Encodes a number that doesn't necessarily fit in the signed 64 bits integers used by SQLite,
+/// such as a GrandPa authority weight, into a blob.
+///
+/// Big endian is used so that the encoding doesn't depend on the machine and so that blobs
+/// compare in the same order as the numbers they encode.
+fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+/// Decodes a blob produced by [`encode_u64`].
+fn decode_u64(value: &[u8]) -> Result<u64, CorruptedError> {
+    <[u8; 8]>::try_from(value)
+        .map(u64::from_be_bytes)
+        .map_err(|_| CorruptedError::InvalidNumber)
+}
+
+/// Pushes the values yielded by `nibbles` at the end of `out`.
+///
+/// Returns an error if one of the values isn't a nibble, in other words is superior or equal
+/// to 16.
+fn extend_nibbles(
+    out: &mut Vec<u8>,
+    nibbles: impl Iterator<Item = u8>,
+) -> Result<(), StorageAccessError> {
+    for (position, value) in nibbles.enumerate() {
+        if value >= 16 {
+            return Err(StorageAccessError::InvalidNibble { position, value });
+        }
+        out.push(value);
+    }
+    Ok(())
+}
+
+/// Turns a list of nibbles into the format of the `partial_key` column of the `trie_node` table.
+///
+/// The first byte is `1` if the number of nibbles is odd, and `0` otherwise. It is followed with
+/// the nibbles, two per byte, the first nibble being the most significant bits. If the number
+/// of nibbles is odd, the least significant bits of the last byte are `0`.
+fn pack_nibbles(nibbles: &[u8]) -> Vec<u8> {
+    debug_assert!(nibbles.iter().all(|n| *n < 16));
+    let mut packed = Vec::with_capacity(1 + nibbles.len().div_ceil(2));
+    packed.push(u8::from(nibbles.len() % 2 == 1));
+    packed.extend(
+        nibbles
+            .chunks(2)
+            .map(|pair| (pair[0] << 4) | pair.get(1).copied().unwrap_or(0)),
+    );
+    packed
+}
+
+/// Turns a value of the `partial_key` column of the `trie_node` table back into a list of
+/// nibbles. See [`pack_nibbles`].
+///
+/// Returns `None` if the value isn't in the expected format.
+fn unpack_nibbles(packed: &[u8]) -> Option<Vec<u8>> {
+    let (is_odd, bytes) = packed.split_first()?;
+    let mut nibbles = bytes
+        .iter()
+        .flat_map(|byte| [byte >> 4, byte & 0xf])
+        .collect::<Vec<_>>();
+    match (is_odd, nibbles.last()) {
+        (0, _) => {}
+        (1, Some(0)) => {
+            nibbles.pop();
+        }
+        _ => return None,
+    }
+    Some(nibbles)
+}
+
+/// Turns the Merkle values of the children of a trie node into the format of the `children`
+/// column of the `trie_node` table. Returns `None` if the node doesn't have any child.
+///
+/// The format starts with a bitmap of two bytes in little endian, where bit `n` is set if the
+/// node has a child at index `n`, like in the encoding of trie nodes. It is followed with, for
+/// each child in increasing index order, the length in bytes of its Merkle value on one byte,
+/// then its Merkle value.
+///
+/// The Merkle values must be at most 255 bytes long.
+fn encode_children<'a>(children: impl IntoIterator<Item = Option<&'a [u8]>>) -> Option<Vec<u8>> {
+    let mut bitmap = 0u16;
+    let mut encoded = vec![0, 0];
+    for (child_num, child) in children.into_iter().enumerate() {
+        let Some(child) = child else { continue };
+        bitmap |= 1 << child_num;
+        encoded.push(u8::try_from(child.len()).unwrap());
+        encoded.extend_from_slice(child);
+    }
+    if bitmap == 0 {
+        return None;
+    }
+    encoded[..2].copy_from_slice(&bitmap.to_le_bytes());
+    Some(encoded)
+}
+
+/// Turns a value of the `children` column of the `trie_node` table back into the Merkle values
+/// of the children, indexed by child index. See [`encode_children`].
+///
+/// Returns `None` if the value isn't in the expected format.
+fn decode_children(encoded: &[u8]) -> Option<[Option<&[u8]>; 16]> {
+    let (bitmap, mut remain) = (encoded.get(..2)?, &encoded[2..]);
+    let bitmap = u16::from_le_bytes([bitmap[0], bitmap[1]]);
+    if bitmap == 0 {
+        return None;
+    }
+
+    let mut children = [None; 16];
+    for (child_num, child) in children.iter_mut().enumerate() {
+        if bitmap & (1 << child_num) == 0 {
+            continue;
+        }
+        let (len, after_len) = remain.split_first()?;
+        if after_len.len() < usize::from(*len) {
+            return None;
+        }
+        let (merkle_value, after_merkle_value) = after_len.split_at(usize::from(*len));
+        *child = Some(merkle_value);
+        remain = after_merkle_value;
+    }
+
+    if !remain.is_empty() {
+        return None;
+    }
+    Some(children)
+}
+
+/// Decodes the value of the `children` column of the `trie_node` table into a list of child
+/// index and Merkle value, ordered by increasing child index.
+fn children_list(encoded: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, CorruptedError> {
+    Ok(decode_children(encoded)
+        .ok_or(CorruptedError::InvalidTrieNodeChildren)?
+        .into_iter()
+        .enumerate()
+        .filter_map(|(child_num, child)| {
+            let child_num = u8::try_from(child_num).unwrap_or_else(|_| unreachable!());
+            Some((child_num, child?.to_vec()))
+        })
+        .collect())
+}
+
+/// Trie node as found in the database. See [`trie_node`].
+struct TrieNode {
+    /// Partial key of the node, as nibbles.
+    partial_key: Vec<u8>,
+    /// `true` if the node has a storage value, either a value or a reference to another trie.
+    has_storage_value: bool,
+    /// If the storage value of this node is a reference to another trie, contains the hash of
+    /// the root node of this other trie.
+    trie_root_ref: Option<Vec<u8>>,
+}
+
+/// Collects the given nibbles into a `Vec`.
+///
+/// Returns [`StorageAccessError::InvalidNibble`] if any of the values yielded by `nibbles` is
+/// superior or equal to 16.
+fn collect_nibbles(nibbles: impl Iterator<Item = u8>) -> Result<Vec<u8>, StorageAccessError> {
+    let mut out = Vec::new();
+    extend_nibbles(&mut out, nibbles)?;
+    Ok(out)
+}
+
+/// Returns the nibbles of the key of the main trie that contains the root of the default child
+/// trie named `child_name`.
+fn child_trie_path_nibbles(child_name: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    trie::bytes_to_nibbles(
+        CHILD_STORAGE_DEFAULT_PREFIX
+            .iter()
+            .chain(child_name.iter())
+            .copied(),
+    )
+    .map(u8::from)
+}
+
+/// Builds the key passed to the SQL queries that look up a key in the storage, made of the
+/// paths of the parent tries each followed with a `0x10` nibble, then the key itself. Also
+/// returns `true` if the key is in the main trie, in other words if there is no parent trie.
+fn storage_key_vectored(
+    parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+    key_nibbles: impl Iterator<Item = u8>,
+) -> Result<(Vec<u8>, bool), StorageAccessError> {
+    let mut key_vectored = Vec::new();
+    let mut is_main_trie = true;
+    for parent_trie_path_nibbles in parent_tries_paths_nibbles {
+        extend_nibbles(&mut key_vectored, parent_trie_path_nibbles)?;
+        key_vectored.push(0x10);
+        is_main_trie = false;
+    }
+    extend_nibbles(&mut key_vectored, key_nibbles)?;
+    Ok((key_vectored, is_main_trie))
+}
+
+fn best_block_hash(database: &rusqlite::Connection) -> Result<[u8; 32], CorruptedError> {
+    let val = meta_get_blob(database, "best")?.ok_or(CorruptedError::MissingMetaKey)?;
+    if val.len() == 32 {
+        let mut out = [0; 32];
+        out.copy_from_slice(&val);
+        Ok(out)
+    } else {
+        Err(CorruptedError::InvalidBlockHashLen)
+    }
+}
+
+fn best_block_number(database: &rusqlite::Connection) -> Result<u64, CorruptedError> {
+    let best_hash = best_block_hash(database)?;
+    let number = prepare_cached(
+        database,
+        "SELECT number FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)",
+    )
+    .map_err(sqlite_err("best_block_number"))?
+    .query_row((&best_hash[..],), |row| row.get::<_, i64>(0))
+    .optional()
+    .map_err(sqlite_err("best_block_number"))?
+    .ok_or(CorruptedError::MissingBlockHeader)?;
+    u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)
+}
+
+fn block_parent(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Option<ParentOf>, CorruptedError> {
+    let Some(parent_hash) = prepare_cached(database, BLOCK_PARENT_QUERY)
+        .map_err(sqlite_err("block_parent"))?
+        .query_row((&block_hash[..],), |row| row.get::<_, Option<Vec<u8>>>(0))
+        .optional()
+        .map_err(sqlite_err("block_parent"))?
+    else {
+        return Ok(None);
+    };
+
+    match parent_hash {
+        Some(parent_hash) => Ok(Some(ParentOf::Block(
+            <[u8; 32]>::try_from(&parent_hash[..])
+                .map_err(|_| CorruptedError::InvalidBlockHashLen)?,
+        ))),
+        None => Ok(Some(ParentOf::Genesis)),
+    }
 }
 
-pub enum InsertTrieNodeStorageValue<'a> {
-    NoValue,
-    Value {
-        value: Cow<'a, [u8]>,
-        /// If `true`, the value is equal to the Merkle value of the root of another trie.
-        references_merkle_value: bool,
-    },
+fn block_info(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Option<BlockRef>, CorruptedError> {
+    let row = prepare_cached(
+        database,
+        r#"SELECT number, parent_hash, state_trie_root_hash, is_best_chain, NOT body_pruned,
+            EXISTS(SELECT 1 FROM blocks_justifications WHERE blocks_justifications.hash = blocks.hash)
+        FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("block_info"))?
+    .query_row((&block_hash[..],), |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, Option<Vec<u8>>>(1)?,
+            row.get::<_, Option<Vec<u8>>>(2)?,
+            row.get::<_, bool>(3)?,
+            row.get::<_, bool>(4)?,
+            row.get::<_, bool>(5)?,
+        ))
+    })
+    .optional()
+    .map_err(sqlite_err("block_info"))?;
+
+    let Some((number, parent_hash, state_trie_root, is_best_chain, has_body, has_justification)) =
+        row
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(BlockRef {
+        hash: *block_hash,
+        number: u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?,
+        parent_hash: parent_hash
+            .map(|hash| <[u8; 32]>::try_from(&hash[..]))
+            .transpose()
+            .map_err(|_| CorruptedError::InvalidBlockHashLen)?,
+        state_trie_root: state_trie_root
+            .map(|hash| <[u8; 32]>::try_from(&hash[..]))
+            .transpose()
+            .map_err(|_| CorruptedError::InvalidTrieHashLen)?,
+        is_best_chain,
+        has_body,
+        has_justification,
+    }))
 }
 
-/// Error while calling [`SqliteFullDatabase::insert`].
-#[derive(Debug, derive_more::Display, derive_more::From)]
-pub enum InsertError {
-    /// Error accessing the database.
-    #[display(fmt = "{_0}")]
-    Corrupted(CorruptedError),
-    /// Block was already in the database.
-    Duplicate,
-    /// Error when decoding the header to import.
-    #[display(fmt = "Failed to decode header: {_0}")]
-    BadHeader(header::Error),
-    /// Parent of the block to insert isn't in the database.
-    MissingParent,
-    /// The new best block would be outside of the finalized chain.
-    BestNotInFinalizedChain,
+fn tree_route(
+    database: &rusqlite::Connection,
+    from: &[u8; 32],
+    to: &[u8; 32],
+) -> Result<Option<TreeRoute>, CorruptedError> {
+    // The temporary table `route` is built by walking down the ancestry of `from` and of `to`
+    // similarly to `set_best_chain`. At each step, the side with the highest block number goes
+    // to its parent, or both sides if their numbers are equal, until both sides are the same
+    // block. The walk also stops if a parent isn't in the database.
+    let mut statement = prepare_cached(
+        database,
+        r#"
+    WITH RECURSIVE
+        route(from_hash, from_number, to_hash, to_number) AS (
+            SELECT blocks_from.hash, blocks_from.number, blocks_to.hash, blocks_to.number
+            FROM blocks AS blocks_from, blocks AS blocks_to
+            WHERE blocks_from.hash = :from AND blocks_to.hash = :to
+        UNION ALL
+            SELECT
+                CASE WHEN route.from_number >= route.to_number THEN blocks_from.parent_hash ELSE route.from_hash END,
+                CASE WHEN route.from_number >= route.to_number THEN route.from_number - 1 ELSE route.from_number END,
+                CASE WHEN route.to_number >= route.from_number THEN blocks_to.parent_hash ELSE route.to_hash END,
+                CASE WHEN route.to_number >= route.from_number THEN route.to_number - 1 ELSE route.to_number END
+            FROM route
+            JOIN blocks AS blocks_from ON blocks_from.hash = route.from_hash
+            JOIN blocks AS blocks_to ON blocks_to.hash = route.to_hash
+            JOIN blocks AS parent_from ON parent_from.hash = CASE WHEN route.from_number >= route.to_number THEN blocks_from.parent_hash ELSE route.from_hash END
+            JOIN blocks AS parent_to ON parent_to.hash = CASE WHEN route.to_number >= route.from_number THEN blocks_to.parent_hash ELSE route.to_hash END
+            WHERE route.from_hash != route.to_hash
+        )
+    SELECT from_hash, from_number, to_hash, to_number FROM route
+        "#,
+    )
+    .map_err(sqlite_err("tree_route"))?;
+
+    let rows = statement
+        .query_map(
+            rusqlite::named_params! { ":from": &from[..], ":to": &to[..] },
+            |row| {
+                Ok((
+                    row.get::<_, [u8; 32]>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, [u8; 32]>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            },
+        )
+        .map_err(sqlite_err("tree_route"))?
+        .map(|row| {
+            let (from_hash, from_number, to_hash, to_number) =
+                row.map_err(sqlite_err("tree_route"))?;
+            let from_number =
+                u64::try_from(from_number).map_err(|_| CorruptedError::InvalidNumber)?;
+            let to_number = u64::try_from(to_number).map_err(|_| CorruptedError::InvalidNumber)?;
+            Ok(((from_hash, from_number), (to_hash, to_number)))
+        })
+        .collect::<Result<Vec<_>, CorruptedError>>()?;
+
+    let Some(&(last_from, last_to)) = rows.last() else {
+        return Ok(None);
+    };
+
+    // If the walk has stopped before both sides have joined, then one of the blocks of the
+    // chain is missing.
+    if last_from.0 != last_to.0 {
+        return Err(CorruptedError::BrokenChain {
+            number: cmp::max(last_from.1, last_to.1).saturating_sub(1),
+        });
+    }
+
+    // The side that doesn't go to its parent is repeated in the rows, hence the `dedup`.
+    let mut retracted = rows
+        .iter()
+        .map(|(from, _)| *from)
+        .filter(|from| *from != last_from)
+        .collect::<Vec<_>>();
+    retracted.dedup();
+    let mut enacted = rows
+        .iter()
+        .rev()
+        .map(|(_, to)| *to)
+        .filter(|to| *to != last_to)
+        .collect::<Vec<_>>();
+    enacted.dedup();
+
+    Ok(Some(TreeRoute {
+        common_ancestor: last_from,
+        retracted,
+        enacted,
+    }))
 }
 
-/// Error while calling [`SqliteFullDatabase::set_finalized`].
-#[derive(Debug, derive_more::Display, derive_more::From)]
-pub enum SetFinalizedError {
-    /// Error accessing the database.
-    Corrupted(CorruptedError),
-    /// New finalized block isn't in the database.
-    UnknownBlock,
-    /// New finalized block must be a child of the previous finalized block.
-    RevertForbidden,
+/// See [`block_ancestry_entry`].
+struct AncestryEntry {
+    /// `None` if the block is the first block that has been inserted in the database.
+    parent_hash: Option<[u8; 32]>,
+    number: u64,
+    /// `None` if the storage of the block has been pruned or if it is empty.
+    state_trie_root_hash: Option<[u8; 32]>,
 }
 
-/// Error while accessing the storage of the finalized block.
-#[derive(Debug, derive_more::Display, derive_more::From)]
-pub enum StorageAccessError {
-    /// Error accessing the database.
-    Corrupted(CorruptedError),
-    /// Some trie nodes of the storage of the requested block hash are missing.
-    IncompleteStorage,
-    /// Requested block couldn't be found in the database.
-    UnknownBlock,
+/// Returns the information about the given block necessary to walk its ancestry, or `None` if
+/// the block isn't in the database.
+fn block_ancestry_entry(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Option<AncestryEntry>, CorruptedError> {
+    let Some((parent_hash, number, state_trie_root_hash)) = prepare_cached(
+        database,
+        r#"SELECT parent_hash, number, state_trie_root_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("block_ancestry_entry"))?
+    .query_row((&block_hash[..],), |row| {
+        Ok((
+            row.get::<_, Option<[u8; 32]>>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, Option<[u8; 32]>>(2)?,
+        ))
+    })
+    .optional()
+    .map_err(sqlite_err("block_ancestry_entry"))?
+    else {
+        return Ok(None);
+    };
+
+    Ok(Some(AncestryEntry {
+        parent_hash,
+        number: u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?,
+        state_trie_root_hash,
+    }))
 }
 
-/// Error in the content of the database.
-// TODO: document and see if any entry is unused
-#[derive(Debug, derive_more::Display)]
-pub enum CorruptedError {
-    /// Block numbers are expected to be 64 bits.
-    // TODO: remove this and use stronger schema
-    InvalidNumber,
-    /// Finalized block number stored in the database doesn't match any block.
-    InvalidFinalizedNum,
-    /// A block hash is expected to be 32 bytes. This isn't the case.
-    InvalidBlockHashLen,
-    /// A trie hash is expected to be 32 bytes. This isn't the case.
-    InvalidTrieHashLen,
-    /// Values in the database are all well-formatted, but are incoherent.
-    #[display(fmt = "Invalid chain information: {_0}")]
-    InvalidChainInformation(chain_information::ValidityError),
-    /// The parent of a block in the database couldn't be found in that same database.
-    BrokenChain,
-    /// Missing a key in the `meta` table.
-    MissingMetaKey,
-    /// Some parts of the database refer to a block by its hash, but the block's constituents
-    /// couldn't be found.
-    MissingBlockHeader,
-    /// The header of a block in the database has failed to decode.
-    #[display(fmt = "Corrupted block header: {_0}")]
-    BlockHeaderCorrupted(header::Error),
-    /// Multiple different consensus algorithms are mixed within the database.
-    ConsensusAlgorithmMix,
-    /// The information about a Babe epoch found in the database has failed to decode.
-    InvalidBabeEpochInformation,
-    /// The version information about a storage entry has failed to decode.
-    InvalidTrieEntryVersion,
-    #[display(fmt = "Internal error: {_0}")]
-    Internal(InternalError),
+/// Returns `true` if all the nodes of the trie whose root has the given hash, including the
+/// nodes of the child tries it references, are in the database.
+fn trie_is_complete(
+    database: &rusqlite::Connection,
+    trie_root_hash: &[u8; 32],
+) -> Result<bool, CorruptedError> {
+    let (has_missing_node, too_deep) = prepare_cached(
+        database,
+        r#"
+        WITH RECURSIVE
+            -- The 16 possible child indices.
+            nibble(num) AS (VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+                (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')),
+
+            -- List of all the trie nodes reachable from the root. The recursion stops at a
+            -- depth of `:max_trie_depth` in order to not loop indefinitely if there is a
+            -- loop in the trie.
+            trie_nodes(node_hash, is_present, children, depth) AS (
+                SELECT :trie_root_hash, trie_node.hash IS NOT NULL, trie_node.children, 0
+                    FROM (SELECT 1) LEFT JOIN trie_node ON trie_node.hash = :trie_root_hash
+
+                UNION ALL
+                SELECT  child_merkle_value(trie_nodes.children, nibble.num), trie_node.hash IS NOT NULL, trie_node.children, trie_nodes.depth + 1
+                    FROM trie_nodes
+                    JOIN nibble
+                        ON child_merkle_value(trie_nodes.children, nibble.num) IS NOT NULL
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = child_merkle_value(trie_nodes.children, nibble.num)
+                    WHERE trie_nodes.is_present AND trie_nodes.depth < :max_trie_depth
+
+                UNION ALL
+                SELECT  trie_node_storage.trie_root_ref, trie_node.hash IS NOT NULL, trie_node.children, trie_nodes.depth + 1
+                    FROM trie_nodes
+                    JOIN trie_node_storage
+                        ON trie_nodes.node_hash = trie_node_storage.node_hash AND trie_node_storage.trie_root_ref IS NOT NULL
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = trie_node_storage.trie_root_ref
+                    WHERE trie_nodes.is_present AND trie_nodes.depth < :max_trie_depth
+            )
+
+        SELECT
+            EXISTS(SELECT 1 FROM trie_nodes WHERE is_present = false),
+            EXISTS(SELECT 1 FROM trie_nodes WHERE depth >= :max_trie_depth)
+        "#,
+    )
+    .map_err(sqlite_err("trie_is_complete"))?
+    .query_row(
+        rusqlite::named_params! {
+            ":trie_root_hash": &trie_root_hash[..],
+            ":max_trie_depth": i64::try_from(MAX_TRIE_DEPTH).unwrap(),
+        },
+        |row| Ok((row.get::<_, bool>(0)?, row.get::<_, bool>(1)?)),
+    )
+    .map_err(sqlite_err("trie_is_complete"))?;
+
+    if too_deep {
+        return Err(CorruptedError::TrieLoopDetected);
+    }
+
+    Ok(!has_missing_node)
+}
+
+fn block_justifications(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Justifications, CorruptedError> {
+    let mut statement = prepare_cached(
+        database,
+        r#"SELECT engine_id, justification FROM blocks_justifications WHERE hash = ? ORDER BY engine_id"#,
+    )
+    .map_err(sqlite_err("block_justifications"))?;
+    let rows = statement
+        .query_map((&block_hash[..],), |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })
+        .map_err(sqlite_err("block_justifications"))?;
+
+    let mut justifications = Vec::new();
+    for row in rows {
+        let (engine_id, justification) = row.map_err(sqlite_err("block_justifications"))?;
+        let engine_id =
+            <[u8; 4]>::try_from(&engine_id[..]).map_err(|_| CorruptedError::InvalidEngineIdLen)?;
+        justifications.push((engine_id, justification));
+    }
+    Ok(justifications)
+}
+
+/// See [`SqliteFullDatabase::full_block`].
+///
+/// The different parts of the block are read one after the other, and `database` must thus be
+/// within a transaction in order for them to be coherent.
+fn full_block(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Option<FullBlock>, CorruptedError> {
+    let Some(scale_encoded_header) = block_header(database, block_hash)? else {
+        return Ok(None);
+    };
+
+    let mut body = Vec::new();
+    let body = match with_block_extrinsics(database, block_hash, |extrinsic| {
+        body.push(extrinsic.to_vec());
+        ops::ControlFlow::Continue(())
+    }) {
+        Ok(Some(())) => Some(body),
+        Ok(None) => return Ok(None),
+        Err(BlockExtrinsicsError::BodyPruned) => None,
+        Err(BlockExtrinsicsError::Corrupted(err)) => return Err(err),
+    };
+
+    Ok(Some(FullBlock {
+        scale_encoded_header,
+        body,
+        justifications: block_justifications(database, block_hash)?,
+    }))
+}
+
+fn with_block_extrinsics(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    mut on_extrinsic: impl FnMut(&[u8]) -> ops::ControlFlow<()>,
+) -> Result<Option<()>, BlockExtrinsicsError> {
+    let body_pruned = prepare_cached(database, r#"SELECT body_pruned FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#)
+        .map_err(sqlite_err("with_block_extrinsics"))?
+        .query_row((&block_hash[..],), |row| row.get::<_, bool>(0))
+        .optional()
+        .map_err(sqlite_err("with_block_extrinsics"))?;
+    match body_pruned {
+        None => return Ok(None),
+        Some(true) => return Err(BlockExtrinsicsError::BodyPruned),
+        Some(false) => {}
+    }
+
+    let mut statement = prepare_cached(
+        database,
+        r#"SELECT extrinsics.bytes FROM blocks_body JOIN extrinsics ON extrinsics.hash = blocks_body.extrinsic_hash WHERE blocks_body.hash = ? ORDER BY idx ASC"#,
+    )
+    .map_err(sqlite_err("with_block_extrinsics"))?;
+    let mut rows = statement
+        .query((&block_hash[..],))
+        .map_err(sqlite_err("with_block_extrinsics"))?;
+    while let Some(row) = rows.next().map_err(sqlite_err("with_block_extrinsics"))? {
+        let extrinsic = match row
+            .get_ref(0)
+            .map_err(sqlite_err("with_block_extrinsics"))?
+        {
+            rusqlite::types::ValueRef::Blob(extrinsic) => extrinsic,
+            other => {
+                return Err(sqlite_err("with_block_extrinsics")(
+                    rusqlite::Error::InvalidColumnType(0, "extrinsic".into(), other.data_type()),
+                )
+                .into())
+            }
+        };
+
+        if on_extrinsic(extrinsic).is_break() {
+            break;
+        }
+    }
+
+    Ok(Some(()))
+}
+
+fn best_block_hash_by_number(
+    database: &rusqlite::Connection,
+    block_number: u64,
+) -> Result<Option<[u8; 32]>, CorruptedError> {
+    let block_number = match i64::try_from(block_number) {
+        Ok(n) => n,
+        Err(_) => return Ok(None),
+    };
+
+    let result = prepare_cached(database, BEST_BLOCK_HASH_BY_NUMBER_QUERY)
+        .map_err(sqlite_err("best_block_hash_by_number"))?
+        .query_row((block_number,), |row| row.get::<_, Vec<u8>>(0))
+        .optional()
+        .map_err(sqlite_err("best_block_hash_by_number"))
+        .and_then(|value| {
+            let Some(value) = value else { return Ok(None) };
+            Ok(Some(
+                <[u8; 32]>::try_from(&value[..])
+                    .map_err(|_| CorruptedError::InvalidBlockHashLen)?,
+            ))
+        })?;
+
+    Ok(result)
+}
+
+fn block_storage_get(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    key_vectored: &[u8],
+    is_main_trie: bool,
+) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+    Ok(
+        block_storage_entry(database, block_hash, key_vectored, is_main_trie, false)?.map(
+            |entry| {
+                (
+                    entry.value.unwrap_or_else(|| unreachable!()),
+                    entry.trie_entry_version,
+                )
+            },
+        ),
+    )
+}
+
+/// Storage entry found by [`block_storage_entry`].
+struct StorageEntry {
+    /// Value of the entry. `None` if only the hash of the value has been requested and is
+    /// stored in the database.
+    value: Option<Vec<u8>>,
+    /// Hash of the value stored in the database. `None` if the entry is the root of a child trie
+    /// or if it has been inserted before the hash was stored.
+    value_hash: Option<[u8; 32]>,
+    /// Merkle value of the node of the entry, if the entry has a value whose hash isn't stored
+    /// in the database.
+    node_without_value_hash: Option<Vec<u8>>,
+    trie_entry_version: trie::TrieEntryVersion,
+}
+
+/// Similar to [`block_storage_get`], but also returns the hash of the value stored in the
+/// database. If `hash_only` is `true`, the value isn't read if its hash is stored.
+fn block_storage_entry(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    key_vectored: &[u8],
+    is_main_trie: bool,
+    hash_only: bool,
+) -> Result<Option<StorageEntry>, StorageAccessError> {
+    // Note that, contrary to other trie traversals, these requests can't loop indefinitely
+    // even if there is a loop in the trie, as `search_remain` becomes shorter at each
+    // iteration.
+    let statement = if is_main_trie {
+        // Specialized version of the request below for the very common situation where the
+        // key is in the main trie, in which case there's no need to handle the `0x10` nibbles
+        // that indicate a jump to a child trie.
+        prepare_cached(
+            database,
+            r#"
+        WITH RECURSIVE
+            -- See the request below for explanations.
+            node_with_key(node_hash, children, search_remain) AS (
+                    SELECT
+                        IIF(COALESCE(SUBSTR(:key, 1, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key), trie_node.hash, NULL),
+                        IIF(COALESCE(SUBSTR(:key, 1, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key), trie_node.children, NULL),
+                        IIF(trie_node.partial_key IS NULL, NULL, COALESCE(SUBSTR(:key, 1 + LENGTH(unpack_nibbles(trie_node.partial_key))), X''))
+                    FROM blocks
+                    LEFT JOIN trie_node ON blocks.state_trie_root_hash = trie_node.hash
+                    WHERE blocks.hash = :block_hash
+                UNION ALL
+                SELECT
+                    IIF(SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))) = unpack_nibbles(trie_node.partial_key), child_merkle_value(node_with_key.children, SUBSTR(node_with_key.search_remain, 1, 1)), NULL),
+                    IIF(SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))) = unpack_nibbles(trie_node.partial_key), trie_node.children, NULL),
+                    CASE
+                        WHEN child_merkle_value(node_with_key.children, SUBSTR(node_with_key.search_remain, 1, 1)) IS NULL THEN X''
+                        WHEN unpack_nibbles(trie_node.partial_key) IS NULL THEN NULL
+                        WHEN SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))) = unpack_nibbles(trie_node.partial_key) THEN SUBSTR(node_with_key.search_remain, 2 + LENGTH(unpack_nibbles(trie_node.partial_key)))
+                        ELSE X'' END
+                FROM node_with_key
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = child_merkle_value(node_with_key.children, SUBSTR(node_with_key.search_remain, 1, 1))
+                    WHERE LENGTH(node_with_key.search_remain) >= 1
+            )
+        SELECT COUNT(blocks.hash) >= 1, node_with_key.search_remain IS NULL, IIF(:hash_only AND trie_node_storage.value_hash IS NOT NULL, X'', COALESCE(decompress_value(trie_node_storage.value, trie_node_storage.value_compressed), trie_node_storage.trie_root_ref)), trie_node_storage.trie_entry_version, trie_node_storage.value_hash, IIF(trie_node_storage.value IS NOT NULL AND trie_node_storage.value_hash IS NULL, trie_node_storage.node_hash, NULL)
+        FROM blocks
+        JOIN node_with_key ON LENGTH(node_with_key.search_remain) = 0 OR node_with_key.search_remain IS NULL
+        LEFT JOIN trie_node_storage ON node_with_key.node_hash = trie_node_storage.node_hash AND node_with_key.search_remain IS NOT NULL
+        WHERE blocks.hash = :block_hash;
+        "#,
+        )
+    } else {
+        prepare_cached(
+            database,
+            r#"
+        WITH RECURSIVE
+            -- At the end of the recursive statement, `node_with_key` must always contain
+            -- one and exactly one item where `search_remain` is either empty or null. Empty
+            -- indicates that we have found a match, while null means that the search has
+            -- been interrupted due to a storage entry not being in the database. If
+            -- `search_remain` is empty, then `node_hash` is either a hash in case of a match
+            -- or null in case there is no entry with the requested key. If `search_remain`
+            -- is null, then `node_hash` is irrelevant.
+            --
+            -- In order to properly handle the situation where the key is empty, the initial
+            -- request of the recursive table building must check whether the partial key of
+            -- the root matches. In other words, all the entries of `node_with_key` (where
+            -- `node_hash` is non-null) contain entries that are known to be in the database
+            -- and after the partial key has already been verified to be correct.
+            --
+            -- `children` is a copy of the column of `trie_node` of the entry whose hash is
+            -- `node_hash`, in order to not look up each node twice. It is null if `node_hash` is
+            -- null.
+            node_with_key(node_hash, children, search_remain) AS (
+                    SELECT
+                        IIF(COALESCE(SUBSTR(:key, 1, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key), trie_node.hash, NULL),
+                        IIF(COALESCE(SUBSTR(:key, 1, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key), trie_node.children, NULL),
+                        IIF(trie_node.partial_key IS NULL, NULL, COALESCE(SUBSTR(:key, 1 + LENGTH(unpack_nibbles(trie_node.partial_key))), X''))
+                    FROM blocks
+                    LEFT JOIN trie_node ON blocks.state_trie_root_hash = trie_node.hash
+                    WHERE blocks.hash = :block_hash
+                UNION ALL
+                SELECT
+                    CASE
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' AND SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(child_trie_root.partial_key))) = unpack_nibbles(child_trie_root.partial_key) THEN trie_node_storage.trie_root_ref
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' THEN NULL
+                        WHEN SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))) = unpack_nibbles(trie_node.partial_key) THEN child_merkle_value(node_with_key.children, SUBSTR(node_with_key.search_remain, 1, 1))
+                        ELSE NULL END,
+                    CASE
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' AND SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(child_trie_root.partial_key))) = unpack_nibbles(child_trie_root.partial_key) THEN child_trie_root.children
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' THEN NULL
+                        WHEN SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))) = unpack_nibbles(trie_node.partial_key) THEN trie_node.children
+                        ELSE NULL END,
+                    CASE
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' AND trie_node_storage.trie_root_ref IS NULL THEN X''
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' AND unpack_nibbles(child_trie_root.partial_key) IS NULL THEN NULL
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' AND SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(child_trie_root.partial_key))) = unpack_nibbles(child_trie_root.partial_key) THEN SUBSTR(node_with_key.search_remain, 2 + LENGTH(unpack_nibbles(child_trie_root.partial_key)))
+                        WHEN HEX(SUBSTR(node_with_key.search_remain, 1, 1)) = '10' THEN X''
+                        WHEN child_merkle_value(node_with_key.children, SUBSTR(node_with_key.search_remain, 1, 1)) IS NULL THEN X''
+                        WHEN unpack_nibbles(trie_node.partial_key) IS NULL THEN NULL
+                        WHEN SUBSTR(node_with_key.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))) = unpack_nibbles(trie_node.partial_key) THEN SUBSTR(node_with_key.search_remain, 2 + LENGTH(unpack_nibbles(trie_node.partial_key)))
+                        ELSE X'' END
+                FROM node_with_key
+                    LEFT JOIN trie_node
+                        ON trie_node.hash = child_merkle_value(node_with_key.children, SUBSTR(node_with_key.search_remain, 1, 1))
+                    LEFT JOIN trie_node_storage
+                        ON node_with_key.node_hash = trie_node_storage.node_hash
+                    LEFT JOIN trie_node AS child_trie_root
+                        ON child_trie_root.hash = trie_node_storage.trie_root_ref
+                    WHERE LENGTH(node_with_key.search_remain) >= 1
+            )
+        SELECT COUNT(blocks.hash) >= 1, node_with_key.search_remain IS NULL, IIF(:hash_only AND trie_node_storage.value_hash IS NOT NULL, X'', COALESCE(decompress_value(trie_node_storage.value, trie_node_storage.value_compressed), trie_node_storage.trie_root_ref)), trie_node_storage.trie_entry_version, trie_node_storage.value_hash, IIF(trie_node_storage.value IS NOT NULL AND trie_node_storage.value_hash IS NULL, trie_node_storage.node_hash, NULL)
+        FROM blocks
+        JOIN node_with_key ON LENGTH(node_with_key.search_remain) = 0 OR node_with_key.search_remain IS NULL
+        LEFT JOIN trie_node_storage ON node_with_key.node_hash = trie_node_storage.node_hash AND node_with_key.search_remain IS NOT NULL
+        WHERE blocks.hash = :block_hash;
+        "#,
+        )
+    };
+    let mut statement =
+        statement.map_err(|err| StorageAccessError::from(sqlite_err("block_storage_get")(err)))?;
+
+    // In order to debug the SQL queries above (for example in case of a failing test),
+    // uncomment this block:
+    //
+    /*println!("{:?}", {
+        let mut statement = prepare_cached(
+            &database,
+                r#"
+            WITH RECURSIVE
+                copy-paste the definition of node_with_key here
+
+            SELECT * FROM node_with_key"#).unwrap();
+        statement
+            .query_map(
+                rusqlite::named_params! {
+                    ":block_hash": &block_hash[..],
+                    ":key": key_vectored,
+                },
+                |row| {
+                    let node_hash = row.get::<_, Option<Vec<u8>>>(0)?.map(hex::encode);
+                    let search_remain = row.get::<_, Option<Vec<u8>>>(1)?;
+                    Ok((node_hash, search_remain))
+                },
+            )
+            .unwrap()
+            .collect::<Vec<_>>()
+    });*/
+
+    let (
+        has_block,
+        incomplete_storage,
+        value,
+        trie_entry_version,
+        value_hash,
+        node_without_value_hash,
+    ) = statement
+        .query_row(
+            rusqlite::named_params! {
+                ":block_hash": &block_hash[..],
+                ":key": key_vectored,
+                ":hash_only": hash_only,
+            },
+            |row| {
+                let has_block = row.get::<_, i64>(0)? != 0;
+                let incomplete_storage = row.get::<_, i64>(1)? != 0;
+                let value = row.get::<_, Option<Vec<u8>>>(2)?;
+                let trie_entry_version = row.get::<_, Option<i64>>(3)?;
+                let value_hash = row.get::<_, Option<Vec<u8>>>(4)?;
+                let node_without_value_hash = row.get::<_, Option<Vec<u8>>>(5)?;
+                Ok((
+                    has_block,
+                    incomplete_storage,
+                    value,
+                    trie_entry_version,
+                    value_hash,
+                    node_without_value_hash,
+                ))
+            },
+        )
+        .map_err(|err| StorageAccessError::from(sqlite_err("block_storage_get")(err)))?;
+
+    if !has_block {
+        return Err(StorageAccessError::UnknownBlock);
+    }
+
+    if incomplete_storage {
+        return Err(StorageAccessError::IncompleteStorage);
+    }
+
+    let Some(value) = value else { return Ok(None) };
+
+    let trie_entry_version = u8::try_from(trie_entry_version.unwrap())
+        .ok()
+        .and_then(|version| trie::TrieEntryVersion::try_from(version).ok())
+        .ok_or(CorruptedError::InvalidTrieEntryVersion)
+        .map_err(StorageAccessError::Corrupted)?;
+    let value_hash = value_hash
+        .map(|hash| <[u8; 32]>::try_from(&hash[..]))
+        .transpose()
+        .map_err(|_| StorageAccessError::Corrupted(CorruptedError::InvalidValueHashLen))?;
+    Ok(Some(StorageEntry {
+        value: if hash_only && value_hash.is_some() {
+            None
+        } else {
+            Some(value)
+        },
+        value_hash,
+        node_without_value_hash,
+        trie_entry_version,
+    }))
+}
+
+fn block_storage_next_key(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    parent_tries_paths_nibbles: &[Vec<u8>],
+    key_nibbles: &[u8],
+    prefix_nibbles: &[u8],
+    branch_nodes: bool,
+    nodes_read: &mut usize,
+) -> Result<Option<Vec<u8>>, StorageAccessError> {
+    let mut trie_root_hash = prepare_cached(
+        database,
+        r#"SELECT state_trie_root_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("block_storage_next_key"))?
+    .query_row((&block_hash[..],), |row| row.get::<_, Option<Vec<u8>>>(0))
+    .optional()
+    .map_err(sqlite_err("block_storage_next_key"))?
+    .ok_or(StorageAccessError::UnknownBlock)?
+    // A missing root can mean that the trie is empty, but also that its storage has been
+    // removed from the database. We can't make the difference.
+    .ok_or(StorageAccessError::IncompleteStorage)?;
+
+    for parent_trie_path in parent_tries_paths_nibbles {
+        match trie_root_ref(database, trie_root_hash, parent_trie_path)? {
+            Some(child_trie_root_hash) => trie_root_hash = child_trie_root_hash,
+            None => return Ok(None),
+        }
+    }
+
+    trie_next_key(
+        database,
+        trie_root_hash,
+        key_nibbles,
+        prefix_nibbles,
+        branch_nodes,
+        nodes_read,
+    )
+}
+
+fn block_storage_closest_descendant_merkle_value(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    key_vectored: &[u8],
+) -> Result<Option<Vec<u8>>, StorageAccessError> {
+    // Note that, contrary to other trie traversals, this request can't loop indefinitely even
+    // if there is a loop in the trie, as `search_remain` becomes shorter at each iteration.
+    let mut statement = prepare_cached(
+        database,
+        r#"
+    WITH RECURSIVE
+        -- At the end of the recursive statement, `closest_descendant` must always contain
+        -- at most one item where `search_remain` is either empty or null. Empty
+        -- indicates that we have found a match, while null means that the search has
+        -- been interrupted due to a storage entry not being in the database. If
+        -- `search_remain` is null, then `node_hash` is irrelevant.
+        -- If `closest_descendant` doesn't have any entry where `search_remain` is empty
+        -- or null, then the request key doesn't have any descendant.
+        --
+        -- `children` is a copy of the column of `trie_node` of the entry whose hash is
+        -- `node_hash`, in order to not look up each node twice.
+        closest_descendant(node_hash, children, search_remain) AS (
+            SELECT
+                    blocks.state_trie_root_hash,
+                    trie_node.children,
+                    CASE
+                        WHEN unpack_nibbles(trie_node.partial_key) IS NULL AND LENGTH(:key) = 0
+                            THEN X''   -- Trie root node isn't in database, but since key is empty we have a match anyway
+                        WHEN unpack_nibbles(trie_node.partial_key) IS NULL AND LENGTH(:key) != 0
+                            THEN NULL  -- Trie root node isn't in database and we can't iterate further
+                        ELSE
+                            COALESCE(SUBSTR(:key, 1 + LENGTH(unpack_nibbles(trie_node.partial_key))), X'')
+                    END
+                FROM blocks
+                LEFT JOIN trie_node ON blocks.state_trie_root_hash = trie_node.hash
+                WHERE blocks.hash = :block_hash
+                    AND (
+                        unpack_nibbles(trie_node.partial_key) IS NULL
+                        OR COALESCE(SUBSTR(unpack_nibbles(trie_node.partial_key), 1, LENGTH(:key)), X'') = :key
+                        OR COALESCE(SUBSTR(:key, 1, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key)
+                    )
+
+            UNION ALL
+            SELECT
+                    COALESCE(child_merkle_value(closest_descendant.children, SUBSTR(closest_descendant.search_remain, 1, 1)), trie_node_storage.trie_root_ref),
+                    trie_node.children,
+                    CASE
+                        WHEN child_merkle_value(closest_descendant.children, SUBSTR(closest_descendant.search_remain, 1, 1)) IS NULL AND trie_node_storage.trie_root_ref IS NULL
+                            THEN X''      -- No child or child trie matching the key.
+                        WHEN trie_node.hash IS NULL AND LENGTH(closest_descendant.search_remain) = 1
+                            THEN X''      -- Descendant node not in trie but we know that it's the result.
+                        WHEN trie_node.hash IS NULL
+                            THEN NULL     -- Descendant node not in trie.
+                        WHEN COALESCE(SUBSTR(unpack_nibbles(trie_node.partial_key), 1, LENGTH(closest_descendant.search_remain) - 1), X'') = COALESCE(SUBSTR(closest_descendant.search_remain, 2), X'')
+                                OR COALESCE(SUBSTR(closest_descendant.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key)
+                            THEN SUBSTR(closest_descendant.search_remain, 2 + LENGTH(unpack_nibbles(trie_node.partial_key)))
+                        ELSE
+                            X''           -- Unreachable.
+                    END
+                FROM closest_descendant
+                LEFT JOIN trie_node_storage
+                    ON closest_descendant.node_hash = trie_node_storage.node_hash
+                    AND HEX(SUBSTR(closest_descendant.search_remain, 1, 1)) = '10'
+                    AND trie_node_storage.trie_root_ref IS NOT NULL
+                LEFT JOIN trie_node ON trie_node.hash = COALESCE(child_merkle_value(closest_descendant.children, SUBSTR(closest_descendant.search_remain, 1, 1)), trie_node_storage.trie_root_ref)
+                WHERE
+                    LENGTH(closest_descendant.search_remain) >= 1
+                    AND (
+                        trie_node.hash IS NULL
+                        OR COALESCE(SUBSTR(unpack_nibbles(trie_node.partial_key), 1, LENGTH(closest_descendant.search_remain) - 1), X'') = COALESCE(SUBSTR(closest_descendant.search_remain, 2), X'')
+                        OR COALESCE(SUBSTR(closest_descendant.search_remain, 2, LENGTH(unpack_nibbles(trie_node.partial_key))), X'') = unpack_nibbles(trie_node.partial_key)
+                    )
+        )
+    SELECT COUNT(blocks.hash) >= 1, closest_descendant.node_hash IS NOT NULL AND closest_descendant.search_remain IS NULL, closest_descendant.node_hash
+    FROM blocks
+    LEFT JOIN closest_descendant ON LENGTH(closest_descendant.search_remain) = 0 OR closest_descendant.search_remain IS NULL
+    WHERE blocks.hash = :block_hash
+    LIMIT 1"#,
+    )
+    .map_err(sqlite_err("block_storage_closest_descendant_merkle_value"))?;
+
+    // In order to debug the SQL query above (for example in case of a failing test),
+    // uncomment this block:
+    //
+    /*println!("{:?}", {
+        let mut statement = prepare_cached(
+            &database,
+                r#"
+            WITH RECURSIVE
+                copy-paste the definition of closest_descendant here
+
+            SELECT * FROM closest_descendant"#).unwrap();
+        statement
+            .query_map(
+                rusqlite::named_params! {
+                    ":block_hash": &block_hash[..],
+                    ":key": key_vectored,
+                },
+                |row| {
+                    let node_hash = row.get::<_, Option<Vec<u8>>>(0)?.map(hex::encode);
+                    let search_remain = row.get::<_, Option<Vec<u8>>>(1)?;
+                    Ok((node_hash, search_remain))
+                },
+            )
+            .unwrap()
+            .collect::<Vec<_>>()
+    });*/
+
+    let (has_block, incomplete_storage, merkle_value) = statement
+        .query_row(
+            rusqlite::named_params! {
+                ":block_hash": &block_hash[..],
+                ":key": key_vectored,
+            },
+            |row| {
+                let has_block = row.get::<_, i64>(0)? != 0;
+                let incomplete_storage = row.get::<_, i64>(1)? != 0;
+                let merkle_value = row.get::<_, Option<Vec<u8>>>(2)?;
+                Ok((has_block, incomplete_storage, merkle_value))
+            },
+        )
+        .map_err(|err| {
+            StorageAccessError::from(sqlite_err("block_storage_closest_descendant_merkle_value")(
+                err,
+            ))
+        })?;
+
+    if !has_block {
+        return Err(StorageAccessError::UnknownBlock);
+    }
+
+    if incomplete_storage {
+        return Err(StorageAccessError::IncompleteStorage);
+    }
+
+    Ok(merkle_value)
+}
+
+/// Loads the trie node with the given hash from the database. Returns `None` if the node is
+/// missing from the database.
+fn trie_node(
+    database: &rusqlite::Connection,
+    node_hash: &[u8],
+) -> Result<Option<TrieNode>, CorruptedError> {
+    prepare_cached(
+        database,
+        r#"
+        SELECT unpack_nibbles(trie_node.partial_key), trie_node_storage.node_hash IS NOT NULL, trie_node_storage.trie_root_ref
+        FROM trie_node
+        LEFT JOIN trie_node_storage ON trie_node_storage.node_hash = trie_node.hash
+        WHERE trie_node.hash = ?"#,
+    )
+    .map_err(sqlite_err("trie_node"))?
+    .query_row((node_hash,), |row| {
+        Ok(TrieNode {
+            partial_key: row.get(0)?,
+            has_storage_value: row.get(1)?,
+            trie_root_ref: row.get(2)?,
+        })
+    })
+    .optional()
+    .map_err(sqlite_err("trie_node"))
+}
+
+/// Returns the children of the given trie node, as a list of child index and hash, ordered by
+/// increasing child index.
+fn trie_node_children(
+    database: &rusqlite::Connection,
+    node_hash: &[u8],
+) -> Result<Vec<(u8, Vec<u8>)>, CorruptedError> {
+    let children = prepare_cached(database, r#"SELECT children FROM trie_node WHERE hash = ?"#)
+        .map_err(sqlite_err("trie_node_children"))?
+        .query_row((node_hash,), |row| row.get::<_, Option<Vec<u8>>>(0))
+        .optional()
+        .map_err(sqlite_err("trie_node_children"))?
+        .flatten();
+    let Some(children) = children else {
+        return Ok(Vec::new());
+    };
+
+    children_list(&children)
+}
+
+/// Inserts an extrinsic of the body of the given block at the given index.
+///
+/// The content of the extrinsic is stored in the `extrinsics` table, where it is shared with all
+/// the other blocks that contain the same extrinsic.
+fn insert_body_extrinsic(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    index: i64,
+    extrinsic: &[u8],
+) -> Result<(), rusqlite::Error> {
+    let extrinsic_hash = blake2_hash(extrinsic);
+    prepare_cached(
+        database,
+        "INSERT OR IGNORE INTO extrinsics(hash, bytes) VALUES (?, ?)",
+    )?
+    .execute((&extrinsic_hash[..], extrinsic))?;
+    prepare_cached(
+        database,
+        "INSERT INTO blocks_body(hash, idx, extrinsic_hash) VALUES (?, ?, ?)",
+    )?
+    .execute((&block_hash[..], index, &extrinsic_hash[..]))?;
+    Ok(())
+}
+
+/// Splits a SCALE-encoded list of extrinsics into the extrinsics it contains. Returns `None` if
+/// the encoding is invalid.
+fn decode_scale_body(scale_encoded_body: &[u8]) -> Option<Vec<&[u8]>> {
+    let (mut remaining, num_extrinsics) =
+        util::nom_scale_compact_usize::<nom::error::Error<&[u8]>>(scale_encoded_body).ok()?;
+
+    // Each extrinsic takes at least one byte, which bounds the capacity to allocate.
+    let mut extrinsics = Vec::with_capacity(cmp::min(num_extrinsics, remaining.len()));
+    for _ in 0..num_extrinsics {
+        let (rest, extrinsic) =
+            util::nom_bytes_decode::<nom::error::Error<&[u8]>>(remaining).ok()?;
+        extrinsics.push(extrinsic);
+        remaining = rest;
+    }
+
+    if !remaining.is_empty() {
+        return None;
+    }
+
+    Some(extrinsics)
+}
+
+/// Encodes the given header, and verifies that the encoded header decodes back into the same
+/// header. Returns `None` if it doesn't.
+///
+/// Block numbers found in the digest are truncated to `block_number_bytes` bytes when encoded,
+/// and a header built for a different number of bytes might not decode at all or decode into a
+/// different header.
+fn encode_header_checked(header: &header::HeaderRef, block_number_bytes: usize) -> Option<Vec<u8>> {
+    let scale_encoded = header.scale_encoding_vec(block_number_bytes);
+    let decoded = header::decode(&scale_encoded, block_number_bytes).ok()?;
+
+    // Block numbers are never truncated when encoded using the size of a `u64`, which makes it
+    // possible to compare the two headers through their encoding.
+    let lossless_bytes = cmp::max(block_number_bytes, mem::size_of::<u64>());
+    if decoded.scale_encoding_vec(lossless_bytes) != header.scale_encoding_vec(lossless_bytes) {
+        return None;
+    }
+
+    Some(scale_encoded)
+}
+
+/// Returns the blake2 hash of the given data.
+fn blake2_hash(data: &[u8]) -> [u8; 32] {
+    <[u8; 32]>::try_from(blake2_rfc::blake2b::blake2b(32, &[], data).as_bytes())
+        .unwrap_or_else(|_| unreachable!())
 }
 
-/// Low-level database error, such as an error while accessing the file system.
-#[derive(Debug, derive_more::Display)]
-pub struct InternalError(rusqlite::Error);
+/// Compresses a storage value according to the given configuration. Returns `None` if the value
+/// must be stored uncompressed, either because it is below the threshold or because compressing
+/// it doesn't reduce its size.
+fn compress_value(value: &[u8], config: &CompressionConfig) -> Option<Vec<u8>> {
+    if value.len() < config.threshold {
+        return None;
+    }
 
-fn meta_get_blob(
-    database: &rusqlite::Connection,
-    key: &str,
-) -> Result<Option<Vec<u8>>, CorruptedError> {
-    let value = database
-        .prepare_cached(r#"SELECT value_blob FROM meta WHERE key = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .query_row((key,), |row| row.get::<_, Vec<u8>>(0))
-        .optional()
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(value)
+    // Compressing a slice in memory can only fail if the compression level is invalid, in which
+    // case the value is simply stored uncompressed.
+    let compressed = zstd::bulk::compress(value, config.level).ok()?;
+    if compressed.len() >= value.len() {
+        return None;
+    }
+    Some(compressed)
 }
 
-fn meta_get_number(
+/// Returns `true` if the node found in the database with the Merkle value of `node` is identical
+/// to `node`. The trie entry version isn't compared.
+fn trie_node_matches(
     database: &rusqlite::Connection,
-    key: &str,
-) -> Result<Option<u64>, CorruptedError> {
-    let value = database
-        .prepare_cached(r#"SELECT value_number FROM meta WHERE key = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .query_row((key,), |row| row.get::<_, i64>(0))
+    node: &InsertTrieNode,
+) -> Result<bool, CorruptedError> {
+    let Some(existing) = trie_node(database, &node.merkle_value)? else {
+        return Ok(false);
+    };
+    if existing.partial_key != *node.partial_key_nibbles {
+        return Ok(false);
+    }
+
+    let existing_value = prepare_cached(database, r#"SELECT decompress_value(value, value_compressed) FROM trie_node_storage WHERE node_hash = ?"#)
+        .map_err(sqlite_err("trie_node_matches"))?
+        .query_row((&node.merkle_value,), |row| {
+            row.get::<_, Option<Vec<u8>>>(0)
+        })
         .optional()
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(value.map(|value| u64::from_ne_bytes(value.to_ne_bytes())))
-}
+        .map_err(sqlite_err("trie_node_matches"))?
+        .flatten();
+    let storage_matches = match &node.storage_value {
+        InsertTrieNodeStorageValue::NoValue => !existing.has_storage_value,
+        InsertTrieNodeStorageValue::Value {
+            value,
+            references_merkle_value: true,
+        } => existing.trie_root_ref.as_deref() == Some(&value[..]),
+        InsertTrieNodeStorageValue::Value {
+            value,
+            references_merkle_value: false,
+        } => existing_value.as_deref() == Some(&value[..]),
+    };
+    if !storage_matches {
+        return Ok(false);
+    }
 
-fn meta_clear(database: &rusqlite::Connection, key: &str) -> Result<(), CorruptedError> {
-    database
-        .prepare_cached(r#"DELETE FROM meta WHERE key = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .execute((key,))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(())
+    let expected_children = node
+        .children_merkle_values
+        .iter()
+        .enumerate()
+        .filter_map(|(child_num, child)| {
+            let child_num = u8::try_from(child_num).unwrap_or_else(|_| unreachable!());
+            Some((child_num, child.as_ref()?.to_vec()))
+        })
+        .collect::<Vec<_>>();
+    Ok(trie_node_children(database, &node.merkle_value)? == expected_children)
 }
 
-fn meta_set_blob(
+/// Follows `key_nibbles` from the root of the given trie, and returns the hash of the root of the
+/// trie referenced by the storage value of the node found there.
+///
+/// Returns `None` if there is no node with this key, or if its storage value isn't a reference
+/// to another trie.
+fn trie_root_ref(
     database: &rusqlite::Connection,
-    key: &str,
-    value: &[u8],
-) -> Result<(), CorruptedError> {
-    database
-        .prepare_cached(r#"INSERT OR REPLACE INTO meta(key, value_blob) VALUES (?, ?)"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .execute((key, value))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(())
+    trie_root_hash: Vec<u8>,
+    key_nibbles: &[u8],
+) -> Result<Option<Vec<u8>>, StorageAccessError> {
+    let mut node_hash = trie_root_hash;
+    let mut key_remain = key_nibbles;
+
+    loop {
+        let node = trie_node(database, &node_hash)?.ok_or(StorageAccessError::IncompleteStorage)?;
+
+        let Some(after_partial_key) = key_remain.strip_prefix(&node.partial_key[..]) else {
+            return Ok(None);
+        };
+
+        let Some((child_num, after_child_num)) = after_partial_key.split_first() else {
+            return Ok(node.trie_root_ref);
+        };
+
+        let Some((_, child_hash)) = trie_node_children(database, &node_hash)?
+            .into_iter()
+            .find(|(n, _)| n == child_num)
+        else {
+            return Ok(None);
+        };
+
+        node_hash = child_hash;
+        key_remain = after_child_num;
+    }
 }
 
-fn meta_set_number(
+/// Returns the first key of the given trie that is superior or equal to `key_nibbles`. See
+/// [`SqliteFullDatabase::block_storage_next_key`].
+///
+/// `nodes_read` is increased by the number of trie nodes that have been read from the database.
+fn trie_next_key(
     database: &rusqlite::Connection,
-    key: &str,
-    value: u64,
-) -> Result<(), CorruptedError> {
-    database
-        .prepare_cached(r#"INSERT OR REPLACE INTO meta(key, value_number) VALUES (?, ?)"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .execute((key, i64::from_ne_bytes(value.to_ne_bytes())))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(())
+    trie_root_hash: Vec<u8>,
+    key_nibbles: &[u8],
+    prefix_nibbles: &[u8],
+    branch_nodes: bool,
+    nodes_read: &mut usize,
+) -> Result<Option<Vec<u8>>, StorageAccessError> {
+    // Compares `a` and `b` considering only their common length.
+    let cmp_common = |a: &[u8], b: &[u8]| {
+        let len = cmp::min(a.len(), b.len());
+        a[..len].cmp(&b[..len])
+    };
+
+    // If `key_nibbles` is superior to all the keys that start with the prefix, then so is the
+    // result, and there's no need to look at the trie at all.
+    if cmp_common(key_nibbles, prefix_nibbles) == cmp::Ordering::Greater {
+        return Ok(None);
+    }
+
+    // Nodes that remain to be visited, with the key of their parent plus child index, a boolean
+    // indicating whether this key is a prefix of `key_nibbles`, and the depth of the node. Nodes
+    // are visited depth-first and in the order of their child index, which visits keys in
+    // increasing order. Nodes whose key is known to be inferior to `key_nibbles`, or superior to
+    // all the keys that start with the prefix, are never pushed to the stack.
+    let mut stack = vec![(trie_root_hash, Vec::new(), true, 0)];
+
+    while let Some((node_hash, mut node_key, mut follows_key, depth)) = stack.pop() {
+        if depth >= MAX_TRIE_DEPTH {
+            return Err(StorageAccessError::Corrupted(
+                CorruptedError::TrieLoopDetected,
+            ));
+        }
+
+        // The first key found by the search is always the result, even if it doesn't start with
+        // the prefix. Because all the nodes below this one start with `node_key`, we can
+        // sometimes know in advance that the result can't start with the prefix.
+        if !follows_key && cmp_common(&node_key, prefix_nibbles) == cmp::Ordering::Less {
+            return Ok(None);
+        }
+
+        *nodes_read += 1;
+        let node = trie_node(database, &node_hash)?.ok_or(StorageAccessError::IncompleteStorage)?;
+        node_key.extend_from_slice(&node.partial_key);
+
+        // The partial key of the node can lead outside of the prefix, in which case all the
+        // nodes remaining in the stack, which have even higher keys, are outside as well.
+        if cmp_common(&node_key, prefix_nibbles) == cmp::Ordering::Greater {
+            return Ok(None);
+        }
+
+        if follows_key {
+            match cmp_common(&node_key, key_nibbles) {
+                cmp::Ordering::Less => continue,
+                cmp::Ordering::Greater => follows_key = false,
+                cmp::Ordering::Equal if node_key.len() >= key_nibbles.len() => follows_key = false,
+                cmp::Ordering::Equal => {}
+            }
+        }
+
+        if !follows_key && (branch_nodes || node.has_storage_value) {
+            return Ok(if node_key.starts_with(prefix_nibbles) {
+                Some(node_key)
+            } else {
+                None
+            });
+        }
+
+        for (child_num, child_hash) in trie_node_children(database, &node_hash)?.into_iter().rev() {
+            let child_follows_key = if follows_key {
+                match child_num.cmp(&key_nibbles[node_key.len()]) {
+                    cmp::Ordering::Less => continue,
+                    cmp::Ordering::Equal => true,
+                    cmp::Ordering::Greater => false,
+                }
+            } else {
+                false
+            };
+
+            let mut child_key = node_key.clone();
+            child_key.push(child_num);
+
+            // Children are iterated in decreasing order. If this child is past the prefix, then
+            // so are the nodes already in the stack, which come after this node, and none of
+            // them will ever be visited.
+            if cmp_common(&child_key, prefix_nibbles) == cmp::Ordering::Greater {
+                stack.clear();
+                continue;
+            }
+
+            stack.push((child_hash, child_key, child_follows_key, depth + 1));
+        }
+    }
+
+    Ok(None)
 }
 
 fn has_block(database: &rusqlite::Connection, hash: &[u8]) -> Result<bool, CorruptedError> {
-    database
-        .prepare_cached(r#"SELECT COUNT(*) FROM blocks WHERE hash = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+    prepare_cached(database, r#"SELECT COUNT(*) FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#)
+        .map_err(sqlite_err("has_block"))?
         .query_row((hash,), |row| Ok(row.get_unwrap::<_, i64>(0) != 0))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))
+        .map_err(sqlite_err("has_block"))
+}
+
+/// Returns `true` if the block with the given hash is stored in the database as part of a chain
+/// other than the current one.
+fn block_of_other_chain(
+    database: &rusqlite::Connection,
+    hash: &[u8],
+) -> Result<bool, CorruptedError> {
+    prepare_cached(
+        database,
+        r#"SELECT COUNT(*) FROM blocks WHERE hash = ? AND chain_id != (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("block_of_other_chain"))?
+    .query_row((hash,), |row| Ok(row.get_unwrap::<_, i64>(0) != 0))
+    .map_err(sqlite_err("block_of_other_chain"))
 }
 
 // TODO: the fact that the meta table stores blobs makes it impossible to use joins ; fix that
@@ -1887,13 +6134,20 @@ fn finalized_num(database: &rusqlite::Connection) -> Result<u64, CorruptedError>
 }
 
 fn finalized_hash(database: &rusqlite::Connection) -> Result<[u8; 32], CorruptedError> {
-    let value = database
-        .prepare_cached(r#"SELECT hash FROM blocks WHERE number = (SELECT value_number FROM meta WHERE key = "finalized")"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .query_row((), |row| row.get::<_, Vec<u8>>(0))
-        .optional()
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .ok_or(CorruptedError::InvalidFinalizedNum)?;
+    // Blocks that aren't descendants of the finalized block can still be found at the height
+    // of the finalized block until they are purged, and the finalized block is distinguished
+    // from them by being part of the best chain.
+    let mut values = prepare_cached(database, r#"SELECT hash FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number = (SELECT value_number FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = "finalized") AND is_best_chain = TRUE LIMIT 2"#)
+        .map_err(sqlite_err("finalized_hash"))?
+        .query_map((), |row| row.get::<_, Vec<u8>>(0))
+        .map_err(sqlite_err("finalized_hash"))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(sqlite_err("finalized_hash"))?;
+
+    if values.len() >= 2 {
+        return Err(CorruptedError::MultipleFinalizedBlocks);
+    }
+    let value = values.pop().ok_or(CorruptedError::InvalidFinalizedNum)?;
 
     if value.len() == 32 {
         let mut out = [0; 32];
@@ -1904,37 +6158,129 @@ fn finalized_hash(database: &rusqlite::Connection) -> Result<[u8; 32], Corrupted
     }
 }
 
-fn block_hashes_by_number(
+fn blocks_by_number(
     database: &rusqlite::Connection,
     number: u64,
-) -> Result<Vec<[u8; 32]>, CorruptedError> {
+) -> Result<Vec<BlockAtNumber>, CorruptedError> {
     let number = match i64::try_from(number) {
         Ok(n) => n,
         Err(_) => return Ok(Vec::new()),
     };
 
-    database
-        .prepare_cached(r#"SELECT hash FROM blocks WHERE number = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .query_map((number,), |row| row.get::<_, Vec<u8>>(0))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+    prepare_cached(database, BLOCKS_BY_NUMBER_QUERY)
+        .map_err(sqlite_err("blocks_by_number"))?
+        .query_map((number,), |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, bool>(1)?))
+        })
+        .map_err(sqlite_err("blocks_by_number"))?
         .map(|value| {
-            let value = value.map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-            <[u8; 32]>::try_from(&value[..]).map_err(|_| CorruptedError::InvalidBlockHashLen)
+            let (hash, is_best_chain) = value.map_err(sqlite_err("blocks_by_number"))?;
+            Ok(BlockAtNumber {
+                hash: <[u8; 32]>::try_from(&hash[..])
+                    .map_err(|_| CorruptedError::InvalidBlockHashLen)?,
+                is_best_chain,
+            })
         })
         .collect::<Result<Vec<_>, _>>()
 }
 
+/// Returns the GrandPa justification of the given block, if any.
+fn block_justification(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Option<Vec<u8>>, CorruptedError> {
+    prepare_cached(
+        database,
+        r#"SELECT justification FROM blocks_justifications WHERE hash = ? AND engine_id = ?"#,
+    )
+    .map_err(sqlite_err("block_justification"))?
+    .query_row((&block_hash[..], &GRANDPA_ENGINE_ID[..]), |row| {
+        row.get::<_, Vec<u8>>(0)
+    })
+    .optional()
+    .map_err(sqlite_err("block_justification"))
+}
+
+/// Stores the justification of the given block and consensus engine, or removes it if
+/// `justification` is `None`. The block must be in the database.
+fn set_block_justification(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+    engine_id: &[u8; 4],
+    justification: Option<&[u8]>,
+) -> Result<(), CorruptedError> {
+    if let Some(justification) = justification {
+        prepare_cached(
+            database,
+            r#"INSERT OR REPLACE INTO blocks_justifications(hash, engine_id, justification) VALUES (?, ?, ?)"#,
+        )
+        .map_err(sqlite_err("set_block_justification"))?
+        .execute((&block_hash[..], &engine_id[..], justification))
+        .map_err(sqlite_err("set_block_justification"))?;
+    } else {
+        prepare_cached(
+            database,
+            r#"DELETE FROM blocks_justifications WHERE hash = ? AND engine_id = ?"#,
+        )
+        .map_err(sqlite_err("set_block_justification"))?
+        .execute((&block_hash[..], &engine_id[..]))
+        .map_err(sqlite_err("set_block_justification"))?;
+    }
+    Ok(())
+}
+
 fn block_header(
     database: &rusqlite::Connection,
     hash: &[u8; 32],
 ) -> Result<Option<Vec<u8>>, CorruptedError> {
-    database
-        .prepare_cached(r#"SELECT header FROM blocks WHERE hash = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .query_row((&hash[..],), |row| row.get::<_, Vec<u8>>(0))
-        .optional()
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))
+    prepare_cached(
+        database,
+        r#"SELECT header FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("block_header"))?
+    .query_row((&hash[..],), |row| row.get::<_, Vec<u8>>(0))
+    .optional()
+    .map_err(sqlite_err("block_header"))
+}
+
+/// Returns `true` if the given block is the finalized block or one of its descendants. Returns
+/// `false` if the block isn't in the database.
+fn descends_from_finalized(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<bool, CorruptedError> {
+    let finalized_number =
+        i64::try_from(finalized_num(database)?).map_err(|_| CorruptedError::InvalidNumber)?;
+
+    // The walk stops at the height of the finalized block, and thus always terminates. The
+    // finalized block is the only block at this height that is part of the best chain.
+    prepare_cached(
+        database,
+        r#"
+        WITH RECURSIVE
+            ancestry(hash, number) AS (
+                SELECT hash, number FROM blocks WHERE hash = :block AND chain_id = (SELECT id FROM current_chain)
+            UNION ALL
+                SELECT parent.hash, parent.number
+                FROM ancestry
+                JOIN blocks AS child ON child.hash = ancestry.hash
+                JOIN blocks AS parent ON parent.hash = child.parent_hash
+                WHERE ancestry.number > :finalized_number
+            )
+        SELECT COUNT(*) >= 1
+        FROM ancestry JOIN blocks ON blocks.hash = ancestry.hash
+        WHERE ancestry.number = :finalized_number AND blocks.is_best_chain = TRUE
+            "#,
+    )
+    .map_err(sqlite_err("descends_from_finalized"))?
+    .query_row(
+        rusqlite::named_params! {
+            ":block": &block_hash[..],
+            ":finalized_number": finalized_number,
+        },
+        |row| row.get::<_, bool>(0),
+    )
+    .map_err(sqlite_err("descends_from_finalized"))
 }
 
 fn set_best_chain(
@@ -1944,7 +6290,6 @@ fn set_best_chain(
     // TODO: can this not be embedded in the SQL statement below?
     let current_best = meta_get_blob(database, "best")?.ok_or(CorruptedError::MissingMetaKey)?;
 
-    // TODO: untested except in the most basic situation
     // In the SQL below, the temporary table `changes` is built by walking down (highest to lowest
     // block number) the new best chain and old best chain. While walking down, the iteration
     // keeps track of the block hashes and their number. If the new best chain has a higher number
@@ -1953,178 +6298,528 @@ fn set_best_chain(
     // compare the block hashes in order to know when to stop iterating. In the context of this
     // algorithm, a `NULL` block hash represents "one past the new/old best block", which allows
     // to not include the new/old best block in the temporary table until it needs to be included.
-    database
-        .prepare_cached(
-            r#"
-        WITH RECURSIVE
-            changes(block_to_include, block_to_retract, block_to_include_number, block_to_retract_number) AS (
-                SELECT NULL, NULL, blocks_inc.number + 1, blocks_ret.number + 1
-                FROM blocks AS blocks_inc, blocks as blocks_ret
-                WHERE blocks_inc.hash = :new_best AND blocks_ret.hash = :current_best
-            UNION ALL
-                SELECT
-                    CASE WHEN changes.block_to_include_number >= changes.block_to_retract_number THEN
-                        COALESCE(blocks_inc.parent_hash, :new_best)
-                    ELSE
-                        changes.block_to_include
-                    END,
-                    CASE WHEN changes.block_to_retract_number >= changes.block_to_include_number THEN
-                        COALESCE(blocks_ret.parent_hash, :current_best)
-                    ELSE
-                        changes.block_to_retract
-                    END,
-                    CASE WHEN changes.block_to_include_number >= block_to_retract_number THEN changes.block_to_include_number - 1
-                    ELSE changes.block_to_include_number END,
-                    CASE WHEN changes.block_to_retract_number >= changes.block_to_include_number THEN changes.block_to_retract_number - 1
-                    ELSE changes.block_to_retract_number END
-                FROM changes
-                LEFT JOIN blocks AS blocks_inc ON blocks_inc.hash = changes.block_to_include
-                LEFT JOIN blocks AS blocks_ret ON blocks_ret.hash = changes.block_to_retract
-                WHERE changes.block_to_include_number != changes.block_to_retract_number
-                    OR COALESCE(blocks_inc.parent_hash, :new_best) != COALESCE(blocks_ret.parent_hash, :current_best)
-            )
-        UPDATE blocks SET is_best_chain = (blocks.hash = changes.block_to_include)
-        FROM changes
-        WHERE blocks.hash = changes.block_to_include OR blocks.hash = changes.block_to_retract;
-            "#,
+    // Since `block_to_include` can be `NULL` while blocks are being retracted, it is compared
+    // using `IS` rather than `=`.
+    prepare_cached(
+        database,
+        r#"
+    WITH RECURSIVE
+        changes(block_to_include, block_to_retract, block_to_include_number, block_to_retract_number) AS (
+            SELECT NULL, NULL, blocks_inc.number + 1, blocks_ret.number + 1
+            FROM blocks AS blocks_inc, blocks as blocks_ret
+            WHERE blocks_inc.hash = :new_best AND blocks_ret.hash = :current_best
+        UNION ALL
+            SELECT
+                CASE WHEN changes.block_to_include_number >= changes.block_to_retract_number THEN
+                    COALESCE(blocks_inc.parent_hash, :new_best)
+                ELSE
+                    changes.block_to_include
+                END,
+                CASE WHEN changes.block_to_retract_number >= changes.block_to_include_number THEN
+                    COALESCE(blocks_ret.parent_hash, :current_best)
+                ELSE
+                    changes.block_to_retract
+                END,
+                CASE WHEN changes.block_to_include_number >= block_to_retract_number THEN changes.block_to_include_number - 1
+                ELSE changes.block_to_include_number END,
+                CASE WHEN changes.block_to_retract_number >= changes.block_to_include_number THEN changes.block_to_retract_number - 1
+                ELSE changes.block_to_retract_number END
+            FROM changes
+            LEFT JOIN blocks AS blocks_inc ON blocks_inc.hash = changes.block_to_include
+            LEFT JOIN blocks AS blocks_ret ON blocks_ret.hash = changes.block_to_retract
+            WHERE changes.block_to_include_number != changes.block_to_retract_number
+                OR COALESCE(blocks_inc.parent_hash, :new_best) != COALESCE(blocks_ret.parent_hash, :current_best)
         )
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .execute(rusqlite::named_params! {
-            ":current_best": current_best,
-            ":new_best": new_best_block_hash
-        })
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+    UPDATE blocks SET is_best_chain = (blocks.hash IS changes.block_to_include)
+    FROM changes
+    WHERE blocks.hash = changes.block_to_include OR blocks.hash = changes.block_to_retract;
+        "#,
+    )
+    .map_err(sqlite_err("set_best_chain"))?
+    .execute(rusqlite::named_params! {
+        ":current_best": current_best,
+        ":new_best": new_best_block_hash
+    })
+    .map_err(sqlite_err("set_best_chain"))?;
 
     meta_set_blob(database, "best", new_best_block_hash)?;
     Ok(())
 }
 
-fn purge_block(database: &rusqlite::Connection, hash: &[u8]) -> Result<(), CorruptedError> {
-    purge_block_storage(database, hash)?;
-    database
-        .prepare_cached("DELETE FROM blocks_body WHERE hash = ?")
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+/// See [`SqliteFullDatabase::prune_bodies_below`].
+fn prune_bodies_below(
+    database: &rusqlite::Connection,
+    block_number: u64,
+) -> Result<u64, CorruptedError> {
+    // Blocks above the finalized block are never affected.
+    let block_number = cmp::min(block_number, finalized_num(database)?.saturating_add(1));
+    let block_number = i64::try_from(block_number).unwrap_or(i64::MAX);
+
+    prepare_cached(
+        database,
+        r#"
+        DELETE FROM blocks_events
+        WHERE hash IN (
+            SELECT hash FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND number < :block_number AND is_best_chain = TRUE AND body_pruned = FALSE
+        )
+    "#,
+    )
+    .map_err(sqlite_err("prune_bodies_below"))?
+    .execute(rusqlite::named_params! { ":block_number": block_number })
+    .map_err(sqlite_err("prune_bodies_below"))?;
+
+    let num_removed = prepare_cached(
+        database,
+        r#"
+        DELETE FROM blocks_body
+        WHERE hash IN (
+            SELECT hash FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND number < :block_number AND is_best_chain = TRUE AND body_pruned = FALSE
+        )
+    "#,
+    )
+    .map_err(sqlite_err("prune_bodies_below"))?
+    .execute(rusqlite::named_params! { ":block_number": block_number })
+    .map_err(sqlite_err("prune_bodies_below"))?;
+
+    prepare_cached(
+        database,
+        r#"
+        UPDATE blocks SET body_pruned = TRUE
+        WHERE chain_id = (SELECT id FROM current_chain) AND number < :block_number AND is_best_chain = TRUE AND body_pruned = FALSE
+    "#,
+    )
+    .map_err(sqlite_err("prune_bodies_below"))?
+    .execute(rusqlite::named_params! { ":block_number": block_number })
+    .map_err(sqlite_err("prune_bodies_below"))?;
+
+    Ok(u64::try_from(num_removed).unwrap_or(u64::MAX))
+}
+
+/// Removes the given block from the database, and returns the number of rows of its body and
+/// the number of trie nodes that have been removed.
+fn purge_block(
+    database: &rusqlite::Connection,
+    hash: &[u8],
+) -> Result<(usize, usize), CorruptedError> {
+    let deleted_trie_nodes = purge_block_storage(database, hash)?;
+    let deleted_body_rows = prepare_cached(database, "DELETE FROM blocks_body WHERE hash = ?")
+        .map_err(sqlite_err("purge_block"))?
         .execute((hash,))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    database
-        .prepare_cached("DELETE FROM blocks WHERE hash = ?")
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+        .map_err(sqlite_err("purge_block"))?;
+    prepare_cached(database, "DELETE FROM blocks_events WHERE hash = ?")
+        .map_err(sqlite_err("purge_block"))?
         .execute((hash,))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(())
+        .map_err(sqlite_err("purge_block"))?;
+    prepare_cached(database, "DELETE FROM blocks_meta WHERE hash = ?")
+        .map_err(sqlite_err("purge_block"))?
+        .execute((hash,))
+        .map_err(sqlite_err("purge_block"))?;
+    prepare_cached(database, "DELETE FROM blocks_justifications WHERE hash = ?")
+        .map_err(sqlite_err("purge_block"))?
+        .execute((hash,))
+        .map_err(sqlite_err("purge_block"))?;
+    prepare_cached(
+        database,
+        "DELETE FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)",
+    )
+    .map_err(sqlite_err("purge_block"))?
+    .execute((hash,))
+    .map_err(sqlite_err("purge_block"))?;
+    Ok((deleted_body_rows, deleted_trie_nodes))
 }
 
-fn purge_block_storage(database: &rusqlite::Connection, hash: &[u8]) -> Result<(), CorruptedError> {
-    // TODO: untested
+/// Reads the [`chain_information::ValidChainInformation`] of the finalized block of the current
+/// chain.
+fn finalized_chain_information(
+    connection: &rusqlite::Connection,
+    block_number_bytes: usize,
+) -> Result<chain_information::ValidChainInformation, CorruptedError> {
+    let finalized_block_hash = finalized_hash(connection)?;
+
+    let finalized_block_header = block_header(connection, &finalized_block_hash)?
+        .ok_or(CorruptedError::MissingBlockHeader)?;
+
+    let finality = match (
+        grandpa_authorities_set_id(connection)?,
+        grandpa_finalized_triggered_authorities(connection)?,
+        grandpa_finalized_scheduled_change(connection)?,
+    ) {
+        (
+            Some(after_finalized_block_authorities_set_id),
+            finalized_triggered_authorities,
+            finalized_scheduled_change,
+        ) => chain_information::ChainInformationFinality::Grandpa {
+            after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities,
+            finalized_scheduled_change,
+        },
+        (None, auth, None) if auth.is_empty() => {
+            chain_information::ChainInformationFinality::Outsourced
+        }
+        _ => return Err(CorruptedError::ConsensusAlgorithmMix),
+    };
+
+    let consensus = match (
+        meta_get_u64_blob(connection, "aura_slot_duration")?,
+        meta_get_u64_blob(connection, "babe_slots_per_epoch")?,
+        meta_get_blob(connection, "babe_finalized_next_epoch")?,
+    ) {
+        (None, Some(slots_per_epoch), Some(finalized_next_epoch)) => {
+            let slots_per_epoch = expect_nz_u64(slots_per_epoch)?;
+            let finalized_next_epoch_transition =
+                Box::new(decode_babe_epoch_information(&finalized_next_epoch)?);
+            let finalized_block_epoch_information =
+                meta_get_blob(connection, "babe_finalized_epoch")?
+                    .map(|v| decode_babe_epoch_information(&v))
+                    .transpose()?
+                    .map(Box::new);
+            chain_information::ChainInformationConsensus::Babe {
+                finalized_block_epoch_information,
+                finalized_next_epoch_transition,
+                slots_per_epoch,
+            }
+        }
+        (Some(slot_duration), None, None) => {
+            let slot_duration = expect_nz_u64(slot_duration)?;
+            let finalized_authorities_list = aura_finalized_authorities(connection)?;
+            chain_information::ChainInformationConsensus::Aura {
+                finalized_authorities_list,
+                slot_duration,
+            }
+        }
+        (None, None, None) => chain_information::ChainInformationConsensus::Unknown,
+        _ => return Err(CorruptedError::ConsensusAlgorithmMix),
+    };
 
-    let state_trie_root_hash = database
-        .prepare_cached(r#"SELECT state_trie_root_hash FROM blocks WHERE hash = ?"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .query_row((hash,), |row| row.get::<_, Vec<u8>>(0))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+    match chain_information::ValidChainInformation::try_from(chain_information::ChainInformation {
+        finalized_block_header: {
+            let header = header::decode(&finalized_block_header, block_number_bytes)
+                .map_err(CorruptedError::BlockHeaderCorrupted)?;
+            Box::new(header.into())
+        },
+        consensus,
+        finality,
+    }) {
+        Ok(ci) => Ok(ci),
+        Err(err) => Err(CorruptedError::InvalidChainInformation(err)),
+    }
+}
 
-    database
-        .prepare_cached(
-            r#"
-            UPDATE blocks SET state_trie_root_hash = NULL
-            WHERE hash = :block_hash
+/// Removes all the blocks of the chain designated by `current_chain` except for the given block,
+/// then all the trie nodes that aren't reachable from the blocks that remain in the database.
+fn wipe_except_block(database: &rusqlite::Connection, hash: &[u8]) -> Result<(), CorruptedError> {
+    // The bodies, events, justifications, and insertion information of the blocks are removed
+    // through the foreign keys.
+    prepare_cached(
+        database,
+        "DELETE FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND hash != ?",
+    )
+    .map_err(sqlite_err("reset/wipe"))?
+    .execute((hash,))
+    .map_err(sqlite_err("reset/wipe"))?;
+
+    // Contrary to `purge_block_storage`, the trie nodes to keep are found by walking down all
+    // the tries of the database, as most of the trie nodes are expected to be removed.
+    // `UNION` discards duplicate rows, which guarantees that the recursion ends even if there
+    // is a loop in the trie.
+    prepare_cached(
+        database,
+        "CREATE TEMP TABLE IF NOT EXISTS kept_trie_node(hash BLOB NOT NULL PRIMARY KEY)",
+    )
+    .and_then(|mut statement| statement.execute(()))
+    .map_err(sqlite_err("reset/wipe"))?;
+    prepare_cached(
+        database,
+        r#"
+            WITH RECURSIVE
+                nibble(num) AS (
+                    VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+                        (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+                ),
+                kept(node_hash) AS (
+                    SELECT state_trie_root_hash FROM blocks WHERE state_trie_root_hash IS NOT NULL
+                    UNION
+                    SELECT child_merkle_value(trie_node.children, nibble.num)
+                        FROM kept
+                        JOIN trie_node ON trie_node.hash = kept.node_hash
+                        JOIN nibble
+                        WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL
+                    UNION
+                    SELECT trie_node_storage.trie_root_ref
+                        FROM kept
+                        JOIN trie_node_storage ON trie_node_storage.node_hash = kept.node_hash
+                        WHERE trie_node_storage.trie_root_ref IS NOT NULL
+                )
+            INSERT OR IGNORE INTO kept_trie_node(hash) SELECT node_hash FROM kept
         "#,
-        )
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-        .execute(rusqlite::named_params! {
-            ":block_hash": hash,
-        })
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+    )
+    .map_err(sqlite_err("reset/wipe"))?
+    .execute(())
+    .map_err(sqlite_err("reset/wipe"))?;
+
+    // The storage values of the trie nodes are removed through the foreign keys.
+    for statement in [
+        "DELETE FROM trie_node_parent WHERE hash NOT IN (SELECT hash FROM kept_trie_node)",
+        "DELETE FROM trie_node WHERE hash NOT IN (SELECT hash FROM kept_trie_node)",
+        "DELETE FROM kept_trie_node",
+    ] {
+        prepare_cached(database, statement)
+            .and_then(|mut statement| statement.execute(()))
+            .map_err(sqlite_err("reset/wipe"))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the root of the state trie of the given block.
+fn block_state_trie_root(
+    database: &rusqlite::Connection,
+    block_hash: &[u8; 32],
+) -> Result<Vec<u8>, StorageAccessError> {
+    prepare_cached(
+        database,
+        r#"SELECT state_trie_root_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("block_state_trie_root"))?
+    .query_row((&block_hash[..],), |row| row.get::<_, Option<Vec<u8>>>(0))
+    .optional()
+    .map_err(sqlite_err("block_state_trie_root"))?
+    .ok_or(StorageAccessError::UnknownBlock)?
+    // A missing root can mean that the trie is empty, but also that its storage has been
+    // removed from the database. We can't make the difference.
+    .ok_or(StorageAccessError::IncompleteStorage)
+}
 
-    // TODO: doesn't delete everything in the situation where a single node with a merkle value is referenced multiple times from the same trie
-    // TODO: currently doesn't follow `trie_root_ref`
-    database
-        .prepare_cached(r#"
+/// Counts the trie nodes reachable from `root_a` and/or `root_b`. If `root_b` is `None`, all the
+/// nodes are reported in [`DedupStats::only_a`].
+fn trie_sharing_stats(
+    database: &rusqlite::Connection,
+    root_a: &[u8],
+    root_b: Option<&[u8]>,
+) -> Result<DedupStats, CorruptedError> {
+    // Each reachable node is tagged with `1` if it is reachable from `root_a` and `2` if it is
+    // reachable from `root_b`. Summing the distinct tags of a node then indicates from which
+    // roots it is reachable. `UNION` discards duplicate rows, which guarantees that the
+    // recursion ends even if there is a loop in the trie.
+    let mut statement = prepare_cached(
+        database,
+        r#"
             WITH RECURSIVE
-                to_delete(node_hash) AS (
-                    SELECT trie_node.hash
-                        FROM trie_node
-                        LEFT JOIN blocks ON blocks.hash != :block_hash AND blocks.state_trie_root_hash = trie_node.hash
-                        LEFT JOIN trie_node_storage ON trie_node_storage.trie_root_ref = trie_node.hash
-                        WHERE trie_node.hash = :state_trie_root_hash AND blocks.hash IS NULL AND trie_node_storage.node_hash IS NULL
-                    UNION ALL
-                    SELECT trie_node_child.child_hash
-                        FROM to_delete
-                        JOIN trie_node_child ON trie_node_child.hash = to_delete.node_hash
-                        LEFT JOIN blocks ON blocks.state_trie_root_hash = trie_node_child.child_hash
-                        LEFT JOIN trie_node_storage ON trie_node_storage.trie_root_ref = to_delete.node_hash
-                        WHERE blocks.hash IS NULL AND trie_node_storage.node_hash IS NULL
+                nibble(num) AS (
+                    VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+                        (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+                ),
+                reachable(node_hash, side) AS (
+                    SELECT :root_a, 1
+                    UNION
+                    SELECT :root_b, 2 WHERE :root_b IS NOT NULL
+                    UNION
+                    SELECT child_merkle_value(trie_node.children, nibble.num), reachable.side
+                        FROM reachable
+                        JOIN trie_node ON trie_node.hash = reachable.node_hash
+                        JOIN nibble
+                        WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL
+                    UNION
+                    SELECT trie_node_storage.trie_root_ref, reachable.side
+                        FROM reachable
+                        JOIN trie_node_storage ON trie_node_storage.node_hash = reachable.node_hash
+                        WHERE trie_node_storage.trie_root_ref IS NOT NULL
+                ),
+                tagged(node_hash, sides) AS (
+                    SELECT node_hash, SUM(DISTINCT side) FROM reachable GROUP BY node_hash
                 )
-            DELETE FROM trie_node
-            WHERE hash IN (SELECT node_hash FROM to_delete)
-        "#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+            SELECT
+                tagged.sides,
+                COUNT(*),
+                SUM(length(trie_node.partial_key) + COALESCE(length(trie_node.children), 0)
+                    + COALESCE(length(trie_node_storage.value), 0))
+            FROM tagged
+            JOIN trie_node ON trie_node.hash = tagged.node_hash
+            LEFT JOIN trie_node_storage ON trie_node_storage.node_hash = tagged.node_hash
+            GROUP BY tagged.sides
+        "#,
+    )
+    .map_err(sqlite_err("trie_sharing_stats"))?;
+    let rows = statement
+        .query_map(
+            rusqlite::named_params! {
+                ":root_a": root_a,
+                ":root_b": root_b,
+            },
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            },
+        )
+        .map_err(sqlite_err("trie_sharing_stats"))?;
+
+    let mut stats = DedupStats {
+        only_a: TrieStats::default(),
+        only_b: TrieStats::default(),
+        shared: TrieStats::default(),
+    };
+    for row in rows {
+        let (sides, nodes, bytes) = row.map_err(sqlite_err("trie_sharing_stats"))?;
+        let entry = match sides {
+            1 => &mut stats.only_a,
+            2 => &mut stats.only_b,
+            3 => &mut stats.shared,
+            _ => unreachable!(),
+        };
+        *entry = TrieStats {
+            nodes: u64::try_from(nodes).map_err(|_| CorruptedError::InvalidNumber)?,
+            bytes: u64::try_from(bytes).map_err(|_| CorruptedError::InvalidNumber)?,
+        };
+    }
+    Ok(stats)
+}
+
+/// Removes the trie nodes of the storage of the given block that aren't used by any other block,
+/// and returns the number of trie nodes that have been removed.
+fn purge_block_storage(
+    database: &rusqlite::Connection,
+    hash: &[u8],
+) -> Result<usize, CorruptedError> {
+    let state_trie_root_hash = prepare_cached(
+        database,
+        r#"SELECT state_trie_root_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)"#,
+    )
+    .map_err(sqlite_err("purge_block_storage"))?
+    .query_row((hash,), |row| row.get::<_, Vec<u8>>(0))
+    .map_err(sqlite_err("purge_block_storage"))?;
+
+    prepare_cached(
+        database,
+        r#"
+            UPDATE blocks SET state_trie_root_hash = NULL
+            WHERE hash = :block_hash
+        "#,
+    )
+    .map_err(sqlite_err("purge_block_storage"))?
+    .execute(rusqlite::named_params! {
+        ":block_hash": hash,
+    })
+    .map_err(sqlite_err("purge_block_storage"))?;
+
+    // The nodes to delete are found through a mark-and-sweep restricted to the trie of the
+    // block being purged.
+    // `candidates` contains all the nodes reachable from the root of this trie, including the
+    // nodes of the child tries. `kept` contains the candidates that are referenced by a block
+    // or by a node that isn't a candidate, plus all the nodes reachable from them. The nodes
+    // that are a candidate but aren't kept are deleted.
+    // Because a node can be referenced multiple times, both by the same trie and by different
+    // tries, this is done in two passes rather than deleting nodes while walking down the trie.
+    // `UNION` discards duplicate rows, which guarantees that the recursion ends even if there
+    // is a loop in the trie.
+    // The nodes to delete are stored in a temporary table, as the rows of `trie_node_parent`
+    // whose parent is one of these nodes must also be deleted.
+    prepare_cached(database, PURGED_TRIE_NODE_TABLE)
+        .and_then(|mut statement| statement.execute(()))
+        .map_err(sqlite_err("purge_block_storage"))?;
+    prepare_cached(database, PURGED_TRIE_NODES_QUERY)
+        .map_err(sqlite_err("purge_block_storage"))?
         .execute(rusqlite::named_params! {
             ":state_trie_root_hash": &state_trie_root_hash,
-            ":block_hash": hash,
         })
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-    Ok(())
+        .map_err(sqlite_err("purge_block_storage"))?;
+
+    prepare_cached(
+        database,
+        r#"
+            WITH nibble(num) AS (
+                VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+                    (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+            )
+            DELETE FROM trie_node_parent
+            WHERE (child_hash, hash) IN (
+                SELECT child_merkle_value(trie_node.children, nibble.num), trie_node.hash
+                FROM purged_trie_node
+                JOIN trie_node ON trie_node.hash = purged_trie_node.hash
+                JOIN nibble
+                WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL
+            )
+        "#,
+    )
+    .map_err(sqlite_err("purge_block_storage"))?
+    .execute(())
+    .map_err(sqlite_err("purge_block_storage"))?;
+    let deleted_trie_nodes = prepare_cached(
+        database,
+        "DELETE FROM trie_node WHERE hash IN (SELECT hash FROM purged_trie_node)",
+    )
+    .map_err(sqlite_err("purge_block_storage"))?
+    .execute(())
+    .map_err(sqlite_err("purge_block_storage"))?;
+    prepare_cached(database, "DELETE FROM purged_trie_node")
+        .map_err(sqlite_err("purge_block_storage"))?
+        .execute(())
+        .map_err(sqlite_err("purge_block_storage"))?;
+    Ok(deleted_trie_nodes)
 }
 
 fn grandpa_authorities_set_id(
     database: &rusqlite::Connection,
 ) -> Result<Option<u64>, CorruptedError> {
-    meta_get_number(database, "grandpa_authorities_set_id")
+    meta_get_u64_blob(database, "grandpa_authorities_set_id")
 }
 
 fn grandpa_finalized_triggered_authorities(
     database: &rusqlite::Connection,
 ) -> Result<Vec<header::GrandpaAuthority>, CorruptedError> {
-    database
-        .prepare_cached(
-            r#"SELECT public_key, weight FROM grandpa_triggered_authorities ORDER BY idx ASC"#,
+    prepare_cached(
+        database,
+        r#"SELECT public_key, weight FROM grandpa_triggered_authorities WHERE chain_id = (SELECT id FROM current_chain) ORDER BY idx ASC"#,
+    )
+    .map_err(sqlite_err("grandpa_finalized_triggered_authorities"))?
+    .query_map((), |row| {
+        let pk = row.get::<_, Vec<u8>>(0)?;
+        let weight = row.get::<_, Vec<u8>>(1)?;
+        Ok((pk, weight))
+    })
+    .map_err(sqlite_err("grandpa_finalized_triggered_authorities"))?
+    .map(|result| {
+        let (public_key, weight) =
+            result.map_err(sqlite_err("grandpa_finalized_triggered_authorities"))?;
+        let public_key = <[u8; 32]>::try_from(&public_key[..])
+            .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
+        let weight =
+            NonZeroU64::new(decode_u64(&weight)?).ok_or(CorruptedError::InvalidNumber)?;
+        Ok(header::GrandpaAuthority { public_key, weight })
+    })
+    .collect::<Result<Vec<_>, _>>()
+}
+
+fn grandpa_finalized_scheduled_change(
+    database: &rusqlite::Connection,
+) -> Result<Option<(u64, Vec<header::GrandpaAuthority>)>, CorruptedError> {
+    if let Some(height) = meta_get_number(database, "grandpa_scheduled_target")? {
+        // TODO: duplicated from above except different table name
+        let out = prepare_cached(
+            database,
+            r#"SELECT public_key, weight FROM grandpa_scheduled_authorities WHERE chain_id = (SELECT id FROM current_chain) ORDER BY idx ASC"#,
         )
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+        .map_err(sqlite_err("grandpa_finalized_scheduled_change"))?
         .query_map((), |row| {
             let pk = row.get::<_, Vec<u8>>(0)?;
-            let weight = row.get::<_, i64>(1)?;
+            let weight = row.get::<_, Vec<u8>>(1)?;
             Ok((pk, weight))
         })
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+        .map_err(sqlite_err("grandpa_finalized_scheduled_change"))?
         .map(|result| {
             let (public_key, weight) =
-                result.map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+                result.map_err(sqlite_err("grandpa_finalized_scheduled_change"))?;
             let public_key = <[u8; 32]>::try_from(&public_key[..])
                 .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
-            let weight = NonZeroU64::new(u64::from_ne_bytes(weight.to_ne_bytes()))
-                .ok_or(CorruptedError::InvalidNumber)?;
+            let weight =
+                NonZeroU64::new(decode_u64(&weight)?).ok_or(CorruptedError::InvalidNumber)?;
             Ok(header::GrandpaAuthority { public_key, weight })
         })
-        .collect::<Result<Vec<_>, _>>()
-}
-
-fn grandpa_finalized_scheduled_change(
-    database: &rusqlite::Connection,
-) -> Result<Option<(u64, Vec<header::GrandpaAuthority>)>, CorruptedError> {
-    if let Some(height) = meta_get_number(database, "grandpa_scheduled_target")? {
-        // TODO: duplicated from above except different table name
-        let out = database
-            .prepare_cached(
-                r#"SELECT public_key, weight FROM grandpa_scheduled_authorities ORDER BY idx ASC"#,
-            )
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .query_map((), |row| {
-                let pk = row.get::<_, Vec<u8>>(0)?;
-                let weight = row.get::<_, i64>(1)?;
-                Ok((pk, weight))
-            })
-            .map_err(|err| CorruptedError::Internal(InternalError(err)))?
-            .map(|result| {
-                let (public_key, weight) =
-                    result.map_err(|err| CorruptedError::Internal(InternalError(err)))?;
-                let public_key = <[u8; 32]>::try_from(&public_key[..])
-                    .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
-                let weight = NonZeroU64::new(u64::from_ne_bytes(weight.to_ne_bytes()))
-                    .ok_or(CorruptedError::InvalidNumber)?;
-                Ok(header::GrandpaAuthority { public_key, weight })
-            })
-            .collect::<Result<Vec<_>, CorruptedError>>()?;
+        .collect::<Result<Vec<_>, CorruptedError>>()?;
 
         Ok(Some((height, out)))
     } else {
@@ -2139,13 +6834,12 @@ fn expect_nz_u64(value: u64) -> Result<NonZeroU64, CorruptedError> {
 fn aura_finalized_authorities(
     database: &rusqlite::Connection,
 ) -> Result<Vec<header::AuraAuthority>, CorruptedError> {
-    database
-        .prepare_cached(r#"SELECT public_key FROM aura_finalized_authorities ORDER BY idx ASC"#)
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+    prepare_cached(database, r#"SELECT public_key FROM aura_finalized_authorities WHERE chain_id = (SELECT id FROM current_chain) ORDER BY idx ASC"#)
+        .map_err(sqlite_err("aura_finalized_authorities"))?
         .query_map((), |row| row.get::<_, Vec<u8>>(0))
-        .map_err(|err| CorruptedError::Internal(InternalError(err)))?
+        .map_err(sqlite_err("aura_finalized_authorities"))?
         .map(|result| {
-            let public_key = result.map_err(|err| CorruptedError::Internal(InternalError(err)))?;
+            let public_key = result.map_err(sqlite_err("aura_finalized_authorities"))?;
             let public_key = <[u8; 32]>::try_from(&public_key[..])
                 .map_err(|_| CorruptedError::InvalidBlockHashLen)?;
             Ok(header::AuraAuthority { public_key })
@@ -2153,8 +6847,15 @@ fn aura_finalized_authorities(
         .collect::<Result<Vec<_>, CorruptedError>>()
 }
 
+/// Version prefixed to the encoding produced by [`encode_babe_epoch_information`].
+///
+/// Version 0 corresponds to the encoding that used to be stored without any prefix. These
+/// blobs are prefixed with version 1, whose payload is identical, when the database is opened.
+const BABE_EPOCH_INFORMATION_VERSION: u8 = 1;
+
 fn encode_babe_epoch_information(info: chain_information::BabeEpochInformationRef) -> Vec<u8> {
-    let mut out = Vec::with_capacity(69 + info.authorities.len() * 40);
+    let mut out = Vec::with_capacity(70 + info.authorities.len() * 40);
+    out.push(BABE_EPOCH_INFORMATION_VERSION);
     out.extend_from_slice(&info.epoch_index.to_le_bytes());
     if let Some(start_slot_number) = info.start_slot_number {
         out.extend_from_slice(&[1]);
@@ -2181,61 +6882,138 @@ fn encode_babe_epoch_information(info: chain_information::BabeEpochInformationRe
 fn decode_babe_epoch_information(
     value: &[u8],
 ) -> Result<chain_information::BabeEpochInformation, CorruptedError> {
-    let result = nom::combinator::all_consuming(nom::combinator::map(
-        nom::sequence::tuple((
-            nom::number::streaming::le_u64,
-            util::nom_option_decode(nom::number::streaming::le_u64),
-            nom::combinator::flat_map(crate::util::nom_scale_compact_usize, |num_elems| {
-                nom::multi::many_m_n(
-                    num_elems,
-                    num_elems,
-                    nom::combinator::map(
-                        nom::sequence::tuple((
-                            nom::bytes::streaming::take(32u32),
-                            nom::number::streaming::le_u64,
-                        )),
-                        move |(public_key, weight)| header::BabeAuthority {
-                            public_key: TryFrom::try_from(public_key).unwrap(),
-                            weight,
-                        },
-                    ),
-                )
-            }),
-            nom::bytes::streaming::take(32u32),
-            nom::sequence::tuple((
-                nom::number::streaming::le_u64,
-                nom::number::streaming::le_u64,
-            )),
-            nom::branch::alt((
-                nom::combinator::map(nom::bytes::streaming::tag(&[0]), |_| {
-                    header::BabeAllowedSlots::PrimarySlots
-                }),
-                nom::combinator::map(nom::bytes::streaming::tag(&[1]), |_| {
-                    header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots
-                }),
-                nom::combinator::map(nom::bytes::streaming::tag(&[2]), |_| {
-                    header::BabeAllowedSlots::PrimaryAndSecondaryVrfSlots
-                }),
-            )),
-        )),
-        |(epoch_index, start_slot_number, authorities, randomness, c, allowed_slots)| {
-            chain_information::BabeEpochInformation {
-                epoch_index,
-                start_slot_number,
-                authorities,
-                randomness: TryFrom::try_from(randomness).unwrap(),
-                c,
-                allowed_slots,
-            }
-        },
-    ))(value)
-    .map(|(_, v)| v)
-    .map_err(|_: nom::Err<nom::error::Error<&[u8]>>| ());
+    let result = match value.split_first() {
+        Some((&BABE_EPOCH_INFORMATION_VERSION, payload)) => {
+            decode_babe_epoch_information_v1(payload)
+        }
+        Some((&version, _)) => Err(BabeEpochInformationDecodeError::UnknownVersion(version)),
+        None => Err(BabeEpochInformationDecodeError::Truncated { offset: 0 }),
+    };
+
+    result.map_err(CorruptedError::InvalidBabeEpochInformation)
+}
+
+/// Decodes the information that follows the version prefix of a version 1 encoding. The
+/// offsets found in the errors are relative to the start of the blob, version prefix included.
+fn decode_babe_epoch_information_v1(
+    value: &[u8],
+) -> Result<chain_information::BabeEpochInformation, BabeEpochInformationDecodeError> {
+    let mut reader = BabeEpochReader {
+        remaining: value,
+        offset: 1,
+    };
+
+    let epoch_index = reader.u64()?;
+
+    let start_slot_number = match reader.byte()? {
+        0 => None,
+        1 => Some(reader.u64()?),
+        _ => {
+            return Err(BabeEpochInformationDecodeError::InvalidValue {
+                offset: reader.offset - 1,
+            })
+        }
+    };
+
+    let num_authorities = reader.compact_usize()?;
+    let authorities_offset = reader.offset;
+    let mut authorities =
+        Vec::with_capacity(cmp::min(num_authorities, reader.remaining.len() / 40));
+    for _ in 0..num_authorities {
+        let Ok((public_key, weight)) = reader
+            .take(32)
+            .and_then(|public_key| Ok((public_key, reader.u64()?)))
+        else {
+            return Err(BabeEpochInformationDecodeError::TruncatedAuthorities {
+                offset: authorities_offset,
+                expected: num_authorities,
+                found: authorities.len(),
+            });
+        };
+        authorities.push(header::BabeAuthority {
+            public_key: <[u8; 32]>::try_from(public_key).unwrap(),
+            weight,
+        });
+    }
+
+    let randomness = <[u8; 32]>::try_from(reader.take(32)?).unwrap();
+    let c = (reader.u64()?, reader.u64()?);
 
-    let result = match result {
-        Ok(r) if r.validate().is_ok() => Ok(r),
-        Ok(_) | Err(()) => Err(()),
+    let allowed_slots = match reader.byte()? {
+        0 => header::BabeAllowedSlots::PrimarySlots,
+        1 => header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+        2 => header::BabeAllowedSlots::PrimaryAndSecondaryVrfSlots,
+        _ => {
+            return Err(BabeEpochInformationDecodeError::InvalidValue {
+                offset: reader.offset - 1,
+            })
+        }
+    };
+
+    if !reader.remaining.is_empty() {
+        return Err(BabeEpochInformationDecodeError::TrailingBytes {
+            offset: reader.offset,
+        });
+    }
+
+    let info = chain_information::BabeEpochInformation {
+        epoch_index,
+        start_slot_number,
+        authorities,
+        randomness,
+        c,
+        allowed_slots,
     };
 
-    result.map_err(|()| CorruptedError::InvalidBabeEpochInformation)
+    info.validate()
+        .map_err(BabeEpochInformationDecodeError::ValidationFailed)?;
+    Ok(info)
+}
+
+/// Reads the fields of an encoded Babe epoch information one by one while keeping track of
+/// their offset. See [`decode_babe_epoch_information_v1`].
+struct BabeEpochReader<'a> {
+    remaining: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BabeEpochReader<'a> {
+    fn take(&mut self, num_bytes: usize) -> Result<&'a [u8], BabeEpochInformationDecodeError> {
+        if self.remaining.len() < num_bytes {
+            return Err(BabeEpochInformationDecodeError::Truncated {
+                offset: self.offset,
+            });
+        }
+
+        let (taken, remaining) = self.remaining.split_at(num_bytes);
+        self.remaining = remaining;
+        self.offset += num_bytes;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, BabeEpochInformationDecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, BabeEpochInformationDecodeError> {
+        Ok(u64::from_le_bytes(
+            <[u8; 8]>::try_from(self.take(8)?).unwrap(),
+        ))
+    }
+
+    fn compact_usize(&mut self) -> Result<usize, BabeEpochInformationDecodeError> {
+        match util::nom_scale_compact_usize::<nom::error::Error<&[u8]>>(self.remaining) {
+            Ok((remaining, value)) => {
+                self.offset += self.remaining.len() - remaining.len();
+                self.remaining = remaining;
+                Ok(value)
+            }
+            Err(nom::Err::Incomplete(_)) => Err(BabeEpochInformationDecodeError::Truncated {
+                offset: self.offset,
+            }),
+            Err(_) => Err(BabeEpochInformationDecodeError::InvalidValue {
+                offset: self.offset,
+            }),
+        }
+    }
 }
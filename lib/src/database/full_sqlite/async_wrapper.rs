@@ -0,0 +1,582 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Asynchronous access to a [`SqliteFullDatabase`].
+//!
+//! As explained in the documentation of the parent module, the database must not be accessed
+//! directly from an asynchronous context. The [`AsyncDatabase`] provided here owns a
+//! [`SqliteFullDatabase`] and runs all the operations on a dedicated thread. Each method returns
+//! a future that completes once the operation has finished on that thread.
+//!
+//! Operations are executed one by one, in the order in which they have been started.
+//!
+//! # Back-pressure
+//!
+//! The number of operations that have been started but not yet picked by the database thread is
+//! bounded (see [`Config::max_pending_operations`]). When this limit is reached, starting an
+//! operation waits until the database thread has made progress.
+//!
+//! # Shutdown
+//!
+//! Dropping the [`AsyncDatabase`] doesn't block. The operations that have already been queued
+//! are still executed, after which the [`SqliteFullDatabase`] is destroyed by the database
+//! thread. Use [`AsyncDatabase::shutdown`] in order to wait until the database has been closed,
+//! for example before opening it again.
+
+#![cfg(feature = "database-sqlite-async")]
+#![cfg_attr(docsrs, doc(cfg(feature = "database-sqlite-async")))]
+
+use super::{
+    BlockAtNumber, BlockExtrinsicsError, CorruptedError, DatabaseEventReceiver, ForkMetrics,
+    FullBlock, InsertError, InsertMeta, InsertOutcome, InterruptHandle, ParentOf, ResumeState,
+    SetBestError, SetFinalizedError, SetFinalizedStats, SqliteFullDatabase, StorageAccessError,
+};
+use crate::{chain::chain_information, trie};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::{
+    mem,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use std::sync::mpsc;
+
+/// Configuration for an [`AsyncDatabase`].
+pub struct Config<TSpawn> {
+    /// Database to access.
+    pub database: SqliteFullDatabase,
+
+    /// Maximum number of operations that have been started but not picked yet by the database
+    /// thread.
+    pub max_pending_operations: NonZeroUsize,
+
+    /// Function called once in order to run the database thread. The function passed as
+    /// parameter blocks until the [`AsyncDatabase`] is destroyed, and must thus be run on a
+    /// thread where blocking is acceptable, for example with `spawn_blocking`.
+    ///
+    /// Use [`spawn_thread`] to run it on a new dedicated thread.
+    pub spawn: TSpawn,
+}
+
+/// Spawns a new thread named `sqlite-database` that runs the given function.
+///
+/// Designed to be passed as [`Config::spawn`].
+///
+/// # Panic
+///
+/// Panics if the operating system fails to create a thread.
+///
+pub fn spawn_thread(task: Box<dyn FnOnce() + Send>) {
+    std::thread::Builder::new()
+        .name("sqlite-database".into())
+        .spawn(task)
+        .unwrap();
+}
+
+/// Operation to run on the database thread.
+type Operation = Box<dyn FnOnce(&SqliteFullDatabase) + Send>;
+
+/// Access to a [`SqliteFullDatabase`] running on a separate thread.
+pub struct AsyncDatabase {
+    /// Sending side of the queue of operations. `None` only while being destroyed.
+    operations: Option<mpsc::Sender<Operation>>,
+
+    /// State shared with the database thread.
+    shared: Arc<Shared>,
+}
+
+/// State shared between the [`AsyncDatabase`] and its thread.
+struct Shared {
+    /// Number of operations that have been sent but not picked by the database thread.
+    num_pending: AtomicUsize,
+
+    /// See [`Config::max_pending_operations`].
+    max_pending_operations: NonZeroUsize,
+
+    /// Notified whenever the database thread picks an operation from the queue.
+    on_operation_picked: event_listener::Event,
+
+    /// `true` once the database thread has stopped and the database is closed.
+    closed: AtomicBool,
+
+    /// Notified when `closed` becomes `true`.
+    on_closed: event_listener::Event,
+}
+
+impl AsyncDatabase {
+    /// Creates a new [`AsyncDatabase`] and starts its thread using [`Config::spawn`].
+    pub fn new(config: Config<impl FnOnce(Box<dyn FnOnce() + Send>)>) -> Self {
+        let (operations_tx, operations_rx) = mpsc::channel::<Operation>();
+
+        let shared = Arc::new(Shared {
+            num_pending: AtomicUsize::new(0),
+            max_pending_operations: config.max_pending_operations,
+            on_operation_picked: event_listener::Event::new(),
+            closed: AtomicBool::new(false),
+            on_closed: event_listener::Event::new(),
+        });
+
+        let database = config.database;
+        let thread_shared = shared.clone();
+        (config.spawn)(Box::new(move || {
+            // Marks the database as closed when leaving this function, including in case of a
+            // panic in one of the operations.
+            struct OnExit(Arc<Shared>);
+            impl Drop for OnExit {
+                fn drop(&mut self) {
+                    self.0.closed.store(true, Ordering::Release);
+                    self.0.on_closed.notify(usize::MAX);
+                    self.0.on_operation_picked.notify(usize::MAX);
+                }
+            }
+            let _on_exit = OnExit(thread_shared.clone());
+
+            // `recv` returns an error once the `AsyncDatabase` has been destroyed and all the
+            // queued operations have been processed.
+            while let Ok(operation) = operations_rx.recv() {
+                thread_shared.num_pending.fetch_sub(1, Ordering::AcqRel);
+                thread_shared.on_operation_picked.notify(usize::MAX);
+                operation(&database);
+            }
+
+            // The database is closed before `_on_exit` is dropped.
+            drop(database);
+        }));
+
+        AsyncDatabase {
+            operations: Some(operations_tx),
+            shared,
+        }
+    }
+
+    /// Runs the given function on the database thread and returns its output.
+    ///
+    /// All the other methods of this struct are built on top of this one.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the database thread has stopped, which can only happen if a previous operation
+    /// has panicked.
+    ///
+    pub async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&SqliteFullDatabase) -> T + Send + 'static,
+    ) -> T {
+        // Wait for a slot in the queue to be available.
+        let mut wait = None;
+        loop {
+            if self.shared.closed.load(Ordering::Acquire) {
+                panic!("database thread has stopped");
+            }
+
+            if self
+                .shared
+                .num_pending
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |old_value| {
+                    if old_value < self.shared.max_pending_operations.get() {
+                        Some(old_value + 1)
+                    } else {
+                        None
+                    }
+                })
+                .is_ok()
+            {
+                break;
+            }
+
+            if let Some(wait) = wait.take() {
+                wait.await;
+            } else {
+                wait = Some(self.shared.on_operation_picked.listen());
+            }
+        }
+
+        let response = Arc::new(Response {
+            value: parking_lot::Mutex::new(ResponseState::Pending),
+            on_ready: event_listener::Event::new(),
+        });
+
+        let responder = Responder(response.clone());
+        let operation: Operation = Box::new(move |database| {
+            let value = operation(database);
+            responder.send(value);
+        });
+
+        if self
+            .operations
+            .as_ref()
+            .unwrap_or_else(|| unreachable!())
+            .send(operation)
+            .is_err()
+        {
+            panic!("database thread has stopped");
+        }
+
+        let mut wait = None;
+        loop {
+            match mem::replace(&mut *response.value.lock(), ResponseState::Pending) {
+                ResponseState::Ready(value) => return value,
+                ResponseState::Dropped => panic!("database thread has stopped"),
+                ResponseState::Pending => {}
+            }
+
+            if let Some(wait) = wait.take() {
+                wait.await;
+            } else {
+                wait = Some(response.on_ready.listen());
+            }
+        }
+    }
+
+    /// Destroys the [`AsyncDatabase`], then waits for all the queued operations to finish and
+    /// for the database to be closed.
+    pub async fn shutdown(mut self) {
+        self.operations = None;
+
+        let mut wait = None;
+        while !self.shared.closed.load(Ordering::Acquire) {
+            if let Some(wait) = wait.take() {
+                wait.await;
+            } else {
+                wait = Some(self.shared.on_closed.listen());
+            }
+        }
+    }
+
+    /// See [`SqliteFullDatabase::best_block_hash`].
+    pub async fn best_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
+        self.run(|database| database.best_block_hash()).await
+    }
+
+    /// See [`SqliteFullDatabase::finalized_block_hash`].
+    pub async fn finalized_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
+        self.run(|database| database.finalized_block_hash()).await
+    }
+
+    /// See [`SqliteFullDatabase::resume_state`].
+    pub async fn resume_state(&self) -> Result<ResumeState, CorruptedError> {
+        self.run(|database| database.resume_state()).await
+    }
+
+    /// See [`SqliteFullDatabase::fork_metrics`].
+    pub async fn fork_metrics(&self) -> Result<ForkMetrics, CorruptedError> {
+        self.run(|database| database.fork_metrics()).await
+    }
+
+    /// See [`SqliteFullDatabase::block_scale_encoded_header`].
+    pub async fn block_scale_encoded_header(
+        &self,
+        block_hash: [u8; 32],
+    ) -> Result<Option<Vec<u8>>, CorruptedError> {
+        self.run(move |database| database.block_scale_encoded_header(&block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::block_parent`].
+    pub async fn block_parent(
+        &self,
+        block_hash: [u8; 32],
+    ) -> Result<Option<ParentOf>, CorruptedError> {
+        self.run(move |database| database.block_parent(&block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::block_extrinsics`].
+    pub async fn block_extrinsics(
+        &self,
+        block_hash: [u8; 32],
+    ) -> Result<Option<Vec<Vec<u8>>>, BlockExtrinsicsError> {
+        self.run(move |database| {
+            Ok(database
+                .block_extrinsics(&block_hash)?
+                .map(|body| body.collect()))
+        })
+        .await
+    }
+
+    /// See [`SqliteFullDatabase::full_block`].
+    pub async fn full_block(
+        &self,
+        block_hash: [u8; 32],
+    ) -> Result<Option<FullBlock>, CorruptedError> {
+        self.run(move |database| database.full_block(&block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::block_hash_by_number`].
+    pub async fn block_hash_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<[u8; 32]>, CorruptedError> {
+        self.run(move |database| Ok(database.block_hash_by_number(block_number)?.collect()))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::blocks_by_number`].
+    pub async fn blocks_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<BlockAtNumber>, CorruptedError> {
+        self.run(move |database| database.blocks_by_number(block_number))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::best_block_hash_by_number`].
+    pub async fn best_block_hash_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<[u8; 32]>, CorruptedError> {
+        self.run(move |database| database.best_block_hash_by_number(block_number))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::to_chain_information`].
+    pub async fn to_chain_information(
+        &self,
+        finalized_block_hash: [u8; 32],
+    ) -> Result<chain_information::ValidChainInformation, StorageAccessError> {
+        self.run(move |database| database.to_chain_information(&finalized_block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::insert`].
+    pub async fn insert(
+        &self,
+        scale_encoded_header: Vec<u8>,
+        is_new_best: bool,
+        body: Vec<Vec<u8>>,
+        runtime_spec_version: Option<u32>,
+        meta: Option<InsertMeta>,
+    ) -> Result<InsertOutcome, InsertError> {
+        self.run(move |database| {
+            database.insert(
+                &scale_encoded_header,
+                is_new_best,
+                body.into_iter(),
+                runtime_spec_version,
+                meta.as_ref(),
+            )
+        })
+        .await
+    }
+
+    /// See [`SqliteFullDatabase::insert_scale_body`].
+    pub async fn insert_scale_body(
+        &self,
+        scale_encoded_header: Vec<u8>,
+        is_new_best: bool,
+        scale_encoded_body: Vec<u8>,
+        runtime_spec_version: Option<u32>,
+        meta: Option<InsertMeta>,
+    ) -> Result<InsertOutcome, InsertError> {
+        self.run(move |database| {
+            database.insert_scale_body(
+                &scale_encoded_header,
+                is_new_best,
+                &scale_encoded_body,
+                runtime_spec_version,
+                meta.as_ref(),
+            )
+        })
+        .await
+    }
+
+    /// See [`SqliteFullDatabase::set_best_block`].
+    pub async fn set_best_block(&self, block_hash: [u8; 32]) -> Result<(), SetBestError> {
+        self.run(move |database| database.set_best_block(&block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::set_finalized`].
+    pub async fn set_finalized(
+        &self,
+        new_finalized_block_hash: [u8; 32],
+    ) -> Result<SetFinalizedStats, SetFinalizedError> {
+        self.run(move |database| database.set_finalized(&new_finalized_block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::interrupt_handle`].
+    ///
+    /// Since the operations are executed one by one, interrupting the operation being executed
+    /// lets the next operations in the queue run.
+    pub async fn interrupt_handle(&self) -> InterruptHandle {
+        self.run(|database| database.interrupt_handle()).await
+    }
+
+    /// See [`SqliteFullDatabase::subscribe`].
+    pub async fn subscribe(&self) -> DatabaseEventReceiver {
+        self.run(|database| database.subscribe()).await
+    }
+
+    /// See [`SqliteFullDatabase::flush`].
+    pub async fn flush(&self) -> Result<(), CorruptedError> {
+        self.run(|database| database.flush()).await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_get_versioned`].
+    ///
+    /// Contrary to the original method, the keys are passed as lists of nibbles.
+    pub async fn block_storage_get(
+        &self,
+        block_hash: [u8; 32],
+        parent_tries_paths_nibbles: Vec<Vec<u8>>,
+        key_nibbles: Vec<u8>,
+    ) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+        self.run(move |database| {
+            database.block_storage_get_versioned(
+                &block_hash,
+                parent_tries_paths_nibbles
+                    .into_iter()
+                    .map(|p| p.into_iter()),
+                key_nibbles.into_iter(),
+            )
+        })
+        .await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_get_bytes`].
+    pub async fn block_storage_get_bytes(
+        &self,
+        block_hash: [u8; 32],
+        key: Vec<u8>,
+    ) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+        self.run(move |database| database.block_storage_get_bytes(&block_hash, &key))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_code`].
+    pub async fn block_storage_code(
+        &self,
+        block_hash: [u8; 32],
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.run(move |database| database.block_storage_code(&block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_heap_pages`].
+    pub async fn block_storage_heap_pages(
+        &self,
+        block_hash: [u8; 32],
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.run(move |database| database.block_storage_heap_pages(&block_hash))
+            .await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_next_key`].
+    ///
+    /// Contrary to the original method, the keys are passed as lists of nibbles.
+    pub async fn block_storage_next_key(
+        &self,
+        block_hash: [u8; 32],
+        parent_tries_paths_nibbles: Vec<Vec<u8>>,
+        key_nibbles: Vec<u8>,
+        prefix_nibbles: Vec<u8>,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.run(move |database| {
+            database.block_storage_next_key(
+                &block_hash,
+                parent_tries_paths_nibbles
+                    .into_iter()
+                    .map(|p| p.into_iter()),
+                key_nibbles.into_iter(),
+                prefix_nibbles.into_iter(),
+                branch_nodes,
+            )
+        })
+        .await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_next_key_bytes`].
+    pub async fn block_storage_next_key_bytes(
+        &self,
+        block_hash: [u8; 32],
+        key: Vec<u8>,
+        prefix: Vec<u8>,
+        or_equal: bool,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.run(move |database| {
+            database.block_storage_next_key_bytes(
+                &block_hash,
+                &key,
+                &prefix,
+                or_equal,
+                branch_nodes,
+            )
+        })
+        .await
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_closest_descendant_merkle_value`].
+    ///
+    /// Contrary to the original method, the keys are passed as lists of nibbles.
+    pub async fn block_storage_closest_descendant_merkle_value(
+        &self,
+        block_hash: [u8; 32],
+        parent_tries_paths_nibbles: Vec<Vec<u8>>,
+        key_nibbles: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        self.run(move |database| {
+            database.block_storage_closest_descendant_merkle_value(
+                &block_hash,
+                parent_tries_paths_nibbles
+                    .into_iter()
+                    .map(|p| p.into_iter()),
+                key_nibbles.into_iter(),
+            )
+        })
+        .await
+    }
+}
+
+/// Slot where the database thread writes the output of an operation.
+struct Response<T> {
+    value: parking_lot::Mutex<ResponseState<T>>,
+    /// Notified when `value` is no longer [`ResponseState::Pending`].
+    on_ready: event_listener::Event,
+}
+
+enum ResponseState<T> {
+    Pending,
+    Ready(T),
+    /// The operation has been destroyed without having been run.
+    Dropped,
+}
+
+/// Writes the output of an operation to a [`Response`], or [`ResponseState::Dropped`] if it is
+/// destroyed before that.
+struct Responder<T>(Arc<Response<T>>);
+
+impl<T> Responder<T> {
+    fn send(self, value: T) {
+        *self.0.value.lock() = ResponseState::Ready(value);
+        // `Drop` notifies the listener.
+    }
+}
+
+impl<T> Drop for Responder<T> {
+    fn drop(&mut self) {
+        let mut value = self.0.value.lock();
+        if matches!(*value, ResponseState::Pending) {
+            *value = ResponseState::Dropped;
+        }
+        drop(value);
+        self.0.on_ready.notify(usize::MAX);
+    }
+}
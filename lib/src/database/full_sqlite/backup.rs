@@ -0,0 +1,267 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Online backups of the database.
+//!
+//! Contains everything related to copying the content of a database while it is in use.
+
+use super::{open, snapshot, sqlite_err, CorruptedError, InternalError, SqliteFullDatabase};
+
+use core::{ffi::c_int, time::Duration};
+use std::path::Path;
+
+/// Number of database pages copied at each step of a backup.
+const PAGES_PER_STEP: c_int = 128;
+
+/// Number of consecutive times a step of a backup is retried while another connection is
+/// holding a lock on the source or destination, before giving up.
+const MAX_BUSY_RETRIES: u32 = 500;
+
+/// Duration to wait before retrying a step of a backup that has failed because of a lock.
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+impl SqliteFullDatabase {
+    /// Copies the content of the database to the given destination file. If the destination
+    /// file already exists, its content is overwritten.
+    ///
+    /// The copy is performed incrementally, and `progress` is called after each step. The
+    /// database can continue to be used normally, including from within `progress`, while the
+    /// backup is in progress. The destination contains the state of the database at the time
+    /// when the backup has started, and can later be opened using [`super::open`].
+    ///
+    /// Returns [`BackupError::Busy`] if another connection holds a lock on the source or the
+    /// destination for too long.
+    ///
+    /// > **Note**: In-memory databases (see [`super::ConfigTy::Memory`]) can't be accessed by
+    /// >           other connections, and are thus locked for the entire duration of the
+    /// >           backup. In that situation, `progress` is called only once, after the backup
+    /// >           has finished.
+    pub fn backup_to(
+        &self,
+        destination: &Path,
+        mut progress: impl FnMut(BackupProgress),
+    ) -> Result<(), BackupError> {
+        let mut destination = rusqlite::Connection::open(destination)
+            .map_err(|err| BackupError::Destination(InternalError::new("backup_to", err)))?;
+        self.copy_into(&mut destination, &mut progress)
+    }
+
+    /// Copies the entire content of the database into a new in-memory database.
+    ///
+    /// The returned database uses the same `block_number_bytes` as `self`, and is completely
+    /// independent from `self`. Modifying one doesn't affect the other. This is useful in order
+    /// to for example experiment with alternative finalization sequences without risking to
+    /// modify the original.
+    ///
+    /// Similar to [`SqliteFullDatabase::backup_to`], `self` isn't locked during the copy,
+    /// unless it is itself an in-memory database.
+    pub fn clone_in_memory(&self) -> Result<SqliteFullDatabase, CorruptedError> {
+        snapshot::assert_no_active_snapshot(self);
+
+        // The size of the cache is reported by SQLite either in number of pages (if positive)
+        // or in kiB (if negative).
+        let cache_size = {
+            let database = self.database.lock();
+            let cache_size = database
+                .query_row("PRAGMA cache_size", (), |row| row.get::<_, i64>(0))
+                .map_err(sqlite_err("clone_in_memory"))?;
+            let page_size = database
+                .query_row("PRAGMA page_size", (), |row| row.get::<_, i64>(0))
+                .map_err(sqlite_err("clone_in_memory"))?;
+            let bytes = if cache_size < 0 {
+                cache_size.saturating_neg().saturating_mul(1024)
+            } else {
+                cache_size.saturating_mul(page_size)
+            };
+            usize::try_from(bytes).unwrap_or(usize::MAX)
+        };
+
+        let super::DatabaseOpen::Empty(empty) = super::open(super::Config {
+            ty: super::ConfigTy::Memory,
+            block_number_bytes: self.block_number_bytes,
+            chain_name: self.chain_name.as_deref(),
+            cache_size,
+            statement_cache_capacity: self.statement_cache_capacity,
+            // The new database is only accessible through the returned object.
+            busy_timeout: Duration::ZERO,
+            read_connections: 0,
+            commit_policy: self.commit_policy,
+            body_retention: self.body_retention,
+            storage_diff_retention: self.storage_diff_retention,
+            store_events: self.store_events,
+            // The metrics of the new database shouldn't be mixed with the ones of `self`.
+            on_operation: None,
+            auto_optimize_interval: self.auto_optimize_interval,
+            value_compression: self.value_compression,
+            allow_shared_readers: false,
+            progress: None,
+        })
+        .map_err(|err| match err {
+            super::OpenError::Internal(err) => CorruptedError::Internal(err),
+            // A newly-created in-memory database doesn't contain any row.
+            super::OpenError::InvalidRows(_) => unreachable!(),
+            // In-memory databases aren't locked, and are never read-only.
+            super::OpenError::AlreadyInUse { .. }
+            | super::OpenError::LockFile(_)
+            | super::OpenError::ReadOnly => unreachable!(),
+        })?
+        else {
+            // A newly-created in-memory database is always empty.
+            unreachable!()
+        };
+
+        let mut clone = empty.into_database_uninitialized();
+        self.copy_into(clone.database.get_mut(), &mut |_| {})
+            .map_err(|err| match err {
+                BackupError::Corrupted(err) => err,
+                BackupError::Destination(err) => CorruptedError::Internal(err),
+                BackupError::Busy => CorruptedError::Busy,
+            })?;
+
+        // The copy has replaced the list of chains of the new database, and the identifier of
+        // the chain must thus be determined again.
+        let chain_id = open::chain_id(clone.database.get_mut(), self.chain_name.as_deref())
+            .map_err(CorruptedError::Internal)?;
+        open::set_current_chain(clone.database.get_mut(), chain_id)
+            .map_err(CorruptedError::Internal)?;
+
+        Ok(clone)
+    }
+
+    /// Copies the entire content of the database to `destination`, calling `progress` after
+    /// each step.
+    fn copy_into(
+        &self,
+        destination: &mut rusqlite::Connection,
+        progress: &mut impl FnMut(BackupProgress),
+    ) -> Result<(), BackupError> {
+        // Modifications that haven't been committed aren't visible to other connections.
+        self.flush().map_err(BackupError::Corrupted)?;
+
+        // `path()` returns an empty string for in-memory databases.
+        let source_path = self
+            .database
+            .lock()
+            .path()
+            .filter(|path| !path.is_empty())
+            .map(|path| path.to_owned());
+
+        if let Some(source_path) = source_path {
+            // Disk databases are copied from a separate read-only connection, so that the main
+            // connection isn't locked during the backup.
+            // This separate connection holds a read transaction for the entire duration of the
+            // backup. Thanks to the WAL mode, this guarantees that the copy is consistent while
+            // not preventing writes.
+            let source = rusqlite::Connection::open_with_flags(
+                source_path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .map_err(|err| BackupError::Corrupted(sqlite_err("copy_into")(err)))?;
+            source
+                .execute_batch("BEGIN DEFERRED; SELECT COUNT(*) FROM meta;")
+                .map_err(|err| BackupError::Corrupted(sqlite_err("copy_into")(err)))?;
+
+            copy_database(&source, destination, progress)
+        } else {
+            let database = self.database.lock();
+            let mut last_progress = None;
+            copy_database(&database, destination, &mut |p| last_progress = Some(p))?;
+            drop(database);
+
+            if let Some(last_progress) = last_progress {
+                progress(last_progress);
+            }
+
+            Ok(())
+        }
+    }
+}
+
+/// Progress of a backup. See [`SqliteFullDatabase::backup_to`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// Number of pages that have been copied so far.
+    pub pages_copied: u32,
+    /// Number of pages that remain to be copied.
+    pub pages_remaining: u32,
+}
+
+/// Error while calling [`SqliteFullDatabase::backup_to`].
+#[derive(Debug, derive_more::Display)]
+pub enum BackupError {
+    /// Error accessing the database being copied.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Error while opening or writing the destination database.
+    #[display(fmt = "Error while writing the backup: {_0}")]
+    Destination(InternalError),
+    /// Another connection has been holding a lock on the source or destination database for too
+    /// long. The destination is incomplete, and the backup can be attempted again later.
+    #[display(fmt = "Database locked by another connection for too long")]
+    Busy,
+}
+
+/// Copies the entire content of `source` to `destination`, calling `progress` after each step.
+fn copy_database(
+    source: &rusqlite::Connection,
+    destination: &mut rusqlite::Connection,
+    progress: &mut impl FnMut(BackupProgress),
+) -> Result<(), BackupError> {
+    let backup = rusqlite::backup::Backup::new(source, destination)
+        .map_err(|err| BackupError::Destination(InternalError::new("copy_database", err)))?;
+
+    let mut busy_retries = 0;
+
+    loop {
+        let step = backup
+            .step(PAGES_PER_STEP)
+            .map_err(|err| BackupError::Destination(InternalError::new("copy_database", err)))?;
+
+        let status = backup.progress();
+        let pages_remaining = u32::try_from(status.remaining).unwrap_or(0);
+        let pages_copied = u32::try_from(status.pagecount)
+            .unwrap_or(0)
+            .saturating_sub(pages_remaining);
+
+        match step {
+            rusqlite::backup::StepResult::Done => {
+                progress(BackupProgress {
+                    pages_copied,
+                    pages_remaining: 0,
+                });
+                return Ok(());
+            }
+            rusqlite::backup::StepResult::More => {
+                busy_retries = 0;
+                progress(BackupProgress {
+                    pages_copied,
+                    pages_remaining,
+                })
+            }
+            // Another connection is holding a lock on the source or destination. Wait a bit
+            // before trying again.
+            _ => {
+                busy_retries += 1;
+                if busy_retries > MAX_BUSY_RETRIES {
+                    return Err(BackupError::Busy);
+                }
+                std::thread::sleep(BUSY_RETRY_DELAY);
+            }
+        }
+    }
+}
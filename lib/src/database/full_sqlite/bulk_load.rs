@@ -0,0 +1,264 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Inserting a large number of blocks quickly, for example during the initial sync.
+//!
+//! See [`SqliteFullDatabase::begin_bulk_load`].
+
+use super::{
+    import::{self, ExportedBlock, ImportError, ImportReport},
+    snapshot, sqlite_err, CorruptedError, OperationKind, SqliteFullDatabase,
+};
+
+use alloc::format;
+use core::sync::atomic::Ordering;
+
+/// Number of blocks after which the modifications are committed while a bulk load is in
+/// progress, regardless of the [`super::CommitPolicy`].
+///
+/// Committing is cheap while a bulk load is in progress, as the data isn't flushed to the disk.
+/// Committing regularly prevents the write-ahead log from growing indefinitely.
+pub(super) const BLOCKS_PER_COMMIT: u64 = 16384;
+
+/// Indices that are removed for the duration of a bulk load, and the statement that creates
+/// each of them.
+///
+/// These indices are only necessary in order to determine whether a trie node or an extrinsic is
+/// still in use when removing blocks or storage, or are redundant with the primary key of their
+/// table. None of them is used when inserting blocks or trie nodes.
+const DEFERRED_INDICES: [(&str, &str); 4] = [
+    (
+        "trie_node_storage_by_trie_root_ref",
+        "CREATE INDEX IF NOT EXISTS trie_node_storage_by_trie_root_ref ON trie_node_storage(trie_root_ref)",
+    ),
+    (
+        "blocks_by_state_trie_root_hash",
+        "CREATE INDEX IF NOT EXISTS blocks_by_state_trie_root_hash ON blocks(state_trie_root_hash)",
+    ),
+    (
+        "blocks_body_by_block",
+        "CREATE INDEX IF NOT EXISTS blocks_body_by_block ON blocks_body(hash)",
+    ),
+    (
+        "blocks_body_by_extrinsic",
+        "CREATE INDEX IF NOT EXISTS blocks_body_by_extrinsic ON blocks_body(extrinsic_hash)",
+    ),
+];
+
+impl SqliteFullDatabase {
+    /// Switches the database to a mode where inserting blocks and trie nodes is faster, at the
+    /// cost of durability. The database goes back to normal when the returned [`BulkLoadGuard`]
+    /// is destroyed or when [`BulkLoadGuard::finish`] is called.
+    ///
+    /// While a bulk load is in progress:
+    ///
+    /// - The data written to the database file isn't flushed to the disk (`PRAGMA synchronous`
+    ///   is `OFF`).
+    /// - The [`super::CommitPolicy`] is ignored, and modifications are instead committed every
+    ///   few thousands blocks.
+    /// - The indices that aren't necessary in order to insert blocks are removed, and are
+    ///   rebuilt at the end of the bulk load. Removing blocks or storage, for example with
+    ///   [`SqliteFullDatabase::purge_finality_orphans`], and
+    ///   [`SqliteFullDatabase::insert_trie_nodes_validated`] are considerably slower.
+    ///
+    /// The database can be accessed normally while a bulk load is in progress. Blocks can be
+    /// inserted in batches with [`BulkLoadGuard::insert_many`].
+    ///
+    /// # Crashes
+    ///
+    /// **If the operating system crashes or the machine loses power while a bulk load is in
+    /// progress, the database file is likely to be corrupted and must be deleted. The sync must
+    /// then start over.** If only the process crashes, the modifications that have been
+    /// committed are kept and the removed indices are rebuilt the next time the database is
+    /// opened, which can take a long time.
+    ///
+    /// Returns [`BeginBulkLoadError::AlreadyActive`] if a bulk load is already in progress.
+    pub fn begin_bulk_load(&self) -> Result<BulkLoadGuard<'_>, BeginBulkLoadError> {
+        let _timer = self.time_operation(OperationKind::BulkLoad);
+        if self.bulk_load_active.swap(true, Ordering::Relaxed) {
+            return Err(BeginBulkLoadError::AlreadyActive);
+        }
+
+        // The guard is created immediately so that the database goes back to normal if an
+        // error happens below.
+        let guard = BulkLoadGuard {
+            database: self,
+            finished: false,
+        };
+
+        // The modifications performed before the bulk load are committed with the normal
+        // durability guarantees.
+        commit_and_set_synchronous(self, "OFF")?;
+
+        let mut database = self.lock_write()?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("begin_bulk_load"))?;
+        for (name, _) in DEFERRED_INDICES {
+            transaction
+                .execute_batch(&format!("DROP INDEX IF EXISTS {name}"))
+                .map_err(sqlite_err("begin_bulk_load"))?;
+        }
+        transaction
+            .commit()
+            .map_err(sqlite_err("begin_bulk_load"))?;
+        database.finish(0)?;
+
+        Ok(guard)
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::begin_bulk_load`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum BeginBulkLoadError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// A bulk load is already in progress. Nothing has been modified.
+    #[display(fmt = "Bulk load already in progress")]
+    AlreadyActive,
+}
+
+impl std::error::Error for BeginBulkLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BeginBulkLoadError::Corrupted(err) => err.source(),
+            BeginBulkLoadError::AlreadyActive => None,
+        }
+    }
+}
+
+/// Bulk load in progress. See [`SqliteFullDatabase::begin_bulk_load`].
+///
+/// The database goes back to normal when this object is destroyed, including in case of a
+/// panic. Use [`BulkLoadGuard::finish`] in order to be informed of errors.
+#[must_use]
+pub struct BulkLoadGuard<'a> {
+    database: &'a SqliteFullDatabase,
+    /// `true` if the database has already gone back to normal.
+    finished: bool,
+}
+
+impl<'a> BulkLoadGuard<'a> {
+    /// Inserts the given blocks in the database. The parent of each block must be either in the
+    /// database or earlier in the iterator, and blocks that are already in the database are
+    /// skipped.
+    ///
+    /// The blocks are inserted in batches, each within its own transaction, which is
+    /// considerably faster than calling [`SqliteFullDatabase::insert`] for each block. The
+    /// latest inserted block becomes the new best block after each batch. If an error happens,
+    /// the blocks of the batch being inserted are discarded, but the blocks of the previous
+    /// batches remain in the database.
+    ///
+    /// The storage of the blocks must be inserted separately, for example with
+    /// [`SqliteFullDatabase::insert_trie_nodes_versioned`].
+    pub fn insert_many(
+        &self,
+        blocks: impl Iterator<Item = ExportedBlock>,
+    ) -> Result<ImportReport, ImportError> {
+        import::insert_blocks(self.database, blocks.map(Ok), None)
+    }
+
+    /// Ends the bulk load, rebuilds the indices that have been removed, and commits all the
+    /// modifications. Can take a long time.
+    ///
+    /// Afterwards, the database provides the same guarantees as if no bulk load had happened.
+    /// The database goes back to normal even if an error is returned.
+    pub fn finish(mut self) -> Result<(), CorruptedError> {
+        self.end()
+    }
+
+    fn end(&mut self) -> Result<(), CorruptedError> {
+        self.finished = true;
+        let _timer = self.database.time_operation(OperationKind::BulkLoad);
+
+        let rebuild_outcome = self.rebuild_indices();
+
+        // The original settings are restored even if the indices couldn't be rebuilt, in which
+        // case they will be rebuilt the next time the database is opened. The data written
+        // during the bulk load is flushed to the disk the next time the write-ahead log is
+        // synchronized, as it is part of the same file.
+        self.database
+            .bulk_load_active
+            .store(false, Ordering::Relaxed);
+        let pragma_outcome = commit_and_set_synchronous(self.database, "NORMAL");
+
+        // The statistics of the query planner are most likely outdated.
+        self.database.analyze_pending.store(true, Ordering::Relaxed);
+
+        rebuild_outcome.and(pragma_outcome)
+    }
+
+    fn rebuild_indices(&self) -> Result<(), CorruptedError> {
+        let mut database = self.database.lock_write()?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("bulk_load_finish"))?;
+        create_deferred_indices(&transaction).map_err(sqlite_err("bulk_load_finish"))?;
+        transaction
+            .commit()
+            .map_err(sqlite_err("bulk_load_finish"))?;
+        database.finish(0)
+    }
+}
+
+impl<'a> Drop for BulkLoadGuard<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.end();
+        }
+    }
+}
+
+/// Commits all the modifications that haven't been committed yet, then sets
+/// `PRAGMA synchronous` to the given value.
+///
+/// `PRAGMA synchronous` can't be modified while a transaction is in progress, and both steps
+/// are thus performed while the connection is locked.
+fn commit_and_set_synchronous(
+    database: &SqliteFullDatabase,
+    value: &str,
+) -> Result<(), CorruptedError> {
+    snapshot::assert_no_active_snapshot(database);
+    let connection = database.database.lock();
+    database.check_uncommitted_writes(&connection)?;
+    if !connection.is_autocommit() {
+        connection
+            .execute_batch("COMMIT")
+            .map_err(sqlite_err("bulk_load"))?;
+    }
+    let events = database.clear_uncommitted_writes();
+    let result = connection
+        .execute_batch(&format!("PRAGMA synchronous = {value}"))
+        .map_err(sqlite_err("bulk_load"));
+    drop(connection);
+    database.emit_events(events);
+    result
+}
+
+/// Creates the indices removed by [`SqliteFullDatabase::begin_bulk_load`], if they don't exist.
+///
+/// Called when the database is opened, in order to recover from a bulk load that has been
+/// interrupted by a crash.
+pub(super) fn create_deferred_indices(
+    database: &rusqlite::Connection,
+) -> Result<(), rusqlite::Error> {
+    for (_, statement) in DEFERRED_INDICES {
+        database.execute_batch(statement)?;
+    }
+    Ok(())
+}
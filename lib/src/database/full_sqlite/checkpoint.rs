@@ -0,0 +1,232 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Export of the finalized block of the database as a checkpoint, and initialization of a
+//! database from such a checkpoint.
+//!
+//! A checkpoint contains the header and justification of the finalized block, and the
+//! consensus and finality information necessary to verify its descendants. It doesn't contain
+//! the storage of the finalized block, which must be downloaded separately.
+//!
+//! Use [`SqliteFullDatabase::export_checkpoint`] to produce a [`Checkpoint`], and
+//! [`DatabaseEmpty::initialize_from_checkpoint`] to initialize a database from it.
+
+use super::{
+    block_justification, finalized_chain_information, finalized_hash, prepare_cached, sqlite_err,
+    CorruptedError, DatabaseEmpty, ResetError, SqliteFullDatabase,
+};
+use crate::{
+    chain::chain_information,
+    chain_spec::{
+        ChainInformationToCheckpointError, CheckpointToChainInformationError, LightSyncState,
+        ParseError,
+    },
+};
+
+use alloc::{string::String, vec::Vec};
+use core::iter;
+
+/// Finalized block of a chain and the information necessary to verify its descendants.
+///
+/// See [`SqliteFullDatabase::export_checkpoint`].
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// Header of the finalized block, and consensus and finality information of the chain at
+    /// this block.
+    pub chain_information: chain_information::ValidChainInformation,
+
+    /// Justification of the finalized block, if the database contains one.
+    pub finalized_block_justification: Option<Vec<u8>>,
+
+    /// Number of bytes used to encode the block numbers in the headers of the chain. See
+    /// [`super::Config::block_number_bytes`].
+    pub block_number_bytes: usize,
+}
+
+impl Checkpoint {
+    /// Turns the checkpoint into the `lightSyncState` field of a chain specification.
+    ///
+    /// The justification of the finalized block isn't part of this format.
+    ///
+    /// Returns an error if the chain doesn't use Babe and Grandpa, or if the finalized block is
+    /// the genesis block.
+    pub fn to_light_sync_state(&self) -> Result<LightSyncState, ChainInformationToCheckpointError> {
+        LightSyncState::from_chain_information(
+            self.chain_information.as_ref(),
+            self.block_number_bytes,
+        )
+    }
+
+    /// Returns the JSON representation of the checkpoint, in the same format as the
+    /// `lightSyncState` field of Substrate chain specifications.
+    ///
+    /// See [`Checkpoint::to_light_sync_state`].
+    pub fn to_json(&self) -> Result<String, ChainInformationToCheckpointError> {
+        Ok(self.to_light_sync_state()?.to_json())
+    }
+
+    /// Parses a checkpoint from its JSON representation, in the same format as the
+    /// `lightSyncState` field of Substrate chain specifications.
+    ///
+    /// This is the reverse operation of [`Checkpoint::to_json`]. As the justification of the
+    /// finalized block isn't part of this format, [`Checkpoint::finalized_block_justification`]
+    /// is always `None`.
+    pub fn from_json(
+        json: &str,
+        block_number_bytes: usize,
+    ) -> Result<Checkpoint, CheckpointFromJsonError> {
+        let light_sync_state = LightSyncState::from_json(json, block_number_bytes)
+            .map_err(CheckpointFromJsonError::Parse)?;
+        let chain_information = light_sync_state
+            .to_chain_information()
+            .map_err(CheckpointFromJsonError::ChainInformation)?;
+
+        Ok(Checkpoint {
+            chain_information,
+            finalized_block_justification: None,
+            block_number_bytes,
+        })
+    }
+}
+
+/// Error while calling [`Checkpoint::from_json`].
+#[derive(Debug, derive_more::Display)]
+pub enum CheckpointFromJsonError {
+    /// The JSON isn't a valid light sync state.
+    #[display(fmt = "{_0}")]
+    Parse(ParseError),
+    /// The light sync state doesn't describe a valid chain information.
+    #[display(fmt = "{_0}")]
+    ChainInformation(CheckpointToChainInformationError),
+}
+
+impl std::error::Error for CheckpointFromJsonError {}
+
+impl SqliteFullDatabase {
+    /// Returns a [`Checkpoint`] of the current finalized block of the database.
+    ///
+    /// Everything is read from a single consistent snapshot of the database, and is thus
+    /// guaranteed to be coherent even if the finalized block is updated in parallel.
+    pub fn export_checkpoint(&self) -> Result<Checkpoint, CorruptedError> {
+        let mut connection = self.read_connection();
+        let connection = connection
+            .savepoint()
+            .map_err(sqlite_err("export_checkpoint"))?;
+
+        let chain_information = finalized_chain_information(&connection, self.block_number_bytes)?;
+
+        let finalized_block_justification =
+            block_justification(&connection, &finalized_hash(&connection)?)?;
+
+        Ok(Checkpoint {
+            chain_information,
+            finalized_block_justification,
+            block_number_bytes: self.block_number_bytes,
+        })
+    }
+}
+
+impl DatabaseEmpty {
+    /// Similar to [`DatabaseEmpty::initialize`], but uses a [`Checkpoint`], for example one
+    /// produced by [`SqliteFullDatabase::export_checkpoint`] from another database.
+    ///
+    /// The body of the finalized block isn't part of a checkpoint, and is considered as pruned.
+    /// The storage of the finalized block isn't part of a checkpoint either, and must be
+    /// downloaded afterwards. See
+    /// [`SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`].
+    ///
+    /// Returns an error if [`Checkpoint::block_number_bytes`] isn't the same as the one the
+    /// database has been opened with. Nothing is written to the database in that situation.
+    pub fn initialize_from_checkpoint(
+        self,
+        checkpoint: &Checkpoint,
+    ) -> Result<SqliteFullDatabase, InitializeFromCheckpointError> {
+        if checkpoint.block_number_bytes != self.block_number_bytes {
+            return Err(InitializeFromCheckpointError::BlockNumberBytesMismatch {
+                checkpoint: checkpoint.block_number_bytes,
+                database: self.block_number_bytes,
+            });
+        }
+
+        let database = self
+            .initialize_incomplete(
+                checkpoint.chain_information.as_ref(),
+                iter::empty(),
+                checkpoint.finalized_block_justification.clone(),
+            )
+            .map_err(|err| match err {
+                ResetError::Corrupted(err) => InitializeFromCheckpointError::Corrupted(err),
+                ResetError::HeaderRoundTripMismatch => {
+                    InitializeFromCheckpointError::HeaderRoundTripMismatch
+                }
+                ResetError::BlockOfOtherChain => InitializeFromCheckpointError::BlockOfOtherChain,
+            })?;
+
+        {
+            let connection = database.lock_write()?;
+            let finalized_block_hash = finalized_hash(&connection)?;
+            prepare_cached(
+                &connection,
+                "UPDATE blocks SET body_pruned = TRUE WHERE hash = ?",
+            )
+            .map_err(sqlite_err("initialize_from_checkpoint"))?
+            .execute((&finalized_block_hash[..],))
+            .map_err(sqlite_err("initialize_from_checkpoint"))?;
+            connection.finish(0)?;
+        }
+
+        database.finish_initialization()?;
+        Ok(database)
+    }
+}
+
+/// Error while calling [`DatabaseEmpty::initialize_from_checkpoint`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum InitializeFromCheckpointError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// The checkpoint has been produced by a database whose block numbers are encoded with a
+    /// different number of bytes.
+    #[display(
+        fmt = "Checkpoint uses {checkpoint} bytes per block number, but the database uses {database}"
+    )]
+    #[from(ignore)]
+    BlockNumberBytesMismatch {
+        /// Value of [`Checkpoint::block_number_bytes`].
+        checkpoint: usize,
+        /// Value of [`super::Config::block_number_bytes`] the database has been opened with.
+        database: usize,
+    },
+    /// See [`ResetError::HeaderRoundTripMismatch`].
+    #[display(fmt = "Finalized block header doesn't decode back into the same header")]
+    #[from(ignore)]
+    HeaderRoundTripMismatch,
+    /// See [`ResetError::BlockOfOtherChain`].
+    #[display(fmt = "Finalized block belongs to another chain of the database")]
+    #[from(ignore)]
+    BlockOfOtherChain,
+}
+
+impl std::error::Error for InitializeFromCheckpointError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitializeFromCheckpointError::Corrupted(err) => err.source(),
+            _ => None,
+        }
+    }
+}
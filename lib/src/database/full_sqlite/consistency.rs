@@ -0,0 +1,326 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Verification of the storage of a block against a reference.
+//!
+//! The storage of the blocks is accessed through recursive SQL queries that implement the
+//! semantics of the trie. [`check_block_storage`] compares the outcome of these queries with
+//! the outcome calculated from a reference list of storage entries, in order to detect bugs in
+//! these queries or a corruption of the database. It is mostly useful in tests.
+//!
+//! Only the main trie is checked. The entries of the reference that are the root of a child
+//! trie are compared like any other entry, but the child trie itself isn't verified.
+
+#![cfg(any(test, feature = "database-sqlite-consistency"))]
+#![cfg_attr(docsrs, doc(cfg(feature = "database-sqlite-consistency")))]
+
+use super::{SqliteFullDatabase, StorageAccessError};
+use crate::trie::{self, TrieEntryVersion};
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use core::{array, iter};
+
+/// Verifies that the storage of the given block matches `reference`, which contains all the
+/// entries of the main trie of the storage of this block.
+///
+/// The following is verified:
+///
+/// - [`SqliteFullDatabase::block_storage_get_versioned`] returns the value of every key of the
+/// reference, and no value for keys that are close to the keys of the reference but not part of
+/// it.
+/// - Calling [`SqliteFullDatabase::block_storage_next_key`] repeatedly, starting from the empty
+/// key, visits exactly the keys of the reference in order. The same verification is done with
+/// branch nodes included.
+/// - [`SqliteFullDatabase::block_storage_closest_descendant_merkle_value`] returns, for every
+/// key that is a prefix of a node of the trie or one nibble longer than a node of the trie, the
+/// Merkle value calculated from the reference.
+///
+/// The number of queries performed is proportional to the total length of the keys of the
+/// reference, meaning that this function is slow on large storages.
+///
+/// The reference must contain at least one entry. The database can't make the difference
+/// between an empty storage and a storage that is missing.
+pub fn check_block_storage(
+    database: &SqliteFullDatabase,
+    block_hash: &[u8; 32],
+    reference: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<(), ConsistencyError> {
+    // The entry versions are needed in order to calculate the Merkle values, and can only be
+    // obtained from the database.
+    let mut entries = Vec::with_capacity(reference.len());
+    for (key, value) in reference {
+        match database.block_storage_get_bytes(block_hash, key)? {
+            Some((obtained, version)) if obtained == *value => {
+                entries.push((&key[..], &value[..], version));
+            }
+            obtained => {
+                return Err(ConsistencyError::WrongValue {
+                    key: key.clone(),
+                    expected: Some(value.clone()),
+                    obtained: obtained.map(|(value, _)| value),
+                })
+            }
+        }
+    }
+
+    // The keys most likely to be confused with the keys of the reference are their prefixes
+    // and the keys that are slightly longer.
+    let absent_keys = reference
+        .keys()
+        .flat_map(|key| {
+            (0..key.len())
+                .map(|len| key[..len].to_vec())
+                .chain([0x00, 0xff].map(|byte| {
+                    key.iter()
+                        .copied()
+                        .chain(iter::once(byte))
+                        .collect::<Vec<_>>()
+                }))
+        })
+        .filter(|key| !reference.contains_key(key))
+        .collect::<BTreeSet<_>>();
+    for key in absent_keys {
+        if let Some((obtained, _)) = database.block_storage_get_bytes(block_hash, &key)? {
+            return Err(ConsistencyError::WrongValue {
+                key,
+                expected: None,
+                obtained: Some(obtained),
+            });
+        }
+    }
+
+    let nodes = reference_trie_nodes(&entries);
+
+    for branch_nodes in [false, true] {
+        let mut expected = nodes
+            .iter()
+            .filter(|node| branch_nodes || node.has_storage_value)
+            .map(|node| &node.key_nibbles);
+
+        // The first call uses the empty key, and the next ones use the previous key followed
+        // with a `0` in order to obtain the key that strictly follows it.
+        let mut key = Vec::new();
+        loop {
+            let expected = expected.next();
+            let obtained = database.block_storage_next_key(
+                block_hash,
+                iter::empty::<iter::Empty<u8>>(),
+                key.iter().copied(),
+                iter::empty(),
+                branch_nodes,
+            )?;
+
+            if obtained.as_ref() != expected {
+                return Err(ConsistencyError::WrongNextKey {
+                    key_nibbles: key,
+                    branch_nodes,
+                    expected: expected.cloned(),
+                    obtained,
+                });
+            }
+
+            let Some(mut obtained) = obtained else { break };
+            obtained.push(0);
+            key = obtained;
+        }
+    }
+
+    let candidates = nodes
+        .iter()
+        .flat_map(|node| {
+            (0..=node.key_nibbles.len())
+                .map(|len| node.key_nibbles[..len].to_vec())
+                .chain((0..16).map(|nibble| {
+                    node.key_nibbles
+                        .iter()
+                        .copied()
+                        .chain(iter::once(nibble))
+                        .collect::<Vec<_>>()
+                }))
+        })
+        .collect::<BTreeSet<_>>();
+    for key_nibbles in candidates {
+        // The closest descendant is the node with the shortest key among the ones that start
+        // with the requested key.
+        let expected = nodes
+            .iter()
+            .filter(|node| node.key_nibbles.starts_with(&key_nibbles))
+            .min_by_key(|node| node.key_nibbles.len())
+            .map(|node| node.merkle_value.clone());
+        let obtained = database.block_storage_closest_descendant_merkle_value(
+            block_hash,
+            iter::empty::<iter::Empty<u8>>(),
+            key_nibbles.iter().copied(),
+        )?;
+
+        if obtained != expected {
+            return Err(ConsistencyError::WrongClosestDescendantMerkleValue {
+                key_nibbles,
+                expected,
+                obtained,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Error potentially returned by [`check_block_storage`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum ConsistencyError {
+    /// Error while accessing the storage of the block.
+    #[display(fmt = "{_0}")]
+    StorageAccess(StorageAccessError),
+    /// [`SqliteFullDatabase::block_storage_get_versioned`] has returned a value different from the
+    /// reference.
+    #[display(fmt = "Wrong value for key 0x{}", "hex::encode(key)")]
+    #[from(ignore)]
+    WrongValue {
+        /// Key whose value has been requested.
+        key: Vec<u8>,
+        /// Value found in the reference.
+        expected: Option<Vec<u8>>,
+        /// Value returned by the database.
+        obtained: Option<Vec<u8>>,
+    },
+    /// [`SqliteFullDatabase::block_storage_next_key`] has returned a key different from the
+    /// reference.
+    #[display(fmt = "Wrong next key for key {key_nibbles:?} (branch nodes: {branch_nodes})")]
+    #[from(ignore)]
+    WrongNextKey {
+        /// Nibbles of the key passed to the function.
+        key_nibbles: Vec<u8>,
+        /// Value of the `branch_nodes` parameter passed to the function.
+        branch_nodes: bool,
+        /// Nibbles of the key found in the reference.
+        expected: Option<Vec<u8>>,
+        /// Nibbles of the key returned by the database.
+        obtained: Option<Vec<u8>>,
+    },
+    /// [`SqliteFullDatabase::block_storage_closest_descendant_merkle_value`] has returned a
+    /// Merkle value different from the reference.
+    #[display(fmt = "Wrong closest descendant Merkle value for key {key_nibbles:?}")]
+    #[from(ignore)]
+    WrongClosestDescendantMerkleValue {
+        /// Nibbles of the key passed to the function.
+        key_nibbles: Vec<u8>,
+        /// Merkle value calculated from the reference.
+        expected: Option<Vec<u8>>,
+        /// Merkle value returned by the database.
+        obtained: Option<Vec<u8>>,
+    },
+}
+
+impl std::error::Error for ConsistencyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            // The `Display` implementation of this variant is the one of the inner error.
+            ConsistencyError::StorageAccess(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Node of the trie built by [`reference_trie_nodes`].
+struct ReferenceNode {
+    key_nibbles: Vec<u8>,
+    has_storage_value: bool,
+    merkle_value: Vec<u8>,
+}
+
+/// Builds the trie containing the given entries, and returns all its nodes ordered by key.
+///
+/// The keys of the entries must be unique.
+fn reference_trie_nodes(entries: &[(&[u8], &[u8], TrieEntryVersion)]) -> Vec<ReferenceNode> {
+    let mut trie = trie::trie_structure::TrieStructure::<(
+        Option<(&[u8], TrieEntryVersion)>,
+        Option<trie::trie_node::MerkleValueOutput>,
+    )>::new();
+
+    for (key, value, version) in entries {
+        match trie.node(trie::bytes_to_nibbles(key.iter().copied())) {
+            trie::trie_structure::Entry::Vacant(entry) => {
+                entry
+                    .insert_storage_value()
+                    .insert((Some((value, *version)), None), (None, None));
+            }
+            trie::trie_structure::Entry::Occupied(trie::trie_structure::NodeAccess::Branch(
+                mut entry,
+            )) => {
+                entry.user_data().0 = Some((value, *version));
+                entry.insert_storage_value();
+            }
+            trie::trie_structure::Entry::Occupied(trie::trie_structure::NodeAccess::Storage(_)) => {
+                unreachable!()
+            }
+        }
+    }
+
+    // The Merkle value of a node depends on the Merkle values of its children, and the nodes
+    // are thus processed in reverse order.
+    let node_indices = trie.iter_ordered().collect::<Vec<_>>();
+    let mut nodes = Vec::with_capacity(node_indices.len());
+    for node_index in node_indices.into_iter().rev() {
+        let mut node_access = trie.node_by_index(node_index).unwrap();
+
+        let children = array::from_fn::<_, 16, _>(|n| {
+            node_access
+                .child_user_data(trie::Nibble::try_from(u8::try_from(n).unwrap()).unwrap())
+                .map(|child| child.1.as_ref().unwrap().clone())
+        });
+
+        let key_nibbles = node_access.full_key().map(u8::from).collect::<Vec<_>>();
+        let storage_value = node_access.user_data().0;
+
+        // Values of at least 33 bytes are hashed starting from version 1 of the trie.
+        let storage_value_hash = match storage_value {
+            Some((value, TrieEntryVersion::V1)) if value.len() >= 33 => Some(
+                <[u8; 32]>::try_from(blake2_rfc::blake2b::blake2b(32, &[], value).as_bytes())
+                    .unwrap_or_else(|_| unreachable!()),
+            ),
+            _ => None,
+        };
+
+        let merkle_value = trie::trie_node::calculate_merkle_value(
+            trie::trie_node::Decoded {
+                children,
+                partial_key: node_access.partial_key(),
+                storage_value: match (storage_value, storage_value_hash.as_ref()) {
+                    (_, Some(hash)) => trie::trie_node::StorageValue::Hashed(hash),
+                    (Some((value, _)), None) => trie::trie_node::StorageValue::Unhashed(value),
+                    (None, None) => trie::trie_node::StorageValue::None,
+                },
+            },
+            trie::HashFunction::Blake2,
+            node_access.is_root_node(),
+        )
+        .unwrap_or_else(|_| unreachable!());
+
+        nodes.push(ReferenceNode {
+            key_nibbles,
+            has_storage_value: storage_value.is_some(),
+            merkle_value: merkle_value.as_ref().to_vec(),
+        });
+        node_access.into_user_data().1 = Some(merkle_value);
+    }
+
+    nodes.reverse();
+    nodes
+}
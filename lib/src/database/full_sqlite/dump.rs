@@ -0,0 +1,301 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Exporting blocks to a flat file and importing them back.
+//!
+//! # Format
+//!
+//! The file starts with the 8 bytes `smoldotb`, followed with the version of the format and the
+//! number of bytes used to encode block numbers, both as 32 bits little endian numbers. The
+//! current version is 1.
+//!
+//! This header is followed with a list of blocks. Each block starts with a byte equal to 1,
+//! followed with the header (as a SCALE-encoded `Vec<u8>`), the body (as a SCALE-encoded
+//! `Vec<Vec<u8>>`), and the GrandPa justification (as a SCALE-encoded `Option<Vec<u8>>`). The
+//! list of blocks ends with a byte equal to 0.
+
+use super::{
+    import::{self, ExportDecodeError, ExportedBlock, ImportError, ImportReport},
+    prepare_cached, sqlite_err, CorruptedError, SqliteFullDatabase, GRANDPA_ENGINE_ID,
+};
+use crate::util;
+
+use alloc::vec::Vec;
+use core::{iter, ops::RangeInclusive};
+use std::io;
+
+/// Bytes found at the start of each export.
+const MAGIC: &[u8; 8] = b"smoldotb";
+
+/// Version of the format written by [`SqliteFullDatabase::export_blocks`].
+const FORMAT_VERSION: u32 = 1;
+
+impl SqliteFullDatabase {
+    /// Writes to `writer` the blocks of the best chain whose number is within the given range,
+    /// with their header, body, and GrandPa justification.
+    ///
+    /// The output can later be passed to [`SqliteFullDatabase::import_blocks`]. See the
+    /// documentation of this module for the format.
+    ///
+    /// Returns the number of blocks that have been written. Blocks of the range that aren't in
+    /// the database are silently ignored.
+    /// Returns [`ExportBlocksError::BodyPruned`] if the body of one of the blocks has been
+    /// removed from the database.
+    ///
+    /// > **Note**: One of the read-only connections (see [`super::Config::read_connections`]),
+    /// >           or the database if there is none, is locked while the export is in progress.
+    /// >           Consider passing a buffered writer.
+    pub fn export_blocks(
+        &self,
+        range: RangeInclusive<u64>,
+        mut writer: impl io::Write,
+    ) -> Result<u64, ExportBlocksError> {
+        writer.write_all(MAGIC).map_err(ExportBlocksError::Io)?;
+        writer
+            .write_all(&FORMAT_VERSION.to_le_bytes())
+            .map_err(ExportBlocksError::Io)?;
+        writer
+            .write_all(
+                &u32::try_from(self.block_number_bytes)
+                    .unwrap_or(u32::MAX)
+                    .to_le_bytes(),
+            )
+            .map_err(ExportBlocksError::Io)?;
+
+        let database = self.read_connection();
+
+        let mut blocks_statement = prepare_cached(
+            &database,
+            "SELECT hash, header, (SELECT justification FROM blocks_justifications WHERE blocks_justifications.hash = blocks.hash AND engine_id = ?), number, body_pruned FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND is_best_chain = TRUE AND number >= ? AND number <= ? ORDER BY number",
+        )
+        .map_err(sqlite_err("export_blocks"))?;
+        let mut body_statement = prepare_cached(
+            &database,
+            "SELECT extrinsics.bytes FROM blocks_body JOIN extrinsics ON extrinsics.hash = blocks_body.extrinsic_hash WHERE blocks_body.hash = ? ORDER BY idx",
+        )
+        .map_err(sqlite_err("export_blocks"))?;
+
+        let range_start = i64::try_from(*range.start()).unwrap_or(i64::MAX);
+        let range_end = i64::try_from(*range.end()).unwrap_or(i64::MAX);
+        let mut blocks = blocks_statement
+            .query((&GRANDPA_ENGINE_ID[..], range_start, range_end))
+            .map_err(sqlite_err("export_blocks"))?;
+
+        let mut num_blocks = 0;
+        while let Some(row) = blocks.next().map_err(sqlite_err("export_blocks"))? {
+            let hash = row
+                .get::<_, Vec<u8>>(0)
+                .map_err(sqlite_err("export_blocks"))?;
+            let header = row
+                .get::<_, Vec<u8>>(1)
+                .map_err(sqlite_err("export_blocks"))?;
+            let justification = row
+                .get::<_, Option<Vec<u8>>>(2)
+                .map_err(sqlite_err("export_blocks"))?;
+
+            if row.get::<_, bool>(4).map_err(sqlite_err("export_blocks"))? {
+                let number = row.get::<_, i64>(3).map_err(sqlite_err("export_blocks"))?;
+                return Err(ExportBlocksError::BodyPruned {
+                    number: u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?,
+                });
+            }
+
+            let body = body_statement
+                .query_map((&hash,), |row| row.get::<_, Vec<u8>>(0))
+                .map_err(sqlite_err("export_blocks"))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(sqlite_err("export_blocks"))?;
+
+            writer.write_all(&[1]).map_err(ExportBlocksError::Io)?;
+            write_bytes(&mut writer, &header)?;
+            writer
+                .write_all(util::encode_scale_compact_usize(body.len()).as_ref())
+                .map_err(ExportBlocksError::Io)?;
+            for extrinsic in &body {
+                write_bytes(&mut writer, extrinsic)?;
+            }
+            match justification {
+                Some(justification) => {
+                    writer.write_all(&[1]).map_err(ExportBlocksError::Io)?;
+                    write_bytes(&mut writer, &justification)?;
+                }
+                None => writer.write_all(&[0]).map_err(ExportBlocksError::Io)?,
+            }
+
+            num_blocks += 1;
+        }
+
+        writer.write_all(&[0]).map_err(ExportBlocksError::Io)?;
+        writer.flush().map_err(ExportBlocksError::Io)?;
+        Ok(num_blocks)
+    }
+
+    /// Inserts in the database the blocks written by [`SqliteFullDatabase::export_blocks`].
+    ///
+    /// The parent of the first block must already be in the database. Blocks that are already
+    /// in the database are skipped, which makes it possible to import the same file into a
+    /// partially-filled database, or to resume an interrupted import.
+    ///
+    /// Similar to [`import::import_from_substrate_export`], blocks are inserted in batches and
+    /// the latest inserted block becomes the new best block. The finalized block isn't
+    /// modified.
+    pub fn import_blocks(
+        &self,
+        mut reader: impl io::Read,
+    ) -> Result<ImportReport, ImportBlocksError> {
+        let mut magic = [0; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|err| ImportBlocksError::Decode(ExportDecodeError::Io(err)))?;
+        if magic != *MAGIC {
+            return Err(ImportBlocksError::InvalidMagic);
+        }
+
+        let mut version = [0; 4];
+        reader
+            .read_exact(&mut version)
+            .map_err(|err| ImportBlocksError::Decode(ExportDecodeError::Io(err)))?;
+        let version = u32::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(ImportBlocksError::UnsupportedVersion(version));
+        }
+
+        let mut block_number_bytes = [0; 4];
+        reader
+            .read_exact(&mut block_number_bytes)
+            .map_err(|err| ImportBlocksError::Decode(ExportDecodeError::Io(err)))?;
+        let block_number_bytes = u32::from_le_bytes(block_number_bytes);
+        if usize::try_from(block_number_bytes).ok() != Some(self.block_number_bytes) {
+            return Err(ImportBlocksError::BlockNumberBytesMismatch(
+                block_number_bytes,
+            ));
+        }
+
+        let mut finished = false;
+        let blocks = iter::from_fn(|| {
+            if finished {
+                return None;
+            }
+            match decode_block(&mut reader) {
+                Ok(Some(block)) => Some(Ok(block)),
+                Ok(None) => {
+                    finished = true;
+                    None
+                }
+                Err(err) => {
+                    finished = true;
+                    Some(Err(ImportBlocksError::Decode(err)))
+                }
+            }
+        });
+
+        import::insert_blocks(self, blocks, None)
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::export_blocks`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum ExportBlocksError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Error while writing the export.
+    #[display(fmt = "{_0}")]
+    Io(io::Error),
+    /// The body of one of the blocks to export has been removed from the database.
+    /// See [`SqliteFullDatabase::prune_bodies_below`].
+    #[display(fmt = "Body of block #{number} has been pruned")]
+    #[from(ignore)]
+    BodyPruned {
+        /// Number of the block whose body is missing.
+        number: u64,
+    },
+}
+
+/// Error while calling [`SqliteFullDatabase::import_blocks`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum ImportBlocksError {
+    /// Error while inserting the blocks in the database.
+    #[display(fmt = "{_0}")]
+    Import(ImportError),
+    /// Error while reading or decoding the export.
+    #[display(fmt = "{_0}")]
+    Decode(ExportDecodeError),
+    /// The data doesn't start with the expected bytes, and thus isn't an export.
+    #[from(ignore)]
+    InvalidMagic,
+    /// The export has been produced by a version of the format that isn't supported.
+    #[display(fmt = "Unsupported export format version: {_0}")]
+    #[from(ignore)]
+    UnsupportedVersion(u32),
+    /// The export uses a different number of bytes to encode block numbers than the database.
+    #[display(fmt = "Export uses {_0} bytes per block number")]
+    #[from(ignore)]
+    BlockNumberBytesMismatch(u32),
+}
+
+/// Writes `bytes` prefixed with its SCALE-compact length.
+fn write_bytes(writer: &mut impl io::Write, bytes: &[u8]) -> Result<(), ExportBlocksError> {
+    writer
+        .write_all(util::encode_scale_compact_usize(bytes.len()).as_ref())
+        .map_err(ExportBlocksError::Io)?;
+    writer.write_all(bytes).map_err(ExportBlocksError::Io)
+}
+
+/// Reads a SCALE-compact length followed with the bytes it indicates.
+fn read_bytes(reader: &mut impl io::Read) -> Result<Vec<u8>, ExportDecodeError> {
+    let len = import::read_compact(reader, &mut Vec::new())?;
+    let mut out = Vec::new();
+    import::read_bytes(reader, len, &mut out)?;
+    Ok(out)
+}
+
+/// Decodes the next block of an export. Returns `None` if the end of the list of blocks has
+/// been reached.
+fn decode_block(reader: &mut impl io::Read) -> Result<Option<ExportedBlock>, ExportDecodeError> {
+    let mut tag = [0];
+    reader.read_exact(&mut tag).map_err(ExportDecodeError::Io)?;
+    match tag[0] {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(ExportDecodeError::InvalidOption),
+    }
+
+    let scale_encoded_header = read_bytes(reader)?;
+
+    let num_extrinsics = import::read_compact(reader, &mut Vec::new())?;
+    let mut body = Vec::with_capacity(usize::try_from(num_extrinsics.min(1024)).unwrap());
+    for _ in 0..num_extrinsics {
+        body.push(read_bytes(reader)?);
+    }
+
+    let mut has_justification = [0];
+    reader
+        .read_exact(&mut has_justification)
+        .map_err(ExportDecodeError::Io)?;
+    let justifications = match has_justification[0] {
+        0 => Vec::new(),
+        1 => vec![(GRANDPA_ENGINE_ID, read_bytes(reader)?)],
+        _ => return Err(ExportDecodeError::InvalidOption),
+    };
+
+    Ok(Some(ExportedBlock {
+        scale_encoded_header,
+        body,
+        justifications,
+    }))
+}
@@ -0,0 +1,194 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Notifications about changes to the best and finalized blocks.
+//!
+//! See [`SqliteFullDatabase::subscribe`].
+
+use super::SqliteFullDatabase;
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crossbeam_queue::ArrayQueue;
+use parking_lot::Mutex;
+
+/// Maximum number of events that haven't been pulled yet by a [`DatabaseEventReceiver`]. When
+/// this limit is reached, the oldest event is discarded.
+const CHANNEL_CAPACITY: usize = 256;
+
+impl SqliteFullDatabase {
+    /// Returns a receiver of the [`Event`]s describing the changes to the best and finalized
+    /// blocks of the database.
+    ///
+    /// Events are emitted after the corresponding modification has been committed, meaning that
+    /// the modification is always visible when reading the database after having received the
+    /// event. If the [`super::CommitPolicy`] delays commits, the events are emitted at the next
+    /// commit, for example during [`SqliteFullDatabase::flush`]. The events of modifications
+    /// that are never committed are never emitted.
+    ///
+    /// Emitting an event never waits for the receiver. If the receiver doesn't pull events fast
+    /// enough and too many events are queued, the oldest ones are discarded. The number of
+    /// discarded events can be obtained with [`DatabaseEventReceiver::take_lagged`].
+    pub fn subscribe(&self) -> DatabaseEventReceiver {
+        self.subscribers.subscribe()
+    }
+}
+
+/// Change to the best or finalized block. See [`SqliteFullDatabase::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The best block of the database has changed, either by
+    /// [`SqliteFullDatabase::insert`], by [`SqliteFullDatabase::set_best_block`], by
+    /// [`SqliteFullDatabase::set_finalized`], or by [`SqliteFullDatabase::reset`].
+    NewBest {
+        /// Hash of the new best block.
+        hash: [u8; 32],
+        /// Number of the new best block.
+        number: u64,
+    },
+
+    /// The finalized block of the database has changed, either by
+    /// [`SqliteFullDatabase::set_finalized`] or by [`SqliteFullDatabase::reset`].
+    Finalized {
+        /// Hash of the new finalized block.
+        hash: [u8; 32],
+        /// Number of the new finalized block.
+        number: u64,
+        /// Blocks whose number is superior to the one of the previous finalized block and
+        /// inferior or equal to the one of the new finalized block, but that aren't ancestors of
+        /// the new finalized block. These blocks can never be finalized anymore, and are removed
+        /// by [`SqliteFullDatabase::purge_finality_orphans`].
+        ///
+        /// Always empty after [`SqliteFullDatabase::reset`].
+        retracted: Vec<[u8; 32]>,
+    },
+}
+
+/// Receives the events emitted by a [`SqliteFullDatabase`]. See
+/// [`SqliteFullDatabase::subscribe`].
+pub struct DatabaseEventReceiver {
+    channel: Arc<Channel>,
+}
+
+impl DatabaseEventReceiver {
+    /// Returns the oldest event that hasn't been pulled yet, or `None` if there isn't any.
+    pub fn try_next(&mut self) -> Option<Event> {
+        self.channel.queue.pop()
+    }
+
+    /// Waits until an event is available and returns it.
+    ///
+    /// Returns `None` if the [`SqliteFullDatabase`] has been destroyed and all the events have
+    /// been pulled.
+    pub async fn next(&mut self) -> Option<Event> {
+        let mut wait = None;
+        loop {
+            if let Some(event) = self.channel.queue.pop() {
+                return Some(event);
+            }
+
+            if self.channel.closed.load(Ordering::Acquire) {
+                // Events might have been pushed between the moment when the queue was checked
+                // and the moment when the database has been destroyed.
+                return self.channel.queue.pop();
+            }
+
+            if let Some(wait) = wait.take() {
+                wait.await;
+            } else {
+                wait = Some(self.channel.on_event.listen());
+            }
+        }
+    }
+
+    /// Returns the number of events that have been discarded because they weren't pulled fast
+    /// enough, and resets this number to zero.
+    pub fn take_lagged(&mut self) -> u64 {
+        self.channel.lagged.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// List of [`DatabaseEventReceiver`]s of a [`SqliteFullDatabase`].
+pub(super) struct Subscribers {
+    list: Mutex<Vec<Weak<Channel>>>,
+}
+
+impl Subscribers {
+    pub(super) fn new() -> Self {
+        Subscribers {
+            list: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn subscribe(&self) -> DatabaseEventReceiver {
+        let channel = Arc::new(Channel {
+            queue: ArrayQueue::new(CHANNEL_CAPACITY),
+            lagged: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            on_event: event_listener::Event::new(),
+        });
+
+        self.list.lock().push(Arc::downgrade(&channel));
+        DatabaseEventReceiver { channel }
+    }
+
+    /// Sends the given event to all the receivers.
+    ///
+    /// Must be called after the modification that the event describes has been committed and
+    /// the database has been unlocked.
+    pub(super) fn emit(&self, event: Event) {
+        let mut list = self.list.lock();
+        list.retain(|channel| {
+            let Some(channel) = channel.upgrade() else {
+                return false;
+            };
+
+            if channel.queue.force_push(event.clone()).is_some() {
+                channel.lagged.fetch_add(1, Ordering::Relaxed);
+            }
+            channel.on_event.notify(usize::MAX);
+            true
+        });
+    }
+}
+
+impl Drop for Subscribers {
+    fn drop(&mut self) {
+        for channel in self.list.get_mut().drain(..) {
+            if let Some(channel) = channel.upgrade() {
+                channel.closed.store(true, Ordering::Release);
+                channel.on_event.notify(usize::MAX);
+            }
+        }
+    }
+}
+
+/// State shared between the [`SqliteFullDatabase`] and one [`DatabaseEventReceiver`].
+struct Channel {
+    /// Events that haven't been pulled yet.
+    queue: ArrayQueue<Event>,
+    /// Number of events discarded since the last call to [`DatabaseEventReceiver::take_lagged`].
+    lagged: AtomicU64,
+    /// `true` if the [`SqliteFullDatabase`] has been destroyed.
+    closed: AtomicBool,
+    /// Notified when an event is pushed to [`Channel::queue`] or when [`Channel::closed`] is set
+    /// to `true`.
+    on_event: event_listener::Event,
+}
@@ -0,0 +1,489 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Pre-populated databases for the purpose of testing.
+//!
+//! [`FixtureChain`] builds an in-memory database containing a chain of blocks, optionally
+//! forks, and the storage of these blocks, without having to manually craft headers and trie
+//! nodes. The hashes of the blocks and the content of the storage are returned alongside with
+//! the database, so that tests can compare them with what the database reports.
+
+#![cfg(test)]
+
+use super::{
+    open, CommitPolicy, Config, ConfigTy, DatabaseOpen, InsertTrieNode, InsertTrieNodeStorageValue,
+    SqliteFullDatabase,
+};
+use crate::{
+    chain::chain_information,
+    header,
+    trie::{self, TrieEntryVersion},
+};
+
+use alloc::borrow::Cow;
+use core::{array, iter, time::Duration};
+use std::collections::HashMap;
+
+/// Prefix of the keys of the main trie whose value is the root of a child trie.
+const CHILD_STORAGE_PREFIX: &[u8] = b":child_storage:default:";
+
+/// Returns the configuration of an in-memory database used by the tests. Tests that need a
+/// different configuration override the relevant fields with the struct update syntax.
+pub(super) fn memory_config(block_number_bytes: usize) -> Config<'static> {
+    Config {
+        block_number_bytes,
+        chain_name: None,
+        cache_size: 2 * 1024 * 1024,
+        statement_cache_capacity: 128,
+        busy_timeout: Duration::from_secs(5),
+        read_connections: 0,
+        commit_policy: CommitPolicy::EveryOperation,
+        body_retention: None,
+        storage_diff_retention: None,
+        store_events: false,
+        on_operation: None,
+        auto_optimize_interval: None,
+        value_compression: None,
+        allow_shared_readers: false,
+        progress: None,
+        ty: ConfigTy::Memory,
+    }
+}
+
+/// Builder for a database containing a chain of blocks.
+///
+/// All the blocks of the database, including the forks, have the same storage.
+pub(super) struct FixtureChain {
+    block_number_bytes: usize,
+    num_blocks: u64,
+    forks_at: Vec<u64>,
+    storage: Vec<(Vec<u8>, Vec<u8>)>,
+    child_tries: Vec<(Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>)>,
+}
+
+impl FixtureChain {
+    /// Starts building a chain only containing a genesis block with an empty storage.
+    pub(super) fn new(block_number_bytes: usize) -> Self {
+        FixtureChain {
+            block_number_bytes,
+            num_blocks: 0,
+            forks_at: Vec::new(),
+            storage: Vec::new(),
+            child_tries: Vec::new(),
+        }
+    }
+
+    /// Sets the number of blocks of the canonical chain after the genesis block. The last
+    /// block is the best block.
+    pub(super) fn with_blocks(mut self, num_blocks: u64) -> Self {
+        self.num_blocks = num_blocks;
+        self
+    }
+
+    /// Adds a fork whose first block is a child of the canonical block with the given number.
+    /// The fork ends at the same height as the canonical chain.
+    ///
+    /// Must be called multiple times in order to add multiple forks, including at the same
+    /// height.
+    pub(super) fn with_fork_at(mut self, height: u64) -> Self {
+        self.forks_at.push(height);
+        self
+    }
+
+    /// Adds entries to the main trie of the storage.
+    pub(super) fn with_storage(
+        mut self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Self {
+        self.storage.extend(entries);
+        self
+    }
+
+    /// Adds a child trie to the storage. Its root is found in the main trie at the key
+    /// `:child_storage:default:` followed with `child_trie`.
+    pub(super) fn with_child_trie(
+        mut self,
+        child_trie: &[u8],
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Self {
+        self.child_tries
+            .push((child_trie.to_vec(), entries.into_iter().collect()));
+        self
+    }
+
+    /// Builds the database.
+    ///
+    /// # Panic
+    ///
+    /// Panics if a fork has been added at a height superior or equal to the number of blocks.
+    ///
+    pub(super) fn build(self) -> Fixture {
+        let mut trie_nodes = Vec::new();
+        let mut main_trie = self.storage;
+        let mut child_tries = HashMap::with_capacity(self.child_tries.len());
+
+        for (child_trie, entries) in self.child_tries {
+            let mut structure = build_trie_structure(&entries, |_| false, TrieEntryVersion::V1);
+            let root = trie_structure_root(&structure);
+            child_tries.insert(
+                child_trie.clone(),
+                trie_structure_merkle_values(&mut structure),
+            );
+            trie_nodes.extend(trie_structure_nodes(&mut structure));
+            main_trie.push((
+                CHILD_STORAGE_PREFIX
+                    .iter()
+                    .copied()
+                    .chain(child_trie)
+                    .collect(),
+                root.to_vec(),
+            ));
+        }
+
+        let mut structure = build_trie_structure(
+            &main_trie,
+            |key| key.starts_with(CHILD_STORAGE_PREFIX),
+            TrieEntryVersion::V1,
+        );
+        let state_root = trie_structure_root(&structure);
+        let main_trie_merkle_values = trie_structure_merkle_values(&mut structure);
+        trie_nodes.extend(trie_structure_nodes(&mut structure));
+        main_trie.sort();
+
+        let DatabaseOpen::Empty(empty_db) = open(memory_config(self.block_number_bytes)).unwrap()
+        else {
+            panic!()
+        };
+
+        let database = empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &state_root,
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap();
+        database
+            .insert_trie_nodes_versioned(trie_nodes.into_iter(), TrieEntryVersion::V1)
+            .unwrap();
+
+        let mut parents = HashMap::new();
+
+        // Blocks are made different from each other through their extrinsics root.
+        let mut insert_chain =
+            |parent_hash: [u8; 32], parent_number: u64, extrinsics_root: u8, is_new_best: bool| {
+                let mut chain = Vec::new();
+                let mut parent_hash = parent_hash;
+                for number in parent_number + 1..=self.num_blocks {
+                    let block = header::HeaderRef {
+                        parent_hash: &parent_hash,
+                        number,
+                        state_root: &state_root,
+                        extrinsics_root: &[extrinsics_root; 32],
+                        digest: header::DigestRef::empty(),
+                    }
+                    .scale_encoding_vec(self.block_number_bytes);
+                    database
+                        .insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, None)
+                        .unwrap();
+                    let hash = header::hash_from_scale_encoded_header(&block);
+                    parents.insert(hash, parent_hash);
+                    parent_hash = hash;
+                    chain.push(hash);
+                }
+                chain
+            };
+
+        let genesis_hash = database.finalized_block_hash().unwrap();
+        let canonical = iter::once(genesis_hash)
+            .chain(insert_chain(genesis_hash, 0, 0, true))
+            .collect::<Vec<_>>();
+
+        let forks = self
+            .forks_at
+            .iter()
+            .enumerate()
+            .map(|(fork_index, height)| {
+                assert!(*height < self.num_blocks);
+                insert_chain(
+                    canonical[usize::try_from(*height).unwrap()],
+                    *height,
+                    u8::try_from(fork_index + 1).unwrap(),
+                    false,
+                )
+            })
+            .collect();
+
+        Fixture {
+            database,
+            canonical,
+            forks,
+            parents,
+            state_root,
+            main_trie,
+            main_trie_merkle_values,
+            child_tries,
+        }
+    }
+}
+
+/// Database built by [`FixtureChain::build`], alongside with the values expected to be found
+/// in it.
+pub(super) struct Fixture {
+    /// The database. Its finalized block is the genesis block, and its best block is the last
+    /// block of [`Fixture::canonical`].
+    pub(super) database: SqliteFullDatabase,
+    /// Hashes of the blocks of the canonical chain, indexed by block number.
+    pub(super) canonical: Vec<[u8; 32]>,
+    /// Hashes of the blocks of each fork, in the order in which they have been added with
+    /// [`FixtureChain::with_fork_at`]. The first block of each fork has a number equal to the
+    /// height passed to [`FixtureChain::with_fork_at`] plus one.
+    pub(super) forks: Vec<Vec<[u8; 32]>>,
+    /// Parent of each block of the database except for the genesis block.
+    pub(super) parents: HashMap<[u8; 32], [u8; 32]>,
+    /// Root of the main trie of the storage, shared by all blocks.
+    pub(super) state_root: [u8; 32],
+    /// Entries of the main trie of the storage, ordered by key. Includes the entries that
+    /// reference child tries.
+    pub(super) main_trie: Vec<(Vec<u8>, Vec<u8>)>,
+    main_trie_merkle_values: Vec<(Vec<u8>, Vec<u8>)>,
+    child_tries: HashMap<Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl Fixture {
+    /// Returns the key of the main trie whose value is the root of the given child trie, as
+    /// nibbles.
+    pub(super) fn child_trie_path(child_trie: &[u8]) -> Vec<u8> {
+        trie::bytes_to_nibbles(CHILD_STORAGE_PREFIX.iter().chain(child_trie).copied())
+            .map(u8::from)
+            .collect()
+    }
+
+    /// Returns the hashes of the given block and of all its ancestors, from the given block to
+    /// the genesis block.
+    pub(super) fn ancestry(&self, block_hash: &[u8; 32]) -> Vec<[u8; 32]> {
+        let mut ancestry = vec![*block_hash];
+        while let Some(parent) = self.parents.get(ancestry.last().unwrap()) {
+            ancestry.push(*parent);
+        }
+        ancestry
+    }
+
+    /// Returns the full key, as nibbles, of every node of the main trie or of the given child
+    /// trie.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the child trie hasn't been added with [`FixtureChain::with_child_trie`].
+    ///
+    pub(super) fn trie_nodes_keys(
+        &self,
+        child_trie: Option<&[u8]>,
+    ) -> impl Iterator<Item = &[u8]> + '_ {
+        self.merkle_values(child_trie)
+            .iter()
+            .map(|(key, _)| &key[..])
+    }
+
+    /// Returns the Merkle value of the node of the main trie or of the given child trie that
+    /// is the closest descendant of the given key, or `None` if there isn't any.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the child trie hasn't been added with [`FixtureChain::with_child_trie`].
+    ///
+    pub(super) fn closest_descendant_merkle_value(
+        &self,
+        child_trie: Option<&[u8]>,
+        key_nibbles: &[u8],
+    ) -> Option<Vec<u8>> {
+        self.merkle_values(child_trie)
+            .iter()
+            .filter(|(key, _)| key.starts_with(key_nibbles))
+            .min_by_key(|(key, _)| key.len())
+            .map(|(_, merkle_value)| merkle_value.clone())
+    }
+
+    fn merkle_values(&self, child_trie: Option<&[u8]>) -> &[(Vec<u8>, Vec<u8>)] {
+        match child_trie {
+            None => &self.main_trie_merkle_values,
+            Some(child_trie) => &self.child_tries[child_trie],
+        }
+    }
+}
+
+/// Trie whose nodes hold their storage value, if any, and their Merkle value.
+type TrieStructure = trie::trie_structure::TrieStructure<(
+    Option<(Vec<u8>, bool)>,
+    Option<trie::trie_node::MerkleValueOutput>,
+)>;
+
+/// Builds the trie containing the given entries, and returns its root and the list of its nodes
+/// ready to be inserted in the database.
+///
+/// `references_merkle_value` indicates whether the value of an entry is the root of a child trie.
+///
+/// The values are never hashed, as in version 0 of the trie.
+pub(super) fn build_trie(
+    entries: &[(Vec<u8>, Vec<u8>)],
+    references_merkle_value: impl Fn(&[u8]) -> bool,
+) -> ([u8; 32], Vec<InsertTrieNode<'static>>) {
+    let mut trie = build_trie_structure(entries, references_merkle_value, TrieEntryVersion::V0);
+    (trie_structure_root(&trie), trie_structure_nodes(&mut trie))
+}
+
+/// Builds the trie containing the given entries and calculates the Merkle values of its nodes.
+///
+/// If `version` is [`TrieEntryVersion::V1`], values of at least 33 bytes are hashed.
+fn build_trie_structure(
+    entries: &[(Vec<u8>, Vec<u8>)],
+    references_merkle_value: impl Fn(&[u8]) -> bool,
+    version: TrieEntryVersion,
+) -> TrieStructure {
+    let mut trie = TrieStructure::new();
+
+    for (key, value) in entries {
+        match trie.node(trie::bytes_to_nibbles(key.iter().copied())) {
+            trie::trie_structure::Entry::Vacant(e) => {
+                e.insert_storage_value().insert(
+                    (Some((value.clone(), references_merkle_value(key))), None),
+                    (None, None),
+                );
+            }
+            trie::trie_structure::Entry::Occupied(trie::trie_structure::NodeAccess::Branch(
+                mut e,
+            )) => {
+                *e.user_data() = (Some((value.clone(), references_merkle_value(key))), None);
+                e.insert_storage_value();
+            }
+            trie::trie_structure::Entry::Occupied(trie::trie_structure::NodeAccess::Storage(_)) => {
+                unreachable!()
+            }
+        }
+    }
+
+    // Calculate the Merkle values of the nodes of the trie.
+    for node_index in trie.iter_ordered().collect::<Vec<_>>().into_iter().rev() {
+        let mut node_access = trie.node_by_index(node_index).unwrap();
+
+        let children = array::from_fn::<_, 16, _>(|n| {
+            node_access
+                .child(trie::Nibble::try_from(u8::try_from(n).unwrap()).unwrap())
+                .map(|mut child| child.user_data().1.as_ref().unwrap().clone())
+        });
+
+        let is_root_node = node_access.is_root_node();
+        let partial_key = node_access.partial_key().collect::<Vec<_>>().into_iter();
+
+        let storage_value_hash = match node_access.user_data().0.as_ref() {
+            Some((v, _)) if version == TrieEntryVersion::V1 && v.len() >= 33 => Some(
+                <[u8; 32]>::try_from(blake2_rfc::blake2b::blake2b(32, &[], v).as_bytes()).unwrap(),
+            ),
+            _ => None,
+        };
+        let storage_value = match (node_access.user_data().0.as_ref(), &storage_value_hash) {
+            (_, Some(hash)) => trie::trie_node::StorageValue::Hashed(hash),
+            (Some((v, _)), None) => trie::trie_node::StorageValue::Unhashed(&v[..]),
+            (None, None) => trie::trie_node::StorageValue::None,
+        };
+
+        let merkle_value = trie::trie_node::calculate_merkle_value(
+            trie::trie_node::Decoded {
+                children,
+                partial_key,
+                storage_value,
+            },
+            trie::HashFunction::Blake2,
+            is_root_node,
+        )
+        .unwrap();
+
+        node_access.into_user_data().1 = Some(merkle_value);
+    }
+
+    trie
+}
+
+fn trie_structure_root(trie: &TrieStructure) -> [u8; 32] {
+    trie.root_user_data()
+        .map(|n| *<&[u8; 32]>::try_from(n.1.as_ref().unwrap().as_ref()).unwrap())
+        .unwrap_or(trie::EMPTY_BLAKE2_TRIE_MERKLE_VALUE)
+}
+
+/// Returns the full key, as nibbles, and the Merkle value of every node of the trie.
+fn trie_structure_merkle_values(trie: &mut TrieStructure) -> Vec<(Vec<u8>, Vec<u8>)> {
+    trie.iter_ordered()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|node_index| {
+            let mut node_access = trie.node_by_index(node_index).unwrap();
+            let key = node_access.full_key().map(u8::from).collect::<Vec<_>>();
+            let merkle_value = node_access
+                .user_data()
+                .1
+                .as_ref()
+                .unwrap()
+                .as_ref()
+                .to_vec();
+            (key, merkle_value)
+        })
+        .collect()
+}
+
+fn trie_structure_nodes(trie: &mut TrieStructure) -> Vec<InsertTrieNode<'static>> {
+    trie.iter_unordered()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|node_index| {
+            let (storage_value, Some(merkle_value)) = &trie[node_index] else {
+                unreachable!()
+            };
+            let storage_value = match storage_value {
+                Some((value, references_merkle_value)) => InsertTrieNodeStorageValue::Value {
+                    value: Cow::Owned(value.clone()),
+                    references_merkle_value: *references_merkle_value,
+                },
+                None => InsertTrieNodeStorageValue::NoValue,
+            };
+            let merkle_value = merkle_value.as_ref().to_owned();
+            let mut node_access = trie.node_by_index(node_index).unwrap();
+
+            InsertTrieNode {
+                storage_value,
+                merkle_value: Cow::Owned(merkle_value),
+                children_merkle_values: array::from_fn::<_, 16, _>(|n| {
+                    let child_index = trie::Nibble::try_from(u8::try_from(n).unwrap()).unwrap();
+                    node_access.child(child_index).map(|mut child| {
+                        Cow::Owned(child.user_data().1.as_ref().unwrap().as_ref().to_vec())
+                    })
+                }),
+                partial_key_nibbles: Cow::Owned(
+                    node_access.partial_key().map(u8::from).collect::<Vec<_>>(),
+                ),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,469 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Importing blocks from a Substrate node.
+//!
+//! Substrate nodes can export their chain using `substrate export-blocks --binary`. The output
+//! consists of the number of blocks, as a 64 bits little endian number, followed with the SCALE
+//! encoding of each block (header, body, and justifications) one after the other.
+//!
+//! Use [`SubstrateExportReader`] in order to decode such an export, then
+//! [`import_from_substrate_export`] in order to insert the blocks in the database. Only headers,
+//! bodies and justifications are imported. The storage of the blocks must be downloaded
+//! separately, for example through warp syncing.
+//!
+//! Importing a long chain can take hours. The number of the latest imported block is stored in
+//! the database after each batch of blocks (see [`import_cursor`]), and blocks whose number is
+//! inferior or equal to this number are skipped. An interrupted import can thus be resumed by
+//! simply importing the same export again.
+
+use super::{
+    finalized_num, has_block, insert_body_extrinsic, meta_get_number, meta_set_number,
+    prepare_cached, set_best_chain, set_block_justification, sqlite_err, CorruptedError,
+    SqliteFullDatabase,
+};
+use crate::header;
+
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
+use rusqlite::OptionalExtension as _;
+use std::io::{self, Read as _};
+
+/// Key in the `meta` table containing the number of the latest block inserted by
+/// [`import_from_substrate_export`].
+const IMPORT_CURSOR_KEY: &str = "substrate_import_cursor";
+
+/// Maximum number of blocks inserted within a single transaction.
+const BLOCKS_PER_TRANSACTION: usize = 4096;
+
+/// Block found in an export produced by a Substrate node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedBlock {
+    /// SCALE encoding of the header of the block.
+    pub scale_encoded_header: Vec<u8>,
+    /// List of SCALE-encoded extrinsics of the block.
+    pub body: Vec<Vec<u8>>,
+    /// List of justifications of the block, with the consensus engine id they belong to.
+    pub justifications: Vec<([u8; 4], Vec<u8>)>,
+}
+
+/// Decodes the output of `substrate export-blocks --binary`.
+///
+/// Implements the [`Iterator`] trait and yields the blocks of the export in order.
+pub struct SubstrateExportReader<R> {
+    reader: R,
+    /// Number of blocks that remain to be decoded.
+    remaining_blocks: u64,
+}
+
+impl<R: io::Read> SubstrateExportReader<R> {
+    /// Initializes a new reader. Reads the number of blocks found at the start of the export.
+    pub fn new(mut reader: R) -> Result<Self, ExportDecodeError> {
+        let mut num_blocks = [0; 8];
+        reader
+            .read_exact(&mut num_blocks)
+            .map_err(ExportDecodeError::Io)?;
+
+        Ok(SubstrateExportReader {
+            reader,
+            remaining_blocks: u64::from_le_bytes(num_blocks),
+        })
+    }
+
+    /// Returns the number of blocks that remain to be decoded, as indicated at the start of the
+    /// export.
+    pub fn remaining_blocks(&self) -> u64 {
+        self.remaining_blocks
+    }
+
+    fn decode_block(&mut self) -> Result<ExportedBlock, ExportDecodeError> {
+        // The header is copied as-is, and is decoded later by the importer. We only need to
+        // know where it ends.
+        let mut scale_encoded_header = Vec::with_capacity(256);
+        read_bytes(&mut self.reader, 32, &mut scale_encoded_header)?;
+        read_compact(&mut self.reader, &mut scale_encoded_header)?;
+        read_bytes(&mut self.reader, 64, &mut scale_encoded_header)?;
+        let num_digest_items = read_compact(&mut self.reader, &mut scale_encoded_header)?;
+        for _ in 0..num_digest_items {
+            let mut ty = [0];
+            self.reader
+                .read_exact(&mut ty)
+                .map_err(ExportDecodeError::Io)?;
+            scale_encoded_header.push(ty[0]);
+            match ty[0] {
+                // Consensus, seal, pre-runtime.
+                4..=6 => {
+                    read_bytes(&mut self.reader, 4, &mut scale_encoded_header)?;
+                    let len = read_compact(&mut self.reader, &mut scale_encoded_header)?;
+                    read_bytes(&mut self.reader, len, &mut scale_encoded_header)?;
+                }
+                // Other.
+                0 => {
+                    let len = read_compact(&mut self.reader, &mut scale_encoded_header)?;
+                    read_bytes(&mut self.reader, len, &mut scale_encoded_header)?;
+                }
+                // Runtime environment updated.
+                8 => {}
+                ty => return Err(ExportDecodeError::UnknownDigestItem(ty)),
+            }
+        }
+
+        // Each extrinsic is itself prefixed with its length, which is considered as part of
+        // the extrinsic.
+        let num_extrinsics = read_compact(&mut self.reader, &mut Vec::new())?;
+        let mut body = Vec::with_capacity(usize::try_from(num_extrinsics.min(1024)).unwrap());
+        for _ in 0..num_extrinsics {
+            let mut extrinsic = Vec::new();
+            let len = read_compact(&mut self.reader, &mut extrinsic)?;
+            read_bytes(&mut self.reader, len, &mut extrinsic)?;
+            body.push(extrinsic);
+        }
+
+        // Justifications are an `Option<Vec<(ConsensusEngineId, Vec<u8>)>>`.
+        let mut justifications = Vec::new();
+        let mut has_justifications = [0];
+        self.reader
+            .read_exact(&mut has_justifications)
+            .map_err(ExportDecodeError::Io)?;
+        match has_justifications[0] {
+            0 => {}
+            1 => {
+                let num_justifications = read_compact(&mut self.reader, &mut Vec::new())?;
+                for _ in 0..num_justifications {
+                    let mut engine_id = [0; 4];
+                    self.reader
+                        .read_exact(&mut engine_id)
+                        .map_err(ExportDecodeError::Io)?;
+                    let len = read_compact(&mut self.reader, &mut Vec::new())?;
+                    let mut justification = Vec::new();
+                    read_bytes(&mut self.reader, len, &mut justification)?;
+                    justifications.push((engine_id, justification));
+                }
+            }
+            _ => return Err(ExportDecodeError::InvalidOption),
+        }
+
+        Ok(ExportedBlock {
+            scale_encoded_header,
+            body,
+            justifications,
+        })
+    }
+}
+
+impl<R: io::Read> Iterator for SubstrateExportReader<R> {
+    type Item = Result<ExportedBlock, ExportDecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining_blocks == 0 {
+            return None;
+        }
+
+        let block = self.decode_block();
+        // Decoding errors aren't recoverable, as we don't know where the next block starts.
+        self.remaining_blocks = match block {
+            Ok(_) => self.remaining_blocks - 1,
+            Err(_) => 0,
+        };
+        Some(block)
+    }
+}
+
+/// Error while decoding an export with a [`SubstrateExportReader`].
+#[derive(Debug, derive_more::Display)]
+pub enum ExportDecodeError {
+    /// Error while reading the export.
+    #[display(fmt = "{_0}")]
+    Io(io::Error),
+    /// Found a SCALE-compact number that doesn't fit in 64 bits.
+    CompactOverflow,
+    /// Unknown type of item in the digest of a header.
+    #[display(fmt = "Unknown digest item type: {_0}")]
+    UnknownDigestItem(u8),
+    /// The justifications of a block are neither present nor absent.
+    InvalidOption,
+}
+
+/// Inserts in the database the blocks produced by a [`SubstrateExportReader`].
+///
+/// Blocks must be ordered by increasing number, and the parent of each block must be either in
+/// the database or earlier in the iterator. Blocks that are already in the database, or whose
+/// number is inferior or equal to the [`import_cursor`], are skipped.
+///
+/// Blocks are inserted in batches, each within its own transaction, and the latest inserted
+/// block becomes the new best block after each batch. If an error happens, the blocks of the
+/// batch being inserted are discarded, but the blocks of the previous batches remain in the
+/// database.
+///
+/// The justifications of all consensus engines (if any) are stored alongside the blocks. See
+/// [`SqliteFullDatabase::block_justifications`]. The finalized block
+/// isn't modified. Use [`SqliteFullDatabase::set_finalized`] after the import in order to
+/// advance it.
+///
+/// > **Note**: The database is locked while `blocks` is being iterated.
+pub fn import_from_substrate_export(
+    db: &SqliteFullDatabase,
+    blocks: impl Iterator<Item = ExportedBlock>,
+) -> Result<ImportReport, ImportError> {
+    insert_blocks(db, blocks.map(Ok), Some(IMPORT_CURSOR_KEY))
+}
+
+/// Inserts the given blocks in the database, in batches.
+///
+/// See [`import_from_substrate_export`]. If `cursor_key` is `Some`, the number of the latest
+/// inserted block is stored in the `meta` table under this key, and blocks whose number is
+/// inferior or equal to it are skipped.
+pub(super) fn insert_blocks<E: From<ImportError>>(
+    db: &SqliteFullDatabase,
+    blocks: impl Iterator<Item = Result<ExportedBlock, E>>,
+    cursor_key: Option<&str>,
+) -> Result<ImportReport, E> {
+    let mut blocks = blocks.fuse();
+    let mut report = ImportReport {
+        imported_blocks: 0,
+        skipped_blocks: 0,
+        last_imported_number: None,
+    };
+
+    loop {
+        let mut database = db.lock_write().map_err(ImportError::Corrupted)?;
+        let transaction = database
+            .savepoint()
+            .map_err(|err| ImportError::Corrupted(sqlite_err("insert_blocks")(err)))?;
+
+        let cursor = match cursor_key {
+            Some(key) => meta_get_number(&transaction, key).map_err(ImportError::Corrupted)?,
+            None => None,
+        };
+
+        let mut last_inserted = None;
+        let mut num_inserted = 0;
+        let mut finished = true;
+
+        for block in blocks.by_ref() {
+            let block = block?;
+            match insert_block(&transaction, db.block_number_bytes, &block, cursor)? {
+                Some(inserted) => last_inserted = Some(inserted),
+                None => {
+                    report.skipped_blocks += 1;
+                    continue;
+                }
+            }
+
+            num_inserted += 1;
+            if num_inserted == BLOCKS_PER_TRANSACTION {
+                finished = false;
+                break;
+            }
+        }
+
+        if let Some((block_hash, number)) = last_inserted {
+            if number > finalized_num(&transaction).map_err(ImportError::Corrupted)? {
+                set_best_chain(&transaction, &block_hash).map_err(ImportError::Corrupted)?;
+            }
+            if let Some(key) = cursor_key {
+                meta_set_number(&transaction, key, number).map_err(ImportError::Corrupted)?;
+            }
+
+            transaction
+                .commit()
+                .map_err(|err| ImportError::Corrupted(sqlite_err("insert_blocks")(err)))?;
+            database
+                .finish(num_inserted as u64)
+                .map_err(ImportError::Corrupted)?;
+
+            report.imported_blocks += num_inserted as u64;
+            report.last_imported_number = Some(number);
+        }
+
+        if finished {
+            if report.imported_blocks != 0 {
+                // The statistics of the query planner are most likely outdated.
+                db.analyze_pending.store(true, Ordering::Relaxed);
+            }
+            return Ok(report);
+        }
+    }
+}
+
+/// Inserts a single block within the given transaction.
+///
+/// Returns the hash and number of the block, or `None` if the block has been skipped because it
+/// is already in the database or not above `cursor`.
+fn insert_block(
+    transaction: &rusqlite::Connection,
+    block_number_bytes: usize,
+    block: &ExportedBlock,
+    cursor: Option<u64>,
+) -> Result<Option<([u8; 32], u64)>, ImportError> {
+    let header = header::decode(&block.scale_encoded_header, block_number_bytes)
+        .map_err(ImportError::BadHeader)?;
+    let block_hash = header::hash_from_scale_encoded_header(&block.scale_encoded_header);
+
+    if cursor.is_some_and(|cursor| header.number <= cursor) || has_block(transaction, &block_hash)?
+    {
+        return Ok(None);
+    }
+
+    let parent_number = prepare_cached(
+        transaction,
+        "SELECT number FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)",
+    )
+    .map_err(sqlite_err("insert_block"))?
+    .query_row((&header.parent_hash[..],), |row| row.get::<_, i64>(0))
+    .optional()
+    .map_err(sqlite_err("insert_block"))?
+    .ok_or(ImportError::MissingParent {
+        number: header.number,
+    })?;
+    let number = match i64::try_from(header.number) {
+        Ok(number) if parent_number.checked_add(1) == Some(number) => number,
+        _ => {
+            return Err(ImportError::NumberMismatch {
+                number: header.number,
+            })
+        }
+    };
+
+    prepare_cached(
+        transaction,
+        "INSERT INTO blocks(chain_id, number, hash, parent_hash, state_trie_root_hash, header, is_best_chain) VALUES ((SELECT id FROM current_chain), ?, ?, ?, ?, ?, FALSE)",
+    )
+    .map_err(sqlite_err("insert_block"))?
+    .execute((
+        number,
+        &block_hash[..],
+        &header.parent_hash[..],
+        &header.state_root[..],
+        &block.scale_encoded_header[..],
+    ))
+    .map_err(sqlite_err("insert_block"))?;
+
+    for (engine_id, justification) in &block.justifications {
+        set_block_justification(transaction, &block_hash, engine_id, Some(justification))?;
+    }
+
+    for (index, extrinsic) in block.body.iter().enumerate() {
+        insert_body_extrinsic(
+            transaction,
+            &block_hash,
+            i64::try_from(index).map_err(|_| CorruptedError::InvalidNumber)?,
+            extrinsic,
+        )
+        .map_err(sqlite_err("insert_block"))?;
+    }
+
+    Ok(Some((block_hash, header.number)))
+}
+
+/// Returns the number of the latest block inserted by [`import_from_substrate_export`], or
+/// `None` if no import has ever happened.
+///
+/// This can be used in order to know from which block number to resume an interrupted import.
+pub fn import_cursor(db: &SqliteFullDatabase) -> Result<Option<u64>, CorruptedError> {
+    let database = db.read_connection();
+    meta_get_number(&database, IMPORT_CURSOR_KEY)
+}
+
+/// Outcome of a successful [`import_from_substrate_export`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Number of blocks that have been inserted in the database.
+    pub imported_blocks: u64,
+    /// Number of blocks that were already in the database or before the [`import_cursor`].
+    pub skipped_blocks: u64,
+    /// Number of the latest block that has been inserted, if any.
+    pub last_imported_number: Option<u64>,
+}
+
+/// Error while calling [`import_from_substrate_export`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum ImportError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Error when decoding the header of a block to import.
+    #[display(fmt = "Failed to decode header: {_0}")]
+    BadHeader(header::Error),
+    /// Parent of the block to import is neither in the database nor earlier in the export.
+    #[display(fmt = "Parent of block #{number} is missing")]
+    #[from(ignore)]
+    MissingParent {
+        /// Number of the block whose parent is missing.
+        number: u64,
+    },
+    /// Number of the block to import isn't equal to the number of its parent plus one.
+    #[display(fmt = "Block #{number} doesn't directly follow its parent")]
+    #[from(ignore)]
+    NumberMismatch {
+        /// Number of the block whose number is invalid.
+        number: u64,
+    },
+}
+
+/// Reads a SCALE-compact number from `reader`, and appends its encoding to `out`.
+pub(super) fn read_compact(
+    reader: &mut impl io::Read,
+    out: &mut Vec<u8>,
+) -> Result<u64, ExportDecodeError> {
+    let mut first_byte = [0];
+    reader
+        .read_exact(&mut first_byte)
+        .map_err(ExportDecodeError::Io)?;
+    out.push(first_byte[0]);
+
+    let num_extra_bytes = match first_byte[0] & 0b11 {
+        0b00 => return Ok(u64::from(first_byte[0] >> 2)),
+        0b01 => 1,
+        0b10 => 3,
+        _ => usize::from(first_byte[0] >> 2) + 4,
+    };
+    if num_extra_bytes > 8 {
+        return Err(ExportDecodeError::CompactOverflow);
+    }
+
+    let mut bytes = [0; 8];
+    reader
+        .read_exact(&mut bytes[..num_extra_bytes])
+        .map_err(ExportDecodeError::Io)?;
+    out.extend_from_slice(&bytes[..num_extra_bytes]);
+
+    if first_byte[0] & 0b11 == 0b11 {
+        Ok(u64::from_le_bytes(bytes))
+    } else {
+        let mut value = [0; 8];
+        value[0] = first_byte[0];
+        value[1..=num_extra_bytes].copy_from_slice(&bytes[..num_extra_bytes]);
+        Ok(u64::from_le_bytes(value) >> 2)
+    }
+}
+
+/// Reads `len` bytes from `reader` and appends them to `out`.
+pub(super) fn read_bytes(
+    reader: &mut impl io::Read,
+    len: u64,
+    out: &mut Vec<u8>,
+) -> Result<(), ExportDecodeError> {
+    let read = reader
+        .take(len)
+        .read_to_end(out)
+        .map_err(ExportDecodeError::Io)?;
+    if u64::try_from(read).ok() != Some(len) {
+        return Err(ExportDecodeError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(())
+}
@@ -0,0 +1,238 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Maintenance operations performed in small steps.
+//!
+//! Removing a large number of blocks or bodies locks the database for a long time, during which
+//! no block can be inserted. The operations of this module instead stop once the [`Budget`]
+//! passed to them has been consumed, and return [`MaintenanceProgress::Remaining`] if some work
+//! remains. The embedder can then insert blocks before calling them again.
+//!
+//! The position at which each operation has stopped is stored in the database, in the same
+//! transaction as the work that has been performed. An operation interrupted by a crash thus
+//! resumes where it had stopped.
+
+use super::{
+    finalized_num, meta_clear, meta_get_number, meta_set_number, prepare_cached, purge_block,
+    sqlite_err, CorruptedError, OperationKind, SqliteFullDatabase,
+};
+
+use core::{cmp, num::NonZeroUsize};
+use rusqlite::OptionalExtension as _;
+use std::time::{Duration, Instant};
+
+/// Key in the `meta` table containing the position of
+/// [`SqliteFullDatabase::purge_finality_orphans_step`].
+const PURGE_ORPHANS_CURSOR_KEY: &str = "purge_orphans_cursor";
+
+/// Key in the `meta` table containing the position of
+/// [`SqliteFullDatabase::prune_bodies_below_step`].
+const PRUNE_BODIES_CURSOR_KEY: &str = "prune_bodies_cursor";
+
+/// Amount of work that a maintenance operation is allowed to perform before returning.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Budget {
+    /// Duration after which the operation stops. The operation always processes at least one
+    /// row, even if this duration is zero, and can thus take longer than this duration.
+    pub max_duration: Duration,
+    /// Maximum number of rows, in other words of blocks, that the operation processes.
+    pub max_rows: NonZeroUsize,
+}
+
+/// Outcome of a maintenance operation. See [`Budget`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MaintenanceProgress {
+    /// The operation is complete.
+    Finished,
+    /// The [`Budget`] has been consumed before the operation is complete, and the operation
+    /// must be called again later. Contains the number of the block at which the operation
+    /// will resume.
+    ///
+    /// Since this position is also stored in the database, the operation resumes from there
+    /// even if the database is closed and opened again in the meantime.
+    ///
+    /// > **Note**: It is possible for the next call to immediately return
+    /// >           [`MaintenanceProgress::Finished`], as the operation doesn't verify whether
+    /// >           work remains before stopping.
+    Remaining(u64),
+}
+
+impl SqliteFullDatabase {
+    /// Similar to [`SqliteFullDatabase::purge_finality_orphans`], but stops once `budget` has
+    /// been consumed.
+    ///
+    /// The orphan blocks are removed from the highest to the lowest number. Blocks that become
+    /// orphans while the operation is in progress, because the finalized block is updated, are
+    /// only removed the next time the operation is started after having finished.
+    pub fn purge_finality_orphans_step(
+        &self,
+        budget: &Budget,
+    ) -> Result<MaintenanceProgress, CorruptedError> {
+        let mut timer = self.time_operation(OperationKind::PurgeFinalityOrphans);
+        let start = Instant::now();
+        let mut database = self.lock_write()?;
+
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("purge_finality_orphans_step"))?;
+
+        // See `purge_finality_orphans`.
+        prepare_cached(&transaction, "PRAGMA defer_foreign_keys = ON")
+            .and_then(|mut statement| statement.execute(()))
+            .map_err(sqlite_err("purge_finality_orphans_step"))?;
+
+        let current_finalized = finalized_num(&transaction)?;
+        let mut cursor = meta_get_number(&transaction, PURGE_ORPHANS_CURSOR_KEY)?
+            .map_or(current_finalized, |cursor| {
+                cmp::min(cursor, current_finalized)
+            });
+
+        let mut num_purged = 0;
+        let progress = loop {
+            if num_purged >= budget.max_rows.get()
+                || (num_purged != 0 && start.elapsed() >= budget.max_duration)
+            {
+                break MaintenanceProgress::Remaining(cursor);
+            }
+
+            let Some((hash, number)) = prepare_cached(
+                &transaction,
+                r#"SELECT hash, number FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number <= ? AND is_best_chain = FALSE ORDER BY number DESC LIMIT 1"#,
+            )
+            .map_err(sqlite_err("purge_finality_orphans_step"))?
+            .query_row((i64::try_from(cursor).unwrap_or(i64::MAX),), |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+            })
+            .optional()
+            .map_err(sqlite_err("purge_finality_orphans_step"))?
+            else {
+                break MaintenanceProgress::Finished;
+            };
+
+            purge_block(&transaction, &hash)?;
+            cursor = u64::try_from(number).map_err(|_| CorruptedError::InvalidNumber)?;
+            num_purged += 1;
+        };
+
+        match progress {
+            MaintenanceProgress::Finished => meta_clear(&transaction, PURGE_ORPHANS_CURSOR_KEY)?,
+            MaintenanceProgress::Remaining(cursor) => {
+                meta_set_number(&transaction, PURGE_ORPHANS_CURSOR_KEY, cursor)?
+            }
+        }
+
+        transaction
+            .commit()
+            .map_err(sqlite_err("purge_finality_orphans_step"))?;
+        database.finish(0)?;
+
+        timer.set_rows(num_purged);
+        Ok(progress)
+    }
+
+    /// Similar to [`SqliteFullDatabase::prune_bodies_below`], but stops once `budget` has been
+    /// consumed.
+    ///
+    /// The bodies are removed from the lowest to the highest number.
+    pub fn prune_bodies_below_step(
+        &self,
+        block_number: u64,
+        budget: &Budget,
+    ) -> Result<MaintenanceProgress, CorruptedError> {
+        let mut timer = self.time_operation(OperationKind::PruneBodies);
+        let start = Instant::now();
+        let mut database = self.lock_write()?;
+
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("prune_bodies_below_step"))?;
+
+        // Blocks above the finalized block are never affected.
+        let block_number = cmp::min(block_number, finalized_num(&transaction)?.saturating_add(1));
+        let mut cursor = meta_get_number(&transaction, PRUNE_BODIES_CURSOR_KEY)?.unwrap_or(0);
+
+        let mut num_removed = 0;
+        let mut num_blocks = 0;
+        let progress = loop {
+            if num_blocks >= budget.max_rows.get()
+                || (num_blocks != 0 && start.elapsed() >= budget.max_duration)
+            {
+                break MaintenanceProgress::Remaining(cursor);
+            }
+
+            let Some((hash, number)) = prepare_cached(
+                &transaction,
+                r#"SELECT hash, number FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number >= ? AND number < ? AND is_best_chain = TRUE AND body_pruned = FALSE ORDER BY number ASC LIMIT 1"#,
+            )
+            .map_err(sqlite_err("prune_bodies_below_step"))?
+            .query_row(
+                (
+                    i64::try_from(cursor).unwrap_or(i64::MAX),
+                    i64::try_from(block_number).unwrap_or(i64::MAX),
+                ),
+                |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()
+            .map_err(sqlite_err("prune_bodies_below_step"))?
+            else {
+                break MaintenanceProgress::Finished;
+            };
+
+            num_removed += prune_block_body(&transaction, &hash)?;
+            cursor = u64::try_from(number)
+                .map_err(|_| CorruptedError::InvalidNumber)?
+                .saturating_add(1);
+            num_blocks += 1;
+        };
+
+        match progress {
+            MaintenanceProgress::Finished => meta_clear(&transaction, PRUNE_BODIES_CURSOR_KEY)?,
+            MaintenanceProgress::Remaining(cursor) => {
+                meta_set_number(&transaction, PRUNE_BODIES_CURSOR_KEY, cursor)?
+            }
+        }
+
+        transaction
+            .commit()
+            .map_err(sqlite_err("prune_bodies_below_step"))?;
+        database.finish(0)?;
+
+        timer.set_rows(num_removed);
+        Ok(progress)
+    }
+}
+
+/// Removes the body and the events of the given block, and marks its body as pruned. Returns
+/// the number of extrinsics that have been removed.
+fn prune_block_body(database: &rusqlite::Connection, hash: &[u8]) -> Result<usize, CorruptedError> {
+    prepare_cached(database, "DELETE FROM blocks_events WHERE hash = ?")
+        .map_err(sqlite_err("prune_block_body"))?
+        .execute((hash,))
+        .map_err(sqlite_err("prune_block_body"))?;
+    let num_removed = prepare_cached(database, "DELETE FROM blocks_body WHERE hash = ?")
+        .map_err(sqlite_err("prune_block_body"))?
+        .execute((hash,))
+        .map_err(sqlite_err("prune_block_body"))?;
+    prepare_cached(
+        database,
+        "UPDATE blocks SET body_pruned = TRUE WHERE hash = ?",
+    )
+    .map_err(sqlite_err("prune_block_body"))?
+    .execute((hash,))
+    .map_err(sqlite_err("prune_block_body"))?;
+    Ok(num_removed)
+}
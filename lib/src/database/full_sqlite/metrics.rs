@@ -0,0 +1,217 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reporting the duration of the operations performed on the database, and the statistics of
+//! the cache of prepared statements.
+//!
+//! See [`super::Config::on_operation`] and [`super::SqliteFullDatabase::statement_cache_stats`].
+
+use alloc::{borrow::ToOwned as _, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::cell::RefCell;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+
+/// Callback invoked after an operation has been performed on the database. See
+/// [`super::Config::on_operation`].
+pub type OperationCallback = Arc<dyn Fn(OperationMetrics) + Send + Sync>;
+
+/// Information about an operation that has been performed on the database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationMetrics {
+    /// Which operation has been performed.
+    pub kind: OperationKind,
+
+    /// Time between the start of the call to the method of the
+    /// [`super::SqliteFullDatabase`] and the moment when the database has been unlocked,
+    /// including the time spent waiting for the database to be available.
+    pub duration: Duration,
+
+    /// Number of rows that have been inserted, modified, or removed by the operation, if known.
+    /// The exact meaning depends on [`OperationMetrics::kind`].
+    pub rows: Option<u64>,
+}
+
+/// Operation whose metrics are reported. See [`OperationMetrics`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OperationKind {
+    /// [`super::SqliteFullDatabase::insert`]. The number of rows is the number of extrinsics
+    /// in the body of the block.
+    Insert,
+    /// [`super::SqliteFullDatabase::insert_trie_nodes_versioned`] and
+    /// [`super::SqliteFullDatabase::insert_trie_nodes_validated`]. The number of rows is the
+    /// number of trie nodes passed as parameter.
+    InsertTrieNodes,
+    /// [`super::SqliteFullDatabase::set_best_block`].
+    SetBest,
+    /// [`super::SqliteFullDatabase::set_finalized`]. The number of rows is the number of blocks
+    /// that have become finalized.
+    SetFinalized,
+    /// [`super::SqliteFullDatabase::purge_finality_orphans`]. The number of rows is the number
+    /// of blocks that have been removed.
+    PurgeFinalityOrphans,
+    /// [`super::SqliteFullDatabase::prune_bodies_below`]. The number of rows is the number of
+    /// extrinsics that have been removed.
+    PruneBodies,
+    /// [`super::SqliteFullDatabase::prune_storage_diffs_below`]. The number of rows is the
+    /// number of blocks whose storage diff has been removed.
+    PruneStorageDiffs,
+    /// [`super::SqliteFullDatabase::reset`].
+    Reset,
+    /// [`super::SqliteFullDatabase::flush`].
+    Flush,
+    /// [`super::SqliteFullDatabase::maintain`].
+    Maintain,
+    /// [`super::SqliteFullDatabase::begin_bulk_load`] and the end of the bulk load, which
+    /// includes rebuilding the indices.
+    BulkLoad,
+    /// [`super::SqliteFullDatabase::block_scale_encoded_header`].
+    BlockHeader,
+    /// [`super::SqliteFullDatabase::block_extrinsics`],
+    /// [`super::SqliteFullDatabase::with_block_extrinsics`], and
+    /// [`super::SqliteFullDatabase::best_chain_bodies_in_range`].
+    BlockExtrinsics,
+    /// [`super::SqliteFullDatabase::full_block`].
+    FullBlock,
+    /// [`super::SqliteFullDatabase::block_storage_get_versioned`].
+    StorageGet,
+    /// [`super::SqliteFullDatabase::block_storage_get_historical`].
+    StorageGetHistorical,
+    /// [`super::SqliteFullDatabase::block_storage_next_key`]. The number of rows is the number
+    /// of trie nodes that have been read from the database.
+    StorageNextKey,
+    /// [`super::SqliteFullDatabase::block_storage_closest_descendant_merkle_value`].
+    StorageClosestDescendantMerkleValue,
+    /// Reading a page of entries of a [`super::StorageIter`]. Reported once per page. The
+    /// number of rows is the number of entries of the page.
+    StorageIterPage,
+    /// [`super::SqliteFullDatabase::block_storage_prefix_entries`]. The number of rows is the
+    /// number of entries that have been returned.
+    StoragePrefixEntries,
+    /// [`super::SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`]. The
+    /// number of rows is the number of missing trie nodes that have been found.
+    MissingTrieNodes,
+    /// [`super::SqliteFullDatabase::query_read_only`]. The number of rows is the number of rows
+    /// that the query has produced.
+    QueryReadOnly,
+}
+
+/// Measures the duration of an operation, and reports it to the [`OperationCallback`] when
+/// destroyed.
+///
+/// Must be created at the very beginning of the operation, before the database is locked, so
+/// that it is destroyed after the database has been unlocked. The callback is thus never
+/// invoked while the database is locked.
+pub(super) struct OperationTimer<'a> {
+    callback: Option<&'a OperationCallback>,
+    kind: OperationKind,
+    start: Instant,
+    rows: Option<u64>,
+}
+
+impl<'a> OperationTimer<'a> {
+    pub(super) fn new(callback: Option<&'a OperationCallback>, kind: OperationKind) -> Self {
+        OperationTimer {
+            callback,
+            kind,
+            start: Instant::now(),
+            rows: None,
+        }
+    }
+
+    /// Sets the number of rows reported in [`OperationMetrics::rows`].
+    pub(super) fn set_rows(&mut self, rows: impl TryInto<u64>) {
+        self.rows = rows.try_into().ok();
+    }
+}
+
+impl<'a> Drop for OperationTimer<'a> {
+    fn drop(&mut self) {
+        if let Some(callback) = self.callback {
+            callback(OperationMetrics {
+                kind: self.kind,
+                duration: self.start.elapsed(),
+                rows: self.rows,
+            });
+        }
+    }
+}
+
+/// Number of times a statement has been looked up in the cache of prepared statements. See
+/// [`super::SqliteFullDatabase::statement_cache_stats`].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    /// Number of times the statement has been found in the cache.
+    pub hits: u64,
+    /// Number of times the statement wasn't in the cache and had to be prepared.
+    pub misses: u64,
+}
+
+/// Statistics of the cache of prepared statements of all the connections of a database,
+/// indexed by SQL text.
+pub(super) type StatementCacheStatsMap = Arc<Mutex<BTreeMap<String, StatementCacheStats>>>;
+
+std::thread_local! {
+    /// Statistics of the databases of which a connection is currently locked on the current
+    /// thread. Lookups in the cache are attributed to the last element.
+    static ACTIVE_STATEMENT_CACHE_STATS: RefCell<Vec<StatementCacheStatsMap>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Attributes the lookups in the cache of prepared statements performed on the current thread
+/// to the given statistics for as long as it is alive.
+///
+/// Must be created when a connection is locked, and destroyed when it is unlocked.
+pub(super) struct StatementCacheStatsGuard {
+    // Prevents the guard from being sent to a different thread.
+    _not_send: core::marker::PhantomData<*const ()>,
+}
+
+impl StatementCacheStatsGuard {
+    pub(super) fn new(stats: &StatementCacheStatsMap) -> Self {
+        ACTIVE_STATEMENT_CACHE_STATS.with(|list| list.borrow_mut().push(stats.clone()));
+        StatementCacheStatsGuard {
+            _not_send: core::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for StatementCacheStatsGuard {
+    fn drop(&mut self) {
+        ACTIVE_STATEMENT_CACHE_STATS.with(|list| list.borrow_mut().pop());
+    }
+}
+
+/// Records a lookup of the given statement in the cache of prepared statements. Does nothing
+/// if no [`StatementCacheStatsGuard`] is alive on the current thread.
+pub(super) fn record_statement_cache_lookup(sql: &str, hit: bool) {
+    ACTIVE_STATEMENT_CACHE_STATS.with(|list| {
+        let list = list.borrow();
+        let Some(stats) = list.last() else {
+            return;
+        };
+        let mut stats = stats.lock();
+        let entry = match stats.get_mut(sql) {
+            Some(entry) => entry,
+            None => stats.entry(sql.to_owned()).or_default(),
+        };
+        if hit {
+            entry.hits += 1;
+        } else {
+            entry.misses += 1;
+        }
+    });
+}
@@ -21,17 +21,43 @@
 
 // TODO:remove all the unwraps in this module that shouldn't be there
 
-use super::{CorruptedError, InternalError, SqliteFullDatabase};
+use super::{bulk_load, events, InternalError, OperationCallback, ResetError, SqliteFullDatabase};
 use crate::chain::chain_information;
 
-use std::path::Path;
+use alloc::{sync::Arc, vec::Vec};
+use core::{
+    fmt, iter,
+    num::NonZeroU64,
+    sync::atomic::{AtomicBool, AtomicUsize},
+};
+use rusqlite::OptionalExtension as _;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Version of the schema of the database once all the migrations have been applied. Stored in
+/// the `user_version` of the database.
+pub(super) const SCHEMA_VERSION: i64 = 18;
 
 /// Opens the database using the given [`Config`].
 ///
 /// Note that this doesn't return a [`SqliteFullDatabase`], but rather a [`DatabaseOpen`].
-pub fn open(config: Config) -> Result<DatabaseOpen, InternalError> {
-    let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE |
-        rusqlite::OpenFlags::SQLITE_OPEN_CREATE |
+pub fn open(mut config: Config) -> Result<DatabaseOpen, OpenError> {
+    let start = Instant::now();
+    let mut progress = config.progress.take();
+    report_progress(&mut progress, OpenProgressStep::Opening);
+
+    // Databases shared with other readers are opened in read-only mode, so that they can't
+    // modify the database while the other readers are accessing it.
+    let read_only = config.allow_shared_readers && matches!(config.ty, ConfigTy::Disk { .. });
+    let access_flags = if read_only {
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+    } else {
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE | rusqlite::OpenFlags::SQLITE_OPEN_CREATE
+    };
+    let flags = access_flags |
         // The "no mutex" option opens SQLite in "multi-threaded" mode, meaning that it can safely
         // be used from multiple threads as long as we don't access the connection from multiple
         // threads *at the same time*. Since we put the connection behind a `Mutex`, and that the
@@ -40,15 +66,19 @@ pub fn open(config: Config) -> Result<DatabaseOpen, InternalError> {
         // See https://www.sqlite.org/threadsafe.html
         rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX;
 
+    // The lock is taken before the database is opened, as the migrations below modify it.
+    let lock_file = match config.ty {
+        ConfigTy::Disk { path, .. } => Some(lock_database(path, read_only)?),
+        ConfigTy::Memory => None,
+    };
+
     let database = match config.ty {
         ConfigTy::Disk { path, .. } => rusqlite::Connection::open_with_flags(path, flags),
         ConfigTy::Memory => rusqlite::Connection::open_in_memory_with_flags(flags),
     }
-    .map_err(InternalError)?;
+    .map_err(internal_err("open"))?;
 
-    // The underlying SQLite wrapper maintains a cache of prepared statements. We set it to a
-    // value superior to the number of different queries we make.
-    database.set_prepared_statement_cache_capacity(64);
+    configure_connection(&database, &config)?;
 
     // Configure the database connection.
     database
@@ -57,36 +87,35 @@ pub fn open(config: Config) -> Result<DatabaseOpen, InternalError> {
 -- See https://sqlite.org/pragma.html and https://www.sqlite.org/wal.html
 PRAGMA journal_mode = WAL;
 PRAGMA synchronous = NORMAL;
-PRAGMA locking_mode = EXCLUSIVE;
+-- The locking mode is left to `NORMAL` so that other connections, such as backup jobs or the
+-- `sqlite3` CLI, can briefly access the file. See `Config::busy_timeout`.
+PRAGMA locking_mode = NORMAL;
 PRAGMA encoding = 'UTF-8';
 PRAGMA trusted_schema = false;
 PRAGMA foreign_keys = ON;
             "#,
         )
-        .map_err(InternalError)?;
+        .map_err(internal_err("open/pragmas"))?;
 
-    // `PRAGMA` queries can't be parametrized, and thus we have to use `format!`.
-    database
-        .execute(
-            &format!(
-                "PRAGMA cache_size = {}",
-                0i64.saturating_sub_unsigned(
-                    u64::try_from((config.cache_size.saturating_sub(1) / 1024).saturating_add(1))
-                        .unwrap_or(u64::max_value()),
-                )
+    report_progress(&mut progress, OpenProgressStep::VerifyingMeta);
+
+    // `PRAGMA foreign_keys = ON` is silently ignored if SQLite has been compiled without support
+    // for foreign keys. Since the schema relies on `ON DELETE CASCADE` in order to not leave
+    // dangling rows behind, make sure that the constraints are actually enforced.
+    let foreign_keys_enabled = database
+        .prepare_cached("PRAGMA foreign_keys")
+        .map_err(internal_err("open/foreign_keys"))?
+        .query_row((), |row| row.get::<_, bool>(0))
+        .map_err(internal_err("open/foreign_keys"))?;
+    if !foreign_keys_enabled {
+        return Err(InternalError::new(
+            "open/foreign_keys",
+            rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+                Some("foreign keys enforcement isn't supported".into()),
             ),
-            (),
         )
-        .map_err(InternalError)?;
-
-    // `PRAGMA` queries can't be parametrized, and thus we have to use `format!`.
-    if let ConfigTy::Disk {
-        memory_map_size, ..
-    } = config.ty
-    {
-        database
-            .execute_batch(&format!("PRAGMA mmap_size = {}", memory_map_size))
-            .map_err(InternalError)?;
+        .into());
     }
 
     // Each SQLite database contains a "user version" whose value can be used by the API user
@@ -94,12 +123,17 @@ PRAGMA foreign_keys = ON;
     // store the schema version.
     let user_version = database
         .prepare_cached("PRAGMA user_version")
-        .map_err(InternalError)?
+        .map_err(internal_err("open/user_version"))?
         .query_row((), |row| row.get::<_, i64>(0))
-        .map_err(InternalError)?;
+        .map_err(internal_err("open/user_version"))?;
+
+    if read_only && user_version != SCHEMA_VERSION {
+        return Err(OpenError::ReadOnly);
+    }
 
     // Migrations.
     if user_version <= 0 {
+        report_migration(&mut progress, user_version, 0);
         database
             .execute_batch(
                 r#"
@@ -114,11 +148,12 @@ Keys in that table:
 
  - `best` (blob): Hash of the best block.
 
- - `finalized` (number): Height of the finalized block, as a 64bits big endian number.
+ - `finalized` (number): Height of the finalized block.
 
- - `grandpa_authorities_set_id` (number): Id of the authorities set that must finalize the block
+ - `grandpa_authorities_set_id` (blob): Id of the authorities set that must finalize the block
  right after the finalized block. The value is 0 at the genesis block, and increased by 1 at every
- authorities change. Missing if and only if the chain doesn't use Grandpa.
+ authorities change, as a 64bits big endian number. Missing if and only if the chain doesn't use
+ Grandpa.
 
  - `grandpa_scheduled_target` (number): Height of the block where the authorities found in
  `grandpa_scheduled_authorities` will be triggered. Blocks whose height is strictly higher than
@@ -126,20 +161,34 @@ Keys in that table:
  been scheduled in or before the finalized block. Missing if no change is scheduled or if the
  chain doesn't use Grandpa.
 
- - `aura_slot_duration` (number): Duration of an Aura slot in milliseconds. Missing if and only if
- the chain doesn't use Aura.
+ - `aura_slot_duration` (blob): Duration of an Aura slot in milliseconds, as a 64bits big endian
+ number. Missing if and only if the chain doesn't use Aura.
+
+ - `babe_slots_per_epoch` (blob): Number of slots per Babe epoch, as a 64bits big endian number.
+ Missing if and only if the chain doesn't use Babe.
+
+ - `babe_finalized_epoch` (blob): One byte containing the version of the encoding, followed
+ with an encoding of a structure that contains the information about the Babe epoch used for
+ the finalized block. Missing if and only if the finalized block is block #0 or the chain
+ doesn't use Babe.
+
+ - `babe_finalized_next_epoch` (blob): Same encoding as `babe_finalized_epoch`, containing the
+ information about the Babe epoch that follows the one described by `babe_finalized_epoch`. If
+ the finalized block is block #0, then this contains information about epoch #0. Missing if
+ and only if the chain doesn't use Babe.
 
- - `babe_slots_per_epoch` (number): Number of slots per Babe epoch. Missing if and only if the
- chain doesn't use Babe.
+ - `substrate_import_cursor` (number): Height of the latest block inserted when importing blocks
+ exported by a Substrate node. Missing if no import has ever happened.
 
- - `babe_finalized_epoch` (blob): SCALE encoding of a structure that contains the information
- about the Babe epoch used for the finalized block. Missing if and only if the finalized
- block is block #0 or the chain doesn't use Babe.
+ - `purge_orphans_cursor` (number): Height from which `SqliteFullDatabase::purge_finality_orphans_step`
+ resumes. Missing if no such operation is in progress.
 
- - `babe_finalized_next_epoch` (blob): SCALE encoding of a structure that contains the information
- about the Babe epoch that follows the one described by `babe_finalized_epoch`. If the
- finalized block is block #0, then this contains information about epoch #0. Missing if and
- only if the chain doesn't use Babe.
+ - `prune_bodies_cursor` (number): Height from which `SqliteFullDatabase::prune_bodies_below_step`
+ resumes. Missing if no such operation is in progress.
+
+ - `initialized` (number): Always 1. Written once the initialization of the chain is complete.
+ A chain that doesn't have this key is considered as empty, and its content is discarded when the
+ database is opened.
 
 */
 CREATE TABLE meta(
@@ -155,7 +204,7 @@ List of all trie nodes of all blocks whose trie is stored in the database.
 */
 CREATE TABLE trie_node(
     hash BLOB NOT NULL PRIMARY KEY,
-    partial_key BLOB NOT NULL    -- Each byte is a nibble, in other words all bytes are <16
+    partial_key BLOB NOT NULL    -- A byte equal to 1 if the number of nibbles is odd and 0 otherwise, followed with the nibbles, two per byte. See `pack_nibbles`. Before version 10, each byte was a nibble.
 );
 
 /*
@@ -256,101 +305,1878 @@ PRAGMA user_version = 1;
 
         "#,
             )
-            .map_err(InternalError)?
+            .map_err(internal_err("open/migration_v1"))?
     }
 
-    let is_empty = database
-        .prepare_cached("SELECT COUNT(*) FROM meta WHERE key = ?")
-        .map_err(InternalError)?
-        .query_row(("best",), |row| row.get::<_, i64>(0))
-        .map_err(InternalError)?
-        == 0;
+    if user_version <= 1 {
+        report_migration(&mut progress, user_version, 1);
+        database
+            .execute_batch(
+                r#"
+/*
+`body_pruned` is TRUE if the entries of `blocks_body` of the block have been removed in order to
+save space. This makes it possible to distinguish a pruned body from an empty body.
+*/
+ALTER TABLE blocks ADD COLUMN body_pruned BOOLEAN NOT NULL DEFAULT FALSE;
 
-    Ok(if !is_empty {
-        DatabaseOpen::Open(SqliteFullDatabase {
-            database: parking_lot::Mutex::new(database),
-            block_number_bytes: config.block_number_bytes, // TODO: consider storing this value in the DB and check it when opening
-        })
-    } else {
-        DatabaseOpen::Empty(DatabaseEmpty {
-            database,
-            block_number_bytes: config.block_number_bytes,
-        })
-    })
-}
+PRAGMA user_version = 2;
 
-/// Configuration for the database.
-#[derive(Debug)]
-pub struct Config<'a> {
-    /// Type of database.
-    pub ty: ConfigTy<'a>,
+        "#,
+            )
+            .map_err(internal_err("open/migration_v2"))?
+    }
 
-    /// Number of bytes used to encode the block number.
-    pub block_number_bytes: usize,
+    if user_version <= 2 {
+        report_migration(&mut progress, user_version, 2);
+        database
+            .execute_batch(
+                r#"
+/*
+`runtime_spec_version` is the specification version of the runtime of the block, if it was known
+when the block was inserted. It refers to an entry in `runtimes`, but the corresponding runtime
+isn't necessarily stored.
+*/
+ALTER TABLE blocks ADD COLUMN runtime_spec_version INTEGER;
 
-    /// Maximum allowed size, in bytes, of the SQLite cache.
-    pub cache_size: usize,
-}
+/*
+Runtimes that have been used by the chain, indexed by their specification version. This makes it
+possible to obtain a runtime without having to read `:code` from the storage of a block.
+`code_hash` is the BLAKE2 hash of `code`.
+*/
+CREATE TABLE runtimes(
+    spec_version INTEGER NOT NULL PRIMARY KEY,
+    code_hash BLOB NOT NULL,
+    code BLOB NOT NULL,
+    heap_pages INTEGER NOT NULL,
+    CHECK(length(code_hash) == 32)
+);
 
-/// Type of database.
-#[derive(Debug)]
-pub enum ConfigTy<'a> {
-    /// Store the database on disk.
-    Disk {
-        /// Path to the directory containing the database.
-        path: &'a Path,
-        /// Maximum allowed amount of memory, in bytes, that SQLite will reserve to memory-map
-        /// files.
-        memory_map_size: usize,
-    },
-    /// Store the database in memory. The database is discarded on destruction.
-    Memory,
-}
+PRAGMA user_version = 3;
 
-/// Either existing database or database prototype.
-pub enum DatabaseOpen {
-    /// A database already existed and has now been opened.
-    Open(SqliteFullDatabase),
+        "#,
+            )
+            .map_err(internal_err("open/migration_v3"))?
+    }
 
-    /// Either a database has just been created, or there existed a database but it is empty.
-    ///
-    /// > **Note**: The situation where a database existed but is empty can happen if you have
-    /// >           previously called [`open`] then dropped the [`DatabaseOpen`] object without
-    /// >           filling the newly-created database with data.
-    Empty(DatabaseEmpty),
-}
+    if user_version <= 3 {
+        report_migration(&mut progress, user_version, 3);
+        database
+            .execute_batch(
+                r#"
+/*
+SCALE-encoded list of events emitted by each block. Only filled if `Config::store_events` is
+true, and removed at the same time as the body of the block.
+*/
+CREATE TABLE blocks_events(
+    hash BLOB NOT NULL PRIMARY KEY,
+    events BLOB NOT NULL,
+    CHECK(length(hash) == 32),
+    FOREIGN KEY (hash) REFERENCES blocks(hash) ON UPDATE CASCADE ON DELETE CASCADE
+);
 
-/// An open database. Holds file descriptors.
-pub struct DatabaseEmpty {
-    /// See the similar field in [`SqliteFullDatabase`].
-    database: rusqlite::Connection,
+PRAGMA user_version = 4;
 
-    /// See the similar field in [`SqliteFullDatabase`].
-    block_number_bytes: usize,
-}
+        "#,
+            )
+            .map_err(internal_err("open/migration_v4"))?
+    }
 
-impl DatabaseEmpty {
-    /// Inserts the given [`chain_information::ChainInformationRef`] in the database prototype in
-    /// order to turn it into an actual database.
-    ///
-    /// Must also pass the body and justification of the finalized block.
-    pub fn initialize<'a>(
-        self,
-        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
-        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
-        finalized_block_justification: Option<Vec<u8>>,
-    ) -> Result<SqliteFullDatabase, CorruptedError> {
-        let database = SqliteFullDatabase {
-            database: parking_lot::Mutex::new(self.database),
-            block_number_bytes: self.block_number_bytes,
-        };
+    if user_version <= 4 {
+        report_migration(&mut progress, user_version, 4);
+        database
+            .execute_batch(
+                r#"
+/*
+Information about the arrival of blocks, as passed when inserting them. Blocks inserted without
+this information don't have an entry.
+`inserted_at_unix_ms` is a number of milliseconds since the UNIX epoch.
+*/
+CREATE TABLE blocks_meta(
+    hash BLOB NOT NULL PRIMARY KEY,
+    inserted_at_unix_ms INTEGER NOT NULL,
+    source TEXT NOT NULL,
+    CHECK(length(hash) == 32),
+    FOREIGN KEY (hash) REFERENCES blocks(hash) ON UPDATE CASCADE ON DELETE CASCADE
+);
 
-        database.reset(
-            chain_information,
-            finalized_block_body,
-            finalized_block_justification,
-        )?;
+PRAGMA user_version = 5;
 
-        Ok(database)
+        "#,
+            )
+            .map_err(internal_err("open/migration_v5"))?
+    }
+
+    if user_version <= 5 {
+        report_migration(&mut progress, user_version, 5);
+        database
+            .execute_batch(
+                r#"
+/*
+Numbers that don't necessarily fit in a signed 64 bits integer used to be stored by
+reinterpreting their bits as a signed integer. They are now stored as 64bits big endian blobs.
+*/
+UPDATE meta SET value_blob = unhex(printf('%016X', value_number)), value_number = NULL
+WHERE key IN ('grandpa_authorities_set_id', 'aura_slot_duration', 'babe_slots_per_epoch')
+    AND value_number IS NOT NULL;
+
+/*
+`weight` is a 64bits big endian number.
+*/
+CREATE TABLE grandpa_triggered_authorities_new(
+    idx INTEGER NOT NULL PRIMARY KEY,
+    public_key BLOB NOT NULL,
+    weight BLOB NOT NULL,
+    CHECK(length(public_key) == 32),
+    CHECK(length(weight) == 8)
+);
+INSERT INTO grandpa_triggered_authorities_new(idx, public_key, weight)
+    SELECT idx, public_key, unhex(printf('%016X', weight)) FROM grandpa_triggered_authorities;
+DROP TABLE grandpa_triggered_authorities;
+ALTER TABLE grandpa_triggered_authorities_new RENAME TO grandpa_triggered_authorities;
+
+/*
+`weight` is a 64bits big endian number.
+*/
+CREATE TABLE grandpa_scheduled_authorities_new(
+    idx INTEGER NOT NULL PRIMARY KEY,
+    public_key BLOB NOT NULL,
+    weight BLOB NOT NULL,
+    CHECK(length(public_key) == 32),
+    CHECK(length(weight) == 8)
+);
+INSERT INTO grandpa_scheduled_authorities_new(idx, public_key, weight)
+    SELECT idx, public_key, unhex(printf('%016X', weight)) FROM grandpa_scheduled_authorities;
+DROP TABLE grandpa_scheduled_authorities;
+ALTER TABLE grandpa_scheduled_authorities_new RENAME TO grandpa_scheduled_authorities;
+
+PRAGMA user_version = 6;
+
+        "#,
+            )
+            .map_err(internal_err("open/migration_v6"))?
+    }
+
+    if user_version <= 6 {
+        report_migration(&mut progress, user_version, 6);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+The Babe epoch information blobs are now prefixed with a version number. The blobs written
+before this prefix was introduced use a format identical to version 1.
+*/
+UPDATE meta SET value_blob = unhex('01' || hex(value_blob))
+WHERE key IN ('babe_finalized_epoch', 'babe_finalized_next_epoch');
+
+PRAGMA user_version = 7;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v7"))?
+    }
+
+    if user_version <= 7 {
+        report_migration(&mut progress, user_version, 7);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+List of the chains whose blocks are stored in the database. A database file can contain the blocks
+of multiple chains, and each `SqliteFullDatabase` only accesses the blocks of one of them, chosen
+using `Config::chain_name`.
+The chain whose `name` is NULL is the one used when no name is configured. It contains all the
+blocks that were stored before multiple chains were supported.
+Every table below, with the exception of the `trie_node*` tables, contains information about a
+specific chain. Trie nodes are shared between all chains, as they are indexed by their hash.
+Since the hash of a block also depends on its ancestors, blocks are still indexed by their hash
+alone.
+*/
+CREATE TABLE chains(
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT UNIQUE
+);
+INSERT INTO chains(id, name) VALUES (0, NULL);
+
+ALTER TABLE blocks ADD COLUMN chain_id INTEGER NOT NULL DEFAULT 0;
+DROP INDEX blocks_by_number;
+DROP INDEX blocks_by_best;
+CREATE INDEX blocks_by_number ON blocks(chain_id, number);
+CREATE INDEX blocks_by_best ON blocks(chain_id, number, is_best_chain);
+
+CREATE TABLE meta_new(
+    chain_id INTEGER NOT NULL,
+    key STRING NOT NULL,
+    value_blob BLOB,
+    value_number INTEGER,
+    PRIMARY KEY (chain_id, key),
+    -- Either `value_blob` or `value_number` must be NULL but not both.
+    CHECK((value_blob IS NULL OR value_number IS NULL) AND (value_blob IS NOT NULL OR value_number IS NOT NULL))
+);
+INSERT INTO meta_new(chain_id, key, value_blob, value_number)
+    SELECT 0, key, value_blob, value_number FROM meta;
+DROP TABLE meta;
+ALTER TABLE meta_new RENAME TO meta;
+
+CREATE TABLE grandpa_triggered_authorities_new(
+    chain_id INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+    public_key BLOB NOT NULL,
+    weight BLOB NOT NULL,
+    PRIMARY KEY (chain_id, idx),
+    CHECK(length(public_key) == 32),
+    CHECK(length(weight) == 8)
+);
+INSERT INTO grandpa_triggered_authorities_new(chain_id, idx, public_key, weight)
+    SELECT 0, idx, public_key, weight FROM grandpa_triggered_authorities;
+DROP TABLE grandpa_triggered_authorities;
+ALTER TABLE grandpa_triggered_authorities_new RENAME TO grandpa_triggered_authorities;
+
+CREATE TABLE grandpa_scheduled_authorities_new(
+    chain_id INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+    public_key BLOB NOT NULL,
+    weight BLOB NOT NULL,
+    PRIMARY KEY (chain_id, idx),
+    CHECK(length(public_key) == 32),
+    CHECK(length(weight) == 8)
+);
+INSERT INTO grandpa_scheduled_authorities_new(chain_id, idx, public_key, weight)
+    SELECT 0, idx, public_key, weight FROM grandpa_scheduled_authorities;
+DROP TABLE grandpa_scheduled_authorities;
+ALTER TABLE grandpa_scheduled_authorities_new RENAME TO grandpa_scheduled_authorities;
+
+CREATE TABLE aura_finalized_authorities_new(
+    chain_id INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+    public_key BLOB NOT NULL,
+    PRIMARY KEY (chain_id, idx),
+    CHECK(length(public_key) == 32)
+);
+INSERT INTO aura_finalized_authorities_new(chain_id, idx, public_key)
+    SELECT 0, idx, public_key FROM aura_finalized_authorities;
+DROP TABLE aura_finalized_authorities;
+ALTER TABLE aura_finalized_authorities_new RENAME TO aura_finalized_authorities;
+
+CREATE TABLE runtimes_new(
+    chain_id INTEGER NOT NULL,
+    spec_version INTEGER NOT NULL,
+    code_hash BLOB NOT NULL,
+    code BLOB NOT NULL,
+    heap_pages INTEGER NOT NULL,
+    PRIMARY KEY (chain_id, spec_version),
+    CHECK(length(code_hash) == 32)
+);
+INSERT INTO runtimes_new(chain_id, spec_version, code_hash, code, heap_pages)
+    SELECT 0, spec_version, code_hash, code, heap_pages FROM runtimes;
+DROP TABLE runtimes;
+ALTER TABLE runtimes_new RENAME TO runtimes;
+
+PRAGMA user_version = 8;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v8"))?
+    }
+
+    if user_version <= 8 {
+        report_migration(&mut progress, user_version, 8);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+`blocks_by_best` now also contains the hash of the blocks, so that finding the blocks at a certain
+height doesn't need to read the rows of `blocks`, which contain the headers. `blocks_by_number` is
+a prefix of `blocks_by_best` and is thus no longer necessary.
+*/
+DROP INDEX blocks_by_number;
+DROP INDEX blocks_by_best;
+CREATE INDEX blocks_by_best ON blocks(chain_id, number, is_best_chain, hash);
+
+PRAGMA user_version = 9;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v9"))?
+    }
+
+    if user_version <= 9 {
+        report_migration(&mut progress, user_version, 9);
+        pack_partial_keys(&database)?;
+    }
+
+    if user_version <= 10 {
+        report_migration(&mut progress, user_version, 10);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+`value_compressed` is TRUE if `value` has been compressed with zstd, in which case it must be
+decompressed before being used. See `Config::value_compression`. Values inserted before this
+column was introduced are never compressed.
+*/
+ALTER TABLE trie_node_storage ADD COLUMN value_compressed BOOLEAN NOT NULL DEFAULT FALSE;
+
+PRAGMA user_version = 11;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v11"))?
+    }
+
+    if user_version <= 11 {
+        report_migration(&mut progress, user_version, 11);
+        store_children_in_trie_node(&database)?;
+    }
+
+    if user_version <= 12 {
+        report_migration(&mut progress, user_version, 12);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+The `initialized` key is now written once the initialization of a chain is complete. The chains
+initialized before this key was introduced are recognized by the presence of the `best` key.
+*/
+INSERT OR IGNORE INTO meta(chain_id, key, value_number)
+    SELECT chain_id, 'initialized', 1 FROM meta WHERE key = 'best';
+
+PRAGMA user_version = 13;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v13"))?
+    }
+
+    if user_version <= 13 {
+        report_migration(&mut progress, user_version, 13);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+`value_hash` is the blake2 hash of the uncompressed `value`. NULL if `value` is NULL, or if the
+value has been inserted before this column was introduced, in which case the hash is filled
+the first time it is requested. See `SqliteFullDatabase::block_storage_value_hash`.
+*/
+ALTER TABLE trie_node_storage ADD COLUMN value_hash BLOB;
+
+PRAGMA user_version = 14;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v14"))?
+    }
+
+    if user_version <= 14 {
+        report_migration(&mut progress, user_version, 14);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+Justifications of the blocks, indexed by the consensus engine they belong to. A block can have
+multiple justifications, each proving its finality through a different engine. The justifications
+were previously stored in the `justification` column of `blocks`, and were all GrandPa
+justifications.
+*/
+CREATE TABLE blocks_justifications(
+    hash BLOB NOT NULL,
+    engine_id BLOB NOT NULL,
+    justification BLOB NOT NULL,
+    PRIMARY KEY (hash, engine_id),
+    CHECK(length(hash) == 32),
+    CHECK(length(engine_id) == 4),
+    FOREIGN KEY (hash) REFERENCES blocks(hash) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+INSERT INTO blocks_justifications(hash, engine_id, justification)
+    SELECT hash, X'46524e4b', justification FROM blocks WHERE justification IS NOT NULL;  -- `FRNK`
+ALTER TABLE blocks DROP COLUMN justification;
+
+PRAGMA user_version = 15;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v15"))?
+    }
+
+    if user_version <= 15 {
+        report_migration(&mut progress, user_version, 15);
+        add_check_constraints(&database)?;
+    }
+
+    if user_version <= 16 {
+        report_migration(&mut progress, user_version, 16);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+The extrinsics of the block bodies are now stored in the `extrinsics` table, indexed by their
+blake2 hash, and `blocks_body` only refers to them. Competing forks frequently contain the same
+extrinsics, which are thus only stored once.
+*/
+CREATE TABLE extrinsics(
+    hash BLOB NOT NULL PRIMARY KEY,
+    bytes BLOB NOT NULL,
+    CHECK(length(hash) == 32)
+);
+INSERT OR IGNORE INTO extrinsics(hash, bytes) SELECT blake2_256(extrinsic), extrinsic FROM blocks_body;
+
+CREATE TABLE blocks_body_new(
+    hash BLOB NOT NULL,
+    idx INTEGER NOT NULL,
+    extrinsic_hash BLOB NOT NULL,
+    UNIQUE(hash, idx),
+    CHECK(length(hash) == 32),
+    CHECK(length(extrinsic_hash) == 32),
+    FOREIGN KEY (hash) REFERENCES blocks(hash) ON UPDATE CASCADE ON DELETE CASCADE,
+    FOREIGN KEY (extrinsic_hash) REFERENCES extrinsics(hash)
+);
+INSERT INTO blocks_body_new(hash, idx, extrinsic_hash)
+    SELECT hash, idx, blake2_256(extrinsic) FROM blocks_body;
+DROP TABLE blocks_body;
+ALTER TABLE blocks_body_new RENAME TO blocks_body;
+CREATE INDEX blocks_body_by_block ON blocks_body(hash);
+CREATE INDEX blocks_body_by_extrinsic ON blocks_body(extrinsic_hash);
+
+/*
+An extrinsic is removed once no block body refers to it anymore, including when the body is
+removed through the foreign key of `blocks_body`.
+*/
+CREATE TRIGGER blocks_body_release_extrinsic AFTER DELETE ON blocks_body
+BEGIN
+    DELETE FROM extrinsics WHERE hash = OLD.extrinsic_hash
+        AND NOT EXISTS(SELECT 1 FROM blocks_body WHERE extrinsic_hash = OLD.extrinsic_hash);
+END;
+
+PRAGMA user_version = 17;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v17"))?
+    }
+
+    if user_version <= 17 {
+        report_migration(&mut progress, user_version, 17);
+        database
+            .execute_batch(
+                r#"
+BEGIN IMMEDIATE;
+
+/*
+Blocks whose storage changes compared to their parent are stored in `storage_diffs`. See
+`SqliteFullDatabase::insert_storage_diff`. A block that isn't in this table doesn't have any
+diff, which is different from having an empty diff.
+*/
+CREATE TABLE blocks_storage_diff(
+    hash BLOB NOT NULL PRIMARY KEY,
+    CHECK(length(hash) == 32),
+    FOREIGN KEY (hash) REFERENCES blocks(hash) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+/*
+Keys of the main trie modified by each block of `blocks_storage_diff`, and their value after the
+block has been executed. `new_value` is NULL if the key has been removed.
+*/
+CREATE TABLE storage_diffs(
+    hash BLOB NOT NULL,
+    key BLOB NOT NULL,
+    new_value BLOB,
+    PRIMARY KEY (hash, key),
+    FOREIGN KEY (hash) REFERENCES blocks_storage_diff(hash) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+PRAGMA user_version = 18;
+
+COMMIT;
+        "#,
+            )
+            .map_err(internal_err("open/migration_v18"))?
+    }
+
+    // Migrations can rebuild entire tables, in which case the statistics used by the query
+    // planner are outdated. Newly-created databases don't contain anything to analyze.
+    let migrations_applied = u32::try_from(SCHEMA_VERSION - user_version.max(0)).unwrap_or(0);
+    if user_version > 0 && migrations_applied > 0 {
+        report_progress(&mut progress, OpenProgressStep::Analyzing);
+        database
+            .execute_batch("PRAGMA optimize")
+            .map_err(internal_err("open/optimize"))?;
+    }
+
+    // The indices removed during a bulk load are missing if the process has crashed while a
+    // bulk load was in progress. Read-only databases can be queried without them, albeit more
+    // slowly.
+    if !read_only {
+        bulk_load::create_deferred_indices(&database).map_err(internal_err("open/indices"))?;
+    }
+
+    let chain_id = if read_only {
+        existing_chain_id(&database, config.chain_name)?.ok_or(OpenError::ReadOnly)?
+    } else {
+        chain_id(&database, config.chain_name)?
+    };
+    set_current_chain(&database, chain_id)?;
+
+    let is_empty = database
+        .prepare_cached(
+            "SELECT COUNT(*) FROM meta WHERE chain_id = (SELECT id FROM current_chain) AND key = ?",
+        )
+        .map_err(internal_err("open/is_empty"))?
+        .query_row(("initialized",), |row| row.get::<_, i64>(0))
+        .map_err(internal_err("open/is_empty"))?
+        == 0;
+
+    // A chain that contains something but whose initialization isn't marked as complete has
+    // been interrupted in the middle of its initialization, and is reset to an empty state.
+    // Read-only databases are left as they are, as they can't be initialized anyway.
+    let discarded_incomplete_initialization = if is_empty && !read_only {
+        discard_incomplete_initialization(&database)?
+    } else {
+        false
+    };
+
+    // The read-only connections are opened only after the migrations, as they can't modify
+    // the schema. In-memory databases can't be shared between connections, and read-only
+    // operations are thus performed using the main connection.
+    let mut readers = Vec::new();
+    if let ConfigTy::Disk { path, .. } = config.ty {
+        for _ in 0..config.read_connections {
+            let reader = rusqlite::Connection::open_with_flags(
+                path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .map_err(internal_err("open/read_connection"))?;
+            configure_connection(&reader, &config)?;
+            set_current_chain(&reader, chain_id)?;
+            readers.push(parking_lot::Mutex::new(reader));
+        }
+    }
+
+    let path = match config.ty {
+        ConfigTy::Disk { path, .. } => Some(path.to_owned()),
+        ConfigTy::Memory => None,
+    };
+
+    let report = OpenReport {
+        schema_version_found: user_version,
+        migrations_applied,
+        duration: start.elapsed(),
+    };
+
+    Ok(if !is_empty {
+        let interrupt_handles = interrupt_handles(&database, &readers);
+        DatabaseOpen::Open(SqliteFullDatabase {
+            database: parking_lot::Mutex::new(database),
+            commit_policy: config.commit_policy,
+            body_retention: config.body_retention,
+            storage_diff_retention: config.storage_diff_retention,
+            store_events: config.store_events,
+            on_operation: config.on_operation,
+            auto_optimize_interval: config.auto_optimize_interval,
+            value_compression: config.value_compression,
+            statement_cache_capacity: config.statement_cache_capacity,
+            last_optimize: parking_lot::Mutex::new(Instant::now()),
+            analyze_pending: AtomicBool::new(false),
+            bulk_load_active: AtomicBool::new(false),
+            uncommitted_writes: parking_lot::Mutex::new(None),
+            statement_cache_stats: Default::default(),
+            readers,
+            next_reader: AtomicUsize::new(0),
+            path,
+            chain_name: config.chain_name.map(|name| name.to_owned()),
+            block_number_bytes: config.block_number_bytes, // TODO: consider storing this value in the DB and check it when opening
+            subscribers: events::Subscribers::new(),
+            interrupt_handles,
+            lock_file,
+            open_report: report,
+        })
+    } else {
+        DatabaseOpen::Empty(DatabaseEmpty {
+            database,
+            commit_policy: config.commit_policy,
+            body_retention: config.body_retention,
+            storage_diff_retention: config.storage_diff_retention,
+            store_events: config.store_events,
+            on_operation: config.on_operation,
+            auto_optimize_interval: config.auto_optimize_interval,
+            value_compression: config.value_compression,
+            statement_cache_capacity: config.statement_cache_capacity,
+            readers,
+            path,
+            chain_name: config.chain_name.map(|name| name.to_owned()),
+            block_number_bytes: config.block_number_bytes,
+            discarded_incomplete_initialization,
+            lock_file,
+            open_report: report,
+        })
+    })
+}
+
+/// Calls the progress callback passed to [`open`], if any.
+fn report_progress(progress: &mut Option<OpenProgressCallback>, step: OpenProgressStep) {
+    if let Some(progress) = progress {
+        progress(step);
+    }
+}
+
+/// Reports the start of the migration that upgrades the schema from `from_version`, while the
+/// schema version of the database was `user_version` when it has been opened.
+fn report_migration(
+    progress: &mut Option<OpenProgressCallback>,
+    user_version: i64,
+    from_version: i64,
+) {
+    let first_version = user_version.max(0);
+    report_progress(
+        progress,
+        OpenProgressStep::Migration {
+            index: u32::try_from(from_version - first_version + 1).unwrap_or(0),
+            total: u32::try_from(SCHEMA_VERSION - first_version).unwrap_or(0),
+        },
+    );
+}
+
+/// Takes the advisory lock of the database whose path is `path`, and returns the file that
+/// holds the lock. The lock is released when this file is closed.
+///
+/// The lock is held on a separate file whose name is the one of the database followed with
+/// `.lock`, as SQLite itself locks the database file. The lock covers the whole database rather
+/// than individual chains, as the migrations and the tables shared between chains (such as the
+/// trie nodes) are modified regardless of the chain being accessed. The identifier of the
+/// current process is written in this file, so that it can be reported by
+/// [`OpenError::AlreadyInUse`].
+fn lock_database(path: &Path, shared: bool) -> Result<std::fs::File, OpenError> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(OpenError::LockFile)?;
+
+    // The functions are called through the trait, as recent versions of the standard library
+    // provide inherent methods with the same names.
+    let result = if shared {
+        fs4::FileExt::try_lock_shared(&file)
+    } else {
+        fs4::FileExt::try_lock_exclusive(&file)
+    };
+    match result {
+        Ok(()) => {}
+        Err(err) if err.raw_os_error() == fs4::lock_contended_error().raw_os_error() => {
+            let mut content = String::new();
+            let pid_hint = io::Read::read_to_string(&mut file, &mut content)
+                .ok()
+                .and_then(|_| content.trim().parse::<u32>().ok());
+            return Err(OpenError::AlreadyInUse { pid_hint });
+        }
+        Err(err) => return Err(OpenError::LockFile(err)),
+    }
+
+    // Shared locks can be held by multiple processes at the same time, in which case the file
+    // is left untouched.
+    if !shared {
+        file.set_len(0).map_err(OpenError::LockFile)?;
+        io::Write::write_all(&mut file, std::process::id().to_string().as_bytes())
+            .map_err(OpenError::LockFile)?;
+    }
+
+    Ok(file)
+}
+
+/// Removes the blocks, the `meta` entries, the authorities, and the runtimes of the chain
+/// designated by `current_chain`, whose initialization hasn't been marked as complete. Returns
+/// `false` if there wasn't anything to remove.
+///
+/// The trie nodes are kept. Since they are identified by their Merkle value, they are reused if
+/// the same state is inserted again, which is typically the case when the initialization is
+/// restarted.
+fn discard_incomplete_initialization(
+    database: &rusqlite::Connection,
+) -> Result<bool, InternalError> {
+    let has_content = database
+        .prepare_cached(
+            r#"
+        SELECT EXISTS(SELECT 1 FROM blocks WHERE chain_id = (SELECT id FROM current_chain))
+            OR EXISTS(SELECT 1 FROM meta WHERE chain_id = (SELECT id FROM current_chain))"#,
+        )
+        .map_err(internal_err("open/discard_incomplete_initialization"))?
+        .query_row((), |row| row.get::<_, bool>(0))
+        .map_err(internal_err("open/discard_incomplete_initialization"))?;
+    if !has_content {
+        return Ok(false);
+    }
+
+    // The transaction is rolled back when dropped, in other words if any of the statements
+    // fails.
+    let transaction =
+        rusqlite::Transaction::new_unchecked(database, rusqlite::TransactionBehavior::Immediate)
+            .map_err(internal_err("open/discard_incomplete_initialization"))?;
+
+    // The bodies, events, and insertion information of the blocks are removed through the
+    // foreign keys.
+    transaction
+        .execute_batch(
+            r#"
+DELETE FROM blocks WHERE chain_id = (SELECT id FROM current_chain);
+DELETE FROM meta WHERE chain_id = (SELECT id FROM current_chain);
+DELETE FROM grandpa_triggered_authorities WHERE chain_id = (SELECT id FROM current_chain);
+DELETE FROM grandpa_scheduled_authorities WHERE chain_id = (SELECT id FROM current_chain);
+DELETE FROM aura_finalized_authorities WHERE chain_id = (SELECT id FROM current_chain);
+DELETE FROM runtimes WHERE chain_id = (SELECT id FROM current_chain);
+            "#,
+        )
+        .map_err(internal_err("open/discard_incomplete_initialization"))?;
+    transaction
+        .commit()
+        .map_err(internal_err("open/discard_incomplete_initialization"))?;
+    Ok(true)
+}
+
+/// Returns the handles that interrupt the queries of the main connection and of the read-only
+/// connections. See [`super::InterruptHandle`].
+fn interrupt_handles(
+    database: &rusqlite::Connection,
+    readers: &[parking_lot::Mutex<rusqlite::Connection>],
+) -> Arc<[rusqlite::InterruptHandle]> {
+    iter::once(database.get_interrupt_handle())
+        .chain(
+            readers
+                .iter()
+                .map(|reader| reader.lock().get_interrupt_handle()),
+        )
+        .collect()
+}
+
+/// Returns the identifier of the chain with the given name in the `chains` table, inserting it
+/// if it doesn't exist yet.
+pub(super) fn chain_id(
+    database: &rusqlite::Connection,
+    chain_name: Option<&str>,
+) -> Result<i64, InternalError> {
+    if let Some(id) = existing_chain_id(database, chain_name)? {
+        return Ok(id);
+    }
+
+    database
+        .prepare_cached("INSERT INTO chains(name) VALUES (?)")
+        .map_err(internal_err("chain_id"))?
+        .execute((chain_name,))
+        .map_err(internal_err("chain_id"))?;
+    Ok(database.last_insert_rowid())
+}
+
+/// Returns the identifier of the chain with the given name in the `chains` table, or `None` if
+/// it doesn't exist.
+fn existing_chain_id(
+    database: &rusqlite::Connection,
+    chain_name: Option<&str>,
+) -> Result<Option<i64>, InternalError> {
+    // `IS` is used rather than `=` so that a `NULL` name matches the default chain.
+    database
+        .prepare_cached("SELECT id FROM chains WHERE name IS ?")
+        .map_err(internal_err("chain_id"))?
+        .query_row((chain_name,), |row| row.get::<_, i64>(0))
+        .optional()
+        .map_err(internal_err("chain_id"))
+}
+
+/// Makes the queries performed through `database` access the chain with the given identifier.
+///
+/// The identifier is stored in the `current_chain` temporary table, which only exists within
+/// the connection, and that queries refer to in order to only access the rows that belong to
+/// that chain.
+pub(super) fn set_current_chain(
+    database: &rusqlite::Connection,
+    chain_id: i64,
+) -> Result<(), InternalError> {
+    database
+        .execute_batch(
+            "CREATE TEMP TABLE IF NOT EXISTS current_chain(id INTEGER NOT NULL); DELETE FROM current_chain;",
+        )
+        .map_err(internal_err("set_current_chain"))?;
+    database
+        .execute("INSERT INTO current_chain(id) VALUES (?)", (chain_id,))
+        .map_err(internal_err("set_current_chain"))?;
+    Ok(())
+}
+
+/// Returns a function that turns an error returned by SQLite during the given logical operation
+/// into an [`InternalError`].
+fn internal_err(operation: &'static str) -> impl Fn(rusqlite::Error) -> InternalError {
+    move |err| InternalError::new(operation, err)
+}
+
+/// Constraints added to the schema in version 16, as the name of the table and a condition that
+/// each row of this table must satisfy. Must match the `CHECK` clauses in
+/// [`add_check_constraints`].
+const CHECK_CONSTRAINTS: [(&str, &str); 9] = [
+    ("blocks", "length(hash) == 32"),
+    ("blocks", "parent_hash IS NULL OR length(parent_hash) == 32"),
+    (
+        "blocks",
+        "state_trie_root_hash IS NULL OR length(state_trie_root_hash) == 32",
+    ),
+    ("blocks", "number >= 0"),
+    ("trie_node_storage", "trie_entry_version IN (0, 1)"),
+    (
+        "trie_node_storage",
+        "value_hash IS NULL OR length(value_hash) == 32",
+    ),
+    ("grandpa_triggered_authorities", "weight != zeroblob(8)"),
+    ("grandpa_scheduled_authorities", "weight != zeroblob(8)"),
+    ("runtimes", "spec_version >= 0 AND heap_pages >= 0"),
+];
+
+/// Migration to version 16 of the schema, where `CHECK` constraints have been added to the
+/// columns whose content is otherwise only verified when it is read.
+///
+/// Adding a constraint to an existing table requires rebuilding the table, which would take a
+/// long time on a database that contains a full chain. The tables are thus only rebuilt if the
+/// database doesn't contain any block or storage value yet, which is normally the case of a
+/// database that has just been created. The existing rows of other databases are verified
+/// instead, and [`OpenError::InvalidRows`] is returned if some of them don't satisfy the
+/// constraints. The constraints are then enforced on the rows inserted or updated later through
+/// triggers.
+fn add_check_constraints(database: &rusqlite::Connection) -> Result<(), OpenError> {
+    let mut invalid_rows = Vec::new();
+    for (table, constraint) in CHECK_CONSTRAINTS {
+        let mut statement = database
+            .prepare(&format!(
+                "SELECT rowid FROM {table} WHERE NOT ({constraint})"
+            ))
+            .map_err(internal_err("open/migration_v16"))?;
+        let rowids = statement
+            .query_map((), |row| row.get::<_, i64>(0))
+            .map_err(internal_err("open/migration_v16"))?;
+        for rowid in rowids {
+            invalid_rows.push(InvalidRow {
+                table,
+                constraint,
+                rowid: rowid.map_err(internal_err("open/migration_v16"))?,
+            });
+        }
+    }
+    if !invalid_rows.is_empty() {
+        return Err(OpenError::InvalidRows(invalid_rows));
+    }
+
+    let is_empty = database
+        .query_row(
+            "SELECT NOT EXISTS(SELECT 1 FROM blocks) AND NOT EXISTS(SELECT 1 FROM trie_node_storage)",
+            (),
+            |row| row.get::<_, bool>(0),
+        )
+        .map_err(internal_err("open/migration_v16"))?;
+
+    if !is_empty {
+        // The triggers run after the row has been written, which makes it possible to verify
+        // the constraint against the row as it is stored. `RAISE(ABORT)` reverts the statement.
+        let mut triggers = String::from("BEGIN IMMEDIATE;\n");
+        for (index, (table, constraint)) in CHECK_CONSTRAINTS.iter().enumerate() {
+            for (name, event) in [("insert", "INSERT"), ("update", "UPDATE")] {
+                triggers.push_str(&format!(
+                    "CREATE TRIGGER {table}_check_{index}_{name} AFTER {event} ON {table} \
+                    WHEN EXISTS(SELECT 1 FROM {table} WHERE rowid = NEW.rowid AND NOT ({constraint})) \
+                    BEGIN SELECT RAISE(ABORT, 'CHECK constraint failed: {constraint}'); END;\n"
+                ));
+            }
+        }
+        triggers.push_str("PRAGMA user_version = 16;\nCOMMIT;");
+        database
+            .execute_batch(&triggers)
+            .map_err(internal_err("open/migration_v16"))?;
+        return Ok(());
+    }
+
+    database
+        .execute_batch(
+            r#"
+BEGIN IMMEDIATE;
+
+/*
+The tables are recreated in order to add `CHECK` constraints to them. The tables are empty or
+almost empty, and copying their content is thus cheap.
+*/
+CREATE TABLE blocks_new(
+    hash BLOB NOT NULL PRIMARY KEY,
+    parent_hash BLOB,  -- NULL only for the genesis block
+    state_trie_root_hash BLOB,  -- NULL if and only if the trie is empty or if the trie storage has been pruned from the database
+    number INTEGER NOT NULL,
+    header BLOB NOT NULL,
+    is_best_chain BOOLEAN NOT NULL,
+    body_pruned BOOLEAN NOT NULL DEFAULT FALSE,
+    runtime_spec_version INTEGER,
+    chain_id INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(number, hash),
+    CHECK(length(hash) == 32),
+    CHECK(parent_hash IS NULL OR length(parent_hash) == 32),
+    CHECK(state_trie_root_hash IS NULL OR length(state_trie_root_hash) == 32),
+    CHECK(number >= 0)
+);
+INSERT INTO blocks_new(hash, parent_hash, state_trie_root_hash, number, header, is_best_chain, body_pruned, runtime_spec_version, chain_id)
+    SELECT hash, parent_hash, state_trie_root_hash, number, header, is_best_chain, body_pruned, runtime_spec_version, chain_id FROM blocks;
+DROP TABLE blocks;
+ALTER TABLE blocks_new RENAME TO blocks;
+CREATE INDEX blocks_by_parent ON blocks(parent_hash);
+CREATE INDEX blocks_by_state_trie_root_hash ON blocks(state_trie_root_hash);
+CREATE INDEX blocks_by_best ON blocks(chain_id, number, is_best_chain, hash);
+
+CREATE TABLE trie_node_storage_new(
+    node_hash BLOB NOT NULL PRIMARY KEY,
+    value BLOB,
+    trie_root_ref BLOB,
+    trie_entry_version INTEGER NOT NULL,
+    value_compressed BOOLEAN NOT NULL DEFAULT FALSE,
+    value_hash BLOB,
+    FOREIGN KEY (node_hash) REFERENCES trie_node(hash) ON UPDATE CASCADE ON DELETE CASCADE,
+    CHECK((value IS NULL) != (trie_root_ref IS NULL)),
+    CHECK(trie_entry_version IN (0, 1)),
+    CHECK(value_hash IS NULL OR length(value_hash) == 32)
+);
+INSERT INTO trie_node_storage_new(node_hash, value, trie_root_ref, trie_entry_version, value_compressed, value_hash)
+    SELECT node_hash, value, trie_root_ref, trie_entry_version, value_compressed, value_hash FROM trie_node_storage;
+DROP TABLE trie_node_storage;
+ALTER TABLE trie_node_storage_new RENAME TO trie_node_storage;
+CREATE INDEX trie_node_storage_by_trie_root_ref ON trie_node_storage(trie_root_ref);
+
+CREATE TABLE grandpa_triggered_authorities_new(
+    chain_id INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+    public_key BLOB NOT NULL,
+    weight BLOB NOT NULL,
+    PRIMARY KEY (chain_id, idx),
+    CHECK(length(public_key) == 32),
+    CHECK(length(weight) == 8),
+    CHECK(weight != zeroblob(8))
+);
+INSERT INTO grandpa_triggered_authorities_new(chain_id, idx, public_key, weight)
+    SELECT chain_id, idx, public_key, weight FROM grandpa_triggered_authorities;
+DROP TABLE grandpa_triggered_authorities;
+ALTER TABLE grandpa_triggered_authorities_new RENAME TO grandpa_triggered_authorities;
+
+CREATE TABLE grandpa_scheduled_authorities_new(
+    chain_id INTEGER NOT NULL,
+    idx INTEGER NOT NULL,
+    public_key BLOB NOT NULL,
+    weight BLOB NOT NULL,
+    PRIMARY KEY (chain_id, idx),
+    CHECK(length(public_key) == 32),
+    CHECK(length(weight) == 8),
+    CHECK(weight != zeroblob(8))
+);
+INSERT INTO grandpa_scheduled_authorities_new(chain_id, idx, public_key, weight)
+    SELECT chain_id, idx, public_key, weight FROM grandpa_scheduled_authorities;
+DROP TABLE grandpa_scheduled_authorities;
+ALTER TABLE grandpa_scheduled_authorities_new RENAME TO grandpa_scheduled_authorities;
+
+CREATE TABLE runtimes_new(
+    chain_id INTEGER NOT NULL,
+    spec_version INTEGER NOT NULL,
+    code_hash BLOB NOT NULL,
+    code BLOB NOT NULL,
+    heap_pages INTEGER NOT NULL,
+    PRIMARY KEY (chain_id, spec_version),
+    CHECK(length(code_hash) == 32),
+    CHECK(spec_version >= 0 AND heap_pages >= 0)
+);
+INSERT INTO runtimes_new(chain_id, spec_version, code_hash, code, heap_pages)
+    SELECT chain_id, spec_version, code_hash, code, heap_pages FROM runtimes;
+DROP TABLE runtimes;
+ALTER TABLE runtimes_new RENAME TO runtimes;
+
+PRAGMA user_version = 16;
+
+COMMIT;
+        "#,
+        )
+        .map_err(internal_err("open/migration_v16"))?;
+    Ok(())
+}
+
+/// Number of rows of the `trie_node` table rewritten within each transaction by
+/// [`rewrite_trie_nodes_in_batches`].
+const TRIE_NODE_MIGRATION_BATCH_SIZE: i64 = 65536;
+
+/// Migration to version 10 of the schema, where the `partial_key` column of the `trie_node`
+/// table contains two nibbles per byte rather than one. See [`super::pack_nibbles`].
+///
+/// Because the `trie_node` table can be very large, the rows are rewritten in batches. See
+/// [`rewrite_trie_nodes_in_batches`].
+fn pack_partial_keys(database: &rusqlite::Connection) -> Result<(), InternalError> {
+    database
+        .execute_batch(
+            r#"
+BEGIN IMMEDIATE;
+
+/*
+Contains the `rowid` of the last row of `trie_node` whose `partial_key` has been packed by the
+migration to version 10. Only exists while this migration is in progress.
+*/
+CREATE TABLE IF NOT EXISTS trie_node_packing_progress(last_rowid INTEGER NOT NULL);
+INSERT INTO trie_node_packing_progress(last_rowid)
+    SELECT -1 WHERE NOT EXISTS(SELECT 1 FROM trie_node_packing_progress);
+
+COMMIT;
+        "#,
+        )
+        .map_err(internal_err("open/migration_v10"))?;
+
+    rewrite_trie_nodes_in_batches(
+        database,
+        "open/migration_v10",
+        "trie_node_packing_progress",
+        "PRAGMA user_version = 10;",
+        |batch_end| {
+            database
+                .prepare_cached(
+                    r#"
+            UPDATE trie_node SET partial_key = pack_nibbles(partial_key)
+            WHERE rowid > (SELECT last_rowid FROM trie_node_packing_progress) AND rowid <= :batch_end"#,
+                )?
+                .execute(rusqlite::named_params! { ":batch_end": batch_end })?;
+            Ok(())
+        },
+    )
+}
+
+/// Migration to version 12 of the schema, where the children of each trie node are stored in
+/// the `children` column of the `trie_node` table rather than in the `trie_node_child` table.
+/// See [`super::encode_children`].
+///
+/// Because the `trie_node` table can be very large, the rows are rewritten in batches. See
+/// [`rewrite_trie_nodes_in_batches`].
+fn store_children_in_trie_node(database: &rusqlite::Connection) -> Result<(), InternalError> {
+    database
+        .execute_batch("BEGIN IMMEDIATE")
+        .map_err(internal_err("open/migration_v12"))?;
+
+    // The progress table is created within the same transaction as the new columns and
+    // tables, and its existence thus indicates that the migration has already started.
+    let in_progress = database
+        .prepare_cached(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'trie_node_children_progress')",
+        )
+        .map_err(internal_err("open/migration_v12"))?
+        .query_row((), |row| row.get::<_, bool>(0))
+        .map_err(internal_err("open/migration_v12"))?;
+    if !in_progress {
+        database
+            .execute_batch(
+                r#"
+/*
+`children` contains the Merkle values of all the children of the node. See `encode_children`.
+NULL if the node doesn't have any child.
+*/
+ALTER TABLE trie_node ADD COLUMN children BLOB;
+
+/*
+Reverse of the `children` column of `trie_node`: contains one entry for each child of each
+trie node. Used in order to determine whether a trie node is still referenced by another node.
+*/
+CREATE TABLE trie_node_parent(
+    child_hash BLOB NOT NULL,
+    hash BLOB NOT NULL,
+    PRIMARY KEY (child_hash, hash)
+) WITHOUT ROWID;
+
+/*
+Contains the `rowid` of the last row of `trie_node` whose `children` has been filled by the
+migration to version 12. Only exists while this migration is in progress.
+*/
+CREATE TABLE trie_node_children_progress(last_rowid INTEGER NOT NULL);
+INSERT INTO trie_node_children_progress(last_rowid) VALUES (-1);
+            "#,
+            )
+            .map_err(internal_err("open/migration_v12"))?;
+    }
+
+    database
+        .execute_batch("COMMIT")
+        .map_err(internal_err("open/migration_v12"))?;
+
+    rewrite_trie_nodes_in_batches(
+        database,
+        "open/migration_v12",
+        "trie_node_children_progress",
+        r#"
+DROP TABLE trie_node_child;
+PRAGMA user_version = 12;
+        "#,
+        |batch_end| {
+            let mut statement = database.prepare_cached(
+                r#"
+            SELECT trie_node_child.hash, trie_node_child.child_num, trie_node_child.child_hash
+            FROM trie_node
+            JOIN trie_node_child ON trie_node_child.hash = trie_node.hash
+            WHERE trie_node.rowid > (SELECT last_rowid FROM trie_node_children_progress)
+                AND trie_node.rowid <= ?
+            ORDER BY trie_node.rowid, trie_node_child.child_num"#,
+            )?;
+            let mut children = statement
+                .query_map((batch_end,), |row| {
+                    let child_num = row.get::<_, Vec<u8>>(1)?;
+                    let child_hash = row.get::<_, Vec<u8>>(2)?;
+                    // Merkle values are never longer than 32 bytes, but this isn't enforced by
+                    // the schema.
+                    let (&[child_num], true) = (&child_num[..], child_hash.len() <= 255) else {
+                        return Err(rusqlite::Error::FromSqlConversionFailure(
+                            1,
+                            rusqlite::types::Type::Blob,
+                            "invalid child in trie_node_child".into(),
+                        ));
+                    };
+                    Ok((row.get::<_, Vec<u8>>(0)?, child_num, child_hash))
+                })?
+                .peekable();
+
+            while let Some(first) = children.next() {
+                let (hash, child_num, child_hash) = first?;
+                let mut node_children: [Option<Vec<u8>>; 16] = Default::default();
+                node_children[usize::from(child_num)] = Some(child_hash);
+                while let Some(Ok((next_hash, ..))) = children.peek() {
+                    if *next_hash != hash {
+                        break;
+                    }
+                    let (_, child_num, child_hash) = children.next().unwrap()?;
+                    node_children[usize::from(child_num)] = Some(child_hash);
+                }
+
+                database
+                    .prepare_cached("UPDATE trie_node SET children = ? WHERE hash = ?")?
+                    .execute((
+                        super::encode_children(node_children.iter().map(|c| c.as_deref())),
+                        &hash,
+                    ))?;
+                for child_hash in node_children.iter().flatten() {
+                    database
+                        .prepare_cached(
+                            "INSERT OR IGNORE INTO trie_node_parent(child_hash, hash) VALUES(?, ?)",
+                        )?
+                        .execute((child_hash, &hash))?;
+                }
+            }
+
+            Ok(())
+        },
+    )
+}
+
+/// Calls `rewrite_batch` with successive batches of rows of the `trie_node` table, in increasing
+/// `rowid` order, then executes `final_statements`.
+///
+/// `progress_table` must be the name of an existing table containing one row with one column
+/// named `last_rowid`. The batch passed to `rewrite_batch` consists of the rows whose `rowid` is
+/// strictly superior to this `last_rowid` and inferior or equal to the parameter.
+///
+/// Each batch is rewritten within its own transaction, and the progress table is updated
+/// within the same transaction, meaning that an interrupted migration resumes where it has
+/// stopped the next time the database is opened. Once all the rows have been rewritten, the
+/// progress table is removed within the same transaction as `final_statements`, which must
+/// update the schema version, so that the rows can't be rewritten twice.
+fn rewrite_trie_nodes_in_batches(
+    database: &rusqlite::Connection,
+    operation: &'static str,
+    progress_table: &str,
+    final_statements: &str,
+    mut rewrite_batch: impl FnMut(i64) -> Result<(), rusqlite::Error>,
+) -> Result<(), InternalError> {
+    loop {
+        database
+            .execute_batch("BEGIN IMMEDIATE")
+            .map_err(internal_err(operation))?;
+
+        let batch_end = database
+            .prepare_cached(&format!(
+                r#"
+            SELECT MAX(rowid) FROM (
+                SELECT rowid FROM trie_node
+                WHERE rowid > (SELECT last_rowid FROM {progress_table})
+                ORDER BY rowid
+                LIMIT ?
+            )"#
+            ))
+            .map_err(internal_err(operation))?
+            .query_row((TRIE_NODE_MIGRATION_BATCH_SIZE,), |row| {
+                row.get::<_, Option<i64>>(0)
+            })
+            .map_err(internal_err(operation))?;
+
+        let Some(batch_end) = batch_end else {
+            database
+                .execute_batch(&format!(
+                    "DROP TABLE {progress_table}; {final_statements} COMMIT;"
+                ))
+                .map_err(internal_err(operation))?;
+            return Ok(());
+        };
+
+        rewrite_batch(batch_end).map_err(internal_err(operation))?;
+        database
+            .prepare_cached(&format!("UPDATE {progress_table} SET last_rowid = ?"))
+            .map_err(internal_err(operation))?
+            .execute((batch_end,))
+            .map_err(internal_err(operation))?;
+        database
+            .execute_batch("COMMIT")
+            .map_err(internal_err(operation))?;
+    }
+}
+
+/// Registers on `database` the SQL functions used by the queries.
+///
+/// - `pack_nibbles(nibbles)` and `unpack_nibbles(packed)` convert between a list of nibbles,
+///   one per byte, and the format of the `partial_key` column of the `trie_node` table. See
+///   [`super::pack_nibbles`]. Both return `NULL` if their parameter is `NULL`.
+/// - `decompress_value(value, compressed)` returns `value` if `compressed` is false, or the
+///   decompressed `value` if `compressed` is true. Designed to be applied to the `value` and
+///   `value_compressed` columns of the `trie_node_storage` table. Returns `NULL` if `value` is
+///   `NULL`.
+/// - `child_merkle_value(children, child_num)` returns the Merkle value of the child whose
+///   index is `child_num` given the value of the `children` column of the `trie_node` table.
+///   See [`super::encode_children`]. `child_num` must be a blob containing one single byte.
+///   Returns `NULL` if `children` is `NULL`, if the child doesn't exist, or if `child_num` is
+///   `NULL`, empty, or superior or equal to 16, which simplifies the queries that walk down
+///   the trie.
+/// - `blake2_256(value)` returns the 32 bytes blake2 hash of `value`, which must be a blob.
+pub(super) fn register_functions(database: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    let flags = || {
+        rusqlite::functions::FunctionFlags::SQLITE_UTF8
+            | rusqlite::functions::FunctionFlags::SQLITE_DETERMINISTIC
+    };
+
+    database.create_scalar_function("pack_nibbles", 1, flags(), |context| {
+        match context.get_raw(0) {
+            rusqlite::types::ValueRef::Null => Ok(None),
+            rusqlite::types::ValueRef::Blob(nibbles) if nibbles.iter().all(|n| *n < 16) => {
+                Ok(Some(super::pack_nibbles(nibbles)))
+            }
+            _ => Err(rusqlite::Error::UserFunctionError(
+                "pack_nibbles: invalid nibbles".into(),
+            )),
+        }
+    })?;
+
+    database.create_scalar_function("unpack_nibbles", 1, flags(), |context| {
+        match context.get_raw(0) {
+            rusqlite::types::ValueRef::Null => Ok(None),
+            rusqlite::types::ValueRef::Blob(packed) => {
+                super::unpack_nibbles(packed).map(Some).ok_or_else(|| {
+                    rusqlite::Error::UserFunctionError("unpack_nibbles: invalid format".into())
+                })
+            }
+            _ => Err(rusqlite::Error::UserFunctionError(
+                "unpack_nibbles: invalid format".into(),
+            )),
+        }
+    })?;
+
+    database.create_scalar_function("decompress_value", 2, flags(), |context| {
+        match context.get_raw(0) {
+            rusqlite::types::ValueRef::Null => Ok(None),
+            rusqlite::types::ValueRef::Blob(value) if !context.get::<bool>(1)? => {
+                Ok(Some(value.to_vec()))
+            }
+            rusqlite::types::ValueRef::Blob(value) => {
+                zstd::decode_all(value).map(Some).map_err(|err| {
+                    rusqlite::Error::UserFunctionError(
+                        format!("decompress_value: invalid zstd data: {err}").into(),
+                    )
+                })
+            }
+            _ => Err(rusqlite::Error::UserFunctionError(
+                "decompress_value: invalid value".into(),
+            )),
+        }
+    })?;
+
+    database.create_scalar_function("child_merkle_value", 2, flags(), |context| {
+        let children = match context.get_raw(0) {
+            rusqlite::types::ValueRef::Null => return Ok(None),
+            rusqlite::types::ValueRef::Blob(children) => children,
+            _ => {
+                return Err(rusqlite::Error::UserFunctionError(
+                    "child_merkle_value: invalid children".into(),
+                ))
+            }
+        };
+        let child_num = match context.get_raw(1) {
+            rusqlite::types::ValueRef::Blob(&[child_num]) if child_num < 16 => child_num,
+            _ => return Ok(None),
+        };
+        let children = super::decode_children(children).ok_or_else(|| {
+            rusqlite::Error::UserFunctionError("child_merkle_value: invalid children".into())
+        })?;
+        Ok(children[usize::from(child_num)].map(|child| child.to_vec()))
+    })?;
+
+    database.create_scalar_function("blake2_256", 1, flags(), |context| {
+        match context.get_raw(0) {
+            rusqlite::types::ValueRef::Blob(value) => Ok(super::blake2_hash(value).to_vec()),
+            _ => Err(rusqlite::Error::UserFunctionError(
+                "blake2_256: invalid value".into(),
+            )),
+        }
+    })?;
+
+    Ok(())
+}
+
+/// Applies to `database` the settings that are common to the main connection and the read-only
+/// connections.
+fn configure_connection(
+    database: &rusqlite::Connection,
+    config: &Config,
+) -> Result<(), InternalError> {
+    // When another connection (for example a backup tool or the `sqlite3` CLI) holds a lock on
+    // the database, SQLite sleeps and retries for at most this duration before returning
+    // `SQLITE_BUSY`.
+    database
+        .busy_timeout(config.busy_timeout)
+        .map_err(internal_err("configure_connection"))?;
+
+    register_functions(database).map_err(internal_err("configure_connection"))?;
+
+    // The underlying SQLite wrapper maintains a cache of prepared statements.
+    database.set_prepared_statement_cache_capacity(config.statement_cache_capacity);
+
+    // `PRAGMA` queries can't be parametrized, and thus we have to use `format!`.
+    database
+        .execute(
+            &format!(
+                "PRAGMA cache_size = {}",
+                0i64.saturating_sub_unsigned(
+                    u64::try_from((config.cache_size.saturating_sub(1) / 1024).saturating_add(1))
+                        .unwrap_or(u64::max_value()),
+                )
+            ),
+            (),
+        )
+        .map_err(internal_err("configure_connection"))?;
+
+    // `PRAGMA` queries can't be parametrized, and thus we have to use `format!`.
+    if let ConfigTy::Disk {
+        memory_map_size, ..
+    } = config.ty
+    {
+        database
+            .execute_batch(&format!("PRAGMA mmap_size = {}", memory_map_size))
+            .map_err(internal_err("configure_connection"))?;
+    }
+
+    Ok(())
+}
+
+/// Configuration for the database.
+pub struct Config<'a> {
+    /// Type of database.
+    pub ty: ConfigTy<'a>,
+
+    /// Number of bytes used to encode the block number.
+    pub block_number_bytes: usize,
+
+    /// Name of the chain to access within the database.
+    ///
+    /// A single database can contain the blocks of multiple chains, for example the ones of a
+    /// main network and of a test network, which share the same file and the same trie nodes.
+    /// The returned [`SqliteFullDatabase`] only ever accesses the blocks of the chain with this
+    /// name. If no chain with this name is in the database yet, [`open`] returns
+    /// [`DatabaseOpen::Empty`].
+    ///
+    /// If `None`, accesses the chain that doesn't have a name, which is in particular the one
+    /// that contains the blocks stored by previous versions of this code.
+    pub chain_name: Option<&'a str>,
+
+    /// Maximum allowed size, in bytes, of the SQLite cache.
+    pub cache_size: usize,
+
+    /// Maximum number of prepared statements kept in the cache of each connection.
+    ///
+    /// Preparing some of the statements, such as the ones that access the storage of a block,
+    /// is expensive. If this value is inferior to the number of different statements used,
+    /// statements are regularly evicted from the cache and have to be prepared again. A value
+    /// of `128` is enough. See [`SqliteFullDatabase::statement_cache_stats`].
+    pub statement_cache_capacity: usize,
+
+    /// Maximum duration to wait for when the database is locked by another connection, for
+    /// example a backup job or the `sqlite3` CLI.
+    ///
+    /// If the lock is still held after this duration, the operation returns
+    /// [`super::CorruptedError::Busy`] or [`super::StorageAccessError::Busy`].
+    pub busy_timeout: Duration,
+
+    /// Number of read-only connections to open in addition to the main connection.
+    ///
+    /// Methods that only read from the database use these connections, so that they can run
+    /// in parallel with each other and with a write. If 0, or if the database is in memory, all
+    /// the operations go through the main connection.
+    ///
+    /// Each connection has its own cache whose size is [`Config::cache_size`].
+    pub read_connections: usize,
+
+    /// When to commit the modifications to the database. See [`CommitPolicy`].
+    pub commit_policy: CommitPolicy,
+
+    /// If `Some`, [`SqliteFullDatabase::set_finalized`] removes the bodies of the blocks whose
+    /// number is inferior to the number of the new finalized block minus this value. In other
+    /// words, the bodies of the finalized block and of this number of its ancestors are kept.
+    ///
+    /// Headers are always kept. See [`SqliteFullDatabase::prune_bodies_below`].
+    pub body_retention: Option<u64>,
+
+    /// If `Some`, [`SqliteFullDatabase::set_finalized`] removes the storage diffs of the blocks
+    /// whose number is inferior to the number of the new finalized block minus this value.
+    ///
+    /// See [`SqliteFullDatabase::insert_storage_diff`] and
+    /// [`SqliteFullDatabase::prune_storage_diffs_below`].
+    pub storage_diff_retention: Option<u64>,
+
+    /// If `false`, [`SqliteFullDatabase::set_block_events`] doesn't store anything, in order to
+    /// not waste space on nodes that don't need the events of blocks.
+    pub store_events: bool,
+
+    /// If `Some`, called after each operation performed on the database with the duration of
+    /// this operation. See [`super::OperationKind`] for the list of operations that are reported.
+    ///
+    /// The callback is always invoked after the database has been unlocked, and a slow
+    /// callback thus doesn't slow down the operations performed in parallel.
+    pub on_operation: Option<OperationCallback>,
+
+    /// If `Some`, the modifications of the database also run `PRAGMA optimize` if at least this
+    /// duration has elapsed since the last time it has been run, in order to keep the query
+    /// plans efficient. See [`SqliteFullDatabase::maintain`].
+    ///
+    /// `PRAGMA optimize` is always run when the [`SqliteFullDatabase`] is destroyed.
+    pub auto_optimize_interval: Option<Duration>,
+
+    /// If `Some`, the storage values inserted with
+    /// [`SqliteFullDatabase::insert_trie_nodes_versioned`] whose size is at least
+    /// [`CompressionConfig::threshold`] are compressed with zstd.
+    ///
+    /// Compressed values are transparently decompressed when they are read. A database can
+    /// contain both compressed and uncompressed values, and this option can be modified between
+    /// two openings of the same database. Values that have already been inserted are left as
+    /// they are.
+    pub value_compression: Option<CompressionConfig>,
+
+    /// If `false`, [`open`] returns [`OpenError::AlreadyInUse`] if the database is already
+    /// opened by another [`SqliteFullDatabase`], whether in the same process or in a different
+    /// one, and no matter which chain it accesses. Two writers accessing the same database would
+    /// otherwise regularly fail with [`super::CorruptedError::Busy`].
+    ///
+    /// If `true`, the database can be opened multiple times at the same time, provided that all
+    /// of them also pass `true`. This is intended for processes that only read from the
+    /// database, such as inspection tools. The database is then opened in read-only mode, and
+    /// all the modifications fail with [`super::CorruptedError::ReadOnly`]. [`open`] returns
+    /// [`OpenError::ReadOnly`] if the database must be modified before it can be used.
+    ///
+    /// Has no effect on in-memory databases.
+    pub allow_shared_readers: bool,
+
+    /// If `Some`, called by [`open`] whenever it starts a new step of the opening. Opening a
+    /// database whose schema must be upgraded can take several minutes, and this callback makes
+    /// it possible to report the progress of the upgrade.
+    ///
+    /// See also [`DatabaseOpen::report`].
+    pub progress: Option<OpenProgressCallback<'a>>,
+}
+
+/// Callback invoked by [`open`]. See [`Config::progress`].
+pub type OpenProgressCallback<'a> = Box<dyn FnMut(OpenProgressStep) + 'a>;
+
+/// Step of [`open`] that is about to start. See [`Config::progress`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpenProgressStep {
+    /// The database file is being locked and opened.
+    Opening,
+    /// The configuration and the schema version of the database are being verified.
+    VerifyingMeta,
+    /// The schema of the database is being upgraded. Each migration upgrades the schema by one
+    /// version, and a database created by an old version of this code can require many of them.
+    Migration {
+        /// Position of this migration, starting from 1.
+        index: u32,
+        /// Number of migrations that are applied in total.
+        total: u32,
+    },
+    /// The statistics used by SQLite in order to optimize queries are being updated after the
+    /// schema has been upgraded.
+    Analyzing,
+}
+
+/// Information about the opening of the database. See [`DatabaseOpen::report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenReport {
+    /// Schema version that the database had before being opened. `0` for a database that has
+    /// just been created.
+    pub schema_version_found: i64,
+    /// Number of migrations that have been applied in order to upgrade the schema.
+    pub migrations_applied: u32,
+    /// Time that [`open`] has taken.
+    pub duration: Duration,
+}
+
+impl<'a> fmt::Debug for Config<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("ty", &self.ty)
+            .field("block_number_bytes", &self.block_number_bytes)
+            .field("chain_name", &self.chain_name)
+            .field("cache_size", &self.cache_size)
+            .field("statement_cache_capacity", &self.statement_cache_capacity)
+            .field("busy_timeout", &self.busy_timeout)
+            .field("read_connections", &self.read_connections)
+            .field("commit_policy", &self.commit_policy)
+            .field("body_retention", &self.body_retention)
+            .field("storage_diff_retention", &self.storage_diff_retention)
+            .field("store_events", &self.store_events)
+            .field("auto_optimize_interval", &self.auto_optimize_interval)
+            .field("value_compression", &self.value_compression)
+            .field("allow_shared_readers", &self.allow_shared_readers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How to compress storage values. See [`Config::value_compression`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Minimum size, in bytes, of a storage value for it to be compressed.
+    ///
+    /// Small values compress poorly, and decompressing them would slow down the storage
+    /// accesses for no benefit. Values whose compressed size isn't smaller than their original
+    /// size are stored uncompressed regardless of this threshold.
+    pub threshold: usize,
+
+    /// zstd compression level, between 1 and 22. Higher levels compress better but are slower.
+    /// The level doesn't affect the speed of decompression.
+    pub level: i32,
+}
+
+/// When to commit the modifications performed on the database.
+///
+/// Committing a modification guarantees that it survives a crash or a power failure, but
+/// requires flushing the data to the disk, which is slow. Grouping multiple modifications in
+/// the same commit makes it possible to insert blocks faster.
+///
+/// Modifications that haven't been committed yet are visible to all the methods of the
+/// [`SqliteFullDatabase`], and can be committed at any time using
+/// [`SqliteFullDatabase::flush`]. They are also committed when the [`SqliteFullDatabase`] is
+/// destroyed.
+///
+/// In case of a crash, the modifications that haven't been committed are lost, but each
+/// individual modification (for example a call to [`SqliteFullDatabase::insert`]) is either
+/// entirely lost or entirely kept. In other words, the database is always left in a state
+/// corresponding to a moment in time between two modifications.
+///
+/// > **Note**: The policy is only checked after each modification. No commit happens in the
+/// >           background.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CommitPolicy {
+    /// Commit after every modification.
+    EveryOperation,
+    /// Commit once this number of blocks have been inserted since the previous commit. Other
+    /// modifications, such as [`SqliteFullDatabase::set_finalized`], are committed alongside.
+    EveryNBlocks(NonZeroU64),
+    /// Commit after a modification if the oldest uncommitted modification has been performed
+    /// at least this long ago.
+    EveryDuration(Duration),
+}
+
+/// Type of database.
+#[derive(Debug)]
+pub enum ConfigTy<'a> {
+    /// Store the database on disk.
+    Disk {
+        /// Path to the directory containing the database.
+        path: &'a Path,
+        /// Maximum allowed amount of memory, in bytes, that SQLite will reserve to memory-map
+        /// files.
+        memory_map_size: usize,
+    },
+    /// Store the database in memory. The database is discarded on destruction.
+    Memory,
+}
+
+/// Error potentially returned by [`open`].
+#[derive(Debug, derive_more::Display)]
+pub enum OpenError {
+    /// Some rows of an existing database don't satisfy the constraints of the current schema,
+    /// which indicates that the database is corrupted.
+    #[display(fmt = "{} row(s) of the database are invalid", "_0.len()")]
+    InvalidRows(Vec<InvalidRow>),
+    /// Error while accessing the database.
+    #[display(fmt = "{_0}")]
+    Internal(InternalError),
+    /// The database is already opened, either by another process or within the same process.
+    /// See [`Config::allow_shared_readers`].
+    #[display(
+        fmt = "Database is already in use{}",
+        "pid_hint.map(|pid| format!(\" by process {pid}\")).unwrap_or_default()"
+    )]
+    AlreadyInUse {
+        /// Identifier of the process that has opened the database, if known. This information
+        /// is only indicative, as the process might have stopped since then.
+        pid_hint: Option<u32>,
+    },
+    /// Error while creating or locking the lock file of the database.
+    #[display(fmt = "Failed to lock the database: {_0}")]
+    LockFile(std::io::Error),
+    /// [`Config::allow_shared_readers`] is `true`, but the database must be modified before it
+    /// can be used, because its schema must be upgraded or because it doesn't contain the chain
+    /// yet. The database must first be opened with [`Config::allow_shared_readers`] set to
+    /// `false`.
+    #[display(fmt = "Database must be modified but is opened in read-only mode")]
+    ReadOnly,
+}
+
+impl From<InternalError> for OpenError {
+    fn from(err: InternalError) -> Self {
+        OpenError::Internal(err)
+    }
+}
+
+impl std::error::Error for OpenError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OpenError::Internal(err) => Some(err),
+            OpenError::LockFile(err) => Some(err),
+            OpenError::InvalidRows(_) | OpenError::AlreadyInUse { .. } | OpenError::ReadOnly => {
+                None
+            }
+        }
+    }
+}
+
+/// Row of the database that doesn't satisfy a constraint. See [`OpenError::InvalidRows`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRow {
+    /// Name of the table containing the row.
+    pub table: &'static str,
+    /// SQL condition that the row doesn't satisfy.
+    pub constraint: &'static str,
+    /// Identifier of the row within its table.
+    pub rowid: i64,
+}
+
+/// Either existing database or database prototype.
+pub enum DatabaseOpen {
+    /// A database already existed and has now been opened.
+    Open(SqliteFullDatabase),
+
+    /// Either a database has just been created, or there existed a database but it is empty.
+    ///
+    /// > **Note**: The situation where a database existed but is empty can happen if you have
+    /// >           previously called [`open`] then dropped the [`DatabaseOpen`] object without
+    /// >           filling the newly-created database with data.
+    ///
+    /// A database whose initialization has been interrupted, for example because the process
+    /// has been killed, is also considered as empty, and what had been inserted is discarded.
+    /// See [`DatabaseEmpty::discarded_incomplete_initialization`].
+    Empty(DatabaseEmpty),
+}
+
+impl DatabaseOpen {
+    /// Returns information about how the database has been opened.
+    pub fn report(&self) -> &OpenReport {
+        match self {
+            DatabaseOpen::Open(database) => database.open_report(),
+            DatabaseOpen::Empty(database) => database.open_report(),
+        }
+    }
+}
+
+/// An open database. Holds file descriptors.
+pub struct DatabaseEmpty {
+    /// See the similar field in [`SqliteFullDatabase`].
+    database: rusqlite::Connection,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    commit_policy: CommitPolicy,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    body_retention: Option<u64>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    storage_diff_retention: Option<u64>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    store_events: bool,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    on_operation: Option<OperationCallback>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    auto_optimize_interval: Option<Duration>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    value_compression: Option<CompressionConfig>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    statement_cache_capacity: usize,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    readers: Vec<parking_lot::Mutex<rusqlite::Connection>>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    path: Option<PathBuf>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    chain_name: Option<String>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    pub(super) block_number_bytes: usize,
+
+    /// See [`DatabaseEmpty::discarded_incomplete_initialization`].
+    discarded_incomplete_initialization: bool,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    lock_file: Option<std::fs::File>,
+
+    /// See the similar field in [`SqliteFullDatabase`].
+    open_report: OpenReport,
+}
+
+impl DatabaseEmpty {
+    /// Returns `true` if the database contained the beginning of an initialization that has
+    /// been interrupted, and that [`open`] has discarded.
+    pub fn discarded_incomplete_initialization(&self) -> bool {
+        self.discarded_incomplete_initialization
+    }
+
+    /// Returns information about how the database has been opened. See [`DatabaseOpen::report`].
+    pub fn open_report(&self) -> &OpenReport {
+        &self.open_report
+    }
+
+    /// Inserts the given [`chain_information::ChainInformationRef`] in the database prototype in
+    /// order to turn it into an actual database.
+    ///
+    /// Must also pass the body and justification of the finalized block.
+    ///
+    /// The initialization is marked as complete before this function returns. If the storage of
+    /// the finalized block must be inserted as part of the initialization, use
+    /// [`DatabaseEmpty::initialize_incomplete`] instead.
+    pub fn initialize<'a>(
+        self,
+        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
+        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
+        finalized_block_justification: Option<Vec<u8>>,
+    ) -> Result<SqliteFullDatabase, ResetError> {
+        let database = self.initialize_incomplete(
+            chain_information,
+            finalized_block_body,
+            finalized_block_justification,
+        )?;
+        database.finish_initialization()?;
+        Ok(database)
+    }
+
+    /// Same as [`DatabaseEmpty::initialize`], but doesn't mark the initialization as complete.
+    ///
+    /// [`SqliteFullDatabase::finish_initialization`] must be called once everything that is
+    /// part of the initialization, such as the trie nodes of the finalized block, has been
+    /// inserted. Until then, [`open`] considers the database as empty and discards its content.
+    pub fn initialize_incomplete<'a>(
+        self,
+        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
+        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
+        finalized_block_justification: Option<Vec<u8>>,
+    ) -> Result<SqliteFullDatabase, ResetError> {
+        let interrupt_handles = interrupt_handles(&self.database, &self.readers);
+        let database = SqliteFullDatabase {
+            database: parking_lot::Mutex::new(self.database),
+            commit_policy: self.commit_policy,
+            body_retention: self.body_retention,
+            storage_diff_retention: self.storage_diff_retention,
+            store_events: self.store_events,
+            on_operation: self.on_operation,
+            auto_optimize_interval: self.auto_optimize_interval,
+            value_compression: self.value_compression,
+            statement_cache_capacity: self.statement_cache_capacity,
+            last_optimize: parking_lot::Mutex::new(Instant::now()),
+            analyze_pending: AtomicBool::new(false),
+            bulk_load_active: AtomicBool::new(false),
+            uncommitted_writes: parking_lot::Mutex::new(None),
+            statement_cache_stats: Default::default(),
+            readers: self.readers,
+            next_reader: AtomicUsize::new(0),
+            path: self.path,
+            chain_name: self.chain_name,
+            block_number_bytes: self.block_number_bytes,
+            subscribers: events::Subscribers::new(),
+            interrupt_handles,
+            lock_file: self.lock_file,
+            open_report: self.open_report,
+        };
+
+        database.reset(
+            chain_information,
+            finalized_block_body,
+            finalized_block_justification,
+        )?;
+
+        database.flush()?;
+
+        Ok(database)
+    }
+
+    /// Turns this prototype into a [`SqliteFullDatabase`] without inserting anything.
+    ///
+    /// The caller is responsible for filling the database before it is used, for example by
+    /// copying the content of another database into it.
+    pub(super) fn into_database_uninitialized(self) -> SqliteFullDatabase {
+        let interrupt_handles = interrupt_handles(&self.database, &self.readers);
+        SqliteFullDatabase {
+            database: parking_lot::Mutex::new(self.database),
+            commit_policy: self.commit_policy,
+            body_retention: self.body_retention,
+            storage_diff_retention: self.storage_diff_retention,
+            store_events: self.store_events,
+            on_operation: self.on_operation,
+            auto_optimize_interval: self.auto_optimize_interval,
+            value_compression: self.value_compression,
+            statement_cache_capacity: self.statement_cache_capacity,
+            last_optimize: parking_lot::Mutex::new(Instant::now()),
+            analyze_pending: AtomicBool::new(false),
+            bulk_load_active: AtomicBool::new(false),
+            uncommitted_writes: parking_lot::Mutex::new(None),
+            statement_cache_stats: Default::default(),
+            readers: self.readers,
+            next_reader: AtomicUsize::new(0),
+            path: self.path,
+            chain_name: self.chain_name,
+            block_number_bytes: self.block_number_bytes,
+            subscribers: events::Subscribers::new(),
+            interrupt_handles,
+            lock_file: self.lock_file,
+            open_report: self.open_report,
+        }
     }
 }
@@ -0,0 +1,155 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Execution of arbitrary read-only SQL queries.
+//!
+//! See [`SqliteFullDatabase::query_read_only`].
+
+use super::{sqlite_err, CorruptedError, InternalError, OperationKind, SqliteFullDatabase};
+
+use alloc::vec::Vec;
+use std::time::{Duration, Instant};
+
+/// Maximum number of rows that [`SqliteFullDatabase::query_read_only`] can return.
+pub const QUERY_MAX_ROWS: usize = 100_000;
+
+/// Maximum duration of a query executed with [`SqliteFullDatabase::query_read_only`].
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of SQLite virtual machine instructions between two verifications of the duration of
+/// a query.
+const PROGRESS_HANDLER_PERIOD: i32 = 1000;
+
+impl SqliteFullDatabase {
+    /// Executes the given SQL query and returns, for each row that it produces, the value
+    /// returned by `f`. Intended for debugging purposes and for ad-hoc analysis of the content of
+    /// the database.
+    ///
+    /// > **Note**: The schema of the database is **not** a stable interface, and can change
+    /// >           between two versions of this code without notice. Queries written against it
+    /// >           can break at any moment.
+    ///
+    /// Only a single statement that doesn't modify the database is accepted, and
+    /// [`QueryError::NotReadOnly`] is returned otherwise. The tables of all the chains stored in
+    /// the database are visible, and `current_chain.id` can be used in order to only look at
+    /// the chain accessed through this [`SqliteFullDatabase`].
+    ///
+    /// The query is aborted if it produces more than [`QUERY_MAX_ROWS`] rows, or if it takes
+    /// more than [`QUERY_TIMEOUT`] to execute. Like other read operations, the query is executed
+    /// on one of the read-only connections if possible (see
+    /// [`super::Config::read_connections`]), and doesn't block writes in that case.
+    pub fn query_read_only<R>(
+        &self,
+        sql: &str,
+        params: impl rusqlite::Params,
+        mut f: impl FnMut(&rusqlite::Row) -> R,
+    ) -> Result<Vec<R>, QueryError> {
+        let mut timer = self.time_operation(OperationKind::QueryReadOnly);
+        let connection = self.read_connection();
+
+        let mut batch = rusqlite::Batch::new(&connection, sql);
+        let mut statement = match batch.next() {
+            Ok(Some(statement)) => statement,
+            Ok(None) => return Err(QueryError::NotReadOnly),
+            Err(err) => {
+                return Err(QueryError::InvalidQuery(InternalError::new(
+                    "query_read_only",
+                    err,
+                )))
+            }
+        };
+        match batch.next() {
+            Ok(None) => {}
+            Ok(Some(_)) => return Err(QueryError::NotReadOnly),
+            Err(err) => {
+                return Err(QueryError::InvalidQuery(InternalError::new(
+                    "query_read_only",
+                    err,
+                )))
+            }
+        }
+        // `sqlite3_stmt_readonly` also considers statements such as `BEGIN` or `SAVEPOINT` as
+        // read-only. Since they don't return any column, they are refused as well.
+        if !statement.readonly() || statement.column_count() == 0 {
+            return Err(QueryError::NotReadOnly);
+        }
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        connection.progress_handler(
+            PROGRESS_HANDLER_PERIOD,
+            Some(move || Instant::now() >= deadline),
+        );
+
+        let query_err = |err: rusqlite::Error| {
+            if err.sqlite_error_code() == Some(rusqlite::ErrorCode::OperationInterrupted)
+                && Instant::now() >= deadline
+            {
+                QueryError::Timeout
+            } else {
+                QueryError::Corrupted(sqlite_err("query_read_only")(err))
+            }
+        };
+        let result = (|| {
+            let mut rows = statement.query(params).map_err(query_err)?;
+            let mut output = Vec::new();
+            while let Some(row) = rows.next().map_err(query_err)? {
+                if output.len() == QUERY_MAX_ROWS {
+                    return Err(QueryError::TooManyRows);
+                }
+                output.push(f(row));
+            }
+            Ok(output)
+        })();
+
+        connection.progress_handler(0, None::<fn() -> bool>);
+
+        let output = result?;
+        timer.set_rows(output.len());
+        Ok(output)
+    }
+}
+
+/// Error potentially returned by [`SqliteFullDatabase::query_read_only`].
+#[derive(Debug, derive_more::Display)]
+pub enum QueryError {
+    /// The SQL text isn't a single statement that doesn't modify the database.
+    #[display(fmt = "Query must be a single read-only statement")]
+    NotReadOnly,
+    /// The SQL text couldn't be compiled, for example because of a syntax error or because it
+    /// refers to a table that doesn't exist.
+    #[display(fmt = "Invalid query: {_0}")]
+    InvalidQuery(InternalError),
+    /// The query has produced more than [`QUERY_MAX_ROWS`] rows.
+    #[display(fmt = "Query has produced too many rows")]
+    TooManyRows,
+    /// The query has taken more than [`QUERY_TIMEOUT`] to execute.
+    #[display(fmt = "Query has timed out")]
+    Timeout,
+    /// Error while executing the query.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+}
+
+impl std::error::Error for QueryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QueryError::InvalidQuery(err) => Some(err),
+            QueryError::Corrupted(err) => Some(err),
+            _ => None,
+        }
+    }
+}
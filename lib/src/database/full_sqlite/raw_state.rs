@@ -0,0 +1,573 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Conversion between the storage of a block and the "raw genesis" format of chain
+//! specifications.
+//!
+//! In this format, the storage is represented as a JSON object containing two fields: `top`,
+//! a map of all the hexadecimal keys of the main trie to their hexadecimal value, and
+//! `childrenDefault`, a map whose keys are the hexadecimal names of the default child tries
+//! and whose values are maps similar to `top`. Entries of the main trie that correspond to the
+//! roots of child tries (i.e. whose key starts with `:child_storage:default:`) are omitted from
+//! `top`.
+//!
+//! Use [`SqliteFullDatabase::export_finalized_state`] to produce such a snapshot, and
+//! [`DatabaseEmpty::initialize_from_raw_state`] to initialize a database from it.
+
+use super::{
+    blake2_hash, children_list, prepare_cached, sqlite_err, CorruptedError, DatabaseEmpty,
+    InsertTrieNode, InsertTrieNodeStorageValue, InsertTrieNodesError, ResetError,
+    SqliteFullDatabase, StorageAccessError, CHILD_STORAGE_DEFAULT_PREFIX, MAX_TRIE_DEPTH,
+};
+use crate::{chain::chain_information, trie};
+
+use alloc::{borrow::Cow, vec::Vec};
+use core::{array, sync::atomic::Ordering};
+use rusqlite::OptionalExtension as _;
+use std::io;
+
+/// Maximum number of trie nodes inserted within a single transaction by
+/// [`DatabaseEmpty::initialize_from_raw_state`].
+const TRIE_NODES_PER_TRANSACTION: usize = 16384;
+
+impl DatabaseEmpty {
+    /// Similar to [`DatabaseEmpty::initialize`], but also inserts the storage of the finalized
+    /// block.
+    ///
+    /// `main_trie` must yield the keys and values of the main trie, and `child_tries` the name
+    /// of each default child trie (i.e. without the `:child_storage:default:` prefix) alongside
+    /// with its keys and values. Entries of `main_trie` whose key starts with
+    /// `:child_storage:default:` are ignored, as they are automatically generated from
+    /// `child_tries`. This corresponds to the "raw genesis" format of chain specifications.
+    ///
+    /// All the trie nodes are calculated from the provided entries, and an error is returned
+    /// before anything is written if the resulting trie root doesn't match the state root found
+    /// in the header of the finalized block.
+    ///
+    /// The trie nodes are inserted in batches, each within its own transaction, and `progress`
+    /// is called after each batch. The chain information is only inserted at the very end, and
+    /// the database is considered as empty by [`super::open`] if the initialization is
+    /// interrupted.
+    ///
+    /// > **Note**: The entire state is held in memory while its trie nodes are calculated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn initialize_from_raw_state<'a>(
+        self,
+        chain_information: impl Into<chain_information::ChainInformationRef<'a>>,
+        finalized_block_body: impl ExactSizeIterator<Item = &'a [u8]>,
+        finalized_block_justification: Option<Vec<u8>>,
+        main_trie: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+        child_tries: impl IntoIterator<Item = (Vec<u8>, Vec<(Vec<u8>, Vec<u8>)>)>,
+        state_version: trie::TrieEntryVersion,
+        mut progress: impl FnMut(RawStateProgress),
+    ) -> Result<SqliteFullDatabase, InitializeFromRawStateError> {
+        let chain_information = chain_information.into();
+
+        // Build the child tries first, as their roots are part of the main trie.
+        let mut tries = Vec::new();
+        let mut main_trie = RawTrie::new(
+            main_trie
+                .into_iter()
+                .filter(|(key, _)| !key.starts_with(CHILD_STORAGE_DEFAULT_PREFIX)),
+            false,
+        );
+        for (child_trie_name, entries) in child_tries {
+            let mut child_trie = RawTrie::new(entries.into_iter(), false);
+            child_trie.calculate_merkle_values(state_version);
+            let Some(child_trie_root) = child_trie.root() else {
+                // Empty child tries don't exist.
+                continue;
+            };
+
+            let key = CHILD_STORAGE_DEFAULT_PREFIX
+                .iter()
+                .chain(child_trie_name.iter())
+                .copied()
+                .collect::<Vec<_>>();
+            main_trie.insert(key, child_trie_root.to_vec(), true);
+            tries.push(child_trie);
+        }
+
+        main_trie.calculate_merkle_values(state_version);
+        let calculated = main_trie
+            .root()
+            .unwrap_or(trie::EMPTY_BLAKE2_TRIE_MERKLE_VALUE);
+        if calculated != *chain_information.finalized_block_header.state_root {
+            return Err(InitializeFromRawStateError::StateRootMismatch {
+                expected: *chain_information.finalized_block_header.state_root,
+                calculated,
+            });
+        }
+        tries.push(main_trie);
+
+        let database = self.into_database_uninitialized();
+
+        let trie_nodes_total = tries.iter().map(|t| t.trie.len() as u64).sum();
+        let mut trie_nodes_inserted = 0;
+        for trie in &mut tries {
+            let node_indices = trie.trie.iter_unordered().collect::<Vec<_>>();
+            for batch in node_indices.chunks(TRIE_NODES_PER_TRANSACTION) {
+                let nodes = batch
+                    .iter()
+                    .map(|node_index| trie.insert_trie_node(*node_index))
+                    .collect::<Vec<_>>();
+                database
+                    .insert_trie_nodes_versioned(nodes.into_iter(), state_version)
+                    .map_err(|err| match err {
+                        InsertTrieNodesError::Corrupted(err) => err,
+                        // Partial keys are built from nibbles, Merkle values are calculated, nodes
+                        // aren't validated, and the version is typed.
+                        InsertTrieNodesError::InvalidNibble { .. }
+                        | InsertTrieNodesError::InvalidChildMerkleValue { .. }
+                        | InsertTrieNodesError::InvalidTrieEntryVersion
+                        | InsertTrieNodesError::OrphanTrieNodes { .. } => unreachable!(),
+                    })?;
+
+                trie_nodes_inserted += batch.len() as u64;
+                progress(RawStateProgress {
+                    trie_nodes_inserted,
+                    trie_nodes_total,
+                });
+            }
+        }
+
+        database
+            .reset(
+                chain_information,
+                finalized_block_body,
+                finalized_block_justification,
+            )
+            .map_err(|err| match err {
+                ResetError::Corrupted(err) => InitializeFromRawStateError::Corrupted(err),
+                ResetError::HeaderRoundTripMismatch => {
+                    InitializeFromRawStateError::HeaderRoundTripMismatch
+                }
+                ResetError::BlockOfOtherChain => InitializeFromRawStateError::BlockOfOtherChain,
+            })?;
+        database.finish_initialization()?;
+
+        // The statistics of the query planner are most likely outdated.
+        database.analyze_pending.store(true, Ordering::Relaxed);
+
+        Ok(database)
+    }
+}
+
+/// Progress of [`DatabaseEmpty::initialize_from_raw_state`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RawStateProgress {
+    /// Number of trie nodes that have been inserted so far.
+    pub trie_nodes_inserted: u64,
+    /// Total number of trie nodes to insert.
+    pub trie_nodes_total: u64,
+}
+
+/// Error while calling [`DatabaseEmpty::initialize_from_raw_state`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum InitializeFromRawStateError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// The root of the trie calculated from the provided entries doesn't match the state root
+    /// of the finalized block.
+    #[display(
+        fmt = "State root mismatch: expected 0x{}, calculated 0x{}",
+        "hex::encode(expected)",
+        "hex::encode(calculated)"
+    )]
+    #[from(ignore)]
+    StateRootMismatch {
+        /// State root found in the header of the finalized block.
+        expected: [u8; 32],
+        /// Root of the trie built from the provided entries.
+        calculated: [u8; 32],
+    },
+    /// See [`ResetError::HeaderRoundTripMismatch`].
+    #[display(fmt = "Finalized block header doesn't decode back into the same header")]
+    #[from(ignore)]
+    HeaderRoundTripMismatch,
+    /// See [`ResetError::BlockOfOtherChain`].
+    #[display(fmt = "Finalized block belongs to another chain of the database")]
+    #[from(ignore)]
+    BlockOfOtherChain,
+}
+
+/// Trie built from a list of storage entries.
+struct RawTrie {
+    trie: trie::trie_structure::TrieStructure<RawTrieNode>,
+}
+
+/// User data of a node of a [`RawTrie`].
+#[derive(Default)]
+struct RawTrieNode {
+    /// Storage value of the node, if any, alongside with whether this value is the Merkle value
+    /// of the root of a child trie.
+    storage_value: Option<(Vec<u8>, bool)>,
+    /// Merkle value of the node, once calculated.
+    merkle_value: Option<trie::trie_node::MerkleValueOutput>,
+}
+
+impl RawTrie {
+    /// Builds the trie containing the given entries. [`RawTrie::calculate_merkle_values`] must
+    /// be called afterwards.
+    fn new(
+        entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+        references_merkle_value: bool,
+    ) -> Self {
+        let mut raw_trie = RawTrie {
+            trie: trie::trie_structure::TrieStructure::new(),
+        };
+        for (key, value) in entries {
+            raw_trie.insert(key, value, references_merkle_value);
+        }
+        raw_trie
+    }
+
+    /// Inserts or replaces an entry. [`RawTrie::calculate_merkle_values`] must be called
+    /// afterwards.
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>, references_merkle_value: bool) {
+        match self.trie.node(trie::bytes_to_nibbles(key.iter().copied())) {
+            trie::trie_structure::Entry::Vacant(entry) => {
+                entry.insert_storage_value().insert(
+                    RawTrieNode {
+                        storage_value: Some((value, references_merkle_value)),
+                        merkle_value: None,
+                    },
+                    RawTrieNode::default(),
+                );
+            }
+            trie::trie_structure::Entry::Occupied(trie::trie_structure::NodeAccess::Branch(
+                mut entry,
+            )) => {
+                entry.user_data().storage_value = Some((value, references_merkle_value));
+                entry.insert_storage_value();
+            }
+            trie::trie_structure::Entry::Occupied(trie::trie_structure::NodeAccess::Storage(
+                mut entry,
+            )) => {
+                entry.user_data().storage_value = Some((value, references_merkle_value));
+            }
+        }
+    }
+
+    /// Calculates the Merkle value of every node, children first.
+    fn calculate_merkle_values(&mut self, state_version: trie::TrieEntryVersion) {
+        for node_index in self
+            .trie
+            .iter_ordered()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            let mut node_access = self.trie.node_by_index(node_index).unwrap();
+
+            let children = array::from_fn::<_, 16, _>(|n| {
+                node_access
+                    .child(trie::Nibble::try_from(u8::try_from(n).unwrap()).unwrap())
+                    .map(|mut child| child.user_data().merkle_value.clone().unwrap())
+            });
+
+            let is_root_node = node_access.is_root_node();
+            let partial_key = node_access.partial_key().collect::<Vec<_>>();
+
+            // In version 1 of the trie, values of 33 bytes or more are hashed.
+            let value_hash = match (&node_access.user_data().storage_value, state_version) {
+                (Some((value, _)), trie::TrieEntryVersion::V1) if value.len() >= 33 => {
+                    Some(blake2_hash(value))
+                }
+                _ => None,
+            };
+            let storage_value = match (&node_access.user_data().storage_value, &value_hash) {
+                (Some(_), Some(hash)) => trie::trie_node::StorageValue::Hashed(hash),
+                (Some((value, _)), None) => trie::trie_node::StorageValue::Unhashed(value),
+                (None, _) => trie::trie_node::StorageValue::None,
+            };
+
+            let merkle_value = trie::trie_node::calculate_merkle_value(
+                trie::trie_node::Decoded {
+                    children,
+                    partial_key: partial_key.into_iter(),
+                    storage_value,
+                },
+                trie::HashFunction::Blake2,
+                is_root_node,
+            )
+            .unwrap_or_else(|_| unreachable!());
+
+            node_access.into_user_data().merkle_value = Some(merkle_value);
+        }
+    }
+
+    /// Returns the Merkle value of the root node, or `None` if the trie is empty.
+    fn root(&self) -> Option<[u8; 32]> {
+        let merkle_value = &self.trie.root_user_data()?.merkle_value;
+        // The Merkle value of the root node is always a hash.
+        Some(
+            <[u8; 32]>::try_from(merkle_value.as_ref().unwrap().as_ref())
+                .unwrap_or_else(|_| unreachable!()),
+        )
+    }
+
+    /// Builds the [`InsertTrieNode`] corresponding to the given node.
+    fn insert_trie_node(
+        &mut self,
+        node_index: trie::trie_structure::NodeIndex,
+    ) -> InsertTrieNode<'static> {
+        let mut node_access = self.trie.node_by_index(node_index).unwrap();
+
+        InsertTrieNode {
+            children_merkle_values: array::from_fn::<_, 16, _>(|n| {
+                node_access
+                    .child(trie::Nibble::try_from(u8::try_from(n).unwrap()).unwrap())
+                    .map(|mut child| {
+                        Cow::Owned(
+                            child
+                                .user_data()
+                                .merkle_value
+                                .as_ref()
+                                .unwrap()
+                                .as_ref()
+                                .to_vec(),
+                        )
+                    })
+            }),
+            partial_key_nibbles: Cow::Owned(node_access.partial_key().map(u8::from).collect()),
+            merkle_value: Cow::Owned(
+                node_access
+                    .user_data()
+                    .merkle_value
+                    .as_ref()
+                    .unwrap()
+                    .as_ref()
+                    .to_vec(),
+            ),
+            storage_value: match &node_access.user_data().storage_value {
+                Some((value, references_merkle_value)) => InsertTrieNodeStorageValue::Value {
+                    value: Cow::Owned(value.clone()),
+                    references_merkle_value: *references_merkle_value,
+                },
+                None => InsertTrieNodeStorageValue::NoValue,
+            },
+        }
+    }
+}
+
+impl SqliteFullDatabase {
+    /// Writes to `writer` the entire storage of the finalized block, main trie and child tries,
+    /// in the "raw genesis" format of chain specifications. See the documentation of this
+    /// module. The output can be used as the value of the `genesis.raw` field of a chain
+    /// specification.
+    ///
+    /// Returns [`StorageAccessError::IncompleteStorage`] without writing anything if some trie
+    /// nodes of the storage of the finalized block are missing from the database.
+    ///
+    /// The storage is read progressively while being written out, and the database isn't
+    /// locked for the entire duration of the export. If the finalized block changes during the
+    /// export, the storage of the initial finalized block continues to be exported, unless it
+    /// gets removed from the database, in which case an error is returned. In case of error,
+    /// the content written to `writer` must be discarded.
+    pub fn export_finalized_state(
+        &self,
+        mut writer: impl io::Write,
+    ) -> Result<(), ExportStateError> {
+        let finalized_hash = self
+            .finalized_block_hash()
+            .map_err(StorageAccessError::from)?;
+
+        // Refuse to emit a partial state.
+        if self
+            .finalized_and_above_missing_trie_nodes_unordered()
+            .map_err(StorageAccessError::from)?
+            .iter()
+            .any(|node| node.blocks.iter().any(|b| b.hash == finalized_hash))
+        {
+            return Err(ExportStateError::Storage(
+                StorageAccessError::IncompleteStorage,
+            ));
+        }
+
+        let state_trie_root = {
+            let database = self.read_connection();
+            let state_trie_root = prepare_cached(
+                &database,
+                "SELECT state_trie_root_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)",
+            )
+            .map_err(|err| StorageAccessError::from(sqlite_err("export_finalized_state")(err)))?
+            .query_row((&finalized_hash[..],), |row| {
+                row.get::<_, Option<Vec<u8>>>(0)
+            })
+            .optional()
+            .map_err(|err| StorageAccessError::from(sqlite_err("export_finalized_state")(err)))?
+            .ok_or(StorageAccessError::UnknownBlock)?;
+            state_trie_root
+        };
+
+        writer
+            .write_all(br#"{"top":{"#)
+            .map_err(ExportStateError::Io)?;
+
+        // The child tries are collected while iterating over the main trie, and are written
+        // afterwards.
+        let mut child_tries = Vec::new();
+        let mut is_first = true;
+        if let Some(state_trie_root) = state_trie_root {
+            self.for_each_trie_entry(state_trie_root, |key, value| {
+                if let Some(child_trie) = key.strip_prefix(CHILD_STORAGE_DEFAULT_PREFIX) {
+                    child_tries.push((child_trie.to_vec(), value.to_vec()));
+                    Ok(())
+                } else {
+                    write_entry(&mut writer, &mut is_first, key, value)
+                }
+            })?;
+        }
+
+        writer
+            .write_all(br#"},"childrenDefault":{"#)
+            .map_err(ExportStateError::Io)?;
+
+        for (child_trie_index, (child_trie, child_trie_root)) in child_tries.into_iter().enumerate()
+        {
+            if child_trie_index != 0 {
+                writer.write_all(b",").map_err(ExportStateError::Io)?;
+            }
+            write!(writer, "\"0x{}\":{{", hex::encode(child_trie)).map_err(ExportStateError::Io)?;
+
+            let mut is_first = true;
+            self.for_each_trie_entry(child_trie_root, |key, value| {
+                write_entry(&mut writer, &mut is_first, key, value)
+            })?;
+
+            writer.write_all(b"}").map_err(ExportStateError::Io)?;
+        }
+
+        writer.write_all(b"}}").map_err(ExportStateError::Io)?;
+        writer.flush().map_err(ExportStateError::Io)?;
+        Ok(())
+    }
+
+    /// Calls `on_entry` with the key and value of each storage entry of the trie whose root
+    /// node is `root_hash`, in increasing key order.
+    ///
+    /// If a storage entry is the root of another trie, the value passed to `on_entry` is the
+    /// Merkle value of this other trie.
+    ///
+    /// A connection to the database is only locked while reading each individual node.
+    fn for_each_trie_entry(
+        &self,
+        root_hash: Vec<u8>,
+        mut on_entry: impl FnMut(&[u8], &[u8]) -> Result<(), ExportStateError>,
+    ) -> Result<(), ExportStateError> {
+        // Nodes that remain to be visited, with the key (in nibbles) of their parent plus child
+        // index, and their depth. Because a node's key is always inferior to the keys of its
+        // descendants, visiting the nodes depth first and in the order of their child index
+        // yields the entries in increasing key order.
+        let mut stack = vec![(root_hash, Vec::new(), 0)];
+
+        while let Some((node_hash, mut key_nibbles, depth)) = stack.pop() {
+            if depth >= MAX_TRIE_DEPTH {
+                return Err(ExportStateError::Storage(StorageAccessError::Corrupted(
+                    CorruptedError::TrieLoopDetected,
+                )));
+            }
+
+            let (partial_key, value, children) = {
+                let database = self.read_connection();
+
+                let (partial_key, value, children) = prepare_cached(
+                    &database,
+                    "SELECT unpack_nibbles(trie_node.partial_key), COALESCE(decompress_value(trie_node_storage.value, trie_node_storage.value_compressed), trie_node_storage.trie_root_ref), trie_node.children FROM trie_node LEFT JOIN trie_node_storage ON trie_node_storage.node_hash = trie_node.hash WHERE trie_node.hash = ?",
+                )
+                .map_err(|err| StorageAccessError::from(sqlite_err("for_each_trie_entry")(err)))?
+                .query_row((&node_hash,), |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, Option<Vec<u8>>>(1)?,
+                        row.get::<_, Option<Vec<u8>>>(2)?,
+                    ))
+                })
+                .optional()
+                .map_err(|err| StorageAccessError::from(sqlite_err("for_each_trie_entry")(err)))?
+                .ok_or(StorageAccessError::IncompleteStorage)?;
+
+                let children = match children {
+                    Some(children) => {
+                        children_list(&children).map_err(StorageAccessError::Corrupted)?
+                    }
+                    None => Vec::new(),
+                };
+
+                (partial_key, value, children)
+            };
+
+            key_nibbles.extend_from_slice(&partial_key);
+
+            if let Some(value) = value {
+                // Keys that don't contain an entire number of bytes can't legitimately exist in
+                // the storage of a block.
+                if key_nibbles.len() % 2 != 0 || key_nibbles.iter().any(|n| *n >= 16) {
+                    return Err(ExportStateError::Storage(StorageAccessError::Corrupted(
+                        CorruptedError::InvalidStorageKey,
+                    )));
+                }
+                let key = key_nibbles
+                    .chunks(2)
+                    .map(|n| (n[0] << 4) | n[1])
+                    .collect::<Vec<_>>();
+                on_entry(&key, &value)?;
+            }
+
+            for (child_num, child_hash) in children.into_iter().rev() {
+                let mut child_key_nibbles = key_nibbles.clone();
+                child_key_nibbles.push(child_num);
+                stack.push((child_hash, child_key_nibbles, depth + 1));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Error while calling [`SqliteFullDatabase::export_finalized_state`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum ExportStateError {
+    /// Error while accessing the storage of the finalized block.
+    #[display(fmt = "{_0}")]
+    Storage(StorageAccessError),
+    /// Error while writing the output.
+    #[display(fmt = "{_0}")]
+    Io(io::Error),
+}
+
+/// Writes a `"key":"value"` JSON entry, preceded with a comma if it isn't the first entry of
+/// the map.
+fn write_entry(
+    writer: &mut impl io::Write,
+    is_first: &mut bool,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(), ExportStateError> {
+    write!(
+        writer,
+        "{}\"0x{}\":\"0x{}\"",
+        if *is_first { "" } else { "," },
+        hex::encode(key),
+        hex::encode(value)
+    )
+    .map_err(ExportStateError::Io)?;
+    *is_first = false;
+    Ok(())
+}
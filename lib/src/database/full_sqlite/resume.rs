@@ -0,0 +1,166 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Verification of the state of the database when the node starts.
+//!
+//! See [`SqliteFullDatabase::resume_state`].
+
+use super::{
+    best_block_hash, best_block_number, finalized_hash, finalized_num, prepare_cached, sqlite_err,
+    trie_is_complete, CorruptedError, SqliteFullDatabase,
+};
+
+use rusqlite::OptionalExtension as _;
+
+/// State of the database as found by [`SqliteFullDatabase::resume_state`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeState {
+    /// Hash of the current best block.
+    pub best_block_hash: [u8; 32],
+    /// Height of the current best block.
+    pub best_block_number: u64,
+    /// Hash of the current finalized block.
+    pub finalized_block_hash: [u8; 32],
+    /// Height of the current finalized block.
+    pub finalized_block_number: u64,
+    /// `true` if the best block is the finalized block or one of its descendants, and all the
+    /// blocks in between are in the database.
+    pub best_descends_from_finalized: bool,
+    /// Number of blocks whose height is strictly superior to the one of the finalized block,
+    /// whether they are part of the best chain or not.
+    pub non_finalized_blocks: u64,
+    /// `true` if all the trie nodes of the storage of the finalized block are in the database.
+    pub finalized_storage_complete: bool,
+    /// Number of blocks whose height is inferior or equal to the one of the finalized block and
+    /// that aren't part of the finalized chain. These blocks can be removed with
+    /// [`SqliteFullDatabase::purge_finality_orphans`].
+    pub orphans_below_finalized: u64,
+}
+
+impl ResumeState {
+    /// Returns `true` if the database is consistent enough for the node to resume from it
+    /// without any additional action.
+    ///
+    /// Orphan blocks below the finalized block don't prevent resuming, as they are never
+    /// accessed.
+    pub fn is_resumable(&self) -> bool {
+        self.best_descends_from_finalized && self.finalized_storage_complete
+    }
+}
+
+impl SqliteFullDatabase {
+    /// Returns the state of the database as seen after a restart, and whether this state is
+    /// consistent.
+    ///
+    /// This is intended to be called when the node starts, after an unclean shutdown or
+    /// otherwise, in order to determine whether it can resume from the content of the database.
+    /// All the information is read from a single consistent snapshot of the database.
+    ///
+    /// > **Note**: Verifying whether the storage of the finalized block is complete requires
+    /// >           going through all of its trie nodes, and can take a long time on large chains.
+    pub fn resume_state(&self) -> Result<ResumeState, CorruptedError> {
+        let mut connection = self.read_connection();
+        let connection = connection.savepoint().map_err(sqlite_err("resume_state"))?;
+
+        let best_block_hash = best_block_hash(&connection)?;
+        let best_block_number = best_block_number(&connection)?;
+        let finalized_block_hash = finalized_hash(&connection)?;
+        let finalized_block_number = finalized_num(&connection)?;
+        let finalized_number_i64 =
+            i64::try_from(finalized_block_number).map_err(|_| CorruptedError::InvalidNumber)?;
+
+        // Unlike `descends_from_finalized`, the ancestry of the best block is walked by following
+        // the parent hashes, as the `is_best_chain` flag is precisely what is being verified.
+        let ancestor_at_finalized_height = prepare_cached(
+            &connection,
+            r#"
+            WITH RECURSIVE
+                ancestry(hash, number) AS (
+                    SELECT hash, number FROM blocks WHERE hash = :block AND chain_id = (SELECT id FROM current_chain)
+                UNION ALL
+                    SELECT parent.hash, parent.number
+                    FROM ancestry
+                    JOIN blocks AS child ON child.hash = ancestry.hash
+                    JOIN blocks AS parent ON parent.hash = child.parent_hash
+                    WHERE ancestry.number > :finalized_number
+                )
+            SELECT hash FROM ancestry WHERE number = :finalized_number
+            "#,
+        )
+        .map_err(sqlite_err("resume_state"))?
+        .query_row(
+            rusqlite::named_params! {
+                ":block": &best_block_hash[..],
+                ":finalized_number": finalized_number_i64,
+            },
+            |row| row.get::<_, Vec<u8>>(0),
+        )
+        .optional()
+        .map_err(sqlite_err("resume_state"))?;
+        let best_descends_from_finalized =
+            ancestor_at_finalized_height.as_deref() == Some(&finalized_block_hash[..]);
+
+        let (non_finalized_blocks, orphans_below_finalized) = prepare_cached(
+            &connection,
+            r#"
+            SELECT
+                COUNT(*) FILTER (WHERE number > :finalized_number),
+                COUNT(*) FILTER (WHERE number <= :finalized_number AND is_best_chain = FALSE)
+            FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain)
+            "#,
+        )
+        .map_err(sqlite_err("resume_state"))?
+        .query_row(
+            rusqlite::named_params! {
+                ":finalized_number": finalized_number_i64,
+            },
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+        )
+        .map_err(sqlite_err("resume_state"))?;
+
+        let finalized_state_root = prepare_cached(
+            &connection,
+            "SELECT state_trie_root_hash FROM blocks WHERE hash = ? AND chain_id = (SELECT id FROM current_chain)",
+        )
+        .map_err(sqlite_err("resume_state"))?
+        .query_row((&finalized_block_hash[..],), |row| {
+            row.get::<_, Option<Vec<u8>>>(0)
+        })
+        .map_err(sqlite_err("resume_state"))?;
+        let finalized_storage_complete = match finalized_state_root {
+            Some(root) => {
+                let root = <[u8; 32]>::try_from(&root[..])
+                    .map_err(|_| CorruptedError::InvalidTrieHashLen)?;
+                trie_is_complete(&connection, &root)?
+            }
+            // The storage of the block has been removed from the database.
+            None => false,
+        };
+
+        Ok(ResumeState {
+            best_block_hash,
+            best_block_number,
+            finalized_block_hash,
+            finalized_block_number,
+            best_descends_from_finalized,
+            non_finalized_blocks: u64::try_from(non_finalized_blocks).unwrap_or(0),
+            finalized_storage_complete,
+            orphans_below_finalized: u64::try_from(orphans_below_finalized).unwrap_or(0),
+        })
+    }
+}
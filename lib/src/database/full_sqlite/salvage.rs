@@ -0,0 +1,1143 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Recovering the content of a damaged database.
+//!
+//! See [`salvage`].
+
+use super::{
+    blake2_hash, decode_children, descends_from_finalized, finalized_hash, finalized_num,
+    meta_get_blob, meta_set_blob, open, prepare_cached, unpack_nibbles, CommitPolicy, Config,
+    ConfigTy, CorruptedError, DatabaseOpen, InternalError, OpenError,
+};
+use crate::{header, trie};
+
+use alloc::{collections::BTreeSet, format, string::String, vec::Vec};
+use core::time::Duration;
+use rusqlite::OptionalExtension as _;
+use std::path::Path;
+
+/// Copies everything that can still be trusted in the database file found at `path` into a new
+/// database file created at `output_path`, and reports what has been dropped.
+///
+/// This is a last resort for when [`open`] fails or when [`CorruptedError`]s are returned
+/// because the file has been damaged, for example by a hardware failure. It is slow, as every
+/// row of the damaged file is read and verified individually.
+///
+/// The damaged file is opened in read-only mode, and is never modified. It must have been
+/// written by this version of the code, in other words it must have been successfully opened
+/// with [`open`] at least once, and must not be in use by another process. Rows that can't be
+/// read are skipped, and the following are copied:
+///
+/// - Blocks whose header can be decoded using `block_number_bytes`, and whose hash, number,
+///   and parent hash match their header.
+/// - Bodies of these blocks, provided that they match the extrinsics root found in the header.
+///   The other blocks are marked as having their body pruned.
+/// - Trie nodes whose Merkle value matches their content. Blocks whose storage has lost some
+///   trie nodes report [`super::StorageAccessError::IncompleteStorage`], and the missing nodes
+///   can be downloaded again, see
+///   [`super::SqliteFullDatabase::finalized_and_above_missing_trie_nodes_unordered`].
+/// - The finality and consensus information, and the list of runtimes.
+///
+/// A chain whose finalized block or consensus information can't be recovered is entirely
+/// dropped, including its blocks. If the best block can't be recovered, the finalized block
+/// becomes the best block.
+///
+/// `output_path` must not exist. If an error is returned, `output_path` might contain a
+/// partially-written database and must be removed.
+pub fn salvage(
+    path: &Path,
+    output_path: &Path,
+    block_number_bytes: usize,
+) -> Result<SalvageReport, SalvageError> {
+    if output_path.exists() {
+        return Err(SalvageError::OutputExists);
+    }
+
+    let source = open_source(path)?;
+
+    let DatabaseOpen::Empty(output) = open(Config {
+        block_number_bytes,
+        chain_name: None,
+        cache_size: 64 * 1024 * 1024,
+        statement_cache_capacity: 128,
+        // The new database isn't accessible to anyone else yet.
+        busy_timeout: Duration::ZERO,
+        read_connections: 0,
+        commit_policy: CommitPolicy::EveryOperation,
+        body_retention: None,
+        storage_diff_retention: None,
+        store_events: false,
+        on_operation: None,
+        auto_optimize_interval: None,
+        // Storage values are copied as they are, compressed or not.
+        value_compression: None,
+        allow_shared_readers: false,
+        progress: None,
+        ty: ConfigTy::Disk {
+            path: output_path,
+            memory_map_size: 0,
+        },
+    })
+    .map_err(|err| match err {
+        OpenError::Internal(err) => SalvageError::Output(err),
+        // The file didn't exist and thus doesn't contain any row.
+        OpenError::InvalidRows(_) => unreachable!(),
+        // Another salvage is writing to the same output path.
+        OpenError::AlreadyInUse { .. } => SalvageError::OutputExists,
+        OpenError::LockFile(err) => SalvageError::OutputLock(err),
+        // `allow_shared_readers` is `false`.
+        OpenError::ReadOnly => unreachable!(),
+    })?
+    else {
+        // The file didn't exist and has just been created.
+        unreachable!()
+    };
+    let mut output = output.into_database_uninitialized();
+    let output = output.database.get_mut();
+
+    let mut report = SalvageReport {
+        blocks_copied: 0,
+        blocks_dropped: 0,
+        bodies_dropped: 0,
+        trie_nodes_copied: 0,
+        trie_nodes_dropped: 0,
+        runtimes_dropped: 0,
+        chains_dropped: Vec::new(),
+        unreadable_ranges: 0,
+    };
+
+    // Everything is written within a single transaction. The foreign keys are only checked
+    // when committing, as the rows are copied in an arbitrary order.
+    output
+        .execute_batch("BEGIN IMMEDIATE; PRAGMA defer_foreign_keys = ON;")
+        .map_err(output_err)?;
+
+    let damaged_chains = copy_chains_information(&source, output, &mut report)?;
+    let blocks_read = copy_blocks(&source, output, block_number_bytes, &mut report)?;
+    drop_unknown_chains(output)?;
+    for chain_id in chain_ids(output)? {
+        // Chains that have never been initialized, such as the default chain of a database
+        // that only contains named chains, don't have anything to recover.
+        if chain_is_empty(output, chain_id)? {
+            continue;
+        }
+
+        let name = chain_name(output, chain_id)?;
+        if damaged_chains.all || damaged_chains.ids.contains(&chain_id) {
+            drop_chain(output, chain_id)?;
+            report.chains_dropped.push(name);
+            continue;
+        }
+
+        open::set_current_chain(output, chain_id).map_err(SalvageError::Output)?;
+        if !fix_best_chain(output)? {
+            drop_chain(output, chain_id)?;
+            report.chains_dropped.push(name);
+        }
+    }
+    report.bodies_dropped = verify_bodies(output, block_number_bytes)?;
+    copy_trie_nodes(&source, output, &mut report)?;
+
+    output.execute_batch("COMMIT").map_err(output_err)?;
+
+    report.blocks_copied = output
+        .query_row("SELECT COUNT(*) FROM blocks", (), |row| {
+            row.get::<_, u64>(0)
+        })
+        .map_err(output_err)?;
+    report.blocks_dropped = blocks_read.saturating_sub(report.blocks_copied);
+    Ok(report)
+}
+
+/// What has been copied or dropped by [`salvage`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Number of blocks that have been copied.
+    pub blocks_copied: u64,
+    /// Number of blocks that could be read but have been dropped, because their header is
+    /// invalid or because their chain has been dropped.
+    pub blocks_dropped: u64,
+    /// Number of copied blocks whose body couldn't be recovered. These blocks are marked as
+    /// having their body pruned.
+    pub bodies_dropped: u64,
+    /// Number of trie nodes that have been copied.
+    pub trie_nodes_copied: u64,
+    /// Number of trie nodes that could be read but have been dropped, because their Merkle
+    /// value doesn't match their content.
+    pub trie_nodes_dropped: u64,
+    /// Number of runtimes that could be read but have been dropped, because their code doesn't
+    /// match its hash.
+    pub runtimes_dropped: u64,
+    /// Names of the chains that have been dropped, because their finalized block or their
+    /// consensus information couldn't be recovered. `None` designates the chain used when
+    /// [`Config::chain_name`] is `None`.
+    pub chains_dropped: Vec<Option<String>>,
+    /// Number of times a range of rows couldn't be read at all. The number of rows that these
+    /// ranges contained isn't known.
+    pub unreadable_ranges: u64,
+}
+
+/// Error while calling [`salvage`].
+#[derive(Debug, derive_more::Display)]
+pub enum SalvageError {
+    /// A file already exists at the output path.
+    #[display(fmt = "Output file already exists")]
+    OutputExists,
+    /// The damaged database couldn't be opened.
+    #[display(fmt = "Failed to open the damaged database: {_0}")]
+    Source(InternalError),
+    /// The damaged database has been written by a different version of the code.
+    #[display(fmt = "Unsupported schema version: {_0}")]
+    UnsupportedSchemaVersion(i64),
+    /// Error while creating or writing the new database.
+    #[display(fmt = "Error while writing the salvaged database: {_0}")]
+    Output(InternalError),
+    /// Error while creating or locking the lock file of the new database.
+    #[display(fmt = "Failed to lock the salvaged database: {_0}")]
+    OutputLock(std::io::Error),
+}
+
+impl std::error::Error for SalvageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SalvageError::Source(err) | SalvageError::Output(err) => Some(err),
+            SalvageError::OutputLock(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn output_err(err: rusqlite::Error) -> SalvageError {
+    SalvageError::Output(InternalError::new("salvage", err))
+}
+
+/// Turns an error returned by one of the helpers of the parent module when accessing the new
+/// database into `Ok(None)` if it indicates that the content of the database is inconsistent.
+fn output_consistent<T>(result: Result<T, CorruptedError>) -> Result<Option<T>, SalvageError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(CorruptedError::Internal(err)) => Err(SalvageError::Output(err)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Opens the damaged database.
+fn open_source(path: &Path) -> Result<rusqlite::Connection, SalvageError> {
+    let source = rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|err| SalvageError::Source(InternalError::new("salvage/open", err)))?;
+
+    // `cell_size_check` makes SQLite verify the pages of the file more thoroughly when reading
+    // them, which turns some forms of damage into errors rather than into garbage.
+    source
+        .execute_batch(
+            r#"
+PRAGMA query_only = ON;
+PRAGMA cell_size_check = ON;
+PRAGMA trusted_schema = OFF;
+            "#,
+        )
+        .map_err(|err| SalvageError::Source(InternalError::new("salvage/open", err)))?;
+
+    let version = source
+        .query_row("PRAGMA user_version", (), |row| row.get::<_, i64>(0))
+        .map_err(|err| SalvageError::Source(InternalError::new("salvage/open", err)))?;
+    if version != open::SCHEMA_VERSION {
+        return Err(SalvageError::UnsupportedSchemaVersion(version));
+    }
+
+    Ok(source)
+}
+
+/// Calls `f` with each row of `table` that can be read, in increasing `rowid` order. The first
+/// column of the rows is the `rowid`, followed with `columns`.
+///
+/// When the damaged file can't be read, the scan continues after skipping a number of `rowid`s
+/// that doubles after each failed attempt, in order to quickly move past the damaged pages.
+/// Each sequence of failed attempts increments `unreadable_ranges`.
+fn scan_table(
+    source: &rusqlite::Connection,
+    table: &str,
+    columns: &str,
+    unreadable_ranges: &mut u64,
+    mut f: impl FnMut(&rusqlite::Row) -> Result<(), SalvageError>,
+) -> Result<(), SalvageError> {
+    let Ok(mut statement) = source.prepare(&format!(
+        "SELECT rowid, {columns} FROM {table} WHERE rowid >= ? ORDER BY rowid"
+    )) else {
+        *unreadable_ranges += 1;
+        return Ok(());
+    };
+
+    let mut start = i64::MIN;
+    let mut skip = 0i64;
+
+    loop {
+        if let Ok(mut rows) = statement.query((start,)) {
+            loop {
+                match rows.next() {
+                    Ok(Some(row)) => {
+                        let Ok(rowid) = row.get::<_, i64>(0) else {
+                            break;
+                        };
+                        start = rowid.saturating_add(1);
+                        skip = 0;
+                        f(row)?;
+                    }
+                    Ok(None) => return Ok(()),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        if skip == 0 {
+            *unreadable_ranges += 1;
+        }
+        skip = skip.saturating_mul(2).max(1);
+        start = start.saturating_add(skip);
+        if start == i64::MAX {
+            return Ok(());
+        }
+    }
+}
+
+/// Chains whose consensus information couldn't be recovered.
+struct DamagedChains {
+    /// `true` if rows that couldn't be read might have belonged to any chain.
+    all: bool,
+    ids: BTreeSet<i64>,
+}
+
+/// Copies the list of chains, the `meta` table, the authorities, and the runtimes.
+fn copy_chains_information(
+    source: &rusqlite::Connection,
+    output: &rusqlite::Connection,
+    report: &mut SalvageReport,
+) -> Result<DamagedChains, SalvageError> {
+    let mut damaged = DamagedChains {
+        all: false,
+        ids: BTreeSet::new(),
+    };
+
+    scan_table(
+        source,
+        "chains",
+        "id, name",
+        &mut report.unreadable_ranges,
+        |row| {
+            if let (Ok(id), Ok(name)) = (row.get::<_, i64>(1), row.get::<_, Option<String>>(2)) {
+                output
+                    .execute(
+                        "INSERT OR IGNORE INTO chains(id, name) VALUES (?, ?)",
+                        (id, name),
+                    )
+                    .map_err(output_err)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    // The tables below are small, and a row that can't be read or that is invalid makes it
+    // impossible to trust the finality information of the chain it belongs to.
+    let unreadable_before = report.unreadable_ranges;
+
+    scan_table(
+        source,
+        "meta",
+        "chain_id, key, value_blob, value_number",
+        &mut report.unreadable_ranges,
+        |row| {
+            let Ok(chain_id) = row.get::<_, i64>(1) else {
+                damaged.all = true;
+                return Ok(());
+            };
+            match (
+                row.get::<_, String>(2),
+                row.get::<_, Option<Vec<u8>>>(3),
+                row.get::<_, Option<i64>>(4),
+            ) {
+                (Ok(key), Ok(blob), Ok(number)) if blob.is_some() != number.is_some() => {
+                    output
+                        .execute(
+                            "INSERT OR IGNORE INTO meta(chain_id, key, value_blob, value_number) VALUES (?, ?, ?, ?)",
+                            (chain_id, key, blob, number),
+                        )
+                        .map_err(output_err)?;
+                }
+                _ => {
+                    damaged.ids.insert(chain_id);
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    for table in [
+        "grandpa_triggered_authorities",
+        "grandpa_scheduled_authorities",
+    ] {
+        scan_table(
+            source,
+            table,
+            "chain_id, idx, public_key, weight",
+            &mut report.unreadable_ranges,
+            |row| {
+                let Ok(chain_id) = row.get::<_, i64>(1) else {
+                    damaged.all = true;
+                    return Ok(());
+                };
+                match (
+                    row.get::<_, i64>(2),
+                    row.get::<_, Vec<u8>>(3),
+                    row.get::<_, Vec<u8>>(4),
+                ) {
+                    (Ok(idx), Ok(public_key), Ok(weight))
+                        if public_key.len() == 32 && weight.len() == 8 =>
+                    {
+                        output
+                            .execute(
+                                &format!("INSERT OR IGNORE INTO {table}(chain_id, idx, public_key, weight) VALUES (?, ?, ?, ?)"),
+                                (chain_id, idx, public_key, weight),
+                            )
+                            .map_err(output_err)?;
+                    }
+                    _ => {
+                        damaged.ids.insert(chain_id);
+                    }
+                }
+                Ok(())
+            },
+        )?;
+    }
+
+    scan_table(
+        source,
+        "aura_finalized_authorities",
+        "chain_id, idx, public_key",
+        &mut report.unreadable_ranges,
+        |row| {
+            let Ok(chain_id) = row.get::<_, i64>(1) else {
+                damaged.all = true;
+                return Ok(());
+            };
+            match (row.get::<_, i64>(2), row.get::<_, Vec<u8>>(3)) {
+                (Ok(idx), Ok(public_key)) if public_key.len() == 32 => {
+                    output
+                        .execute(
+                            "INSERT OR IGNORE INTO aura_finalized_authorities(chain_id, idx, public_key) VALUES (?, ?, ?)",
+                            (chain_id, idx, public_key),
+                        )
+                        .map_err(output_err)?;
+                }
+                _ => {
+                    damaged.ids.insert(chain_id);
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    if report.unreadable_ranges != unreadable_before {
+        damaged.all = true;
+    }
+
+    // Losing an authority would silently lower the number of signatures necessary in order
+    // to finalize a block. The indices of the authorities of each chain must be contiguous.
+    for table in [
+        "grandpa_triggered_authorities",
+        "grandpa_scheduled_authorities",
+        "aura_finalized_authorities",
+    ] {
+        let mut statement = output
+            .prepare(&format!(
+                "SELECT chain_id FROM {table} GROUP BY chain_id HAVING MIN(idx) != 0 OR MAX(idx) != COUNT(*) - 1"
+            ))
+            .map_err(output_err)?;
+        let chains = statement
+            .query_map((), |row| row.get::<_, i64>(0))
+            .map_err(output_err)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(output_err)?;
+        damaged.ids.extend(chains);
+    }
+
+    // Runtimes can be found again in the storage of the blocks, and losing some of them is
+    // thus not a problem.
+    scan_table(
+        source,
+        "runtimes",
+        "chain_id, spec_version, code_hash, code, heap_pages",
+        &mut report.unreadable_ranges,
+        |row| {
+            match (
+                row.get::<_, i64>(1),
+                row.get::<_, i64>(2),
+                row.get::<_, Vec<u8>>(3),
+                row.get::<_, Vec<u8>>(4),
+                row.get::<_, i64>(5),
+            ) {
+                (Ok(chain_id), Ok(spec_version), Ok(code_hash), Ok(code), Ok(heap_pages))
+                    if *blake2_rfc::blake2b::blake2b(32, &[], &code).as_bytes() == code_hash =>
+                {
+                    output
+                        .execute(
+                            "INSERT OR IGNORE INTO runtimes(chain_id, spec_version, code_hash, code, heap_pages) VALUES (?, ?, ?, ?, ?)",
+                            (chain_id, spec_version, code_hash, code, heap_pages),
+                        )
+                        .map_err(output_err)?;
+                }
+                _ => report.runtimes_dropped += 1,
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(damaged)
+}
+
+/// Copies the blocks whose header is valid, then their body, events, and insertion
+/// information. Returns the number of blocks that have been read.
+fn copy_blocks(
+    source: &rusqlite::Connection,
+    output: &rusqlite::Connection,
+    block_number_bytes: usize,
+    report: &mut SalvageReport,
+) -> Result<u64, SalvageError> {
+    let mut blocks_read = 0;
+
+    scan_table(
+        source,
+        "blocks",
+        "hash, parent_hash, state_trie_root_hash, number, header, is_best_chain, body_pruned, runtime_spec_version, chain_id",
+        &mut report.unreadable_ranges,
+        |row| {
+            blocks_read += 1;
+
+            let (
+                Ok(hash),
+                Ok(parent_hash),
+                Ok(state_trie_root_hash),
+                Ok(number),
+                Ok(scale_encoded_header),
+                Ok(is_best_chain),
+                Ok(body_pruned),
+                Ok(runtime_spec_version),
+                Ok(chain_id),
+            ) = (
+                row.get::<_, Vec<u8>>(1),
+                row.get::<_, Option<Vec<u8>>>(2),
+                row.get::<_, Option<Vec<u8>>>(3),
+                row.get::<_, i64>(4),
+                row.get::<_, Vec<u8>>(5),
+                row.get::<_, bool>(6),
+                row.get::<_, bool>(7),
+                row.get::<_, Option<i64>>(8),
+                row.get::<_, i64>(9),
+            )
+            else {
+                return Ok(());
+            };
+
+            let Ok(decoded) = header::decode(&scale_encoded_header, block_number_bytes) else {
+                return Ok(());
+            };
+            if header::hash_from_scale_encoded_header(&scale_encoded_header)[..] != hash[..]
+                || u64::try_from(number) != Ok(decoded.number)
+                || parent_hash
+                    .as_ref()
+                    .is_some_and(|parent_hash| parent_hash[..] != decoded.parent_hash[..])
+                || state_trie_root_hash
+                    .as_ref()
+                    .is_some_and(|root| root[..] != decoded.state_root[..])
+            {
+                return Ok(());
+            }
+
+            prepare_cached(
+                output,
+                "INSERT OR IGNORE INTO blocks(hash, parent_hash, state_trie_root_hash, number, header, is_best_chain, body_pruned, runtime_spec_version, chain_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .and_then(|mut statement| {
+                statement.execute((
+                    hash,
+                    parent_hash,
+                    state_trie_root_hash,
+                    number,
+                    scale_encoded_header,
+                    is_best_chain,
+                    body_pruned,
+                    runtime_spec_version,
+                    chain_id,
+                ))
+            })
+            .map_err(output_err)?;
+            Ok(())
+        },
+    )?;
+
+    // The bodies are verified later, once all of their rows have been copied. The hash of the
+    // extrinsics is calculated again rather than copied, so that an extrinsic whose content
+    // has been damaged doesn't end up shared with other blocks.
+    scan_table(
+        source,
+        "blocks_body",
+        "hash, idx, (SELECT bytes FROM extrinsics WHERE extrinsics.hash = blocks_body.extrinsic_hash)",
+        &mut report.unreadable_ranges,
+        |row| {
+            if let (Ok(hash), Ok(idx), Ok(extrinsic)) = (
+                row.get::<_, Vec<u8>>(1),
+                row.get::<_, i64>(2),
+                row.get::<_, Vec<u8>>(3),
+            ) {
+                let extrinsic_hash = blake2_hash(&extrinsic);
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO extrinsics(hash, bytes) SELECT :extrinsic_hash, :extrinsic WHERE EXISTS(SELECT 1 FROM blocks WHERE hash = :hash)",
+                )
+                .and_then(|mut statement| {
+                    statement.execute(rusqlite::named_params! {
+                        ":hash": hash,
+                        ":extrinsic_hash": &extrinsic_hash[..],
+                        ":extrinsic": extrinsic,
+                    })
+                })
+                .map_err(output_err)?;
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO blocks_body(hash, idx, extrinsic_hash) SELECT :hash, :idx, :extrinsic_hash WHERE EXISTS(SELECT 1 FROM blocks WHERE hash = :hash)",
+                )
+                .and_then(|mut statement| {
+                    statement.execute(rusqlite::named_params! {
+                        ":hash": hash,
+                        ":idx": idx,
+                        ":extrinsic_hash": &extrinsic_hash[..],
+                    })
+                })
+                .map_err(output_err)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    scan_table(
+        source,
+        "blocks_events",
+        "hash, events",
+        &mut report.unreadable_ranges,
+        |row| {
+            if let (Ok(hash), Ok(events)) = (row.get::<_, Vec<u8>>(1), row.get::<_, Vec<u8>>(2)) {
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO blocks_events(hash, events) SELECT :hash, :events WHERE EXISTS(SELECT 1 FROM blocks WHERE hash = :hash)",
+                )
+                .and_then(|mut statement| {
+                    statement.execute(rusqlite::named_params! {
+                        ":hash": hash,
+                        ":events": events,
+                    })
+                })
+                .map_err(output_err)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    scan_table(
+        source,
+        "blocks_meta",
+        "hash, inserted_at_unix_ms, source",
+        &mut report.unreadable_ranges,
+        |row| {
+            if let (Ok(hash), Ok(inserted_at_unix_ms), Ok(block_source)) = (
+                row.get::<_, Vec<u8>>(1),
+                row.get::<_, i64>(2),
+                row.get::<_, String>(3),
+            ) {
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO blocks_meta(hash, inserted_at_unix_ms, source) SELECT :hash, :inserted_at_unix_ms, :source WHERE EXISTS(SELECT 1 FROM blocks WHERE hash = :hash)",
+                )
+                .and_then(|mut statement| {
+                    statement.execute(rusqlite::named_params! {
+                        ":hash": hash,
+                        ":inserted_at_unix_ms": inserted_at_unix_ms,
+                        ":source": block_source,
+                    })
+                })
+                .map_err(output_err)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    scan_table(
+        source,
+        "blocks_justifications",
+        "hash, engine_id, justification",
+        &mut report.unreadable_ranges,
+        |row| {
+            if let (Ok(hash), Ok(engine_id), Ok(justification)) = (
+                row.get::<_, Vec<u8>>(1),
+                row.get::<_, Vec<u8>>(2),
+                row.get::<_, Vec<u8>>(3),
+            ) {
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO blocks_justifications(hash, engine_id, justification) SELECT :hash, :engine_id, :justification WHERE EXISTS(SELECT 1 FROM blocks WHERE hash = :hash)",
+                )
+                .and_then(|mut statement| {
+                    statement.execute(rusqlite::named_params! {
+                        ":hash": hash,
+                        ":engine_id": engine_id,
+                        ":justification": justification,
+                    })
+                })
+                .map_err(output_err)?;
+            }
+            Ok(())
+        },
+    )?;
+
+    Ok(blocks_read)
+}
+
+/// Returns the identifiers of all the chains of the new database.
+fn chain_ids(output: &rusqlite::Connection) -> Result<Vec<i64>, SalvageError> {
+    let mut statement = output
+        .prepare("SELECT id FROM chains ORDER BY id")
+        .map_err(output_err)?;
+    let chain_ids = statement
+        .query_map((), |row| row.get::<_, i64>(0))
+        .map_err(output_err)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(output_err)?;
+    Ok(chain_ids)
+}
+
+fn chain_name(
+    output: &rusqlite::Connection,
+    chain_id: i64,
+) -> Result<Option<String>, SalvageError> {
+    output
+        .query_row("SELECT name FROM chains WHERE id = ?", (chain_id,), |row| {
+            row.get::<_, Option<String>>(0)
+        })
+        .map_err(output_err)
+}
+
+/// Returns `true` if the given chain doesn't have any block or any entry in the `meta` table,
+/// in which case it has never been initialized.
+fn chain_is_empty(output: &rusqlite::Connection, chain_id: i64) -> Result<bool, SalvageError> {
+    output
+        .query_row(
+            "SELECT NOT EXISTS(SELECT 1 FROM blocks WHERE chain_id = :chain_id) AND NOT EXISTS(SELECT 1 FROM meta WHERE chain_id = :chain_id)",
+            rusqlite::named_params! { ":chain_id": chain_id },
+            |row| row.get::<_, bool>(0),
+        )
+        .map_err(output_err)
+}
+
+/// Removes from the new database the rows whose chain isn't in the `chains` table, which can
+/// happen if the row of a chain couldn't be read.
+fn drop_unknown_chains(output: &rusqlite::Connection) -> Result<(), SalvageError> {
+    for table in [
+        "blocks",
+        "meta",
+        "grandpa_triggered_authorities",
+        "grandpa_scheduled_authorities",
+        "aura_finalized_authorities",
+        "runtimes",
+    ] {
+        output
+            .execute(
+                &format!("DELETE FROM {table} WHERE chain_id NOT IN (SELECT id FROM chains)"),
+                (),
+            )
+            .map_err(output_err)?;
+    }
+    Ok(())
+}
+
+/// Removes from the new database everything that belongs to the given chain.
+fn drop_chain(output: &rusqlite::Connection, chain_id: i64) -> Result<(), SalvageError> {
+    // The bodies, events, and insertion information of the blocks are removed through the
+    // foreign keys.
+    for table in [
+        "blocks",
+        "meta",
+        "grandpa_triggered_authorities",
+        "grandpa_scheduled_authorities",
+        "aura_finalized_authorities",
+        "runtimes",
+    ] {
+        output
+            .execute(
+                &format!("DELETE FROM {table} WHERE chain_id = ?"),
+                (chain_id,),
+            )
+            .map_err(output_err)?;
+    }
+    Ok(())
+}
+
+/// Makes sure that the best block of the chain designated by `current_chain` is the finalized
+/// block or one of its descendants, and that the blocks between them are marked as being part
+/// of the best chain.
+///
+/// Returns `false` if the finalized block of the chain couldn't be recovered.
+fn fix_best_chain(output: &rusqlite::Connection) -> Result<bool, SalvageError> {
+    let Some(finalized_hash) = output_consistent(finalized_hash(output))? else {
+        return Ok(false);
+    };
+    let Some(finalized_number) = output_consistent(finalized_num(output))? else {
+        return Ok(false);
+    };
+    let finalized_number = i64::try_from(finalized_number).unwrap_or(i64::MAX);
+
+    // The best chain is rebuilt starting from the finalized block. The blocks below the
+    // finalized block are left untouched.
+    output
+        .execute(
+            "UPDATE blocks SET is_best_chain = FALSE WHERE chain_id = (SELECT id FROM current_chain) AND number > ?",
+            (finalized_number,),
+        )
+        .map_err(output_err)?;
+
+    let best = output_consistent(meta_get_blob(output, "best"))?
+        .flatten()
+        .and_then(|best| <[u8; 32]>::try_from(best).ok());
+    let best = match best {
+        Some(best) if output_consistent(descends_from_finalized(output, &best))? == Some(true) => {
+            best
+        }
+        _ => {
+            if output_consistent(meta_set_blob(output, "best", &finalized_hash))?.is_none() {
+                return Ok(false);
+            }
+            finalized_hash
+        }
+    };
+
+    output
+        .execute(
+            r#"
+        WITH RECURSIVE
+            ancestry(hash, number) AS (
+                SELECT hash, number FROM blocks WHERE hash = :best
+            UNION ALL
+                SELECT parent.hash, parent.number
+                FROM ancestry
+                JOIN blocks AS child ON child.hash = ancestry.hash
+                JOIN blocks AS parent ON parent.hash = child.parent_hash
+                WHERE ancestry.number > :finalized_number
+            )
+        UPDATE blocks SET is_best_chain = TRUE
+        WHERE hash IN (SELECT hash FROM ancestry WHERE number > :finalized_number)
+            "#,
+            rusqlite::named_params! {
+                ":best": &best[..],
+                ":finalized_number": finalized_number,
+            },
+        )
+        .map_err(output_err)?;
+
+    Ok(true)
+}
+
+/// Verifies the body of each block of the new database whose body isn't pruned against the
+/// extrinsics root of its header. Bodies that don't match are removed, and their block is marked
+/// as having its body pruned. Returns the number of bodies that have been removed.
+fn verify_bodies(
+    output: &rusqlite::Connection,
+    block_number_bytes: usize,
+) -> Result<u64, SalvageError> {
+    let mut num_removed = 0;
+    let mut last_rowid = -1i64;
+
+    loop {
+        // The blocks are read by batches, as loading all the headers at once could use a lot
+        // of memory.
+        let mut statement = prepare_cached(
+            output,
+            "SELECT rowid, hash, header FROM blocks WHERE rowid > ? AND body_pruned = FALSE ORDER BY rowid LIMIT 1024",
+        )
+        .map_err(output_err)?;
+        let blocks = statement
+            .query_map((last_rowid,), |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Vec<u8>>(1)?,
+                    row.get::<_, Vec<u8>>(2)?,
+                ))
+            })
+            .map_err(output_err)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(output_err)?;
+        drop(statement);
+
+        let Some((rowid, ..)) = blocks.last() else {
+            return Ok(num_removed);
+        };
+        last_rowid = *rowid;
+
+        for (_, hash, scale_encoded_header) in blocks {
+            let mut statement = prepare_cached(
+                output,
+                "SELECT idx, extrinsics.bytes FROM blocks_body JOIN extrinsics ON extrinsics.hash = blocks_body.extrinsic_hash WHERE blocks_body.hash = ? ORDER BY idx",
+            )
+            .map_err(output_err)?;
+            let body = statement
+                .query_map((&hash,), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+                })
+                .map_err(output_err)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(output_err)?;
+
+            let is_complete = body
+                .iter()
+                .enumerate()
+                .all(|(n, (idx, _))| i64::try_from(n) == Ok(*idx));
+            let extrinsics = body
+                .into_iter()
+                .map(|(_, extrinsic)| extrinsic)
+                .collect::<Vec<_>>();
+            let is_valid = is_complete
+                && header::decode(&scale_encoded_header, block_number_bytes).is_ok_and(|decoded| {
+                    *decoded.extrinsics_root == header::extrinsics_root(&extrinsics)
+                });
+            if is_valid {
+                continue;
+            }
+
+            // The events are removed at the same time as the body of the block.
+            output
+                .execute_batch("SAVEPOINT verify_bodies")
+                .map_err(output_err)?;
+            for statement in [
+                "DELETE FROM blocks_body WHERE hash = ?",
+                "DELETE FROM blocks_events WHERE hash = ?",
+                "UPDATE blocks SET body_pruned = TRUE WHERE hash = ?",
+            ] {
+                prepare_cached(output, statement)
+                    .and_then(|mut statement| statement.execute((&hash,)))
+                    .map_err(output_err)?;
+            }
+            output
+                .execute_batch("RELEASE verify_bodies")
+                .map_err(output_err)?;
+            num_removed += 1;
+        }
+    }
+}
+
+/// Copies the trie nodes whose Merkle value matches their content.
+fn copy_trie_nodes(
+    source: &rusqlite::Connection,
+    output: &rusqlite::Connection,
+    report: &mut SalvageReport,
+) -> Result<(), SalvageError> {
+    scan_table(
+        source,
+        "trie_node",
+        "hash, partial_key, children",
+        &mut report.unreadable_ranges,
+        |row| {
+            let (Ok(merkle_value), Ok(partial_key), Ok(children)) = (
+                row.get::<_, Vec<u8>>(1),
+                row.get::<_, Vec<u8>>(2),
+                row.get::<_, Option<Vec<u8>>>(3),
+            ) else {
+                report.trie_nodes_dropped += 1;
+                return Ok(());
+            };
+
+            let Ok(storage) = prepare_cached(
+                source,
+                "SELECT value, value_compressed, trie_root_ref, trie_entry_version FROM trie_node_storage WHERE node_hash = ?",
+            )
+            .and_then(|mut statement| {
+                statement
+                    .query_row((&merkle_value,), |row| {
+                        Ok(TrieNodeStorage {
+                            value: row.get(0)?,
+                            value_compressed: row.get(1)?,
+                            trie_root_ref: row.get(2)?,
+                            trie_entry_version: row.get(3)?,
+                        })
+                    })
+                    .optional()
+            })
+            else {
+                report.trie_nodes_dropped += 1;
+                return Ok(());
+            };
+
+            if !trie_node_is_valid(
+                &merkle_value,
+                &partial_key,
+                children.as_deref(),
+                storage.as_ref(),
+            ) {
+                report.trie_nodes_dropped += 1;
+                return Ok(());
+            }
+
+            prepare_cached(
+                output,
+                "INSERT OR IGNORE INTO trie_node(hash, partial_key, children) VALUES(?, ?, ?)",
+            )
+            .and_then(|mut statement| statement.execute((&merkle_value, &partial_key, &children)))
+            .map_err(output_err)?;
+            if let Some(storage) = storage {
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO trie_node_storage(node_hash, value, value_compressed, trie_root_ref, trie_entry_version) VALUES(?, ?, ?, ?, ?)",
+                )
+                .and_then(|mut statement| {
+                    statement.execute((
+                        &merkle_value,
+                        storage.value,
+                        storage.value_compressed,
+                        storage.trie_root_ref,
+                        storage.trie_entry_version,
+                    ))
+                })
+                .map_err(output_err)?;
+            }
+            for child in children
+                .as_deref()
+                .and_then(decode_children)
+                .into_iter()
+                .flatten()
+                .flatten()
+            {
+                prepare_cached(
+                    output,
+                    "INSERT OR IGNORE INTO trie_node_parent(child_hash, hash) VALUES(?, ?)",
+                )
+                .and_then(|mut statement| statement.execute((child, &merkle_value)))
+                .map_err(output_err)?;
+            }
+
+            report.trie_nodes_copied += 1;
+            Ok(())
+        },
+    )
+}
+
+/// Row of the `trie_node_storage` table.
+struct TrieNodeStorage {
+    value: Option<Vec<u8>>,
+    value_compressed: bool,
+    trie_root_ref: Option<Vec<u8>>,
+    trie_entry_version: i64,
+}
+
+/// Returns `true` if the content of the given row of the `trie_node` table, and of the
+/// corresponding row of the `trie_node_storage` table if any, is well-formed and matches the
+/// Merkle value of the node.
+fn trie_node_is_valid(
+    merkle_value: &[u8],
+    partial_key: &[u8],
+    children: Option<&[u8]>,
+    storage: Option<&TrieNodeStorage>,
+) -> bool {
+    if merkle_value.is_empty() || merkle_value.len() > 32 {
+        return false;
+    }
+
+    let Some(partial_key) = unpack_nibbles(partial_key).and_then(|nibbles| {
+        nibbles
+            .into_iter()
+            .map(|nibble| trie::Nibble::try_from(nibble).ok())
+            .collect::<Option<Vec<_>>>()
+    }) else {
+        return false;
+    };
+
+    let children = match children.map(decode_children) {
+        None => [None; 16],
+        Some(Some(children)) => children,
+        Some(None) => return false,
+    };
+    if children
+        .iter()
+        .flatten()
+        .any(|child| child.is_empty() || child.len() > 32)
+    {
+        return false;
+    }
+
+    let storage_value = match storage {
+        None => None,
+        Some(storage) => {
+            let value = match (
+                &storage.value,
+                storage.value_compressed,
+                &storage.trie_root_ref,
+            ) {
+                (Some(value), false, None) => value.clone(),
+                (Some(value), true, None) => match zstd::decode_all(&value[..]) {
+                    Ok(value) => value,
+                    Err(_) => return false,
+                },
+                (None, false, Some(trie_root_ref)) => trie_root_ref.clone(),
+                _ => return false,
+            };
+            let Some(version) = u8::try_from(storage.trie_entry_version)
+                .ok()
+                .and_then(|version| trie::TrieEntryVersion::try_from(version).ok())
+            else {
+                return false;
+            };
+            Some((value, version))
+        }
+    };
+
+    // In version 1 of the trie, values of 33 bytes or more are hashed.
+    let value_hash = match &storage_value {
+        Some((value, trie::TrieEntryVersion::V1)) if value.len() >= 33 => Some(
+            <[u8; 32]>::try_from(blake2_rfc::blake2b::blake2b(32, &[], value).as_bytes())
+                .unwrap_or_else(|_| unreachable!()),
+        ),
+        _ => None,
+    };
+
+    // The Merkle value of the root node of a trie is always a hash, while the Merkle value of
+    // other nodes is a hash only if their encoding is at least 32 bytes. A Merkle value of 32
+    // bytes is thus calculated as if the node was a root node, which gives the same outcome
+    // for non-root nodes.
+    let calculated = trie::trie_node::calculate_merkle_value(
+        trie::trie_node::Decoded {
+            children,
+            partial_key: partial_key.into_iter(),
+            storage_value: match (&storage_value, &value_hash) {
+                (_, Some(hash)) => trie::trie_node::StorageValue::Hashed(hash),
+                (Some((value, _)), None) => trie::trie_node::StorageValue::Unhashed(value),
+                (None, None) => trie::trie_node::StorageValue::None,
+            },
+        },
+        trie::HashFunction::Blake2,
+        merkle_value.len() == 32,
+    );
+
+    calculated.is_ok_and(|calculated| calculated.as_ref() == merkle_value)
+}
@@ -0,0 +1,260 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Reading from the database multiple times while observing the same state.
+//!
+//! See [`SqliteFullDatabase::with_read_snapshot`].
+
+use super::{
+    best_block_hash, best_block_hash_by_number, block_header, block_info, block_parent,
+    block_storage_closest_descendant_merkle_value, block_storage_get, block_storage_next_key,
+    blocks_by_number, collect_nibbles, finalized_hash, full_block, sqlite_err,
+    storage_key_vectored, tree_route, with_block_extrinsics, BlockAtNumber, BlockExtrinsicsError,
+    BlockRef, CorruptedError, FullBlock, ParentOf, SqliteFullDatabase, StorageAccessError,
+    TreeRoute,
+};
+use crate::trie;
+
+use alloc::vec::Vec;
+use core::{cell::RefCell, ops};
+
+impl SqliteFullDatabase {
+    /// Calls `f` with a [`ReadSnapshot`] through which all the reads observe the same state of
+    /// the database, then returns the value returned by `f`.
+    ///
+    /// Each method of [`SqliteFullDatabase`] releases the database once it returns, meaning that
+    /// for example a block can be purged or the finalized block can be updated in parallel in
+    /// between two calls. The [`ReadSnapshot`] instead holds one connection and one transaction
+    /// for the entire duration of `f`, and none of the modifications performed in parallel are
+    /// visible through it.
+    ///
+    /// Because the connection is held for the entire duration of `f`, modifications performed
+    /// in parallel might have to wait for `f` to return. `f` should thus return quickly.
+    ///
+    /// # Panics
+    ///
+    /// `f` must not call any of the methods of this [`SqliteFullDatabase`] and must only use
+    /// the [`ReadSnapshot`] instead, as doing so could otherwise deadlock. Calling a method of
+    /// this [`SqliteFullDatabase`] from within `f` on the same thread panics.
+    pub fn with_read_snapshot<R>(
+        &self,
+        f: impl FnOnce(&ReadSnapshot) -> R,
+    ) -> Result<R, CorruptedError> {
+        let mut connection = self.read_connection();
+        let transaction = connection
+            .savepoint()
+            .map_err(sqlite_err("with_read_snapshot"))?;
+
+        let _guard = ActiveSnapshotGuard::new(self);
+        Ok(f(&ReadSnapshot {
+            connection: &transaction,
+        }))
+    }
+}
+
+/// Read-only access to the database where all the reads observe the same state.
+///
+/// See [`SqliteFullDatabase::with_read_snapshot`]. The methods of this struct behave the same
+/// as the methods of [`SqliteFullDatabase`] with the same name.
+pub struct ReadSnapshot<'a> {
+    connection: &'a rusqlite::Connection,
+}
+
+impl<'a> ReadSnapshot<'a> {
+    /// See [`SqliteFullDatabase::best_block_hash`].
+    pub fn best_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
+        best_block_hash(self.connection)
+    }
+
+    /// See [`SqliteFullDatabase::finalized_block_hash`].
+    pub fn finalized_block_hash(&self) -> Result<[u8; 32], CorruptedError> {
+        finalized_hash(self.connection)
+    }
+
+    /// See [`SqliteFullDatabase::block_scale_encoded_header`].
+    ///
+    /// Contrary to [`SqliteFullDatabase::block_scale_encoded_header`], calling this method
+    /// multiple times with the same block hash always returns the same result.
+    pub fn block_scale_encoded_header(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, CorruptedError> {
+        block_header(self.connection, block_hash)
+    }
+
+    /// See [`SqliteFullDatabase::block_parent`].
+    pub fn block_parent(&self, block_hash: &[u8; 32]) -> Result<Option<ParentOf>, CorruptedError> {
+        block_parent(self.connection, block_hash)
+    }
+
+    /// See [`SqliteFullDatabase::block_info`].
+    pub fn block_info(&self, block_hash: &[u8; 32]) -> Result<Option<BlockRef>, CorruptedError> {
+        block_info(self.connection, block_hash)
+    }
+
+    /// See [`SqliteFullDatabase::block_extrinsics`].
+    pub fn block_extrinsics(
+        &self,
+        block_hash: &[u8; 32],
+    ) -> Result<Option<impl ExactSizeIterator<Item = Vec<u8>>>, BlockExtrinsicsError> {
+        let mut result = Vec::new();
+        let outcome = self.with_block_extrinsics(block_hash, |extrinsic| {
+            result.push(extrinsic.to_vec());
+            ops::ControlFlow::Continue(())
+        })?;
+        Ok(outcome.map(|()| result.into_iter()))
+    }
+
+    /// See [`SqliteFullDatabase::with_block_extrinsics`].
+    pub fn with_block_extrinsics(
+        &self,
+        block_hash: &[u8; 32],
+        on_extrinsic: impl FnMut(&[u8]) -> ops::ControlFlow<()>,
+    ) -> Result<Option<()>, BlockExtrinsicsError> {
+        with_block_extrinsics(self.connection, block_hash, on_extrinsic)
+    }
+
+    /// See [`SqliteFullDatabase::full_block`].
+    pub fn full_block(&self, block_hash: &[u8; 32]) -> Result<Option<FullBlock>, CorruptedError> {
+        full_block(self.connection, block_hash)
+    }
+
+    /// See [`SqliteFullDatabase::block_hash_by_number`].
+    pub fn block_hash_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<impl ExactSizeIterator<Item = [u8; 32]>, CorruptedError> {
+        Ok(blocks_by_number(self.connection, block_number)?
+            .into_iter()
+            .map(|block| block.hash))
+    }
+
+    /// See [`SqliteFullDatabase::blocks_by_number`].
+    pub fn blocks_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Vec<BlockAtNumber>, CorruptedError> {
+        blocks_by_number(self.connection, block_number)
+    }
+
+    /// See [`SqliteFullDatabase::best_block_hash_by_number`].
+    pub fn best_block_hash_by_number(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<[u8; 32]>, CorruptedError> {
+        best_block_hash_by_number(self.connection, block_number)
+    }
+
+    /// See [`SqliteFullDatabase::tree_route`].
+    pub fn tree_route(
+        &self,
+        from: &[u8; 32],
+        to: &[u8; 32],
+    ) -> Result<Option<TreeRoute>, CorruptedError> {
+        tree_route(self.connection, from, to)
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_get_versioned`].
+    pub fn block_storage_get(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+    ) -> Result<Option<(Vec<u8>, trie::TrieEntryVersion)>, StorageAccessError> {
+        let (key_vectored, is_main_trie) =
+            storage_key_vectored(parent_tries_paths_nibbles, key_nibbles)?;
+        block_storage_get(self.connection, block_hash, &key_vectored, is_main_trie)
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_next_key`].
+    pub fn block_storage_next_key(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+        prefix_nibbles: impl Iterator<Item = u8>,
+        branch_nodes: bool,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        let parent_tries_paths_nibbles = parent_tries_paths_nibbles
+            .map(collect_nibbles)
+            .collect::<Result<Vec<_>, _>>()?;
+        block_storage_next_key(
+            self.connection,
+            block_hash,
+            &parent_tries_paths_nibbles,
+            &collect_nibbles(key_nibbles)?,
+            &collect_nibbles(prefix_nibbles)?,
+            branch_nodes,
+            &mut 0,
+        )
+    }
+
+    /// See [`SqliteFullDatabase::block_storage_closest_descendant_merkle_value`].
+    pub fn block_storage_closest_descendant_merkle_value(
+        &self,
+        block_hash: &[u8; 32],
+        parent_tries_paths_nibbles: impl Iterator<Item = impl Iterator<Item = u8>>,
+        key_nibbles: impl Iterator<Item = u8>,
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        let (key_vectored, _) = storage_key_vectored(parent_tries_paths_nibbles, key_nibbles)?;
+        block_storage_closest_descendant_merkle_value(self.connection, block_hash, &key_vectored)
+    }
+}
+
+std::thread_local! {
+    /// Addresses of the [`SqliteFullDatabase`]s for which [`SqliteFullDatabase::with_read_snapshot`]
+    /// is currently being called on the current thread.
+    static ACTIVE_SNAPSHOTS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Panics if [`SqliteFullDatabase::with_read_snapshot`] is currently being called on the current
+/// thread with the given database.
+///
+/// Must be called before locking any of the connections of the database.
+pub(super) fn assert_no_active_snapshot(database: &SqliteFullDatabase) {
+    let address = database as *const SqliteFullDatabase as usize;
+    if ACTIVE_SNAPSHOTS.with(|list| list.borrow().contains(&address)) {
+        panic!(
+            "SqliteFullDatabase accessed from within the closure passed to with_read_snapshot; \
+            use the ReadSnapshot instead"
+        );
+    }
+}
+
+/// Registers the database in [`ACTIVE_SNAPSHOTS`] for as long as it is alive.
+struct ActiveSnapshotGuard {
+    address: usize,
+}
+
+impl ActiveSnapshotGuard {
+    fn new(database: &SqliteFullDatabase) -> Self {
+        let address = database as *const SqliteFullDatabase as usize;
+        ACTIVE_SNAPSHOTS.with(|list| list.borrow_mut().push(address));
+        ActiveSnapshotGuard { address }
+    }
+}
+
+impl Drop for ActiveSnapshotGuard {
+    fn drop(&mut self) {
+        ACTIVE_SNAPSHOTS.with(|list| {
+            let mut list = list.borrow_mut();
+            if let Some(pos) = list.iter().rposition(|a| *a == self.address) {
+                list.remove(pos);
+            }
+        });
+    }
+}
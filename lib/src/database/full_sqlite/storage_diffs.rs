@@ -0,0 +1,228 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Storage changes of individual blocks.
+//!
+//! The storage of a block can only be accessed if all the trie nodes of its state are in the
+//! database. Storing the list of storage changes made by each block, in other words its *diff*,
+//! makes it possible to read the storage of blocks whose trie nodes are missing, by walking
+//! back from the block to the closest ancestor whose storage is available.
+
+use super::{
+    block_parent, block_storage_get, has_block, prepare_cached, sqlite_err, storage_key_vectored,
+    CorruptedError, OperationKind, ParentOf, SqliteFullDatabase, StorageAccessError,
+};
+use crate::trie;
+
+use alloc::vec::Vec;
+use core::iter;
+use rusqlite::OptionalExtension as _;
+
+impl SqliteFullDatabase {
+    /// Stores the list of changes that the given block has made to the main trie of the storage
+    /// of its parent, as calculated when executing the block. Each item of `diff` contains a key
+    /// and its new value, or `None` if the key has been removed.
+    ///
+    /// The diff previously stored for this block, if any, is replaced. The diffs of the child
+    /// tries aren't stored, and their content can thus only be accessed normally.
+    ///
+    /// The diffs are used by [`SqliteFullDatabase::block_storage_get_historical`], and can be
+    /// removed with [`SqliteFullDatabase::prune_storage_diffs_below`]. They are also removed
+    /// alongside with their block.
+    pub fn insert_storage_diff(
+        &self,
+        block_hash: &[u8; 32],
+        diff: impl Iterator<Item = (impl AsRef<[u8]>, Option<impl AsRef<[u8]>>)>,
+    ) -> Result<(), InsertStorageDiffError> {
+        // Process the iterator at the very beginning and before locking the database, in order
+        // to avoid a deadlock in case its `next()` function accesses the database as well.
+        let diff = diff.collect::<Vec<_>>();
+
+        let mut database = self.lock_write()?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("insert_storage_diff"))?;
+
+        if !has_block(&transaction, block_hash)? {
+            return Err(InsertStorageDiffError::UnknownBlock);
+        }
+
+        // Removing the block from `blocks_storage_diff` also removes its previous diff through
+        // the foreign key.
+        prepare_cached(
+            &transaction,
+            "DELETE FROM blocks_storage_diff WHERE hash = ?",
+        )
+        .map_err(sqlite_err("insert_storage_diff"))?
+        .execute((&block_hash[..],))
+        .map_err(sqlite_err("insert_storage_diff"))?;
+        prepare_cached(
+            &transaction,
+            "INSERT INTO blocks_storage_diff(hash) VALUES (?)",
+        )
+        .map_err(sqlite_err("insert_storage_diff"))?
+        .execute((&block_hash[..],))
+        .map_err(sqlite_err("insert_storage_diff"))?;
+
+        {
+            let mut statement = prepare_cached(
+                &transaction,
+                "INSERT OR REPLACE INTO storage_diffs(hash, key, new_value) VALUES (?, ?, ?)",
+            )
+            .map_err(sqlite_err("insert_storage_diff"))?;
+            for (key, new_value) in &diff {
+                statement
+                    .execute((
+                        &block_hash[..],
+                        key.as_ref(),
+                        new_value.as_ref().map(|value| value.as_ref()),
+                    ))
+                    .map_err(sqlite_err("insert_storage_diff"))?;
+            }
+        }
+
+        transaction
+            .commit()
+            .map_err(sqlite_err("insert_storage_diff"))?;
+        database.finish(0)?;
+        Ok(())
+    }
+
+    /// Returns the value associated with the given key in the main trie of the given block.
+    ///
+    /// Contrary to [`SqliteFullDatabase::block_storage_get_bytes`], the value can be found even
+    /// if some trie nodes of the storage of the block are missing, provided that the diffs of
+    /// the block and of its ancestors have been stored with
+    /// [`SqliteFullDatabase::insert_storage_diff`]. The diffs are looked up from the block
+    /// towards its ancestors, until either a diff containing the key or an ancestor whose
+    /// storage is complete is found.
+    ///
+    /// Returns [`StorageAccessError::IncompleteStorage`] if neither is found, for example
+    /// because the diff of one of the blocks in between is missing.
+    pub fn block_storage_get_historical(
+        &self,
+        block_hash: &[u8; 32],
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, StorageAccessError> {
+        let _timer = self.time_operation(OperationKind::StorageGetHistorical);
+        let (key_vectored, _) = storage_key_vectored(
+            iter::empty::<iter::Empty<u8>>(),
+            trie::bytes_to_nibbles(key.iter().copied()).map(u8::from),
+        )?;
+
+        let mut connection = self.read_connection();
+        // The ancestors of the block are read within the same transaction in order to not
+        // observe a partially-purged chain.
+        let transaction = connection
+            .savepoint()
+            .map_err(sqlite_err("block_storage_get_historical"))?;
+
+        let mut current = *block_hash;
+        loop {
+            match block_storage_get(&transaction, &current, &key_vectored, true) {
+                Ok(value) => return Ok(value.map(|(value, _)| value)),
+                Err(StorageAccessError::IncompleteStorage) => {}
+                // The parent of the first block that has been inserted isn't in the database.
+                Err(StorageAccessError::UnknownBlock) if current != *block_hash => {
+                    return Err(StorageAccessError::IncompleteStorage)
+                }
+                Err(err) => return Err(err),
+            }
+
+            let has_diff = prepare_cached(
+                &transaction,
+                "SELECT EXISTS(SELECT 1 FROM blocks_storage_diff WHERE hash = ?)",
+            )
+            .map_err(sqlite_err("block_storage_get_historical"))?
+            .query_row((&current[..],), |row| row.get::<_, bool>(0))
+            .map_err(sqlite_err("block_storage_get_historical"))?;
+            if !has_diff {
+                return Err(StorageAccessError::IncompleteStorage);
+            }
+
+            let entry = prepare_cached(
+                &transaction,
+                "SELECT new_value FROM storage_diffs WHERE hash = ? AND key = ?",
+            )
+            .map_err(sqlite_err("block_storage_get_historical"))?
+            .query_row((&current[..], key), |row| row.get::<_, Option<Vec<u8>>>(0))
+            .optional()
+            .map_err(sqlite_err("block_storage_get_historical"))?;
+            if let Some(new_value) = entry {
+                return Ok(new_value);
+            }
+
+            match block_parent(&transaction, &current)? {
+                Some(ParentOf::Block(parent_hash)) => current = parent_hash,
+                // The diff of the genesis block is relative to an empty storage.
+                Some(ParentOf::Genesis) => return Ok(None),
+                None => return Err(StorageAccessError::UnknownBlock),
+            }
+        }
+    }
+
+    /// Removes the diffs of the blocks whose number is strictly inferior to the given number,
+    /// and returns the number of blocks whose diff has been removed.
+    ///
+    /// See [`SqliteFullDatabase::insert_storage_diff`]. See also
+    /// [`super::Config::storage_diff_retention`].
+    pub fn prune_storage_diffs_below(&self, block_number: u64) -> Result<u64, CorruptedError> {
+        let mut timer = self.time_operation(OperationKind::PruneStorageDiffs);
+        let mut database = self.lock_write()?;
+        let transaction = database
+            .savepoint()
+            .map_err(sqlite_err("prune_storage_diffs_below"))?;
+        let num_removed = prune_storage_diffs_below(&transaction, block_number)?;
+        transaction
+            .commit()
+            .map_err(sqlite_err("prune_storage_diffs_below"))?;
+        database.finish(0)?;
+        timer.set_rows(num_removed);
+        Ok(num_removed)
+    }
+}
+
+/// Removes the diffs of the blocks whose number is strictly inferior to the given number, and
+/// returns the number of blocks whose diff has been removed.
+pub(super) fn prune_storage_diffs_below(
+    transaction: &rusqlite::Connection,
+    block_number: u64,
+) -> Result<u64, CorruptedError> {
+    let num_removed = prepare_cached(
+        transaction,
+        r#"
+        DELETE FROM blocks_storage_diff
+        WHERE hash IN (
+            SELECT hash FROM blocks
+            WHERE chain_id = (SELECT id FROM current_chain) AND number < ?
+        )"#,
+    )
+    .map_err(sqlite_err("prune_storage_diffs_below"))?
+    .execute((i64::try_from(block_number).unwrap_or(i64::MAX),))
+    .map_err(sqlite_err("prune_storage_diffs_below"))?;
+    Ok(u64::try_from(num_removed).unwrap_or(u64::MAX))
+}
+
+/// Error while calling [`SqliteFullDatabase::insert_storage_diff`].
+#[derive(Debug, derive_more::Display, derive_more::From)]
+pub enum InsertStorageDiffError {
+    /// Error accessing the database.
+    #[display(fmt = "{_0}")]
+    Corrupted(CorruptedError),
+    /// Block couldn't be found in the database.
+    UnknownBlock,
+}
@@ -0,0 +1,302 @@
+// Smoldot
+// Copyright (C) 2023  Pierre Krieger
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Iterating over the storage entries of a block.
+//!
+//! See [`SqliteFullDatabase::block_storage_iter`] and
+//! [`SqliteFullDatabase::block_storage_prefix_entries`].
+
+use super::{
+    block_state_trie_root, block_storage_get, block_storage_next_key, children_list,
+    prepare_cached, sqlite_err, CorruptedError, OperationKind, SqliteFullDatabase,
+    StorageAccessError, MAX_TRIE_DEPTH,
+};
+use crate::trie;
+
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::num::NonZeroUsize;
+use rusqlite::OptionalExtension as _;
+
+/// Maximum number of entries read from the database every time the database is locked.
+const PAGE_SIZE: usize = 256;
+
+impl SqliteFullDatabase {
+    /// Returns an iterator to all the entries of the main trie of the storage of the given
+    /// block whose key starts with `prefix`, in increasing key order.
+    ///
+    /// The entries are read from the database by pages of multiple entries, and the database is
+    /// only locked while reading each page. In other words, the database isn't locked while the
+    /// iterator is alive, and destroying the iterator before it has finished doesn't need any
+    /// clean up.
+    ///
+    /// Because the database isn't locked for the entire iteration, modifications performed in
+    /// parallel can be observed between two pages. If the block or its storage are removed from
+    /// the database in the middle of the iteration, the iterator yields an error. The iterator
+    /// ends after yielding an error.
+    pub fn block_storage_iter(&self, block_hash: &[u8; 32], prefix: &[u8]) -> StorageIter<'_> {
+        let prefix_nibbles = trie::bytes_to_nibbles(prefix.iter().copied())
+            .map(u8::from)
+            .collect::<Vec<_>>();
+
+        StorageIter {
+            database: self,
+            block_hash: *block_hash,
+            next_key_nibbles: Some(prefix_nibbles.clone()),
+            prefix_nibbles,
+            page: VecDeque::new(),
+        }
+    }
+
+    /// Returns the keys and values of the entries of the main trie of the storage of the given
+    /// block whose key starts with `prefix` and is superior or equal to `start_key`, in
+    /// increasing key order.
+    ///
+    /// Contrary to [`SqliteFullDatabase::block_storage_iter`], the trie is walked down only once
+    /// and the subtrees that can't contain any matching key are skipped. All the entries are
+    /// read from a single consistent snapshot of the database.
+    ///
+    /// If `max_value_bytes` is `Some`, the entries stop being collected as soon as the total
+    /// size of their values reaches this limit, and [`PrefixEntries::continuation`] contains
+    /// the key of the next entry. Calling this function again with this key as `start_key`
+    /// resumes where the previous call has stopped. At least one entry is always returned if
+    /// there is any.
+    ///
+    /// If an entry is the root of a child trie, its value is the Merkle value of this child
+    /// trie, similar to [`SqliteFullDatabase::block_storage_get_versioned`].
+    ///
+    /// Returns [`StorageAccessError::UnknownBlock`] if the block isn't in the database, or
+    /// [`StorageAccessError::IncompleteStorage`] if some of the trie nodes that must be visited
+    /// are missing from the database.
+    pub fn block_storage_prefix_entries(
+        &self,
+        block_hash: &[u8; 32],
+        prefix: &[u8],
+        start_key: Option<&[u8]>,
+        max_value_bytes: Option<NonZeroUsize>,
+    ) -> Result<PrefixEntries, StorageAccessError> {
+        let mut timer = self.time_operation(OperationKind::StoragePrefixEntries);
+        let prefix_nibbles = trie::bytes_to_nibbles(prefix.iter().copied())
+            .map(u8::from)
+            .collect::<Vec<_>>();
+        let start_nibbles = trie::bytes_to_nibbles(start_key.unwrap_or_default().iter().copied())
+            .map(u8::from)
+            .collect::<Vec<_>>();
+
+        // Returns `true` if the subtree whose keys all start with `key_nibbles` might contain
+        // keys that start with `prefix` and are superior or equal to `start_key`.
+        let subtree_matches = |key_nibbles: &[u8]| {
+            let common = key_nibbles.len().min(prefix_nibbles.len());
+            let start = &start_nibbles[..key_nibbles.len().min(start_nibbles.len())];
+            key_nibbles[..common] == prefix_nibbles[..common] && key_nibbles >= start
+        };
+
+        let mut connection = self.read_connection();
+        let transaction = connection
+            .savepoint()
+            .map_err(sqlite_err("block_storage_prefix_entries"))?;
+
+        let mut statement = prepare_cached(
+            &transaction,
+            "SELECT unpack_nibbles(trie_node.partial_key), COALESCE(decompress_value(trie_node_storage.value, trie_node_storage.value_compressed), trie_node_storage.trie_root_ref), trie_node.children FROM trie_node LEFT JOIN trie_node_storage ON trie_node_storage.node_hash = trie_node.hash WHERE trie_node.hash = ?",
+        )
+        .map_err(sqlite_err("block_storage_prefix_entries"))?;
+
+        let mut output = PrefixEntries {
+            entries: Vec::new(),
+            continuation: None,
+        };
+        let mut value_bytes = 0usize;
+
+        // Nodes that remain to be visited, with the key (in nibbles) of their parent plus child
+        // index, and their depth. Visiting the nodes depth first and in the order of their
+        // child index yields the entries in increasing key order.
+        let mut stack = vec![(
+            block_state_trie_root(&transaction, block_hash)?,
+            Vec::new(),
+            0,
+        )];
+
+        while let Some((node_hash, mut key_nibbles, depth)) = stack.pop() {
+            if depth >= MAX_TRIE_DEPTH {
+                return Err(StorageAccessError::Corrupted(
+                    CorruptedError::TrieLoopDetected,
+                ));
+            }
+
+            let (partial_key, value, children) = statement
+                .query_row((&node_hash,), |row| {
+                    Ok((
+                        row.get::<_, Option<Vec<u8>>>(0)?,
+                        row.get::<_, Option<Vec<u8>>>(1)?,
+                        row.get::<_, Option<Vec<u8>>>(2)?,
+                    ))
+                })
+                .optional()
+                .map_err(sqlite_err("block_storage_prefix_entries"))?
+                .ok_or(StorageAccessError::IncompleteStorage)?;
+
+            key_nibbles.extend_from_slice(&partial_key.unwrap_or_default());
+            if !subtree_matches(&key_nibbles) {
+                continue;
+            }
+
+            if let Some(value) = value.filter(|_| {
+                key_nibbles.starts_with(&prefix_nibbles) && key_nibbles >= start_nibbles
+            }) {
+                // Keys that don't contain an entire number of bytes can't legitimately exist in
+                // the storage of a block.
+                if key_nibbles.len() % 2 != 0 || key_nibbles.iter().any(|n| *n >= 16) {
+                    return Err(StorageAccessError::Corrupted(
+                        CorruptedError::InvalidStorageKey,
+                    ));
+                }
+                let key = key_nibbles
+                    .chunks(2)
+                    .map(|n| (n[0] << 4) | n[1])
+                    .collect::<Vec<_>>();
+
+                if max_value_bytes.is_some_and(|max| value_bytes >= max.get()) {
+                    output.continuation = Some(key);
+                    break;
+                }
+
+                value_bytes = value_bytes.saturating_add(value.len());
+                output.entries.push((key, value));
+            }
+
+            if let Some(children) = children {
+                for (child_num, child_hash) in children_list(&children)
+                    .map_err(StorageAccessError::Corrupted)?
+                    .into_iter()
+                    .rev()
+                {
+                    let mut child_key_nibbles = key_nibbles.clone();
+                    child_key_nibbles.push(child_num);
+                    if subtree_matches(&child_key_nibbles) {
+                        stack.push((child_hash, child_key_nibbles, depth + 1));
+                    }
+                }
+            }
+        }
+
+        timer.set_rows(output.entries.len());
+        Ok(output)
+    }
+}
+
+/// Entries returned by [`SqliteFullDatabase::block_storage_prefix_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixEntries {
+    /// Keys and values of the entries, in increasing key order.
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+
+    /// If the size limit has been reached, key of the entry following the last one of
+    /// [`PrefixEntries::entries`]. `None` if all the matching entries have been returned.
+    pub continuation: Option<Vec<u8>>,
+}
+
+/// Iterator to the storage entries of a block. See [`SqliteFullDatabase::block_storage_iter`].
+///
+/// Yields the key, value, and trie entry version of each entry.
+pub struct StorageIter<'a> {
+    database: &'a SqliteFullDatabase,
+    block_hash: [u8; 32],
+    prefix_nibbles: Vec<u8>,
+    /// Key, as nibbles, from which the next page starts, inclusive. `None` if the iteration is
+    /// over.
+    next_key_nibbles: Option<Vec<u8>>,
+    /// Entries that have been read from the database but not yielded yet.
+    page: VecDeque<(Vec<u8>, Vec<u8>, trie::TrieEntryVersion)>,
+}
+
+impl<'a> StorageIter<'a> {
+    /// Reads the next page of entries, starting at `key_nibbles`, and updates
+    /// [`StorageIter::next_key_nibbles`].
+    fn read_page(&mut self, mut key_nibbles: Vec<u8>) -> Result<(), StorageAccessError> {
+        let mut timer = self.database.time_operation(OperationKind::StorageIterPage);
+
+        // All the entries of the page are read within the same transaction, in order for them
+        // to be consistent with each other.
+        let mut connection = self.database.read_connection();
+        let transaction = connection
+            .savepoint()
+            .map_err(sqlite_err("block_storage_iter"))?;
+
+        while self.page.len() < PAGE_SIZE {
+            let Some(found_key_nibbles) = block_storage_next_key(
+                &transaction,
+                &self.block_hash,
+                &[],
+                &key_nibbles,
+                &self.prefix_nibbles,
+                false,
+                &mut 0,
+            )?
+            else {
+                timer.set_rows(self.page.len());
+                return Ok(());
+            };
+
+            // Keys that don't contain an entire number of bytes can't legitimately exist in the
+            // storage of a block.
+            if found_key_nibbles.len() % 2 != 0 {
+                return Err(StorageAccessError::Corrupted(
+                    CorruptedError::InvalidStorageKey,
+                ));
+            }
+
+            let (value, version) =
+                block_storage_get(&transaction, &self.block_hash, &found_key_nibbles, true)?
+                    .ok_or(StorageAccessError::IncompleteStorage)?;
+
+            let key = found_key_nibbles
+                .chunks_exact(2)
+                .map(|nibbles| (nibbles[0] << 4) | nibbles[1])
+                .collect();
+            self.page.push_back((key, value, version));
+
+            // The smallest key strictly superior to the one that has been found is this key
+            // followed with a `0` nibble.
+            key_nibbles = found_key_nibbles;
+            key_nibbles.push(0);
+        }
+
+        timer.set_rows(self.page.len());
+        self.next_key_nibbles = Some(key_nibbles);
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for StorageIter<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>, trie::TrieEntryVersion), StorageAccessError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.page.pop_front() {
+            return Some(Ok(entry));
+        }
+
+        let key_nibbles = self.next_key_nibbles.take()?;
+        if let Err(err) = self.read_page(key_nibbles) {
+            self.page.clear();
+            return Some(Err(err));
+        }
+
+        self.page.pop_front().map(Ok)
+    }
+}
+
+impl<'a> core::iter::FusedIterator for StorageIter<'a> {}
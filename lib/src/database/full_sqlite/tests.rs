@@ -18,13 +18,34 @@
 #![cfg(test)]
 
 use super::{
-    open, Config, ConfigTy, DatabaseOpen, InsertTrieNode, InsertTrieNodeStorageValue,
-    StorageAccessError,
+    consistency,
+    fixtures::{build_trie, memory_config, Fixture, FixtureChain},
+    import, open, BabeEpochInformationDecodeError, BabeEpochTransition, BackupError,
+    BeginBulkLoadError, BlockAtNumber, BlockExtrinsicsError, BlockRef, Budget, Checkpoint,
+    CheckpointFromJsonError, CommitPolicy, CompressionConfig, Config, ConfigTy, ConsensusEngine,
+    CorruptedError, DatabaseOpen, DedupStats, DigestSummary, Event, ExportBlocksError,
+    ExportStateError, ForkMetrics, FullBlock, ImportBlocksError, InitializeFromCheckpointError,
+    InitializeFromRawStateError, InsertError, InsertMeta, InsertStats, InsertStorageDiffError,
+    InsertTrieNode, InsertTrieNodeStorageValue, InsertTrieNodesError, InsertTrieNodesStats,
+    InternalError, InvalidRow, MaintenanceProgress, MetaEntry, OpenError, OpenProgressStep,
+    OperationKind, OperationMetrics, ParentOf, PurgeReport, QueryError, ResetError, ResumeState,
+    SetBestError, SetBlockEventsError, SetBlockJustificationError, SetFinalizedStats,
+    StorageAccessError, TrieStats,
+};
+use crate::{
+    chain::chain_information,
+    executor::vm::HeapPages,
+    header,
+    trie::{self, TrieEntryVersion},
 };
-use crate::{chain::chain_information, header, trie};
 
-use alloc::borrow::Cow;
-use core::{array, iter};
+use alloc::{borrow::Cow, collections::BTreeMap, sync::Arc};
+use core::{
+    array, iter,
+    num::{NonZeroU64, NonZeroUsize},
+    time::Duration,
+};
+use parking_lot::Mutex;
 use rand::distributions::{Distribution as _, Uniform};
 
 #[test]
@@ -32,9 +53,8 @@ fn empty_database_fill_then_query() {
     // Repeat the test many times due to randomness.
     for _ in 0..1024 {
         let DatabaseOpen::Empty(empty_db) = open(Config {
-            block_number_bytes: 4,
-            cache_size: 2 * 1024 * 1024,
-            ty: ConfigTy::Memory,
+            read_connections: 2,
+            ..memory_config(4)
         })
         .unwrap() else {
             panic!()
@@ -180,7 +200,8 @@ fn empty_database_fill_then_query() {
                     None,
                 )
                 .unwrap();
-            db.insert_trie_nodes(trie_entries_linear, 0).unwrap();
+            db.insert_trie_nodes_versioned(trie_entries_linear, TrieEntryVersion::V0)
+                .unwrap();
             db
         };
 
@@ -192,7 +213,7 @@ fn empty_database_fill_then_query() {
                 .map(|_| uniform_sample(0, 255))
                 .collect::<Vec<_>>();
             let actual = open_db
-                .block_storage_get(
+                .block_storage_get_versioned(
                     &block0_hash,
                     iter::empty::<iter::Empty<_>>(),
                     trie::bytes_to_nibbles(key.iter().copied()).map(u8::from),
@@ -200,7 +221,7 @@ fn empty_database_fill_then_query() {
                 .unwrap();
             let expected = trie
                 .node_by_full_key(trie::bytes_to_nibbles(key.iter().copied()))
-                .and_then(|n| Some((trie[n].0.as_ref()?.clone(), 0u8)));
+                .and_then(|n| Some((trie[n].0.as_ref()?.clone(), TrieEntryVersion::V0)));
             assert_eq!(
                 actual,
                 expected,
@@ -282,9 +303,8 @@ fn empty_database_fill_then_query() {
 #[test]
 fn unknown_block() {
     let DatabaseOpen::Empty(empty_db) = open(Config {
-        block_number_bytes: 4,
-        cache_size: 2 * 1024 * 1024,
-        ty: ConfigTy::Memory,
+        read_connections: 2,
+        ..memory_config(4)
     })
     .unwrap() else {
         panic!()
@@ -309,7 +329,11 @@ fn unknown_block() {
         .unwrap();
 
     assert!(matches!(
-        db.block_storage_get(&[0xff; 32], iter::empty::<iter::Empty<_>>(), [].into_iter()),
+        db.block_storage_get_versioned(
+            &[0xff; 32],
+            iter::empty::<iter::Empty<_>>(),
+            [].into_iter()
+        ),
         Err(StorageAccessError::UnknownBlock)
     ));
 
@@ -347,9 +371,8 @@ fn unknown_block() {
 #[test]
 fn storage_get_partial() {
     let DatabaseOpen::Empty(empty_db) = open(Config {
-        block_number_bytes: 4,
-        cache_size: 2 * 1024 * 1024,
-        ty: ConfigTy::Memory,
+        read_connections: 2,
+        ..memory_config(4)
     })
     .unwrap() else {
         panic!()
@@ -374,7 +397,7 @@ fn storage_get_partial() {
         .unwrap();
 
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1].into_iter(),
@@ -383,7 +406,7 @@ fn storage_get_partial() {
     ));
 
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 1, 1, 1].into_iter(),
@@ -392,7 +415,7 @@ fn storage_get_partial() {
     ));
 
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 2].into_iter(),
@@ -401,7 +424,7 @@ fn storage_get_partial() {
     ));
 
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 1, 2].into_iter(),
@@ -412,7 +435,7 @@ fn storage_get_partial() {
     // The empty key is specifically tested due to SQLite having some weird behaviors mixing
     // null and empty bytes.
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [].into_iter(),
@@ -420,7 +443,7 @@ fn storage_get_partial() {
         Err(StorageAccessError::IncompleteStorage)
     ));
 
-    db.insert_trie_nodes(
+    db.insert_trie_nodes_versioned(
         [InsertTrieNode {
             merkle_value: Cow::Borrowed(&[1; 32]),
             partial_key_nibbles: Cow::Borrowed(&[1, 1]),
@@ -448,12 +471,12 @@ fn storage_get_partial() {
             },
         }]
         .into_iter(),
-        0,
+        TrieEntryVersion::V0,
     )
     .unwrap();
 
     assert_eq!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1].into_iter(),
@@ -465,7 +488,7 @@ fn storage_get_partial() {
     );
 
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 1, 1, 1].into_iter(),
@@ -474,7 +497,7 @@ fn storage_get_partial() {
     ));
 
     assert!(db
-        .block_storage_get(
+        .block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 2].into_iter(),
@@ -483,7 +506,7 @@ fn storage_get_partial() {
         .is_none());
 
     assert!(matches!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 1, 2].into_iter(),
@@ -491,7 +514,7 @@ fn storage_get_partial() {
         Err(StorageAccessError::IncompleteStorage)
     ));
 
-    db.insert_trie_nodes(
+    db.insert_trie_nodes_versioned(
         [InsertTrieNode {
             merkle_value: Cow::Borrowed(&[2; 32]),
             partial_key_nibbles: Cow::Borrowed(&[1, 1]),
@@ -505,12 +528,12 @@ fn storage_get_partial() {
             },
         }]
         .into_iter(),
-        0,
+        TrieEntryVersion::V0,
     )
     .unwrap();
 
     assert_eq!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1].into_iter(),
@@ -522,7 +545,7 @@ fn storage_get_partial() {
     );
 
     assert_eq!(
-        db.block_storage_get(
+        db.block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 1, 1, 1].into_iter(),
@@ -534,7 +557,7 @@ fn storage_get_partial() {
     );
 
     assert!(db
-        .block_storage_get(
+        .block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 2].into_iter(),
@@ -543,7 +566,7 @@ fn storage_get_partial() {
         .is_none());
 
     assert!(db
-        .block_storage_get(
+        .block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [1, 1, 1, 2].into_iter(),
@@ -554,7 +577,7 @@ fn storage_get_partial() {
     // The empty key is specifically tested due to SQLite having some weird behaviors mixing
     // null and empty bytes.
     assert!(db
-        .block_storage_get(
+        .block_storage_get_versioned(
             &db.block_hash_by_number(0).unwrap().next().unwrap(),
             iter::empty::<iter::Empty<_>>(),
             [].into_iter(),
@@ -566,9 +589,8 @@ fn storage_get_partial() {
 #[test]
 fn storage_next_key_partial() {
     let DatabaseOpen::Empty(empty_db) = open(Config {
-        block_number_bytes: 4,
-        cache_size: 2 * 1024 * 1024,
-        ty: ConfigTy::Memory,
+        read_connections: 2,
+        ..memory_config(4)
     })
     .unwrap() else {
         panic!()
@@ -616,7 +638,7 @@ fn storage_next_key_partial() {
         Err(StorageAccessError::IncompleteStorage)
     ));
 
-    db.insert_trie_nodes(
+    db.insert_trie_nodes_versioned(
         [InsertTrieNode {
             merkle_value: Cow::Borrowed(&[1; 32]),
             partial_key_nibbles: Cow::Borrowed(&[1, 1]),
@@ -641,7 +663,7 @@ fn storage_next_key_partial() {
             storage_value: InsertTrieNodeStorageValue::NoValue,
         }]
         .into_iter(),
-        0,
+        TrieEntryVersion::V0,
     )
     .unwrap();
 
@@ -726,7 +748,7 @@ fn storage_next_key_partial() {
         Ok(None)
     ));
 
-    db.insert_trie_nodes(
+    db.insert_trie_nodes_versioned(
         [InsertTrieNode {
             merkle_value: Cow::Borrowed(&[3; 32]),
             partial_key_nibbles: Cow::Borrowed(&[2]),
@@ -740,7 +762,7 @@ fn storage_next_key_partial() {
             },
         }]
         .into_iter(),
-        0,
+        TrieEntryVersion::V0,
     )
     .unwrap();
 
@@ -768,7 +790,7 @@ fn storage_next_key_partial() {
         Err(StorageAccessError::IncompleteStorage)
     ));
 
-    db.insert_trie_nodes(
+    db.insert_trie_nodes_versioned(
         [InsertTrieNode {
             merkle_value: Cow::Borrowed(&[2; 32]),
             partial_key_nibbles: Cow::Borrowed(&[1, 1]),
@@ -782,7 +804,7 @@ fn storage_next_key_partial() {
             },
         }]
         .into_iter(),
-        0,
+        TrieEntryVersion::V0,
     )
     .unwrap();
 
@@ -838,13 +860,16 @@ fn storage_next_key_partial() {
 }
 
 #[test]
-fn storage_closest_descendant_merkle_value_partial() {
-    let DatabaseOpen::Empty(empty_db) = open(Config {
-        block_number_bytes: 4,
-        cache_size: 2 * 1024 * 1024,
-        ty: ConfigTy::Memory,
-    })
-    .unwrap() else {
+fn storage_get_bytes() {
+    let main_trie = [
+        (b":code".to_vec(), b"runtime".to_vec()),
+        (b":heappages".to_vec(), 2048u64.to_le_bytes().to_vec()),
+        (b"abc".to_vec(), vec![1, 2, 3]),
+        (b"abd".to_vec(), vec![4]),
+    ];
+    let (main_trie_root, main_trie_nodes) = build_trie(&main_trie, |_| false);
+
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
         panic!()
     };
 
@@ -855,7 +880,7 @@ fn storage_closest_descendant_merkle_value_partial() {
                     number: 0,
                     extrinsics_root: &[0; 32],
                     parent_hash: &[0; 32],
-                    state_root: &[1; 32],
+                    state_root: &main_trie_root,
                     digest: header::DigestRef::empty(),
                 },
                 consensus: chain_information::ChainInformationConsensusRef::Unknown,
@@ -865,217 +890,9045 @@ fn storage_closest_descendant_merkle_value_partial() {
             None,
         )
         .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+    db.insert_trie_nodes_versioned(main_trie_nodes.into_iter(), TrieEntryVersion::V1)
+        .unwrap();
+
+    // The byte keys give the same results as the equivalent nibble keys.
+    for key in [
+        &b":code"[..],
+        b":heappages",
+        b"abc",
+        b"abd",
+        b"ab",
+        b"abe",
+        b"",
+    ] {
+        let expected = db
+            .block_storage_get_versioned(
+                &block_hash,
+                iter::empty::<iter::Empty<u8>>(),
+                trie::bytes_to_nibbles(key.iter().copied()).map(u8::from),
+            )
+            .unwrap();
+        assert_eq!(
+            expected,
+            main_trie
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| (v.clone(), TrieEntryVersion::V1))
+        );
+        assert_eq!(
+            db.block_storage_get_bytes(&block_hash, key).unwrap(),
+            expected
+        );
+    }
 
     assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [].into_iter(),
-        )
-        .unwrap()
-        .unwrap(),
-        vec![1; 32]
+        db.block_storage_code(&block_hash).unwrap(),
+        Some(b"runtime".to_vec())
+    );
+    let heap_pages = db.block_storage_heap_pages(&block_hash).unwrap();
+    assert_eq!(
+        crate::executor::storage_heap_pages_to_value(heap_pages.as_deref()).unwrap(),
+        HeapPages::new(2048)
     );
 
     assert!(matches!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1].into_iter(),
-        ),
-        Err(StorageAccessError::IncompleteStorage)
+        db.block_storage_code(&[0xff; 32]),
+        Err(StorageAccessError::UnknownBlock)
     ));
+}
 
-    assert!(matches!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1].into_iter(),
-        ),
-        Err(StorageAccessError::IncompleteStorage)
-    ));
+#[test]
+fn storage_next_key_bytes() {
+    let main_trie = [
+        (b":code".to_vec(), b"runtime".to_vec()),
+        (b"abc".to_vec(), vec![1, 2, 3]),
+        (b"abd".to_vec(), vec![4]),
+        (b"zzz".to_vec(), vec![5]),
+    ];
+    let (main_trie_root, main_trie_nodes) = build_trie(&main_trie, |_| false);
 
-    assert!(matches!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 2, 3, 4, 5].into_iter(),
-        ),
-        Err(StorageAccessError::IncompleteStorage)
-    ));
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
 
-    db.insert_trie_nodes(
-        [InsertTrieNode {
-            merkle_value: Cow::Borrowed(&[1; 32]),
-            partial_key_nibbles: Cow::Borrowed(&[1, 1]),
-            children_merkle_values: [
-                None,
-                Some(Cow::Borrowed(&[2; 32])),
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-            ],
-            storage_value: InsertTrieNodeStorageValue::Value {
-                value: Cow::Borrowed(b"hello"),
-                references_merkle_value: false,
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &main_trie_root,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
             },
-        }]
-        .into_iter(),
-        0,
-    )
-    .unwrap();
-
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1].into_iter(),
+            iter::empty(),
+            None,
         )
-        .unwrap()
-        .unwrap(),
-        vec![1; 32]
-    );
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+    db.insert_trie_nodes_versioned(main_trie_nodes.into_iter(), TrieEntryVersion::V1)
+        .unwrap();
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1].into_iter(),
-        )
-        .unwrap()
-        .unwrap(),
-        vec![1; 32]
-    );
+    let mut sorted_keys = main_trie.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+    sorted_keys.sort();
 
+    for key in [
+        &b""[..],
+        b":code",
+        b"a",
+        b"ab",
+        b"abc",
+        b"abca",
+        b"abd",
+        b"b",
+        b"zzz",
+        b"zzzz",
+    ] {
+        for prefix in [&b""[..], b"a", b"ab", b"abd", b"z", b"q"] {
+            for or_equal in [false, true] {
+                let expected = sorted_keys
+                    .iter()
+                    .find(|k| {
+                        if or_equal {
+                            &k[..] >= key
+                        } else {
+                            &k[..] > key
+                        }
+                    })
+                    .filter(|k| k.starts_with(prefix))
+                    .cloned();
+                assert_eq!(
+                    db.block_storage_next_key_bytes(&block_hash, key, prefix, or_equal, false)
+                        .unwrap(),
+                    expected,
+                    "key={key:?} prefix={prefix:?} or_equal={or_equal}"
+                );
+            }
+        }
+    }
+
+    // The keys `abc` and `abd` share a branch node whose key is `ab` followed with one nibble.
+    assert!(matches!(
+        db.block_storage_next_key_bytes(&block_hash, b"ab", b"", true, true),
+        Err(StorageAccessError::OddNumberOfNibbles)
+    ));
     assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 1].into_iter(),
-        )
-        .unwrap()
-        .unwrap(),
-        vec![2; 32]
+        db.block_storage_next_key_bytes(&block_hash, b"abc", b"", true, true)
+            .unwrap(),
+        Some(b"abc".to_vec())
     );
 
     assert!(matches!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 1, 1].into_iter(),
-        ),
-        Err(StorageAccessError::IncompleteStorage)
+        db.block_storage_next_key_bytes(&[0xff; 32], b"", b"", true, false),
+        Err(StorageAccessError::UnknownBlock)
     ));
+}
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 2].into_iter(),
-        )
-        .unwrap(),
-        None
-    );
+#[test]
+fn storage_iter() {
+    // More entries than a single page, some of them sharing a prefix.
+    let storage = (0..1000u32)
+        .map(|n| (n.to_be_bytes()[1..].to_vec(), n.to_le_bytes().to_vec()))
+        .chain([
+            (b":code".to_vec(), b"runtime".to_vec()),
+            (b":heappages".to_vec(), vec![0; 40]),
+        ])
+        .collect::<BTreeMap<_, _>>();
+    let fixture = FixtureChain::new(4)
+        .with_blocks(1)
+        .with_storage(storage.clone())
+        .build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[1];
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [5].into_iter(),
-        )
-        .unwrap(),
-        None
-    );
+    for prefix in [
+        &b""[..],
+        b"\0",
+        b"\0\x02",
+        b"\0\x03\xe7",
+        b":",
+        b"\x01",
+        b"\0\x03\xe7\0",
+    ] {
+        let obtained = db
+            .block_storage_iter(&block_hash, prefix)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = storage
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone(), TrieEntryVersion::V1))
+            .collect::<Vec<_>>();
+        assert_eq!(obtained, expected, "prefix={prefix:?}");
+    }
 
-    db.insert_trie_nodes(
-        [InsertTrieNode {
-            merkle_value: Cow::Borrowed(&[2; 32]),
-            partial_key_nibbles: Cow::Borrowed(&[1, 1]),
-            children_merkle_values: [
-                None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-                None, None,
-            ],
-            storage_value: InsertTrieNodeStorageValue::Value {
-                value: Cow::Borrowed(b"world"),
-                references_merkle_value: false,
-            },
-        }]
-        .into_iter(),
-        0,
-    )
-    .unwrap();
+    // The database isn't locked while the iterator is alive, even before the iteration has
+    // finished. The database has no read connection, meaning that modifying it while the
+    // iterator is alive would otherwise deadlock.
+    let mut iter = db.block_storage_iter(&block_hash, b"");
+    assert_eq!(iter.next().unwrap().unwrap().0, b"\0\0\0".to_vec());
+    db.set_finalized(&block_hash).unwrap();
+    assert_eq!(iter.by_ref().count(), storage.len() - 1);
+    assert!(iter.next().is_none());
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1].into_iter(),
-        )
-        .unwrap()
-        .unwrap(),
-        vec![1; 32]
-    );
+    // The iterator ends after an error.
+    let mut iter = db.block_storage_iter(&[0xff; 32], b"");
+    assert!(matches!(
+        iter.next(),
+        Some(Err(StorageAccessError::UnknownBlock))
+    ));
+    assert!(iter.next().is_none());
+}
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 1].into_iter(),
-        )
-        .unwrap()
-        .unwrap(),
-        vec![2; 32]
-    );
+#[test]
+fn storage_consistency_random() {
+    fn uniform_sample(min: u8, max: u8) -> u8 {
+        Uniform::new_inclusive(min, max).sample(&mut rand::thread_rng())
+    }
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 1, 1].into_iter(),
-        )
-        .unwrap()
-        .unwrap(),
-        vec![2; 32]
-    );
+    // Repeat the test many times due to randomness.
+    for _ in 0..64 {
+        // Keys are mostly built from a long common prefix followed with a few bytes among a
+        // small set, in order to produce many branch nodes without a storage value.
+        let common_prefix = (0..uniform_sample(0, 48))
+            .map(|_| uniform_sample(0, 255))
+            .collect::<Vec<_>>();
+        let mut reference = BTreeMap::new();
+        for _ in 0..uniform_sample(1, 24) {
+            let mut key = if uniform_sample(0, 3) == 0 {
+                Vec::new()
+            } else {
+                common_prefix.clone()
+            };
+            for _ in 0..uniform_sample(0, 4) {
+                key.push([0x00, 0x01, 0x10, 0x11, 0xf0, 0xff][usize::from(uniform_sample(0, 5))]);
+            }
+            // Values of at least 33 bytes are hashed in the trie.
+            let value = (0..uniform_sample(0, 40))
+                .map(|_| uniform_sample(0, 255))
+                .collect::<Vec<_>>();
+            reference.insert(key, value);
+        }
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 2].into_iter(),
-        )
-        .unwrap(),
-        None
-    );
+        let fixture = FixtureChain::new(4).with_storage(reference.clone()).build();
+        consistency::check_block_storage(&fixture.database, &fixture.canonical[0], &reference)
+            .unwrap();
+    }
+}
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
-            iter::empty::<iter::Empty<_>>(),
-            [1, 1, 1, 2].into_iter(),
-        )
-        .unwrap(),
-        None
-    );
+#[test]
+fn partial_keys_packing() {
+    for nibbles in [
+        &[][..],
+        &[0],
+        &[0xf],
+        &[1, 2],
+        &[1, 2, 3],
+        &[0xf, 0, 0xf, 0, 0],
+    ] {
+        let packed = super::pack_nibbles(nibbles);
+        assert_eq!(packed.len(), 1 + nibbles.len().div_ceil(2));
+        assert_eq!(super::unpack_nibbles(&packed).as_deref(), Some(nibbles));
+    }
 
-    assert_eq!(
-        db.block_storage_closest_descendant_merkle_value(
-            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+    assert_eq!(super::unpack_nibbles(&[]), None);
+    assert_eq!(super::unpack_nibbles(&[1]), None);
+    assert_eq!(super::unpack_nibbles(&[1, 0x12]), None);
+    assert_eq!(super::unpack_nibbles(&[2, 0x12]), None);
+}
+
+#[test]
+fn partial_keys_packing_migration() {
+    let reference = (0..=255u8)
+        .map(|n| {
+            (
+                vec![n; usize::from(n % 5) + 1],
+                vec![n; usize::from(n % 40)],
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let child_trie = [
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+    ];
+
+    // Whether the migration has been interrupted, and the number of rows that had been
+    // packed before the interruption.
+    for interrupted_after in [None, Some(0), Some(100)] {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("database.sqlite");
+
+        let fixture = FixtureChain::new(4)
+            .with_storage(reference.clone())
+            .with_child_trie(b"foo", child_trie.clone())
+            .build();
+        fixture.database.backup_to(&path, |_| {}).unwrap();
+
+        // Turn the database back into the format that predates the packing.
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        revert_schema_to_v11(&connection);
+        connection
+            .execute_batch(
+                r#"
+UPDATE trie_node SET partial_key = unpack_nibbles(partial_key);
+ALTER TABLE trie_node_storage DROP COLUMN value_compressed;
+PRAGMA user_version = 9;
+            "#,
+            )
+            .unwrap();
+        if let Some(num_rows) = interrupted_after {
+            connection
+                .execute_batch(&format!(
+                    r#"
+CREATE TABLE trie_node_packing_progress(last_rowid INTEGER NOT NULL);
+INSERT INTO trie_node_packing_progress(last_rowid)
+    SELECT COALESCE(MAX(rowid), -1) FROM (SELECT rowid FROM trie_node ORDER BY rowid LIMIT {num_rows});
+UPDATE trie_node SET partial_key = pack_nibbles(partial_key)
+    WHERE rowid <= (SELECT last_rowid FROM trie_node_packing_progress);
+                "#
+                ))
+                .unwrap();
+        }
+        drop(connection);
+
+        let DatabaseOpen::Open(db) = open(Config {
+            read_connections: 1,
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        let main_trie = fixture
+            .main_trie
+            .iter()
+            .cloned()
+            .collect::<BTreeMap<_, _>>();
+        consistency::check_block_storage(&db, &fixture.canonical[0], &main_trie).unwrap();
+
+        let child_trie_path = Fixture::child_trie_path(b"foo");
+        for (key, value) in &child_trie {
+            assert_eq!(
+                db.block_storage_get_versioned(
+                    &fixture.canonical[0],
+                    iter::once(child_trie_path.iter().copied()),
+                    trie::bytes_to_nibbles(key.iter().copied()).map(u8::from)
+                )
+                .unwrap(),
+                Some((value.clone(), TrieEntryVersion::V1))
+            );
+        }
+        assert!(db
+            .finalized_and_above_missing_trie_nodes_unordered()
+            .unwrap()
+            .is_empty());
+    }
+}
+
+#[test]
+fn children_encoding() {
+    let merkle_values = [&[][..], &[0xaa], &[0xbb; 31], &[0xcc; 32]];
+    for bitmap in [0x0001u16, 0x8000, 0x8001, 0x1234, 0xffff] {
+        let children = array::from_fn::<_, 16, _>(|n| {
+            (bitmap & (1 << n) != 0).then_some(merkle_values[n % merkle_values.len()])
+        });
+        let encoded = super::encode_children(children).unwrap();
+        assert_eq!(&encoded[..2], &bitmap.to_le_bytes());
+        assert_eq!(super::decode_children(&encoded), Some(children));
+    }
+
+    assert_eq!(super::encode_children([None; 16]), None);
+
+    assert_eq!(super::decode_children(&[]), None);
+    assert_eq!(super::decode_children(&[0x01]), None);
+    assert_eq!(super::decode_children(&[0, 0]), None);
+    assert_eq!(super::decode_children(&[0x01, 0, 2, 0xaa]), None);
+    assert_eq!(super::decode_children(&[0x01, 0, 1, 0xaa, 0xbb]), None);
+}
+
+#[test]
+fn trie_node_children_migration() {
+    let reference = (0..=255u8)
+        .map(|n| {
+            (
+                vec![n; usize::from(n % 5) + 1],
+                vec![n; usize::from(n % 40)],
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let child_trie = [
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+    ];
+
+    // Whether the migration has been interrupted, and the number of rows that had been
+    // migrated before the interruption.
+    for interrupted_after in [None, Some(0), Some(100)] {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("database.sqlite");
+
+        let fixture = FixtureChain::new(4)
+            .with_storage(reference.clone())
+            .with_child_trie(b"foo", child_trie.clone())
+            .build();
+        fixture.database.backup_to(&path, |_| {}).unwrap();
+
+        let count_children = |connection: &rusqlite::Connection| {
+            connection
+                .query_row(
+                    r#"
+WITH nibble(num) AS (
+    VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+        (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+)
+SELECT COUNT(*) FROM trie_node JOIN nibble
+WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL
+                "#,
+                    (),
+                    |row| row.get::<_, i64>(0),
+                )
+                .unwrap()
+        };
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        open::register_functions(&connection).unwrap();
+        let num_children = count_children(&connection);
+        assert!(num_children > 0);
+
+        // Turn the database back into the format that predates the `children` column, or
+        // into the state of a migration that has been interrupted.
+        match interrupted_after {
+            None => revert_schema_to_v11(&connection),
+            Some(num_rows) => {
+                revert_schema_to_v13(&connection);
+                connection
+                .execute_batch(&format!(
+                    r#"
+CREATE TABLE trie_node_children_progress(last_rowid INTEGER NOT NULL);
+INSERT INTO trie_node_children_progress(last_rowid)
+    SELECT COALESCE(MAX(rowid), -1) FROM (SELECT rowid FROM trie_node ORDER BY rowid LIMIT {num_rows});
+
+CREATE TABLE trie_node_child(
+    hash BLOB NOT NULL,
+    child_num BLOB NOT NULL,
+    child_hash BLOB NOT NULL,
+    PRIMARY KEY (hash, child_num),
+    FOREIGN KEY (hash) REFERENCES trie_node(hash) ON UPDATE CASCADE ON DELETE CASCADE
+    CHECK(LENGTH(child_num) == 1 AND HEX(child_num) < '10')
+);
+WITH nibble(num) AS (
+    VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+        (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+)
+INSERT INTO trie_node_child(hash, child_num, child_hash)
+    SELECT trie_node.hash, nibble.num, child_merkle_value(trie_node.children, nibble.num)
+    FROM trie_node JOIN nibble
+    WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL;
+
+DELETE FROM trie_node_parent WHERE hash IN (
+    SELECT hash FROM trie_node WHERE rowid > (SELECT last_rowid FROM trie_node_children_progress)
+);
+UPDATE trie_node SET children = NULL
+    WHERE rowid > (SELECT last_rowid FROM trie_node_children_progress);
+
+PRAGMA user_version = 11;
+                "#
+                    ))
+                    .unwrap();
+            }
+        }
+        drop(connection);
+
+        let DatabaseOpen::Open(db) = open(Config {
+            read_connections: 1,
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        let main_trie = fixture
+            .main_trie
+            .iter()
+            .cloned()
+            .collect::<BTreeMap<_, _>>();
+        consistency::check_block_storage(&db, &fixture.canonical[0], &main_trie).unwrap();
+
+        let child_trie_path = Fixture::child_trie_path(b"foo");
+        for (key, value) in &child_trie {
+            assert_eq!(
+                db.block_storage_get_versioned(
+                    &fixture.canonical[0],
+                    iter::once(child_trie_path.iter().copied()),
+                    trie::bytes_to_nibbles(key.iter().copied()).map(u8::from)
+                )
+                .unwrap(),
+                Some((value.clone(), TrieEntryVersion::V1))
+            );
+        }
+        assert!(db
+            .finalized_and_above_missing_trie_nodes_unordered()
+            .unwrap()
+            .is_empty());
+
+        let connection = db.database.lock();
+        assert_eq!(count_children(&connection), num_children);
+        assert_eq!(
+            connection
+                .query_row("SELECT COUNT(*) FROM trie_node_parent", (), |row| {
+                    row.get::<_, i64>(0)
+                })
+                .unwrap(),
+            num_children
+        );
+    }
+}
+
+#[test]
+fn value_compression() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+    let open_config = |value_compression| Config {
+        read_connections: 1,
+        value_compression,
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    };
+    let compression = Some(CompressionConfig {
+        threshold: 64,
+        level: 3,
+    });
+
+    // Random bytes don't compress, and are thus stored uncompressed despite their size.
+    let incompressible = (0..1000)
+        .map(|_| Uniform::new_inclusive(0, 255).sample(&mut rand::thread_rng()))
+        .collect::<Vec<u8>>();
+    let genesis_trie = [
+        (b"abc".to_vec(), vec![1; 1000]),
+        (b"abd".to_vec(), vec![2; 10]),
+    ]
+    .into_iter()
+    .collect::<BTreeMap<_, _>>();
+    let block1_trie = [
+        (b"abc".to_vec(), vec![1; 1000]),
+        (b"abd".to_vec(), vec![3; 1000]),
+        (b"abe".to_vec(), vec![4; 10]),
+        (b"abf".to_vec(), incompressible),
+    ]
+    .into_iter()
+    .collect::<BTreeMap<_, _>>();
+    let build = |trie: &BTreeMap<Vec<u8>, Vec<u8>>| {
+        build_trie(
+            &trie
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>(),
+            |_| false,
+        )
+    };
+    let (genesis_root, genesis_nodes) = build(&genesis_trie);
+    let (block1_root, block1_nodes) = build(&block1_trie);
+
+    // The genesis storage is inserted without compression.
+    let DatabaseOpen::Empty(empty_db) = open(open_config(None)).unwrap() else {
+        panic!()
+    };
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &genesis_root,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    db.insert_trie_nodes_versioned(genesis_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+    drop(db);
+
+    // The storage of block 1 is inserted with compression. Inserting the nodes that already
+    // exist verifies that they are compared with the decompressed values.
+    let DatabaseOpen::Open(db) = open(open_config(compression)).unwrap() else {
+        panic!()
+    };
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &block1_root,
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    db.insert_trie_nodes_versioned(block1_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+    db.insert_trie_nodes_versioned(
+        build(&genesis_trie)
+            .1
+            .into_iter()
+            .chain(build(&block1_trie).1),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    // Only the value of `abd` of block 1 has been compressed. The value of `abc` had already
+    // been inserted uncompressed, and the other values are either too small or incompressible.
+    let num_compressed = |db: &super::SqliteFullDatabase| {
+        db.database
+            .lock()
+            .query_row(
+                "SELECT COUNT(*) FROM trie_node_storage WHERE value_compressed",
+                (),
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+    };
+    assert_eq!(num_compressed(&db), 1);
+
+    consistency::check_block_storage(&db, &genesis_hash, &genesis_trie).unwrap();
+    consistency::check_block_storage(&db, &block1_hash, &block1_trie).unwrap();
+    drop(db);
+
+    // Compressed values remain readable after compression has been disabled.
+    let DatabaseOpen::Open(db) = open(open_config(None)).unwrap() else {
+        panic!()
+    };
+    assert_eq!(num_compressed(&db), 1);
+    consistency::check_block_storage(&db, &genesis_hash, &genesis_trie).unwrap();
+    consistency::check_block_storage(&db, &block1_hash, &block1_trie).unwrap();
+    assert_eq!(
+        db.block_storage_get_bytes(&block1_hash, b"abd").unwrap(),
+        Some((vec![3; 1000], TrieEntryVersion::V0))
+    );
+}
+
+#[test]
+fn storage_consistency_detects_mismatch() {
+    let reference = [
+        (b"abc".to_vec(), vec![1]),
+        (b"abd".to_vec(), vec![2]),
+        (b"abdz".to_vec(), vec![3; 40]),
+        (b"zzz".to_vec(), vec![4]),
+    ]
+    .into_iter()
+    .collect::<BTreeMap<_, _>>();
+    let fixture = FixtureChain::new(4).with_storage(reference.clone()).build();
+    let check = |reference: &BTreeMap<Vec<u8>, Vec<u8>>| {
+        consistency::check_block_storage(&fixture.database, &fixture.canonical[0], reference)
+    };
+
+    check(&reference).unwrap();
+
+    let mut wrong_value = reference.clone();
+    wrong_value.insert(b"abd".to_vec(), vec![5]);
+    assert!(matches!(
+        check(&wrong_value),
+        Err(consistency::ConsistencyError::WrongValue { key, expected: Some(_), obtained: Some(_) })
+            if key == b"abd"
+    ));
+
+    let mut extra_key = reference.clone();
+    extra_key.insert(b"abe".to_vec(), vec![6]);
+    assert!(matches!(
+        check(&extra_key),
+        Err(consistency::ConsistencyError::WrongValue { key, expected: Some(_), obtained: None })
+            if key == b"abe"
+    ));
+
+    let mut missing_key = reference.clone();
+    missing_key.remove(&b"abdz"[..]);
+    assert!(matches!(
+        check(&missing_key),
+        Err(consistency::ConsistencyError::WrongNextKey {
+            branch_nodes: false,
+            expected: Some(_),
+            obtained: Some(_),
+            ..
+        })
+    ));
+
+    let mut missing_prefix = reference.clone();
+    missing_prefix.remove(&b"abd"[..]);
+    assert!(matches!(
+        check(&missing_prefix),
+        Err(consistency::ConsistencyError::WrongValue { key, expected: None, obtained: Some(_) })
+            if key == b"abd"
+    ));
+
+    assert!(matches!(
+        consistency::check_block_storage(&fixture.database, &[0xff; 32], &reference),
+        Err(consistency::ConsistencyError::StorageAccess(
+            StorageAccessError::UnknownBlock
+        ))
+    ));
+}
+
+#[test]
+fn storage_next_key_child_trie() {
+    let child_trie = [
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+        (b"\x01\x30".to_vec(), vec![0xcc]),
+        (b"\xf0".to_vec(), vec![0xdd]),
+    ];
+    let (child_trie_root, child_trie_nodes) = build_trie(&child_trie, |_| false);
+
+    let main_trie = [
+        (b":code".to_vec(), b"runtime".to_vec()),
+        (b"abc".to_vec(), vec![1, 2, 3]),
+        (b"abd".to_vec(), vec![4]),
+        (
+            b":child_storage:default:foo".to_vec(),
+            child_trie_root.to_vec(),
+        ),
+        (b"zzz".to_vec(), vec![5]),
+    ];
+    let (main_trie_root, main_trie_nodes) = build_trie(&main_trie, |key| {
+        key.starts_with(b":child_storage:default:")
+    });
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &main_trie_root,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+    db.insert_trie_nodes_versioned(main_trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+
+    let nibbles = |bytes: &[u8]| {
+        trie::bytes_to_nibbles(bytes.iter().copied())
+            .map(u8::from)
+            .collect::<Vec<_>>()
+    };
+    let child_trie_path = nibbles(b":child_storage:default:foo");
+
+    // The root node of the child trie is missing.
+    assert!(matches!(
+        db.block_storage_next_key(
+            &block_hash,
+            iter::once(child_trie_path.iter().copied()),
+            iter::empty(),
+            iter::empty(),
+            false
+        ),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+
+    db.insert_trie_nodes_versioned(child_trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+
+    // Compare the output with the expected result, for all the possible keys and prefixes of
+    // up to three nibbles.
+    for (trie_path, entries) in [
+        (None, &main_trie[..]),
+        (Some(&child_trie_path), &child_trie[..]),
+    ] {
+        let mut entries = entries.iter().map(|(k, _)| nibbles(k)).collect::<Vec<_>>();
+        entries.sort();
+
+        let candidates = iter::once(Vec::new())
+            .chain((0..16).map(|a| vec![a]))
+            .chain((0..16).flat_map(|a| (0..16).map(move |b| vec![a, b])))
+            .chain((0..16).flat_map(|a| [0, 1, 3, 15].map(|b| vec![a, b, 0])))
+            .chain(entries.clone())
+            .collect::<Vec<_>>();
+
+        for key in &candidates {
+            for prefix in [Vec::new(), key.clone(), key[..key.len().min(1)].to_vec()] {
+                let expected = entries
+                    .iter()
+                    .find(|e| *e >= key)
+                    .filter(|e| e.starts_with(&prefix))
+                    .cloned();
+
+                let obtained = db
+                    .block_storage_next_key(
+                        &block_hash,
+                        trie_path.map(|p| p.iter().copied()).into_iter(),
+                        key.iter().copied(),
+                        prefix.iter().copied(),
+                        false,
+                    )
+                    .unwrap();
+
+                assert_eq!(obtained, expected, "key: {key:?}, prefix: {prefix:?}");
+            }
+        }
+    }
+
+    // Storage values of the child trie can be read as well.
+    for key in [
+        &b"\x01"[..],
+        b"\x01\x02",
+        b"\x01\x30",
+        b"\xf0",
+        b"\x02",
+        b"\x01\x02\x03",
+    ] {
+        let expected = child_trie
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| (v.clone(), TrieEntryVersion::V0));
+        assert_eq!(
+            db.block_storage_get_versioned(
+                &block_hash,
+                iter::once(child_trie_path.iter().copied()),
+                nibbles(key).into_iter()
+            )
+            .unwrap(),
+            expected
+        );
+    }
+
+    // Branch nodes are returned if requested. `abc` and `abd` have a common branch node.
+    let branch_node_key = {
+        let mut key = nibbles(b"ab");
+        key.push(6);
+        key
+    };
+    for (branch_nodes, expected) in [(true, branch_node_key.clone()), (false, nibbles(b"abc"))] {
+        assert_eq!(
+            db.block_storage_next_key(
+                &block_hash,
+                iter::empty::<iter::Empty<_>>(),
+                nibbles(b"ab").into_iter(),
+                iter::empty(),
+                branch_nodes
+            )
+            .unwrap(),
+            Some(expected)
+        );
+    }
+
+    // Paths that don't lead to a child trie.
+    for path in [nibbles(b":code"), nibbles(b":child_storage:default:bar")] {
+        assert_eq!(
+            db.block_storage_next_key(
+                &block_hash,
+                iter::once(path.into_iter()),
+                iter::empty(),
+                iter::empty(),
+                false
+            )
+            .unwrap(),
+            None
+        );
+    }
+}
+
+#[test]
+fn trie_loop_detected() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+
+    // Two nodes that are each other's child.
+    let node = |merkle_value: &'static [u8; 32], child: &'static [u8; 32]| InsertTrieNode {
+        merkle_value: Cow::Borrowed(merkle_value),
+        partial_key_nibbles: Cow::Borrowed(&[]),
+        children_merkle_values: array::from_fn(|n| (n == 0).then_some(Cow::Borrowed(&child[..]))),
+        storage_value: InsertTrieNodeStorageValue::NoValue,
+    };
+    db.insert_trie_nodes_versioned(
+        [node(&[1; 32], &[2; 32]), node(&[2; 32], &[1; 32])].into_iter(),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    assert!(matches!(
+        db.block_storage_next_key(
+            &block_hash,
             iter::empty::<iter::Empty<_>>(),
-            [1, 1, 1, 1, 1, 1, 1, 1].into_iter(),
+            iter::empty(),
+            iter::empty(),
+            false
+        ),
+        Err(StorageAccessError::Corrupted(
+            CorruptedError::TrieLoopDetected
+        ))
+    ));
+
+    assert!(matches!(
+        db.finalized_and_above_missing_trie_nodes_unordered(),
+        Err(CorruptedError::TrieLoopDetected)
+    ));
+
+    assert!(matches!(
+        db.trie_node_referencing_blocks(&[2; 32]),
+        Err(CorruptedError::TrieLoopDetected)
+    ));
+
+    assert!(matches!(
+        db.export_finalized_state(Vec::new()),
+        Err(ExportStateError::Storage(StorageAccessError::Corrupted(
+            CorruptedError::TrieLoopDetected
+        )))
+    ));
+
+    // Requests that follow a key finish even in the presence of a loop.
+    assert_eq!(
+        db.block_storage_get_versioned(
+            &block_hash,
+            iter::empty::<iter::Empty<_>>(),
+            [0; 8].into_iter()
         )
         .unwrap(),
         None
     );
 }
+
+#[test]
+fn missing_trie_nodes_many_blocks() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Root node shared by all the blocks, whose two children are missing.
+    db.insert_trie_nodes_versioned(
+        iter::once(InsertTrieNode {
+            merkle_value: Cow::Borrowed(&[1; 32]),
+            partial_key_nibbles: Cow::Borrowed(&[]),
+            children_merkle_values: array::from_fn(|n| match n {
+                0 => Some(Cow::Borrowed(&[2; 32][..])),
+                1 => Some(Cow::Borrowed(&[3; 32][..])),
+                _ => None,
+            }),
+            storage_value: InsertTrieNodeStorageValue::NoValue,
+        }),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    let mut parent_hash = db.finalized_block_hash().unwrap();
+    for number in 1..=300 {
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+    }
+
+    // Lower the maximum length of strings and blobs, in order to make sure that the
+    // implementation doesn't concatenate the information about all the blocks a node belongs to.
+    // 301 block hashes are way above this limit.
+    unsafe {
+        rusqlite::ffi::sqlite3_limit(
+            db.database.lock().handle(),
+            rusqlite::ffi::SQLITE_LIMIT_LENGTH,
+            4096,
+        );
+    }
+
+    let mut missing = db
+        .finalized_and_above_missing_trie_nodes_unordered()
+        .unwrap();
+    missing.sort_by_key(|node| node.trie_node_hash);
+    assert_eq!(missing.len(), 2);
+    for (node, (merkle_value, nibble)) in missing.iter().zip([([2; 32], 0), ([3; 32], 1)]) {
+        assert_eq!(node.trie_node_hash, merkle_value);
+        assert_eq!(node.blocks.len(), 301);
+        let mut numbers = node.blocks.iter().map(|b| b.number).collect::<Vec<_>>();
+        numbers.sort_unstable();
+        assert_eq!(numbers, (0..=300).collect::<Vec<_>>());
+        assert!(node.blocks.iter().all(
+            |b| b.trie_node_key_nibbles == [nibble] && b.parent_tries_paths_nibbles.is_empty()
+        ));
+    }
+}
+
+#[test]
+fn closest_ancestor_with_storage() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    // The trie of `[1; 32]` is complete. The trie of `[2; 32]` is entirely missing. The trie of
+    // `[3; 32]` is missing a child.
+    let node = |merkle_value: &'static [u8; 32], child: Option<&'static [u8; 32]>| InsertTrieNode {
+        merkle_value: Cow::Borrowed(merkle_value),
+        partial_key_nibbles: Cow::Borrowed(&[]),
+        children_merkle_values: array::from_fn(|n| {
+            child
+                .filter(|_| n == 0)
+                .map(|child| Cow::Borrowed(&child[..]))
+        }),
+        storage_value: InsertTrieNodeStorageValue::NoValue,
+    };
+    db.insert_trie_nodes_versioned(
+        [node(&[1; 32], None), node(&[3; 32], Some(&[4; 32]))].into_iter(),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    let mut hashes = vec![genesis_hash];
+    for (number, state_root) in [(1, [2; 32]), (2, [3; 32]), (3, [1; 32]), (4, [2; 32])] {
+        let block = header::HeaderRef {
+            parent_hash: hashes.last().unwrap(),
+            number,
+            state_root: &state_root,
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        hashes.push(header::hash_from_scale_encoded_header(&block));
+    }
+
+    assert_eq!(db.closest_ancestor_with_storage(&hashes[0]).unwrap(), None);
+    assert_eq!(
+        db.closest_ancestor_with_storage(&hashes[1]).unwrap(),
+        Some((hashes[0], 0))
+    );
+    assert_eq!(
+        db.closest_ancestor_with_storage(&hashes[3]).unwrap(),
+        Some((hashes[0], 0))
+    );
+    assert_eq!(
+        db.closest_ancestor_with_storage(&hashes[4]).unwrap(),
+        Some((hashes[3], 3))
+    );
+    assert_eq!(db.closest_ancestor_with_storage(&[0xff; 32]).unwrap(), None);
+}
+
+#[test]
+fn verify_finalized_chain_continuity() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let mut parent_hash = db.finalized_block_hash().unwrap();
+    for number in 1..=5 {
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+        if number == 4 {
+            db.set_finalized(&parent_hash).unwrap();
+        }
+    }
+
+    db.verify_finalized_chain_continuity(0).unwrap();
+    db.verify_finalized_chain_continuity(4).unwrap();
+    db.verify_finalized_chain_continuity(10).unwrap();
+
+    db.database
+        .lock()
+        .execute(
+            "UPDATE blocks SET parent_hash = ? WHERE number = 3",
+            (&[0xff; 32][..],),
+        )
+        .unwrap();
+    assert!(matches!(
+        db.verify_finalized_chain_continuity(0),
+        Err(CorruptedError::BrokenChain { number: 3 })
+    ));
+    db.verify_finalized_chain_continuity(3).unwrap();
+
+    db.database
+        .lock()
+        .execute("DELETE FROM blocks WHERE number = 4", ())
+        .unwrap();
+    assert!(matches!(
+        db.verify_finalized_chain_continuity(3),
+        Err(CorruptedError::BrokenChain { number: 4 })
+    ));
+}
+
+#[test]
+fn set_finalized_ignores_competing_fork() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let authorities = [header::GrandpaAuthority {
+        public_key: [3; 32],
+        weight: NonZeroU64::new(1).unwrap(),
+    }];
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Grandpa {
+                    after_finalized_block_authorities_set_id: 0,
+                    finalized_triggered_authorities: &authorities,
+                    finalized_scheduled_change: None,
+                },
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let insert = |parent_hash: &[u8; 32], number, digest, is_new_best| {
+        let block = header::HeaderRef {
+            parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest,
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+
+    let block1 = insert(
+        &db.finalized_block_hash().unwrap(),
+        1,
+        header::DigestRef::empty(),
+        true,
+    );
+    let block2 = insert(&block1, 2, header::DigestRef::empty(), true);
+    let block3 = insert(&block2, 3, header::DigestRef::empty(), true);
+
+    // Competing fork at height 2 that changes the GrandPa authorities.
+    let fork_digest = [header::DigestItem::GrandpaConsensus(
+        header::GrandpaConsensusLog::ScheduledChange(header::GrandpaScheduledChange {
+            next_authorities: vec![header::GrandpaAuthority {
+                public_key: [4; 32],
+                weight: NonZeroU64::new(1).unwrap(),
+            }],
+            delay: 0,
+        }),
+    )];
+    let fork2 = insert(
+        &block1,
+        2,
+        header::DigestRef::from_slice(&fork_digest).unwrap(),
+        false,
+    );
+
+    db.set_finalized(&block3).unwrap();
+
+    // The authorities change of the fork must not have been applied.
+    let chain_information = db.to_chain_information(&block3).unwrap();
+    let chain_information::ChainInformationFinalityRef::Grandpa {
+        after_finalized_block_authorities_set_id,
+        finalized_triggered_authorities,
+        ..
+    } = chain_information.as_ref().finality
+    else {
+        panic!()
+    };
+    assert_eq!(after_finalized_block_authorities_set_id, 0);
+    assert_eq!(finalized_triggered_authorities, &authorities[..]);
+
+    assert_eq!(db.best_block_hash_by_number(2).unwrap(), Some(block2));
+    assert!(db.block_parent(&fork2).unwrap().is_some());
+}
+
+#[test]
+fn maintain() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        auto_optimize_interval: Some(Duration::ZERO),
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Modifications run `PRAGMA optimize` when the interval has elapsed.
+    let before = *db.last_optimize.lock();
+    let block = header::HeaderRef {
+        parent_hash: &db.finalized_block_hash().unwrap(),
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert!(*db.last_optimize.lock() > before);
+
+    // After a large import, `maintain` rebuilds all the statistics.
+    db.maintain().unwrap();
+    db.database
+        .lock()
+        .execute("DELETE FROM sqlite_stat1", ())
+        .unwrap();
+    db.analyze_pending
+        .store(true, core::sync::atomic::Ordering::Relaxed);
+    db.maintain().unwrap();
+    assert!(!db
+        .analyze_pending
+        .load(core::sync::atomic::Ordering::Relaxed));
+    let num_statistics = db
+        .database
+        .lock()
+        .query_row(
+            "SELECT COUNT(*) FROM sqlite_stat1 WHERE tbl = 'blocks'",
+            (),
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap();
+    assert!(num_statistics > 0);
+}
+
+#[test]
+fn debug_output() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let block = header::HeaderRef {
+        parent_hash: &db.finalized_block_hash().unwrap(),
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    assert_eq!(db.path(), Some(&*path));
+    let output = format!("{db:?}");
+    assert!(output.contains(&format!("{path:?}")));
+    assert!(output.contains("block_number_bytes: 4"));
+    assert!(output.contains("best_number: 1"));
+    assert!(output.contains("finalized_number: 0"));
+
+    // The numbers are omitted rather than waited for if the database is in use.
+    let lock = db.database.lock();
+    let output = format!("{db:?}");
+    assert!(output.contains("block_number_bytes: 4"));
+    assert!(!output.contains("best_number"));
+    drop(lock);
+
+    let memory_db = db.clone_in_memory().unwrap();
+    assert_eq!(memory_db.path(), None);
+    assert!(format!("{memory_db:?}").contains("\":memory:\""));
+}
+
+#[test]
+fn sqlite_build_info() {
+    let info = super::sqlite_build_info().unwrap();
+    assert_eq!(info.version, super::sqlite_version());
+    assert!(!info.compile_options.is_empty());
+    assert!(info.max_length >= 1024 * 1024);
+    assert!(info.max_sql_length >= 1024 * 1024);
+}
+
+#[test]
+fn babe_epoch_information_encoding() {
+    // Repeat the test many times due to randomness.
+    for _ in 0..1024 {
+        let c0 = rand::random::<u64>();
+        let info = chain_information::BabeEpochInformation {
+            epoch_index: rand::random(),
+            start_slot_number: if rand::random() {
+                Some(rand::random())
+            } else {
+                None
+            },
+            authorities: (0..Uniform::new_inclusive(0, 16).sample(&mut rand::thread_rng()))
+                .map(|_| header::BabeAuthority {
+                    public_key: rand::random(),
+                    weight: rand::random(),
+                })
+                .collect(),
+            randomness: rand::random(),
+            c: (
+                c0,
+                Uniform::new_inclusive(c0, u64::MAX).sample(&mut rand::thread_rng()),
+            ),
+            allowed_slots: match Uniform::new_inclusive(0, 2).sample(&mut rand::thread_rng()) {
+                0 => header::BabeAllowedSlots::PrimarySlots,
+                1 => header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+                _ => header::BabeAllowedSlots::PrimaryAndSecondaryVrfSlots,
+            },
+        };
+
+        let encoded = super::encode_babe_epoch_information(From::from(&info));
+        let decoded = super::decode_babe_epoch_information(&encoded).unwrap();
+        assert_eq!(decoded.epoch_index, info.epoch_index);
+        assert_eq!(decoded.start_slot_number, info.start_slot_number);
+        assert_eq!(decoded.authorities, info.authorities);
+        assert_eq!(decoded.randomness, info.randomness);
+        assert_eq!(decoded.c, info.c);
+        assert_eq!(decoded.allowed_slots, info.allowed_slots);
+
+        let mut unknown_version = encoded.clone();
+        unknown_version[0] = 2;
+        assert!(matches!(
+            super::decode_babe_epoch_information(&unknown_version),
+            Err(CorruptedError::InvalidBabeEpochInformation(
+                BabeEpochInformationDecodeError::UnknownVersion(2)
+            ))
+        ));
+
+        let truncated = &encoded[..Uniform::new(0, encoded.len()).sample(&mut rand::thread_rng())];
+        assert!(matches!(
+            super::decode_babe_epoch_information(truncated),
+            Err(CorruptedError::InvalidBabeEpochInformation(
+                BabeEpochInformationDecodeError::Truncated { .. }
+                    | BabeEpochInformationDecodeError::TruncatedAuthorities { .. }
+            ))
+        ));
+    }
+}
+
+#[test]
+fn babe_epoch_information_decode_errors() {
+    let info = chain_information::BabeEpochInformation {
+        epoch_index: 1,
+        start_slot_number: Some(2),
+        authorities: vec![
+            header::BabeAuthority {
+                public_key: [3; 32],
+                weight: 4,
+            },
+            header::BabeAuthority {
+                public_key: [5; 32],
+                weight: 6,
+            },
+        ],
+        randomness: [7; 32],
+        c: (1, 4),
+        allowed_slots: header::BabeAllowedSlots::PrimarySlots,
+    };
+    let encoded = super::encode_babe_epoch_information(From::from(&info));
+    let decode_err = |value: &[u8]| match super::decode_babe_epoch_information(value) {
+        Err(CorruptedError::InvalidBabeEpochInformation(err)) => err,
+        _ => panic!(),
+    };
+
+    // Version, epoch index, start slot number, number of authorities.
+    let authorities_offset = 1 + 8 + 9 + 1;
+    assert_eq!(
+        decode_err(&encoded[..authorities_offset + 50]),
+        BabeEpochInformationDecodeError::TruncatedAuthorities {
+            offset: authorities_offset,
+            expected: 2,
+            found: 1,
+        }
+    );
+
+    assert_eq!(
+        decode_err(&encoded[..5]),
+        BabeEpochInformationDecodeError::Truncated { offset: 1 }
+    );
+
+    let mut invalid_option = encoded.clone();
+    invalid_option[9] = 2;
+    assert_eq!(
+        decode_err(&invalid_option),
+        BabeEpochInformationDecodeError::InvalidValue { offset: 9 }
+    );
+
+    let mut invalid_allowed_slots = encoded.clone();
+    *invalid_allowed_slots.last_mut().unwrap() = 3;
+    assert_eq!(
+        decode_err(&invalid_allowed_slots),
+        BabeEpochInformationDecodeError::InvalidValue {
+            offset: encoded.len() - 1
+        }
+    );
+
+    let mut trailing_bytes = encoded.clone();
+    trailing_bytes.push(0);
+    assert_eq!(
+        decode_err(&trailing_bytes),
+        BabeEpochInformationDecodeError::TrailingBytes {
+            offset: encoded.len()
+        }
+    );
+
+    let invalid_constant = super::encode_babe_epoch_information(From::from(
+        &chain_information::BabeEpochInformation { c: (4, 1), ..info },
+    ));
+    assert_eq!(
+        decode_err(&invalid_constant),
+        BabeEpochInformationDecodeError::ValidationFailed(
+            chain_information::BabeValidityError::InvalidConstant
+        )
+    );
+}
+
+/// Turns the schema of the database back into the one that predates the storage diffs, as if
+/// the database had been created by an older version.
+fn revert_schema_to_v17(connection: &rusqlite::Connection) {
+    connection
+        .execute_batch(
+            r#"
+DROP TABLE storage_diffs;
+DROP TABLE blocks_storage_diff;
+PRAGMA user_version = 17;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one where the extrinsics are stored in the
+/// `blocks_body` table, as if the database had been created by an older version.
+fn revert_schema_to_v16(connection: &rusqlite::Connection) {
+    revert_schema_to_v17(connection);
+    connection
+        .execute_batch(
+            r#"
+CREATE TABLE blocks_body_old(
+    hash BLOB NOT NULL,
+    idx INTEGER NOT NULL,
+    extrinsic BLOB NOT NULL,
+    UNIQUE(hash, idx),
+    CHECK(length(hash) == 32),
+    FOREIGN KEY (hash) REFERENCES blocks(hash) ON UPDATE CASCADE ON DELETE CASCADE
+);
+INSERT INTO blocks_body_old(hash, idx, extrinsic)
+    SELECT blocks_body.hash, idx, bytes FROM blocks_body JOIN extrinsics ON extrinsics.hash = blocks_body.extrinsic_hash;
+DROP TRIGGER blocks_body_release_extrinsic;
+DROP TABLE blocks_body;
+DROP TABLE extrinsics;
+ALTER TABLE blocks_body_old RENAME TO blocks_body;
+CREATE INDEX blocks_body_by_block ON blocks_body(hash);
+PRAGMA user_version = 16;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one that predates the `CHECK` constraints
+/// added in version 16, as if the database had been created by an older version.
+fn revert_schema_to_v15(connection: &rusqlite::Connection) {
+    revert_schema_to_v16(connection);
+    connection
+        .execute_batch(
+            r#"
+PRAGMA foreign_keys = OFF;
+
+CREATE TABLE blocks_old(
+    hash BLOB NOT NULL PRIMARY KEY,
+    parent_hash BLOB,
+    state_trie_root_hash BLOB,
+    number INTEGER NOT NULL,
+    header BLOB NOT NULL,
+    is_best_chain BOOLEAN NOT NULL,
+    body_pruned BOOLEAN NOT NULL DEFAULT FALSE,
+    runtime_spec_version INTEGER,
+    chain_id INTEGER NOT NULL DEFAULT 0,
+    UNIQUE(number, hash)
+);
+INSERT INTO blocks_old SELECT hash, parent_hash, state_trie_root_hash, number, header, is_best_chain, body_pruned, runtime_spec_version, chain_id FROM blocks;
+DROP TABLE blocks;
+ALTER TABLE blocks_old RENAME TO blocks;
+CREATE INDEX blocks_by_parent ON blocks(parent_hash);
+CREATE INDEX blocks_by_state_trie_root_hash ON blocks(state_trie_root_hash);
+CREATE INDEX blocks_by_best ON blocks(chain_id, number, is_best_chain, hash);
+
+CREATE TABLE trie_node_storage_old(
+    node_hash BLOB NOT NULL PRIMARY KEY,
+    value BLOB,
+    trie_root_ref BLOB,
+    trie_entry_version INTEGER NOT NULL,
+    value_compressed BOOLEAN NOT NULL DEFAULT FALSE,
+    value_hash BLOB,
+    FOREIGN KEY (node_hash) REFERENCES trie_node(hash) ON UPDATE CASCADE ON DELETE CASCADE,
+    CHECK((value IS NULL) != (trie_root_ref IS NULL))
+);
+INSERT INTO trie_node_storage_old SELECT node_hash, value, trie_root_ref, trie_entry_version, value_compressed, value_hash FROM trie_node_storage;
+DROP TABLE trie_node_storage;
+ALTER TABLE trie_node_storage_old RENAME TO trie_node_storage;
+CREATE INDEX trie_node_storage_by_trie_root_ref ON trie_node_storage(trie_root_ref);
+
+PRAGMA foreign_keys = ON;
+PRAGMA user_version = 15;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one where the GrandPa justifications are
+/// stored in the `justification` column of the `blocks` table, as if the database had been
+/// created by an older version.
+fn revert_schema_to_v14(connection: &rusqlite::Connection) {
+    revert_schema_to_v15(connection);
+    connection
+        .execute_batch(
+            r#"
+ALTER TABLE blocks ADD COLUMN justification BLOB;
+UPDATE blocks SET justification = (
+    SELECT justification FROM blocks_justifications
+    WHERE blocks_justifications.hash = blocks.hash AND engine_id = CAST('FRNK' AS BLOB)
+);
+DROP TABLE blocks_justifications;
+PRAGMA user_version = 14;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one that predates the `value_hash` column
+/// of the `trie_node_storage` table, as if the database had been created by an older version.
+fn revert_schema_to_v13(connection: &rusqlite::Connection) {
+    revert_schema_to_v14(connection);
+    connection
+        .execute_batch(
+            r#"
+ALTER TABLE trie_node_storage DROP COLUMN value_hash;
+PRAGMA user_version = 13;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one that predates the `initialized` key in
+/// the `meta` table, as if the database had been created by an older version.
+fn revert_schema_to_v12(connection: &rusqlite::Connection) {
+    revert_schema_to_v13(connection);
+    connection
+        .execute_batch(
+            r#"
+DELETE FROM meta WHERE key = 'initialized';
+PRAGMA user_version = 12;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one where the children of the trie nodes are
+/// stored in the `trie_node_child` table, as if the database had been created by an older
+/// version.
+fn revert_schema_to_v11(connection: &rusqlite::Connection) {
+    revert_schema_to_v12(connection);
+    open::register_functions(connection).unwrap();
+    connection
+        .execute_batch(
+            r#"
+CREATE TABLE trie_node_child(
+    hash BLOB NOT NULL,
+    child_num BLOB NOT NULL,
+    child_hash BLOB NOT NULL,
+    PRIMARY KEY (hash, child_num),
+    FOREIGN KEY (hash) REFERENCES trie_node(hash) ON UPDATE CASCADE ON DELETE CASCADE
+    CHECK(LENGTH(child_num) == 1 AND HEX(child_num) < '10')
+);
+CREATE INDEX trie_node_child_by_hash ON trie_node_child(hash);
+CREATE INDEX trie_node_child_by_child_hash ON trie_node_child(child_hash);
+
+WITH nibble(num) AS (
+    VALUES (X'00'), (X'01'), (X'02'), (X'03'), (X'04'), (X'05'), (X'06'), (X'07'),
+        (X'08'), (X'09'), (X'0a'), (X'0b'), (X'0c'), (X'0d'), (X'0e'), (X'0f')
+)
+INSERT INTO trie_node_child(hash, child_num, child_hash)
+    SELECT trie_node.hash, nibble.num, child_merkle_value(trie_node.children, nibble.num)
+    FROM trie_node JOIN nibble
+    WHERE child_merkle_value(trie_node.children, nibble.num) IS NOT NULL;
+
+ALTER TABLE trie_node DROP COLUMN children;
+DROP TABLE trie_node_parent;
+
+PRAGMA user_version = 11;
+            "#,
+        )
+        .unwrap();
+}
+
+/// Turns the schema of the database back into the one that predates the support for multiple
+/// chains, as if the database had been created by an older version. Only works if the database
+/// contains a single chain.
+fn revert_schema_to_v7(connection: &rusqlite::Connection) {
+    revert_schema_to_v11(connection);
+    connection
+        .execute_batch(
+            r#"
+ALTER TABLE trie_node_storage DROP COLUMN value_compressed;
+
+DROP TABLE chains;
+
+DROP INDEX blocks_by_best;
+ALTER TABLE blocks DROP COLUMN chain_id;
+CREATE INDEX blocks_by_number ON blocks(number);
+CREATE INDEX blocks_by_best ON blocks(number, is_best_chain);
+
+CREATE TABLE meta_old(
+    key STRING NOT NULL PRIMARY KEY,
+    value_blob BLOB,
+    value_number INTEGER,
+    CHECK((value_blob IS NULL OR value_number IS NULL) AND (value_blob IS NOT NULL OR value_number IS NOT NULL))
+);
+INSERT INTO meta_old SELECT key, value_blob, value_number FROM meta;
+DROP TABLE meta;
+ALTER TABLE meta_old RENAME TO meta;
+
+CREATE TABLE triggered_old(idx INTEGER NOT NULL PRIMARY KEY, public_key BLOB NOT NULL, weight BLOB NOT NULL);
+INSERT INTO triggered_old SELECT idx, public_key, weight FROM grandpa_triggered_authorities;
+DROP TABLE grandpa_triggered_authorities;
+ALTER TABLE triggered_old RENAME TO grandpa_triggered_authorities;
+
+CREATE TABLE scheduled_old(idx INTEGER NOT NULL PRIMARY KEY, public_key BLOB NOT NULL, weight BLOB NOT NULL);
+INSERT INTO scheduled_old SELECT idx, public_key, weight FROM grandpa_scheduled_authorities;
+DROP TABLE grandpa_scheduled_authorities;
+ALTER TABLE scheduled_old RENAME TO grandpa_scheduled_authorities;
+
+CREATE TABLE aura_old(idx INTEGER NOT NULL PRIMARY KEY, public_key BLOB NOT NULL);
+INSERT INTO aura_old SELECT idx, public_key FROM aura_finalized_authorities;
+DROP TABLE aura_finalized_authorities;
+ALTER TABLE aura_old RENAME TO aura_finalized_authorities;
+
+CREATE TABLE runtimes_old(spec_version INTEGER NOT NULL PRIMARY KEY, code_hash BLOB NOT NULL, code BLOB NOT NULL, heap_pages INTEGER NOT NULL);
+INSERT INTO runtimes_old SELECT spec_version, code_hash, code, heap_pages FROM runtimes;
+DROP TABLE runtimes;
+ALTER TABLE runtimes_old RENAME TO runtimes;
+
+PRAGMA user_version = 7;
+            "#,
+        )
+        .unwrap();
+}
+
+#[test]
+fn babe_epoch_information_migration() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_config = || Config {
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    };
+
+    let epoch = chain_information::BabeEpochInformation {
+        epoch_index: 0,
+        start_slot_number: None,
+        authorities: vec![header::BabeAuthority {
+            public_key: [3; 32],
+            weight: 5,
+        }],
+        randomness: [4; 32],
+        c: (1, 4),
+        allowed_slots: header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+    };
+
+    let finalized_block_hash = {
+        let DatabaseOpen::Empty(empty_db) = open(open_config()).unwrap() else {
+            panic!()
+        };
+        let db = empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &[1; 32],
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Babe {
+                        slots_per_epoch: NonZeroU64::new(10).unwrap(),
+                        finalized_block_epoch_information: None,
+                        finalized_next_epoch_transition: From::from(&epoch),
+                    },
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap();
+
+        let finalized_block_hash = db.finalized_block_hash().unwrap();
+
+        // Turn the database back into the format that predates the version prefix.
+        let database = db.database.lock();
+        revert_schema_to_v7(&database);
+        database
+            .execute_batch(
+                r#"
+UPDATE meta SET value_blob = substr(value_blob, 2) WHERE key = 'babe_finalized_next_epoch';
+PRAGMA user_version = 6;
+        "#,
+            )
+            .unwrap();
+        finalized_block_hash
+    };
+
+    let DatabaseOpen::Open(db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+    let chain_information = db.to_chain_information(&finalized_block_hash).unwrap();
+    let chain_information::ChainInformationConsensusRef::Babe {
+        finalized_next_epoch_transition,
+        ..
+    } = chain_information.as_ref().consensus
+    else {
+        panic!()
+    };
+    assert_eq!(finalized_next_epoch_transition.epoch_index, 0);
+    assert_eq!(
+        finalized_next_epoch_transition
+            .authorities
+            .map(header::BabeAuthority::from)
+            .collect::<Vec<_>>(),
+        epoch.authorities
+    );
+    assert_eq!(*finalized_next_epoch_transition.randomness, [4; 32]);
+    assert_eq!(finalized_next_epoch_transition.c, (1, 4));
+}
+
+#[test]
+fn block_info() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            Some(vec![1, 2, 3]),
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[2; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    db.insert(&block1, true, iter::once(&[5u8][..]), None, None)
+        .unwrap();
+
+    let fork1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[3; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let fork1_hash = header::hash_from_scale_encoded_header(&fork1);
+    db.insert(&fork1, false, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    let genesis = db.block_info(&genesis_hash).unwrap().unwrap();
+    assert_eq!(genesis.number, 0);
+    assert_eq!(genesis.state_trie_root, Some([1; 32]));
+    assert!(genesis.is_best_chain);
+    assert!(genesis.has_justification);
+
+    assert_eq!(
+        db.block_info(&block1_hash).unwrap(),
+        Some(BlockRef {
+            hash: block1_hash,
+            number: 1,
+            parent_hash: Some(genesis_hash),
+            state_trie_root: Some([2; 32]),
+            is_best_chain: true,
+            has_body: true,
+            has_justification: false,
+        })
+    );
+
+    let fork = db.block_info(&fork1_hash).unwrap().unwrap();
+    assert_eq!(fork.state_trie_root, Some([3; 32]));
+    assert!(!fork.is_best_chain);
+
+    assert_eq!(db.block_info(&[0xff; 32]).unwrap(), None);
+}
+
+#[test]
+fn tree_route() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis = (db.finalized_block_hash().unwrap(), 0);
+
+    // Builds the chain `genesis -> 1 -> 2` and the fork `genesis -> 1' -> 2' -> 3'`.
+    let insert_chain = |state_root: u8, length: u64| {
+        let mut chain = Vec::new();
+        let mut parent_hash = genesis.0;
+        for number in 1..=length {
+            let block = header::HeaderRef {
+                parent_hash: &parent_hash,
+                number,
+                state_root: &[state_root; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None)
+                .unwrap();
+            parent_hash = header::hash_from_scale_encoded_header(&block);
+            chain.push((parent_hash, number));
+        }
+        chain
+    };
+    let chain = insert_chain(2, 2);
+    let fork = insert_chain(3, 3);
+
+    let route = db.tree_route(&chain[1].0, &fork[2].0).unwrap().unwrap();
+    assert_eq!(route.common_ancestor, genesis);
+    assert_eq!(route.retracted, vec![chain[1], chain[0]]);
+    assert_eq!(route.enacted, fork);
+
+    let route = db.tree_route(&fork[1].0, &chain[0].0).unwrap().unwrap();
+    assert_eq!(route.common_ancestor, genesis);
+    assert_eq!(route.retracted, vec![fork[1], fork[0]]);
+    assert_eq!(route.enacted, vec![chain[0]]);
+
+    let route = db.tree_route(&genesis.0, &chain[1].0).unwrap().unwrap();
+    assert_eq!(route.common_ancestor, genesis);
+    assert!(route.retracted.is_empty());
+    assert_eq!(route.enacted, chain);
+
+    let route = db.tree_route(&fork[2].0, &fork[0].0).unwrap().unwrap();
+    assert_eq!(route.common_ancestor, fork[0]);
+    assert_eq!(route.retracted, vec![fork[2], fork[1]]);
+    assert!(route.enacted.is_empty());
+
+    let route = db.tree_route(&chain[1].0, &chain[1].0).unwrap().unwrap();
+    assert_eq!(route.common_ancestor, chain[1]);
+    assert!(route.retracted.is_empty());
+    assert!(route.enacted.is_empty());
+
+    assert!(db.tree_route(&chain[1].0, &[0xff; 32]).unwrap().is_none());
+    assert!(db.tree_route(&[0xff; 32], &chain[1].0).unwrap().is_none());
+}
+
+#[test]
+fn set_best_block_reorgs() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis = db.finalized_block_hash().unwrap();
+
+    // Parent of each block, used to determine the expected best chain.
+    let mut parents = std::collections::HashMap::<[u8; 32], [u8; 32]>::new();
+    let mut insert_chain = |parent: [u8; 32], parent_number: u64, state_root: u8, length: u64| {
+        let mut chain = Vec::new();
+        let mut parent_hash = parent;
+        for number in parent_number + 1..=parent_number + length {
+            let block = header::HeaderRef {
+                parent_hash: &parent_hash,
+                number,
+                state_root: &[state_root; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None)
+                .unwrap();
+            let hash = header::hash_from_scale_encoded_header(&block);
+            parents.insert(hash, parent_hash);
+            parent_hash = hash;
+            chain.push(hash);
+        }
+        chain
+    };
+
+    let chain_a = insert_chain(genesis, 0, 2, 10);
+    // Deep fork branching off `chain_a` and longer than it.
+    let chain_b = insert_chain(chain_a[3], 4, 3, 12);
+    // Fork that only shares the finalized block with the other chains.
+    let chain_c = insert_chain(genesis, 0, 4, 5);
+
+    let all_blocks = iter::once(genesis)
+        .chain(chain_a.iter().copied())
+        .chain(chain_b.iter().copied())
+        .chain(chain_c.iter().copied())
+        .collect::<Vec<_>>();
+
+    let check_best_chain = |best: [u8; 32]| {
+        let mut expected = vec![best];
+        while let Some(parent) = parents.get(expected.last().unwrap()) {
+            expected.push(*parent);
+        }
+
+        assert_eq!(db.best_block_hash().unwrap(), best);
+        for block in &all_blocks {
+            let info = db.block_info(block).unwrap().unwrap();
+            assert_eq!(info.is_best_chain, expected.contains(block));
+            if info.is_best_chain {
+                assert_eq!(
+                    db.best_block_hash_by_number(info.number).unwrap(),
+                    Some(*block)
+                );
+            }
+        }
+        assert_eq!(
+            db.best_block_hash_by_number(u64::try_from(expected.len()).unwrap())
+                .unwrap(),
+            None
+        );
+    };
+
+    for best in [
+        chain_a[9],
+        chain_b[11],
+        chain_c[2],
+        chain_a[5],
+        chain_b[0],
+        chain_a[3],
+        chain_c[4],
+        genesis,
+        chain_b[7],
+        chain_b[7],
+    ] {
+        db.set_best_block(&best).unwrap();
+        check_best_chain(best);
+    }
+
+    assert!(matches!(
+        db.set_best_block(&[0xff; 32]),
+        Err(SetBestError::UnknownBlock)
+    ));
+
+    // Once `chain_a[3]` is finalized, `chain_c` can no longer become the best chain.
+    db.set_finalized(&chain_a[3]).unwrap();
+    assert!(matches!(
+        db.set_best_block(&chain_c[4]),
+        Err(SetBestError::NotInFinalizedChain)
+    ));
+    assert!(matches!(
+        db.set_best_block(&chain_a[2]),
+        Err(SetBestError::NotInFinalizedChain)
+    ));
+    check_best_chain(chain_b[7]);
+
+    db.set_best_block(&chain_a[9]).unwrap();
+    check_best_chain(chain_a[9]);
+    db.set_best_block(&chain_a[3]).unwrap();
+    check_best_chain(chain_a[3]);
+}
+
+#[test]
+fn insert_reports_best_chain_change() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis = (db.finalized_block_hash().unwrap(), 0);
+
+    let insert = |parent: ([u8; 32], u64), state_root: u8, is_new_best: bool| {
+        let block = header::HeaderRef {
+            parent_hash: &parent.0,
+            number: parent.1 + 1,
+            state_root: &[state_root; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        let outcome = db
+            .insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        (
+            (header::hash_from_scale_encoded_header(&block), parent.1 + 1),
+            outcome,
+        )
+    };
+
+    // Extending the best chain enacts only the new block.
+    let (block1, outcome) = insert(genesis, 2, true);
+    let route = outcome.best_chain_change.unwrap();
+    assert_eq!(route.common_ancestor, genesis);
+    assert!(route.retracted.is_empty());
+    assert_eq!(route.enacted, vec![block1]);
+    let (block2, _) = insert(block1, 2, true);
+
+    // Blocks that don't become the best block don't report any change.
+    let (fork1, outcome) = insert(genesis, 3, false);
+    assert!(outcome.best_chain_change.is_none());
+    let (fork2, outcome) = insert(fork1, 3, false);
+    assert!(outcome.best_chain_change.is_none());
+
+    // Switching to a different fork reports the blocks that have been retracted and enacted.
+    let (fork3, outcome) = insert(fork2, 3, true);
+    let route = outcome.best_chain_change.unwrap();
+    assert_eq!(route.common_ancestor, genesis);
+    assert_eq!(route.retracted, vec![block2, block1]);
+    assert_eq!(route.enacted, vec![fork1, fork2, fork3]);
+    assert_eq!(db.best_block_hash().unwrap(), fork3.0);
+}
+
+#[test]
+fn insert_best_fork_below_finalized() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis = db.finalized_block_hash().unwrap();
+
+    let header = |parent_hash: &[u8; 32], number: u64, state_root: u8| {
+        header::HeaderRef {
+            parent_hash,
+            number,
+            state_root: &[state_root; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4)
+    };
+
+    // Builds the chain `genesis -> 1 -> 2`, with `2` finalized, and the fork
+    // `genesis -> 1' -> 2' -> 3'`.
+    let mut parent_hash = genesis;
+    for number in 1..=2 {
+        let block = header(&parent_hash, number, 2);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+    }
+    let finalized = parent_hash;
+    db.set_finalized(&finalized).unwrap();
+
+    let mut parent_hash = genesis;
+    for number in 1..=3 {
+        let block = header(&parent_hash, number, 3);
+        db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+    }
+
+    // `4'` is higher than the finalized block, but doesn't descend from it.
+    let fork_block = header(&parent_hash, 4, 3);
+    assert!(matches!(
+        db.insert(&fork_block, true, iter::empty::<Vec<u8>>(), None, None),
+        Err(InsertError::BestNotInFinalizedChain)
+    ));
+    assert!(db
+        .block_info(&header::hash_from_scale_encoded_header(&fork_block))
+        .unwrap()
+        .is_none());
+    assert_eq!(db.best_block_hash().unwrap(), finalized);
+
+    // The same block can still be inserted without becoming the best block.
+    db.insert(&fork_block, false, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(db.best_block_hash().unwrap(), finalized);
+
+    // Descendants of the finalized block can become the best block.
+    let block = header(&finalized, 3, 2);
+    db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(
+        db.best_block_hash().unwrap(),
+        header::hash_from_scale_encoded_header(&block)
+    );
+}
+
+#[test]
+fn finalized_hash_ignores_forks_at_same_height() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis = db.finalized_block_hash().unwrap();
+
+    // Several blocks are inserted at the same height. The one with the highest hash is
+    // finalized, while the other ones aren't purged.
+    let finalized = (2..6)
+        .map(|state_root| {
+            let block = header::HeaderRef {
+                parent_hash: &genesis,
+                number: 1,
+                state_root: &[state_root; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None)
+                .unwrap();
+            header::hash_from_scale_encoded_header(&block)
+        })
+        .max()
+        .unwrap();
+    db.set_best_block(&finalized).unwrap();
+    db.set_finalized(&finalized).unwrap();
+
+    for _ in 0..5 {
+        assert_eq!(db.finalized_block_hash().unwrap(), finalized);
+    }
+    assert_eq!(db.best_block_hash().unwrap(), finalized);
+}
+
+#[test]
+fn multiple_chains() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_chain = |chain_name: Option<&str>| {
+        open(Config {
+            chain_name,
+            read_connections: 2,
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap()
+    };
+
+    // Initializes a chain whose blocks, authorities and runtime are all derived from `id`, and
+    // returns the hashes of its blocks.
+    let fill = |db: DatabaseOpen, id: u8| {
+        let DatabaseOpen::Empty(empty_db) = db else {
+            panic!()
+        };
+        let db = empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &[id; 32],
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Grandpa {
+                        after_finalized_block_authorities_set_id: u64::from(id),
+                        finalized_triggered_authorities: &[header::GrandpaAuthority {
+                            public_key: [id; 32],
+                            weight: NonZeroU64::new(1).unwrap(),
+                        }],
+                        finalized_scheduled_change: None,
+                    },
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap();
+
+        let mut hashes = vec![db.finalized_block_hash().unwrap()];
+        for number in 1..=3 {
+            let block = header::HeaderRef {
+                parent_hash: hashes.last().unwrap(),
+                number,
+                state_root: &[id; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+                .unwrap();
+            hashes.push(header::hash_from_scale_encoded_header(&block));
+        }
+        db.set_finalized(&hashes[1]).unwrap();
+        db.store_runtime(1, &[id], HeapPages::new(u32::from(id)))
+            .unwrap();
+        (db, hashes)
+    };
+
+    let check = |db: &super::SqliteFullDatabase, hashes: &[[u8; 32]], id: u8| {
+        assert_eq!(db.best_block_hash().unwrap(), hashes[3]);
+        assert_eq!(db.finalized_block_hash().unwrap(), hashes[1]);
+        for (number, hash) in hashes.iter().enumerate() {
+            let number = u64::try_from(number).unwrap();
+            assert_eq!(db.best_block_hash_by_number(number).unwrap(), Some(*hash));
+            assert_eq!(
+                db.block_hash_by_number(number).unwrap().collect::<Vec<_>>(),
+                vec![*hash]
+            );
+        }
+        assert_eq!(db.best_block_hash_by_number(4).unwrap(), None);
+
+        let chain_information = db.to_chain_information(&hashes[1]).unwrap();
+        let chain_information::ChainInformationFinalityRef::Grandpa {
+            after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities,
+            ..
+        } = chain_information.as_ref().finality
+        else {
+            panic!()
+        };
+        assert_eq!(after_finalized_block_authorities_set_id, u64::from(id));
+        assert_eq!(finalized_triggered_authorities.len(), 1);
+        assert_eq!(finalized_triggered_authorities[0].public_key, [id; 32]);
+
+        let runtime = db.runtime_by_spec_version(1).unwrap().unwrap();
+        assert_eq!(runtime.code, vec![id]);
+    };
+
+    // The database is locked as a whole, and the chains are thus filled one after the other.
+    let (db_default, hashes_default) = fill(open_chain(None), 1);
+    assert_eq!(db_default.chain_name(), None);
+    check(&db_default, &hashes_default, 1);
+    drop(db_default);
+
+    let (db_testnet, hashes_testnet) = fill(open_chain(Some("testnet")), 2);
+    assert_eq!(db_testnet.chain_name(), Some("testnet"));
+    assert!(format!("{db_testnet:?}").contains(r#"chain_name: "testnet""#));
+    check(&db_testnet, &hashes_testnet, 2);
+    check(&db_testnet.clone_in_memory().unwrap(), &hashes_testnet, 2);
+    drop(db_testnet);
+
+    let DatabaseOpen::Open(db) = open_chain(None) else {
+        panic!()
+    };
+    check(&db, &hashes_default, 1);
+    drop(db);
+
+    let DatabaseOpen::Open(db) = open_chain(Some("testnet")) else {
+        panic!()
+    };
+    check(&db, &hashes_testnet, 2);
+    drop(db);
+
+    assert!(matches!(open_chain(Some("other")), DatabaseOpen::Empty(_)));
+}
+
+#[test]
+fn chains_sharing_genesis() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_chain = |chain_name: Option<&str>| {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            chain_name,
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+        empty_db
+    };
+
+    let genesis = |state_root: &[u8; 32]| chain_information::ChainInformation {
+        finalized_block_header: Box::new(header::Header {
+            number: 0,
+            extrinsics_root: [0; 32],
+            parent_hash: [0; 32],
+            state_root: *state_root,
+            digest: header::DigestRef::empty().into(),
+        }),
+        consensus: chain_information::ChainInformationConsensus::Unknown,
+        finality: chain_information::ChainInformationFinality::Outsourced,
+    };
+
+    let db_default = open_chain(None)
+        .initialize(&genesis(&[1; 32]), iter::empty(), None)
+        .unwrap();
+    let genesis_hash = db_default.finalized_block_hash().unwrap();
+    drop(db_default);
+
+    // The genesis block already belongs to the default chain, and can't be moved to the other
+    // chain.
+    assert!(matches!(
+        open_chain(Some("other")).initialize(&genesis(&[1; 32]), iter::empty(), None),
+        Err(ResetError::BlockOfOtherChain)
+    ));
+
+    // The blocks of a chain aren't visible from the other chains.
+    let db_other = open_chain(Some("other"))
+        .initialize(&genesis(&[2; 32]), iter::empty(), None)
+        .unwrap();
+    assert_ne!(db_other.finalized_block_hash().unwrap(), genesis_hash);
+    assert_eq!(
+        db_other.block_scale_encoded_header(&genesis_hash).unwrap(),
+        None
+    );
+    assert!(matches!(
+        db_other.set_finalized(&genesis_hash),
+        Err(super::SetFinalizedError::UnknownBlock)
+    ));
+    drop(db_other);
+
+    let DatabaseOpen::Open(db_default) = open(Config {
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+    assert_eq!(db_default.finalized_block_hash().unwrap(), genesis_hash);
+    assert_eq!(db_default.best_block_hash().unwrap(), genesis_hash);
+    assert!(db_default
+        .block_scale_encoded_header(&genesis_hash)
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn invalid_nibbles() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+
+    let valid = [1, 2, 3];
+    let invalid = [1, 2, 0x20];
+    let is_invalid_nibble = |result: Result<_, StorageAccessError>| {
+        matches!(
+            result,
+            Err(StorageAccessError::InvalidNibble {
+                position: 2,
+                value: 0x20
+            })
+        )
+    };
+
+    for (parent_path, key) in [(&invalid, &valid), (&valid, &invalid)] {
+        assert!(is_invalid_nibble(
+            db.block_storage_get_versioned(
+                &block_hash,
+                iter::once(parent_path.iter().copied()),
+                key.iter().copied()
+            )
+            .map(|_| ())
+        ));
+        assert!(is_invalid_nibble(
+            db.block_storage_closest_descendant_merkle_value(
+                &block_hash,
+                iter::once(parent_path.iter().copied()),
+                key.iter().copied()
+            )
+            .map(|_| ())
+        ));
+    }
+
+    for (parent_path, key, prefix) in [
+        (&invalid, &valid, &valid),
+        (&valid, &invalid, &valid),
+        (&valid, &valid, &invalid),
+    ] {
+        assert!(is_invalid_nibble(
+            db.block_storage_next_key(
+                &block_hash,
+                iter::once(parent_path.iter().copied()),
+                key.iter().copied(),
+                prefix.iter().copied(),
+                true
+            )
+            .map(|_| ())
+        ));
+    }
+
+    assert!(matches!(
+        db.insert_trie_nodes_versioned(
+            iter::once(InsertTrieNode {
+                merkle_value: Cow::Borrowed(&[1; 32]),
+                partial_key_nibbles: Cow::Borrowed(&invalid),
+                children_merkle_values: array::from_fn(|_| None),
+                storage_value: InsertTrieNodeStorageValue::NoValue,
+            }),
+            TrieEntryVersion::V0
+        ),
+        Err(InsertTrieNodesError::InvalidNibble {
+            position: 2,
+            value: 0x20
+        })
+    ));
+
+    // Nothing has been inserted.
+    assert!(matches!(
+        db.block_storage_get_versioned(&block_hash, iter::empty::<iter::Empty<_>>(), iter::empty()),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+}
+
+#[test]
+fn insert_trie_nodes_validated() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let node = |merkle_value: &'static [u8; 32],
+                child: Option<&'static [u8; 32]>,
+                child_trie: Option<&'static [u8; 32]>| InsertTrieNode {
+        merkle_value: Cow::Borrowed(merkle_value),
+        partial_key_nibbles: Cow::Borrowed(&[]),
+        children_merkle_values: array::from_fn(|n| {
+            child
+                .filter(|_| n == 0)
+                .map(|child| Cow::Borrowed(&child[..]))
+        }),
+        storage_value: match child_trie {
+            Some(root) => InsertTrieNodeStorageValue::Value {
+                value: Cow::Borrowed(&root[..]),
+                references_merkle_value: true,
+            },
+            None => InsertTrieNodeStorageValue::NoValue,
+        },
+    };
+    let num_trie_nodes = || {
+        db.database
+            .lock()
+            .query_row("SELECT COUNT(*) FROM trie_node", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+    };
+
+    // The root of the state of the block, a node referenced by a node of the same batch, and
+    // the root of a child trie.
+    db.insert_trie_nodes_validated(
+        [
+            node(&[1; 32], Some(&[2; 32]), None),
+            node(&[2; 32], None, Some(&[3; 32])),
+            node(&[3; 32], None, None),
+        ]
+        .into_iter(),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+    assert_eq!(num_trie_nodes(), 3);
+
+    // A node referenced by a node already in the database.
+    db.insert_trie_nodes_versioned(
+        iter::once(node(&[4; 32], Some(&[5; 32]), None)),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+    db.insert_trie_nodes_validated(iter::once(node(&[5; 32], None, None)), TrieEntryVersion::V0)
+        .unwrap();
+    assert_eq!(num_trie_nodes(), 5);
+
+    // Nodes that nothing references.
+    match db.insert_trie_nodes_validated(
+        [
+            node(&[6; 32], Some(&[7; 32]), None),
+            node(&[7; 32], None, None),
+            node(&[8; 32], None, None),
+        ]
+        .into_iter(),
+        TrieEntryVersion::V0,
+    ) {
+        Err(InsertTrieNodesError::OrphanTrieNodes { merkle_values }) => {
+            assert_eq!(merkle_values, vec![vec![6; 32], vec![8; 32]])
+        }
+        _ => panic!(),
+    }
+    assert_eq!(num_trie_nodes(), 5);
+
+    // Without validation, the nodes are inserted.
+    db.insert_trie_nodes_versioned(iter::once(node(&[8; 32], None, None)), TrieEntryVersion::V0)
+        .unwrap();
+    assert_eq!(num_trie_nodes(), 6);
+}
+
+#[test]
+fn insert_trie_nodes_chunked() {
+    // No read connection, so that reading from within the iterator uses the same connection as
+    // the insertion.
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        commit_policy: CommitPolicy::EveryNBlocks(NonZeroU64::new(1000).unwrap()),
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Chain of nodes, each node being the first child of the previous one.
+    let merkle_values = (1..=7u8).map(|n| [n; 32]).collect::<Vec<_>>();
+    let nodes = merkle_values
+        .iter()
+        .enumerate()
+        .map(|(n, merkle_value)| InsertTrieNode {
+            merkle_value: Cow::Borrowed(&merkle_value[..]),
+            partial_key_nibbles: Cow::Borrowed(&[]),
+            children_merkle_values: array::from_fn(|child| {
+                merkle_values
+                    .get(n + 1)
+                    .filter(|_| child == 0)
+                    .map(|child| Cow::Borrowed(&child[..]))
+            }),
+            storage_value: InsertTrieNodeStorageValue::NoValue,
+        });
+
+    // Accessing the database from within the iterator must not deadlock, and must observe the
+    // nodes of the previous chunks.
+    let mut missing_seen = Vec::new();
+    let mut progress = Vec::new();
+    db.insert_trie_nodes_chunked(
+        nodes.inspect(|_| {
+            let missing = db
+                .finalized_and_above_missing_trie_nodes_unordered()
+                .unwrap();
+            assert_eq!(missing.len(), 1);
+            missing_seen.push(missing[0].trie_node_hash[0]);
+        }),
+        TrieEntryVersion::V0,
+        NonZeroUsize::new(3).unwrap(),
+        |num_inserted| progress.push(num_inserted),
+    )
+    .unwrap();
+
+    assert_eq!(missing_seen, [1, 1, 1, 4, 4, 4, 7]);
+    assert_eq!(progress, [3, 6, 7]);
+    assert!(db
+        .finalized_and_above_missing_trie_nodes_unordered()
+        .unwrap()
+        .is_empty());
+
+    // Each chunk has been committed, despite the commit policy.
+    assert!(db.database.lock().is_autocommit());
+}
+
+#[test]
+fn purge_shared_trie_nodes() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let node = |merkle_value: &'static [u8; 32],
+                partial_key_nibbles: &'static [u8],
+                children: &[(usize, &'static [u8; 32])],
+                storage_value: Option<(&'static [u8], bool)>| InsertTrieNode {
+        merkle_value: Cow::Borrowed(merkle_value),
+        partial_key_nibbles: Cow::Borrowed(partial_key_nibbles),
+        children_merkle_values: array::from_fn(|n| {
+            children
+                .iter()
+                .find(|(index, _)| *index == n)
+                .map(|(_, child)| Cow::Borrowed(&child[..]))
+        }),
+        storage_value: match storage_value {
+            Some((value, references_merkle_value)) => InsertTrieNodeStorageValue::Value {
+                value: Cow::Borrowed(value),
+                references_merkle_value,
+            },
+            None => InsertTrieNodeStorageValue::NoValue,
+        },
+    };
+
+    // The trie of the block that is kept references the same subtree twice, and a child trie.
+    // The trie of the block that is purged shares nodes with it, references one of its own
+    // subtrees twice, and has its own child trie.
+    db.insert_trie_nodes_versioned(
+        [
+            node(
+                &[1; 32],
+                &[],
+                &[(0, &[2; 32]), (1, &[2; 32]), (2, &[3; 32])],
+                None,
+            ),
+            node(&[2; 32], &[5], &[], Some((b"d", false))),
+            node(&[3; 32], &[6], &[], Some((&[4; 32], true))),
+            node(&[4; 32], &[], &[], Some((b"c", false))),
+            node(
+                &[5; 32],
+                &[],
+                &[
+                    (0, &[2; 32]),
+                    (1, &[6; 32]),
+                    (2, &[7; 32]),
+                    (3, &[3; 32]),
+                    (4, &[6; 32]),
+                ],
+                None,
+            ),
+            node(&[6; 32], &[7], &[], Some((b"x", false))),
+            node(&[7; 32], &[6], &[], Some((&[8; 32], true))),
+            node(&[8; 32], &[], &[], Some((b"ca", false))),
+        ]
+        .into_iter(),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    let block_purged = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[5; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block_purged_hash = header::hash_from_scale_encoded_header(&block_purged);
+    db.insert(
+        &block_purged,
+        false,
+        [&b"foo"[..], &b"bar"[..]].into_iter(),
+        None,
+        None,
+    )
+    .unwrap();
+
+    let block_kept = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block_kept_hash = header::hash_from_scale_encoded_header(&block_kept);
+    db.insert(&block_kept, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    db.set_finalized(&block_kept_hash).unwrap();
+    assert_eq!(
+        db.purge_finality_orphans(None).unwrap(),
+        PurgeReport {
+            purged_blocks: vec![block_purged_hash],
+            deleted_body_rows: 2,
+            deleted_trie_nodes: 4,
+            more_remaining: false,
+        }
+    );
+
+    let remaining_trie_nodes = db
+        .database
+        .lock()
+        .prepare("SELECT hash FROM trie_node ORDER BY hash")
+        .unwrap()
+        .query_map((), |row| row.get::<_, Vec<u8>>(0))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(
+        remaining_trie_nodes,
+        (1..=4u8).map(|n| vec![n; 32]).collect::<Vec<_>>()
+    );
+
+    for key in [[0, 5], [1, 5]] {
+        assert_eq!(
+            db.block_storage_get_versioned(
+                &block_kept_hash,
+                iter::empty::<iter::Empty<_>>(),
+                key.into_iter()
+            )
+            .unwrap(),
+            Some((b"d".to_vec(), TrieEntryVersion::V0))
+        );
+    }
+    assert_eq!(
+        db.block_storage_get_versioned(
+            &block_kept_hash,
+            iter::once([2, 6].into_iter()),
+            iter::empty()
+        )
+        .unwrap(),
+        Some((b"c".to_vec(), TrieEntryVersion::V0))
+    );
+}
+
+#[test]
+fn purge_finality_orphans_limit() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    // Blocks are made different from each other through their extrinsics root.
+    let insert = |parent_hash: &[u8; 32], number: u64, fork: u8, is_new_best: bool| {
+        let block = header::HeaderRef {
+            parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[fork; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+
+    let canonical1 = insert(&genesis_hash, 1, 0, true);
+    let canonical2 = insert(&canonical1, 2, 0, true);
+    let fork1 = insert(&genesis_hash, 1, 1, false);
+    let fork2 = insert(&fork1, 2, 1, false);
+    let other_fork1 = insert(&genesis_hash, 1, 2, false);
+    db.set_finalized(&canonical2).unwrap();
+
+    // The orphans with the highest number are purged first.
+    let report = db
+        .purge_finality_orphans(Some(NonZeroUsize::new(2).unwrap()))
+        .unwrap();
+    assert_eq!(report.purged_blocks.len(), 2);
+    assert_eq!(report.purged_blocks[0], fork2);
+    assert!(report.more_remaining);
+
+    let report = db
+        .purge_finality_orphans(Some(NonZeroUsize::new(2).unwrap()))
+        .unwrap();
+    assert_eq!(report.purged_blocks.len(), 1);
+    assert!(!report.more_remaining);
+
+    let report = db.purge_finality_orphans(None).unwrap();
+    assert!(report.purged_blocks.is_empty());
+    assert!(!report.more_remaining);
+
+    for hash in [fork1, fork2, other_fork1] {
+        assert!(db.block_scale_encoded_header(&hash).unwrap().is_none());
+    }
+    for hash in [genesis_hash, canonical1, canonical2] {
+        assert!(db.block_scale_encoded_header(&hash).unwrap().is_some());
+    }
+}
+
+#[test]
+fn purge_finality_orphans_forks() {
+    for block_number_bytes in [4, 8] {
+        let fixture = FixtureChain::new(block_number_bytes)
+            .with_blocks(6)
+            .with_fork_at(0)
+            .with_fork_at(2)
+            .with_fork_at(2)
+            .with_fork_at(4)
+            .with_storage([(b"foo".to_vec(), vec![1])])
+            .build();
+        let db = &fixture.database;
+
+        let check_purge = |expected: Vec<[u8; 32]>, kept: Vec<[u8; 32]>| {
+            let report = db.purge_finality_orphans(None).unwrap();
+            assert!(!report.more_remaining);
+            // All the blocks share the same storage.
+            assert_eq!(report.deleted_trie_nodes, 0);
+
+            let mut purged = report.purged_blocks.clone();
+            purged.sort();
+            let mut expected_sorted = expected.clone();
+            expected_sorted.sort();
+            assert_eq!(purged, expected_sorted);
+
+            for hash in &expected {
+                assert!(db.block_scale_encoded_header(hash).unwrap().is_none());
+            }
+            for hash in &kept {
+                assert!(db.block_scale_encoded_header(hash).unwrap().is_some());
+                assert_eq!(
+                    db.block_storage_get_bytes(hash, b"foo").unwrap(),
+                    Some((vec![1], TrieEntryVersion::V1))
+                );
+            }
+        };
+
+        // Only the orphans whose number is inferior or equal to the finalized block are
+        // purged. The blocks above them are purged once the finalized block reaches them.
+        db.set_finalized(&fixture.canonical[3]).unwrap();
+        check_purge(
+            fixture.forks[0][..3]
+                .iter()
+                .chain(&fixture.forks[1][..1])
+                .chain(&fixture.forks[2][..1])
+                .copied()
+                .collect(),
+            fixture
+                .canonical
+                .iter()
+                .chain(&fixture.forks[0][3..])
+                .chain(&fixture.forks[1][1..])
+                .chain(&fixture.forks[2][1..])
+                .chain(&fixture.forks[3])
+                .copied()
+                .collect(),
+        );
+
+        db.set_finalized(&fixture.canonical[6]).unwrap();
+        check_purge(
+            fixture.forks[0][3..]
+                .iter()
+                .chain(&fixture.forks[1][1..])
+                .chain(&fixture.forks[2][1..])
+                .chain(&fixture.forks[3])
+                .copied()
+                .collect(),
+            fixture.canonical.clone(),
+        );
+
+        check_purge(Vec::new(), fixture.canonical.clone());
+    }
+}
+
+#[test]
+fn best_chain_reorgs_forks() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(8)
+        .with_fork_at(2)
+        .with_fork_at(5)
+        .with_fork_at(5)
+        .build();
+    let db = &fixture.database;
+
+    let check_best_chain = |best: &[u8; 32]| {
+        let ancestry = fixture.ancestry(best);
+        assert_eq!(db.best_block_hash().unwrap(), *best);
+        for (number, hash) in ancestry.iter().rev().enumerate() {
+            assert_eq!(
+                db.best_block_hash_by_number(u64::try_from(number).unwrap())
+                    .unwrap(),
+                Some(*hash)
+            );
+        }
+        for hash in fixture
+            .canonical
+            .iter()
+            .chain(fixture.forks.concat().iter())
+        {
+            assert_eq!(
+                db.block_info(hash).unwrap().unwrap().is_best_chain,
+                ancestry.contains(hash)
+            );
+        }
+        assert_eq!(
+            db.best_block_hash_by_number(u64::try_from(ancestry.len()).unwrap())
+                .unwrap(),
+            None
+        );
+    };
+
+    check_best_chain(fixture.canonical.last().unwrap());
+
+    // Move the best block between the forks, including to blocks that aren't the head of their
+    // fork, then back to the canonical chain.
+    for best in [
+        fixture.forks[0][5],
+        fixture.forks[1][1],
+        fixture.forks[2][0],
+        fixture.forks[0][0],
+        fixture.canonical[7],
+        fixture.forks[2][2],
+        fixture.canonical[8],
+    ] {
+        db.set_best_block(&best).unwrap();
+        check_best_chain(&best);
+    }
+
+    // Extending a fork with a new best block reports the reorganization.
+    let new_block = header::HeaderRef {
+        parent_hash: &fixture.forks[1][2],
+        number: 9,
+        state_root: &fixture.state_root,
+        extrinsics_root: &[0xff; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let new_block_hash = header::hash_from_scale_encoded_header(&new_block);
+    let outcome = db
+        .insert(&new_block, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    let route = outcome.best_chain_change.unwrap();
+    assert_eq!(route.common_ancestor, (fixture.canonical[5], 5));
+    assert_eq!(
+        route.retracted,
+        vec![
+            (fixture.canonical[8], 8),
+            (fixture.canonical[7], 7),
+            (fixture.canonical[6], 6)
+        ]
+    );
+    assert_eq!(
+        route.enacted,
+        vec![
+            (fixture.forks[1][0], 6),
+            (fixture.forks[1][1], 7),
+            (fixture.forks[1][2], 8),
+            (new_block_hash, 9)
+        ]
+    );
+    assert_eq!(db.best_block_hash().unwrap(), new_block_hash);
+    for (number, hash) in fixture.canonical[..=5]
+        .iter()
+        .chain(&fixture.forks[1])
+        .enumerate()
+    {
+        assert_eq!(
+            db.best_block_hash_by_number(u64::try_from(number).unwrap())
+                .unwrap(),
+            Some(*hash)
+        );
+    }
+}
+
+#[test]
+fn child_trie_storage_access() {
+    let child_trie = [
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+        (b"\x01\x30".to_vec(), vec![0xcc]),
+        (b"\xf0".to_vec(), vec![0xdd]),
+    ];
+    let fixture = FixtureChain::new(4)
+        .with_blocks(2)
+        .with_storage([
+            (b":code".to_vec(), b"runtime".to_vec()),
+            (b"abc".to_vec(), vec![1, 2, 3]),
+            (b"abd".to_vec(), vec![4]),
+        ])
+        .with_child_trie(b"foo", child_trie.clone())
+        .with_child_trie(b"bar", [(b"\x02".to_vec(), vec![0xee])])
+        .build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[2];
+
+    let nibbles = |bytes: &[u8]| {
+        trie::bytes_to_nibbles(bytes.iter().copied())
+            .map(u8::from)
+            .collect::<Vec<_>>()
+    };
+
+    for (child_trie, entries) in [
+        (None, &fixture.main_trie[..]),
+        (Some(&b"foo"[..]), &child_trie[..]),
+    ] {
+        let parent_tries_paths = child_trie.map(Fixture::child_trie_path);
+
+        // The values of the child trie are reached by following the reference found in the
+        // main trie.
+        for (key, value) in entries {
+            assert_eq!(
+                db.block_storage_get_versioned(
+                    &block_hash,
+                    parent_tries_paths.iter().map(|p| p.iter().copied()),
+                    nibbles(key).into_iter()
+                )
+                .unwrap(),
+                Some((value.clone(), TrieEntryVersion::V1))
+            );
+        }
+
+        // Compare the closest descendant with the expected result for the keys of every node
+        // of the trie, all their prefixes, and all keys of up to two nibbles.
+        let candidates = fixture
+            .trie_nodes_keys(child_trie)
+            .flat_map(|key| (0..=key.len()).map(move |n| key[..n].to_vec()))
+            .chain((0..16).map(|a| vec![a]))
+            .chain((0..16).flat_map(|a| (0..16).map(move |b| vec![a, b])))
+            .collect::<Vec<_>>();
+
+        for key in &candidates {
+            let obtained = db
+                .block_storage_closest_descendant_merkle_value(
+                    &block_hash,
+                    parent_tries_paths.iter().map(|p| p.iter().copied()),
+                    key.iter().copied(),
+                )
+                .unwrap();
+            assert_eq!(
+                obtained,
+                fixture.closest_descendant_merkle_value(child_trie, key),
+                "child_trie={child_trie:?} key={key:?}"
+            );
+        }
+    }
+
+    // Child tries that don't exist.
+    for child_trie in [&b"baz"[..], b"fo", b"fooo"] {
+        let path = Fixture::child_trie_path(child_trie);
+        assert_eq!(
+            db.block_storage_closest_descendant_merkle_value(
+                &block_hash,
+                iter::once(path.iter().copied()),
+                iter::empty(),
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(
+            db.block_storage_next_key(
+                &block_hash,
+                iter::once(path.iter().copied()),
+                iter::empty(),
+                iter::empty(),
+                false
+            )
+            .unwrap(),
+            None
+        );
+    }
+
+    // The entry that references a child trie isn't itself a child trie path if followed with
+    // more nibbles.
+    let path = Fixture::child_trie_path(b"bar");
+    assert_eq!(
+        db.block_storage_next_key(
+            &block_hash,
+            iter::once(path.iter().copied()),
+            iter::empty(),
+            iter::empty(),
+            false
+        )
+        .unwrap(),
+        Some(nibbles(b"\x02"))
+    );
+}
+
+#[test]
+fn insert_conflicting_trie_nodes() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_hash = db.finalized_block_hash().unwrap();
+
+    let node = |child: &'static [u8; 32], value: &'static [u8]| InsertTrieNode {
+        merkle_value: Cow::Borrowed(&[1; 32]),
+        partial_key_nibbles: Cow::Borrowed(&[]),
+        children_merkle_values: array::from_fn(|n| (n == 3).then_some(Cow::Borrowed(&child[..]))),
+        storage_value: InsertTrieNodeStorageValue::Value {
+            value: Cow::Borrowed(value),
+            references_merkle_value: false,
+        },
+    };
+
+    db.insert_trie_nodes_versioned(iter::once(node(&[2; 32], b"foo")), TrieEntryVersion::V0)
+        .unwrap();
+
+    // Inserting an identical node is a no-op.
+    db.insert_trie_nodes_versioned(iter::once(node(&[2; 32], b"foo")), TrieEntryVersion::V1)
+        .unwrap();
+
+    // Nodes with the same Merkle value but a different storage value or children are refused.
+    for conflicting in [node(&[2; 32], b"bar"), node(&[3; 32], b"foo")] {
+        match db.insert_trie_nodes_versioned(iter::once(conflicting), TrieEntryVersion::V0) {
+            Err(InsertTrieNodesError::Corrupted(CorruptedError::ConflictingTrieNode {
+                merkle_value,
+            })) => assert_eq!(merkle_value, vec![1; 32]),
+            _ => panic!(),
+        }
+    }
+
+    // The original node is left untouched.
+    assert_eq!(
+        db.block_storage_get_versioned(&block_hash, iter::empty::<iter::Empty<_>>(), iter::empty())
+            .unwrap(),
+        Some((b"foo".to_vec(), TrieEntryVersion::V0))
+    );
+    assert_eq!(
+        db.database
+            .lock()
+            .query_row("SELECT COUNT(*) FROM trie_node_parent", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap(),
+        1
+    );
+}
+
+#[test]
+fn storage_closest_descendant_merkle_value_partial() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![1; 32]
+    );
+
+    assert!(matches!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1].into_iter(),
+        ),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+
+    assert!(matches!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1].into_iter(),
+        ),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+
+    assert!(matches!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 2, 3, 4, 5].into_iter(),
+        ),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+
+    db.insert_trie_nodes_versioned(
+        [InsertTrieNode {
+            merkle_value: Cow::Borrowed(&[1; 32]),
+            partial_key_nibbles: Cow::Borrowed(&[1, 1]),
+            children_merkle_values: [
+                None,
+                Some(Cow::Borrowed(&[2; 32])),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            ],
+            storage_value: InsertTrieNodeStorageValue::Value {
+                value: Cow::Borrowed(b"hello"),
+                references_merkle_value: false,
+            },
+        }]
+        .into_iter(),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![1; 32]
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![1; 32]
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 1].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![2; 32]
+    );
+
+    assert!(matches!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 1, 1].into_iter(),
+        ),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 2].into_iter(),
+        )
+        .unwrap(),
+        None
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [5].into_iter(),
+        )
+        .unwrap(),
+        None
+    );
+
+    db.insert_trie_nodes_versioned(
+        [InsertTrieNode {
+            merkle_value: Cow::Borrowed(&[2; 32]),
+            partial_key_nibbles: Cow::Borrowed(&[1, 1]),
+            children_merkle_values: [
+                None, None, None, None, None, None, None, None, None, None, None, None, None, None,
+                None, None,
+            ],
+            storage_value: InsertTrieNodeStorageValue::Value {
+                value: Cow::Borrowed(b"world"),
+                references_merkle_value: false,
+            },
+        }]
+        .into_iter(),
+        TrieEntryVersion::V0,
+    )
+    .unwrap();
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![1; 32]
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 1].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![2; 32]
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 1, 1].into_iter(),
+        )
+        .unwrap()
+        .unwrap(),
+        vec![2; 32]
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 2].into_iter(),
+        )
+        .unwrap(),
+        None
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 1, 2].into_iter(),
+        )
+        .unwrap(),
+        None
+    );
+
+    assert_eq!(
+        db.block_storage_closest_descendant_merkle_value(
+            &db.block_hash_by_number(0).unwrap().next().unwrap(),
+            iter::empty::<iter::Empty<_>>(),
+            [1, 1, 1, 1, 1, 1, 1, 1].into_iter(),
+        )
+        .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn busy_database() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_config = |busy_timeout| Config {
+        busy_timeout,
+        read_connections: 2,
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    };
+
+    let DatabaseOpen::Empty(empty_db) = open(open_config(Duration::ZERO)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+
+    // A second connection grabs the write lock of the database.
+    let other_connection = rusqlite::Connection::open(&path).unwrap();
+    other_connection.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+    assert!(matches!(
+        db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None),
+        Err(InsertError::Corrupted(CorruptedError::Busy))
+    ));
+
+    // Reading isn't blocked by another writer.
+    assert_eq!(db.finalized_block_hash().unwrap(), genesis_hash);
+
+    // The operation succeeds once the lock is released.
+    other_connection.execute_batch("COMMIT").unwrap();
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    drop(db);
+
+    // Reopen the database with a non-zero timeout, and release the lock in the background.
+    let DatabaseOpen::Open(db) = open(open_config(Duration::from_secs(30))).unwrap() else {
+        panic!()
+    };
+
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    let block2 = header::HeaderRef {
+        parent_hash: &block1_hash,
+        number: 2,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+
+    other_connection.execute_batch("BEGIN IMMEDIATE").unwrap();
+    let unlock_thread = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(200));
+        other_connection.execute_batch("COMMIT").unwrap();
+    });
+
+    db.insert(&block2, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(
+        db.best_block_hash().unwrap(),
+        header::hash_from_scale_encoded_header(&block2)
+    );
+
+    unlock_thread.join().unwrap();
+}
+
+#[test]
+fn read_connections() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    // Reading doesn't require the main connection.
+    {
+        let _main_connection = db.database.lock();
+        assert_eq!(db.best_block_hash().unwrap(), genesis_hash);
+        assert!(db
+            .block_scale_encoded_header(&genesis_hash)
+            .unwrap()
+            .is_some());
+    }
+
+    // Writes are visible to the read-only connections as soon as they have returned.
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(db.best_block_hash().unwrap(), block1_hash);
+    assert_eq!(
+        db.block_hash_by_number(1).unwrap().collect::<Vec<_>>(),
+        vec![block1_hash]
+    );
+
+    db.set_finalized(&block1_hash).unwrap();
+    assert_eq!(db.finalized_block_hash().unwrap(), block1_hash);
+}
+
+#[test]
+fn grouped_commits() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        commit_policy: CommitPolicy::EveryNBlocks(NonZeroU64::new(3).unwrap()),
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Another connection only sees the blocks that have been committed.
+    let other_connection = rusqlite::Connection::open(&path).unwrap();
+    let num_committed_blocks = || {
+        other_connection
+            .query_row("SELECT COUNT(*) FROM blocks", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+    };
+    assert_eq!(num_committed_blocks(), 1);
+
+    let mut parent_hash = db.finalized_block_hash().unwrap();
+    for number in 1..=7 {
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+
+        // Uncommitted blocks are visible through the database object.
+        assert_eq!(db.best_block_hash().unwrap(), parent_hash);
+        assert_eq!(
+            num_committed_blocks(),
+            1 + i64::try_from(number / 3 * 3).unwrap()
+        );
+    }
+
+    // A failed modification doesn't affect the ones that are waiting to be committed.
+    assert!(matches!(
+        db.set_finalized(&[0xff; 32]),
+        Err(super::SetFinalizedError::UnknownBlock)
+    ));
+    assert_eq!(db.best_block_hash().unwrap(), parent_hash);
+
+    db.flush().unwrap();
+    assert_eq!(num_committed_blocks(), 8);
+
+    // Uncommitted modifications are committed when the database is closed.
+    db.set_finalized(&parent_hash).unwrap();
+    drop(db);
+    assert_eq!(
+        other_connection
+            .query_row(
+                "SELECT value_number FROM meta WHERE key = 'finalized'",
+                (),
+                |row| row.get::<_, i64>(0)
+            )
+            .unwrap(),
+        7
+    );
+}
+
+#[test]
+fn insert_database_full() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    // Prevent the database from growing.
+    let set_max_page_count = |max_page_count: i64| {
+        db.database
+            .lock()
+            .query_row(
+                &format!("PRAGMA max_page_count = {max_page_count}"),
+                (),
+                |_| Ok(()),
+            )
+            .unwrap()
+    };
+    let page_count = db
+        .database
+        .lock()
+        .query_row("PRAGMA page_count", (), |row| row.get::<_, i64>(0))
+        .unwrap();
+    set_max_page_count(page_count);
+
+    let block = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block_hash = header::hash_from_scale_encoded_header(&block);
+    let body = [vec![0xaa; 512 * 1024]];
+
+    assert!(matches!(
+        db.insert(&block, true, body.iter(), None, None),
+        Err(InsertError::Corrupted(_))
+    ));
+
+    // The failed insertion has been entirely rolled back.
+    assert!(db
+        .block_scale_encoded_header(&block_hash)
+        .unwrap()
+        .is_none());
+    assert_eq!(db.best_block_hash().unwrap(), genesis_hash);
+
+    // The database is still usable.
+    set_max_page_count(i64::from(u32::MAX));
+    db.insert(&block, true, body.iter(), None, None).unwrap();
+    assert_eq!(db.best_block_hash().unwrap(), block_hash);
+}
+
+#[test]
+fn insert_duplicate_compares_body() {
+    let fixture = FixtureChain::new(4).with_blocks(1).build();
+    let db = &fixture.database;
+
+    let block = header::HeaderRef {
+        parent_hash: &fixture.canonical[1],
+        number: 2,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block_hash = header::hash_from_scale_encoded_header(&block);
+    let body = [vec![1, 2, 3], vec![4], vec![]];
+    db.insert(&block, true, body.iter(), None, None).unwrap();
+
+    let insert_again = |body: &[Vec<u8>]| match db.insert(&block, true, body.iter(), None, None) {
+        Err(InsertError::Duplicate {
+            body_stored,
+            body_matches,
+        }) => (body_stored, body_matches),
+        _ => panic!(),
+    };
+
+    assert_eq!(insert_again(&body), (true, true));
+    assert_eq!(insert_again(&body[..2]), (true, false));
+    assert_eq!(insert_again(&[]), (true, false));
+    assert_eq!(
+        insert_again(&[vec![1, 2, 3], vec![4], vec![], vec![5]]),
+        (true, false)
+    );
+    assert_eq!(
+        insert_again(&[vec![1, 2, 3], vec![5], vec![]]),
+        (true, false)
+    );
+
+    // Nothing has been modified by the failed insertions.
+    assert_eq!(
+        db.block_extrinsics(&block_hash)
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>(),
+        body
+    );
+
+    db.set_finalized(&block_hash).unwrap();
+    db.prune_bodies_below(3).unwrap();
+    assert_eq!(insert_again(&body), (false, false));
+}
+
+#[test]
+fn insert_decoded() {
+    let fixture = FixtureChain::new(4).with_blocks(1).build();
+    let db = &fixture.database;
+
+    let header = header::HeaderRef {
+        parent_hash: &fixture.canonical[1],
+        number: 2,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    };
+    let scale_encoded_header = header.scale_encoding_vec(4);
+    let block_hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
+
+    db.insert_decoded(
+        header,
+        &scale_encoded_header,
+        true,
+        iter::empty::<Vec<u8>>(),
+        None,
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(db.best_block_hash().unwrap(), block_hash);
+    assert_eq!(
+        db.block_scale_encoded_header(&block_hash).unwrap().unwrap(),
+        scale_encoded_header
+    );
+    assert!(matches!(
+        db.insert(
+            &scale_encoded_header,
+            true,
+            iter::empty::<Vec<u8>>(),
+            None,
+            None
+        ),
+        Err(InsertError::Duplicate { .. })
+    ));
+}
+
+#[test]
+fn insert_number_out_of_range() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(8)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let block = header::HeaderRef {
+        parent_hash: &db.finalized_block_hash().unwrap(),
+        number: 1 << 63,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(8);
+
+    assert!(matches!(
+        db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None),
+        Err(InsertError::NumberOutOfRange { number }) if number == 1 << 63
+    ));
+    assert!(db
+        .block_scale_encoded_header(&header::hash_from_scale_encoded_header(&block))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn insert_number_mismatch() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 500,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let finalized_hash = db.finalized_block_hash().unwrap();
+
+    for number in [10, 500, 502] {
+        let block = header::HeaderRef {
+            parent_hash: &finalized_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+
+        assert!(matches!(
+            db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None),
+            Err(InsertError::NumberMismatch { parent: 500, child }) if child == number
+        ));
+        assert!(db
+            .block_scale_encoded_header(&header::hash_from_scale_encoded_header(&block))
+            .unwrap()
+            .is_none());
+    }
+
+    let block = header::HeaderRef {
+        parent_hash: &finalized_hash,
+        number: 501,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block, false, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+}
+
+#[test]
+fn backup_while_inserting() {
+    let directory = tempfile::tempdir().unwrap();
+    let source_path = directory.path().join("source.sqlite");
+    let backup_path = directory.path().join("backup.sqlite");
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ty: ConfigTy::Disk {
+            path: &source_path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Insert enough blocks with large bodies for the backup to require multiple steps.
+    let mut best_hash = db.finalized_block_hash().unwrap();
+    let insert_block = |parent_hash: &[u8; 32], number: u64| {
+        let block = header::HeaderRef {
+            parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(
+            &block,
+            true,
+            iter::once(vec![number as u8; 4000]),
+            None,
+            None,
+        )
+        .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+    for number in 1..=200 {
+        best_hash = insert_block(&best_hash, number);
+    }
+    let best_hash_before_backup = best_hash;
+
+    let mut num_progress_reports = 0;
+    let mut next_number = 201;
+    db.backup_to(&backup_path, |progress| {
+        num_progress_reports += 1;
+        if progress.pages_remaining != 0 {
+            // Keep inserting blocks in the middle of the backup.
+            best_hash = insert_block(&best_hash, next_number);
+            next_number += 1;
+        }
+    })
+    .unwrap();
+    assert!(num_progress_reports >= 2);
+    assert_ne!(db.best_block_hash().unwrap(), best_hash_before_backup);
+
+    // The backup contains the state of the database at the time when the backup started.
+    let DatabaseOpen::Open(backup) = open(Config {
+        read_connections: 2,
+        ty: ConfigTy::Disk {
+            path: &backup_path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    assert_eq!(backup.best_block_hash().unwrap(), best_hash_before_backup);
+    let mut hash = best_hash_before_backup;
+    for number in (1..=200).rev() {
+        assert_eq!(
+            backup.best_block_hash_by_number(number).unwrap(),
+            Some(hash)
+        );
+        assert_eq!(
+            backup
+                .block_extrinsics(&hash)
+                .unwrap()
+                .unwrap()
+                .collect::<Vec<_>>(),
+            vec![vec![number as u8; 4000]]
+        );
+        let Some(ParentOf::Block(parent_hash)) = backup.block_parent(&hash).unwrap() else {
+            panic!()
+        };
+        hash = parent_hash;
+    }
+    assert_eq!(hash, db.finalized_block_hash().unwrap());
+    assert!(backup.best_block_hash_by_number(201).unwrap().is_none());
+}
+
+#[test]
+fn backup_destination_locked() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("backup.sqlite");
+    let fixture = FixtureChain::new(4).build();
+
+    // Another connection holds a lock on the destination for the entire duration of the backup.
+    let other = rusqlite::Connection::open(&path).unwrap();
+    other.execute_batch("BEGIN EXCLUSIVE").unwrap();
+
+    assert!(matches!(
+        fixture.database.backup_to(&path, |_| {}),
+        Err(BackupError::Busy)
+    ));
+
+    other.execute_batch("COMMIT").unwrap();
+    fixture.database.backup_to(&path, |_| {}).unwrap();
+}
+
+#[cfg(feature = "database-sqlite-async")]
+#[test]
+fn async_database() {
+    use super::async_wrapper;
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let db = async_wrapper::AsyncDatabase::new(async_wrapper::Config {
+        database: db,
+        max_pending_operations: core::num::NonZeroUsize::new(1).unwrap(),
+        spawn: async_wrapper::spawn_thread,
+    });
+
+    futures_executor::block_on(async move {
+        let genesis_hash = db.finalized_block_hash().await.unwrap();
+
+        // Insert a chain of blocks while many reads are queued at the same time, in order to
+        // exercise the back-pressure.
+        let mut parent_hash = genesis_hash;
+        for number in 1..=20 {
+            let header = header::HeaderRef {
+                parent_hash: &parent_hash,
+                number,
+                state_root: &[1; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            let hash = header::hash_from_scale_encoded_header(&header);
+
+            let (inserted, headers) = futures_util::future::join(
+                db.insert(header.clone(), true, vec![vec![number as u8]], None, None),
+                futures_util::future::join_all(
+                    (0..8).map(|_| db.block_scale_encoded_header(parent_hash)),
+                ),
+            )
+            .await;
+            inserted.unwrap();
+            assert!(headers.into_iter().all(|h| h.unwrap().is_some()));
+
+            assert_eq!(
+                db.block_scale_encoded_header(hash).await.unwrap(),
+                Some(header)
+            );
+            parent_hash = hash;
+        }
+
+        assert_eq!(db.best_block_hash().await.unwrap(), parent_hash);
+        assert_eq!(
+            db.block_extrinsics(parent_hash).await.unwrap(),
+            Some(vec![vec![20]])
+        );
+
+        db.set_finalized(parent_hash).await.unwrap();
+        assert_eq!(db.finalized_block_hash().await.unwrap(), parent_hash);
+
+        db.shutdown().await;
+    });
+}
+
+#[test]
+fn with_block_extrinsics() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    let body = vec![vec![1, 2, 3], vec![], vec![4; 4096]];
+    db.insert(&block1, true, body.iter(), None, None).unwrap();
+
+    let mut extrinsics = Vec::new();
+    assert_eq!(
+        db.with_block_extrinsics(&block1_hash, |extrinsic| {
+            extrinsics.push(extrinsic.to_vec());
+            core::ops::ControlFlow::Continue(())
+        })
+        .unwrap(),
+        Some(())
+    );
+    assert_eq!(extrinsics, body);
+
+    // Stopping early.
+    let mut num_calls = 0;
+    db.with_block_extrinsics(&block1_hash, |_| {
+        num_calls += 1;
+        core::ops::ControlFlow::Break(())
+    })
+    .unwrap()
+    .unwrap();
+    assert_eq!(num_calls, 1);
+
+    // Blocks with an empty body and unknown blocks are distinguished.
+    assert_eq!(
+        db.with_block_extrinsics(&genesis_hash, |_| panic!())
+            .unwrap(),
+        Some(())
+    );
+    assert_eq!(
+        db.with_block_extrinsics(&[0xff; 32], |_| panic!()).unwrap(),
+        None
+    );
+    assert!(db.block_extrinsics(&[0xff; 32]).unwrap().is_none());
+}
+
+#[test]
+fn runtimes_cache() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    assert!(db.runtime_by_spec_version(1).unwrap().is_none());
+
+    db.store_runtime(1, b"runtime 1", HeapPages::new(2048))
+        .unwrap();
+    db.store_runtime(2, b"runtime 2", HeapPages::new(64))
+        .unwrap();
+    let runtime = db.runtime_by_spec_version(1).unwrap().unwrap();
+    assert_eq!(runtime.code, b"runtime 1");
+    assert_eq!(
+        runtime.code_hash[..],
+        *blake2_rfc::blake2b::blake2b(32, &[], b"runtime 1").as_bytes()
+    );
+    assert_eq!(runtime.heap_pages, HeapPages::new(2048));
+
+    // Storing a runtime with the same specification version overwrites the previous one.
+    db.store_runtime(1, b"runtime 1 bis", HeapPages::new(1024))
+        .unwrap();
+    let runtime = db.runtime_by_spec_version(1).unwrap().unwrap();
+    assert_eq!(runtime.code, b"runtime 1 bis");
+    assert_eq!(runtime.heap_pages, HeapPages::new(1024));
+    assert_eq!(
+        db.runtime_by_spec_version(2).unwrap().unwrap().code,
+        b"runtime 2"
+    );
+
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), Some(2), None)
+        .unwrap();
+
+    assert_eq!(db.runtime_spec_version_at(&block1_hash).unwrap(), Some(2));
+    assert_eq!(db.runtime_spec_version_at(&genesis_hash).unwrap(), None);
+    assert_eq!(db.runtime_spec_version_at(&[0xff; 32]).unwrap(), None);
+}
+
+#[test]
+fn block_events() {
+    for store_events in [false, true] {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            read_connections: 2,
+            store_events,
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        let db = empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &[1; 32],
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap();
+        let genesis_hash = db.finalized_block_hash().unwrap();
+
+        let insert = |parent_hash: &[u8; 32], number: u64, fork: u8, is_new_best: bool| {
+            let block = header::HeaderRef {
+                parent_hash,
+                number,
+                state_root: &[1; 32],
+                extrinsics_root: &[fork; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            db.insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, None)
+                .unwrap();
+            header::hash_from_scale_encoded_header(&block)
+        };
+        let block1 = insert(&genesis_hash, 1, 0, true);
+        let block2 = insert(&block1, 2, 0, true);
+        let fork1 = insert(&genesis_hash, 1, 1, false);
+
+        for (hash, events) in [
+            (&block1, b"events 1"),
+            (&block2, b"events 2"),
+            (&fork1, b"events f"),
+        ] {
+            db.set_block_events(hash, events).unwrap();
+        }
+
+        // Nothing is stored or even checked if storing events is disabled.
+        if !store_events {
+            assert!(db.block_events(&block1).unwrap().is_none());
+            db.set_block_events(&[0xff; 32], b"events").unwrap();
+            continue;
+        }
+
+        assert!(matches!(
+            db.set_block_events(&[0xff; 32], b"events"),
+            Err(SetBlockEventsError::UnknownBlock)
+        ));
+        assert_eq!(db.block_events(&block1).unwrap().unwrap(), b"events 1");
+        assert!(db.block_events(&genesis_hash).unwrap().is_none());
+
+        db.set_block_events(&block1, b"events 1 bis").unwrap();
+        assert_eq!(db.block_events(&block1).unwrap().unwrap(), b"events 1 bis");
+
+        // Events are removed together with the body and when purging a block.
+        db.set_finalized(&block2).unwrap();
+        db.prune_bodies_below(2).unwrap();
+        db.purge_finality_orphans(None).unwrap();
+        assert!(db.block_events(&block1).unwrap().is_none());
+        assert!(db.block_events(&fork1).unwrap().is_none());
+        assert_eq!(db.block_events(&block2).unwrap().unwrap(), b"events 2");
+    }
+}
+
+#[test]
+fn block_insert_metadata() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let insert = |number: u64, fork: u8, is_new_best: bool, meta: Option<&InsertMeta>| {
+        let block = header::HeaderRef {
+            parent_hash: &genesis_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[fork; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, meta)
+            .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+
+    let meta = InsertMeta {
+        inserted_at_unix_ms: 1_700_000_000_000,
+        source: "12D3KooWExample".to_owned(),
+    };
+    let block1 = insert(1, 0, true, Some(&meta));
+    let fork1 = insert(1, 1, false, Some(&meta));
+    let without_meta = insert(1, 2, false, None);
+
+    assert_eq!(
+        db.block_insert_metadata(&block1).unwrap(),
+        Some(meta.clone())
+    );
+    assert_eq!(db.block_insert_metadata(&without_meta).unwrap(), None);
+    assert_eq!(db.block_insert_metadata(&genesis_hash).unwrap(), None);
+    assert_eq!(db.block_insert_metadata(&[0xff; 32]).unwrap(), None);
+
+    // Purging a block removes its metadata.
+    db.set_finalized(&block1).unwrap();
+    db.purge_finality_orphans(None).unwrap();
+    assert_eq!(db.block_insert_metadata(&fork1).unwrap(), None);
+    assert_eq!(
+        db.database
+            .lock()
+            .query_row("SELECT COUNT(*) FROM blocks_meta", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap(),
+        1
+    );
+
+    // An insertion time that can't be stored is rejected, and the block isn't inserted.
+    let block = header::HeaderRef {
+        parent_hash: &block1,
+        number: 2,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let invalid_meta = InsertMeta {
+        inserted_at_unix_ms: u64::MAX,
+        source: String::new(),
+    };
+    assert!(matches!(
+        db.insert(
+            &block,
+            true,
+            iter::empty::<Vec<u8>>(),
+            None,
+            Some(&invalid_meta)
+        ),
+        Err(InsertError::InvalidMeta {
+            inserted_at_unix_ms: u64::MAX
+        })
+    ));
+    assert_eq!(
+        db.block_scale_encoded_header(&header::hash_from_scale_encoded_header(&block))
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn prune_bodies() {
+    for body_retention in [None, Some(1)] {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            read_connections: 2,
+            body_retention,
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        let db = empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &[1; 32],
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                [&b"genesis"[..]].into_iter(),
+                None,
+            )
+            .unwrap();
+
+        // Blocks #1 to #5, each with two extrinsics, plus a fork at #1.
+        let mut hashes = vec![db.finalized_block_hash().unwrap()];
+        for number in 1..=5 {
+            let block = header::HeaderRef {
+                parent_hash: hashes.last().unwrap(),
+                number,
+                state_root: &[1; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4);
+            db.insert(&block, true, [vec![1], vec![2]].iter(), None, None)
+                .unwrap();
+            hashes.push(header::hash_from_scale_encoded_header(&block));
+        }
+        let fork = header::HeaderRef {
+            parent_hash: &hashes[0],
+            number: 1,
+            state_root: &[1; 32],
+            extrinsics_root: &[1; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&fork, false, [vec![1]].iter(), None, None)
+            .unwrap();
+        let fork_hash = header::hash_from_scale_encoded_header(&fork);
+
+        db.set_finalized(&hashes[3]).unwrap();
+
+        match body_retention {
+            // The bodies of the blocks above the finalized block are kept.
+            None => assert_eq!(db.prune_bodies_below(10).unwrap(), 7),
+            // The bodies of #0 and #1 have been removed by `set_finalized`.
+            Some(_) => assert_eq!(db.prune_bodies_below(10).unwrap(), 4),
+        }
+        assert_eq!(db.prune_bodies_below(10).unwrap(), 0);
+
+        for hash in &hashes[..=3] {
+            assert!(db.block_scale_encoded_header(hash).unwrap().is_some());
+            assert!(matches!(
+                db.block_extrinsics(hash),
+                Err(BlockExtrinsicsError::BodyPruned)
+            ));
+        }
+        for hash in &hashes[4..] {
+            assert_eq!(db.block_extrinsics(hash).unwrap().unwrap().len(), 2);
+        }
+        assert_eq!(db.block_extrinsics(&fork_hash).unwrap().unwrap().len(), 1);
+
+        assert!(matches!(
+            db.export_blocks(0..=5, Vec::new()),
+            Err(ExportBlocksError::BodyPruned { number: 0 })
+        ));
+        db.export_blocks(4..=5, Vec::new()).unwrap();
+    }
+}
+
+#[test]
+fn read_snapshot_is_consistent() {
+    let directory = tempfile::tempdir().unwrap();
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ty: ConfigTy::Disk {
+            path: &directory.path().join("db.sqlite"),
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let insert = |fork: u8, is_new_best: bool| {
+        let block = header::HeaderRef {
+            parent_hash: &genesis_hash,
+            number: 1,
+            state_root: &[1; 32],
+            extrinsics_root: &[fork; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, is_new_best, iter::once(vec![fork]), None, None)
+            .unwrap();
+        (
+            block.clone(),
+            header::hash_from_scale_encoded_header(&block),
+        )
+    };
+    let (_, block1_hash) = insert(0, true);
+    let (fork1, fork1_hash) = insert(1, false);
+
+    let outcome = db
+        .with_read_snapshot(|snapshot| {
+            assert_eq!(snapshot.finalized_block_hash().unwrap(), genesis_hash);
+
+            // Finalize the best block and purge the fork from a different thread while the
+            // snapshot is alive.
+            std::thread::scope(|scope| {
+                scope
+                    .spawn(|| {
+                        db.set_finalized(&block1_hash).unwrap();
+                        db.purge_finality_orphans(None).unwrap();
+                    })
+                    .join()
+                    .unwrap();
+            });
+
+            // The modifications aren't visible through the snapshot.
+            assert_eq!(snapshot.finalized_block_hash().unwrap(), genesis_hash);
+            assert_eq!(snapshot.best_block_hash().unwrap(), block1_hash);
+            assert_eq!(
+                snapshot.block_scale_encoded_header(&fork1_hash).unwrap(),
+                Some(fork1.clone())
+            );
+            assert_eq!(
+                snapshot.block_parent(&fork1_hash).unwrap(),
+                Some(ParentOf::Block(genesis_hash))
+            );
+            assert_eq!(
+                snapshot
+                    .block_extrinsics(&fork1_hash)
+                    .unwrap()
+                    .unwrap()
+                    .collect::<Vec<_>>(),
+                vec![vec![1]]
+            );
+            assert_eq!(snapshot.block_hash_by_number(1).unwrap().len(), 2);
+            assert_eq!(
+                snapshot.best_block_hash_by_number(1).unwrap(),
+                Some(block1_hash)
+            );
+            12
+        })
+        .unwrap();
+    assert_eq!(outcome, 12);
+
+    assert_eq!(db.finalized_block_hash().unwrap(), block1_hash);
+    assert_eq!(db.block_scale_encoded_header(&fork1_hash).unwrap(), None);
+}
+
+#[test]
+#[should_panic(expected = "with_read_snapshot")]
+fn read_snapshot_reentrancy_panics() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Without the guard, this would deadlock, as the database only has one connection.
+    let _ = db.with_read_snapshot(|_| db.best_block_hash());
+}
+
+#[test]
+fn subscribe_events() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let mut receiver = db.subscribe();
+    assert_eq!(receiver.try_next(), None);
+
+    let insert = |parent_hash: &[u8; 32], number: u64, fork: u8, is_new_best: bool| {
+        let block = header::HeaderRef {
+            parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[fork; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, is_new_best, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+
+    // Inserting a block that isn't the new best doesn't generate any event.
+    let block1 = insert(&genesis_hash, 1, 0, true);
+    let fork1 = insert(&genesis_hash, 1, 1, false);
+    let block2 = insert(&block1, 2, 0, true);
+    assert_eq!(
+        receiver.try_next(),
+        Some(Event::NewBest {
+            hash: block1,
+            number: 1
+        })
+    );
+    assert_eq!(
+        receiver.try_next(),
+        Some(Event::NewBest {
+            hash: block2,
+            number: 2
+        })
+    );
+    assert_eq!(receiver.try_next(), None);
+
+    // The event is emitted after the modification is visible.
+    db.set_finalized(&block2).unwrap();
+    let event = receiver.try_next().unwrap();
+    assert_eq!(db.finalized_block_hash().unwrap(), block2);
+    assert_eq!(
+        event,
+        Event::Finalized {
+            hash: block2,
+            number: 2,
+            retracted: vec![fork1]
+        }
+    );
+
+    // Receivers that don't pull events lose the oldest ones.
+    let mut parent = block2;
+    for number in 3..=302 {
+        parent = insert(&parent, number, 0, true);
+    }
+    assert_eq!(receiver.take_lagged(), 44);
+    assert_eq!(receiver.take_lagged(), 0);
+    let mut last_number = 0;
+    while let Some(event) = receiver.try_next() {
+        let Event::NewBest { number, .. } = event else {
+            panic!()
+        };
+        last_number = number;
+    }
+    assert_eq!(last_number, 302);
+
+    // The receiver is notified when the database is destroyed.
+    drop(db);
+    assert_eq!(futures_executor::block_on(receiver.next()), None);
+}
+
+#[test]
+fn subscribe_events_delayed_until_commit() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        commit_policy: CommitPolicy::EveryNBlocks(NonZeroU64::new(3).unwrap()),
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    db.flush().unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let mut receiver = db.subscribe();
+
+    let insert = |parent_hash: &[u8; 32], number: u64| {
+        let block = header::HeaderRef {
+            parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+
+    // Nothing is emitted as long as the modifications aren't committed.
+    let block1 = insert(&genesis_hash, 1);
+    db.set_finalized(&block1).unwrap();
+    assert_eq!(receiver.try_next(), None);
+
+    // A failed modification doesn't queue any event.
+    assert!(db.set_best_block(&[0xff; 32]).is_err());
+
+    // Committing emits the queued events in order.
+    db.flush().unwrap();
+    assert_eq!(
+        receiver.try_next(),
+        Some(Event::NewBest {
+            hash: block1,
+            number: 1
+        })
+    );
+    assert_eq!(
+        receiver.try_next(),
+        Some(Event::Finalized {
+            hash: block1,
+            number: 1,
+            retracted: Vec::new()
+        })
+    );
+    assert_eq!(receiver.try_next(), None);
+
+    // Reaching the commit policy emits the events as well.
+    let block2 = insert(&block1, 2);
+    let block3 = insert(&block2, 3);
+    assert_eq!(receiver.try_next(), None);
+    let block4 = insert(&block3, 4);
+    for (hash, number) in [(block2, 2), (block3, 3), (block4, 4)] {
+        assert_eq!(receiver.try_next(), Some(Event::NewBest { hash, number }));
+    }
+    assert_eq!(receiver.try_next(), None);
+}
+
+#[test]
+fn operation_metrics() {
+    let reported = Arc::new(Mutex::new(Vec::<OperationMetrics>::new()));
+
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        on_operation: Some({
+            let reported = reported.clone();
+            Arc::new(move |metrics| reported.lock().push(metrics))
+        }),
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    reported.lock().clear();
+
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(
+        &block1,
+        true,
+        [vec![1], vec![2], vec![3]].into_iter(),
+        None,
+        None,
+    )
+    .unwrap();
+    db.set_finalized(&header::hash_from_scale_encoded_header(&block1))
+        .unwrap();
+    let _ = db.block_storage_get_versioned(
+        &genesis_hash,
+        iter::empty::<iter::Empty<u8>>(),
+        iter::empty(),
+    );
+
+    let reported = reported
+        .lock()
+        .iter()
+        .map(|metrics| (metrics.kind, metrics.rows))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        reported,
+        vec![
+            (OperationKind::Insert, Some(3)),
+            (OperationKind::SetFinalized, Some(1)),
+            (OperationKind::StorageGet, None),
+        ]
+    );
+}
+
+#[test]
+fn interrupt_long_query() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let interrupt_handle = db.interrupt_handle();
+
+    std::thread::scope(|scope| {
+        // Run a query that never ends, and interrupt it from a different thread while the
+        // database is locked.
+        let query = scope.spawn(|| {
+            db.database
+                .lock()
+                .query_row(
+                    "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT COUNT(*) FROM c",
+                    (),
+                    |row| row.get::<_, i64>(0),
+                )
+                .map_err(super::sqlite_err("interrupt_long_query"))
+        });
+
+        while !query.is_finished() {
+            interrupt_handle.interrupt();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(matches!(
+            query.join().unwrap(),
+            Err(CorruptedError::Interrupted)
+        ));
+    });
+
+    // Operations started afterwards aren't affected.
+    db.best_block_hash().unwrap();
+    assert!(matches!(
+        StorageAccessError::from(CorruptedError::Interrupted),
+        StorageAccessError::Interrupted
+    ));
+}
+
+#[test]
+fn large_numbers_migration() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_config = || Config {
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    };
+
+    // Numbers that don't fit in a signed 64 bits integer.
+    let authorities = [header::GrandpaAuthority {
+        public_key: [3; 32],
+        weight: NonZeroU64::new(u64::MAX).unwrap(),
+    }];
+    let set_id = 1 << 63;
+
+    let check = |db: &super::SqliteFullDatabase| {
+        let chain_information = db
+            .to_chain_information(&db.finalized_block_hash().unwrap())
+            .unwrap();
+        let chain_information::ChainInformationFinalityRef::Grandpa {
+            after_finalized_block_authorities_set_id,
+            finalized_triggered_authorities,
+            finalized_scheduled_change,
+        } = chain_information.as_ref().finality
+        else {
+            panic!()
+        };
+        assert_eq!(after_finalized_block_authorities_set_id, set_id);
+        assert_eq!(finalized_triggered_authorities, &authorities[..]);
+        assert_eq!(finalized_scheduled_change, Some((5, &authorities[..])));
+    };
+
+    let DatabaseOpen::Empty(empty_db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 1,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Grandpa {
+                    after_finalized_block_authorities_set_id: set_id,
+                    finalized_triggered_authorities: &authorities,
+                    finalized_scheduled_change: Some((5, &authorities)),
+                },
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    check(&db);
+    drop(db);
+
+    // Revert the database to the previous schema, where numbers were stored by reinterpreting
+    // their bits as signed integers.
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    revert_schema_to_v7(&connection);
+    connection
+        .execute_batch(
+            r#"
+UPDATE meta SET value_number = -9223372036854775808, value_blob = NULL
+WHERE key = 'grandpa_authorities_set_id';
+
+CREATE TABLE triggered(idx INTEGER NOT NULL PRIMARY KEY, public_key BLOB NOT NULL, weight INTEGER NOT NULL);
+INSERT INTO triggered SELECT idx, public_key, -1 FROM grandpa_triggered_authorities;
+DROP TABLE grandpa_triggered_authorities;
+ALTER TABLE triggered RENAME TO grandpa_triggered_authorities;
+
+CREATE TABLE scheduled(idx INTEGER NOT NULL PRIMARY KEY, public_key BLOB NOT NULL, weight INTEGER NOT NULL);
+INSERT INTO scheduled SELECT idx, public_key, -1 FROM grandpa_scheduled_authorities;
+DROP TABLE grandpa_scheduled_authorities;
+ALTER TABLE scheduled RENAME TO grandpa_scheduled_authorities;
+
+PRAGMA user_version = 5;
+            "#,
+        )
+        .unwrap();
+
+    let DatabaseOpen::Open(db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+    check(&db);
+}
+
+#[test]
+fn internal_error_context() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Simulate a damaged database.
+    db.database
+        .lock()
+        .execute_batch("DROP TABLE blocks_body")
+        .unwrap();
+
+    let block = header::HeaderRef {
+        parent_hash: &db.finalized_block_hash().unwrap(),
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+
+    let err = db
+        .insert(&block, true, [vec![1, 2, 3]].into_iter(), None, None)
+        .unwrap_err();
+    let InsertError::Corrupted(CorruptedError::Internal(internal)) = &err else {
+        panic!("{err:?}")
+    };
+    assert_eq!(internal.operation(), "insert");
+
+    // The chain of sources skips the transparent `InsertError::Corrupted` and ends with the
+    // error returned by SQLite.
+    let source = std::error::Error::source(&err).unwrap();
+    assert!(source.is::<InternalError>());
+    let source = source.source().unwrap();
+    assert!(source.is::<rusqlite::Error>());
+    assert!(err.to_string().contains("blocks_body"));
+}
+
+#[test]
+fn write_stats() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        body_retention: Some(0),
+        storage_diff_retention: Some(1),
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let mut parent_hash = db.finalized_block_hash().unwrap();
+    for number in 1..=3 {
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        let outcome = db
+            .insert(
+                &block,
+                true,
+                [vec![1, 2, 3], vec![4, 5]].into_iter(),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            outcome.stats,
+            InsertStats {
+                header_bytes: block.len(),
+                body_items: 2,
+                body_bytes: 5,
+            }
+        );
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+        db.insert_storage_diff(&parent_hash, [(&b"foo"[..], Some(&[1][..]))].into_iter())
+            .unwrap();
+    }
+
+    let nodes = || {
+        [
+            InsertTrieNode {
+                merkle_value: Cow::Owned(vec![1; 32]),
+                partial_key_nibbles: Cow::Owned(vec![]),
+                children_merkle_values: array::from_fn(|n| {
+                    if n == 0 {
+                        Some(Cow::Owned(vec![2; 32]))
+                    } else {
+                        None
+                    }
+                }),
+                storage_value: InsertTrieNodeStorageValue::NoValue,
+            },
+            InsertTrieNode {
+                merkle_value: Cow::Owned(vec![2; 32]),
+                partial_key_nibbles: Cow::Owned(vec![3, 4]),
+                children_merkle_values: array::from_fn(|_| None),
+                storage_value: InsertTrieNodeStorageValue::Value {
+                    value: Cow::Owned(vec![0xaa; 10]),
+                    references_merkle_value: false,
+                },
+            },
+        ]
+        .into_iter()
+    };
+    assert_eq!(
+        db.insert_trie_nodes_versioned(nodes(), TrieEntryVersion::V0)
+            .unwrap(),
+        InsertTrieNodesStats {
+            inserted_nodes: 2,
+            ignored_nodes: 0,
+            storage_value_bytes: 10,
+        }
+    );
+    assert_eq!(
+        db.insert_trie_nodes_versioned(nodes(), TrieEntryVersion::V0)
+            .unwrap(),
+        InsertTrieNodesStats {
+            inserted_nodes: 0,
+            ignored_nodes: 2,
+            storage_value_bytes: 0,
+        }
+    );
+
+    // With a body retention of 0, finalizing block 3 prunes the bodies of blocks 1 and 2.
+    // With a storage diff retention of 1, it prunes the storage diff of block 1.
+    assert_eq!(
+        db.set_finalized(&parent_hash).unwrap(),
+        SetFinalizedStats {
+            newly_finalized_blocks: 3,
+            pruned_body_rows: 4,
+            pruned_storage_diffs: 1,
+            babe_epoch_transition: None,
+        }
+    );
+    assert_eq!(
+        db.set_finalized(&parent_hash).unwrap(),
+        SetFinalizedStats {
+            newly_finalized_blocks: 0,
+            pruned_body_rows: 0,
+            pruned_storage_diffs: 0,
+            babe_epoch_transition: None,
+        }
+    );
+}
+
+#[test]
+fn clone_in_memory_is_independent() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    let clone = db.clone_in_memory().unwrap();
+    assert_eq!(clone.best_block_hash().unwrap(), block1_hash);
+    assert_eq!(clone.finalized_block_hash().unwrap(), genesis_hash);
+
+    // Modifying the clone doesn't affect the original.
+    clone.set_finalized(&block1_hash).unwrap();
+    assert_eq!(clone.finalized_block_hash().unwrap(), block1_hash);
+    assert_eq!(db.finalized_block_hash().unwrap(), genesis_hash);
+
+    // Modifying the original doesn't affect the clone.
+    let block2 = header::HeaderRef {
+        parent_hash: &block1_hash,
+        number: 2,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block2_hash = header::hash_from_scale_encoded_header(&block2);
+    db.insert(&block2, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(db.best_block_hash().unwrap(), block2_hash);
+    assert_eq!(clone.best_block_hash().unwrap(), block1_hash);
+    assert!(clone
+        .block_scale_encoded_header(&block2_hash)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn import_substrate_export() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        read_connections: 2,
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let genesis = header::HeaderRef {
+        number: 0,
+        extrinsics_root: &[0; 32],
+        parent_hash: &[0; 32],
+        state_root: &[1; 32],
+        digest: header::DigestRef::empty(),
+    };
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: genesis.clone(),
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Build an export containing the genesis block followed with 10 children, in the format
+    // produced by `substrate export-blocks --binary`.
+    let mut headers = vec![genesis.scale_encoding_vec(4)];
+    for number in 1..=10 {
+        let parent_hash = header::hash_from_scale_encoded_header(headers.last().unwrap());
+        headers.push(
+            header::HeaderRef {
+                parent_hash: &parent_hash,
+                number,
+                state_root: &[1; 32],
+                extrinsics_root: &[0; 32],
+                digest: header::DigestRef::empty(),
+            }
+            .scale_encoding_vec(4),
+        );
+    }
+    let mut export = u64::try_from(headers.len()).unwrap().to_le_bytes().to_vec();
+    for (number, header) in headers.iter().enumerate() {
+        export.extend_from_slice(header);
+        // Body made of one extrinsic, itself prefixed with its length.
+        export.extend_from_slice(&[1 << 2, 2 << 2, 0xaa, u8::try_from(number).unwrap()]);
+        // GrandPa justification.
+        export.extend_from_slice(&[1, 1 << 2]);
+        export.extend_from_slice(b"FRNK");
+        export.extend_from_slice(&[1 << 2, 0xff]);
+    }
+
+    let blocks = import::SubstrateExportReader::new(&export[..])
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(blocks.len(), 11);
+    assert_eq!(blocks[3].scale_encoded_header, headers[3]);
+    assert_eq!(blocks[3].body, vec![vec![2 << 2, 0xaa, 3]]);
+    assert_eq!(blocks[3].justifications, vec![(*b"FRNK", vec![0xff])]);
+
+    // Import only the first half, as if the import had been interrupted.
+    let report = import::import_from_substrate_export(&db, blocks.iter().take(6).cloned()).unwrap();
+    assert_eq!(report.imported_blocks, 5);
+    assert_eq!(report.skipped_blocks, 1);
+    assert_eq!(import::import_cursor(&db).unwrap(), Some(5));
+
+    // Resuming the import skips the blocks that have already been imported.
+    let report = import::import_from_substrate_export(&db, blocks.iter().cloned()).unwrap();
+    assert_eq!(report.imported_blocks, 5);
+    assert_eq!(report.skipped_blocks, 6);
+    assert_eq!(report.last_imported_number, Some(10));
+
+    let best_hash = header::hash_from_scale_encoded_header(&headers[10]);
+    assert_eq!(db.best_block_hash().unwrap(), best_hash);
+    assert_eq!(db.block_hash_by_number(7).unwrap().count(), 1);
+    assert_eq!(
+        db.block_extrinsics(&best_hash)
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec![vec![2 << 2, 0xaa, 10]]
+    );
+
+    // A block whose parent is unknown is refused.
+    let orphan = header::HeaderRef {
+        parent_hash: &[0xee; 32],
+        number: 11,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    assert!(matches!(
+        import::import_from_substrate_export(
+            &db,
+            iter::once(import::ExportedBlock {
+                scale_encoded_header: orphan,
+                body: Vec::new(),
+                justifications: Vec::new(),
+            })
+        ),
+        Err(import::ImportError::MissingParent { number: 11 })
+    ));
+}
+
+#[test]
+fn hot_queries_use_indices() {
+    let fixture = FixtureChain::new(4).with_blocks(3).with_fork_at(1).build();
+    let connection = fixture.database.database.lock();
+
+    let query_plan = |sql: &str| {
+        let mut statement = connection
+            .prepare(&format!("EXPLAIN QUERY PLAN {sql}"))
+            .unwrap();
+        let mut rows = statement.raw_query();
+        let mut plan = Vec::new();
+        while let Some(row) = rows.next().unwrap() {
+            plan.push(row.get::<_, String>(3).unwrap());
+        }
+        plan
+    };
+
+    // The table filled by `PURGED_TRIE_NODES_QUERY` must exist for its plan to be determined.
+    connection
+        .execute_batch(super::PURGED_TRIE_NODE_TABLE)
+        .unwrap();
+
+    let tables = [
+        "blocks",
+        "blocks_body",
+        "extrinsics",
+        "trie_node",
+        "trie_node_parent",
+        "trie_node_storage",
+    ];
+    for (sql, expected) in [
+        (
+            super::BEST_BLOCK_HASH_BY_NUMBER_QUERY,
+            &["SEARCH blocks USING COVERING INDEX blocks_by_best (chain_id=? AND number=? AND is_best_chain=?)"][..],
+        ),
+        (
+            super::BLOCKS_BY_NUMBER_QUERY,
+            &["SEARCH blocks USING COVERING INDEX blocks_by_best (chain_id=? AND number=?)"],
+        ),
+        (
+            super::FINALITY_ORPHANS_QUERY,
+            &["SEARCH blocks USING COVERING INDEX blocks_by_best (chain_id=? AND number<?)"],
+        ),
+        (
+            super::BEST_CHAIN_BODIES_IN_RANGE_QUERY,
+            &["SEARCH blocks USING COVERING INDEX blocks_by_best (chain_id=? AND number>? AND number<?)"],
+        ),
+        (
+            super::BLOCK_PARENT_QUERY,
+            &["SEARCH blocks USING INDEX sqlite_autoindex_blocks_1 (hash=?)"],
+        ),
+        (
+            // Reachability of the trie nodes.
+            super::PURGED_TRIE_NODES_QUERY,
+            &[
+                "SEARCH blocks USING COVERING INDEX blocks_by_state_trie_root_hash (state_trie_root_hash=?)",
+                "SEARCH trie_node_parent USING PRIMARY KEY (child_hash=?)",
+                "SEARCH trie_node_storage USING INDEX trie_node_storage_by_trie_root_ref (trie_root_ref=?)",
+            ],
+        ),
+    ] {
+        let plan = query_plan(sql);
+        for expected in expected {
+            assert!(plan.iter().any(|line| line == expected), "{sql}: {plan:?}");
+        }
+        // Scanning the common table expressions of the statement is fine, but not scanning a
+        // table of the database.
+        assert!(
+            !plan.iter().any(|line| line
+                .strip_prefix("SCAN ")
+                .and_then(|scanned| scanned.split(' ').next())
+                .map_or(false, |scanned| tables.contains(&scanned))),
+            "{sql}: {plan:?}"
+        );
+    }
+}
+
+#[test]
+fn bulk_load() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_db = || {
+        open(Config {
+            read_connections: 2,
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap()
+    };
+
+    let DatabaseOpen::Empty(empty_db) = open_db() else {
+        panic!()
+    };
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    let num_indices = |db: &super::SqliteFullDatabase| {
+        db.database
+            .lock()
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND sql IS NOT NULL",
+                (),
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+    };
+    let synchronous = |db: &super::SqliteFullDatabase| {
+        db.database
+            .lock()
+            .query_row("PRAGMA synchronous", (), |row| row.get::<_, i64>(0))
+            .unwrap()
+    };
+    let initial_num_indices = num_indices(&db);
+    let initial_synchronous = synchronous(&db);
+
+    let mut blocks = Vec::new();
+    let mut parent_hash = db.finalized_block_hash().unwrap();
+    for number in 1..=10 {
+        let scale_encoded_header = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        parent_hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
+        blocks.push(import::ExportedBlock {
+            scale_encoded_header,
+            body: vec![vec![u8::try_from(number).unwrap()]],
+            justifications: Vec::new(),
+        });
+    }
+
+    let guard = db.begin_bulk_load().unwrap();
+    assert_eq!(num_indices(&db), initial_num_indices - 4);
+    assert_eq!(synchronous(&db), 0);
+    assert!(matches!(
+        db.begin_bulk_load(),
+        Err(BeginBulkLoadError::AlreadyActive)
+    ));
+    assert_eq!(num_indices(&db), initial_num_indices - 4);
+    let report = guard.insert_many(blocks.iter().take(6).cloned()).unwrap();
+    assert_eq!(report.imported_blocks, 6);
+    guard.finish().unwrap();
+    assert_eq!(num_indices(&db), initial_num_indices);
+    assert_eq!(synchronous(&db), initial_synchronous);
+
+    let best_hash = header::hash_from_scale_encoded_header(&blocks[5].scale_encoded_header);
+    assert_eq!(db.best_block_hash().unwrap(), best_hash);
+    assert_eq!(
+        db.block_extrinsics(&best_hash)
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec![vec![6]]
+    );
+
+    // The database goes back to normal in case of a panic.
+    let outcome = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _guard = db.begin_bulk_load().unwrap();
+        panic!()
+    }));
+    assert!(outcome.is_err());
+    assert_eq!(num_indices(&db), initial_num_indices);
+    assert_eq!(synchronous(&db), initial_synchronous);
+
+    // Simulate a crash during a bulk load. The indices are rebuilt when the database is
+    // opened again.
+    let guard = db.begin_bulk_load().unwrap();
+    guard.insert_many(blocks.iter().cloned()).unwrap();
+    core::mem::forget(guard);
+    drop(db);
+
+    let DatabaseOpen::Open(db) = open_db() else {
+        panic!()
+    };
+    assert_eq!(num_indices(&db), initial_num_indices);
+    assert_eq!(
+        db.best_block_hash().unwrap(),
+        header::hash_from_scale_encoded_header(&blocks[9].scale_encoded_header)
+    );
+}
+
+#[test]
+fn export_then_import_blocks() {
+    let open_db = || {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            read_connections: 2,
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &[1; 32],
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap()
+    };
+
+    let source = open_db();
+    let destination = open_db();
+
+    let mut headers = Vec::new();
+    let mut parent_hash = source.finalized_block_hash().unwrap();
+    for number in 1..=10 {
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        source
+            .insert(&block, true, iter::once(vec![number as u8; 3]), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+        headers.push(block);
+    }
+
+    // The destination already contains some of the blocks.
+    for block in &headers[..3] {
+        let hash = header::hash_from_scale_encoded_header(block);
+        let body = source.block_extrinsics(&hash).unwrap().unwrap();
+        destination.insert(block, true, body, None, None).unwrap();
+    }
+
+    let mut export = Vec::new();
+    assert_eq!(source.export_blocks(1..=10, &mut export).unwrap(), 10);
+
+    let report = destination.import_blocks(&export[..]).unwrap();
+    assert_eq!(report.imported_blocks, 7);
+    assert_eq!(report.skipped_blocks, 3);
+    assert_eq!(
+        destination.best_block_hash().unwrap(),
+        source.best_block_hash().unwrap()
+    );
+    for block in &headers {
+        let hash = header::hash_from_scale_encoded_header(block);
+        assert_eq!(
+            destination
+                .block_scale_encoded_header(&hash)
+                .unwrap()
+                .unwrap(),
+            *block
+        );
+        assert_eq!(
+            destination
+                .block_extrinsics(&hash)
+                .unwrap()
+                .unwrap()
+                .collect::<Vec<_>>(),
+            source
+                .block_extrinsics(&hash)
+                .unwrap()
+                .unwrap()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    // Exports of an unknown version are refused.
+    export[8] = 2;
+    assert!(matches!(
+        destination.import_blocks(&export[..]),
+        Err(ImportBlocksError::UnsupportedVersion(2))
+    ));
+}
+
+#[test]
+fn export_finalized_state() {
+    let child_trie = [
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+    ];
+    let (child_trie_root, child_trie_nodes) = build_trie(&child_trie, |_| false);
+
+    let main_trie = [
+        (b":code".to_vec(), b"runtime".to_vec()),
+        (b"abc".to_vec(), vec![1, 2, 3]),
+        (
+            b":child_storage:default:foo".to_vec(),
+            child_trie_root.to_vec(),
+        ),
+    ];
+    let (main_trie_root, main_trie_nodes) = build_trie(&main_trie, |key| {
+        key.starts_with(b":child_storage:default:")
+    });
+
+    let open_db = |state_root: &[u8; 32]| {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            read_connections: 2,
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root,
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap()
+    };
+
+    let db = open_db(&main_trie_root);
+
+    // The storage isn't in the database yet.
+    let mut output = Vec::new();
+    assert!(matches!(
+        db.export_finalized_state(&mut output),
+        Err(ExportStateError::Storage(
+            StorageAccessError::IncompleteStorage
+        ))
+    ));
+    assert!(output.is_empty());
+
+    db.insert_trie_nodes_versioned(main_trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+    db.insert_trie_nodes_versioned(child_trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+
+    db.export_finalized_state(&mut output).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&output).unwrap(),
+        serde_json::json!({
+            "top": {
+                "0x3a636f6465": "0x72756e74696d65",
+                "0x616263": "0x010203",
+            },
+            "childrenDefault": {
+                "0x666f6f": {
+                    "0x01": "0xaa",
+                    "0x0102": "0xbb",
+                },
+            },
+        })
+    );
+}
+
+#[test]
+fn initialize_from_raw_state() {
+    let child_trie = vec![
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+    ];
+    let main_trie = vec![
+        (b":code".to_vec(), vec![0x5a; 64]),
+        (b"abc".to_vec(), vec![1, 2, 3]),
+        (b"abd".to_vec(), vec![4]),
+    ];
+
+    // Calculate the expected state root independently.
+    let (child_trie_root, _) = build_trie(&child_trie, |_| false);
+    let mut main_trie_with_child = main_trie.clone();
+    main_trie_with_child.push((
+        b":child_storage:default:foo".to_vec(),
+        child_trie_root.to_vec(),
+    ));
+    let (state_root, _) = build_trie(&main_trie_with_child, |_| false);
+
+    let open_empty = || {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            read_connections: 2,
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+        empty_db
+    };
+
+    let chain_information = |state_root| chain_information::ChainInformationRef {
+        finalized_block_header: header::HeaderRef {
+            number: 0,
+            extrinsics_root: &[0; 32],
+            parent_hash: &[0; 32],
+            state_root,
+            digest: header::DigestRef::empty(),
+        },
+        consensus: chain_information::ChainInformationConsensusRef::Unknown,
+        finality: chain_information::ChainInformationFinalityRef::Outsourced,
+    };
+
+    // A wrong state root is detected.
+    assert!(matches!(
+        open_empty().initialize_from_raw_state(
+            chain_information(&[0xff; 32]),
+            iter::empty(),
+            None,
+            main_trie.clone(),
+            [(b"foo".to_vec(), child_trie.clone())],
+            trie::TrieEntryVersion::V0,
+            |_| {},
+        ),
+        Err(InitializeFromRawStateError::StateRootMismatch { calculated, .. })
+            if calculated == state_root
+    ));
+
+    let mut last_progress = None;
+    let db = open_empty()
+        .initialize_from_raw_state(
+            chain_information(&state_root),
+            iter::empty(),
+            None,
+            main_trie.clone(),
+            [(b"foo".to_vec(), child_trie.clone())],
+            trie::TrieEntryVersion::V0,
+            |progress| last_progress = Some(progress),
+        )
+        .unwrap();
+    let last_progress = last_progress.unwrap();
+    assert_eq!(
+        last_progress.trie_nodes_inserted,
+        last_progress.trie_nodes_total
+    );
+
+    let finalized_hash = db.finalized_block_hash().unwrap();
+    assert_eq!(
+        db.block_storage_get_versioned(
+            &finalized_hash,
+            iter::empty::<iter::Empty<_>>(),
+            trie::bytes_to_nibbles(b"abd".iter().copied()).map(u8::from),
+        )
+        .unwrap(),
+        Some((vec![4], TrieEntryVersion::V0))
+    );
+
+    // Exporting the state gives back the original entries.
+    let mut output = Vec::new();
+    db.export_finalized_state(&mut output).unwrap();
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&output).unwrap(),
+        serde_json::json!({
+            "top": {
+                "0x3a636f6465": format!("0x{}", "5a".repeat(64)),
+                "0x616263": "0x010203",
+                "0x616264": "0x04",
+            },
+            "childrenDefault": {
+                "0x666f6f": {
+                    "0x01": "0xaa",
+                    "0x0102": "0xbb",
+                },
+            },
+        })
+    );
+}
+
+#[test]
+fn statement_cache_stats() {
+    let open_database = |statement_cache_capacity| {
+        let DatabaseOpen::Empty(empty_db) = open(Config {
+            statement_cache_capacity,
+            ..memory_config(4)
+        })
+        .unwrap() else {
+            panic!()
+        };
+
+        let (state_root, trie_nodes) = build_trie(
+            &[
+                (b"abc".to_vec(), vec![1]),
+                (b"abd".to_vec(), vec![2]),
+                (b"b".to_vec(), vec![3]),
+            ],
+            |_| false,
+        );
+        let db = empty_db
+            .initialize(
+                chain_information::ChainInformationRef {
+                    finalized_block_header: header::HeaderRef {
+                        number: 0,
+                        extrinsics_root: &[0; 32],
+                        parent_hash: &[0; 32],
+                        state_root: &state_root,
+                        digest: header::DigestRef::empty(),
+                    },
+                    consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                    finality: chain_information::ChainInformationFinalityRef::Outsourced,
+                },
+                iter::empty(),
+                None,
+            )
+            .unwrap();
+        db.insert_trie_nodes_versioned(trie_nodes.into_iter(), TrieEntryVersion::V0)
+            .unwrap();
+        db
+    };
+
+    let run_workload = |db: &super::SqliteFullDatabase| {
+        let finalized_hash = db.finalized_block_hash().unwrap();
+        assert_eq!(db.best_block_hash().unwrap(), finalized_hash);
+        assert_eq!(
+            db.block_storage_get_versioned(
+                &finalized_hash,
+                iter::empty::<iter::Empty<_>>(),
+                trie::bytes_to_nibbles(b"abd".iter().copied()).map(u8::from),
+            )
+            .unwrap(),
+            Some((vec![2], TrieEntryVersion::V0))
+        );
+        assert!(db
+            .block_storage_next_key(
+                &finalized_hash,
+                iter::empty::<iter::Empty<_>>(),
+                trie::bytes_to_nibbles(b"abd".iter().copied()).map(u8::from),
+                iter::empty(),
+                false,
+            )
+            .unwrap()
+            .is_some());
+        assert!(db
+            .block_storage_closest_descendant_merkle_value(
+                &finalized_hash,
+                iter::empty::<iter::Empty<_>>(),
+                iter::empty(),
+            )
+            .unwrap()
+            .is_some());
+    };
+
+    let total = |db: &super::SqliteFullDatabase| {
+        db.statement_cache_stats()
+            .values()
+            .fold((0, 0), |(hits, misses), stats| {
+                (hits + stats.hits, misses + stats.misses)
+            })
+    };
+
+    // With a large enough cache, every statement is prepared only once.
+    let db = open_database(128);
+    run_workload(&db);
+    let (hits_before, misses_before) = total(&db);
+    assert!(misses_before >= 4);
+    run_workload(&db);
+    let (hits_after, misses_after) = total(&db);
+    assert_eq!(misses_after, misses_before);
+    assert!(hits_after >= hits_before + 4);
+    assert!(db
+        .statement_cache_stats()
+        .values()
+        .all(|stats| stats.misses == 1));
+
+    // With a cache that can only contain a single statement, the statements keep being evicted
+    // and prepared again.
+    let db = open_database(1);
+    run_workload(&db);
+    let (_, misses_before) = total(&db);
+    run_workload(&db);
+    let (_, misses_after) = total(&db);
+    assert!(misses_after >= misses_before + 4);
+}
+
+#[test]
+fn salvage_damaged_database() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+    let output_path = directory.path().join("salvaged.sqlite");
+
+    let open_db = |path: &std::path::Path| {
+        open(Config {
+            ty: ConfigTy::Disk {
+                path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap()
+    };
+
+    let (state_root, trie_nodes) = build_trie(
+        &[
+            (b"abc".to_vec(), vec![1, 2, 3]),
+            (b"abd".to_vec(), vec![4]),
+            (b"b".to_vec(), vec![5]),
+        ],
+        |_| false,
+    );
+
+    let DatabaseOpen::Empty(empty_db) = open_db(&path) else {
+        panic!()
+    };
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &header::extrinsics_root(&[] as &[Vec<u8>]),
+                    parent_hash: &[0; 32],
+                    state_root: &state_root,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    db.insert_trie_nodes_versioned(trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    let mut blocks = vec![genesis_hash];
+    for number in 1..=3 {
+        let body = vec![vec![u8::try_from(number).unwrap(); 4]];
+        let scale_encoded_header = header::HeaderRef {
+            parent_hash: blocks.last().unwrap(),
+            number,
+            state_root: &state_root,
+            extrinsics_root: &header::extrinsics_root(&body),
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&scale_encoded_header, true, body.iter(), None, None)
+            .unwrap();
+        blocks.push(header::hash_from_scale_encoded_header(
+            &scale_encoded_header,
+        ));
+    }
+    drop(db);
+
+    // Damage the database: the body of block 2 no longer matches its header, the header of
+    // block 3 can't be decoded anymore, and one of the storage values no longer matches its
+    // trie node.
+    let num_trie_nodes = {
+        let database = rusqlite::Connection::open(&path).unwrap();
+        database
+            .execute(
+                "UPDATE extrinsics SET bytes = X'ff' WHERE hash IN (SELECT extrinsic_hash FROM blocks_body WHERE hash = ?)",
+                (&blocks[2][..],),
+            )
+            .unwrap();
+        database
+            .execute(
+                "UPDATE blocks SET header = X'00' WHERE hash = ?",
+                (&blocks[3][..],),
+            )
+            .unwrap();
+        database
+            .execute(
+                "UPDATE trie_node_storage SET value = X'06' WHERE value = X'04'",
+                (),
+            )
+            .unwrap();
+        database
+            .query_row("SELECT COUNT(*) FROM trie_node", (), |row| {
+                row.get::<_, u64>(0)
+            })
+            .unwrap()
+    };
+
+    let report = super::salvage(&path, &output_path, 4).unwrap();
+    assert_eq!(
+        report,
+        super::SalvageReport {
+            blocks_copied: 3,
+            blocks_dropped: 1,
+            bodies_dropped: 1,
+            trie_nodes_copied: num_trie_nodes - 1,
+            trie_nodes_dropped: 1,
+            runtimes_dropped: 0,
+            chains_dropped: Vec::new(),
+            unreadable_ranges: 0,
+        }
+    );
+
+    // The output is never overwritten.
+    assert!(matches!(
+        super::salvage(&path, &output_path, 4),
+        Err(super::SalvageError::OutputExists)
+    ));
+
+    let DatabaseOpen::Open(db) = open_db(&output_path) else {
+        panic!()
+    };
+    assert_eq!(db.finalized_block_hash().unwrap(), genesis_hash);
+    // The best block has been lost, and the finalized block is used instead.
+    assert_eq!(db.best_block_hash().unwrap(), genesis_hash);
+    assert_eq!(
+        db.block_extrinsics(&blocks[1])
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec![vec![1; 4]]
+    );
+    assert!(matches!(
+        db.block_extrinsics(&blocks[2]),
+        Err(BlockExtrinsicsError::BodyPruned)
+    ));
+    assert!(db.block_extrinsics(&blocks[3]).unwrap().is_none());
+
+    assert_eq!(
+        db.block_storage_get_versioned(
+            &blocks[2],
+            iter::empty::<iter::Empty<_>>(),
+            trie::bytes_to_nibbles(b"abc".iter().copied()).map(u8::from),
+        )
+        .unwrap(),
+        Some((vec![1, 2, 3], TrieEntryVersion::V0))
+    );
+    assert!(matches!(
+        db.block_storage_get_versioned(
+            &blocks[2],
+            iter::empty::<iter::Empty<_>>(),
+            trie::bytes_to_nibbles(b"abd".iter().copied()).map(u8::from),
+        ),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+}
+
+#[test]
+fn interrupted_initialization_discarded() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_db = || {
+        open(Config {
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+        .unwrap()
+    };
+
+    let (state_root, trie_nodes) = build_trie(
+        &[(b"abc".to_vec(), vec![1, 2, 3]), (b"abd".to_vec(), vec![4])],
+        |_| false,
+    );
+    let chain_information = chain_information::ChainInformationRef {
+        finalized_block_header: header::HeaderRef {
+            number: 0,
+            extrinsics_root: &[0; 32],
+            parent_hash: &[0; 32],
+            state_root: &state_root,
+            digest: header::DigestRef::empty(),
+        },
+        consensus: chain_information::ChainInformationConsensusRef::Unknown,
+        finality: chain_information::ChainInformationFinalityRef::Outsourced,
+    };
+
+    // The process is killed after the chain information has been inserted, but before the
+    // trie nodes have been.
+    let DatabaseOpen::Empty(empty_db) = open_db() else {
+        panic!()
+    };
+    assert!(!empty_db.discarded_incomplete_initialization());
+    let db = empty_db
+        .initialize_incomplete(chain_information.clone(), iter::empty(), None)
+        .unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    drop(db);
+
+    let DatabaseOpen::Empty(empty_db) = open_db() else {
+        panic!()
+    };
+    assert!(empty_db.discarded_incomplete_initialization());
+
+    // The content has been removed, and the initialization can start over.
+    let db = empty_db
+        .initialize_incomplete(chain_information.clone(), iter::empty(), None)
+        .unwrap();
+    assert_eq!(
+        db.block_hash_by_number(0).unwrap().collect::<Vec<_>>(),
+        vec![genesis_hash]
+    );
+    db.insert_trie_nodes_versioned(trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+    db.finish_initialization().unwrap();
+    drop(db);
+
+    let DatabaseOpen::Open(db) = open_db() else {
+        panic!()
+    };
+    assert_eq!(db.finalized_block_hash().unwrap(), genesis_hash);
+    assert_eq!(
+        db.block_storage_get_versioned(
+            &genesis_hash,
+            iter::empty::<iter::Empty<_>>(),
+            trie::bytes_to_nibbles(b"abd".iter().copied()).map(u8::from)
+        )
+        .unwrap(),
+        Some((vec![4], TrieEntryVersion::V0))
+    );
+}
+
+#[test]
+fn reset_and_wipe() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(4)
+        .with_fork_at(1)
+        .with_storage([(b"abc".to_vec(), vec![1, 2, 3]), (b"abd".to_vec(), vec![4])])
+        .with_child_trie(b"foo", [(b"\x01".to_vec(), vec![0xaa])])
+        .build();
+    let db = &fixture.database;
+
+    let count_trie_nodes = || {
+        db.database
+            .lock()
+            .query_row("SELECT COUNT(*) FROM trie_node", (), |row| {
+                row.get::<_, i64>(0)
+            })
+            .unwrap()
+    };
+    assert!(count_trie_nodes() > 0);
+
+    // The new finalized block is far away from the blocks of the database, and its storage
+    // is entirely different.
+    let (state_root, trie_nodes) = build_trie(&[(b"xyz".to_vec(), vec![9; 40])], |_| false);
+    let new_finalized = header::HeaderRef {
+        parent_hash: &[0xee; 32],
+        number: 1000,
+        state_root: &state_root,
+        extrinsics_root: &header::extrinsics_root(&[] as &[Vec<u8>]),
+        digest: header::DigestRef::empty(),
+    };
+    let new_finalized_hash = new_finalized.hash(4);
+    db.reset_and_wipe(
+        chain_information::ChainInformationRef {
+            finalized_block_header: new_finalized,
+            consensus: chain_information::ChainInformationConsensusRef::Unknown,
+            finality: chain_information::ChainInformationFinalityRef::Outsourced,
+        },
+        iter::empty(),
+        None,
+    )
+    .unwrap();
+
+    for block in fixture
+        .canonical
+        .iter()
+        .chain(fixture.forks.iter().flatten())
+    {
+        assert!(db.block_scale_encoded_header(block).unwrap().is_none());
+    }
+    assert_eq!(count_trie_nodes(), 0);
+    assert_eq!(db.finalized_block_hash().unwrap(), new_finalized_hash);
+    assert_eq!(db.best_block_hash().unwrap(), new_finalized_hash);
+
+    // The storage of the new finalized block can then be downloaded.
+    db.insert_trie_nodes_versioned(trie_nodes.into_iter(), TrieEntryVersion::V0)
+        .unwrap();
+    assert!(db
+        .finalized_and_above_missing_trie_nodes_unordered()
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        db.block_storage_get_versioned(
+            &new_finalized_hash,
+            iter::empty::<iter::Empty<_>>(),
+            trie::bytes_to_nibbles(b"xyz".iter().copied()).map(u8::from)
+        )
+        .unwrap(),
+        Some((vec![9; 40], TrieEntryVersion::V0))
+    );
+}
+
+#[test]
+fn checkpoint_round_trip() {
+    let open_empty = || {
+        let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+            panic!()
+        };
+        empty_db
+    };
+
+    let epoch = |epoch_index, start_slot_number| chain_information::BabeEpochInformation {
+        epoch_index,
+        start_slot_number: Some(start_slot_number),
+        authorities: vec![header::BabeAuthority {
+            public_key: [1; 32],
+            weight: 1,
+        }],
+        randomness: [2; 32],
+        c: (1, 4),
+        allowed_slots: header::BabeAllowedSlots::PrimarySlots,
+    };
+    let current_epoch = epoch(3, 1800);
+    let next_epoch = epoch(4, 2400);
+    let grandpa_authorities = [header::GrandpaAuthority {
+        public_key: [3; 32],
+        weight: NonZeroU64::new(1).unwrap(),
+    }];
+
+    let digest = [
+        header::DigestItem::BabePreDigest(header::BabePreDigest::SecondaryPlain(
+            header::BabeSecondaryPlainPreDigest {
+                authority_index: 0,
+                slot_number: 2000,
+            },
+        )),
+        header::DigestItem::BabeSeal([0; 64]),
+    ];
+
+    let source = open_empty()
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    parent_hash: &[4; 32],
+                    number: 1000,
+                    state_root: &[5; 32],
+                    extrinsics_root: &header::extrinsics_root(&[] as &[Vec<u8>]),
+                    digest: header::DigestRef::from_slice(&digest).unwrap(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Babe {
+                    slots_per_epoch: NonZeroU64::new(600).unwrap(),
+                    finalized_block_epoch_information: Some((&current_epoch).into()),
+                    finalized_next_epoch_transition: (&next_epoch).into(),
+                },
+                finality: chain_information::ChainInformationFinalityRef::Grandpa {
+                    after_finalized_block_authorities_set_id: 2,
+                    finalized_triggered_authorities: &grandpa_authorities,
+                    finalized_scheduled_change: None,
+                },
+            },
+            iter::empty(),
+            Some(vec![6; 16]),
+        )
+        .unwrap();
+
+    let checkpoint = source.export_checkpoint().unwrap();
+    assert_eq!(checkpoint.finalized_block_justification, Some(vec![6; 16]));
+    assert_eq!(
+        checkpoint
+            .chain_information
+            .as_ref()
+            .finalized_block_header
+            .hash(4),
+        source.finalized_block_hash().unwrap()
+    );
+    let json = checkpoint.to_json().unwrap();
+    assert!(json.contains("\"babeEpochChanges\""));
+    assert!(json.contains("\"grandpaAuthoritySet\""));
+
+    let db = open_empty()
+        .initialize_from_checkpoint(&checkpoint)
+        .unwrap();
+    let finalized_hash = db.finalized_block_hash().unwrap();
+    assert_eq!(finalized_hash, source.finalized_block_hash().unwrap());
+    assert!(matches!(
+        db.block_extrinsics(&finalized_hash),
+        Err(BlockExtrinsicsError::BodyPruned)
+    ));
+    assert!(
+        db.block_info(&finalized_hash)
+            .unwrap()
+            .unwrap()
+            .has_justification
+    );
+
+    // Exporting again produces the same checkpoint.
+    assert_eq!(db.export_checkpoint().unwrap().to_json().unwrap(), json);
+
+    // A checkpoint using a different number of bytes per block number is rejected.
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(8)).unwrap() else {
+        panic!()
+    };
+    assert!(matches!(
+        empty_db.initialize_from_checkpoint(&checkpoint),
+        Err(InitializeFromCheckpointError::BlockNumberBytesMismatch {
+            checkpoint: 4,
+            database: 8
+        })
+    ));
+}
+
+#[test]
+fn checkpoint_from_substrate_json() {
+    // `lightSyncState` field of a Westend chain specification produced by Substrate.
+    let json = include_str!("../../chain_spec/tests/westend-light-sync-state.json");
+
+    let checkpoint = Checkpoint::from_json(json, 4).unwrap();
+    assert_eq!(checkpoint.finalized_block_justification, None);
+    assert_eq!(checkpoint.block_number_bytes, 4);
+
+    let chain_information = checkpoint.chain_information.as_ref();
+    assert_eq!(chain_information.finalized_block_header.number, 15543919);
+    assert!(matches!(
+        chain_information.consensus,
+        chain_information::ChainInformationConsensusRef::Babe {
+            finalized_block_epoch_information: Some(_),
+            ..
+        }
+    ));
+    assert!(matches!(
+        chain_information.finality,
+        chain_information::ChainInformationFinalityRef::Grandpa { .. }
+    ));
+
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+    let db = empty_db.initialize_from_checkpoint(&checkpoint).unwrap();
+    assert_eq!(
+        db.finalized_block_hash().unwrap(),
+        chain_information.finalized_block_header.hash(4)
+    );
+
+    // Exporting the checkpoint and parsing it back produces the same checkpoint.
+    let exported = db.export_checkpoint().unwrap().to_json().unwrap();
+    assert_eq!(
+        Checkpoint::from_json(&exported, 4)
+            .unwrap()
+            .to_json()
+            .unwrap(),
+        exported
+    );
+
+    assert!(matches!(
+        Checkpoint::from_json("{}", 4),
+        Err(CheckpointFromJsonError::Parse(_))
+    ));
+}
+
+#[test]
+fn meta_entries() {
+    let fixture = FixtureChain::new(4).build();
+    let db = &fixture.database;
+
+    db.database
+        .lock()
+        .execute(
+            "INSERT INTO meta(chain_id, key, value_blob) VALUES ((SELECT id FROM current_chain), 'zzz_large', ?)",
+            (vec![7u8; MetaEntry::MAX_BLOB_LEN + 10],),
+        )
+        .unwrap();
+
+    let entries = db.meta_entries().unwrap();
+    assert!(entries.windows(2).all(|w| w[0].key < w[1].key));
+
+    let best = entries.iter().find(|e| e.key == "best").unwrap();
+    assert_eq!(best.value_blob.as_deref(), Some(&fixture.canonical[0][..]));
+    assert_eq!(best.value_blob_len, Some(32));
+    assert_eq!(best.value_number, None);
+
+    let initialized = entries.iter().find(|e| e.key == "initialized").unwrap();
+    assert_eq!(initialized.value_number, Some(1));
+    assert_eq!(initialized.value_blob, None);
+
+    assert_eq!(
+        entries.last().unwrap(),
+        &MetaEntry {
+            key: "zzz_large".into(),
+            value_number: None,
+            value_blob: Some(vec![7; MetaEntry::MAX_BLOB_LEN]),
+            value_blob_len: Some(MetaEntry::MAX_BLOB_LEN + 10),
+        }
+    );
+}
+
+#[test]
+fn block_digest_summary() {
+    let fixture = FixtureChain::new(4).with_blocks(1).build();
+    let db = &fixture.database;
+
+    assert_eq!(
+        db.block_digest_summary(&fixture.canonical[0]).unwrap(),
+        Some(DigestSummary {
+            consensus_engine: None,
+            slot_number: None,
+            authority_index: None,
+            epoch_change: false,
+            authorities_change: false,
+            has_seal: false,
+        })
+    );
+    assert_eq!(db.block_digest_summary(&[0xaa; 32]).unwrap(), None);
+
+    // Aura block that changes the list of authorities.
+    let aura_authorities = [header::AuraAuthority {
+        public_key: [1; 32],
+    }];
+    let aura_digest = [
+        header::DigestItem::AuraPreDigest(header::AuraPreDigest { slot_number: 42 }),
+        header::DigestItem::AuraConsensus(header::AuraConsensusLog::AuthoritiesChange(
+            aura_authorities.to_vec(),
+        )),
+        header::DigestItem::AuraSeal([0; 64]),
+    ];
+    let aura_block = header::HeaderRef {
+        parent_hash: &fixture.canonical[1],
+        number: 2,
+        state_root: &fixture.state_root,
+        extrinsics_root: &header::extrinsics_root(&[] as &[Vec<u8>]),
+        digest: header::DigestRef::from_slice(&aura_digest).unwrap(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&aura_block, false, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(
+        db.block_digest_summary(&header::hash_from_scale_encoded_header(&aura_block))
+            .unwrap(),
+        Some(DigestSummary {
+            consensus_engine: Some(ConsensusEngine::Aura),
+            slot_number: Some(42),
+            authority_index: None,
+            epoch_change: false,
+            authorities_change: true,
+            has_seal: true,
+        })
+    );
+
+    // Real Polkadot block, which starts a new epoch and schedules a Grandpa change.
+    let polkadot_header = include_bytes!("../../header/tests/header-polkadot-512271");
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::decode(polkadot_header, 4).unwrap(),
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    assert_eq!(
+        db.block_digest_summary(&header::hash_from_scale_encoded_header(polkadot_header))
+            .unwrap(),
+        Some(DigestSummary {
+            consensus_engine: Some(ConsensusEngine::Babe),
+            slot_number: Some(265598163),
+            authority_index: Some(75),
+            epoch_change: true,
+            authorities_change: true,
+            has_seal: true,
+        })
+    );
+}
+
+#[test]
+fn missing_justification_set_changes() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Blocks 2 and 3 schedule a change that is enacted by blocks 2 and 5, and block 6 schedules
+    // a change that isn't enacted yet.
+    let mut parent_hash = db.finalized_block_hash().unwrap();
+    for number in 1..=6 {
+        let delay = match number {
+            2 => Some(0),
+            3 => Some(2),
+            6 => Some(5),
+            _ => None,
+        };
+        let digest = delay
+            .map(|delay| {
+                header::DigestItem::GrandpaConsensus(header::GrandpaConsensusLog::ScheduledChange(
+                    header::GrandpaScheduledChange {
+                        next_authorities: vec![header::GrandpaAuthority {
+                            public_key: [number as u8; 32],
+                            weight: NonZeroU64::new(1).unwrap(),
+                        }],
+                        delay,
+                    },
+                ))
+            })
+            .into_iter()
+            .collect::<Vec<_>>();
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[1; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::from_slice(&digest).unwrap(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        parent_hash = header::hash_from_scale_encoded_header(&block);
+    }
+    db.set_finalized(&parent_hash).unwrap();
+
+    assert_eq!(
+        db.missing_justification_set_changes(0, 100).unwrap(),
+        vec![2, 5]
+    );
+
+    db.set_block_justification(
+        &db.best_block_hash_by_number(2).unwrap().unwrap(),
+        b"FRNK",
+        &[0],
+    )
+    .unwrap();
+    assert_eq!(
+        db.missing_justification_set_changes(0, 100).unwrap(),
+        vec![5]
+    );
+
+    // Only the headers within the range are scanned.
+    assert_eq!(db.missing_justification_set_changes(3, 3).unwrap(), vec![5]);
+    assert!(db
+        .missing_justification_set_changes(4, 5)
+        .unwrap()
+        .is_empty());
+    assert!(db
+        .missing_justification_set_changes(7, 100)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn child_storage_by_name() {
+    let foo_entries = vec![
+        (b"\x01".to_vec(), vec![0xaa]),
+        (b"\x01\x02".to_vec(), vec![0xbb]),
+        (b"\x01\x30".to_vec(), vec![0xcc]),
+        (b"\xf0".to_vec(), vec![0xdd]),
+    ];
+    let bar_entries = vec![
+        (b"\x01".to_vec(), vec![0x11]),
+        (b"\x02".to_vec(), vec![0xee]),
+    ];
+    let fixture = FixtureChain::new(4)
+        .with_blocks(1)
+        .with_storage([(b"\x01".to_vec(), vec![0x99])])
+        .with_child_trie(b"foo", foo_entries.clone())
+        .with_child_trie(b"bar", bar_entries.clone())
+        .build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[1];
+
+    for (child_name, entries) in [(&b"foo"[..], &foo_entries), (&b"bar"[..], &bar_entries)] {
+        // The root is the value found in the main trie.
+        let root_key = [&b":child_storage:default:"[..], child_name].concat();
+        let expected_root = fixture
+            .main_trie
+            .iter()
+            .find(|(key, _)| *key == root_key)
+            .unwrap()
+            .1
+            .clone();
+        assert_eq!(
+            db.child_storage_root(&block_hash, child_name)
+                .unwrap()
+                .map(|root| root.to_vec()),
+            Some(expected_root)
+        );
+
+        for (key, value) in entries {
+            assert_eq!(
+                db.child_storage_get(&block_hash, child_name, key).unwrap(),
+                Some((value.clone(), TrieEntryVersion::V1))
+            );
+        }
+
+        // Iterating over the child trie yields all its keys in order.
+        let mut keys = Vec::new();
+        let mut next = db
+            .child_storage_next_key(&block_hash, child_name, &[], &[], true, false)
+            .unwrap();
+        while let Some(key) = next {
+            next = db
+                .child_storage_next_key(&block_hash, child_name, &key, &[], false, false)
+                .unwrap();
+            keys.push(key);
+        }
+        assert_eq!(
+            keys,
+            entries.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>()
+        );
+    }
+
+    // Lookups are scoped to the requested child trie.
+    assert_eq!(
+        db.child_storage_get(&block_hash, b"foo", b"\x02").unwrap(),
+        None
+    );
+    assert_eq!(
+        db.child_storage_get(&block_hash, b"bar", b"\x01").unwrap(),
+        Some((vec![0x11], TrieEntryVersion::V1))
+    );
+    assert_eq!(
+        db.block_storage_get_bytes(&block_hash, b"\x01").unwrap(),
+        Some((vec![0x99], TrieEntryVersion::V1))
+    );
+    assert_eq!(
+        db.child_storage_next_key(&block_hash, b"foo", b"\x01", b"\x01", false, false)
+            .unwrap(),
+        Some(b"\x01\x02".to_vec())
+    );
+    assert_eq!(
+        db.child_storage_next_key(&block_hash, b"foo", b"\x01\x30", b"\x01", false, false)
+            .unwrap(),
+        None
+    );
+
+    // Unknown child tries.
+    assert_eq!(db.child_storage_root(&block_hash, b"baz").unwrap(), None);
+    assert_eq!(
+        db.child_storage_get(&block_hash, b"baz", b"\x01").unwrap(),
+        None
+    );
+    assert_eq!(
+        db.child_storage_next_key(&block_hash, b"baz", &[], &[], true, false)
+            .unwrap(),
+        None
+    );
+}
+
+#[test]
+fn trie_dedup_stats() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    // The values are large enough for all the nodes to be hashed rather than inlined in their
+    // parent. Only the entry of `c` and the nodes above it differ between the two tries.
+    let entries_a = vec![
+        (b"a".to_vec(), vec![1; 40]),
+        (b"b".to_vec(), vec![2; 40]),
+        (b"c".to_vec(), vec![3; 40]),
+    ];
+    let entries_b = vec![
+        (b"a".to_vec(), vec![1; 40]),
+        (b"b".to_vec(), vec![2; 40]),
+        (b"c".to_vec(), vec![4; 40]),
+    ];
+    let (root_a, nodes_a) = build_trie(&entries_a, |_| false);
+    let (root_b, nodes_b) = build_trie(&entries_b, |_| false);
+    let hashes_a = nodes_a
+        .iter()
+        .map(|n| n.merkle_value.to_vec())
+        .collect::<hashbrown::HashSet<_>>();
+    let hashes_b = nodes_b
+        .iter()
+        .map(|n| n.merkle_value.to_vec())
+        .collect::<hashbrown::HashSet<_>>();
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &root_a,
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let block_a = db.finalized_block_hash().unwrap();
+    let block_b = header::HeaderRef {
+        parent_hash: &block_a,
+        number: 1,
+        state_root: &root_b,
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block_b, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    let block_b = header::hash_from_scale_encoded_header(&block_b);
+    db.insert_trie_nodes_versioned(nodes_a.into_iter().chain(nodes_b), TrieEntryVersion::V0)
+        .unwrap();
+
+    let stats = db.trie_dedup_stats(&block_a, &block_b).unwrap();
+    assert_eq!(
+        stats.only_a.nodes,
+        u64::try_from(hashes_a.difference(&hashes_b).count()).unwrap()
+    );
+    assert_eq!(
+        stats.only_b.nodes,
+        u64::try_from(hashes_b.difference(&hashes_a).count()).unwrap()
+    );
+    assert_eq!(
+        stats.shared.nodes,
+        u64::try_from(hashes_a.intersection(&hashes_b).count()).unwrap()
+    );
+    assert!(stats.shared.nodes >= 2);
+    assert!(stats.only_a.bytes >= 40 && stats.only_b.bytes >= 40 && stats.shared.bytes >= 80);
+
+    // The statistics of a single block are the sum of the nodes unique to it and shared.
+    let stats_a = db.trie_stats(&block_a).unwrap();
+    assert_eq!(
+        stats_a,
+        TrieStats {
+            nodes: stats.only_a.nodes + stats.shared.nodes,
+            bytes: stats.only_a.bytes + stats.shared.bytes,
+        }
+    );
+
+    // A block compared with itself shares everything.
+    assert_eq!(
+        db.trie_dedup_stats(&block_a, &block_a).unwrap(),
+        DedupStats {
+            only_a: TrieStats::default(),
+            only_b: TrieStats::default(),
+            shared: stats_a,
+        }
+    );
+
+    assert!(matches!(
+        db.trie_stats(&[0xaa; 32]),
+        Err(StorageAccessError::UnknownBlock)
+    ));
+}
+
+#[test]
+fn fork_blocks_above_finalized() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(6)
+        .with_fork_at(0)
+        .with_fork_at(4)
+        .build();
+    let db = &fixture.database;
+
+    let check = |expected: Vec<[u8; 32]>| {
+        let forks = db.fork_blocks_above_finalized().unwrap();
+        assert!(forks.windows(2).all(|w| w[0].1 <= w[1].1));
+        for (hash, number) in &forks {
+            assert_eq!(db.block_info(hash).unwrap().unwrap().number, *number);
+        }
+        let mut hashes = forks.into_iter().map(|(hash, _)| hash).collect::<Vec<_>>();
+        hashes.sort();
+        let mut expected = expected;
+        expected.sort();
+        assert_eq!(hashes, expected);
+    };
+
+    check(
+        fixture.forks[0]
+            .iter()
+            .chain(&fixture.forks[1])
+            .copied()
+            .collect(),
+    );
+
+    // Orphans at or below the finalized block are no longer reported, even before being purged.
+    db.set_finalized(&fixture.canonical[2]).unwrap();
+    check(
+        fixture.forks[0][2..]
+            .iter()
+            .chain(&fixture.forks[1])
+            .copied()
+            .collect(),
+    );
+
+    db.set_finalized(&fixture.canonical[6]).unwrap();
+    check(Vec::new());
+}
+
+#[test]
+fn block_storage_value_hash() {
+    let entries = vec![
+        (b"foo".to_vec(), vec![1]),
+        (b"bar".to_vec(), vec![2; 4096]),
+        (b"baz".to_vec(), Vec::new()),
+    ];
+    let fixture = FixtureChain::new(4)
+        .with_storage(entries.clone())
+        .with_child_trie(b"child", vec![(b"a".to_vec(), vec![3])])
+        .build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[0];
+
+    let keys = entries
+        .iter()
+        .map(|(key, _)| key.clone())
+        .chain(iter::once(b":child_storage:default:child".to_vec()))
+        .collect::<Vec<_>>();
+    let check = || {
+        for key in &keys {
+            let (value, version) = db
+                .block_storage_get_bytes(&block_hash, key)
+                .unwrap()
+                .unwrap();
+            let expected =
+                <[u8; 32]>::try_from(blake2_rfc::blake2b::blake2b(32, &[], &value).as_bytes())
+                    .unwrap();
+            assert_eq!(
+                db.block_storage_value_hash(
+                    &block_hash,
+                    trie::bytes_to_nibbles(key.iter().copied()).map(u8::from)
+                )
+                .unwrap(),
+                Some((expected, version))
+            );
+        }
+    };
+    let count_hashes = || {
+        db.database
+            .lock()
+            .query_row(
+                "SELECT COUNT(*) FROM trie_node_storage WHERE value_hash IS NOT NULL",
+                (),
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+    };
+
+    // The hashes are stored when the values are inserted.
+    check();
+    assert_eq!(count_hashes(), 4);
+
+    // Values inserted before the hashes were stored get their hash filled on first access.
+    db.database
+        .lock()
+        .execute("UPDATE trie_node_storage SET value_hash = NULL", ())
+        .unwrap();
+    check();
+    assert_eq!(count_hashes(), 3);
+    check();
+
+    assert_eq!(
+        db.block_storage_value_hash(
+            &block_hash,
+            trie::bytes_to_nibbles(b"unknown".iter().copied()).map(u8::from)
+        )
+        .unwrap(),
+        None
+    );
+    assert!(matches!(
+        db.block_storage_value_hash(&[0xaa; 32], iter::empty()),
+        Err(StorageAccessError::UnknownBlock)
+    ));
+}
+
+#[test]
+fn storage_prefix_entries() {
+    let storage = (0..1000u32)
+        .map(|n| (n.to_be_bytes()[1..].to_vec(), n.to_le_bytes().to_vec()))
+        .chain([
+            (b":code".to_vec(), b"runtime".to_vec()),
+            (b":heappages".to_vec(), vec![0; 40]),
+        ])
+        .collect::<BTreeMap<_, _>>();
+    let fixture = FixtureChain::new(4)
+        .with_blocks(1)
+        .with_storage(storage.clone())
+        .build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[1];
+
+    for prefix in [
+        &b""[..],
+        b"\0",
+        b"\0\x02",
+        b"\0\x03\xe7",
+        b":",
+        b"\x01",
+        b"\0\x03\xe7\0",
+    ] {
+        let expected = storage
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+
+        let obtained = db
+            .block_storage_prefix_entries(&block_hash, prefix, None, None)
+            .unwrap();
+        assert_eq!(obtained.entries, expected, "prefix={prefix:?}");
+        assert_eq!(obtained.continuation, None);
+
+        // Collecting the entries by chunks using the continuation key yields the same entries.
+        for max_value_bytes in [1, 4, 10] {
+            let mut obtained = Vec::new();
+            let mut start_key = None;
+            loop {
+                let chunk = db
+                    .block_storage_prefix_entries(
+                        &block_hash,
+                        prefix,
+                        start_key.as_deref(),
+                        NonZeroUsize::new(max_value_bytes),
+                    )
+                    .unwrap();
+                assert!(
+                    chunk.entries.iter().map(|(_, v)| v.len()).sum::<usize>()
+                        < max_value_bytes + 40
+                );
+                obtained.extend(chunk.entries);
+                match chunk.continuation {
+                    Some(key) => start_key = Some(key),
+                    None => break,
+                }
+            }
+            assert_eq!(obtained, expected, "prefix={prefix:?}");
+        }
+    }
+
+    // Entries inferior to the start key are skipped, even if the start key doesn't exist.
+    let obtained = db
+        .block_storage_prefix_entries(&block_hash, b"\0\x03", Some(b"\0\x03\xe0\x01"), None)
+        .unwrap();
+    assert_eq!(
+        obtained.entries,
+        storage
+            .range(b"\0\x03\xe0\x01".to_vec()..)
+            .filter(|(key, _)| key.starts_with(b"\0\x03"))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>()
+    );
+
+    assert!(matches!(
+        db.block_storage_prefix_entries(&[0xff; 32], b"", None, None),
+        Err(StorageAccessError::UnknownBlock)
+    ));
+}
+
+#[test]
+fn block_justifications() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let fixture = FixtureChain::new(4).with_blocks(2).build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[1];
+
+    assert_eq!(db.block_justification(&block_hash).unwrap(), None);
+    assert!(db.block_justifications(&block_hash).unwrap().is_empty());
+    assert!(
+        !db.block_info(&block_hash)
+            .unwrap()
+            .unwrap()
+            .has_justification
+    );
+
+    db.set_block_justification(&block_hash, b"FRNK", &[1])
+        .unwrap();
+    db.set_block_justification(&block_hash, b"BEEF", &[2, 2])
+        .unwrap();
+    db.set_block_justification(&block_hash, b"FRNK", &[3])
+        .unwrap();
+    assert_eq!(db.block_justification(&block_hash).unwrap(), Some(vec![3]));
+    assert_eq!(
+        db.block_justifications(&block_hash).unwrap(),
+        vec![(*b"BEEF", vec![2, 2]), (*b"FRNK", vec![3])]
+    );
+    assert!(
+        db.block_info(&block_hash)
+            .unwrap()
+            .unwrap()
+            .has_justification
+    );
+
+    assert!(matches!(
+        db.set_block_justification(&[0xff; 32], b"FRNK", &[1]),
+        Err(SetBlockJustificationError::UnknownBlock)
+    ));
+    assert!(db.block_justifications(&[0xff; 32]).unwrap().is_empty());
+
+    // The GrandPa justifications stored before the introduction of the `blocks_justifications`
+    // table are migrated.
+    db.backup_to(&path, |_| {}).unwrap();
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    revert_schema_to_v14(&connection);
+    drop(connection);
+
+    let DatabaseOpen::Open(db) = open(Config {
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+    assert_eq!(
+        db.block_justifications(&block_hash).unwrap(),
+        vec![(*b"FRNK", vec![3])]
+    );
+    assert!(db
+        .block_justifications(&fixture.canonical[0])
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn foreign_keys_enforced() {
+    let fixture = FixtureChain::new(4).with_blocks(2).build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[2];
+
+    assert!(db.check_foreign_keys().unwrap().is_empty());
+
+    {
+        let connection = db.database.lock();
+        let insert_body = |hash: &[u8; 32]| {
+            connection
+                .execute(
+                    "INSERT OR IGNORE INTO extrinsics(hash, bytes) VALUES (zeroblob(32), X'00')",
+                    (),
+                )
+                .unwrap();
+            connection
+                .execute(
+                    "INSERT INTO blocks_body(hash, idx, extrinsic_hash) VALUES (?, 0, zeroblob(32))",
+                    (&hash[..],),
+                )
+                .map_err(super::sqlite_err("insert_body"))
+        };
+
+        // The body of an unknown block is refused.
+        assert!(matches!(
+            insert_body(&[0xff; 32]),
+            Err(CorruptedError::ForeignKeyViolation {
+                operation: "insert_body"
+            })
+        ));
+
+        // Removing a block also removes its body rather than leaving it dangling.
+        insert_body(&block_hash).unwrap();
+        connection
+            .execute("DELETE FROM blocks WHERE hash = ?", (&block_hash[..],))
+            .unwrap();
+        let remaining_body_rows = connection
+            .query_row(
+                "SELECT COUNT(*) FROM blocks_body WHERE hash = ?",
+                (&block_hash[..],),
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap();
+        assert_eq!(remaining_body_rows, 0);
+    }
+
+    assert!(db.check_foreign_keys().unwrap().is_empty());
+
+    // Rows written while the constraints aren't enforced, for example by a different program,
+    // are reported.
+    {
+        let connection = db.database.lock();
+        connection
+            .execute_batch(
+                "PRAGMA foreign_keys = OFF;
+                INSERT INTO extrinsics(hash, bytes) VALUES (zeroblob(32), X'00');
+                INSERT INTO blocks_body(hash, idx, extrinsic_hash) VALUES (zeroblob(32), 0, zeroblob(32));
+                PRAGMA foreign_keys = ON;",
+            )
+            .unwrap();
+    }
+
+    let violations = db.check_foreign_keys().unwrap();
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].table, "blocks_body");
+    assert_eq!(violations[0].parent, "blocks");
+    assert!(violations[0].rowid.is_some());
+}
+
+#[test]
+fn check_constraints() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let fixture = FixtureChain::new(4).with_blocks(2).build();
+    let db = &fixture.database;
+
+    let insert_invalid_block = |connection: &rusqlite::Connection| {
+        connection
+            .execute(
+                "INSERT INTO blocks(hash, parent_hash, number, header, is_best_chain) VALUES (X'0000', NULL, 5, X'', FALSE)",
+                (),
+            )
+            .map(|_| connection.last_insert_rowid())
+    };
+
+    // Invalid rows are refused by a newly-created database.
+    {
+        let connection = db.database.lock();
+        assert_eq!(
+            insert_invalid_block(&connection)
+                .unwrap_err()
+                .sqlite_error_code(),
+            Some(rusqlite::ErrorCode::ConstraintViolation)
+        );
+        assert_eq!(
+            connection
+                .execute(
+                    "INSERT INTO grandpa_triggered_authorities(chain_id, idx, public_key, weight) VALUES (0, 1000, zeroblob(32), zeroblob(8))",
+                    (),
+                )
+                .unwrap_err()
+                .sqlite_error_code(),
+            Some(rusqlite::ErrorCode::ConstraintViolation)
+        );
+    }
+
+    // The rows of a database created before the constraints existed are verified when the
+    // database is opened.
+    db.backup_to(&path, |_| {}).unwrap();
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    revert_schema_to_v15(&connection);
+    let invalid_rowid = insert_invalid_block(&connection).unwrap();
+    drop(connection);
+
+    let open_database = || {
+        open(Config {
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+    };
+
+    let Err(OpenError::InvalidRows(invalid_rows)) = open_database() else {
+        panic!()
+    };
+    assert_eq!(
+        invalid_rows,
+        vec![InvalidRow {
+            table: "blocks",
+            constraint: "length(hash) == 32",
+            rowid: invalid_rowid,
+        }]
+    );
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute("DELETE FROM blocks WHERE rowid = ?", (invalid_rowid,))
+        .unwrap();
+    drop(connection);
+
+    let DatabaseOpen::Open(db) = open_database().unwrap() else {
+        panic!()
+    };
+    assert_eq!(
+        db.finalized_block_hash().unwrap(),
+        fixture.database.finalized_block_hash().unwrap()
+    );
+
+    // The tables of the migrated database haven't been rebuilt, but the constraints are
+    // nonetheless enforced on the rows written after the migration.
+    let connection = db.database.lock();
+    assert_eq!(
+        insert_invalid_block(&connection)
+            .unwrap_err()
+            .sqlite_error_code(),
+        Some(rusqlite::ErrorCode::ConstraintViolation)
+    );
+    assert_eq!(
+        connection
+            .execute("UPDATE blocks SET number = -1", ())
+            .unwrap_err()
+            .sqlite_error_code(),
+        Some(rusqlite::ErrorCode::ConstraintViolation)
+    );
+}
+
+#[test]
+fn query_read_only() {
+    let fixture = FixtureChain::new(4).with_blocks(3).build();
+    let db = &fixture.database;
+
+    let numbers = db
+        .query_read_only(
+            "SELECT number FROM blocks WHERE chain_id = (SELECT id FROM current_chain) AND number >= ? ORDER BY number",
+            (2,),
+            |row| row.get::<_, i64>(0).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(numbers, vec![2, 3]);
+
+    for sql in [
+        "DELETE FROM blocks",
+        "BEGIN",
+        "SELECT 1; DELETE FROM blocks",
+        "",
+    ] {
+        assert!(matches!(
+            db.query_read_only(sql, (), |_| ()),
+            Err(QueryError::NotReadOnly)
+        ));
+    }
+    assert!(matches!(
+        db.query_read_only("SELECT * FROM no_such_table", (), |_| ()),
+        Err(QueryError::InvalidQuery(_))
+    ));
+    assert!(matches!(
+        db.query_read_only(
+            "WITH RECURSIVE n(i) AS (VALUES (0) UNION ALL SELECT i + 1 FROM n) SELECT i FROM n",
+            (),
+            |_| ()
+        ),
+        Err(QueryError::TooManyRows)
+    ));
+
+    // The connection remains usable afterwards.
+    assert_eq!(db.block_hash_by_number(3).unwrap().count(), 1);
+}
+
+#[test]
+fn blocks_by_number_order() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(3)
+        .with_fork_at(1)
+        .with_fork_at(1)
+        .build();
+    let db = &fixture.database;
+
+    // The block of the best chain comes first, then the others by increasing hash.
+    let mut forks = vec![fixture.forks[0][0], fixture.forks[1][0]];
+    forks.sort();
+    let expected = iter::once(BlockAtNumber {
+        hash: fixture.canonical[2],
+        is_best_chain: true,
+    })
+    .chain(forks.iter().map(|hash| BlockAtNumber {
+        hash: *hash,
+        is_best_chain: false,
+    }))
+    .collect::<Vec<_>>();
+    assert_eq!(db.blocks_by_number(2).unwrap(), expected);
+    assert_eq!(
+        db.block_hash_by_number(2).unwrap().collect::<Vec<_>>(),
+        expected.iter().map(|block| block.hash).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        db.with_read_snapshot(|snapshot| snapshot.blocks_by_number(2))
+            .unwrap()
+            .unwrap(),
+        expected
+    );
+
+    // The order follows the best chain.
+    db.set_best_block(&forks[1]).unwrap();
+    let mut others = vec![fixture.canonical[2], forks[0]];
+    others.sort();
+    let expected = iter::once(BlockAtNumber {
+        hash: forks[1],
+        is_best_chain: true,
+    })
+    .chain(others.iter().map(|hash| BlockAtNumber {
+        hash: *hash,
+        is_best_chain: false,
+    }))
+    .collect::<Vec<_>>();
+    assert_eq!(db.blocks_by_number(2).unwrap(), expected);
+
+    assert!(db.blocks_by_number(100).unwrap().is_empty());
+}
+
+#[test]
+fn genesis_block_parent() {
+    let fixture = FixtureChain::new(4).with_blocks(1).build();
+    let db = &fixture.database;
+
+    assert_eq!(
+        db.block_parent(&fixture.canonical[0]).unwrap(),
+        Some(ParentOf::Genesis)
+    );
+    assert_eq!(
+        db.block_parent(&fixture.canonical[1]).unwrap(),
+        Some(ParentOf::Block(fixture.canonical[0]))
+    );
+    assert_eq!(db.block_parent(&[0xff; 32]).unwrap(), None);
+
+    let chain_information = db.to_chain_information(&fixture.canonical[0]).unwrap();
+    db.reset(chain_information.as_ref(), iter::empty(), None)
+        .unwrap();
+    assert_eq!(
+        db.block_parent(&fixture.canonical[0]).unwrap(),
+        Some(ParentOf::Genesis)
+    );
+    assert_eq!(
+        db.with_read_snapshot(|snapshot| snapshot.block_parent(&fixture.canonical[0]))
+            .unwrap()
+            .unwrap(),
+        Some(ParentOf::Genesis)
+    );
+}
+
+#[test]
+fn maintenance_steps() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(6)
+        .with_fork_at(0)
+        .with_fork_at(2)
+        .build();
+    let db = &fixture.database;
+    db.set_finalized(&fixture.canonical[5]).unwrap();
+
+    let budget = Budget {
+        max_duration: Duration::from_secs(3600),
+        max_rows: NonZeroUsize::new(3).unwrap(),
+    };
+    let cursor = |key: &str| {
+        db.meta_entries()
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.key == key)
+            .and_then(|entry| entry.value_number)
+    };
+
+    // The forks contain 5 and 3 blocks below the finalized block, removed from the highest to
+    // the lowest number.
+    let mut steps = Vec::new();
+    loop {
+        let progress = db.purge_finality_orphans_step(&budget).unwrap();
+        steps.push(progress);
+        if let MaintenanceProgress::Remaining(number) = progress {
+            assert_eq!(cursor("purge_orphans_cursor"), Some(number));
+        } else {
+            break;
+        }
+    }
+    assert_eq!(
+        steps,
+        vec![
+            MaintenanceProgress::Remaining(4),
+            MaintenanceProgress::Remaining(3),
+            MaintenanceProgress::Finished,
+        ]
+    );
+    assert_eq!(cursor("purge_orphans_cursor"), None);
+    for hash in fixture.forks[0][..5].iter().chain(&fixture.forks[1][..3]) {
+        assert!(db.block_scale_encoded_header(hash).unwrap().is_none());
+    }
+    assert_eq!(
+        db.purge_finality_orphans_step(&budget).unwrap(),
+        MaintenanceProgress::Finished
+    );
+
+    // A duration of zero still lets the operation process one block.
+    let budget = Budget {
+        max_duration: Duration::from_secs(0),
+        max_rows: NonZeroUsize::new(100).unwrap(),
+    };
+    assert_eq!(
+        db.prune_bodies_below_step(3, &budget).unwrap(),
+        MaintenanceProgress::Remaining(1)
+    );
+    assert_eq!(cursor("prune_bodies_cursor"), Some(1));
+    assert_eq!(
+        db.prune_bodies_below_step(3, &budget).unwrap(),
+        MaintenanceProgress::Remaining(2)
+    );
+    assert_eq!(
+        db.prune_bodies_below_step(3, &budget).unwrap(),
+        MaintenanceProgress::Remaining(3)
+    );
+    assert_eq!(
+        db.prune_bodies_below_step(3, &budget).unwrap(),
+        MaintenanceProgress::Finished
+    );
+    assert_eq!(cursor("prune_bodies_cursor"), None);
+    for hash in &fixture.canonical[..3] {
+        assert!(matches!(
+            db.block_extrinsics(hash),
+            Err(BlockExtrinsicsError::BodyPruned)
+        ));
+    }
+    assert!(db
+        .block_extrinsics(&fixture.canonical[3])
+        .unwrap()
+        .is_some());
+}
+
+#[test]
+fn extrinsics_deduplicated() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let fixture = FixtureChain::new(4).with_blocks(1).build();
+    let db = &fixture.database;
+
+    let body = vec![vec![1, 2, 3], vec![4, 5], vec![1, 2, 3]];
+    let count_extrinsics = |connection: &rusqlite::Connection| {
+        connection
+            .query_row(
+                "SELECT COUNT(*) FROM extrinsics WHERE bytes IN (X'010203', X'0405')",
+                (),
+                |row| row.get::<_, i64>(0),
+            )
+            .unwrap()
+    };
+
+    // Two competing blocks with the same body, made different through their state root.
+    let insert = |state_root: u8| {
+        let block = header::HeaderRef {
+            parent_hash: &fixture.canonical[1],
+            number: 2,
+            state_root: &[state_root; 32],
+            extrinsics_root: &header::extrinsics_root(&body),
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, false, body.iter(), None, None).unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+    let block_a = insert(1);
+    let block_b = insert(2);
+
+    assert_eq!(count_extrinsics(&db.database.lock()), 2);
+    for hash in [block_a, block_b] {
+        assert_eq!(
+            db.block_extrinsics(&hash)
+                .unwrap()
+                .unwrap()
+                .collect::<Vec<_>>(),
+            body
+        );
+    }
+
+    // The bodies of a database created before the extrinsics were deduplicated are migrated
+    // when the database is opened.
+    db.backup_to(&path, |_| {}).unwrap();
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    revert_schema_to_v16(&connection);
+    drop(connection);
+    let DatabaseOpen::Open(migrated) = open(Config {
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+    assert_eq!(count_extrinsics(&migrated.database.lock()), 2);
+    for hash in [block_a, block_b] {
+        assert_eq!(
+            migrated
+                .block_extrinsics(&hash)
+                .unwrap()
+                .unwrap()
+                .collect::<Vec<_>>(),
+            body
+        );
+    }
+    drop(migrated);
+
+    // The extrinsics are only removed once no block refers to them anymore.
+    db.set_best_block(&block_a).unwrap();
+    db.set_finalized(&block_a).unwrap();
+    let report = db.purge_finality_orphans(None).unwrap();
+    assert_eq!(report.purged_blocks, vec![block_b]);
+    assert_eq!(count_extrinsics(&db.database.lock()), 2);
+    assert_eq!(
+        db.block_extrinsics(&block_a)
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>(),
+        body
+    );
+
+    db.prune_bodies_below(3).unwrap();
+    assert_eq!(count_extrinsics(&db.database.lock()), 0);
+}
+
+#[test]
+fn full_block() {
+    let fixture = FixtureChain::new(4).with_blocks(3).build();
+    let db = &fixture.database;
+    let block_hash = fixture.canonical[1];
+
+    db.set_block_justification(&block_hash, b"FRNK", &[1])
+        .unwrap();
+    let expected = FullBlock {
+        scale_encoded_header: db.block_scale_encoded_header(&block_hash).unwrap().unwrap(),
+        body: Some(db.block_extrinsics(&block_hash).unwrap().unwrap().collect()),
+        justifications: vec![(*b"FRNK", vec![1])],
+    };
+    assert_eq!(db.full_block(&block_hash).unwrap(), Some(expected.clone()));
+    assert_eq!(
+        db.with_read_snapshot(|snapshot| snapshot.full_block(&block_hash))
+            .unwrap()
+            .unwrap(),
+        Some(expected.clone())
+    );
+    assert_eq!(db.full_block(&[0xff; 32]).unwrap(), None);
+
+    // A pruned body is reported as such rather than as an empty body.
+    db.set_finalized(&fixture.canonical[2]).unwrap();
+    db.prune_bodies_below(2).unwrap();
+    assert_eq!(
+        db.full_block(&block_hash).unwrap(),
+        Some(FullBlock {
+            body: None,
+            ..expected
+        })
+    );
+}
+
+#[test]
+fn storage_diffs() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(2)
+        .with_storage([(b"foo".to_vec(), vec![1])])
+        .build();
+    let db = &fixture.database;
+
+    // Blocks whose trie nodes aren't in the database.
+    let mut blocks = Vec::new();
+    for number in 3..=4 {
+        let block = header::HeaderRef {
+            parent_hash: blocks.last().unwrap_or(&fixture.canonical[2]),
+            number,
+            state_root: &[0xaa; 32],
+            extrinsics_root: &[0; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        db.insert(&block, true, iter::empty::<Vec<u8>>(), None, None)
+            .unwrap();
+        blocks.push(header::hash_from_scale_encoded_header(&block));
+    }
+
+    assert!(matches!(
+        db.block_storage_get_historical(&blocks[1], b"foo"),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+
+    db.insert_storage_diff(
+        &blocks[0],
+        [(&b"foo"[..], Some(&[2][..])), (&b"bar"[..], Some(&[3][..]))].into_iter(),
+    )
+    .unwrap();
+    db.insert_storage_diff(&blocks[1], [(&b"foo"[..], None::<&[u8]>)].into_iter())
+        .unwrap();
+    assert!(matches!(
+        db.insert_storage_diff(&[0xff; 32], iter::empty::<(&[u8], Option<&[u8]>)>()),
+        Err(InsertStorageDiffError::UnknownBlock)
+    ));
+
+    assert_eq!(
+        db.block_storage_get_historical(&blocks[1], b"foo").unwrap(),
+        None
+    );
+    assert_eq!(
+        db.block_storage_get_historical(&blocks[1], b"bar").unwrap(),
+        Some(vec![3])
+    );
+    assert_eq!(
+        db.block_storage_get_historical(&blocks[0], b"foo").unwrap(),
+        Some(vec![2])
+    );
+    // Keys that aren't in any diff are read from the closest ancestor whose storage is
+    // complete.
+    assert_eq!(
+        db.block_storage_get_historical(&blocks[1], b"baz").unwrap(),
+        None
+    );
+    assert_eq!(
+        db.block_storage_get_historical(&fixture.canonical[2], b"foo")
+            .unwrap(),
+        Some(vec![1])
+    );
+    assert!(matches!(
+        db.block_storage_get_historical(&[0xff; 32], b"foo"),
+        Err(StorageAccessError::UnknownBlock)
+    ));
+
+    // Inserting a diff again replaces the previous one.
+    db.insert_storage_diff(&blocks[0], [(&b"bar"[..], Some(&[4][..]))].into_iter())
+        .unwrap();
+    assert_eq!(
+        db.block_storage_get_historical(&blocks[0], b"foo").unwrap(),
+        Some(vec![1])
+    );
+
+    assert_eq!(db.prune_storage_diffs_below(4).unwrap(), 1);
+    assert_eq!(db.prune_storage_diffs_below(4).unwrap(), 0);
+    assert!(matches!(
+        db.block_storage_get_historical(&blocks[1], b"bar"),
+        Err(StorageAccessError::IncompleteStorage)
+    ));
+    assert_eq!(
+        db.block_storage_get_historical(&blocks[1], b"foo").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn open_already_in_use() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let open_disk = |allow_shared_readers, chain_name| {
+        open(Config {
+            statement_cache_capacity: 32,
+            busy_timeout: Duration::ZERO,
+            allow_shared_readers,
+            chain_name,
+            ty: ConfigTy::Disk {
+                path: &path,
+                memory_map_size: 0,
+            },
+            ..memory_config(4)
+        })
+    };
+
+    let DatabaseOpen::Empty(empty_db) = open_disk(false, None).unwrap() else {
+        panic!()
+    };
+    let first = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    let genesis_hash = first.finalized_block_hash().unwrap();
+
+    assert!(matches!(
+        open_disk(false, None),
+        Err(OpenError::AlreadyInUse { pid_hint: Some(pid) }) if pid == std::process::id()
+    ));
+    assert!(matches!(
+        open_disk(true, None),
+        Err(OpenError::AlreadyInUse { .. })
+    ));
+    // The lock covers the whole database, including the other chains.
+    assert!(matches!(
+        open_disk(false, Some("other")),
+        Err(OpenError::AlreadyInUse { .. })
+    ));
+
+    // The lock is released when the database is closed.
+    drop(first);
+    let Ok(DatabaseOpen::Open(reader1)) = open_disk(true, None) else {
+        panic!()
+    };
+    let reader2 = open_disk(true, None).unwrap();
+    assert!(matches!(
+        open_disk(false, None),
+        Err(OpenError::AlreadyInUse { .. })
+    ));
+
+    // Shared readers can't modify the database, including in order to create a chain.
+    assert_eq!(reader1.finalized_block_hash().unwrap(), genesis_hash);
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    assert!(matches!(
+        reader1.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None),
+        Err(InsertError::Corrupted(CorruptedError::ReadOnly))
+    ));
+    assert!(matches!(
+        open_disk(true, Some("other")),
+        Err(OpenError::ReadOnly)
+    ));
+
+    drop((reader1, reader2));
+    assert!(open_disk(false, None).is_ok());
+}
+
+#[test]
+fn open_progress() {
+    let directory = tempfile::tempdir().unwrap();
+    let path = directory.path().join("database.sqlite");
+
+    let steps = Arc::new(Mutex::new(Vec::new()));
+    let open_config = || Config {
+        progress: Some(Box::new({
+            let steps = steps.clone();
+            move |step| steps.lock().push(step)
+        })),
+        ty: ConfigTy::Disk {
+            path: &path,
+            memory_map_size: 0,
+        },
+        ..memory_config(4)
+    };
+
+    // A new database goes through all the migrations, but isn't analyzed.
+    let DatabaseOpen::Empty(empty_db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+    assert_eq!(empty_db.open_report().schema_version_found, 0);
+    assert_eq!(empty_db.open_report().migrations_applied, 18);
+    {
+        let steps = core::mem::take(&mut *steps.lock());
+        assert_eq!(steps.len(), 20);
+        assert_eq!(steps[0], OpenProgressStep::Opening);
+        assert_eq!(steps[1], OpenProgressStep::VerifyingMeta);
+        for (n, step) in steps[2..].iter().enumerate() {
+            assert_eq!(
+                *step,
+                OpenProgressStep::Migration {
+                    index: u32::try_from(n).unwrap() + 1,
+                    total: 18
+                }
+            );
+        }
+    }
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    revert_schema_to_v16(&db.database.lock());
+    drop(db);
+
+    let database = open(open_config()).unwrap();
+    assert_eq!(database.report().schema_version_found, 16);
+    assert_eq!(database.report().migrations_applied, 2);
+    assert_eq!(
+        core::mem::take(&mut *steps.lock()),
+        vec![
+            OpenProgressStep::Opening,
+            OpenProgressStep::VerifyingMeta,
+            OpenProgressStep::Migration { index: 1, total: 2 },
+            OpenProgressStep::Migration { index: 2, total: 2 },
+            OpenProgressStep::Analyzing,
+        ]
+    );
+    drop(database);
+
+    // Opening an up-to-date database doesn't apply any migration.
+    let DatabaseOpen::Open(db) = open(open_config()).unwrap() else {
+        panic!()
+    };
+    assert_eq!(db.open_report().schema_version_found, 18);
+    assert_eq!(db.open_report().migrations_applied, 0);
+    assert_eq!(
+        core::mem::take(&mut *steps.lock()),
+        vec![OpenProgressStep::Opening, OpenProgressStep::VerifyingMeta]
+    );
+}
+
+#[test]
+fn resume_state() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(3)
+        .with_fork_at(1)
+        .with_storage([(b"foo".to_vec(), vec![1])])
+        .build();
+    let db = &fixture.database;
+    db.set_finalized(&fixture.canonical[2]).unwrap();
+
+    let state = db.resume_state().unwrap();
+    assert_eq!(
+        state,
+        ResumeState {
+            best_block_hash: fixture.canonical[3],
+            best_block_number: 3,
+            finalized_block_hash: fixture.canonical[2],
+            finalized_block_number: 2,
+            best_descends_from_finalized: true,
+            non_finalized_blocks: 2,
+            finalized_storage_complete: true,
+            orphans_below_finalized: 1,
+        }
+    );
+    assert!(state.is_resumable());
+
+    db.purge_finality_orphans(None).unwrap();
+    assert_eq!(db.resume_state().unwrap().orphans_below_finalized, 0);
+
+    // Simulate a best block that has been stored without its parent being linked to the
+    // finalized block.
+    db.database
+        .lock()
+        .execute(
+            "UPDATE blocks SET parent_hash = ? WHERE hash = ?",
+            (&[0xee; 32][..], &fixture.canonical[3][..]),
+        )
+        .unwrap();
+    let state = db.resume_state().unwrap();
+    assert!(!state.best_descends_from_finalized);
+    assert!(!state.is_resumable());
+}
+
+#[test]
+fn insert_scale_body() {
+    let fixture = FixtureChain::new(4).build();
+    let db = &fixture.database;
+
+    let block = header::HeaderRef {
+        parent_hash: &fixture.canonical[0],
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block_hash = header::hash_from_scale_encoded_header(&block);
+
+    // Two extrinsics, `[1, 2, 3]` and `[]`.
+    let body = [0x08, 0x0c, 1, 2, 3, 0x00];
+
+    for bad_body in [
+        &[][..],
+        &body[..body.len() - 1],
+        &[&body[..], &[0][..]].concat(),
+        // Claims an enormous number of extrinsics.
+        &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff][..],
+    ] {
+        assert!(matches!(
+            db.insert_scale_body(&block, true, bad_body, None, None),
+            Err(InsertError::BadBody)
+        ));
+    }
+    assert!(db
+        .block_scale_encoded_header(&block_hash)
+        .unwrap()
+        .is_none());
+
+    db.insert_scale_body(&block, true, &body, None, None)
+        .unwrap();
+    assert_eq!(
+        db.block_extrinsics(&block_hash)
+            .unwrap()
+            .unwrap()
+            .collect::<Vec<_>>(),
+        vec![vec![1, 2, 3], vec![]]
+    );
+}
+
+#[test]
+fn fork_metrics() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(4)
+        .with_fork_at(0)
+        .with_fork_at(2)
+        .with_fork_at(2)
+        .build();
+    let db = &fixture.database;
+
+    assert_eq!(
+        db.fork_metrics().unwrap(),
+        ForkMetrics {
+            leaves: 4,
+            max_fork_depth: 4,
+            non_best_blocks: 8,
+        }
+    );
+
+    // All the forks now branch from below the finalized block, and don't count towards the
+    // depth anymore. Their last block is still above the finalized block.
+    db.set_finalized(&fixture.canonical[3]).unwrap();
+    assert_eq!(
+        db.fork_metrics().unwrap(),
+        ForkMetrics {
+            leaves: 4,
+            max_fork_depth: 0,
+            non_best_blocks: 8,
+        }
+    );
+
+    db.set_finalized(&fixture.canonical[4]).unwrap();
+    db.purge_finality_orphans(None).unwrap();
+    assert_eq!(
+        db.fork_metrics().unwrap(),
+        ForkMetrics {
+            leaves: 1,
+            max_fork_depth: 0,
+            non_best_blocks: 0,
+        }
+    );
+}
+
+#[test]
+fn trie_nodes_wire_encoded() {
+    let fixture = FixtureChain::new(4)
+        .with_storage([
+            (b"a".to_vec(), vec![1]),
+            (b"ab".to_vec(), vec![2; 40]),
+            (b"abc".to_vec(), vec![3; 32]),
+            (b"b".to_vec(), Vec::new()),
+        ])
+        .with_child_trie(b"child", [(b"foo".to_vec(), vec![4; 100])])
+        .build();
+    let db = &fixture.database;
+
+    // Nodes whose encoding is shorter than 32 bytes are inlined in their parent and don't have
+    // a hash, except for the root nodes.
+    let mut hashes = Vec::new();
+    for child_trie in [None, Some(&b"child"[..])] {
+        for key in fixture.trie_nodes_keys(child_trie) {
+            let merkle_value = fixture
+                .closest_descendant_merkle_value(child_trie, key)
+                .unwrap();
+            if let Ok(hash) = <[u8; 32]>::try_from(&merkle_value[..]) {
+                hashes.push(hash);
+            }
+        }
+    }
+    assert!(hashes.contains(&fixture.state_root));
+    assert!(hashes.len() >= 3);
+
+    let encoded = db
+        .trie_nodes_wire_encoded(hashes.iter().copied().chain(iter::once([0xff; 32])))
+        .unwrap();
+    assert_eq!(encoded.len(), hashes.len() + 1);
+    assert!(encoded.last().unwrap().is_none());
+    for (hash, encoded) in hashes.iter().zip(&encoded) {
+        assert_eq!(super::blake2_hash(encoded.as_ref().unwrap()), *hash);
+    }
+}
+
+#[test]
+fn uncommitted_writes_lost() {
+    let DatabaseOpen::Empty(empty_db) = open(Config {
+        commit_policy: CommitPolicy::EveryNBlocks(NonZeroU64::new(3).unwrap()),
+        ..memory_config(4)
+    })
+    .unwrap() else {
+        panic!()
+    };
+
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    number: 0,
+                    extrinsics_root: &[0; 32],
+                    parent_hash: &[0; 32],
+                    state_root: &[1; 32],
+                    digest: header::DigestRef::empty(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+    db.flush().unwrap();
+    let genesis_hash = db.finalized_block_hash().unwrap();
+    let mut receiver = db.subscribe();
+
+    let block1 = header::HeaderRef {
+        parent_hash: &genesis_hash,
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let block1_hash = header::hash_from_scale_encoded_header(&block1);
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    assert_eq!(db.best_block_hash().unwrap(), block1_hash);
+
+    // SQLite rolls back the transaction on its own, as it does for example after some I/O
+    // errors.
+    db.database.lock().execute_batch("ROLLBACK").unwrap();
+
+    // The loss is reported by the next operation, and the events of the lost modifications are
+    // never emitted.
+    assert!(matches!(
+        db.set_best_block(&genesis_hash),
+        Err(SetBestError::Corrupted(
+            CorruptedError::UncommittedWritesLost { num_blocks: 1 }
+        ))
+    ));
+    assert_eq!(db.best_block_hash().unwrap(), genesis_hash);
+    assert_eq!(db.block_scale_encoded_header(&block1_hash).unwrap(), None);
+    db.flush().unwrap();
+    assert_eq!(receiver.try_next(), None);
+
+    // The lost modifications can be performed again.
+    db.insert(&block1, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+    db.flush().unwrap();
+    assert_eq!(
+        receiver.try_next(),
+        Some(Event::NewBest {
+            hash: block1_hash,
+            number: 1
+        })
+    );
+    assert_eq!(receiver.try_next(), None);
+}
+
+#[test]
+fn storage_full() {
+    let fixture = FixtureChain::new(4).build();
+    let db = &fixture.database;
+
+    // Forbid the database from growing.
+    db.database
+        .lock()
+        .execute_batch("PRAGMA max_page_count = 1")
+        .unwrap();
+
+    let block = header::HeaderRef {
+        parent_hash: &fixture.canonical[0],
+        number: 1,
+        state_root: &[1; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    let body = vec![vec![0xaa; 1024 * 1024]];
+    assert!(matches!(
+        db.insert(&block, true, body.iter(), None, None),
+        Err(InsertError::Corrupted(CorruptedError::StorageFull))
+    ));
+
+    // The database is still usable, and the insertion succeeds once space is available.
+    assert_eq!(db.best_block_hash().unwrap(), fixture.canonical[0]);
+    db.database
+        .lock()
+        .execute_batch("PRAGMA max_page_count = 1073741823")
+        .unwrap();
+    db.insert(&block, true, body.iter(), None, None).unwrap();
+    assert_eq!(
+        db.best_block_hash().unwrap(),
+        header::hash_from_scale_encoded_header(&block)
+    );
+}
+
+#[test]
+fn blocks_by_hash_prefix() {
+    let fixture = FixtureChain::new(4).with_blocks(20).build();
+    let db = &fixture.database;
+
+    for (number, hash) in fixture.canonical.iter().enumerate() {
+        let expected = vec![(*hash, u64::try_from(number).unwrap())];
+        assert_eq!(db.blocks_by_hash_prefix(&hash[..4], 10).unwrap(), expected);
+        assert_eq!(db.blocks_by_hash_prefix(&hash[..], 10).unwrap(), expected);
+    }
+
+    // An empty prefix matches all the blocks, in the order of their hash.
+    let mut sorted = fixture.canonical.clone();
+    sorted.sort();
+    let all = db.blocks_by_hash_prefix(&[], 100).unwrap();
+    assert_eq!(
+        all.iter().map(|(hash, _)| *hash).collect::<Vec<_>>(),
+        sorted
+    );
+    assert_eq!(db.blocks_by_hash_prefix(&[], 5).unwrap(), all[..5]);
+
+    // Prefixes made of `0xff` bytes don't have a successor.
+    let expected = all
+        .iter()
+        .filter(|(hash, _)| hash[0] == 0xff)
+        .copied()
+        .collect::<Vec<_>>();
+    assert_eq!(db.blocks_by_hash_prefix(&[0xff], 100).unwrap(), expected);
+
+    // The prefix of a block that isn't in the database.
+    let unknown = (0..=0xffu8)
+        .find(|byte| !fixture.canonical.iter().any(|hash| hash[0] == *byte))
+        .unwrap();
+    assert!(db.blocks_by_hash_prefix(&[unknown], 10).unwrap().is_empty());
+}
+
+#[test]
+fn storage_next_key_reads_few_nodes_under_small_prefix() {
+    // A large section of the trie is placed before the prefix being iterated, and another one
+    // after it.
+    let small_section = [vec![0xab, 1], vec![0xab, 2, 0], vec![0xab, 3]];
+    let fixture = FixtureChain::new(4)
+        .with_storage((0..2000u16).map(|n| {
+            let [hi, lo] = n.to_be_bytes();
+            (vec![0x00, hi, lo], vec![1])
+        }))
+        .with_storage(small_section.iter().map(|key| (key.clone(), vec![2])))
+        .with_storage((0..2000u16).map(|n| {
+            let [hi, lo] = n.to_be_bytes();
+            (vec![0xf0, hi, lo], vec![3])
+        }))
+        .build();
+    let block_hash = fixture.canonical[0];
+    let connection = fixture.database.database.lock();
+
+    let next_key = |key: &[u8], prefix: &[u8]| {
+        let mut nodes_read = 0;
+        let result = super::block_storage_next_key(
+            &connection,
+            &block_hash,
+            &[],
+            key,
+            prefix,
+            false,
+            &mut nodes_read,
+        )
+        .unwrap();
+        (result, nodes_read)
+    };
+    let nibbles = |bytes: &[u8]| {
+        trie::bytes_to_nibbles(bytes.iter().copied())
+            .map(u8::from)
+            .collect::<Vec<_>>()
+    };
+
+    let prefix = nibbles(&[0xab]);
+    let mut key = prefix.clone();
+    let mut found = Vec::new();
+    loop {
+        let (result, nodes_read) = next_key(&key, &prefix);
+        assert!(nodes_read <= 6, "{nodes_read} nodes read");
+        let Some(result) = result else { break };
+        key = result.iter().copied().chain(iter::once(0)).collect();
+        found.push(result);
+    }
+    assert_eq!(
+        found,
+        small_section
+            .iter()
+            .map(|key| nibbles(key))
+            .collect::<Vec<_>>()
+    );
+
+    // The first key of the trie doesn't start with the prefix.
+    assert_eq!(next_key(&[], &prefix), (None, 1));
+
+    // A key past the prefix doesn't require reading anything.
+    assert_eq!(next_key(&nibbles(&[0xac]), &prefix), (None, 0));
+}
+
+#[test]
+fn set_finalized_babe_epoch_transition() {
+    let DatabaseOpen::Empty(empty_db) = open(memory_config(4)).unwrap() else {
+        panic!()
+    };
+
+    let epoch =
+        |epoch_index, start_slot_number, authority| chain_information::BabeEpochInformation {
+            epoch_index,
+            start_slot_number: Some(start_slot_number),
+            authorities: vec![header::BabeAuthority {
+                public_key: [authority; 32],
+                weight: 1,
+            }],
+            randomness: [authority; 32],
+            c: (1, 4),
+            allowed_slots: header::BabeAllowedSlots::PrimarySlots,
+        };
+    let current_epoch = epoch(3, 1800, 1);
+    let next_epoch = epoch(4, 2400, 2);
+
+    let pre_digest = |slot_number| {
+        header::DigestItem::BabePreDigest(header::BabePreDigest::SecondaryPlain(
+            header::BabeSecondaryPlainPreDigest {
+                authority_index: 0,
+                slot_number,
+            },
+        ))
+    };
+
+    let genesis_digest = [pre_digest(2000)];
+    let db = empty_db
+        .initialize(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    parent_hash: &[4; 32],
+                    number: 1000,
+                    state_root: &[5; 32],
+                    extrinsics_root: &[0; 32],
+                    digest: header::DigestRef::from_slice(&genesis_digest).unwrap(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Babe {
+                    slots_per_epoch: NonZeroU64::new(600).unwrap(),
+                    finalized_block_epoch_information: Some((&current_epoch).into()),
+                    finalized_next_epoch_transition: (&next_epoch).into(),
+                },
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+        .unwrap();
+
+    // Block 1001 is the first block of epoch 4 and announces epoch 5.
+    let block1001_digest = [
+        pre_digest(2400),
+        header::DigestItem::BabeConsensus(header::BabeConsensusLog::NextEpochData(
+            header::BabeNextEpoch {
+                authorities: vec![header::BabeAuthority {
+                    public_key: [3; 32],
+                    weight: 1,
+                }],
+                randomness: [3; 32],
+            },
+        )),
+    ];
+    let block1001 = header::HeaderRef {
+        parent_hash: &db.finalized_block_hash().unwrap(),
+        number: 1001,
+        state_root: &[5; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::from_slice(&block1001_digest).unwrap(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block1001, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    let block1002_digest = [pre_digest(2401)];
+    let block1002 = header::HeaderRef {
+        parent_hash: &header::hash_from_scale_encoded_header(&block1001),
+        number: 1002,
+        state_root: &[5; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::from_slice(&block1002_digest).unwrap(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block1002, true, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    assert_eq!(
+        db.set_finalized(&header::hash_from_scale_encoded_header(&block1001))
+            .unwrap()
+            .babe_epoch_transition,
+        Some(BabeEpochTransition {
+            prev: Some(current_epoch),
+            next: next_epoch,
+        })
+    );
+
+    // Finalizing a block within the same epoch doesn't report any transition.
+    assert_eq!(
+        db.set_finalized(&header::hash_from_scale_encoded_header(&block1002))
+            .unwrap()
+            .babe_epoch_transition,
+        None
+    );
+}
+
+#[test]
+fn best_chain_bodies_in_range() {
+    let fixture = FixtureChain::new(4).build();
+    let db = &fixture.database;
+
+    // Inserts a block whose body contains `num_extrinsics` extrinsics made of its number, a
+    // marker distinguishing forks, and their index.
+    let insert = |parent_hash: [u8; 32], number: u64, fork: u8, num_extrinsics: u8| {
+        let block = header::HeaderRef {
+            parent_hash: &parent_hash,
+            number,
+            state_root: &[0; 32],
+            extrinsics_root: &[fork; 32],
+            digest: header::DigestRef::empty(),
+        }
+        .scale_encoding_vec(4);
+        let body = (0..num_extrinsics)
+            .map(|index| vec![u8::try_from(number).unwrap(), fork, index])
+            .collect::<Vec<_>>();
+        db.insert(&block, fork == 0, body.into_iter(), None, None)
+            .unwrap();
+        header::hash_from_scale_encoded_header(&block)
+    };
+
+    // Block 2 has an empty body, and the body of block 1 is pruned below. The fork at
+    // height 2 isn't part of the best chain.
+    let mut canonical = vec![fixture.canonical[0]];
+    for number in 1..=4 {
+        let num_extrinsics = if number == 2 { 0 } else { 2 };
+        let hash = insert(*canonical.last().unwrap(), number, 0, num_extrinsics);
+        canonical.push(hash);
+    }
+    insert(canonical[1], 2, 1, 3);
+    db.set_finalized(&canonical[2]).unwrap();
+    db.prune_bodies_below(2).unwrap();
+
+    let collect = |range, max| {
+        let mut out = Vec::new();
+        db.best_chain_bodies_in_range(range, |number, hash, index, extrinsic| {
+            out.push((number, hash, index, extrinsic.to_vec()));
+            if out.len() == max {
+                core::ops::ControlFlow::Break(())
+            } else {
+                core::ops::ControlFlow::Continue(())
+            }
+        })
+        .unwrap();
+        out
+    };
+
+    assert_eq!(
+        collect(0..=u64::MAX, usize::MAX),
+        vec![
+            (3, canonical[3], 0, vec![3, 0, 0]),
+            (3, canonical[3], 1, vec![3, 0, 1]),
+            (4, canonical[4], 0, vec![4, 0, 0]),
+            (4, canonical[4], 1, vec![4, 0, 1]),
+        ]
+    );
+    assert_eq!(collect(5..=10, usize::MAX), Vec::new());
+    assert_eq!(collect(3..=3, 1), vec![(3, canonical[3], 0, vec![3, 0, 0])]);
+}
+
+#[test]
+fn reset_rejects_header_not_round_tripping() {
+    let fixture = FixtureChain::new(4).with_blocks(2).build();
+    let db = &fixture.database;
+
+    // The block number in this digest item doesn't fit in 4 bytes.
+    let digest = [header::DigestItem::GrandpaConsensus(
+        header::GrandpaConsensusLog::Pause(u64::from(u32::MAX) + 1),
+    )];
+    let reset = |digest: &[header::DigestItem]| {
+        db.reset(
+            chain_information::ChainInformationRef {
+                finalized_block_header: header::HeaderRef {
+                    parent_hash: &[1; 32],
+                    number: 100,
+                    state_root: &[2; 32],
+                    extrinsics_root: &[3; 32],
+                    digest: header::DigestRef::from_slice(digest).unwrap(),
+                },
+                consensus: chain_information::ChainInformationConsensusRef::Unknown,
+                finality: chain_information::ChainInformationFinalityRef::Outsourced,
+            },
+            iter::empty(),
+            None,
+        )
+    };
+
+    assert!(matches!(
+        reset(&digest),
+        Err(ResetError::HeaderRoundTripMismatch)
+    ));
+    assert_eq!(db.finalized_block_hash().unwrap(), fixture.canonical[0]);
+    assert_eq!(db.best_block_hash().unwrap(), fixture.canonical[2]);
+
+    // The same header with a block number that fits is accepted.
+    let digest = [header::DigestItem::GrandpaConsensus(
+        header::GrandpaConsensusLog::Pause(u64::from(u32::MAX)),
+    )];
+    reset(&digest).unwrap();
+    assert_eq!(
+        db.best_block_hash_by_number(100).unwrap(),
+        Some(db.finalized_block_hash().unwrap())
+    );
+}
+
+#[test]
+fn trie_node_referencing_blocks() {
+    let fixture = FixtureChain::new(4)
+        .with_blocks(2)
+        .with_storage([(b"a".to_vec(), vec![1; 40]), (b"b".to_vec(), vec![2; 40])])
+        .with_child_trie(b"child", [(b"foo".to_vec(), vec![3; 100])])
+        .build();
+    let db = &fixture.database;
+
+    // A block whose storage is entirely different.
+    let other = header::HeaderRef {
+        parent_hash: &fixture.canonical[2],
+        number: 3,
+        state_root: &[9; 32],
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&other, false, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    let expected = fixture
+        .canonical
+        .iter()
+        .enumerate()
+        .map(|(number, hash)| (*hash, u64::try_from(number).unwrap()))
+        .collect::<Vec<_>>();
+
+    let child_leaf = fixture
+        .closest_descendant_merkle_value(Some(&b"child"[..]), &[])
+        .unwrap();
+    let main_leaf = fixture
+        .closest_descendant_merkle_value(
+            None,
+            &trie::bytes_to_nibbles(b"a".iter().copied())
+                .map(u8::from)
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    for merkle_value in [&fixture.state_root[..], &main_leaf, &child_leaf] {
+        assert!(db.trie_node_exists(merkle_value).unwrap());
+        assert_eq!(
+            db.trie_node_referencing_blocks(merkle_value).unwrap(),
+            expected
+        );
+    }
+
+    // The storage of the last block isn't in the database, but the block references it.
+    assert!(!db.trie_node_exists(&[9; 32]).unwrap());
+    assert_eq!(
+        db.trie_node_referencing_blocks(&[9; 32]).unwrap(),
+        vec![(header::hash_from_scale_encoded_header(&other), 3)]
+    );
+
+    assert!(!db.trie_node_exists(&[0xff; 32]).unwrap());
+    assert!(db
+        .trie_node_referencing_blocks(&[0xff; 32])
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn set_finalized_rejects_fork_below_finalized() {
+    let fixture = FixtureChain::new(4).with_blocks(4).with_fork_at(1).build();
+    let db = &fixture.database;
+    db.set_finalized(&fixture.canonical[2]).unwrap();
+
+    // The fork has branched off below the finalized block, and is made longer than the
+    // canonical chain.
+    let fork = &fixture.forks[0];
+    let block5 = header::HeaderRef {
+        parent_hash: fork.last().unwrap(),
+        number: 5,
+        state_root: &fixture.state_root,
+        extrinsics_root: &[0; 32],
+        digest: header::DigestRef::empty(),
+    }
+    .scale_encoding_vec(4);
+    db.insert(&block5, false, iter::empty::<Vec<u8>>(), None, None)
+        .unwrap();
+
+    assert!(matches!(
+        db.set_finalized(&header::hash_from_scale_encoded_header(&block5)),
+        Err(super::SetFinalizedError::NotDescendant)
+    ));
+    // A different block at the height of the finalized block is refused as well.
+    assert!(matches!(
+        db.set_finalized(&fork[0]),
+        Err(super::SetFinalizedError::NotDescendant)
+    ));
+    assert_eq!(db.finalized_block_hash().unwrap(), fixture.canonical[2]);
+
+    assert_eq!(
+        db.set_finalized(&fixture.canonical[2])
+            .unwrap()
+            .newly_finalized_blocks,
+        0
+    );
+    assert_eq!(
+        db.set_finalized(&fixture.canonical[4])
+            .unwrap()
+            .newly_finalized_blocks,
+        2
+    );
+}
+
+#[test]
+fn set_finalized_fork_block_becomes_best() {
+    let fixture = FixtureChain::new(4).with_blocks(3).with_fork_at(1).build();
+    let db = &fixture.database;
+    let fork = &fixture.forks[0];
+    let mut receiver = db.subscribe();
+
+    // The best block isn't changed before finalizing the fork.
+    assert_eq!(
+        db.set_finalized(&fork[0]).unwrap().newly_finalized_blocks,
+        2
+    );
+    assert_eq!(db.finalized_block_hash().unwrap(), fork[0]);
+    assert_eq!(db.best_block_hash().unwrap(), fork[0]);
+    assert_eq!(db.best_block_hash_by_number(2).unwrap(), Some(fork[0]));
+    assert_eq!(
+        db.export_checkpoint()
+            .unwrap()
+            .chain_information
+            .as_ref()
+            .finalized_block_header
+            .hash(4),
+        fork[0]
+    );
+    assert_eq!(
+        receiver.try_next(),
+        Some(Event::NewBest {
+            hash: fork[0],
+            number: 2
+        })
+    );
+    assert!(matches!(
+        receiver.try_next(),
+        Some(Event::Finalized { hash, number: 2, .. }) if hash == fork[0]
+    ));
+
+    // The rest of the fork can then be finalized.
+    db.prune_bodies_below(2).unwrap();
+    assert_eq!(
+        db.set_finalized(&fork[1]).unwrap().newly_finalized_blocks,
+        1
+    );
+    assert_eq!(db.finalized_block_hash().unwrap(), fork[1]);
+}
+
+#[test]
+#[allow(deprecated)]
+fn raw_trie_entry_version() {
+    let reference = [(b"abc".to_vec(), vec![1])]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+    let fixture = FixtureChain::new(4).with_storage(reference).build();
+    let db = &fixture.database;
+
+    assert_eq!(
+        db.block_storage_get(
+            &fixture.canonical[0],
+            iter::empty::<iter::Empty<u8>>(),
+            trie::bytes_to_nibbles(b"abc".iter().copied()).map(u8::from),
+        )
+        .unwrap(),
+        Some((vec![1], 1))
+    );
+
+    assert!(matches!(
+        db.insert_trie_nodes(iter::empty(), 2),
+        Err(InsertTrieNodesError::InvalidTrieEntryVersion)
+    ));
+    let stats = db.insert_trie_nodes(iter::empty(), 0).unwrap();
+    assert_eq!(stats.inserted_nodes, 0);
+}